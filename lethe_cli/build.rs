@@ -0,0 +1,11 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/lethe.proto");
+        let descriptors = protox::compile(["proto/lethe.proto"], ["proto"]).expect("proto/lethe.proto doesn't compile");
+        tonic_build::configure()
+            .build_client(false)
+            .compile_fds(descriptors)
+            .expect("Failed to generate the gRPC service");
+    }
+}
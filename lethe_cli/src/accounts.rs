@@ -0,0 +1,167 @@
+//! User accounts for `lethe serve --users`.
+//!
+//! The file is a small TOML document, one table per user:
+//!
+//! ```toml
+//! [users.alice]
+//! password = "$argon2id$v=19$..."   # written by `lethe users add`
+//! read = ["/"]
+//! write = ["/alice", "/shared"]
+//! ```
+//!
+//! Only this shape is understood: `[users.<name>]` headers, string values
+//! and single-line string arrays. Write access implies read access.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default)]
+pub struct Account {
+    pub name: String,
+    /// Argon2 PHC string, never the password itself
+    pub password: String,
+    pub read: Vec<String>,
+    pub write: Vec<String>,
+}
+
+/// Bare TOML keys; anything else would need quoting
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Canonical form of a permission prefix: leading slash, no trailing one
+pub fn normalize_prefix(prefix: &str) -> String {
+    format!("/{}", prefix.trim_matches('/'))
+}
+
+pub fn load(path: &Path) -> Result<Vec<Account>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+    parse(&text).with_context(|| format!("Invalid users file {:?}", path))
+}
+
+/// Appends one account table, creating the file if needed
+pub fn append(path: &Path, account: &Account) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {:?}", path))?;
+    writeln!(file, "\n[users.{}]", account.name)?;
+    writeln!(file, "password = {}", quote(&account.password))?;
+    writeln!(file, "read = {}", list(&account.read))?;
+    writeln!(file, "write = {}", list(&account.write))?;
+    Ok(())
+}
+
+// --- Parsing ---
+
+fn parse(text: &str) -> Result<Vec<Account>> {
+    let mut accounts: Vec<Account> = Vec::new();
+
+    for (n, raw) in text.lines().enumerate() {
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        // 1. Table header
+        if let Some(header) = line.strip_prefix('[') {
+            let header = header.split(']').next().unwrap_or("").trim();
+            let name = header.strip_prefix("users.")
+                .with_context(|| format!("line {}: expected [users.<name>]", n + 1))?;
+            if !valid_name(name) {
+                anyhow::bail!("line {}: invalid user name {:?}", n + 1, name);
+            }
+            if accounts.iter().any(|a| a.name == name) {
+                anyhow::bail!("line {}: user {:?} is defined twice", n + 1, name);
+            }
+            accounts.push(Account { name: name.to_string(), ..Default::default() });
+            continue;
+        }
+
+        // 2. key = value inside the current table
+        let (key, value) = line.split_once('=')
+            .with_context(|| format!("line {}: expected key = value", n + 1))?;
+        let account = accounts.last_mut()
+            .with_context(|| format!("line {}: key outside a [users.<name>] table", n + 1))?;
+        let value = value.trim();
+        let parsed = match key.trim() {
+            "password" => parse_string(value)
+                .and_then(|(s, rest)| check_trailing(rest).map(|()| account.password = s)),
+            "read" => parse_list(value).map(|l| account.read = l),
+            "write" => parse_list(value).map(|l| account.write = l),
+            other => Err(anyhow::anyhow!("unknown key {:?}", other)),
+        };
+        parsed.with_context(|| format!("line {}", n + 1))?;
+    }
+
+    for account in &mut accounts {
+        if account.password.is_empty() {
+            anyhow::bail!("user {:?} has no password", account.name);
+        }
+        account.read = account.read.iter().map(|p| normalize_prefix(p)).collect();
+        account.write = account.write.iter().map(|p| normalize_prefix(p)).collect();
+    }
+    Ok(accounts)
+}
+
+/// Parses a leading basic string, returning it and the rest of the input
+fn parse_string(input: &str) -> Result<(String, &str)> {
+    let body = input.strip_prefix('"').context("expected a quoted string")?;
+    let mut out = String::new();
+    let mut chars = body.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((out, &body[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, '"')) => out.push('"'),
+                Some((_, '\\')) => out.push('\\'),
+                Some((_, 'n')) => out.push('\n'),
+                Some((_, 't')) => out.push('\t'),
+                _ => anyhow::bail!("unsupported escape in string"),
+            },
+            c => out.push(c),
+        }
+    }
+    anyhow::bail!("unterminated string")
+}
+
+fn parse_list(input: &str) -> Result<Vec<String>> {
+    let mut rest = input.strip_prefix('[').context("expected [\"...\", ...]")?.trim_start();
+    let mut items = Vec::new();
+    loop {
+        if let Some(after) = rest.strip_prefix(']') {
+            check_trailing(after)?;
+            return Ok(items);
+        }
+        let (item, after) = parse_string(rest)?;
+        items.push(item);
+        rest = after.trim_start();
+        if let Some(after) = rest.strip_prefix(',') {
+            rest = after.trim_start();
+        } else if !rest.starts_with(']') {
+            anyhow::bail!("expected , or ] in list");
+        }
+    }
+}
+
+fn check_trailing(rest: &str) -> Result<()> {
+    let rest = rest.trim();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        anyhow::bail!("unexpected {:?} after value", rest)
+    }
+}
+
+// --- Writing ---
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn list(items: &[String]) -> String {
+    format!("[{}]", items.iter().map(|i| quote(i)).collect::<Vec<_>>().join(", "))
+}
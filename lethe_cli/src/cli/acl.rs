@@ -0,0 +1,95 @@
+use anyhow::Result;
+use std::collections::BTreeSet;
+
+use lethe_core::access::{self, Access, EVERYONE};
+use lethe_core::index::IndexManager;
+
+use super::ops::unlock_vault;
+use crate::daemon::claim::claim;
+
+/// Names must be users of the vault, or `*`
+fn names(index_mgr: &IndexManager, given: Vec<String>) -> Result<BTreeSet<String>> {
+    let mut names = BTreeSet::new();
+    for name in given.iter().flat_map(|n| n.split(',')).map(str::trim).filter(|n| !n.is_empty()) {
+        if name != EVERYONE && !access::users(&index_mgr.data).any(|(n, _)| n == name) {
+            anyhow::bail!("{} is not a user of this vault. Add them with `lethe users add --vault`.", name);
+        }
+        names.insert(name.to_string());
+    }
+    Ok(names)
+}
+
+fn list(names: &BTreeSet<String>) -> String {
+    if names.is_empty() {
+        "-".to_string()
+    } else {
+        names.iter().cloned().collect::<Vec<_>>().join(", ")
+    }
+}
+
+pub fn do_acl_set(prefix: String, read: Vec<String>, write: Vec<String>, vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let _claim = claim(&vault_path, "lethe acl set")?;
+    let mut index_mgr = IndexManager::load(vault_path, &key)?;
+    let read = names(&index_mgr, read)?;
+    let write = names(&index_mgr, write)?;
+    if read.is_empty() && write.is_empty() {
+        println!("Nobody is listed: {} and everything below it will be closed to all users.", access::normalize(&prefix));
+    }
+
+    index_mgr.set_acl(&prefix, read, write);
+    index_mgr.save(&key)?;
+    println!("Rule set on {}.", access::normalize(&prefix));
+    Ok(())
+}
+
+pub fn do_acl_clear(prefix: String, vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let _claim = claim(&vault_path, "lethe acl clear")?;
+    let mut index_mgr = IndexManager::load(vault_path, &key)?;
+    if !index_mgr.clear_acl(&prefix) {
+        anyhow::bail!("No rule on {}", access::normalize(&prefix));
+    }
+    index_mgr.save(&key)?;
+    println!("Rule on {} cleared.", access::normalize(&prefix));
+    Ok(())
+}
+
+pub fn do_acl_list(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let index_mgr = IndexManager::load(vault_path, &key)?;
+    let rules: Vec<_> = index_mgr.data.acls.iter().filter(|(_, rule)| !rule.removed).collect();
+    if rules.is_empty() {
+        println!("No rules. Vault users can't open anything until one grants them a folder.");
+        return Ok(());
+    }
+
+    println!("{:<24} | {:<24} | {:<24}", "FOLDER", "READ", "WRITE");
+    println!("{:-<78}", "-");
+    for (prefix, rule) in rules {
+        println!("{:<24} | {:<24} | {:<24}", prefix, list(&rule.read), list(&rule.write));
+    }
+    Ok(())
+}
+
+pub fn do_acl_check(user: String, path: String, vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let index_mgr = IndexManager::load(vault_path, &key)?;
+    let path = access::normalize(&path);
+    if !access::users(&index_mgr.data).any(|(n, _)| *n == user) {
+        anyhow::bail!("{} is not a user of this vault", user);
+    }
+
+    let verdict = if access::allows(&index_mgr.data, &user, &path, Access::Write) {
+        "read and write"
+    } else if access::allows(&index_mgr.data, &user, &path, Access::Read) {
+        "read"
+    } else {
+        "not open"
+    };
+    match access::rule_for(&index_mgr.data, &path) {
+        Some((prefix, _)) => println!("{}: {} for {} (rule on {})", path, verdict, user, prefix),
+        None => println!("{}: {} for {} (no rule covers it)", path, verdict, user),
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+//! `lethe agent`: see `daemon::agent`
+
+use anyhow::Result;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::daemon::agent::{self, AgentRequest, AgentResponse};
+use crate::daemon::registry;
+
+/// Run under this name (a link to `lethe`), it is `lethe agent run`
+pub const PROGRAM: &str = "lethe-agent";
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn expect_ok(response: AgentResponse) -> Result<()> {
+    match response {
+        AgentResponse::Ok(msg) => {
+            println!("{}", msg);
+            Ok(())
+        }
+        AgentResponse::Error(msg) => anyhow::bail!("{}", msg),
+        _ => anyhow::bail!("Unexpected response from the agent"),
+    }
+}
+
+pub async fn do_agent_run(ttl: Duration) -> Result<()> {
+    if ttl.as_secs() == 0 {
+        anyhow::bail!("--ttl must be at least a second");
+    }
+    println!("Lethe agent running; keys are kept for {} after each unlock.", humantime::format_duration(ttl));
+    println!("Press Ctrl+C or run `lethe agent stop` to stop and forget them.");
+    agent::run(ttl).await?;
+    println!("Agent stopped; keys forgotten.");
+    Ok(())
+}
+
+pub fn do_agent_list() -> Result<()> {
+    let held = match agent::send(AgentRequest::List)? {
+        AgentResponse::Held(held) => held,
+        AgentResponse::Error(msg) => anyhow::bail!("{}", msg),
+        _ => anyhow::bail!("Unexpected response from the agent"),
+    };
+    if held.is_empty() {
+        println!("The agent holds no keys.");
+    }
+    for key in held {
+        let left = Duration::from_secs(key.expires.saturating_sub(now_secs()));
+        println!("   {:<50} {} left", key.vault, humantime::format_duration(left));
+    }
+    Ok(())
+}
+
+/// Drops the key for `vault`, or every key
+pub fn do_agent_forget(vault: Option<String>) -> Result<()> {
+    let id = vault.map(|v| registry::instance_id(Path::new(&v)));
+    expect_ok(agent::send(AgentRequest::Forget { id })?)
+}
+
+pub fn do_agent_stop() -> Result<()> {
+    expect_ok(agent::send(AgentRequest::Stop)?)
+}
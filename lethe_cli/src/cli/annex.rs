@@ -0,0 +1,283 @@
+//! `lethe annex-remote`: a git-annex external special remote, so a
+//! git-annex repository can keep file content in a vault.
+//!
+//! git-annex runs `git-annex-remote-lethe` (a link to this binary, or a
+//! script running `lethe annex-remote`) and talks to it over stdin and
+//! stdout, one request per line. Each annexed key becomes the vault file
+//! `<folder>/<key>`, so it is chunked, deduplicated and encrypted like any
+//! other file and travels with push, pull and sync-peer.
+//!
+//! Configs: `vault` (the vault folder, required) and `folder` (default
+//! `/annex`). The password is asked for on the terminal at `initremote`
+//! and kept with git-annex's other credentials (`.git/annex/creds`), or
+//! taken from `LETHE_PASSWORD`; `savecreds=no` asks every time instead.
+//!
+//! Nothing may be printed on stdout but protocol lines, so this logs to
+//! stderr and claims the vault quietly.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+use lethe_core::activity;
+use lethe_core::audit::{self, Operation};
+use lethe_core::crypto::MasterKey;
+use lethe_core::header;
+use lethe_core::index::IndexManager;
+use lethe_core::progress::Silent;
+use lethe_core::reader::FileReader;
+use lethe_core::storage::BlockManager;
+
+use super::ops::unlock_key;
+use crate::daemon::claim::claim_quietly;
+
+/// File name git-annex looks for on the PATH for `externaltype=lethe`
+pub const PROGRAM: &str = "git-annex-remote-lethe";
+
+const DEFAULT_FOLDER: &str = "/annex";
+
+/// One git-annex session
+struct Annex<R, W> {
+    input: R,
+    output: W,
+    vault: Option<PathBuf>,
+    folder: String,
+    key: Option<MasterKey>,
+}
+
+impl<R: BufRead, W: Write> Annex<R, W> {
+    fn send(&mut self, line: &str) -> Result<()> {
+        writeln!(self.output, "{}", line)?;
+        self.output.flush()?;
+        Ok(())
+    }
+
+    /// The next line from git-annex; None once it hangs up
+    fn recv(&mut self) -> Result<Option<String>> {
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+    }
+
+    /// Sends a request of ours and returns git-annex's answer
+    fn ask(&mut self, request: &str, answer: &str) -> Result<String> {
+        self.send(request)?;
+        let reply = self.recv()?.context("git-annex hung up")?;
+        match reply.split_once(' ').unwrap_or((&reply, "")) {
+            (word, rest) if word == answer => Ok(rest.to_string()),
+            ("ERROR", message) => anyhow::bail!("git-annex: {}", message),
+            _ => anyhow::bail!("Expected {} from git-annex, got {:?}", answer, reply),
+        }
+    }
+
+    fn config(&mut self, name: &str) -> Result<String> {
+        self.ask(&format!("GETCONFIG {}", name), "VALUE")
+    }
+
+    /// Reads `vault` and `folder`
+    fn settings(&mut self) -> Result<()> {
+        let vault = self.config("vault")?;
+        if vault.is_empty() {
+            anyhow::bail!("Set vault=<vault folder> (git annex initremote ... vault=$HOME/.lethe_vault)");
+        }
+        let vault = PathBuf::from(vault);
+        if !header::exists(&vault) {
+            anyhow::bail!("Invalid vault path: {:?}. (Did you run 'lethe init'?)", vault);
+        }
+        let folder = self.config("folder")?;
+        self.folder = format!("/{}", if folder.is_empty() { DEFAULT_FOLDER } else { &folder }.trim_matches('/'));
+        self.vault = Some(vault);
+        Ok(())
+    }
+
+    /// Unlocks with `password`, checking it against the index
+    fn unlock(&mut self, password: &str) -> Result<()> {
+        let vault = self.vault.clone().context("No vault set")?;
+        let key = unlock_key(&vault, password)?;
+        activity::unlocked(&vault, &key);
+        IndexManager::load(vault.clone(), &key).context("Wrong password?")?;
+        let _ = audit::record(&vault, &key, Operation::Unlock { command: "lethe annex-remote".to_string() });
+        self.key = Some(key);
+        Ok(())
+    }
+
+    fn initremote(&mut self) -> Result<()> {
+        self.settings()?;
+        // git-annex runs remotes from the top of the repository
+        let vault = fs::canonicalize(self.vault.as_ref().context("No vault set")?)?;
+        self.send(&format!("SETCONFIG vault {}", vault.display()))?;
+        self.vault = Some(vault);
+        let password = password()?;
+        self.unlock(&password)?;
+        if self.config("savecreds")? != "no" {
+            self.send(&format!("SETCREDS password lethe {}", password))?;
+        }
+        Ok(())
+    }
+
+    fn prepare(&mut self) -> Result<()> {
+        self.settings()?;
+        let creds = self.ask("GETCREDS password", "CREDS")?;
+        let saved = creds.split_once(' ').map(|(_, password)| password.to_string()).filter(|p| !p.is_empty());
+        let password = match saved {
+            Some(password) => password,
+            None => password()?,
+        };
+        self.unlock(&password)
+    }
+
+    fn session(&self) -> Result<(PathBuf, &MasterKey)> {
+        match (&self.vault, &self.key) {
+            (Some(vault), Some(key)) => Ok((vault.clone(), key)),
+            _ => anyhow::bail!("Not prepared"),
+        }
+    }
+
+    fn path(&self, key: &str) -> Result<String> {
+        if key.is_empty() || key.contains('/') {
+            anyhow::bail!("Invalid key {:?}", key);
+        }
+        Ok(format!("{}/{}", self.folder, key))
+    }
+
+    fn store(&self, key: &str, file: &str) -> Result<()> {
+        let (vault, master) = self.session()?;
+        let source = fs::File::open(file).with_context(|| format!("Failed to read {}", file))?;
+        let _claim = claim_quietly(&vault, "lethe annex-remote")?;
+        let mut index_mgr = IndexManager::load(vault.clone(), master)?;
+        let storage = BlockManager::new(&vault)?;
+        index_mgr.store_reader(&storage, master, self.path(key)?, source, &Silent)?;
+        index_mgr.save(master)
+    }
+
+    fn retrieve(&self, key: &str, file: &str) -> Result<()> {
+        let (vault, master) = self.session()?;
+        let index_mgr = IndexManager::load(vault.clone(), master)?;
+        let entry = index_mgr.get_file(&self.path(key)?).context("Not in the vault")?;
+        let storage = BlockManager::new(&vault)?;
+        let mut out = fs::File::create(file).with_context(|| format!("Failed to write {}", file))?;
+        FileReader::new(&storage, master, entry).copy_to(&mut out, &Silent)
+            .with_context(|| format!("Failed to write {}", file))?;
+        Ok(())
+    }
+
+    fn present(&self, key: &str) -> Result<bool> {
+        let (vault, master) = self.session()?;
+        let index_mgr = IndexManager::load(vault, master)?;
+        Ok(index_mgr.get_file(&self.path(key)?).is_some())
+    }
+
+    /// Removing a key that isn't there succeeds, as git-annex expects
+    fn remove(&self, key: &str) -> Result<()> {
+        let (vault, master) = self.session()?;
+        let path = self.path(key)?;
+        let _claim = claim_quietly(&vault, "lethe annex-remote")?;
+        let mut index_mgr = IndexManager::load(vault, master)?;
+        index_mgr.check_mutable(&path)?;
+        if index_mgr.data.files.remove(&path).is_some() {
+            index_mgr.touch_parent(&path);
+            index_mgr.save(master)?;
+        }
+        Ok(())
+    }
+
+    /// Answers one request; false once git-annex is done
+    fn handle(&mut self, line: &str) -> Result<bool> {
+        let (request, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let reply = match request {
+            "EXTENSIONS" => "EXTENSIONS".to_string(),
+            "INITREMOTE" => match self.initremote() {
+                Ok(()) => "INITREMOTE-SUCCESS".to_string(),
+                Err(e) => format!("INITREMOTE-FAILURE {}", one_line(&e)),
+            },
+            "PREPARE" => match self.prepare() {
+                Ok(()) => "PREPARE-SUCCESS".to_string(),
+                Err(e) => format!("PREPARE-FAILURE {}", one_line(&e)),
+            },
+            "TRANSFER" => {
+                let mut words = rest.splitn(3, ' ');
+                let (direction, key, file) = (words.next().unwrap_or(""), words.next().unwrap_or(""), words.next().unwrap_or(""));
+                let result = match direction {
+                    "STORE" => self.store(key, file),
+                    "RETRIEVE" => self.retrieve(key, file),
+                    _ => Err(anyhow::anyhow!("Unknown direction {:?}", direction)),
+                };
+                match result {
+                    Ok(()) => format!("TRANSFER-SUCCESS {} {}", direction, key),
+                    Err(e) => format!("TRANSFER-FAILURE {} {} {}", direction, key, one_line(&e)),
+                }
+            }
+            "CHECKPRESENT" => match self.present(rest) {
+                Ok(true) => format!("CHECKPRESENT-SUCCESS {}", rest),
+                Ok(false) => format!("CHECKPRESENT-FAILURE {}", rest),
+                Err(e) => format!("CHECKPRESENT-UNKNOWN {} {}", rest, one_line(&e)),
+            },
+            "REMOVE" => match self.remove(rest) {
+                Ok(()) => format!("REMOVE-SUCCESS {}", rest),
+                Err(e) => format!("REMOVE-FAILURE {} {}", rest, one_line(&e)),
+            },
+            "GETCOST" => "COST 100".to_string(),
+            "GETAVAILABILITY" => "AVAILABILITY LOCAL".to_string(),
+            "LISTCONFIGS" => {
+                self.send("CONFIG vault the Lethe vault folder")?;
+                self.send("CONFIG folder vault folder holding the annexed files (default /annex)")?;
+                self.send("CONFIG savecreds set to no to ask for the password every time")?;
+                "CONFIGEND".to_string()
+            }
+            "GETINFO" => {
+                if let Some(vault) = &self.vault {
+                    let vault = vault.display().to_string();
+                    self.send("INFOFIELD vault")?;
+                    self.send(&format!("INFOVALUE {}", vault))?;
+                    self.send("INFOFIELD folder")?;
+                    self.send(&format!("INFOVALUE {}", self.folder))?;
+                }
+                "INFOEND".to_string()
+            }
+            "ERROR" => {
+                log::error!("git-annex: {}", rest);
+                return Ok(false);
+            }
+            _ => "UNSUPPORTED-REQUEST".to_string(),
+        };
+        self.send(&reply)?;
+        Ok(true)
+    }
+}
+
+/// Protocol lines end at a newline, and so must every message
+fn one_line(e: &anyhow::Error) -> String {
+    format!("{:#}", e).replace(['\r', '\n'], " ")
+}
+
+/// `LETHE_PASSWORD`, or asked for on the terminal; stdin is git-annex's
+fn password() -> Result<String> {
+    if let Ok(password) = std::env::var("LETHE_PASSWORD") {
+        return Ok(password);
+    }
+    rpassword::prompt_password("Lethe vault password for git-annex: ")
+        .context("No terminal to ask for the vault password on. Set LETHE_PASSWORD.")
+}
+
+// --- COMMAND HANDLERS ---
+
+pub fn do_annex_remote() -> Result<()> {
+    let stdin = io::stdin();
+    let mut annex = Annex {
+        input: stdin.lock(),
+        output: io::stdout().lock(),
+        vault: None,
+        folder: DEFAULT_FOLDER.to_string(),
+        key: None,
+    };
+    annex.send("VERSION 1")?;
+    while let Some(line) = annex.recv()? {
+        if !annex.handle(&line)? {
+            break;
+        }
+    }
+    Ok(())
+}
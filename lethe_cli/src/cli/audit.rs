@@ -0,0 +1,54 @@
+use anyhow::Result;
+use std::time::{Duration, UNIX_EPOCH};
+
+use lethe_core::audit::{self, Operation};
+
+use super::ops::unlock_vault;
+
+fn describe(operation: &Operation) -> (&'static str, String) {
+    match operation {
+        Operation::Unlock { command } => ("unlock", command.clone()),
+        Operation::Mount { target } => ("mount", target.clone()),
+        Operation::Unmount { target } => ("unmount", target.clone()),
+        Operation::Delete { path } => ("delete", path.clone()),
+        Operation::SnapshotDelete { name } => ("delete", format!("snapshot {}", name)),
+        Operation::Clean { blocks, bytes } => ("clean", format!("{} block(s) removed ({})", blocks, humansize::format_size(*bytes, humansize::BINARY))),
+        Operation::Rekey { reason } => ("rekey", reason.clone()),
+        Operation::Sync { kind, with } => ("sync", format!("{} {}", kind, with)),
+        Operation::BlockRepair { block, volume, reason } => ("repair", format!("block {} on {}: {}", block, volume, reason)),
+        Operation::BlockDamage { block, found } => ("damage", format!("block {}: {}", block, found)),
+        Operation::Publish { prefix, out, files, bytes } => {
+            ("publish", format!("{} to {} ({} file(s), {})", prefix, out, files, humansize::format_size(*bytes, humansize::BINARY)))
+        }
+        Operation::Release { path, files } => ("release", format!("{} ({} write-once file(s))", path, files)),
+    }
+}
+
+pub fn do_audit(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let trail = audit::read(&vault_path, &key)?;
+    if trail.entries.is_empty() && trail.unreadable == 0 {
+        println!("Nothing audited yet.");
+        return Ok(());
+    }
+
+    for entry in &trail.entries {
+        if !entry.chained {
+            println!("--- records are missing or were altered here ---");
+        }
+        let record = &entry.record;
+        let time = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(record.time));
+        let device = format!("{} ({})", record.device_name, &record.device[..record.device.len().min(8)]);
+        let (kind, what) = describe(&record.operation);
+        println!("{}  {:<26} {:<8} {}", time, device, kind, what);
+    }
+
+    if !trail.intact() {
+        println!();
+        println!("WARNING: The audit log was tampered with or damaged.");
+        if trail.unreadable > 0 {
+            println!("   {} record(s) no longer open.", trail.unreadable);
+        }
+    }
+    Ok(())
+}
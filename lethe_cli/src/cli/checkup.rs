@@ -0,0 +1,325 @@
+//! A quick look at how a copy of the vault is holding up, taken when it is
+//! unlocked: whether the index replicas agree, how long since its blocks
+//! were last verified, blocks nothing uses any more, and what an
+//! interrupted save or a failed push left behind. `lethe stats` shows it
+//! in full; mounts log it, and `lethe ls` mentions it when something is
+//! off, so small problems get seen before they add up. Files whose blocks
+//! are missing would only fail to open, so `lethe mount` lists them first
+//! and offers to move them out of the way, to `QUARANTINE_DIR`.
+//!
+//! It only reads what is cheap to read: the replicas and a listing of the
+//! blocks, no block is decrypted. A hidden vault gets none, as its index
+//! lives in the outer vault's padding and everything else is the outer's.
+
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use lethe_core::crypto::MasterKey;
+use lethe_core::index::{self, IndexManager, Listing};
+use lethe_core::storage::BlockManager;
+
+use crate::daemon::claim::claim;
+use crate::sync::health::{self, Health};
+use crate::sync::outbox::{self, Outbox};
+
+/// Days after which a copy is due another `lethe verify`
+pub const VERIFY_DAYS: u64 = 30;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// A leftover `.tmp` younger than this may still be being written
+const TMP_GRACE: Duration = Duration::from_secs(60);
+
+/// Where `lethe mount` moves files whose blocks are missing, at their path
+pub const QUARANTINE_DIR: &str = "/.quarantine";
+
+/// Damaged files listed before asking
+const SHOWN: usize = 10;
+
+fn size(bytes: u64) -> String {
+    humansize::format_size(bytes, humansize::BINARY)
+}
+
+pub struct Checkup {
+    /// Replicas rewritten on load for being behind, missing or unreadable
+    pub repaired: usize,
+    /// Replicas on disk that are byte for byte the same, of 3
+    pub agreeing: usize,
+    /// Last `lethe verify` that found this copy whole
+    pub verified: Option<u64>,
+    /// Blocks on disk that nothing references, and their size
+    pub unreferenced: usize,
+    pub unreferenced_bytes: u64,
+    /// Live files with blocks this copy doesn't have, with how many
+    pub damaged: Vec<(String, usize)>,
+    /// Files an interrupted save left in the vault folder
+    pub leftovers: Vec<String>,
+    /// The backup remote is owed a push
+    pub push_owed: bool,
+}
+
+/// Something to look at, and what to run about it
+pub struct Finding {
+    pub problem: String,
+    pub advice: String,
+    /// The vault is at risk now, not just untidy
+    pub serious: bool,
+}
+
+impl Checkup {
+    /// None for a hidden vault
+    pub fn take(vault: &Path, key: &MasterKey, index_mgr: &IndexManager) -> Result<Option<Self>> {
+        if index_mgr.is_hidden() {
+            return Ok(None);
+        }
+        let referenced = index_mgr.referenced_blocks();
+        let (mut unreferenced, mut unreferenced_bytes) = (0, 0);
+        let mut present = HashSet::new();
+        for (id, len) in BlockManager::new(vault)?.list_blocks()? {
+            if !referenced.contains(id.as_str()) {
+                unreferenced += 1;
+                unreferenced_bytes += len;
+            }
+            present.insert(id);
+        }
+        let mut damaged: Vec<(String, usize)> = index_mgr.data.files.iter()
+            .filter(|(path, e)| !e.is_dir && !e.is_expired() && !index::is_snapshot_path(path))
+            .map(|(path, e)| (path.clone(), e.blocks.iter().filter(|id| !present.contains(*id)).count()))
+            .filter(|(_, missing)| *missing > 0)
+            .collect();
+        damaged.sort();
+        Ok(Some(Self {
+            repaired: index_mgr.stale_replicas(),
+            unreferenced,
+            unreferenced_bytes,
+            damaged,
+            ..Self::files_only(vault, key, index_mgr.data.revision)?
+        }))
+    }
+
+    /// `take` for a vault only listed (`IndexManager::read_listing`):
+    /// without the block count, which needs the block maps, or replicas
+    /// rewritten on load, as listing rewrites none
+    pub fn quick(vault: &Path, key: &MasterKey, listing: &Listing) -> Result<Option<Self>> {
+        if listing.hidden {
+            return Ok(None);
+        }
+        Self::files_only(vault, key, listing.revision).map(Some)
+    }
+
+    /// What the files around the index tell, at index revision `revision`
+    fn files_only(vault: &Path, key: &MasterKey, revision: u64) -> Result<Self> {
+        let push_owed = outbox::remote(vault, key).is_some() && {
+            let outbox = Outbox::load(vault, key)?;
+            outbox.pending.is_some() || revision > outbox.pushed
+        };
+        Ok(Self {
+            repaired: 0,
+            agreeing: agreeing_replicas(vault),
+            verified: Health::load(vault, key)?.verified,
+            unreferenced: 0,
+            unreferenced_bytes: 0,
+            damaged: Vec::new(),
+            leftovers: leftovers(vault),
+            push_owed,
+        })
+    }
+
+    pub fn findings(&self, vault: &str) -> Vec<Finding> {
+        let mut found = Vec::new();
+        if self.agreeing < 3 {
+            found.push(Finding {
+                problem: format!("only {} of 3 index replicas agree", self.agreeing),
+                advice: format!("run `lethe repair --vault {}`, then check the disk", vault),
+                serious: true,
+            });
+        }
+        if !self.damaged.is_empty() {
+            found.push(Finding {
+                problem: format!("{} file(s) refer to blocks this copy doesn't have and won't open", self.damaged.len()),
+                advice: format!("run `lethe repair --vault {}`, or pull from a copy that has them", vault),
+                serious: true,
+            });
+        }
+        if self.repaired > 0 {
+            found.push(Finding {
+                problem: format!("{} index replica(s) were behind or damaged and were rewritten on unlock", self.repaired),
+                advice: "if this keeps happening, check the disk".to_string(),
+                serious: false,
+            });
+        }
+        let age = self.verified.map(|at| health::now().saturating_sub(at) / DAY);
+        if age.is_none_or(|days| days > VERIFY_DAYS) {
+            found.push(Finding {
+                problem: match age {
+                    Some(days) => format!("blocks last verified {} day(s) ago", days),
+                    None => "blocks never verified".to_string(),
+                },
+                advice: format!(
+                    "run `lethe manifest --vault {0} --out manifest.sig` and `lethe verify --manifest manifest.sig {0}`",
+                    vault
+                ),
+                serious: false,
+            });
+        }
+        if self.unreferenced > 0 {
+            found.push(Finding {
+                problem: format!("{} block(s), {}, that no file uses", self.unreferenced, size(self.unreferenced_bytes)),
+                advice: format!("run `lethe clean --vault {}` to free them", vault),
+                serious: false,
+            });
+        }
+        if !self.leftovers.is_empty() {
+            found.push(Finding {
+                problem: format!("left over from an interrupted save: {}", self.leftovers.join(", ")),
+                advice: format!("the next save clears them; `lethe repair --vault {}` saves now", vault),
+                serious: false,
+            });
+        }
+        if self.push_owed {
+            found.push(Finding {
+                problem: "the backup remote is owed a push".to_string(),
+                advice: format!("run `lethe remote flush --vault {}`", vault),
+                serious: false,
+            });
+        }
+        found
+    }
+}
+
+/// One word for how the vault is doing
+pub fn verdict(findings: &[Finding]) -> &'static str {
+    match findings {
+        [] => "good",
+        _ if findings.iter().any(|f| f.serious) => "poor",
+        _ => "fair",
+    }
+}
+
+/// A line saying there is something to look at, if there is
+pub fn nudge(findings: &[Finding]) -> Option<String> {
+    (!findings.is_empty()).then(|| {
+        format!("Vault health: {}, {} thing(s) to look at (see `lethe stats`).", verdict(findings), findings.len())
+    })
+}
+
+/// The size of the largest set of replicas with the same bytes. Every save
+/// writes the same sealed index to all three, so any difference is damage
+/// or an interrupted save.
+fn agreeing_replicas(vault: &Path) -> usize {
+    let mut same: HashMap<Vec<u8>, usize> = HashMap::new();
+    for i in 0..3 {
+        if let Ok(bytes) = fs::read(vault.join(format!("meta_{}.bin", i))) {
+            *same.entry(bytes).or_default() += 1;
+        }
+    }
+    same.into_values().max().unwrap_or(0)
+}
+
+/// `.tmp` files in the vault folder no writer is still busy with
+fn leftovers(vault: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(vault) else { return Vec::new() };
+    let settled = SystemTime::now().checked_sub(TMP_GRACE).unwrap_or(SystemTime::UNIX_EPOCH);
+    let mut names: Vec<String> = entries.flatten()
+        .filter(|e| e.path().extension().is_some_and(|ext| ext == "tmp"))
+        .filter(|e| e.metadata().and_then(|m| m.modified()).is_ok_and(|at| at < settled))
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Logs the checkup of a vault just unlocked, and says so on the terminal
+/// if something needs looking at
+pub fn report_unlock(vault: &Path, key: &MasterKey, index_mgr: &IndexManager) {
+    let checkup = match Checkup::take(vault, key, index_mgr) {
+        Ok(Some(checkup)) => checkup,
+        Ok(None) => return,
+        Err(e) => {
+            log::warn!("Vault health check failed: {:#}", e);
+            return;
+        }
+    };
+    let findings = checkup.findings(&vault.display().to_string());
+    log::info!("Vault health: {}", verdict(&findings));
+    for finding in &findings {
+        log::warn!("Vault health: {}; {}", finding.problem, finding.advice);
+    }
+    if let Some(line) = nudge(&findings) {
+        println!("{}", line);
+    }
+}
+
+/// Before a mount shows the files: lists those whose blocks are missing
+/// and, on a terminal, offers to move them to `QUARANTINE_DIR`, so they
+/// aren't shown where they would only fail to open
+pub fn check_before_mount(vault: &Path, key: &MasterKey, index_mgr: &mut IndexManager) -> Result<()> {
+    let Some(checkup) = Checkup::take(vault, key, index_mgr)? else { return Ok(()) };
+    if checkup.repaired > 0 {
+        println!("{} index replica(s) were behind or damaged and have been rewritten.", checkup.repaired);
+    } else if checkup.agreeing < 3 {
+        println!("Only {} of 3 index replicas agree; the newest that opens is used.", checkup.agreeing);
+    }
+    // Those already moved aren't offered again
+    let (held, damaged): (Vec<_>, Vec<_>) = checkup.damaged.iter().partition(|(path, _)| index::is_below(path, QUARANTINE_DIR));
+    if !held.is_empty() {
+        println!("{} file(s) in {} still miss blocks.", held.len(), QUARANTINE_DIR);
+    }
+    if damaged.is_empty() {
+        return Ok(());
+    }
+    println!("{} file(s) refer to blocks missing from this copy and would fail to open:", damaged.len());
+    for (path, missing) in damaged.iter().take(SHOWN) {
+        println!("   {}  ({} block(s) missing)", path, missing);
+    }
+    if damaged.len() > SHOWN {
+        println!("   ... and {} more", damaged.len() - SHOWN);
+    }
+    if !io::stdin().is_terminal() {
+        println!("Mounting them as they are; `lethe repair` or a pull from another copy may bring the blocks back.");
+        return Ok(());
+    }
+    print!("Move them to {} before mounting? [y/N] ", QUARANTINE_DIR);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        return Ok(());
+    }
+
+    let _claim = claim(vault, "lethe mount")?;
+    *index_mgr = IndexManager::load(vault.to_path_buf(), key)?;
+    let (mut moved, mut kept) = (0, 0);
+    for (path, _) in damaged {
+        if index_mgr.get_file(path).is_none() {
+            continue;
+        }
+        if index_mgr.check_mutable(path).is_err() {
+            kept += 1;
+            continue;
+        }
+        let to = (1..)
+            .map(|n| match n {
+                1 => format!("{}{}", QUARANTINE_DIR, path),
+                n => format!("{}{} ({})", QUARANTINE_DIR, path, n),
+            })
+            .find(|to| index_mgr.get_file(to).is_none())
+            .unwrap();
+        if index_mgr.rename_tree(path, &to) {
+            moved += 1;
+        }
+    }
+    index_mgr.save(key)?;
+    println!(
+        "Moved {} file(s) to {}. Once `lethe repair` or a pull brings their blocks back, move them back in the mount.",
+        moved, QUARANTINE_DIR
+    );
+    if kept > 0 {
+        println!("   {} write-once file(s) stay where they are; `lethe release` lets them move.", kept);
+    }
+    Ok(())
+}
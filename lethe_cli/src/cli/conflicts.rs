@@ -0,0 +1,73 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use std::time::{Duration, UNIX_EPOCH};
+
+use lethe_core::index::IndexManager;
+use lethe_core::merge::conflict_original;
+
+use super::ops::unlock_vault;
+use crate::daemon::claim::claim;
+
+/// Which version of a conflicted file survives
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Keep {
+    /// The version at the original path
+    Original,
+    /// The conflict copy, moved back to the original path
+    Copy,
+}
+
+pub fn do_conflicts_list(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let index_mgr = IndexManager::load(vault_path, &key)?;
+
+    let mut copies: Vec<_> = index_mgr.data.files.values()
+        .filter(|e| !e.is_dir && conflict_original(&e.path).is_some())
+        .collect();
+    if copies.is_empty() {
+        println!("No conflicts.");
+        return Ok(());
+    }
+    copies.sort_by(|a, b| a.path.cmp(&b.path));
+
+    println!("{:<12} | {:<22} | {:<40}", "SIZE", "MODIFIED", "CONFLICT COPY");
+    println!("{:-<80}", "-");
+    for entry in copies {
+        let modified = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(entry.modified));
+        let size_str = humansize::format_size(entry.size, humansize::BINARY);
+        println!("{:<12} | {:<22} | {}", size_str, modified.to_string(), entry.path);
+    }
+    println!("\nResolve with: lethe conflicts resolve <copy> --keep original|copy --vault {}", vault);
+    Ok(())
+}
+
+pub fn do_conflicts_resolve(copy: String, keep: Keep, vault: String) -> Result<()> {
+    let original = match conflict_original(&copy) {
+        Some(original) => original,
+        None => anyhow::bail!("{} is not a conflict copy", copy),
+    };
+
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let _claim = claim(&vault_path, "lethe conflicts resolve")?;
+    let mut index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    index_mgr.check_mutable(&copy)?;
+    if matches!(keep, Keep::Copy) {
+        index_mgr.check_mutable(&original)?;
+    }
+
+    let Some(mut entry) = index_mgr.data.files.remove(&copy) else {
+        anyhow::bail!("File not found in vault: {}", copy);
+    };
+    match keep {
+        Keep::Original => println!("Kept {}; removed {}", original, copy),
+        Keep::Copy => {
+            entry.path = original.clone();
+            index_mgr.data.files.insert(original.clone(), entry);
+            println!("Moved {} over {}", copy, original);
+        }
+    }
+    index_mgr.touch_parent(&copy);
+    index_mgr.save(&key)?;
+    println!("Run `lethe clean` to reclaim the discarded version's blocks.");
+    Ok(())
+}
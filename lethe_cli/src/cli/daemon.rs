@@ -0,0 +1,493 @@
+use anyhow::Result;
+use log::{error, info, warn};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+use lethe_core::activity::{self, Op};
+use lethe_core::audit::{self, Operation};
+use lethe_core::crypto::MasterKey;
+use lethe_core::header;
+use lethe_core::index::IndexManager;
+use lethe_core::vault_lock::VaultLock;
+
+use crate::cli::checkup;
+use crate::cli::mount::{attach, MountHandle, MountOptions, Pause};
+use crate::cli::ops::{resolve_vault_path, unlock_key};
+use crate::daemon::ipc::{self, Command, DaemonStatus, Request, Response};
+use crate::daemon::registry::{self, Registration};
+use crate::daemon::retention;
+use crate::daemon::scrub::Scrubber;
+use crate::daemon::service::{self, ServiceSpec};
+use crate::daemon::{sentinel, Activity, SentinelConfig};
+use crate::sync::outbox::{self, Replay};
+
+/// How often a mount sends its saves to the backup remote, if one is set
+const PUSH_EVERY: Duration = Duration::from_secs(60);
+
+/// What ended an unlocked session
+enum Outcome {
+    Locked,
+    Exit,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// The Sentinel loop: mount while unlocked, unmount on any lock trigger.
+///
+/// With `resident` the process survives a lock and waits for the next
+/// `lethe daemon unlock`; otherwise the first lock ends it.
+pub async fn run_sentinel(
+    vault_path: PathBuf,
+    opts: MountOptions,
+    cfg: SentinelConfig,
+    mut unlocked: Option<MasterKey>,
+    resident: bool,
+) -> Result<()> {
+    let id = registry::instance_id(&vault_path);
+    let (tx, mut rx) = mpsc::channel::<Command>(8);
+    let listener = match ipc::Server::bind(&id).await {
+        Ok(server) => Some(tokio::spawn(server.run(tx))),
+        Err(e) if resident => return Err(e),
+        Err(e) => {
+            warn!("Control channel disabled: {}", e);
+            None
+        }
+    };
+    let mut registration = Registration::create(&id, &vault_path)?;
+
+    let vault_str = vault_path.display().to_string();
+
+    let result = loop {
+        // 1. Locked: wait for a key
+        let key = match unlocked.take() {
+            Some(session) => session,
+            None if !resident => break Ok(()),
+            None => {
+                println!("Sentinel armed. Vault is locked (run 'lethe daemon unlock').");
+                match wait_for_unlock(&vault_path, &vault_str, &mut rx).await {
+                    Some(session) => session,
+                    None => break Ok(()),
+                }
+            }
+        };
+
+        // 2. Unlocked: take the vault, mount and watch the lock triggers.
+        // Pruning first releases targets held by Sentinels that crashed.
+        ipc::live_sentinels().await;
+        let mut held = match VaultLock::acquire(&vault_path, "lethe mount") {
+            Ok(lock) => Some(lock),
+            Err(e) if resident => {
+                error!("Mount failed: {}", e);
+                continue;
+            }
+            Err(e) => break Err(e),
+        };
+        // Read only now that the vault is held, so a `lethe put` or sync
+        // that saved since the unlock isn't overwritten by the mount
+        let index_mgr = match IndexManager::load(vault_path.clone(), &key) {
+            Ok(index_mgr) => index_mgr,
+            Err(e) if resident => {
+                error!("Mount failed: {}", e);
+                continue;
+            }
+            Err(e) => break Err(e),
+        };
+        checkup::report_unlock(&vault_path, &key, &index_mgr);
+        let activity = Activity::new();
+        // The mount takes the key; pushes to the remote need their own
+        let push_key = MasterKey::new(*key.as_bytes());
+        let handle = match attach(&vault_path, index_mgr, key, &opts, activity.clone()) {
+            Ok(handle) => handle,
+            Err(e) if resident => {
+                error!("Mount failed: {}", e);
+                continue;
+            }
+            Err(e) => break Err(e),
+        };
+
+        if let Err(e) = registration.set_mounted(Some(handle.target.clone()), handle.port, handle.owns_dir) {
+            warn!("{}", e);
+        }
+        info!("Vault unlocked, mounted at {}", handle.target);
+        let _ = audit::record(&vault_path, &push_key, Operation::Mount { target: handle.target.clone() });
+        activity::count(&vault_path, &push_key, Op::Mount, 0, 0);
+        println!("   (Press Ctrl+C to Lock & Quit)");
+        let status = DaemonStatus {
+            vault: vault_str.clone(),
+            mountpoint: Some(handle.target.clone()),
+            unlocked_since: Some(now_secs()),
+            last_activity: activity.last_active(),
+            dirty_buffers: 0,
+            cache: None,
+            scrub: None,
+        };
+        let outcome = watch_unlocked(cfg, &activity, status, (&vault_path, &push_key), &handle, &mut held, &mut rx).await;
+
+        let target = handle.target.clone();
+        handle.detach().await;
+        let _ = audit::record(&vault_path, &push_key, Operation::Unmount { target });
+        drop(held);
+        if let Err(e) = registration.set_mounted(None, None, false) {
+            warn!("{}", e);
+        }
+        info!("Vault locked");
+        println!("\nVault Locked.");
+
+        if let Outcome::Exit = outcome {
+            break Ok(());
+        }
+    };
+
+    if let Some(listener) = listener {
+        listener.abort();
+    }
+    result
+}
+
+/// Sends what the mount saved since the last push, if a remote is set.
+/// The mount stands aside meanwhile so the push sees a settled index.
+async fn push_owed(vault_path: &Path, key: &MasterKey, handle: &MountHandle) {
+    match tokio::task::block_in_place(|| outbox::owed(vault_path, key)) {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            warn!("Could not check for a queued push: {}", e);
+            return;
+        }
+    }
+    let pause = match handle.pause().await {
+        Ok(pause) => pause,
+        Err(e) => {
+            warn!("Push postponed, the mount could not step aside: {}", e);
+            return;
+        }
+    };
+    match tokio::task::block_in_place(|| outbox::replay(vault_path, key)) {
+        Ok(Replay::Sent) => info!("Pushed to the remote"),
+        Ok(Replay::Queued(e)) => warn!("Remote unreachable, push queued: {}", e),
+        Ok(_) => {}
+        Err(e) => warn!("Push failed: {}", e),
+    }
+    if let Err(e) = pause.resume().await {
+        warn!("The mount could not reload the index after a push: {}", e);
+    }
+}
+
+/// Takes or deletes the snapshots the retention policy has due. The
+/// mount stands aside meanwhile, and reloads the index left.
+async fn keep_retention(vault_path: &Path, key: &MasterKey, handle: &MountHandle) {
+    match tokio::task::block_in_place(|| retention::pending(vault_path, key)) {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            warn!("Could not check the retention policy: {:#}", e);
+            return;
+        }
+    }
+    let pause = match handle.pause().await {
+        Ok(pause) => pause,
+        Err(e) => {
+            warn!("Retention postponed, the mount could not step aside: {}", e);
+            return;
+        }
+    };
+    let applied = tokio::task::block_in_place(|| {
+        let mut index_mgr = IndexManager::load(vault_path.to_path_buf(), key)?;
+        retention::enforce(&mut index_mgr, vault_path, key)
+    });
+    if let Err(e) = applied {
+        warn!("Retention failed: {:#}", e);
+    }
+    if let Err(e) = pause.resume().await {
+        warn!("The mount could not reload the index after retention: {}", e);
+    }
+}
+
+/// Takes the vault back after a handoff. The command lets go just before
+/// it hangs up, so this only waits if a third process slipped in.
+async fn retake(vault: &Path) -> Option<VaultLock> {
+    for _ in 0..50 {
+        match VaultLock::try_acquire(vault, "lethe mount") {
+            Ok(Some(lock)) => return Some(lock),
+            Ok(None) => tokio::time::sleep(std::time::Duration::from_millis(100)).await,
+            Err(e) => {
+                warn!("{}", e);
+                break;
+            }
+        }
+    }
+    warn!("Resumed without the vault lock: {}", lethe_core::vault_lock::in_use(vault));
+    None
+}
+
+async fn watch_unlocked(
+    cfg: SentinelConfig,
+    activity: &Activity,
+    mut status: DaemonStatus,
+    (vault_path, key): (&Path, &MasterKey),
+    handle: &MountHandle,
+    held: &mut Option<VaultLock>,
+    rx: &mut mpsc::Receiver<Command>,
+) -> Outcome {
+    let watcher = sentinel::watch(cfg, activity.clone());
+    let lock_requested = handle.lock_requested();
+    tokio::pin!(watcher);
+    tokio::pin!(lock_requested);
+    // Dropped on any way out, which lets the mount go on before it detaches
+    let mut paused: Option<Pause> = None;
+    let mut push_tick = tokio::time::interval(PUSH_EVERY);
+    push_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut retention_tick = tokio::time::interval(retention::EVERY);
+    retention_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let scrubber = Scrubber::start(vault_path.to_path_buf(), Arc::new(MasterKey::new(*key.as_bytes())));
+
+    loop {
+        tokio::select! {
+            _ = sentinel::shutdown_signal() => return Outcome::Exit,
+            _ = push_tick.tick(), if paused.is_none() => push_owed(vault_path, key, handle).await,
+            _ = retention_tick.tick(), if paused.is_none() => keep_retention(vault_path, key, handle).await,
+            _ = &mut lock_requested => {
+                info!("Lock requested by a server client");
+                println!("\nLock requested by a client.");
+                return Outcome::Locked;
+            }
+            reason = &mut watcher => {
+                info!("Auto-lock: {}", reason);
+                println!("\nAuto-lock: {}.", reason);
+                return Outcome::Locked;
+            }
+            Some(cmd) = rx.recv() => match cmd.request {
+                Request::Status => {
+                    status.last_activity = activity.last_active();
+                    status.dirty_buffers = activity.dirty();
+                    status.cache = handle.cache.as_ref().map(|c| c.stats());
+                    status.scrub = scrubber.status();
+                    let _ = cmd.reply.send(Response::Status(status.clone()));
+                }
+                Request::Unmount { target } if status.matches(&target) => {
+                    let _ = cmd.reply.send(Response::Ok(format!("Unmounted {}.", target)));
+                    return Outcome::Locked;
+                }
+                Request::Unmount { target } => {
+                    let _ = cmd.reply.send(Response::Error(format!("Not mounted here: {}", target)));
+                }
+                Request::Lock => {
+                    let _ = cmd.reply.send(Response::Ok("Vault locked.".to_string()));
+                    return Outcome::Locked;
+                }
+                Request::Panic => {
+                    let _ = cmd.reply.send(Response::Ok("Vault locked, Sentinel exiting.".to_string()));
+                    return Outcome::Exit;
+                }
+                Request::Unlock { .. } => {
+                    let _ = cmd.reply.send(Response::Error("Vault is already unlocked.".to_string()));
+                }
+                Request::SyncStatus => {
+                    let _ = cmd.reply.send(Response::Error("This is a Sentinel, not `lethe syncd`.".to_string()));
+                }
+                Request::Handoff if paused.is_some() => {
+                    let _ = cmd.reply.send(Response::Error("The mount is already standing aside for another command.".to_string()));
+                }
+                Request::Handoff => match handle.pause().await {
+                    Ok(pause) => {
+                        *held = None;
+                        paused = Some(pause);
+                        info!("Vault handed to another process");
+                        let _ = cmd.reply.send(Response::Ok(format!("The mount at {} saved and stepped aside.", handle.target)));
+                    }
+                    Err(e) => {
+                        let _ = cmd.reply.send(Response::Error(format!("The mount could not step aside: {}", e)));
+                    }
+                },
+                Request::Resume => match paused.take() {
+                    Some(pause) => {
+                        *held = retake(vault_path).await;
+                        let response = match pause.resume().await {
+                            Ok(()) => Response::Ok(format!("The mount at {} picked up the changes.", handle.target)),
+                            Err(e) => Response::Error(format!("The mount resumed but could not reload the index: {}", e)),
+                        };
+                        info!("Vault taken back after a handoff");
+                        let _ = cmd.reply.send(response);
+                    }
+                    None => {
+                        let _ = cmd.reply.send(Response::Error("The mount is not standing aside.".to_string()));
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Serves IPC while locked. Returns None when the Sentinel should exit.
+async fn wait_for_unlock(
+    vault_path: &Path,
+    vault_str: &str,
+    rx: &mut mpsc::Receiver<Command>,
+) -> Option<MasterKey> {
+    loop {
+        tokio::select! {
+            _ = sentinel::shutdown_signal() => return None,
+            cmd = rx.recv() => {
+                let cmd = cmd?;
+                match cmd.request {
+                    Request::Status => {
+                        let _ = cmd.reply.send(Response::Status(DaemonStatus {
+                            vault: vault_str.to_string(),
+                            mountpoint: None,
+                            unlocked_since: None,
+                            last_activity: 0,
+                            dirty_buffers: 0,
+                            cache: None,
+                            scrub: None,
+                        }));
+                    }
+                    Request::Unmount { target } => {
+                        let _ = cmd.reply.send(Response::Error(format!("Vault is locked, nothing mounted at {}", target)));
+                    }
+                    Request::Unlock { password } => {
+                        // Argon2 is deliberately slow; keep it off the executor
+                        let path = vault_path.to_path_buf();
+                        let attempt = tokio::task::spawn_blocking(move || -> Result<MasterKey> {
+                            let key = unlock_key(&path, &password)?;
+                            activity::unlocked(&path, &key);
+                            let _ = audit::record(&path, &key, Operation::Unlock { command: "lethe daemon unlock".to_string() });
+                            Ok(key)
+                        }).await;
+
+                        match attempt {
+                            Ok(Ok(key)) => {
+                                let _ = cmd.reply.send(Response::Ok("Vault unlocked.".to_string()));
+                                return Some(key);
+                            }
+                            Ok(Err(e)) => {
+                                let _ = cmd.reply.send(Response::Error(e.to_string()));
+                            }
+                            Err(e) => {
+                                let _ = cmd.reply.send(Response::Error(format!("Unlock task failed: {}", e)));
+                            }
+                        }
+                    }
+                    Request::Lock => {
+                        let _ = cmd.reply.send(Response::Ok("Vault is already locked.".to_string()));
+                    }
+                    Request::Panic => {
+                        let _ = cmd.reply.send(Response::Ok("Sentinel exiting.".to_string()));
+                        return None;
+                    }
+                    Request::SyncStatus => {
+                        let _ = cmd.reply.send(Response::Error("This is a Sentinel, not `lethe syncd`.".to_string()));
+                    }
+                    Request::Handoff | Request::Resume => {
+                        let _ = cmd.reply.send(Response::Error("Vault is locked; nothing is mounted.".to_string()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// --- COMMAND HANDLERS ---
+
+pub async fn do_daemon_run(
+    vault: Option<String>,
+    mountpoint: Option<String>,
+    cfg: SentinelConfig,
+) -> Result<()> {
+    let vault_path = resolve_vault_path(vault.as_deref())?;
+    if !header::exists(&vault_path) {
+        anyhow::bail!("Invalid vault path: {:?}. (Did you run 'lethe init'?)", vault_path);
+    }
+
+    println!("Lethe Sentinel starting for {:?}", vault_path);
+    let opts = MountOptions { mountpoint, ..Default::default() };
+    run_sentinel(vault_path, opts, cfg, None, true).await?;
+    println!("Sentinel stopped.");
+    Ok(())
+}
+
+pub fn do_daemon_install(
+    vault: Option<String>,
+    mountpoint: Option<String>,
+    idle_timeout: Option<u64>,
+    no_auto_lock: bool,
+) -> Result<()> {
+    let vault_path = resolve_vault_path(vault.as_deref())?;
+    if !header::exists(&vault_path) {
+        anyhow::bail!("Invalid vault path: {:?}. (Did you run 'lethe init'?)", vault_path);
+    }
+
+    // Services don't start in the user's working directory
+    let spec = ServiceSpec {
+        vault: std::fs::canonicalize(&vault_path)?,
+        mountpoint,
+        idle_timeout,
+        no_auto_lock,
+    };
+
+    println!("{}", service::install(&spec)?);
+    println!("Logs: {:?}", service::log_path()?);
+    Ok(())
+}
+
+pub fn do_daemon_uninstall() -> Result<()> {
+    println!("{}", service::uninstall()?);
+    Ok(())
+}
+
+pub async fn do_daemon_status(vault: Option<String>) -> Result<()> {
+    let record = ipc::find_sentinel(vault.as_deref().map(Path::new)).await?;
+    match ipc::send(&record.id, Request::Status).await? {
+        Response::Status(status) => {
+            println!("Vault:      {}", status.vault);
+            match (status.mountpoint, status.unlocked_since) {
+                (Some(target), Some(since)) => {
+                    println!("State:      UNLOCKED");
+                    println!("Mounted at: {}", target);
+                    println!("Uptime:     {}s", now_secs().saturating_sub(since));
+                    println!("Idle:       {}s", now_secs().saturating_sub(status.last_activity));
+                    println!("Dirty:      {} open file(s)", status.dirty_buffers);
+                    if let Some(scrub) = status.scrub {
+                        println!("Scrub:      {} of {} block(s) checked this pass, {} damaged", scrub.checked, scrub.blocks, scrub.damaged);
+                        if scrub.damaged > 0 {
+                            println!("            Run `lethe repair`, or `lethe pull` from a remote, and see `lethe audit`.");
+                        }
+                    }
+                }
+                _ => println!("State:      LOCKED"),
+            }
+            Ok(())
+        }
+        Response::Ok(msg) => {
+            println!("{}", msg);
+            Ok(())
+        }
+        Response::Error(msg) => anyhow::bail!("Sentinel error: {}", msg),
+        Response::Sync(_) => anyhow::bail!("Unexpected response from Sentinel"),
+    }
+}
+
+pub async fn do_daemon_lock(vault: Option<String>) -> Result<()> {
+    let record = ipc::find_sentinel(vault.as_deref().map(Path::new)).await?;
+    report(ipc::send(&record.id, Request::Lock).await?)
+}
+
+pub async fn do_daemon_unlock(vault: Option<String>) -> Result<()> {
+    let record = ipc::find_sentinel(vault.as_deref().map(Path::new)).await?;
+    let password = tokio::task::block_in_place(|| rpassword::prompt_password("Enter Vault Password: "))?;
+    report(ipc::send(&record.id, Request::Unlock { password }).await?)
+}
+
+fn report(response: Response) -> Result<()> {
+    match response {
+        Response::Ok(msg) => {
+            println!("{}", msg);
+            Ok(())
+        }
+        Response::Error(msg) => anyhow::bail!("Sentinel error: {}", msg),
+        Response::Status(_) | Response::Sync(_) => anyhow::bail!("Unexpected response from Sentinel"),
+    }
+}
@@ -0,0 +1,139 @@
+use anyhow::Result;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use lethe_core::audit::{self, Operation};
+use lethe_core::crypto::MasterKey;
+use lethe_core::device;
+use lethe_core::index::IndexManager;
+use lethe_core::keyring::{self, DeviceKey, Keyring};
+
+use super::ops::unlock_outer;
+use crate::daemon::claim::claim;
+
+fn load_keyring(vault_path: &Path, key: &MasterKey) -> Result<Keyring> {
+    Keyring::load(vault_path, key)?
+        .ok_or_else(|| anyhow::anyhow!("Devices aren't enabled for this vault. Run `lethe devices enable` first."))
+}
+
+/// Slot id for a device name, a slot id or a unique prefix of one
+fn find_slot(ring: &Keyring, device: &str) -> Result<String> {
+    let by_name: Vec<&String> = ring.slots.iter().filter(|(_, s)| s.name == device).map(|(id, _)| id).collect();
+    let by_id: Vec<&String> = ring.slots.keys().filter(|id| id.starts_with(device)).collect();
+    match (by_name.as_slice(), by_id.as_slice()) {
+        ([id], _) | ([], [id]) => Ok(id.to_string()),
+        ([], []) => anyhow::bail!("No enrolled device named {:?}. See `lethe devices list`.", device),
+        _ => anyhow::bail!("{:?} matches more than one device; give its slot id instead.", device),
+    }
+}
+
+fn print_join(device: &DeviceKey, vault: &str) {
+    println!("   lethe devices join {} --vault {}", device.code(), vault);
+}
+
+pub fn do_devices_enable(vault: String, name: Option<String>) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe devices enable")?;
+    if Keyring::load(&vault_path, &key)?.is_some() {
+        anyhow::bail!("Devices are already enabled for this vault. Add one with `lethe devices enroll <name>`.");
+    }
+    let mut index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+
+    // The device key goes first: a keyring with no one to open it would
+    // lock this copy out
+    let name = name.unwrap_or_else(device::name);
+    let (ring, index_key, me) = Keyring::create(&name, &key)?;
+    keyring::remember(&vault_path, &me)?;
+    ring.save(&vault_path, &key)?;
+    index_mgr.reseal(Some(index_key), &key)?;
+    let _ = audit::record(&vault_path, &key, Operation::Rekey { reason: "devices enabled".to_string() });
+
+    println!("Devices enabled. This copy is enrolled as {:?}.", name);
+    println!("   Other copies now need `lethe devices enroll <name>` here and `lethe devices join` there before they can sync.");
+    Ok(())
+}
+
+pub fn do_devices_list(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let ring = load_keyring(&vault_path, &key)?;
+    let mine = ring.find(&keyring::device_keys(&vault_path), &key).map(|(device, _)| device.slot.clone());
+
+    println!("{} enrolled device(s), keyring epoch {}:", ring.slots.len(), ring.epoch);
+    let mut slots: Vec<_> = ring.slots.iter().collect();
+    slots.sort_by_key(|(_, slot)| slot.enrolled);
+    for (id, slot) in slots {
+        let enrolled = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(slot.enrolled));
+        let marker = if mine.as_ref() == Some(id) { "  (this device)" } else { "" };
+        println!("   {:<20} slot {}  enrolled {}{}", slot.name, &id[..8], enrolled, marker);
+    }
+    if mine.is_none() {
+        println!("This copy is not enrolled.");
+    }
+    Ok(())
+}
+
+pub fn do_devices_enroll(name: String, vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe devices enroll")?;
+    let mut ring = load_keyring(&vault_path, &key)?;
+    if ring.slots.values().any(|s| s.name == name) {
+        anyhow::bail!("A device named {:?} is already enrolled.", name);
+    }
+    let index_key = keyring::index_key(&vault_path, &key)?.ok_or_else(keyring::not_enrolled)?;
+
+    let device = ring.enroll(&name, &index_key.current, &key)?;
+    ring.save(&vault_path, &key)?;
+
+    println!("Enrolled {:?}. On that device, run:", name);
+    print_join(&device, "<its vault path>");
+    println!("   It can sync once it has joined. Keep the code secret: with the password it opens the index.");
+    Ok(())
+}
+
+pub fn do_devices_join(code: String, vault: String) -> Result<()> {
+    let device = DeviceKey::from_code(&code)?;
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    keyring::remember(&vault_path, &device)?;
+
+    match Keyring::load(&vault_path, &key)? {
+        Some(ring) if ring.find(std::slice::from_ref(&device), &key).is_some() => {
+            println!("Joined as {:?}.", ring.slots[&device.slot].name);
+        }
+        _ => {
+            println!("Device key saved. This copy doesn't have the keyring listing it yet;");
+            println!("   it arrives with the next sync-peer or pull from the device that issued the code.");
+        }
+    }
+    Ok(())
+}
+
+pub fn do_devices_revoke(device: String, vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe devices revoke")?;
+    let mut ring = load_keyring(&vault_path, &key)?;
+    let slot = find_slot(&ring, &device)?;
+    let name = ring.slots[&slot].name.clone();
+
+    let mut index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    let devices = keyring::device_keys(&vault_path);
+    let (me, _) = ring.find(&devices, &key).ok_or_else(keyring::not_enrolled)?;
+    let old = keyring::index_key(&vault_path, &key)?.ok_or_else(keyring::not_enrolled)?;
+
+    // Keyring first: it still holds the old index key for the replicas
+    let (index_key, issued) = ring.revoke(&slot, me, &old, &key)?;
+    ring.save(&vault_path, &key)?;
+    index_mgr.reseal(Some(index_key), &key)?;
+    let _ = audit::record(&vault_path, &key, Operation::Rekey { reason: format!("{} revoked", name) });
+
+    println!("Revoked {:?}. The index key was rotated; index updates from now on don't open through its slot.", name);
+    if !issued.is_empty() {
+        println!("The other devices need a new code. On each, run:");
+        for (name, device) in &issued {
+            println!("   {}:", name);
+            print_join(device, "<its vault path>");
+        }
+        println!("   They keep syncing once they have joined and reached this copy's keyring.");
+    }
+    println!("   The revoked device keeps what it already holds; its old slot opens nothing newer.");
+    Ok(())
+}
@@ -0,0 +1,158 @@
+//! `lethe drill`: a restore rehearsal on a random sample of files.
+//!
+//! A backup is only as good as the last time something was brought back
+//! from it. The drill picks `--sample` files (a percentage of the vault, or
+//! a count), restores each into a private folder under the temp directory,
+//! reads it back and checks every block against the chunk hash the index
+//! keeps for it, then shreds the plaintext. Files stored before chunk
+//! hashes were kept can only be checked for their size, and the summary
+//! says how many those were. A drill that passes is noted next to the
+//! index and shown by `lethe stats`.
+
+use anyhow::{Context, Result};
+use rand::seq::SliceRandom;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use lethe_core::crypto::{CryptoEngine, MasterKey};
+use lethe_core::index::{is_snapshot_path, FileEntry, IndexManager};
+use lethe_core::progress::{self, ProgressSink};
+use lethe_core::reader::FileReader;
+use lethe_core::storage::{self, BlockManager};
+
+use super::ops::unlock_vault;
+use super::progress::Bar;
+use crate::container::create_private_dir;
+use crate::sync::health;
+
+fn size(bytes: u64) -> String {
+    humansize::format_size(bytes, humansize::BINARY)
+}
+
+/// What `--sample` asks for
+enum Sample {
+    Percent(f64),
+    Files(usize),
+}
+
+impl Sample {
+    /// `5%` or `20`
+    fn parse(sample: &str) -> Result<Self> {
+        let parsed = match sample.trim().strip_suffix('%') {
+            Some(percent) => percent.trim().parse().ok().filter(|p| *p > 0.0 && *p <= 100.0).map(Sample::Percent),
+            None => sample.trim().parse().ok().filter(|n| *n > 0).map(Sample::Files),
+        };
+        parsed.with_context(|| format!("--sample takes a percentage above 0 and up to 100, or a number of files, not {:?}", sample))
+    }
+
+    /// How many of `total` files, at least one
+    fn of(&self, total: usize) -> usize {
+        let count = match *self {
+            Sample::Percent(p) => (total as f64 * p / 100.0).ceil() as usize,
+            Sample::Files(n) => n,
+        };
+        count.clamp(1, total)
+    }
+}
+
+/// Reads back the restored copy at `path`. True if every block matched its
+/// chunk hash, false if only the size could be checked.
+fn check(path: &Path, entry: &FileEntry, key: &MasterKey) -> Result<bool> {
+    let len = fs::metadata(path)?.len();
+    if len != entry.size {
+        anyhow::bail!("restored {} byte(s), the index says {}", len, entry.size);
+    }
+    if entry.hashes.is_empty() || entry.hashes.len() != entry.sizes.len() {
+        return Ok(false);
+    }
+    let mut file = fs::File::open(path)?;
+    let mut block = Vec::new();
+    for (i, (hash, &len)) in entry.hashes.iter().zip(&entry.sizes).enumerate() {
+        block.resize(len as usize, 0);
+        file.read_exact(&mut block).with_context(|| format!("block {} is short", i))?;
+        if CryptoEngine::chunk_hash(&block, key) != *hash {
+            anyhow::bail!("block {} does not match its hash in the index", i);
+        }
+    }
+    Ok(true)
+}
+
+/// Restores the file at `target`, checks it and shreds it again
+fn drill_file(block_mgr: &BlockManager, key: &MasterKey, entry: &FileEntry, target: &Path, bar: &Bar) -> Result<bool> {
+    let checked = (|| -> Result<bool> {
+        let mut file = fs::File::create(target)?;
+        FileReader::new(block_mgr, key, entry).copy_to(&mut file, bar)?;
+        file.sync_all()?;
+        drop(file);
+        check(target, entry, key)
+    })();
+    if target.exists() {
+        storage::shred(target).with_context(|| format!("Failed to shred {:?}", target))?;
+    }
+    checked
+}
+
+pub fn do_drill(vault: String, sample: String, tmp: Option<PathBuf>) -> Result<()> {
+    let sample = Sample::parse(&sample)?;
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    let block_mgr = BlockManager::new(&vault_path)?;
+
+    let files: Vec<(&String, &FileEntry)> = index_mgr.data.files.iter()
+        .filter(|(path, e)| !e.is_dir && !e.is_expired() && !is_snapshot_path(path))
+        .collect();
+    if files.is_empty() {
+        println!("The vault holds no files to drill.");
+        return Ok(());
+    }
+    let count = sample.of(files.len());
+    let mut picked: Vec<_> = files.choose_multiple(&mut rand::thread_rng(), count).copied().collect();
+    picked.sort_by_key(|(path, _)| *path);
+    let total: u64 = picked.iter().map(|(_, e)| e.size).sum();
+
+    let dir = tmp.unwrap_or_else(std::env::temp_dir).join(format!("lethe-drill-{}", std::process::id()));
+    create_private_dir(&dir)?;
+    println!("Drilling {} of {} file(s), {}, in {:?}...", picked.len(), files.len(), size(total), dir);
+
+    let bar = Bar::new("Drilling");
+    bar.start(total, picked.len() as u64);
+    let (mut hashed, mut sized) = (0, 0);
+    let mut failed = Vec::new();
+    let mut cancelled = false;
+    tokio::task::block_in_place(|| {
+        for (i, (path, entry)) in picked.iter().enumerate() {
+            if bar.cancelled() {
+                cancelled = true;
+                break;
+            }
+            // Numbered, so no vault path ever decides where plaintext lands
+            match drill_file(&block_mgr, &key, entry, &dir.join(i.to_string()), &bar) {
+                Ok(true) => hashed += 1,
+                Ok(false) => sized += 1,
+                Err(e) if progress::is_cancelled(&e) => {
+                    cancelled = true;
+                    break;
+                }
+                Err(e) => failed.push(format!("{}: {:#}", path, e)),
+            }
+        }
+    });
+    drop(bar);
+    fs::remove_dir(&dir).with_context(|| format!("Failed to remove {:?}", dir))?;
+
+    if cancelled {
+        anyhow::bail!("Cancelled; nothing restored was left behind");
+    }
+    for failure in &failed {
+        println!("FAILED: {}", failure);
+    }
+    if !failed.is_empty() {
+        anyhow::bail!("{} of {} file(s) could not be restored intact; the vault may be damaged, see `lethe verify`", failed.len(), picked.len());
+    }
+    println!("Drill passed: {} file(s) restored, {} checked block by block against the index and {} by size only.", picked.len(), hashed, sized);
+    if !index_mgr.is_hidden() {
+        health::copy_drilled(&vault_path, &key);
+    }
+    Ok(())
+}
@@ -0,0 +1,132 @@
+//! `lethe dupes`: identical files under different vault paths, found from
+//! the chunk hashes the index already keeps, so nothing is decrypted.
+//!
+//! A file shares blocks with any identical one already stored, so copies
+//! with blocks of their own are ones written side by side (two files
+//! saved at once through a mount) or on two devices and brought together
+//! by sync. `--link` points each copy at the first file's blocks, leaving
+//! the blocks only it used for `lethe clean`. Files stored before chunk
+//! hashes were kept, or with another chunk size, can't be compared this
+//! way and are left out.
+
+use anyhow::Result;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use lethe_core::index::{self, FileEntry, IndexManager, VaultIndex};
+use lethe_core::storage::BlockManager;
+
+use super::ops::{unlock_outer, unlock_vault};
+use crate::daemon::claim::claim;
+use crate::sync::outbox;
+
+fn size(bytes: u64) -> String {
+    humansize::format_size(bytes, humansize::BINARY)
+}
+
+/// Groups of live files with the same content, each sorted by path
+fn groups(data: &VaultIndex) -> Vec<Vec<&FileEntry>> {
+    let mut by_content: BTreeMap<(u64, &[String]), Vec<&FileEntry>> = BTreeMap::new();
+    let live = data.files.iter().filter(|(path, e)| {
+        !e.is_dir && !e.is_expired() && e.size > 0 && !e.hashes.is_empty() && !index::is_snapshot_path(path)
+    });
+    for (_, entry) in live {
+        by_content.entry((entry.size, entry.hashes.as_slice())).or_default().push(entry);
+    }
+    let mut groups: Vec<Vec<&FileEntry>> = by_content.into_values().filter(|g| g.len() > 1).collect();
+    for group in &mut groups {
+        group.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+    // Most space first
+    groups.sort_by_key(|g| std::cmp::Reverse(g[0].size * (g.len() as u64 - 1)));
+    groups
+}
+
+/// Points every file in each group at the blocks of the first. Returns
+/// the paths changed.
+fn link(data: &mut VaultIndex) -> Vec<String> {
+    let plan: Vec<(String, FileEntry)> = groups(data).iter()
+        .flat_map(|group| group[1..].iter()
+            .filter(|e| e.blocks != group[0].blocks)
+            .map(|e| (e.path.clone(), group[0].clone())))
+        .collect();
+    let mut changed = Vec::new();
+    for (path, first) in plan {
+        if let Some(entry) = data.files.get_mut(&path) {
+            entry.blocks = first.blocks;
+            entry.sizes = first.sizes;
+            entry.stored = first.stored;
+            changed.push(path);
+        }
+    }
+    changed
+}
+
+/// What the blocks nothing references once `linked` replaces `data` take on disk
+fn freed(data: &VaultIndex, linked: &VaultIndex, vault: &Path) -> Result<(usize, u64)> {
+    let storage = BlockManager::new(vault)?;
+    let still: HashSet<&str> = linked.referenced_blocks();
+    let dropped: Vec<&str> = data.referenced_blocks().into_iter().filter(|id| !still.contains(id)).collect();
+    let bytes = dropped.iter()
+        .filter_map(|id| storage.block_path(id).ok().and_then(|path| fs::metadata(path).ok()))
+        .map(|meta| meta.len())
+        .sum();
+    Ok((dropped.len(), bytes))
+}
+
+pub fn do_dupes(vault: String, link_copies: bool) -> Result<()> {
+    if link_copies {
+        return do_dupes_link(vault);
+    }
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+
+    let groups = groups(&index_mgr.data);
+    if groups.is_empty() {
+        println!("No duplicate files.");
+        return Ok(());
+    }
+    let mut separate = 0;
+    for group in &groups {
+        println!("{} x {}:", group.len(), size(group[0].size));
+        for entry in group {
+            let shared = entry.blocks == group[0].blocks;
+            if !shared {
+                separate += 1;
+            }
+            println!("   {}{}", entry.path, if shared { "" } else { "  (own blocks)" });
+        }
+    }
+    let copies: usize = groups.iter().map(|g| g.len() - 1).sum();
+    println!("\n{} duplicate(s) in {} group(s).", copies, groups.len());
+    if separate > 0 {
+        let mut linked = index_mgr.data.clone();
+        link(&mut linked);
+        let (blocks, bytes) = freed(&index_mgr.data, &linked, &vault_path)?;
+        println!("{} keep blocks of their own; `lethe dupes --link` would free {} block(s), about {}.", separate, blocks, size(bytes));
+    } else {
+        println!("They already share their blocks, so they take no extra space.");
+    }
+    Ok(())
+}
+
+fn do_dupes_link(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe dupes")?;
+    let mut index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+
+    let before = index_mgr.data.clone();
+    let changed = link(&mut index_mgr.data);
+    if changed.is_empty() {
+        println!("No duplicates with blocks of their own.");
+        return Ok(());
+    }
+    let (blocks, bytes) = freed(&before, &index_mgr.data, &vault_path)?;
+    index_mgr.save(&key)?;
+    for path in &changed {
+        println!("   linked {}", path);
+    }
+    println!("{} file(s) now share blocks. Run `lethe clean` to free {} block(s), about {}.", changed.len(), blocks, size(bytes));
+    outbox::after_write(&vault_path, &key)
+}
@@ -0,0 +1,99 @@
+use anyhow::Result;
+use std::time::{Duration, UNIX_EPOCH};
+
+use lethe_core::events::{Change, Event, Subscription};
+
+use super::ops::unlock_vault;
+use crate::daemon::sentinel;
+
+/// How often `--follow` looks at the log
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+pub(crate) fn describe(change: &Change) -> (&'static str, String) {
+    match change {
+        Change::FileAdded { path, is_dir: true, .. } => ("added", format!("{}/", path)),
+        Change::FileAdded { path, size, .. } => ("added", format!("{} ({})", path, humansize::format_size(*size, humansize::BINARY))),
+        Change::FileModified { path, size } => ("modified", format!("{} ({})", path, humansize::format_size(*size, humansize::BINARY))),
+        Change::FileDeleted { path } => ("deleted", path.clone()),
+        Change::FileRenamed { from, to } => ("renamed", format!("{} -> {}", from, to)),
+        Change::SnapshotCreated { name } => ("snapshot", format!("{} created", name)),
+        Change::SnapshotDeleted { name } => ("snapshot", format!("{} deleted", name)),
+        Change::GcRun { blocks, bytes } => ("clean", format!("{} block(s) removed ({})", blocks, humansize::format_size(*bytes, humansize::BINARY))),
+    }
+}
+
+pub(crate) fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// One line of JSON: the event's fields, then the change's
+fn json(event: &Event) -> String {
+    let (kind, fields) = match &event.change {
+        Change::FileAdded { path, size, is_dir } => {
+            ("file_added", format!("\"path\":{},\"size\":{},\"is_dir\":{}", quote(path), size, is_dir))
+        }
+        Change::FileModified { path, size } => ("file_modified", format!("\"path\":{},\"size\":{}", quote(path), size)),
+        Change::FileDeleted { path } => ("file_deleted", format!("\"path\":{}", quote(path))),
+        Change::FileRenamed { from, to } => ("file_renamed", format!("\"from\":{},\"to\":{}", quote(from), quote(to))),
+        Change::SnapshotCreated { name } => ("snapshot_created", format!("\"name\":{}", quote(name))),
+        Change::SnapshotDeleted { name } => ("snapshot_deleted", format!("\"name\":{}", quote(name))),
+        Change::GcRun { blocks, bytes } => ("gc_run", format!("\"blocks\":{},\"bytes\":{}", blocks, bytes)),
+    };
+    format!(
+        "{{\"type\":\"{}\",\"time\":{},\"revision\":{},\"device\":{},{}}}",
+        kind, event.time, event.revision, quote(&event.device), fields
+    )
+}
+
+fn print(events: &[Event], as_json: bool) {
+    for event in events {
+        if as_json {
+            println!("{}", json(event));
+            continue;
+        }
+        let time = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(event.time));
+        let (kind, what) = describe(&event.change);
+        println!("{}  {:<16} {:<9} {}", time, event.device, kind, what);
+    }
+}
+
+pub async fn do_events(vault: String, follow: bool, new: bool, as_json: bool) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let mut subscription = if follow && new {
+        Subscription::from_now(&vault_path, &key)
+    } else {
+        Subscription::from_start(&vault_path, &key)
+    };
+
+    let logged = subscription.poll()?;
+    if logged.is_empty() && !follow && !as_json {
+        println!("No events logged yet.");
+    }
+    print(&logged, as_json);
+    if !follow {
+        return Ok(());
+    }
+
+    let shutdown = sentinel::shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => return Ok(()),
+            _ = tokio::time::sleep(POLL_INTERVAL) => print(&subscription.poll()?, as_json),
+        }
+    }
+}
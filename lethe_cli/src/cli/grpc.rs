@@ -0,0 +1,147 @@
+//! `lethe grpc`: serves the unlocked vault over gRPC to local programs
+//! (the `grpc` feature; see `crate::grpc`).
+//!
+//! By default it listens on a unix socket in the runtime folder that only
+//! its user can open. With `--listen` it takes a loopback port instead,
+//! and each call must carry the token written next to it, as
+//! `authorization: Bearer <token>` metadata.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+#[cfg(feature = "grpc")]
+use anyhow::Context;
+
+#[cfg(feature = "grpc")]
+use lethe_core::nonblocking::Vault;
+#[cfg(feature = "grpc")]
+use lethe_core::vault_lock::VaultLock;
+
+#[cfg(feature = "grpc")]
+use super::ops::{resolve_vault_path, unlock_vault};
+#[cfg(feature = "grpc")]
+use crate::daemon::{registry, sentinel};
+#[cfg(feature = "grpc")]
+use crate::grpc::Service;
+
+/// Serves the vault at `vault` until Ctrl+C
+#[cfg(feature = "grpc")]
+pub async fn do_grpc(vault: Option<String>, socket: Option<PathBuf>, listen: Option<String>) -> Result<()> {
+    let vault_path = resolve_vault_path(vault.as_deref())?;
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault_path.to_string_lossy()))?;
+    // No one else writes the index while it is served
+    let _held = VaultLock::acquire(&vault_path, "lethe grpc")?;
+    let vault = Vault::open(vault_path.clone(), key).await?;
+    let id = registry::instance_id(&vault_path);
+
+    match listen {
+        Some(listen) => serve_tcp(vault, &listen, registry::runtime_dir()?.join(format!("{}.grpc.token", id))).await?,
+        #[cfg(unix)]
+        None => serve_unix(vault, socket.unwrap_or(registry::runtime_dir()?.join(format!("{}.grpc.sock", id)))).await?,
+        #[cfg(not(unix))]
+        None => {
+            let _ = socket;
+            anyhow::bail!("Unix sockets aren't available here; pass --listen 127.0.0.1:<port>");
+        }
+    }
+    println!("\nServer stopped.");
+    Ok(())
+}
+
+#[cfg(all(feature = "grpc", unix))]
+async fn serve_unix(vault: Vault, path: PathBuf) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::net::{UnixListener, UnixStream};
+    use tokio_stream::wrappers::UnixListenerStream;
+    use tokio_stream::StreamExt;
+
+    if path.exists() {
+        if UnixStream::connect(&path).await.is_ok() {
+            anyhow::bail!("Something is already listening on {:?}", path);
+        }
+        // Left behind by a server that crashed
+        std::fs::remove_file(&path).with_context(|| format!("Failed to remove the stale socket {:?}", path))?;
+    }
+    let listener = UnixListener::bind(&path).with_context(|| format!("Failed to bind {:?}", path))?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    let _cleanup = RemoveOnDrop(path.clone());
+    let incoming = UnixListenerStream::new(listener).filter(|conn| match conn {
+        Ok(stream) if !crate::daemon::agent::same_user(stream) => {
+            log::warn!("Refused a gRPC connection from another user");
+            false
+        }
+        _ => true,
+    });
+
+    println!("gRPC service running on unix:{}", path.display());
+    println!("   (Press Ctrl+C to Lock & Quit)");
+    tonic::transport::Server::builder()
+        .add_service(Service::new(vault))
+        .serve_with_incoming_shutdown(incoming, sentinel::shutdown_signal())
+        .await
+        .context("The gRPC server failed")
+}
+
+#[cfg(feature = "grpc")]
+async fn serve_tcp(vault: Vault, listen: &str, token_file: PathBuf) -> Result<()> {
+    let addr: std::net::SocketAddr = listen.parse()
+        .with_context(|| format!("Invalid listen address: {}", listen))?;
+    if !addr.ip().is_loopback() {
+        anyhow::bail!("{} is reachable from the network; gRPC only listens on loopback (127.0.0.1 or ::1).", addr);
+    }
+
+    let mut bytes = [0u8; 32];
+    rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut bytes);
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    write_private(&token_file, &token)?;
+    let _cleanup = RemoveOnDrop(token_file.clone());
+    let expected = format!("Bearer {}", token);
+    // Interceptors return tonic's `Status` as it is
+    #[allow(clippy::result_large_err)]
+    let check = move |request: tonic::Request<()>| {
+        match request.metadata().get("authorization").and_then(|v| v.to_str().ok()) {
+            Some(given) if given == expected => Ok(request),
+            _ => Err(tonic::Status::unauthenticated("Send the token from the server's token file as `authorization: Bearer <token>`")),
+        }
+    };
+
+    println!("gRPC service running at http://{}", addr);
+    println!("   Token in {}", token_file.display());
+    println!("   (Press Ctrl+C to Lock & Quit)");
+    tonic::transport::Server::builder()
+        .add_service(tonic::service::interceptor::InterceptedService::new(Service::new(vault), check))
+        .serve_with_shutdown(addr, sentinel::shutdown_signal())
+        .await
+        .with_context(|| format!("Failed to serve on {}", addr))
+}
+
+/// Writes `contents` to a file only this user can read
+#[cfg(feature = "grpc")]
+fn write_private(path: &std::path::Path, contents: &str) -> Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).with_context(|| format!("Failed to write {:?}", path))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(feature = "grpc")]
+struct RemoveOnDrop(PathBuf);
+
+#[cfg(feature = "grpc")]
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(not(feature = "grpc"))]
+pub async fn do_grpc(vault: Option<String>, socket: Option<PathBuf>, listen: Option<String>) -> Result<()> {
+    let _ = (vault, socket, listen);
+    anyhow::bail!("This build has no gRPC service. Rebuild with `cargo build --features grpc`.")
+}
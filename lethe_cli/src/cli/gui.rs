@@ -0,0 +1,438 @@
+//! `lethe gui`: a small window to unlock, mount and lock a vault, for
+//! those who would rather not use a terminal (the `gui` feature).
+//!
+//! The window drives a Sentinel over its control channel, as `lethe daemon
+//! unlock` and `lock` do, and starts one (`lethe daemon run --service`)
+//! if the vault has none; the Sentinel does the mounting and stays when
+//! the window closes. Once the Sentinel has unlocked, the window derives
+//! the key as well, to read the index without writing to it: what the
+//! vault holds, its health, and the files changed last, which open
+//! through the mount. It forgets the key when the vault locks.
+
+use anyhow::Result;
+
+#[cfg(feature = "gui")]
+pub fn do_gui(vault: Option<String>, mountpoint: Option<String>) -> Result<()> {
+    let vault_path = super::ops::resolve_vault_path(vault.as_deref())?;
+    if !lethe_core::header::exists(&vault_path) {
+        anyhow::bail!("Invalid vault path: {:?}. (Did you run 'lethe init'?)", vault_path);
+    }
+    // The window needs the main thread; the Sentinel calls run on the runtime
+    tokio::task::block_in_place(|| window::run(vault_path, mountpoint))
+}
+
+#[cfg(not(feature = "gui"))]
+pub fn do_gui(vault: Option<String>, mountpoint: Option<String>) -> Result<()> {
+    let _ = (vault, mountpoint);
+    anyhow::bail!("This build has no window. Rebuild with `cargo build --features gui`.")
+}
+
+#[cfg(feature = "gui")]
+mod window {
+    use anyhow::{Context, Result};
+    use eframe::egui;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tokio::runtime::Handle;
+    use zeroize::Zeroize;
+
+    use lethe_core::crypto::MasterKey;
+    use lethe_core::index::{is_snapshot_path, IndexManager};
+
+    use crate::cli::checkup::{self, Checkup};
+    use crate::cli::ops::unlock_key;
+    use crate::daemon::ipc::{self, DaemonStatus, Request, Response};
+    use crate::daemon::registry;
+
+    /// How often the Sentinel is asked how it is
+    const POLL: Duration = Duration::from_secs(1);
+
+    /// How long a Sentinel just started has to answer
+    const START_WAIT: Duration = Duration::from_secs(10);
+
+    /// Files listed as changed last
+    const RECENT: usize = 8;
+
+    fn size(bytes: u64) -> String {
+        humansize::format_size(bytes, humansize::BINARY)
+    }
+
+    fn now_secs() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+    }
+
+    fn ago(at: u64) -> String {
+        match now_secs().saturating_sub(at) / 60 {
+            0 => "just now".to_string(),
+            m => format!("{} ago", humantime::format_duration(Duration::from_secs(m * 60))),
+        }
+    }
+
+    /// What the vault holds, read from its index
+    struct Overview {
+        files: usize,
+        bytes: u64,
+        snapshots: usize,
+        revision: u64,
+        /// `checkup::verdict` and what it found; None for a hidden vault
+        health: Option<(&'static str, Vec<String>)>,
+        /// (path, size, modified), newest first
+        recent: Vec<(String, u64, u64)>,
+    }
+
+    #[derive(Default)]
+    struct Shared {
+        /// None until the Sentinel first answers
+        status: Option<DaemonStatus>,
+        key: Option<Arc<MasterKey>>,
+        overview: Option<Overview>,
+        /// A request is on its way
+        busy: bool,
+        message: String,
+    }
+
+    struct LetheApp {
+        vault: PathBuf,
+        id: String,
+        password: String,
+        runtime: Handle,
+        shared: Arc<Mutex<Shared>>,
+    }
+
+    pub fn run(vault: PathBuf, mountpoint: Option<String>) -> Result<()> {
+        let options = eframe::NativeOptions {
+            viewport: egui::ViewportBuilder::default().with_inner_size([460.0, 560.0]).with_title("Lethe"),
+            ..Default::default()
+        };
+        let runtime = Handle::current();
+        eframe::run_native(
+            "Lethe",
+            options,
+            Box::new(move |cc| {
+                let app = LetheApp {
+                    id: registry::instance_id(&vault),
+                    vault,
+                    password: String::new(),
+                    runtime,
+                    shared: Arc::new(Mutex::new(Shared::default())),
+                };
+                app.watch(cc.egui_ctx.clone(), mountpoint);
+                Ok(Box::new(app))
+            }),
+        )
+        .map_err(|e| anyhow::anyhow!("The window could not open: {}", e))
+    }
+
+    async fn status(id: &str) -> Result<DaemonStatus> {
+        match ipc::send(id, Request::Status).await? {
+            Response::Status(status) => Ok(status),
+            Response::Error(msg) => anyhow::bail!("{}", msg),
+            Response::Ok(_) | Response::Sync(_) => anyhow::bail!("Unexpected response from Sentinel"),
+        }
+    }
+
+    /// Starts a Sentinel for `vault` in the background and waits for it
+    async fn start_sentinel(vault: &Path, id: &str, mountpoint: Option<String>) -> Result<()> {
+        let mut command = Command::new(std::env::current_exe()?);
+        command.args(["daemon", "run", "--service", "--vault"]).arg(vault);
+        if let Some(target) = mountpoint {
+            command.args(["--mountpoint", &target]);
+        }
+        command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null())
+            .spawn()
+            .context("Failed to start the Sentinel")?;
+        let started = std::time::Instant::now();
+        while started.elapsed() < START_WAIT {
+            if status(id).await.is_ok() {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        anyhow::bail!("The Sentinel didn't answer; see `lethe daemon status` and the service log")
+    }
+
+    /// Reads the index without writing to it, not even to resync replicas
+    fn overview(vault: &Path, key: &MasterKey) -> Result<Overview> {
+        let index = IndexManager::load_read_only(vault.to_path_buf(), key)?;
+        let mut live: Vec<(String, u64, u64)> = index.data.files.iter()
+            .filter(|(path, e)| !e.is_dir && !e.is_expired() && !is_snapshot_path(path))
+            .map(|(path, e)| (path.clone(), e.size, e.modified))
+            .collect();
+        let (files, bytes) = (live.len(), live.iter().map(|(_, size, _)| size).sum());
+        live.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+        live.truncate(RECENT);
+        let health = Checkup::take(vault, key, &index)?.map(|checkup| {
+            let findings = checkup.findings(&vault.to_string_lossy());
+            (checkup::verdict(&findings), findings.into_iter().map(|f| f.problem).collect())
+        });
+        Ok(Overview {
+            files,
+            bytes,
+            snapshots: index.data.snapshots.len(),
+            revision: index.data.revision,
+            health,
+            recent: live,
+        })
+    }
+
+    /// Opens `path` with whatever the system opens such files with
+    fn open_with_system(path: &Path) -> Result<()> {
+        #[cfg(windows)]
+        let mut command = Command::new("explorer");
+        #[cfg(target_os = "macos")]
+        let mut command = Command::new("open");
+        #[cfg(not(any(windows, target_os = "macos")))]
+        let mut command = Command::new("xdg-open");
+        command.arg(path).spawn().with_context(|| format!("Failed to open {:?}", path))?;
+        Ok(())
+    }
+
+    impl LetheApp {
+        fn shared(&self) -> std::sync::MutexGuard<'_, Shared> {
+            self.shared.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+        }
+
+        /// Finds or starts the Sentinel, then follows it for as long as
+        /// the window is open
+        fn watch(&self, ctx: egui::Context, mountpoint: Option<String>) {
+            let (vault, id, shared) = (self.vault.clone(), self.id.clone(), self.shared.clone());
+            self.runtime.spawn(async move {
+                if status(&id).await.is_err() {
+                    shared.lock().unwrap().message = "Starting the Sentinel...".to_string();
+                    ctx.request_repaint();
+                    if let Err(e) = start_sentinel(&vault, &id, mountpoint).await {
+                        shared.lock().unwrap().message = format!("{:#}", e);
+                    }
+                }
+                loop {
+                    let answer = status(&id).await;
+                    {
+                        let mut shared = shared.lock().unwrap();
+                        match answer {
+                            Ok(status) => {
+                                if status.unlocked_since.is_none() {
+                                    shared.key = None;
+                                    shared.overview = None;
+                                }
+                                if shared.message.starts_with("Starting") {
+                                    shared.message.clear();
+                                }
+                                shared.status = Some(status);
+                            }
+                            Err(_) if shared.status.is_some() => {
+                                shared.status = None;
+                                shared.key = None;
+                                shared.overview = None;
+                                shared.message = "The Sentinel stopped. Reopen the window to start it again.".to_string();
+                            }
+                            Err(_) => {}
+                        }
+                    }
+                    ctx.request_repaint();
+                    tokio::time::sleep(POLL).await;
+                }
+            });
+        }
+
+        fn unlock(&mut self, ctx: &egui::Context) {
+            let password = std::mem::take(&mut self.password);
+            let (vault, id, shared, ctx) = (self.vault.clone(), self.id.clone(), self.shared.clone(), ctx.clone());
+            self.shared().busy = true;
+            self.runtime.spawn(async move {
+                let mut password = password;
+                let sent = ipc::send(&id, Request::Unlock { password: password.clone() }).await;
+                let outcome = match sent {
+                    Ok(Response::Ok(_)) => {
+                        let path = vault.clone();
+                        let theirs = password.clone();
+                        tokio::task::spawn_blocking(move || -> Result<(MasterKey, Overview)> {
+                            let key = unlock_key(&path, &theirs)?;
+                            let overview = overview(&path, &key)?;
+                            Ok((key, overview))
+                        })
+                        .await
+                        .map_err(anyhow::Error::from)
+                        .and_then(|read| read)
+                    }
+                    Ok(Response::Error(msg)) => Err(anyhow::anyhow!("{}", msg)),
+                    Ok(_) => Err(anyhow::anyhow!("Unexpected response from Sentinel")),
+                    Err(e) => Err(e),
+                };
+                password.zeroize();
+                let mut shared = shared.lock().unwrap();
+                shared.busy = false;
+                match outcome {
+                    Ok((key, overview)) => {
+                        shared.key = Some(Arc::new(key));
+                        shared.overview = Some(overview);
+                        shared.message.clear();
+                    }
+                    Err(e) => shared.message = format!("{:#}", e),
+                }
+                ctx.request_repaint();
+            });
+        }
+
+        fn lock(&self, ctx: &egui::Context) {
+            let (id, shared, ctx) = (self.id.clone(), self.shared.clone(), ctx.clone());
+            self.shared().busy = true;
+            self.runtime.spawn(async move {
+                let sent = ipc::send(&id, Request::Lock).await;
+                let mut shared = shared.lock().unwrap();
+                shared.busy = false;
+                match sent {
+                    Ok(Response::Ok(_)) => {
+                        shared.key = None;
+                        shared.overview = None;
+                        shared.message.clear();
+                    }
+                    Ok(Response::Error(msg)) => shared.message = msg,
+                    Ok(_) => shared.message = "Unexpected response from Sentinel".to_string(),
+                    Err(e) => shared.message = format!("{:#}", e),
+                }
+                ctx.request_repaint();
+            });
+        }
+
+        fn refresh(&self, ctx: &egui::Context) {
+            let Some(key) = self.shared().key.clone() else { return };
+            let (vault, shared, ctx) = (self.vault.clone(), self.shared.clone(), ctx.clone());
+            self.shared().busy = true;
+            self.runtime.spawn_blocking(move || {
+                let read = overview(&vault, &key);
+                let mut shared = shared.lock().unwrap();
+                shared.busy = false;
+                match read {
+                    Ok(overview) => shared.overview = Some(overview),
+                    Err(e) => shared.message = format!("{:#}", e),
+                }
+                ctx.request_repaint();
+            });
+        }
+
+        fn show_overview(&self, ui: &mut egui::Ui, overview: &Overview, mountpoint: Option<&str>) {
+            egui::Grid::new("overview").num_columns(2).show(ui, |ui| {
+                ui.label("Files");
+                ui.label(format!("{}, {}", overview.files, size(overview.bytes)));
+                ui.end_row();
+                ui.label("Snapshots");
+                ui.label(overview.snapshots.to_string());
+                ui.end_row();
+                ui.label("Revision");
+                ui.label(overview.revision.to_string());
+                ui.end_row();
+                if let Some((verdict, _)) = &overview.health {
+                    ui.label("Health");
+                    ui.label(*verdict);
+                    ui.end_row();
+                }
+            });
+            if let Some((_, problems)) = &overview.health {
+                for problem in problems {
+                    ui.small(format!("• {}", problem));
+                }
+            }
+            ui.separator();
+            ui.heading("Changed Last");
+            if overview.recent.is_empty() {
+                ui.label("The vault is empty.");
+            }
+            for (path, bytes, modified) in &overview.recent {
+                ui.horizontal(|ui| {
+                    let opened = mountpoint.is_some() && ui.button("Open").clicked();
+                    ui.label(path).on_hover_text(format!("{}, changed {}", size(*bytes), ago(*modified)));
+                    if let (true, Some(target)) = (opened, mountpoint) {
+                        let file = Path::new(target).join(path.trim_start_matches('/'));
+                        if let Err(e) = open_with_system(&file) {
+                            self.shared().message = format!("{:#}", e);
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    impl eframe::App for LetheApp {
+        fn ui(&mut self, ui: &mut egui::Ui, _frame: &mut eframe::Frame) {
+            let ctx = ui.ctx().clone();
+            egui::CentralPanel::default().show(ui, |ui| {
+                ui.heading("Lethe");
+                ui.label(self.vault.display().to_string());
+                ui.separator();
+
+                let (status, busy, message, overview) = {
+                    let mut shared = self.shared();
+                    (shared.status.clone(), shared.busy, shared.message.clone(), shared.overview.take())
+                };
+                match &status {
+                    None => {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.label("Waiting for the Sentinel...");
+                        });
+                    }
+                    Some(DaemonStatus { unlocked_since: None, .. }) => {
+                        ui.label("Locked");
+                        let field = ui.add(egui::TextEdit::singleline(&mut self.password).password(true).hint_text("Vault password"));
+                        let entered = field.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                        let clicked = ui.add_enabled(!busy, egui::Button::new("Unlock and Mount")).clicked();
+                        if (entered || clicked) && !busy && !self.password.is_empty() {
+                            self.unlock(&ctx);
+                        }
+                    }
+                    Some(status) => {
+                        let target = status.mountpoint.as_deref().unwrap_or("(not mounted)");
+                        ui.label(format!("Unlocked, mounted at {}", target));
+                        if let Some(since) = status.unlocked_since {
+                            ui.small(format!("Since {}; last used {}", ago(since), ago(status.last_activity)));
+                        }
+                        if status.dirty_buffers > 0 {
+                            ui.small(format!("{} open file(s) not saved yet", status.dirty_buffers));
+                        }
+                        if let Some(scrub) = status.scrub.as_ref().filter(|s| s.damaged > 0) {
+                            ui.colored_label(ui.visuals().warn_fg_color, format!("The scrub found {} damaged block(s); run `lethe repair`", scrub.damaged));
+                        }
+                        ui.horizontal(|ui| {
+                            if ui.add_enabled(!busy, egui::Button::new("Lock")).clicked() {
+                                self.lock(&ctx);
+                            }
+                            if overview.is_some() && ui.add_enabled(!busy, egui::Button::new("Refresh")).clicked() {
+                                self.refresh(&ctx);
+                            }
+                        });
+                        ui.separator();
+                        match &overview {
+                            Some(overview) => self.show_overview(ui, overview, status.mountpoint.as_deref()),
+                            None if busy => {
+                                ui.spinner();
+                            }
+                            // Unlocked elsewhere: the window has no key of its own
+                            None => {
+                                ui.small("Unlocked from elsewhere; lock and unlock here to see what the vault holds.");
+                            }
+                        }
+                    }
+                }
+                if busy {
+                    ui.spinner();
+                }
+                if !message.is_empty() {
+                    ui.separator();
+                    ui.colored_label(ui.visuals().warn_fg_color, &message);
+                }
+
+                let mut shared = self.shared();
+                if shared.overview.is_none() && shared.key.is_some() {
+                    shared.overview = overview;
+                }
+            });
+        }
+
+        fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+            self.password.zeroize();
+            self.shared().key = None;
+        }
+    }
+}
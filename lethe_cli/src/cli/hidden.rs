@@ -0,0 +1,79 @@
+use anyhow::Result;
+
+use lethe_core::hidden;
+use lethe_core::index::IndexManager;
+use lethe_core::storage::BlockManager;
+
+use super::ops::{derive_vault_key, unlock_outer};
+use super::strength;
+use crate::daemon::claim::claim;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Padding is sized like default chunks, so it would stand out in a vault
+/// that cuts files to another size
+fn custom_chunks(index_mgr: &IndexManager) -> Result<()> {
+    if index_mgr.data.chunk_size != 0 {
+        anyhow::bail!("The vault cuts files to a chunk size of its own, which padding would stand out from. `lethe chunk-size default` first.");
+    }
+    Ok(())
+}
+
+/// Adds `mb` MiB of padding to the vault, so that padding is nothing a
+/// vault without a hidden one lacks, and sets how many entries the index
+/// is kept as large as
+pub fn do_pad(vault: String, mb: u64, entries: Option<u32>) -> Result<()> {
+    if mb == 0 && entries.is_none() {
+        anyhow::bail!("Nothing to add: give --mb, --entries or both.");
+    }
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe pad")?;
+    let mut index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    let storage = BlockManager::new(&vault_path)?;
+
+    if mb > 0 {
+        custom_chunks(&index_mgr)?;
+        let ids = hidden::pad(&storage, mb * MIB)?;
+        let count = ids.len();
+        index_mgr.add_padding(ids);
+        println!("Added {} padding block(s). The vault now keeps {}.", count, index_mgr.data.padding.len());
+    }
+    if let Some(entries) = entries {
+        index_mgr.set_entry_floor(entries);
+        match entries {
+            0 => println!("The index is no longer kept larger than its entries need."),
+            n => println!("The index is now kept as large as {} entries would make it.", n),
+        }
+    }
+    index_mgr.save(&key)?;
+    Ok(())
+}
+
+/// Makes a hidden vault of `mb` MiB inside the vault, opened by its own password
+pub fn do_hidden_create(vault: String, mb: u64) -> Result<()> {
+    let (vault_path, outer_key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe hidden create")?;
+    let mut outer = IndexManager::load(vault_path.clone(), &outer_key)?;
+    custom_chunks(&outer)?;
+    let storage = BlockManager::new(&vault_path)?;
+
+    let password = rpassword::prompt_password("Set Hidden Vault Password: ")?;
+    let confirm = rpassword::prompt_password("Confirm Password: ")?;
+    if password != confirm {
+        anyhow::bail!("Passwords do not match.");
+    }
+    if password.is_empty() {
+        anyhow::bail!("Password cannot be empty.");
+    }
+    strength::check(&password)?;
+    let key = tokio::task::block_in_place(|| derive_vault_key(&vault_path, &password))?;
+    if key.as_bytes() == outer_key.as_bytes() {
+        anyhow::bail!("The hidden vault needs a password of its own.");
+    }
+
+    println!("Writing {} MiB of padding...", mb);
+    hidden::create(&mut outer, &storage, &outer_key, &key, mb * MIB)?;
+    println!("Hidden vault created. Give its password wherever Lethe asks for one to open it instead.");
+    println!("   Nothing written to the outer vault from now on can overwrite it. It isn't synced or backed up.");
+    Ok(())
+}
@@ -0,0 +1,235 @@
+//! `lethe inspect`: what is on disk, decoded, for working out what went
+//! wrong with a vault. It reads each replica on its own rather than taking
+//! the newest that opens, and reports what doesn't open instead of failing.
+
+use anyhow::Result;
+use std::fs::{self, File};
+use std::io::Read;
+use std::time::{Duration, UNIX_EPOCH};
+
+use lethe_core::crypto::{CryptoEngine, MasterKey};
+use lethe_core::events::Subscription;
+use lethe_core::header::{self, VaultHeader};
+use lethe_core::hidden;
+use lethe_core::index::{is_within, FileEntry, IndexManager, VaultIndex};
+use lethe_core::keyring;
+use lethe_core::lockout;
+use lethe_core::plaintext::Zeroizing;
+use lethe_core::storage::BlockManager;
+
+use super::events::describe;
+use super::ops::unlock_vault;
+
+/// Bytes in front of the ciphertext of a block or replica
+const NONCE_LEN: usize = 24;
+/// Poly1305 tag at the end of the ciphertext
+const TAG_LEN: u64 = 16;
+
+fn size(bytes: u64) -> String {
+    humansize::format_size(bytes, humansize::BINARY)
+}
+
+fn time(secs: u64) -> String {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs)).to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn do_inspect(vault: String, path: Option<String>, decrypt: bool) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+
+    println!("Header ({})", header::HEADER_FILE);
+    match VaultHeader::load(&vault_path) {
+        Ok(header) => {
+            let params = &header.kdf.params;
+            let format = match header.legacy {
+                true => "none, from salt.loader".to_string(),
+                false => header.format.to_string(),
+            };
+            println!("   format   {}", format);
+            println!(
+                "   kdf      {} m={} KiB t={} p={}, salt {}",
+                header.kdf.algorithm, params.memory_kib, params.iterations, params.parallelism, header.salt()
+            );
+            println!("   cipher   {}", header.cipher);
+            println!("   features {}", header.features.iter().cloned().collect::<Vec<_>>().join(", "));
+            if let Some(limit) = header.wipe_after {
+                println!("   wipe     after {} failed unlocks in a row, {} so far", limit, lockout::failures(&vault_path));
+            }
+        }
+        Err(e) => println!("   unreadable: {:#}", e),
+    }
+
+    let storage = BlockManager::new(&vault_path)?;
+    if hidden::holds(&storage, &key) {
+        println!("\nHidden vault: its index is in padding blocks {}", hidden::index_ids(&storage, &key).join(", "));
+        let index = IndexManager::load(vault_path.clone(), &key)?.data;
+        print_clock(&index);
+        return print_index(&index, &storage, &key, path.as_deref(), decrypt);
+    }
+
+    // A broken keyring shouldn't hide the replicas the master key opens
+    let index_key = keyring::index_key(&vault_path, &key).unwrap_or_else(|e| {
+        println!("\nKeyring unreadable ({:#}); trying the master key alone", e);
+        None
+    });
+
+    println!("\nReplicas");
+    let mut newest: Option<VaultIndex> = None;
+    for (file, opened) in IndexManager::replicas(&vault_path, &key, index_key.as_ref()) {
+        let name = file.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let len = fs::metadata(&file).map(|m| m.len()).unwrap_or(0);
+        match opened {
+            Ok((index, opened_with)) => {
+                let files = index.files.values().filter(|e| !e.is_dir).count();
+                println!(
+                    "   {}  {:>10}  revision {}, {} file(s), {} snapshot(s), opened with the {}",
+                    name, size(len), index.revision, files, index.snapshots.len(), opened_with
+                );
+                if newest.as_ref().is_none_or(|n| index.revision > n.revision) {
+                    newest = Some(index);
+                }
+            }
+            Err(e) => println!("   {}  {:>10}  {:#}", name, size(len), e),
+        }
+    }
+    let Some(index) = newest else {
+        anyhow::bail!("No replica opens, so there is no block map to show");
+    };
+
+    print_clock(&index);
+
+    println!("\nHistory ({})", lethe_core::events::EVENTS_FILE);
+    match Subscription::from_start(&vault_path, &key).poll() {
+        Ok(events) if events.is_empty() => println!("   nothing logged"),
+        Ok(events) => {
+            for event in events {
+                let (kind, what) = describe(&event.change);
+                println!("   rev {:<6} {}  {:<16} {:<9} {}", event.revision, time(event.time), event.device, kind, what);
+            }
+        }
+        Err(e) => println!("   unreadable: {:#}", e),
+    }
+
+    print_index(&index, &storage, &key, path.as_deref(), decrypt)
+}
+
+fn print_clock(index: &VaultIndex) {
+    println!("\nClock (revision {}, index version {})", index.revision, index.version);
+    if index.clock.is_empty() {
+        println!("   empty: written before vector clocks");
+    }
+    for (device, counter) in &index.clock {
+        let host = index.devices.get(device).map(String::as_str).unwrap_or("?");
+        println!("   {}  {:<16} {} save(s)", device, host, counter);
+    }
+}
+
+/// The block counts, then every entry at or below `path` with its blocks
+fn print_index(index: &VaultIndex, storage: &BlockManager, key: &MasterKey, path: Option<&str>, decrypt: bool) -> Result<()> {
+    let on_disk = storage.list_blocks()?;
+    let referenced = index.referenced_blocks();
+    let missing = referenced.iter().filter(|id| !storage.has_block(id)).count();
+    let unreferenced = on_disk.iter().filter(|(id, _)| !referenced.contains(id.as_str())).count();
+    println!("\nBlocks");
+    println!(
+        "   {} on disk, {} referenced ({} padding), {} missing, {} unreferenced",
+        on_disk.len(), referenced.len(), index.padding.len(), missing, unreferenced
+    );
+
+    let mut entries: Vec<&FileEntry> = index.files.values()
+        .filter(|e| path.is_none_or(|p| is_within(&e.path, p)))
+        .collect();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    if entries.is_empty() {
+        if let Some(p) = path {
+            anyhow::bail!("No entry at or below {}", p);
+        }
+    }
+
+    println!("\nEntries");
+    for entry in entries {
+        print_entry(entry, storage, key, decrypt);
+    }
+    Ok(())
+}
+
+/// An entry and where each of its blocks is, from the block file's header
+fn print_entry(entry: &FileEntry, storage: &BlockManager, key: &MasterKey, decrypt: bool) {
+    let mut dot = match &entry.dot {
+        Some(dot) => format!("{}:{}", dot.device, dot.counter),
+        None => "none".to_string(),
+    };
+    if let Some(hlc) = &entry.hlc {
+        dot.push_str(&format!(" at {} #{}", time(hlc.wall / 1000), hlc.counter));
+    }
+    if entry.is_dir {
+        println!("   {}/  modified {}, dot {}", entry.path, time(entry.modified), dot);
+        return;
+    }
+    println!(
+        "   {}  {} in {} block(s), modified {}, dot {}",
+        entry.path, size(entry.size), entry.blocks.len(), time(entry.modified), dot
+    );
+    let known: u64 = entry.sizes.iter().sum();
+    if !entry.sizes.is_empty() && (entry.sizes.len() != entry.blocks.len() || known != entry.size) {
+        println!("      ! {} block size(s) adding up to {}, for {} block(s) of {}", entry.sizes.len(), known, entry.blocks.len(), entry.size);
+    }
+    if !entry.hashes.is_empty() && entry.hashes.len() != entry.blocks.len() {
+        println!("      ! {} hash(es) for {} block(s)", entry.hashes.len(), entry.blocks.len());
+    }
+
+    let mut offset = 0;
+    for (i, id) in entry.blocks.iter().enumerate() {
+        let plain = entry.sizes.get(i).copied();
+        let range = match plain {
+            Some(len) => format!("{}..{}", offset, offset + len),
+            None => "?".to_string(),
+        };
+        offset += plain.unwrap_or(0);
+        let hash = entry.hashes.get(i).map(|h| &h[..h.len().min(16)]).unwrap_or("-");
+
+        let Some(volume) = storage.locate(id) else {
+            println!("      #{:<4} {}  {:<22} hash {}  MISSING", i, id, range, hash);
+            continue;
+        };
+        let file = match storage.block_path(id) {
+            Ok(file) => file,
+            Err(e) => {
+                println!("      #{:<4} {}  {}", i, id, e);
+                continue;
+            }
+        };
+        let len = fs::metadata(&file).map(|m| m.len()).unwrap_or(0);
+        let mut nonce = [0u8; NONCE_LEN];
+        let header = match File::open(&file).and_then(|mut f| f.read_exact(&mut nonce)) {
+            Ok(()) => format!(
+                "nonce {}, {} ciphertext",
+                hex(&nonce[..8]), size(len.saturating_sub(NONCE_LEN as u64 + TAG_LEN))
+            ),
+            Err(_) => "too short for a nonce".to_string(),
+        };
+        println!("      #{:<4} {}  {:<22} hash {}  on {}, {}, {}", i, id, range, hash, volume, size(len), header);
+
+        if decrypt {
+            match storage.read_block(id, key).map(Zeroizing::new) {
+                Ok(data) => {
+                    let mut problems = Vec::new();
+                    if plain.is_some_and(|p| p != data.len() as u64) {
+                        problems.push(format!("holds {} bytes, the index says {}", data.len(), plain.unwrap_or(0)));
+                    }
+                    if entry.hashes.get(i).is_some_and(|h| CryptoEngine::chunk_hash(&data, key) != *h) {
+                        problems.push("hash doesn't match".to_string());
+                    }
+                    match problems.is_empty() {
+                        true => println!("            decrypts, {} plaintext", size(data.len() as u64)),
+                        false => println!("            ! {}", problems.join("; ")),
+                    }
+                }
+                Err(e) => println!("            ! {:#}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! `lethe manifest` signs a list of the vault's blocks; `lethe verify`
+//! checks one against a folder of blocks without the password (see
+//! `lethe_core::manifest`).
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lethe_core::header;
+use lethe_core::index::IndexManager;
+use lethe_core::manifest::{self, Signer};
+use lethe_core::progress::{self, ProgressSink};
+use lethe_core::storage::BlockManager;
+
+use super::ops::unlock_outer;
+use super::progress::Bar;
+use crate::daemon::agent;
+use crate::daemon::claim::claim;
+use crate::sync::health;
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub fn do_manifest(vault: String, out: PathBuf) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe manifest")?;
+    let index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    if !index_mgr.data.padding.is_empty() {
+        anyhow::bail!("The vault has padding. A manifest would tell it apart from data, and a hidden vault in it changes blocks a manifest lists.");
+    }
+    let storage = BlockManager::new(&vault_path)?;
+
+    println!("Hashing blocks...");
+    let mut blocks = BTreeMap::new();
+    for id in index_mgr.referenced_blocks() {
+        let path = storage.block_path(id)?;
+        let bytes = fs::read(&path).with_context(|| format!("Block {} is missing (on a volume that isn't attached?)", id))?;
+        blocks.insert(id.to_string(), manifest::file_hash(&bytes));
+    }
+
+    let signer = Signer::new(&key);
+    let count = blocks.len();
+    let signed = signer.sign(now_secs(), blocks)?;
+    let public_key = signer.public_key();
+    fs::write(&out, signed).with_context(|| format!("Failed to write {:?}", out))?;
+
+    println!("Signed {} block(s) into {:?}.", count, out);
+    println!("Vault public key: {}", public_key);
+    println!("Give the key to whoever verifies, once, by a channel you trust: `lethe verify --key` checks manifests against it.");
+    Ok(())
+}
+
+pub fn do_verify(manifest_file: PathBuf, key: Option<String>, dirs: Vec<PathBuf>) -> Result<()> {
+    let bytes = fs::read(&manifest_file).with_context(|| format!("Failed to read {:?}", manifest_file))?;
+    let manifest = manifest::open(&bytes)?;
+    match key {
+        Some(key) if !key.trim().eq_ignore_ascii_case(&manifest.public_key) => {
+            anyhow::bail!("The manifest is signed by {}, not the key given. It doesn't come from that vault.", manifest.public_key)
+        }
+        Some(_) => println!("Signature good, by the key given."),
+        None => {
+            println!("Signature good, by key {}.", manifest.public_key);
+            println!("Without --key that only shows the manifest is intact; compare the key with the vault's.");
+        }
+    }
+
+    // Blocks next to the manifest unless told otherwise
+    let dirs = match dirs.is_empty() {
+        true => vec![manifest_file.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new(".")).to_path_buf()],
+        false => dirs,
+    };
+    let (mut missing, mut damaged) = (Vec::new(), Vec::new());
+    let bar = Bar::new("Verifying");
+    bar.start(0, manifest.blocks.len() as u64);
+    for (id, expected) in &manifest.blocks {
+        progress::check(&bar)?;
+        if !id.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+            anyhow::bail!("The manifest lists {:?}, which isn't a block id", id);
+        }
+        let name = format!("blk_{}.bin", id);
+        let Some(path) = dirs.iter().map(|d| d.join(&name)).find(|p| p.exists()) else {
+            missing.push(id);
+            bar.advance(0, 1);
+            continue;
+        };
+        let bytes = fs::read(&path).with_context(|| format!("Failed to read {:?}", path))?;
+        if manifest::file_hash(&bytes) != *expected {
+            damaged.push(id);
+        }
+        bar.advance(bytes.len() as u64, 1);
+    }
+    drop(bar);
+
+    let created = humantime::format_rfc3339_seconds(UNIX_EPOCH + std::time::Duration::from_secs(manifest.created));
+    println!("Manifest of {}: {} block(s).", created, manifest.blocks.len());
+    for id in &missing {
+        println!("   missing  {}", id);
+    }
+    for id in &damaged {
+        println!("   damaged  {}", id);
+    }
+    if !missing.is_empty() || !damaged.is_empty() {
+        anyhow::bail!("{} block(s) missing and {} damaged", missing.len(), damaged.len());
+    }
+    println!("Every block is present and intact.");
+
+    // A vault folder checked in full counts as verified (see `checkup`).
+    // That is noted sealed, so only with the key of a running agent.
+    let made = UNIX_EPOCH + std::time::Duration::from_secs(manifest.created);
+    for dir in dirs.iter().filter(|dir| header::exists(dir)) {
+        let storage = BlockManager::new(dir)?;
+        // Blocks it leaves out for being orphans were there when it was made
+        let newer = storage.list_blocks()?.into_iter()
+            .filter(|(id, _)| !manifest.blocks.contains_key(id))
+            .filter(|(id, _)| {
+                let modified = storage.block_path(id).and_then(|path| Ok(fs::metadata(path)?.modified()?));
+                modified.is_ok_and(|at| at >= made)
+            })
+            .count();
+        match newer {
+            0 => {
+                if let Some(key) = agent::cached_key(dir) {
+                    health::copy_verified(dir, &key);
+                }
+            }
+            n => println!("{} block(s) in {} are newer than the manifest; make a new one to check them too.", n, dir.display()),
+        }
+    }
+    Ok(())
+}
@@ -1,14 +1,52 @@
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
+use crate::sync::bwlimit::BwLimit;
+
 pub mod ops;
 pub mod mount;
+pub mod daemon;
+pub mod serve;
+pub mod users;
+pub mod acl;
+pub mod snapshot;
+pub mod sync;
+pub mod conflicts;
+pub mod share;
+pub mod events;
+pub mod audit;
+pub mod devices;
+pub mod volumes;
+pub mod syncd;
+pub mod annex;
+pub mod stats;
+pub mod inspect;
+pub mod open;
+pub mod hidden;
+pub mod manifest;
+pub mod agent;
+pub mod pack;
+pub mod restore;
+pub mod progress;
+pub mod dupes;
+pub mod checkup;
+pub mod strength;
+pub mod drill;
+pub mod search;
+pub mod publish;
+pub mod gui;
+pub mod grpc;
+pub mod relocate;
 
 #[derive(Parser)]
 #[command(name = "lethe", about = "A serverless, encrypted, distributed filesystem.", version = "1.0.0")]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+
+    /// Record timing spans to this file as folded stacks, for a flamegraph
+    #[arg(long, global = true, value_name = "PATH")]
+    pub trace_file: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
@@ -18,7 +56,15 @@ pub enum Commands {
     Init { 
         /// Path to create vault (Defaults to ~/.lethe_vault)
         #[arg(short, long)] 
-        path: Option<String> 
+        path: Option<String>,
+        /// Pack the vault into this file instead of a folder. An existing
+        /// file (e.g. a video) keeps its contents and carries the vault after them.
+        #[arg(long, conflicts_with = "path")]
+        container: Option<PathBuf>,
+        /// Destroy the vault after this many failed unlocks in a row. Asks
+        /// for a confirmation phrase; can't be undone once triggered.
+        #[arg(long)]
+        wipe_after: Option<u32>,
     },
 
     /// Mount the vault as a drive
@@ -30,24 +76,934 @@ pub enum Commands {
         
         /// Drive letter (Windows) or Mountpoint (Unix). Defaults to Z:
         #[arg(short, long)] 
-        mountpoint: Option<String> 
+        mountpoint: Option<String>,
+
+        /// Auto-lock after this many minutes without file activity
+        #[arg(long)]
+        idle_timeout: Option<u64>,
+
+        /// Stay mounted when the workstation locks or goes to sleep
+        #[arg(long, default_value_t = false)]
+        no_auto_lock: bool,
+
+        /// Also serve a browser file manager (Windows)
+        #[arg(long, default_value_t = false)]
+        web_ui: bool,
+
+        /// Save files left open for writing after this many idle seconds (0 = only on close)
+        #[arg(long, default_value_t = 5)]
+        flush_after: u64,
+
+        /// Save the index at most once per this many seconds, so a bulk copy doesn't rewrite it per file (0 = after every change)
+        #[arg(long, default_value_t = 2)]
+        save_every: u64,
+
+        /// Seconds the kernel may cache file attributes (FUSE)
+        #[arg(long, default_value_t = 1.0)]
+        attr_ttl: f64,
+
+        /// Seconds the kernel may cache name lookups (FUSE)
+        #[arg(long, default_value_t = 1.0)]
+        entry_ttl: f64,
+
+        /// Bypass the kernel page cache; always consistent, slower for small reads (FUSE)
+        #[arg(long, default_value_t = false)]
+        direct_io: bool,
+
+        /// Let the kernel batch writes before sending them; faster, but more is lost on a crash (FUSE)
+        #[arg(long, default_value_t = false, conflicts_with = "direct_io")]
+        writeback_cache: bool,
+
+        /// MiB of each open file kept in RAM; the rest goes, encrypted, to the vault folder (FUSE)
+        #[arg(long, default_value_t = 64)]
+        buffer_mb: usize,
+
+        /// Blocks to read ahead of each file read in order (0 = none)
+        #[arg(long, default_value_t = lethe_core::config::DEFAULT_READ_AHEAD)]
+        read_ahead: usize,
+
+        /// MiB of decrypted blocks kept for all open files (0 = no cache)
+        #[arg(long, default_value_t = lethe_core::config::DEFAULT_CACHE_MB)]
+        cache_mb: usize,
+
+        /// MiB more of the cache kept encrypted in the temp folder once RAM is full (0 = none)
+        #[arg(long, default_value_t = 0)]
+        cache_disk_mb: usize,
+
+        /// Show another vault's `lethe serve` at /remote/NAME (FUSE; repeatable)
+        #[arg(long, value_name = "NAME=http[s]://USER@HOST[:PORT]")]
+        attach: Vec<crate::cli::mount::Attached>,
+    },
+
+    /// Serve the vault over WebDAV without mounting it (starts locked)
+    Serve {
+        /// Path to vault (Defaults to ~/.lethe_vault)
+        #[arg(short, long)]
+        vault: Option<String>,
+
+        /// Address to listen on
+        #[arg(short, long, default_value = "127.0.0.1:4918")]
+        listen: String,
+
+        /// Also serve a browser file manager at /_lethe/ui
+        #[arg(long, default_value_t = false)]
+        web_ui: bool,
+
+        /// Make and keep encrypted thumbnails of images and PDFs, for the web UI
+        #[arg(long, default_value_t = false)]
+        previews: bool,
+
+        /// Require these accounts (see `lethe users add`)
+        #[arg(long)]
+        users: Option<PathBuf>,
+
+        /// Serve a read-only standby of the vault at this remote (as
+        /// `lethe pull` takes), pulling from it while unlocked
+        #[arg(long)]
+        replica_of: Option<String>,
+
+        /// Seconds between pulls of a replica
+        #[arg(long, default_value_t = 60, requires = "replica_of")]
+        pull_every: u64,
+    },
+
+    /// Serve the vault as an S3 bucket, to clients signing with its access key
+    S3Serve {
+        /// Path to vault (Defaults to ~/.lethe_vault)
+        #[arg(short, long)]
+        vault: Option<String>,
+
+        /// Address to listen on
+        #[arg(short, long, default_value = "127.0.0.1:4920")]
+        listen: String,
+
+        /// Bucket name clients should use
+        #[arg(long, default_value = "lethe")]
+        bucket: String,
+
+        /// Access key requests must be signed with; the secret is read from
+        /// LETHE_S3_SECRET, or asked for
+        #[arg(long)]
+        access_key: String,
+    },
+
+    /// Serve the vault over gRPC to local programs (needs the `grpc` feature)
+    Grpc {
+        /// Path to vault (Defaults to ~/.lethe_vault)
+        #[arg(short, long)]
+        vault: Option<String>,
+
+        /// Unix socket to listen on (Defaults to one in the runtime folder)
+        #[arg(long, conflicts_with = "listen")]
+        socket: Option<PathBuf>,
+
+        /// Loopback address to listen on instead, with a token (e.g. 127.0.0.1:4921)
+        #[arg(short, long)]
+        listen: Option<String>,
+    },
+
+    /// Manage accounts for `lethe serve`
+    Users {
+        #[command(subcommand)]
+        action: UsersAction,
+    },
+
+    /// Manage which vault users may read and write which folders
+    Acl {
+        #[command(subcommand)]
+        action: AclAction,
+    },
+
+    /// Add padding: random blocks the vault keeps, which a hidden vault can
+    /// live in, or room in the index for entries the vault doesn't have
+    Pad {
+        /// How much, in MiB
+        #[arg(long, default_value_t = 0)]
+        mb: u64,
+
+        /// Keep the index as large as this many entries would make it, so
+        /// its size doesn't follow files being added (0 turns this off)
+        #[arg(long)]
+        entries: Option<u32>,
+
+        #[arg(long)]
+        vault: String,
+    },
+
+    /// Make a hidden vault inside this one, opened by a password of its own
+    Hidden {
+        #[command(subcommand)]
+        action: HiddenAction,
+    },
+
+    /// Freeze, list or drop read-only snapshots (shown under /.snapshots)
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotAction,
+    },
+
+    /// Sync with another machine holding the same vault (password and key)
+    SyncPeer {
+        /// Peer to dial, as host or host:port
+        #[arg(required_unless_present = "listen")]
+        peer: Option<String>,
+
+        /// Wait for peers instead of dialing one
+        #[arg(long, conflicts_with = "peer", num_args = 0..=1, default_missing_value = "0.0.0.0:4919")]
+        listen: Option<String>,
+
+        #[arg(long)]
+        vault: String,
+
+        /// Cap block transfers: a rate like 2M, or a local-time timetable like "08:00,512K 19:00,off" (0 pauses)
+        #[arg(long)]
+        bwlimit: Option<BwLimit>,
+    },
+
+    /// Copy new blocks and the index to a backup copy (ssh://host/path, sftp:host:/path, s3:bucket/prefix, webdav:https://host/path or a folder)
+    Push {
+        remote: String,
+        #[arg(long)] vault: String,
+
+        /// Put the index on this remote as well, apart from the blocks
+        #[arg(long)]
+        index: Option<String>,
+
+        /// Overwrite a remote copy that is newer than this one
+        #[arg(long, default_value_t = false)]
+        force: bool,
+
+        /// Cap block transfers: a rate like 2M, or a local-time timetable like "08:00,512K 19:00,off" (0 pauses)
+        #[arg(long)]
+        bwlimit: Option<BwLimit>,
+    },
+
+    /// Update this vault from a copy made with `lethe push`
+    Pull {
+        remote: String,
+        #[arg(long)] vault: String,
+
+        /// Read the index from this remote first (a push made with --index)
+        #[arg(long)]
+        index: Option<String>,
+
+        /// Take the remote index even if it is older than the local one
+        #[arg(long, default_value_t = false)]
+        force: bool,
+
+        /// Cap block transfers: a rate like 2M, or a local-time timetable like "08:00,512K 19:00,off" (0 pauses)
+        #[arg(long)]
+        bwlimit: Option<BwLimit>,
+    },
+
+    /// Check that a remote made with `lethe push` still holds every block its index needs
+    Scrub {
+        remote: String,
+        #[arg(long)] vault: String,
+
+        /// Read the index from this remote (a push made with --index)
+        #[arg(long)]
+        index: Option<String>,
+
+        /// Download and decrypt every block too, rather than only listing them
+        #[arg(long, default_value_t = false)]
+        deep: bool,
+    },
+
+    /// Keep a backup remote that writes push to as they happen, queueing while it is unreachable
+    Remote {
+        #[command(subcommand)]
+        action: RemoteAction,
+    },
+
+    /// Stay running and keep local folders, peers and remotes in step with the vault
+    Syncd {
+        #[command(subcommand)]
+        action: SyncdAction,
+    },
+
+    /// Spread the vault's blocks over more disks than the vault folder's
+    Volumes {
+        #[command(subcommand)]
+        action: VolumesAction,
+    },
+
+    /// Carry vault updates between copies that never meet on a network
+    Bundle {
+        #[command(subcommand)]
+        action: BundleAction,
+    },
+
+    /// Write the whole vault (header, index and every block) to one file
+    Pack {
+        /// Pack file to write, e.g. vault.lpack
+        #[arg(short, long)]
+        out: PathBuf,
+
+        #[arg(long)]
+        vault: String,
+    },
+
+    /// Turn a file made with `lethe pack` back into a vault folder
+    Unpack {
+        pack: PathBuf,
+
+        /// New folder for the vault
+        #[arg(long)]
+        into: PathBuf,
+    },
+
+    /// Seal files or folders into a bundle someone can open with a passphrase
+    Share {
+        /// Vault paths to include (folders bring everything below them)
+        #[arg(required = true)]
+        paths: Vec<String>,
+
+        /// Bundle file to write
+        #[arg(short, long)]
+        out: PathBuf,
+
+        #[arg(long)]
+        vault: String,
+    },
+
+    /// List or extract a bundle made with `lethe share`
+    OpenShare {
+        bundle: PathBuf,
+
+        /// Folder to extract into
+        #[arg(short, long, default_value = ".")]
+        out: PathBuf,
+
+        /// Only list what the bundle holds
+        #[arg(long, default_value_t = false)]
+        list: bool,
+    },
+
+    /// Show what saves changed in the vault: files, snapshots, clean-ups
+    Events {
+        /// Keep waiting for new events after the logged ones
+        #[arg(short, long, default_value_t = false)]
+        follow: bool,
+
+        /// With --follow, skip the events logged so far
+        #[arg(long, default_value_t = false)]
+        new: bool,
+
+        /// One JSON object per line, for scripts
+        #[arg(long, default_value_t = false)]
+        json: bool,
+
+        #[arg(long)]
+        vault: String,
+    },
+
+    /// Keep git-annex content in a vault (run by git-annex as git-annex-remote-lethe)
+    AnnexRemote,
+
+    /// Sign a list of the vault's blocks and their hashes, which anyone
+    /// can check a copy against without the password (`lethe verify`)
+    Manifest {
+        /// Where to write the manifest
+        #[arg(long, default_value = "manifest.sig")]
+        out: PathBuf,
+
+        #[arg(long)]
+        vault: String,
+    },
+
+    /// Check a folder of blocks against a manifest; needs no password
+    Verify {
+        /// The manifest, from `lethe manifest`
+        #[arg(long)]
+        manifest: PathBuf,
+
+        /// The vault's public key, as `lethe manifest` printed it
+        #[arg(long)]
+        key: Option<String>,
+
+        /// Folders holding the blocks (default: the manifest's folder)
+        dirs: Vec<PathBuf>,
+    },
+
+    /// Show who unlocked, mounted, synced or deleted from this copy, and when
+    Audit {
+        #[arg(long)]
+        vault: String,
+    },
+
+    /// Show what the vault holds and how long since each replica was reached and scrubbed
+    Stats {
+        #[arg(long)]
+        vault: String,
+
+        /// Warn about replicas not reached or scrubbed for this many days
+        #[arg(long, default_value_t = crate::sync::health::STALE_DAYS)]
+        stale_after: u64,
+
+        /// Stop tracking a replica that is gone (as named in the list)
+        #[arg(long)]
+        forget: Option<String>,
+
+        /// Show how the vault grew and when it will fill its disk
+        #[arg(long, default_value_t = false, conflicts_with = "forget")]
+        history: bool,
+
+        /// With --history, the space it has to grow into, e.g. 10G for a
+        /// remote of that size (default: what it uses plus the disk's free space)
+        #[arg(long, value_parser = crate::cli::volumes::parse_size, requires = "history")]
+        capacity: Option<u64>,
+
+        /// Show how well each file type compresses
+        #[arg(long, default_value_t = false, conflicts_with_all = ["forget", "history"])]
+        compression: bool,
+
+        /// Show how often this copy was unlocked, put to, read from,
+        /// cleaned and mounted, and when
+        #[arg(long, default_value_t = false, conflicts_with_all = ["forget", "history", "compression"])]
+        activity: bool,
+    },
+
+    /// Build or refresh the encrypted index of words in text files, for `lethe grep`
+    IndexContent {
+        #[arg(long)]
+        vault: String,
+    },
+
+    /// Find the lines holding some words in the vault's text files
+    Grep {
+        /// Words to look for, together and ignoring case
+        query: String,
+
+        #[arg(long)]
+        vault: String,
+
+        /// Only list the files that match
+        #[arg(short = 'l', long, default_value_t = false)]
+        files_only: bool,
+    },
+
+    /// List identical files stored under different paths
+    Dupes {
+        #[arg(long)]
+        vault: String,
+
+        /// Make each copy share the first file's blocks; `lethe clean` then frees the rest
+        #[arg(long, default_value_t = false)]
+        link: bool,
+    },
+
+    /// Browse a vault read-only at a prompt (ls, stat, cat, get)
+    Open {
+        /// Write nothing at all to the vault folder, and check on exit that
+        /// it is unchanged. For a vault that is evidence or on failing media.
+        #[arg(long, default_value_t = false)]
+        forensic: bool,
+
+        #[arg(long)]
+        vault: String,
+    },
+
+    /// Dump the header, each index replica, the revision history and every
+    /// entry's block map, for working out what is wrong with a vault
+    Inspect {
+        /// Only the entries at or below this vault path
+        path: Option<String>,
+
+        /// Decrypt each listed block and check it against the index
+        #[arg(long, default_value_t = false)]
+        decrypt: bool,
+
+        #[arg(long)]
+        vault: String,
+    },
+
+    /// Review files that changed on two machines before a sync
+    Conflicts {
+        #[command(subcommand)]
+        action: ConflictsAction,
+    },
+
+    /// Enroll, list and revoke the devices allowed to sync this vault
+    Devices {
+        #[command(subcommand)]
+        action: DevicesAction,
+    },
+
+    /// Open a window to unlock, mount and lock the vault (needs the `gui` feature)
+    Gui {
+        /// Path to vault (Defaults to ~/.lethe_vault)
+        #[arg(short, long)]
+        vault: Option<String>,
+
+        /// Where the Sentinel mounts it, if the window has to start one
+        #[arg(short, long)]
+        mountpoint: Option<String>,
+    },
+
+    /// List active mounts
+    Mounts {
+        /// Also show each mount's block cache
+        #[arg(short, long, default_value_t = false)]
+        verbose: bool,
+    },
+
+    /// Flush and detach a mount (by mountpoint or vault path)
+    Unmount {
+        target: String,
     },
 
     Put { 
         #[arg(short, long)] file: PathBuf, 
         #[arg(short, long)] dest: String, 
-        #[arg(long)] vault: String 
+        #[arg(long)] vault: String,
+        /// Hide the file this long after now (e.g. 30d, 12h) and let `lethe clean` shred it
+        #[arg(long, value_parser = humantime::parse_duration)] expire: Option<std::time::Duration>,
+        /// Make the file write once: it can't be changed, moved or deleted until `lethe release`
+        #[arg(long, conflicts_with = "expire")] immutable: bool,
     },
     Ls { #[arg(long)] vault: String },
     Get { 
         #[arg(short, long)] src: String, 
         #[arg(short, long)] out: PathBuf, 
-        #[arg(long)] vault: String 
+        #[arg(long)] vault: String,
+        /// Blocks fetched and decrypted at once (default: one per CPU)
+        #[arg(long)] jobs: Option<usize>,
+    },
+    /// Restore every vault path listed in a file (one per line) in one go
+    Restore {
+        /// File listing vault paths; folders bring everything below them
+        #[arg(long)] manifest: PathBuf,
+        /// Folder to restore into, keeping vault paths
+        #[arg(short, long)] out: PathBuf,
+        #[arg(long)] vault: String,
+        /// Files restored at once (default: one per CPU)
+        #[arg(long)] jobs: Option<usize>,
+    },
+    /// Decrypt a subtree into a plain folder to publish, e.g. as a static
+    /// site; recorded in the audit log
+    Publish {
+        /// Vault folder (or file) to publish
+        #[arg(long)] prefix: String,
+        /// New or empty folder to publish into
+        #[arg(short, long)] out: PathBuf,
+        #[arg(long)] vault: String,
+        /// Don't add an index.html listing to folders without one
+        #[arg(long, default_value_t = false)] no_index: bool,
+    },
+    /// Restore a random sample of files to a temp folder, check them against the index and shred them
+    Drill {
+        /// A percentage of the files, like `1%`, or a number of files
+        #[arg(long, default_value = "1%")] sample: String,
+        #[arg(long)] vault: String,
+        /// Folder to restore into (default: the system temp folder)
+        #[arg(long)] tmp: Option<PathBuf>,
     },
     Repair { #[arg(long)] vault: String },
+    /// Let write-once files change again. Always asks for the password,
+    /// even with the key held by `lethe agent`
+    Release {
+        /// File, or folder whose write-once files to release
+        #[arg(long)] path: String,
+        #[arg(long)] vault: String,
+    },
     Panic,
+
+    /// Move a vault folder, taking along its device keys, device id and
+    /// installed Sentinel service, which name it by path
+    Relocate {
+        /// The vault folder
+        old: String,
+        /// Where it goes; must not exist yet
+        new: String,
+    },
+
+    /// Control the background Sentinel
+    #[command(alias = "d")]
+    Daemon {
+        #[command(subcommand)]
+        action: DaemonAction,
+    },
+
+    /// Keep vault keys after one unlock so later commands don't ask again
+    Agent {
+        #[command(subcommand)]
+        action: AgentAction,
+    },
     Clean {
         #[arg(long)] vault: String,
         #[arg(long, default_value_t = false)] dry_run: bool,
+        /// Keep orphans younger than this (e.g. 1h, 0s): a writer may not have saved the index that uses them yet
+        #[arg(long, default_value = "1h", value_parser = humantime::parse_duration)] grace: std::time::Duration,
+        /// First delete snapshots older than this (e.g. 90d), freeing the
+        /// blocks only they hold
+        #[arg(long, visible_alias = "purge-versions-older-than", value_parser = humantime::parse_duration)]
+        purge_snapshots_older_than: Option<std::time::Duration>,
+    },
+    /// Move a vault from before content ids onto them (blocks are renamed
+    /// after a keyed hash of what they hold), and onto the split index
+    Upgrade {
+        #[arg(long)] vault: String,
+    },
+    /// Show or set the average size new files are cut into, e.g. 4M for
+    /// large media, or `default`
+    ChunkSize {
+        size: Option<String>,
+        #[arg(long)] vault: String,
+    },
+    /// Show, or turn `on` or `off`, refusing new paths Windows can't open
+    /// (CON, trailing dots, `<>:"|?*`, over 260 characters)
+    WindowsNames {
+        #[arg(value_parser = ["on", "off"])] setting: Option<String>,
+        #[arg(long)] vault: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SnapshotAction {
+    /// Freeze the current file table under a name
+    Create {
+        name: String,
+        #[arg(long)] vault: String,
+    },
+    /// List snapshots
+    List { #[arg(long)] vault: String },
+    /// Drop a snapshot (its blocks go on the next `lethe clean`)
+    Delete {
+        name: String,
+        #[arg(long)] vault: String,
+    },
+    /// Show or set the retention policy: automatic snapshots, and how many
+    /// and how long to keep them. Durations take `off`.
+    Retention {
+        /// Take a snapshot this often, e.g. 1d (at least 1h)
+        #[arg(long, value_parser = crate::cli::snapshot::parse_period)]
+        every: Option<std::time::Duration>,
+        /// Automatic snapshots to keep, newest first (0 for any number)
+        #[arg(long)] keep: Option<u32>,
+        /// Delete automatic snapshots older than this, e.g. 90d
+        #[arg(long, value_parser = crate::cli::snapshot::parse_period)]
+        max_age: Option<std::time::Duration>,
+        #[arg(long)] vault: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum HiddenAction {
+    /// Carve a hidden vault out of new padding (asks for both passwords)
+    Create {
+        /// Its size, in MiB
+        #[arg(long)] mb: u64,
+        #[arg(long)] vault: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConflictsAction {
+    /// List conflict copies left by sync-peer or pull
+    List { #[arg(long)] vault: String },
+    /// Keep one version of a conflicted file and drop the other
+    Resolve {
+        /// The conflict copy, e.g. "/docs/report (conflict from laptop 2024-06-01).docx"
+        copy: String,
+        #[arg(long, value_enum)] keep: conflicts::Keep,
+        #[arg(long)] vault: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum RemoteAction {
+    /// Push to this remote after every write (ssh://host/path, sftp:host:/path, s3:bucket/prefix, webdav:https://host/path or a folder)
+    Set {
+        remote: String,
+        /// Put the index on this remote as well, apart from the blocks
+        #[arg(long)] index: Option<String>,
+        #[arg(long)] vault: String,
+    },
+    /// Show the remote and any push waiting for it
+    Show { #[arg(long)] vault: String },
+    /// Stop pushing, dropping a queued push
+    Clear { #[arg(long)] vault: String },
+    /// Send a queued push now
+    Flush { #[arg(long)] vault: String },
+}
+
+#[derive(Subcommand)]
+pub enum SyncdAction {
+    /// Keep everything in step until stopped (Ctrl+C or `lethe syncd stop`)
+    Run {
+        #[arg(long)] vault: String,
+        /// Seconds between looks at the folders
+        #[arg(long, default_value_t = 10)]
+        interval: u64,
+        /// Minutes between rounds with peers and remotes
+        #[arg(long, default_value_t = 5)]
+        every: u64,
+        /// Warn about replicas not reached or scrubbed for this many days
+        #[arg(long, default_value_t = crate::sync::health::STALE_DAYS)]
+        stale_after: u64,
+    },
+    /// Mirror a local folder both ways with a folder in the vault
+    Folder {
+        path: PathBuf,
+        /// Vault folder to mirror it with (default: /<folder name>)
+        #[arg(long)] into: Option<String>,
+        #[arg(long)] vault: String,
+    },
+    /// Sync with a peer running `lethe sync-peer --listen`
+    Peer {
+        addr: String,
+        #[arg(long)] vault: String,
+    },
+    /// Pull from and push to a remote (same forms as `lethe push`)
+    Remote {
+        remote: String,
+        #[arg(long)] vault: String,
+    },
+    /// Stop keeping a folder, peer or remote in step
+    Forget {
+        what: String,
+        #[arg(long)] vault: String,
+    },
+    /// Show what is kept in step
+    List { #[arg(long)] vault: String },
+    /// Ask a running `lethe syncd` how each folder, peer and remote is doing
+    Status {
+        /// Path to vault (Defaults to ~/.lethe_vault)
+        #[arg(short, long)]
+        vault: Option<String>,
+    },
+    /// Stop a running `lethe syncd`
+    Stop {
+        /// Path to vault (Defaults to ~/.lethe_vault)
+        #[arg(short, long)]
+        vault: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum VolumesAction {
+    /// Let new blocks go to a folder on another disk as well
+    Add {
+        path: PathBuf,
+        /// Name to refer to it by (default: the folder name)
+        #[arg(long)] name: Option<String>,
+        /// Most it may hold, e.g. 500G (default: no limit)
+        #[arg(long, value_parser = crate::cli::volumes::parse_size)] capacity: Option<u64>,
+        #[arg(long)] vault: String,
+    },
+    /// Show the volumes, what they hold and which are attached
+    List { #[arg(long)] vault: String },
+    /// Move a volume's blocks to the others and stop using it
+    Remove {
+        name: String,
+        #[arg(long)] vault: String,
+    },
+    /// How new blocks are spread: round-robin or capacity
+    Policy {
+        policy: lethe_core::volumes::Policy,
+        #[arg(long)] vault: String,
+    },
+    /// Change the most a volume (`main` for the vault folder) may hold: a size like 500G, or off
+    Capacity {
+        name: String,
+        capacity: String,
+        #[arg(long)] vault: String,
+    },    /// Keep each new block on this many volumes, so a failing disk loses nothing
+    Copies {
+        #[arg(value_parser = clap::value_parser!(u64).range(1..))]
+        n: u64,
+        #[arg(long)] vault: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum BundleAction {
+    /// Write this copy's changes to a bundle file
+    Create {
+        /// Only changes after an earlier bundle from here (its revision)
+        #[arg(long)] since: Option<u64>,
+        #[arg(short, long)] out: PathBuf,
+        #[arg(long)] vault: String,
+    },
+    /// Merge a bundle from another copy into this one
+    Apply {
+        bundle: PathBuf,
+        #[arg(long)] vault: String,
+    },
+    /// List bundles made here and the newest applied from each copy
+    List { #[arg(long)] vault: String },
+}
+
+#[derive(Subcommand)]
+pub enum DevicesAction {
+    /// Start requiring enrollment, with this copy as the first device
+    Enable {
+        /// Defaults to the host name
+        #[arg(long)] name: Option<String>,
+        #[arg(long)] vault: String,
+    },
+    /// List enrolled devices
+    List { #[arg(long)] vault: String },
+    /// Enroll a new device and print the code it joins with
+    Enroll {
+        name: String,
+        #[arg(long)] vault: String,
+    },
+    /// Join with a code from `lethe devices enroll`, on the new device
+    Join {
+        code: String,
+        #[arg(long)] vault: String,
+    },
+    /// Revoke a lost device (by name or slot id) and rotate the index key
+    Revoke {
+        device: String,
+        #[arg(long)] vault: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum UsersAction {
+    /// Add an account to a users file or to the vault (prompts for its password)
+    Add {
+        /// Users file, created if missing
+        #[arg(short, long, required_unless_present = "vault", conflicts_with = "vault")]
+        file: Option<PathBuf>,
+
+        /// Keep the account in the vault itself, with its own unlock slot
+        #[arg(long)]
+        vault: Option<String>,
+
+        /// Login name (letters, digits, '-' and '_')
+        name: String,
+
+        /// Folder the account may read (repeatable)
+        #[arg(long)]
+        read: Vec<String>,
+
+        /// Folder the account may read and write (repeatable)
+        #[arg(long)]
+        write: Vec<String>,
+    },
+    /// List the accounts in a users file or the vault
+    List {
+        #[arg(short, long, required_unless_present = "vault", conflicts_with = "vault")]
+        file: Option<PathBuf>,
+        #[arg(long)]
+        vault: Option<String>,
+    },
+    /// Remove an account from the vault
+    Remove {
+        name: String,
+        #[arg(long)] vault: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum AclAction {
+    /// Set who may read and write below a folder, replacing its rule
+    Set {
+        prefix: String,
+        /// User who may read (repeatable; "*" for everyone)
+        #[arg(long)] read: Vec<String>,
+        /// User who may read and write (repeatable; "*" for everyone)
+        #[arg(long)] write: Vec<String>,
+        #[arg(long)] vault: String,
+    },
+    /// Drop a folder's rule, so the enclosing folder's applies again
+    Clear {
+        prefix: String,
+        #[arg(long)] vault: String,
+    },
+    /// List the rules
+    List { #[arg(long)] vault: String },
+    /// Show what a user may do with a path
+    Check {
+        user: String,
+        path: String,
+        #[arg(long)] vault: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum AgentAction {
+    /// Hold keys until stopped (Ctrl+C or `lethe agent stop`)
+    Run {
+        /// How long each key is kept after its unlock
+        #[arg(long, default_value = "15m", value_parser = humantime::parse_duration)]
+        ttl: std::time::Duration,
+    },
+    /// Show which vaults' keys are held and for how long
+    List,
+    /// Drop the key for a vault, or every key
+    Forget {
+        /// Vault to forget (default: all of them)
+        #[arg(long)] vault: Option<String>,
+    },
+    /// Stop the agent, forgetting every key
+    Stop,
+}
+
+#[derive(Subcommand)]
+pub enum DaemonAction {
+    /// Run the Sentinel in the foreground, starting locked
+    Run {
+        /// Path to vault (Defaults to ~/.lethe_vault)
+        #[arg(short, long)]
+        vault: Option<String>,
+
+        /// Drive letter (Windows) or Mountpoint (Unix). Defaults to Z:
+        #[arg(short, long)]
+        mountpoint: Option<String>,
+
+        /// Auto-lock after this many minutes without file activity
+        #[arg(long)]
+        idle_timeout: Option<u64>,
+
+        /// Stay mounted when the workstation locks or goes to sleep
+        #[arg(long, default_value_t = false)]
+        no_auto_lock: bool,
+
+        /// Run headless and log to the service log file (used by `install`)
+        #[arg(long, default_value_t = false)]
+        service: bool,
+    },
+    /// Register the Sentinel to start automatically at login
+    Install {
+        /// Path to vault (Defaults to ~/.lethe_vault)
+        #[arg(short, long)]
+        vault: Option<String>,
+
+        /// Drive letter (Windows) or Mountpoint (Unix). Defaults to Z:
+        #[arg(short, long)]
+        mountpoint: Option<String>,
+
+        /// Auto-lock after this many minutes without file activity
+        #[arg(long)]
+        idle_timeout: Option<u64>,
+
+        /// Stay mounted when the workstation locks or goes to sleep
+        #[arg(long, default_value_t = false)]
+        no_auto_lock: bool,
+    },
+    /// Remove the automatic-start registration
+    Uninstall,
+    /// Show whether the vault is locked or mounted
+    Status {
+        /// Which Sentinel to ask (needed when several are running)
+        #[arg(short, long)]
+        vault: Option<String>,
+    },
+    /// Unmount the vault and wipe the key
+    Lock {
+        /// Which Sentinel to lock (needed when several are running)
+        #[arg(short, long)]
+        vault: Option<String>,
+    },
+    /// Prompt for the password and mount the vault
+    Unlock {
+        /// Which Sentinel to unlock (needed when several are running)
+        #[arg(short, long)]
+        vault: Option<String>,
     },
 }
\ No newline at end of file
@@ -1,95 +1,439 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use lethe_core::cache::BlockCache;
+use lethe_core::crypto::MasterKey;
 use lethe_core::index::IndexManager;
 use lethe_core::storage::BlockManager;
+use lethe_core::VaultConfig;
+use crate::cli::checkup;
+use crate::cli::daemon::run_sentinel;
 use crate::cli::ops::{resolve_vault_path, unlock_vault};
-use std::path::PathBuf;
+use crate::daemon::ipc::{self, Request, Response};
+use crate::daemon::agent::{self, AgentRequest, AgentResponse};
+use crate::daemon::{guard, registry, Activity, SentinelConfig};
+use crate::volume::volume_label;
+use crate::settings::Settings;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
 
 // --- Platform Specific Imports ---
 #[cfg(windows)]
-use crate::dav::{LetheWebDav, LetheState};
+use crate::dav::LetheState;
 #[cfg(windows)]
-use std::process::{Command, Stdio};
+use crate::server::{self, webui, ServerOptions};
 #[cfg(windows)]
-use log::error;
+use std::process::{Command, Stdio};
 
 #[cfg(unix)]
-use crate::fs_fuse::LetheFS;
+use crate::federation::{Federated, FEDERATION_DIR};
+#[cfg(unix)]
+use crate::fs_fuse::{LetheFS, SharedFS};
+#[cfg(unix)]
+use crate::locks::LockTable;
+#[cfg(unix)]
+use crate::vfs::Backing;
 #[cfg(unix)]
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::sync::{Mutex, Weak};
 
-pub async fn do_mount(vault: Option<String>, mountpoint: Option<String>) -> Result<()> {
-    let vault_path = resolve_vault_path(vault.as_deref())?;
+/// Open files are saved after this long without writes, by default
+pub const DEFAULT_FLUSH_AFTER: Duration = Duration::from_secs(5);
 
-    println!("Lethe Daemon Initialized.");
-    
-    // 1. Shared Unlock Logic (Same for both platforms)
-    // We assume this is a blocking operation prompting for password
-    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(vault_path.to_str().unwrap()))?;
-    
-    // Load Index & Storage
-    let index_mgr = IndexManager::load(vault_path.clone(), &key)?;
-    let block_mgr = BlockManager::new(&vault_path)?;
-    println!("Vault Unlocked.");
+/// Index changes are saved at most this often, by default; matches `--save-every`
+pub const DEFAULT_SAVE_EVERY: Duration = Duration::from_secs(2);
+
+/// RAM each open file may use by default; matches `--buffer-mb`
+pub const DEFAULT_BUFFER_LIMIT: usize = 64 * 1024 * 1024;
+
+/// How a vault is exposed once unlocked
+#[derive(Debug, Clone)]
+pub struct MountOptions {
+    /// Drive letter (Windows) or mountpoint (Unix); picked if omitted
+    pub mountpoint: Option<String>,
+    /// Serve the browser file manager next to WebDAV (Windows)
+    pub web_ui: bool,
+    /// Save files still open for writing once idle this long (None = only on close).
+    /// WebDAV saves each upload as it completes, so only FUSE needs this.
+    pub flush_after: Option<Duration>,
+    /// Saves of the index are coalesced to one per this long; zero saves
+    /// after every change
+    pub save_every: Duration,
+    /// Kernel caching (FUSE only)
+    pub cache: KernelCache,
+    /// Bytes of each open file held in RAM before the rest spills (FUSE only)
+    pub buffer_limit: usize,
+    /// Read-ahead and block cache
+    pub config: VaultConfig,
+    /// Other vaults' servers to show under `/remote` (FUSE only)
+    pub attach: Vec<Attached>,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            mountpoint: None,
+            web_ui: false,
+            flush_after: Some(DEFAULT_FLUSH_AFTER),
+            save_every: DEFAULT_SAVE_EVERY,
+            cache: KernelCache::default(),
+            buffer_limit: DEFAULT_BUFFER_LIMIT,
+            config: VaultConfig::default(),
+            attach: Vec::new(),
+        }
+    }
+}
+
+/// Another vault's `lethe serve`, shown at `/remote/<name>`. Parsed from
+/// `NAME=http[s]://USER@HOST[:PORT]`; the password is asked for at mount.
+#[derive(Clone)]
+pub struct Attached {
+    pub name: String,
+    /// Reached over `https://`, e.g. through a TLS proxy in front of it
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: String,
+}
+
+impl Attached {
+    fn scheme(&self) -> &'static str {
+        if self.tls { "https" } else { "http" }
+    }
+
+    /// Where its API paths are relative to, e.g. `https://host:443`
+    pub fn base_url(&self) -> String {
+        format!("{}://{}:{}", self.scheme(), self.host, self.port)
+    }
+}
+
+impl std::str::FromStr for Attached {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let usage = || format!("Expected NAME=http[s]://USER@HOST[:PORT], got {:?}", s);
+        let (name, url) = s.split_once('=').ok_or_else(usage)?;
+        let (tls, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+            (Some(rest), _) => (true, rest),
+            (None, Some(rest)) => (false, rest),
+            (None, None) => return Err("Only http:// and https:// servers can be attached".to_string()),
+        };
+        let (user, authority) = rest.trim_end_matches('/').split_once('@').ok_or_else(usage)?;
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| format!("Invalid port: {}", port))?),
+            None => (authority, if tls { 443 } else { 4918 }),
+        };
+        let valid = |p: &str| !p.is_empty() && !p.contains('/');
+        if !valid(name) || !valid(user) || !valid(host) {
+            return Err(usage());
+        }
+        Ok(Self { name: name.to_string(), tls, host: host.to_string(), port, user: user.to_string(), password: String::new() })
+    }
+}
+
+impl std::fmt::Display for Attached {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ({}://{}@{}:{})", self.name, self.scheme(), self.user, self.host, self.port)
+    }
+}
+
+// Keeps the password out of logs
+impl std::fmt::Debug for Attached {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Attached({})", self)
+    }
+}
+
+/// How much the kernel may cache a FUSE mount; longer TTLs are faster but
+/// can go stale. WebDAV mounts ignore this.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KernelCache {
+    /// How long file attributes (size, mtime) stay valid
+    pub attr_ttl: Duration,
+    /// How long name lookups stay valid
+    pub entry_ttl: Duration,
+    /// Bypass the page cache so every read and write reaches Lethe
+    pub direct_io: bool,
+    /// Let the kernel batch writes in the page cache before sending them
+    pub writeback: bool,
+}
+
+impl Default for KernelCache {
+    fn default() -> Self {
+        Self {
+            attr_ttl: Duration::from_secs(1),
+            entry_ttl: Duration::from_secs(1),
+            direct_io: false,
+            writeback: false,
+        }
+    }
+}
+
+/// A vault exposed to the OS. Call `detach` to flush and unmount it.
+pub struct MountHandle {
+    /// Drive letter (Windows) or mountpoint path (Unix)
+    pub target: String,
+    /// Loopback WebDAV port (Windows only)
+    pub port: Option<u16>,
+    /// We created the mountpoint directory and remove it on detach
+    pub owns_dir: bool,
+    /// Decrypted blocks shared by the mount's open files
+    pub cache: Option<Arc<BlockCache>>,
+    #[cfg(windows)]
+    server: tokio::task::JoinHandle<()>,
+    #[cfg(windows)]
+    state: LetheState,
+    #[cfg(unix)]
+    session: fuser::BackgroundSession,
+    #[cfg(unix)]
+    fs: Weak<Mutex<LetheFS>>,
+}
+
+/// A mount standing aside while another process writes the vault. Every
+/// filesystem call waits; resuming, or dropping it, reloads the index.
+pub struct Pause {
+    #[cfg(unix)]
+    resume: std::sync::mpsc::Sender<oneshot::Sender<Result<()>>>,
+    #[cfg(windows)]
+    index: Option<tokio::sync::OwnedRwLockWriteGuard<IndexManager>>,
+    #[cfg(windows)]
+    key: Arc<MasterKey>,
+}
+
+fn reload(index: &mut IndexManager, key: &MasterKey) -> Result<()> {
+    *index = IndexManager::load(index.root_path().to_path_buf(), key)?;
+    Ok(())
+}
+
+impl Pause {
+    /// Reloads the index the other process left and lets calls through again
+    pub async fn resume(self) -> Result<()> {
+        #[cfg(unix)]
+        {
+            let (reply, rx) = oneshot::channel();
+            self.resume.send(reply).ok().context("The mount is gone")?;
+            rx.await.context("The mount is gone")?
+        }
+
+        #[cfg(windows)]
+        {
+            let mut pause = self;
+            let mut index = pause.index.take().context("The mount is gone")?;
+            reload(&mut index, &pause.key)
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Pause {
+    fn drop(&mut self) {
+        if let Some(mut index) = self.index.take() {
+            if let Err(e) = reload(&mut index, &self.key) {
+                log::error!("Failed to reload the index after a handoff: {}", e);
+            }
+        }
+    }
+}
+
+impl MountHandle {
+    /// Resolves when a client of the mount asks for it to be locked.
+    /// Only the WebDAV server has such clients; FUSE mounts never fire.
+    pub async fn lock_requested(&self) {
+        #[cfg(windows)]
+        self.state.lock_requested().await;
+
+        #[cfg(unix)]
+        std::future::pending::<()>().await;
+    }
+
+    /// Saves what the mount holds and stops it touching the index until
+    /// the returned `Pause` resumes it
+    pub async fn pause(&self) -> Result<Pause> {
+        #[cfg(windows)]
+        {
+            self.state.flush_index().await?;
+            let index = self.state.index.clone().write_owned().await;
+            Ok(Pause { index: Some(index), key: self.state.key.clone() })
+        }
+
+        #[cfg(unix)]
+        {
+            let fs = self.fs.upgrade().context("The mount is gone")?;
+            let (paused, rx) = oneshot::channel();
+            let (resume, requests) = std::sync::mpsc::channel::<oneshot::Sender<Result<()>>>();
+
+            // FUSE calls run on their own threads; this one holds them off
+            std::thread::spawn(move || {
+                let mut guard = fs.lock().unwrap();
+                let fs = &mut *guard;
+                fs.flush_idle(Duration::ZERO);
+                fs.flush_index();
+                let _ = paused.send(());
+
+                let reply = requests.recv().ok();
+                let result = reload(&mut fs.index, &fs.backing.key);
+                match reply {
+                    Some(reply) => {
+                        let _ = reply.send(result);
+                    }
+                    None => {
+                        if let Err(e) = result {
+                            log::error!("Failed to reload the index after a handoff: {}", e);
+                        }
+                    }
+                }
+            });
+            rx.await.context("The mount is gone")?;
+            Ok(Pause { resume })
+        }
+    }
+
+    pub async fn detach(self) {
+        #[cfg(windows)]
+        {
+            if let Err(e) = self.state.flush_index().await {
+                log::error!("Failed to save the index before unmounting: {}", e);
+            }
+            let _ = Command::new("net").args(["use", &self.target, "/delete", "/y"])
+                .stdout(Stdio::null()).stderr(Stdio::null()).status();
+            self.server.abort();
+        }
+
+        #[cfg(unix)]
+        {
+            // Joining drops LetheFS, which zeroizes the key
+            self.session.join();
+            if self.owns_dir {
+                guard::remove_mountpoint(Path::new(&self.target));
+            }
+        }
+    }
+}
+
+/// The configured mountpoint (see `crate::settings`), or the first drive
+/// letter / mountpoint not already claimed by another Sentinel
+fn default_target(vault_path: &Path) -> Result<String> {
+    let claimed = registry::claimed_targets();
+    let configured = Settings::load()?.mountpoint;
+
+    #[cfg(windows)]
+    {
+        let _ = vault_path;
+        // From the configured letter down, skipping letters in use by a
+        // disk, a mapped share or another Sentinel
+        let first = configured.as_deref()
+            .and_then(|d| d.chars().next())
+            .map_or('Z', |l| l.to_ascii_uppercase());
+        ('D'..=first).rev()
+            .map(|l| format!("{}:", l))
+            .find(|d| !claimed.contains(d) && !Path::new(&format!("{}\\", d)).exists())
+            .ok_or_else(|| anyhow::anyhow!("No free drive letter from {}: down; pass --mountpoint", first))
+    }
+
+    #[cfg(unix)]
+    {
+        let home = dirs::home_dir().context("Could not determine home directory")?;
+        let primary = configured.map_or_else(|| home.join("LetheMount"), PathBuf::from);
+        if !claimed.contains(&primary.display().to_string()) {
+            return Ok(primary.display().to_string());
+        }
+        let name = vault_path.file_name().map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| registry::instance_id(vault_path));
+        let mut next = primary.into_os_string();
+        next.push(format!("-{}", name.trim_start_matches('.')));
+        Ok(PathBuf::from(next).display().to_string())
+    }
+}
+
+/// First loopback port not claimed by another Sentinel and free to bind
+#[cfg(windows)]
+fn pick_port() -> Result<u16> {
+    let claimed = registry::claimed_ports();
+    (4918..5018)
+        .find(|p| !claimed.contains(p) && std::net::TcpListener::bind(("127.0.0.1", *p)).is_ok())
+        .ok_or_else(|| anyhow::anyhow!("No free loopback port for the WebDAV server"))
+}
+
+/// Mounts an unlocked vault and returns immediately
+pub fn attach(
+    vault_path: &Path,
+    index_mgr: IndexManager,
+    key: MasterKey,
+    opts: &MountOptions,
+    activity: Activity,
+) -> Result<MountHandle> {
+    let block_mgr = BlockManager::new(vault_path)?;
+
+    let target = match &opts.mountpoint {
+        Some(target) => target.clone(),
+        None => default_target(vault_path)?,
+    };
+    let label = volume_label(vault_path);
+    if registry::claimed_targets().contains(&target) {
+        anyhow::bail!("{} is already used by another Lethe mount", target);
+    }
 
     // =========================================================
     //  WINDOWS EXECUTION PATH (WebDAV)
     // =========================================================
     #[cfg(target_os = "windows")]
     {
+        if opts.cache != KernelCache::default() {
+            println!("Note: FUSE cache options have no effect on the WebDAV drive.");
+        }
+        if !opts.attach.is_empty() {
+            anyhow::bail!("--attach needs a FUSE mount (Linux or macOS).");
+        }
+
         // 1. Prepare State
-        let state = LetheState::new(index_mgr, block_mgr, key);
-        let lethe_fs = LetheWebDav { state };
-        
-        let dav_server = dav_server::DavHandler::builder()
-            .filesystem(Box::new(lethe_fs))
-            .locksystem(dav_server::memls::MemLs::new()) 
-            .build_handler();
-
-        let port = 4918;
+        let state = LetheState::new(index_mgr, block_mgr, key, activity).tuned(&opts.config);
+        state.spawn_index_writer(opts.save_every);
+        let routes = server::routes(state.clone(), ServerOptions { web_ui: opts.web_ui });
+
+        let port = pick_port()?;
         let addr = ([127, 0, 0, 1], port);
-        
+
         // 2. Start Server
-        let server_handle = tokio::spawn(async move {
-            warp::serve(dav_server::warp::dav_handler(dav_server))
-                .run(addr)
-                .await;
+        let server = tokio::spawn(async move {
+            warp::serve(routes).run(addr).await;
         });
         println!("WebDAV Server running at http://127.0.0.1:{}", port);
+        if opts.web_ui {
+            println!("Web UI at http://127.0.0.1:{}{}", port, webui::ui_path());
+        }
 
         // 3. Mount Drive
-        let drive_letter = mountpoint.unwrap_or_else(|| "Z:".to_string());
-        
+        let drive_letter = target;
+
         // Cleanup old mounts silently
-        let _ = Command::new("net").args(&["use", &drive_letter, "/delete", "/y"])
+        let _ = Command::new("net").args(["use", &drive_letter, "/delete", "/y"])
             .stdout(Stdio::null()).stderr(Stdio::null()).status();
-        
+
         let status = Command::new("net")
-            .args(&["use", &drive_letter, &format!("http://127.0.0.1:{}", port)])
+            .args(["use", &drive_letter, &format!("http://127.0.0.1:{}", port)])
             .stdout(Stdio::null())
             .status()?;
 
-        if status.success() {
-            println!("Mounted to {}.", drive_letter);
-            // Rename Drive
-            let _ = Command::new("powershell")
-                .args(&["-Command", &format!("$sh=New-Object -ComObject Shell.Application;$sh.NameSpace('{}').Self.Name='Lethe Vault'", drive_letter)])
-                .stdout(Stdio::null()).stderr(Stdio::null()).status();
-            
-            // Open Explorer
-            let _ = Command::new("explorer").arg(&drive_letter).spawn();
-        } else {
-            error!("Mount failed.");
-            return Ok(());
-        }
-
-        println!("   (Press Ctrl+C to Lock & Quit)");
-        tokio::signal::ctrl_c().await?;
-        
-        println!("\nVault Locked.");
-        let _ = Command::new("net").args(&["use", &drive_letter, "/delete", "/y"])
+        if !status.success() {
+            server.abort();
+            anyhow::bail!("Mount failed.");
+        }
+
+        println!("Mounted to {}.", drive_letter);
+        // Rename Drive
+        let _ = Command::new("powershell")
+            .args(["-Command", &format!("$sh=New-Object -ComObject Shell.Application;$sh.NameSpace('{}').Self.Name='{}'", drive_letter, label.replace('\'', "''"))])
             .stdout(Stdio::null()).stderr(Stdio::null()).status();
-        
-        server_handle.abort();
+
+        // Open Explorer
+        let _ = Command::new("explorer").arg(&drive_letter).spawn();
+
+        let cache = state.cache.clone();
+        Ok(MountHandle { target: drive_letter, port: Some(port), owns_dir: false, cache, server, state })
     }
 
     // =========================================================
@@ -97,19 +441,30 @@ pub async fn do_mount(vault: Option<String>, mountpoint: Option<String>) -> Resu
     // =========================================================
     #[cfg(unix)]
     {
-        let mount_path = mountpoint.map(PathBuf::from).unwrap_or_else(|| {
-             // Default mountpoint logic for Linux
-             let home = dirs::home_dir().unwrap();
-             home.join("LetheMount")
-        });
+        if opts.web_ui {
+            println!("Note: --web-ui needs the WebDAV server; use 'lethe serve --web-ui' on this platform.");
+        }
+        let mount_path = PathBuf::from(target);
+
+        // A crashed mount that nobody recorded still blocks the directory
+        if guard::kernel_mounts().contains(&mount_path.display().to_string()) {
+            log::warn!("Releasing orphaned Lethe mount at {:?}", mount_path);
+            guard::force_unmount(&mount_path.display().to_string());
+        }
 
         // Ensure mount directory exists
-        if !mount_path.exists() {
+        let owns_dir = !mount_path.exists();
+        if owns_dir {
             std::fs::create_dir_all(&mount_path)?;
         }
 
-        println!("Mounting FUSE filesystem at {:?}", mount_path);
-        println!("   (Press Ctrl+C to unmount)");
+        println!("Mounting {} (FUSE) at {:?}", label, mount_path);
+
+        let mut remotes = Vec::new();
+        for remote in &opts.attach {
+            remotes.push(Federated::connect(remote.clone())?);
+            println!("Attached {} at {}/{}", remote, FEDERATION_DIR, remote.name);
+        }
 
         let mut inode_map = HashMap::new();
         inode_map.insert(1, "/".to_string());
@@ -117,47 +472,218 @@ pub async fn do_mount(vault: Option<String>, mountpoint: Option<String>) -> Resu
         // Initialize the LetheFS struct
         let fs = LetheFS {
             index: index_mgr,
-            storage: block_mgr,
-            key: key,
+            backing: Backing::new(block_mgr, key, opts.buffer_limit, &opts.config),
             inode_map,
             write_buffer: HashMap::new(),
+            dirty: HashSet::new(),
+            pending_mtime: HashMap::new(),
+            written_at: HashMap::new(),
+            activity,
+            cache: opts.cache,
+            remotes,
+            save_every: opts.save_every,
+            unsaved_since: None,
+            locks: LockTable::default(),
+            handles: HashMap::new(),
+            unlinked: HashMap::new(),
+            last_fh: 0,
         };
+        let cache = fs.backing.cache.clone();
+        let fs = SharedFS::new(fs);
+        let shared = Arc::downgrade(&fs.0);
+        fs.spawn_flusher(opts.flush_after, opts.save_every);
 
         // Standard FUSE mount options
-        let options = vec![
+        let mut options = vec![
             fuser::MountOption::RW,
             fuser::MountOption::FSName("lethe".to_string()),
             fuser::MountOption::AutoUnmount,
             fuser::MountOption::AllowOther,
         ];
+        // macFUSE shows this name on the desktop and in Finder's sidebar
+        if cfg!(target_os = "macos") {
+            options.push(fuser::MountOption::CUSTOM(format!("volname={}", label)));
+        }
+
+        // The session runs on a background thread; dropping it unmounts
+        let session = match fuser::spawn_mount2(fs, &mount_path, &options) {
+            Ok(session) => session,
+            Err(e) => {
+                if owns_dir {
+                    guard::remove_mountpoint(&mount_path);
+                }
+                return Err(e.into());
+            }
+        };
+
+        Ok(MountHandle { target: mount_path.display().to_string(), port: None, owns_dir, cache, session, fs: shared })
+    }
+}
+
+pub async fn do_mount(vault: Option<String>, opts: MountOptions, sentinel_cfg: SentinelConfig) -> Result<()> {
+    let vault_path = resolve_vault_path(vault.as_deref())?;
+
+    println!("Lethe Daemon Initialized.");
+
+    // 1. Shared Unlock Logic (Same for both platforms)
+    // We assume this is a blocking operation prompting for password
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(vault_path.to_str().unwrap()))?;
+
+    // Files that would only fail to open are offered a way out first. The
+    // mount loads the index again once it holds the vault.
+    let mut index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    println!("Vault Unlocked.");
+    tokio::task::block_in_place(|| checkup::check_before_mount(&vault_path, &key, &mut index_mgr))?;
+    drop(index_mgr);
 
-        // This call blocks until the filesystem is unmounted (Ctrl+C)
-        fuser::mount2(fs, &mount_path, &options)?;
-        
-        println!("\nUnmounted successfully.");
+    let mut opts = opts;
+    for remote in &mut opts.attach {
+        let prompt = format!("Password for {}: ", remote);
+        remote.password = tokio::task::block_in_place(|| rpassword::prompt_password(prompt))?;
     }
 
+    // 2. Mount and stay until Ctrl+C, an IPC lock, or an auto-lock trigger
+    run_sentinel(vault_path, opts, sentinel_cfg, Some(key), false).await?;
+
+    println!("\nUnmounted successfully.");
+    Ok(())
+}
+
+pub async fn do_mounts(verbose: bool) -> Result<()> {
+    let mut rows = Vec::new();
+    for record in ipc::live_sentinels().await {
+        if let Ok(Response::Status(status)) = ipc::send(&record.id, Request::Status).await {
+            if let (Some(target), Some(since)) = (status.mountpoint.clone(), status.unlocked_since) {
+                rows.push((target, since, status));
+            }
+        }
+    }
+
+    if rows.is_empty() {
+        println!("No active Lethe mounts.");
+        return Ok(());
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let ago = |ts: u64| humantime::format_duration(Duration::from_secs(now.saturating_sub(ts))).to_string();
+
+    println!("\nActive Mounts:");
+    println!("{:<20} | {:<30} | {:<12} | {:<5} | LAST ACTIVITY", "MOUNTPOINT", "VAULT", "UPTIME", "DIRTY");
+    println!("{:-<90}", "-");
+    for (target, since, status) in rows {
+        println!(
+            "{:<20} | {:<30} | {:<12} | {:<5} | {} ago",
+            target, status.vault, ago(since), status.dirty_buffers, ago(status.last_activity)
+        );
+        if !verbose {
+            continue;
+        }
+        match status.cache {
+            Some(cache) => {
+                let size = |n: usize| humansize::format_size(n as u64, humansize::BINARY);
+                let looked = cache.hits + cache.overflow_hits + cache.misses;
+                let rate = match looked {
+                    0 => "-".to_string(),
+                    n => format!("{:.0}%", (cache.hits + cache.overflow_hits) as f64 * 100.0 / n as f64),
+                };
+                println!(
+                    "{:<20}   cache {} of {} in {} block(s); {} hit(s), {} miss(es) ({} hits), {} evicted",
+                    "", size(cache.bytes), size(cache.limit), cache.blocks, cache.hits, cache.misses, rate, cache.evicted
+                );
+                if cache.overflow_limit > 0 {
+                    println!(
+                        "{:<20}   on disk {} of {} in {} block(s); {} hit(s)",
+                        "", size(cache.overflow_bytes as usize), size(cache.overflow_limit as usize),
+                        cache.overflow_blocks, cache.overflow_hits
+                    );
+                }
+            }
+            None => println!("{:<20}   no block cache", ""),
+        }
+    }
+    println!();
     Ok(())
 }
 
-pub fn do_panic() -> Result<()> {
+pub async fn do_unmount(target: String) -> Result<()> {
+    for record in ipc::live_sentinels().await {
+        let status = match ipc::send(&record.id, Request::Status).await {
+            Ok(Response::Status(status)) => status,
+            _ => continue,
+        };
+        if !status.matches(&target) {
+            continue;
+        }
+        return match ipc::send(&record.id, Request::Unmount { target: target.clone() }).await? {
+            Response::Ok(msg) => {
+                println!("{}", msg);
+                Ok(())
+            }
+            Response::Error(msg) => anyhow::bail!("{}", msg),
+            Response::Status(_) | Response::Sync(_) => anyhow::bail!("Unexpected response from Sentinel"),
+        };
+    }
+    anyhow::bail!("No active Lethe mount found for {}", target)
+}
+
+pub async fn do_panic() -> Result<()> {
+    // Running Sentinels know exactly what they mounted
+    let sentinels = ipc::live_sentinels().await;
+    #[cfg(windows)]
+    let recorded: Vec<String> = sentinels.iter().filter_map(|r| r.target.clone()).collect();
+    if sentinels.is_empty() {
+        println!("No running Sentinel found.");
+    }
+    for record in &sentinels {
+        match ipc::send(&record.id, Request::Panic).await {
+            Ok(Response::Ok(msg)) => println!("Sentinel ({}): {}", record.vault, msg),
+            Ok(Response::Error(msg)) => println!("Sentinel ({}) refused panic: {}", record.vault, msg),
+            // An exiting Sentinel may close the channel before replying
+            _ => println!("Sentinel ({}): exited.", record.vault),
+        }
+    }
+
+    // Give them a moment to flush and unmount on their own
+    for _ in 0..25 {
+        if ipc::live_sentinels().await.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
     #[cfg(target_os = "windows")]
     {
-        for drive in ["Z:", "Y:", "X:"] {
-            let _ = std::process::Command::new("net")
-                .args(&["use", drive, "/delete", "/y"])
-                .stdout(std::process::Stdio::null())
-                .stderr(std::process::Stdio::null())
-                .status();
+        // Only the drives the Sentinels recorded, should one not have let
+        // go of its own
+        let leftovers: Vec<&String> = recorded.iter().filter(|drive| guard::force_unmount(drive)).collect();
+        for drive in &leftovers {
+            println!("Panic Cleanup: Unmounted {}", drive);
+        }
+        if leftovers.is_empty() {
+            println!("Panic Cleanup: No Lethe drives left.");
         }
-        println!("Panic Cleanup: Attempted to unmount Z:, Y:, X:");
     }
 
     #[cfg(unix)]
     {
-        println!("Panic command is a Windows-specific cleanup tool.");
-        println!("On Unix, FUSE handles auto-unmount.");
-        println!("If stuck, try: fusermount -u <path>");
+        // Whatever is still mounted belongs to a process that is gone or
+        // hung. The kernel's table has them all, recorded or not.
+        let leftovers = guard::kernel_mounts();
+        for target in &leftovers {
+            if guard::force_unmount(target) {
+                println!("Panic Cleanup: Unmounted {}", target);
+            } else {
+                println!("Panic Cleanup: Could not unmount {} (try: fusermount -uz {})", target, target);
+            }
+        }
+        if leftovers.is_empty() {
+            println!("Panic Cleanup: No Lethe mounts left.");
+        }
+    }
+
+    // Keys a `lethe agent` holds would unlock the vaults again
+    if let Ok(AgentResponse::Ok(msg)) = agent::send(AgentRequest::Forget { id: None }) {
+        println!("Agent: {}", msg);
     }
 
     Ok(())
@@ -0,0 +1,232 @@
+//! `lethe open`: a read-only prompt over a vault, for looking around and
+//! copying files out without mounting it.
+//!
+//! With `--forensic` nothing may be written to the vault folder at all:
+//! the unlock isn't audited, a `salt.loader` vault keeps its old header,
+//! damaged replicas stay as they are, and no lock, replica or temporary
+//! file is made. The folder's listing
+//! (names, sizes, mtimes) is taken before the password prompt and checked
+//! again on the way out.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use walkdir::WalkDir;
+
+use lethe_core::crypto::MasterKey;
+use lethe_core::header::{self, VaultHeader};
+use lethe_core::index::{FileEntry, IndexManager, VaultIndex};
+use lethe_core::progress::Silent;
+use lethe_core::reader::FileReader;
+use lethe_core::storage::BlockManager;
+
+use super::ops::{resolve_vault_path, unlock_vault};
+use crate::container;
+
+/// Size and mtime of everything in a folder
+type Listing = BTreeMap<PathBuf, (u64, Option<SystemTime>)>;
+
+fn listing(dir: &Path) -> Result<Listing> {
+    let mut found = Listing::new();
+    for entry in WalkDir::new(dir) {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        found.insert(entry.path().to_path_buf(), (meta.len(), meta.modified().ok()));
+    }
+    Ok(found)
+}
+
+pub fn do_open(vault: String, forensic: bool) -> Result<()> {
+    let (vault_path, key, before) = match forensic {
+        true => unlock_forensic(&vault)?,
+        false => {
+            let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+            (vault_path, key, None)
+        }
+    };
+    let index = match forensic {
+        true => IndexManager::load_read_only(vault_path.clone(), &key)?.data,
+        false => IndexManager::load(vault_path.clone(), &key)?.data,
+    };
+    let storage = BlockManager::new(&vault_path)?;
+
+    println!(
+        "Opened read-only: {} file(s), revision {}. Type `help` for commands.",
+        index.files.values().filter(|e| !e.is_dir).count(), index.revision
+    );
+    let shell = Shell { index, storage, key, vault: vault_path.clone(), forensic };
+    let ran = shell.run();
+
+    if let Some(before) = before {
+        let after = listing(&vault_path)?;
+        if after == before {
+            println!("Vault folder unchanged ({} entries checked).", before.len());
+        } else {
+            let changed: Vec<_> = before.keys().chain(after.keys())
+                .filter(|p| before.get(*p) != after.get(*p))
+                .collect::<std::collections::BTreeSet<_>>()
+                .into_iter().collect();
+            eprintln!("WARNING: the vault folder changed while it was open, by something other than Lethe:");
+            for path in changed {
+                eprintln!("   {}", path.display());
+            }
+        }
+    }
+    ran
+}
+
+/// Unlocks without writing: no audit record, no header upgrade
+fn unlock_forensic(vault: &str) -> Result<(PathBuf, MasterKey, Option<Listing>)> {
+    let vault_path = resolve_vault_path(Some(vault))?;
+    if container::pending(&vault_path) {
+        anyhow::bail!("--forensic takes a vault folder. A container is unpacked and packed back when it changes; copy it and open the copy instead.");
+    }
+    if !header::exists(&vault_path) {
+        anyhow::bail!("Invalid vault path: {:?}. (Did you run 'lethe init'?)", vault_path);
+    }
+    let header = VaultHeader::load(&vault_path)?;
+    // Counting a failure is a write
+    if header.wipe_after.is_some() {
+        anyhow::bail!("The vault is destroyed after too many failed unlocks, and --forensic can't count them. Copy the vault folder and open the copy instead.");
+    }
+    let before = listing(&vault_path)?;
+
+    let password = rpassword::prompt_password("Enter Vault Password: ")?;
+    let key = tokio::task::block_in_place(|| header.derive_key(&password))?;
+    Ok((vault_path, key, Some(before)))
+}
+
+struct Shell {
+    index: VaultIndex,
+    storage: BlockManager,
+    key: MasterKey,
+    vault: PathBuf,
+    forensic: bool,
+}
+
+const HELP: &str = "\
+   ls [DIR]          entries directly in DIR (default /)
+   stat PATH         size, modification time and block count
+   cat PATH          print a file
+   get PATH DEST     copy a file out of the vault
+   help              this list
+   exit              leave";
+
+/// `docs/a.pdf`, `/docs/a.pdf/` -> `/docs/a.pdf`
+fn vault_path(arg: &str) -> String {
+    format!("/{}", arg.trim().trim_matches('/'))
+}
+
+impl Shell {
+    fn run(&self) -> Result<()> {
+        let stdin = io::stdin();
+        loop {
+            print!("lethe> ");
+            io::stdout().flush()?;
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                println!();
+                return Ok(());
+            }
+            let line = line.trim();
+            let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let done = match command {
+                "" => Ok(()),
+                "ls" => self.ls(rest),
+                "stat" => self.stat(rest),
+                "cat" => self.cat(rest),
+                "get" => self.get(rest),
+                "help" => {
+                    println!("{}", HELP);
+                    Ok(())
+                }
+                "exit" | "quit" => return Ok(()),
+                other => Err(anyhow::anyhow!("Unknown command `{}`. Type `help` for the list.", other)),
+            };
+            if let Err(e) = done {
+                println!("{:#}", e);
+            }
+        }
+    }
+
+    fn file(&self, arg: &str) -> Result<&FileEntry> {
+        let path = vault_path(arg);
+        match self.index.files.get(&path) {
+            Some(entry) if entry.is_dir => anyhow::bail!("{} is a folder", path),
+            Some(entry) => Ok(entry),
+            None => anyhow::bail!("No such file: {}", path),
+        }
+    }
+
+    fn ls(&self, arg: &str) -> Result<()> {
+        let dir = vault_path(arg);
+        let prefix = if dir == "/" { dir.clone() } else { format!("{}/", dir) };
+        let mut children: Vec<&FileEntry> = self.index.files.values()
+            .filter(|e| e.path.strip_prefix(&prefix).is_some_and(|rest| !rest.is_empty() && !rest.contains('/')))
+            .collect();
+        if children.is_empty() && dir != "/" && !self.index.files.get(&dir).is_some_and(|e| e.is_dir) {
+            anyhow::bail!("No such folder: {}", dir);
+        }
+        children.sort_by(|a, b| a.path.cmp(&b.path));
+        for entry in children {
+            let name = &entry.path[prefix.len()..];
+            match entry.is_dir {
+                true => println!("{:<12} {}/", "-", name),
+                false => println!("{:<12} {}", humansize::format_size(entry.size, humansize::BINARY), name),
+            }
+        }
+        Ok(())
+    }
+
+    fn stat(&self, arg: &str) -> Result<()> {
+        let path = vault_path(arg);
+        let entry = self.index.files.get(&path).with_context(|| format!("No such file: {}", path))?;
+        let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(entry.modified);
+        println!("   path      {}{}", entry.path, if entry.is_dir { "/" } else { "" });
+        println!("   size      {} ({} bytes)", humansize::format_size(entry.size, humansize::BINARY), entry.size);
+        println!("   modified  {}", humantime::format_rfc3339_seconds(modified));
+        println!("   blocks    {}", entry.blocks.len());
+        if let Some(expires) = entry.expires {
+            let at = std::time::UNIX_EPOCH + std::time::Duration::from_secs(expires);
+            let state = if entry.is_expired() { ", expired" } else { "" };
+            println!("   expires   {}{}", humantime::format_rfc3339_seconds(at), state);
+        }
+        Ok(())
+    }
+
+    fn cat(&self, arg: &str) -> Result<()> {
+        let entry = self.file(arg)?;
+        let mut out = io::stdout().lock();
+        FileReader::new(&self.storage, &self.key, entry).copy_to(&mut out, &Silent)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    fn get(&self, arg: &str) -> Result<()> {
+        let (src, dest) = arg.trim().rsplit_once(' ').context("Usage: get PATH DEST")?;
+        let entry = self.file(src)?;
+        let dest = PathBuf::from(dest);
+
+        // Nothing goes into the vault folder, even by way of a copy
+        let parent = match dest.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let parent = std::fs::canonicalize(&parent).with_context(|| format!("No such folder: {:?}", parent))?;
+        if parent.starts_with(std::fs::canonicalize(&self.vault)?) {
+            anyhow::bail!("{:?} is inside the vault folder", dest);
+        }
+
+        // Evidence elsewhere on the machine isn't overwritten either
+        let mut file = match self.forensic {
+            true => File::create_new(&dest),
+            false => File::create(&dest),
+        }.with_context(|| format!("Failed to create {:?}", dest))?;
+        let written = FileReader::new(&self.storage, &self.key, entry).copy_to(&mut file, &Silent)?;
+        println!("Saved {} to {:?}", humansize::format_size(written, humansize::BINARY), dest);
+        Ok(())
+    }
+}
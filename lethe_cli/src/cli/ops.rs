@@ -1,21 +1,54 @@
 use anyhow::{Context, Result};
+use clap::CommandFactory;
 use log::error;
+use std::collections::{BTreeSet, HashSet};
 use std::fs;
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use walkdir::WalkDir;
 
-use lethe_core::crypto::{CryptoEngine, MasterKey};
-use lethe_core::index::IndexManager;
+use lethe_core::activity::{self, Op};
+use lethe_core::audit::{self, Operation};
+use lethe_core::chunker;
+use lethe_core::crypto::MasterKey;
+use lethe_core::events::Change;
+use lethe_core::header::{self, VaultHeader};
+use lethe_core::hidden;
+use lethe_core::index::{self, IndexManager};
+use lethe_core::lockout;
+use lethe_core::progress::{self, ProgressSink};
+use lethe_core::reader::FileReader;
+use lethe_core::retention;
 use lethe_core::storage::BlockManager;
 
-use std::collections::HashSet;
-use std::ffi::OsStr;
+use super::checkup::{self, Checkup};
+use super::progress::Bar;
+use super::strength;
+use crate::container;
+use crate::daemon::agent;
+use crate::daemon::claim::claim;
+use crate::daemon::registry;
+use crate::sync::{outbox, remote};
+
 
 // --- SHARED HELPERS ---
 
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
 pub fn resolve_vault_path(path: Option<&str>) -> Result<PathBuf> {
     match path {
+        Some(p) if remote::is_remote(p) => {
+            anyhow::bail!("{} is a remote. --vault takes the folder of a local copy; remotes go to push, pull and `lethe remote set`.", p)
+        }
+        // A container (see `crate::container`) is worked on in a folder of its own
+        Some(p) if Path::new(p).is_file() => {
+            let work = container::work_dir(Path::new(p))?;
+            container::register(Path::new(p), &work);
+            Ok(work)
+        }
         Some(p) => Ok(PathBuf::from(p)),
         None => dirs::home_dir()
             .map(|p| p.join(".lethe_vault"))
@@ -23,56 +56,185 @@ pub fn resolve_vault_path(path: Option<&str>) -> Result<PathBuf> {
     }
 }
 
+/// The vault folder at `vault`, for commands that don't need the password
+pub fn vault_dir(vault: &str) -> Result<PathBuf> {
+    let vault_path = resolve_vault_path(Some(vault))?;
+    if !header::exists(&vault_path) {
+        anyhow::bail!("Invalid vault path: {:?}. (Did you run 'lethe init'?)", vault_path);
+    }
+    Ok(vault_path)
+}
+
+/// `path` made absolute, with symlinks resolved as far as it exists
+fn real_path(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    absolute.ancestors()
+        .find_map(|existing| Some(fs::canonicalize(existing).ok()?.join(absolute.strip_prefix(existing).ok()?)))
+        .unwrap_or(absolute)
+}
+
+/// Mount targets of open vaults, with the vault folder of each
+fn mounts() -> Vec<(PathBuf, PathBuf)> {
+    registry::list().into_iter()
+        .filter_map(|record| Some((real_path(Path::new(&record.target?)), real_path(Path::new(&record.vault)))))
+        .collect()
+}
+
+/// Refuses a new vault at `vault_path` inside another vault's folder or
+/// mount: the outer one would hold it twice over, or not at all
+fn check_init_place(vault_path: &Path) -> Result<()> {
+    if vault_path.exists() {
+        match header::exists(vault_path) {
+            true => anyhow::bail!("Vault already exists at {:?}", vault_path),
+            false => anyhow::bail!("{:?} already exists and isn't a vault. A vault needs a new folder of its own, e.g. {:?}.", vault_path, vault_path.join(".lethe_vault")),
+        }
+    }
+    let real = real_path(vault_path);
+    if let Some(outer) = real.ancestors().skip(1).find(|dir| header::exists(dir)) {
+        anyhow::bail!("{:?} is inside the vault folder {:?}. Make the new vault outside it.", vault_path, outer);
+    }
+    if let Some((target, outer)) = mounts().into_iter().find(|(target, _)| real.starts_with(target)) {
+        anyhow::bail!("{:?} is inside {:?}, where the vault at {:?} is mounted. Make the new vault outside the mount.", vault_path, target, outer);
+    }
+    Ok(())
+}
+
+/// Refuses to put `source` into the vault at `vault_path` when it is the
+/// vault folder, is in it or holds it, or is in the vault's own mount:
+/// the vault would take in its own blocks, or files it already has
+fn check_put_source(source: &Path, vault_path: &Path) -> Result<()> {
+    let (source_real, vault_real) = (real_path(source), real_path(vault_path));
+    if source_real.starts_with(&vault_real) {
+        anyhow::bail!("{:?} is in the vault folder {:?} itself; putting it would store the vault's encrypted blocks in it again.", source, vault_path);
+    }
+    if vault_real.starts_with(&source_real) {
+        anyhow::bail!("{:?} holds the vault folder {:?}; putting it would store the vault inside itself. Put the folders next to the vault one by one instead.", source, vault_path);
+    }
+    if let Some((target, _)) = mounts().into_iter().find(|(target, vault)| *vault == vault_real && source_real.starts_with(target)) {
+        anyhow::bail!("{:?} is in this vault's mount at {:?}; its files are in the vault already. Copy within the mount instead.", source, target);
+    }
+    Ok(())
+}
+
 pub fn unlock_vault(vault_path_str: &str) -> Result<(PathBuf, MasterKey)> {
     let vault_path = resolve_vault_path(Some(vault_path_str))?;
-    let salt_path = vault_path.join("salt.loader");
+    let in_container = container::pending(&vault_path);
 
-    if !salt_path.exists() {
+    if !in_container && !header::exists(&vault_path) {
         anyhow::bail!(
             "Invalid vault path: {:?}. (Did you run 'lethe init'?)",
             vault_path
         );
     }
 
-    let password = rpassword::prompt_password("Enter Vault Password: ")?;
-    let salt = fs::read_to_string(salt_path).context("Failed to read salt file")?;
+    // A running `lethe agent` may hold the key from an earlier unlock
+    let cached = if in_container { None } else { agent::cached_key(&vault_path) };
+    let key = match cached {
+        Some(key) => key,
+        None => {
+            let password = rpassword::prompt_password("Enter Vault Password: ")?;
+            if in_container {
+                container::open(&vault_path, &password)?;
+            }
+            let key = unlock_key(&vault_path, &password)?;
+            if !in_container {
+                agent::offer_key(&vault_path, &key);
+            }
+            key
+        }
+    };
+    // A wrong password is turned away here; the command then fails on its own
+    let _ = audit::record(&vault_path, &key, Operation::Unlock { command: invoked() });
+    activity::unlocked(&vault_path, &key);
+    Ok((vault_path, key))
+}
 
-    let (key, _) = CryptoEngine::derive_key_with_salt(&password, salt.trim())?;
+/// `unlock_vault` for commands that read or write the outer vault's own
+/// files, which a hidden vault (see `lethe_core::hidden`) must not touch
+pub fn unlock_outer(vault_path_str: &str) -> Result<(PathBuf, MasterKey)> {
+    let (vault_path, key) = unlock_vault(vault_path_str)?;
+    if hidden::holds(&BlockManager::new(&vault_path)?, &key) {
+        anyhow::bail!("`{}` isn't available in a hidden vault", invoked());
+    }
     Ok((vault_path, key))
 }
 
+/// The command being run, without its arguments (`lethe devices enroll`)
+pub fn invoked() -> String {
+    let mut command = super::Cli::command();
+    let mut words = vec!["lethe".to_string()];
+    for arg in std::env::args().skip(1) {
+        let Some(sub) = command.find_subcommand(&arg).cloned() else { break };
+        words.push(arg);
+        command = sub;
+    }
+    words.join(" ")
+}
+
+/// Derives the vault key from a password without prompting
+#[tracing::instrument(name = "unlock", skip_all)]
+pub fn derive_vault_key(vault_path: &Path, password: &str) -> Result<MasterKey> {
+    let header = VaultHeader::load(vault_path)?;
+    let key = header.derive_key(password)?;
+    // Vaults from before the header get one now, and a damaged or missing
+    // copy is put back
+    if header.legacy || header.stale {
+        header.save(vault_path)?;
+    }
+    Ok(key)
+}
+
+/// `derive_vault_key` for an unlock, counted against the vault's policy
+/// on failed unlocks (see `lethe_core::lockout`)
+pub fn unlock_key(vault_path: &Path, password: &str) -> Result<MasterKey> {
+    let key = derive_vault_key(vault_path, password)?;
+    lockout::check(vault_path, &VaultHeader::load(vault_path)?, &key)?;
+    Ok(key)
+}
+
 fn upload_worker(
     path: &Path,
     dest: &str,
     block_mgr: &BlockManager,
     index_mgr: &mut IndexManager,
     key: &MasterKey,
+    expires: Option<u64>,
+    bar: &Bar,
 ) -> Result<()> {
-    print!("Processing {} ... ", path.display());
-    io::stdout().flush()?;
-
-    let data = fs::read(path).context("Failed to read source file")?;
-    let size = data.len() as u64;
-
-    let block_id = block_mgr.write_block(&data, key)?;
+    let source = fs::File::open(path).with_context(|| format!("Failed to read {}", path.display()))?;
 
     let clean_dest = dest.replace("//", "/");
-    index_mgr.add_file(clean_dest, vec![block_id], size);
+    let written = index_mgr.store_reader(block_mgr, key, clean_dest.clone(), source, bar)?;
+    index_mgr.set_expiry(&clean_dest, expires);
+    let chunks = index_mgr.get_file(&clean_dest).map(|e| e.blocks.len()).unwrap_or(0);
 
-    println!("OK");
+    if written < chunks {
+        bar.println(format_args!("Processing {} ... OK ({} of {} chunks already stored)", path.display(), chunks - written, chunks));
+    } else {
+        bar.println(format_args!("Processing {} ... OK", path.display()));
+    }
     Ok(())
 }
 
 // --- COMMAND HANDLERS ---
 
-pub fn do_init(path: Option<String>) -> Result<()> {
-    let vault_path = resolve_vault_path(path.as_deref())?;
-    if vault_path.exists() {
-        anyhow::bail!("Vault already exists at {:?}", vault_path);
+pub fn do_init(path: Option<String>, container_file: Option<PathBuf>, wipe_after: Option<u32>) -> Result<()> {
+    if let Some(file) = container_file {
+        return init_container(file, wipe_after);
     }
 
+    let vault_path = resolve_vault_path(path.as_deref())?;
+    check_init_place(&vault_path)?;
+
     println!("Initializing vault at: {:?}", vault_path);
+    confirm_wipe(wipe_after)?;
+    let password = new_password()?;
+    init_at(&vault_path, &password, wipe_after)?;
+    println!("Vault initialized successfully.");
+    Ok(())
+}
 
+fn new_password() -> Result<String> {
     let password = rpassword::prompt_password("Set Master Password: ")?;
     let confirm = rpassword::prompt_password("Confirm Password: ")?;
 
@@ -82,32 +244,92 @@ pub fn do_init(path: Option<String>) -> Result<()> {
     if password.is_empty() {
         anyhow::bail!("Password cannot be empty.");
     }
+    strength::check(&password)?;
+    Ok(password)
+}
 
-    fs::create_dir_all(&vault_path).context("Failed to create vault directory")?;
+/// Makes sure `--wipe-after` is meant, with a phrase typed out in full
+fn confirm_wipe(wipe_after: Option<u32>) -> Result<()> {
+    let Some(attempts) = wipe_after else {
+        return Ok(());
+    };
+    if attempts == 0 {
+        anyhow::bail!("--wipe-after needs at least one attempt");
+    }
+    let phrase = format!("destroy after {} failures", attempts);
+    println!("After {} failed unlocks in a row, the vault will be destroyed for good. Nobody,", attempts);
+    println!("including you, can get its files back after that.");
+    print!("Type \"{}\" to confirm: ", phrase);
+    io::stdout().flush()?;
+    let mut typed = String::new();
+    io::stdin().read_line(&mut typed)?;
+    if typed.trim() != phrase {
+        anyhow::bail!("Not confirmed; no vault was made.");
+    }
+    Ok(())
+}
+
+fn init_at(vault_path: &Path, password: &str, wipe_after: Option<u32>) -> Result<()> {
+    fs::create_dir_all(vault_path).context("Failed to create vault directory")?;
 
     println!("Generating keys (Argon2id)...");
 
-    let (key, salt) = tokio::task::block_in_place(|| CryptoEngine::derive_key(&password))?;
-    fs::write(vault_path.join("salt.loader"), &salt).context("Failed to write salt")?;
+    let mut header = VaultHeader::new();
+    if let Some(attempts) = wipe_after {
+        header.set_wipe_after(attempts);
+    }
+    let key = tokio::task::block_in_place(|| header.derive_key(password))?;
+    if wipe_after.is_some() {
+        header.set_key_check(&key);
+    }
+    header.save(vault_path)?;
 
-    let mut index_mgr = IndexManager::new_empty(vault_path.clone(), salt);
+    let mut index_mgr = IndexManager::new_empty(vault_path.to_path_buf(), header.salt().to_string());
     index_mgr.save(&key)?;
 
-    let _ = BlockManager::new(&vault_path)?;
-
-    println!("Vault initialized successfully.");
+    let _ = BlockManager::new(vault_path)?;
     Ok(())
 }
 
-pub fn do_put(file: PathBuf, dest: String, vault: String) -> Result<()> {
-    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
-    let mut index_mgr = IndexManager::load(vault_path.clone(), &key)?;
-    let block_mgr = BlockManager::new(&vault_path)?;
+/// `lethe init --container`: a vault packed into `file`, after whatever it holds
+fn init_container(file: PathBuf, wipe_after: Option<u32>) -> Result<()> {
+    if file.is_dir() {
+        anyhow::bail!("{:?} is a folder; a container is a file", file);
+    }
+    let carrier = file.exists();
+    match carrier {
+        true => println!("Initializing vault inside {:?}, after its contents", file),
+        false => println!("Initializing vault in a new container at {:?}", file),
+    }
+    confirm_wipe(wipe_after)?;
+    let password = new_password()?;
+
+    let staging = container::init_dir();
+    container::create_private_dir(&staging)?;
+    let packed = init_at(&staging, &password, wipe_after).and_then(|()| {
+        println!("Packing...");
+        tokio::task::block_in_place(|| lethe_core::container::Container::create(&file, &staging, &password))
+    });
+    let _ = fs::remove_dir_all(&staging);
+    packed?;
+
+    println!("Vault initialized successfully. Pass {:?} wherever Lethe takes a vault.", file);
+    Ok(())
+}
 
+pub fn do_put(file: PathBuf, dest: String, vault: String, expire: Option<Duration>, immutable: bool) -> Result<()> {
+    let expires = expire.map(|after| now_secs() + after.as_secs());
     if !file.exists() {
         anyhow::bail!("Source file not found: {:?}", file);
     }
+    check_put_source(&file, &resolve_vault_path(Some(&vault))?)?;
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let _claim = claim(&vault_path, "lethe put")?;
+    let mut index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    let block_mgr = BlockManager::new(&vault_path)?;
 
+    // Everything to upload, with where it goes, so the bar knows the total
+    let mut uploads = Vec::new();
     if file.is_dir() {
         println!("Uploading directory: {:?}", file);
 
@@ -121,40 +343,125 @@ pub fn do_put(file: PathBuf, dest: String, vault: String) -> Result<()> {
                 let clean_dest = dest.trim_end_matches('/');
                 let vault_dest = format!("{}/{}", clean_dest, clean_relative);
 
-                upload_worker(path, &vault_dest, &block_mgr, &mut index_mgr, &key)?;
+                uploads.push((path.to_path_buf(), vault_dest, entry.metadata()?.len()));
             }
         }
     } else {
-        upload_worker(&file, &dest, &block_mgr, &mut index_mgr, &key)?;
+        let len = fs::metadata(&file)?.len();
+        uploads.push((file.clone(), dest.clone(), len));
     }
 
+    // Before anything is stored, so a bad name doesn't stop it half way
+    for (_, vault_dest, _) in &uploads {
+        index_mgr.check_new_path(&vault_dest.replace("//", "/"))?;
+        index_mgr.check_mutable(&vault_dest.replace("//", "/"))?;
+    }
+    if immutable {
+        require_write_once(&vault_path, &index_mgr)?;
+    }
+
+    let bar = Bar::new("Uploading");
+    bar.start(uploads.iter().map(|(_, _, len)| len).sum(), uploads.len() as u64);
+    for (path, vault_dest, _) in &uploads {
+        if let Err(e) = upload_worker(path, vault_dest, &block_mgr, &mut index_mgr, &key, expires, &bar) {
+            if !progress::is_cancelled(&e) {
+                return Err(e);
+            }
+            // Files stored before the cancel are kept
+            drop(bar);
+            index_mgr.save(&key)?;
+            outbox::after_write(&vault_path, &key)?;
+            anyhow::bail!("Cancelled; the files uploaded before {} were kept", path.display());
+        }
+        index_mgr.set_immutable(&vault_dest.replace("//", "/"), immutable);
+    }
+    drop(bar);
+
     index_mgr.save(&key)?;
     println!("Upload complete.");
+    if immutable {
+        println!("   Write-once: nothing can change, move or delete it until `lethe release`.");
+    }
+    activity::count(&vault_path, &key, Op::Put, uploads.len() as u64, uploads.iter().map(|(_, _, len)| len).sum());
+    outbox::after_write(&vault_path, &key)
+}
+
+/// Marks the vault as holding write-once files, before the first is
+/// stored, so builds that wouldn't enforce them refuse it
+fn require_write_once(vault_path: &Path, index_mgr: &IndexManager) -> Result<()> {
+    // Its header is the outer vault's, and would give it away
+    if index_mgr.is_hidden() {
+        anyhow::bail!("A hidden vault can't hold write-once files");
+    }
+    let mut header = VaultHeader::load(vault_path)?;
+    if header.features.insert(header::FEATURE_WRITE_ONCE.to_string()) {
+        header.save(vault_path)?;
+    }
     Ok(())
 }
 
+/// `lethe release`: lets the write-once files at or below `path` change
+/// again. It asks for the password whether or not an agent holds the key.
+pub fn do_release(path: String, vault: String) -> Result<()> {
+    let vault_path = resolve_vault_path(Some(&vault))?;
+    if !header::exists(&vault_path) {
+        anyhow::bail!("Invalid vault path: {:?}. (Did you run 'lethe init'?)", vault_path);
+    }
+    let path = format!("/{}", path.trim_matches('/'));
+    println!("Releasing write-once files at {} takes the vault password again.", path);
+    let password = rpassword::prompt_password("Enter Vault Password: ")?;
+    let key = tokio::task::block_in_place(|| unlock_key(&vault_path, &password))?;
+    let _ = audit::record(&vault_path, &key, Operation::Unlock { command: invoked() });
+    activity::unlocked(&vault_path, &key);
+
+    let _claim = claim(&vault_path, "lethe release")?;
+    let mut index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    let released: Vec<String> = index_mgr.data.files.iter()
+        .filter(|(p, e)| e.immutable && index::is_within(p, &path))
+        .map(|(p, _)| p.clone())
+        .collect();
+    if released.is_empty() {
+        println!("Nothing at {} is write-once.", path);
+        return Ok(());
+    }
+    for p in &released {
+        index_mgr.set_immutable(p, false);
+    }
+    index_mgr.save(&key)?;
+    audit::record(&vault_path, &key, Operation::Release { path: path.clone(), files: released.len() as u64 })
+        .context("Failed to record the release in the audit log")?;
+    println!("Released {} file(s) at {}; they can be changed and deleted again.", released.len(), path);
+    outbox::after_write(&vault_path, &key)
+}
+
 pub fn do_ls(vault: String) -> Result<()> {
     let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
-    let index_mgr = IndexManager::load(vault_path, &key)?;
+    // Names and sizes only: the block maps stay sealed
+    let listing = IndexManager::read_listing(&vault_path, &key)?;
 
     println!("\nVault Contents:");
     println!("{:<12} | {:<40}", "SIZE", "PATH");
     println!("{:-<60}", "-");
 
-    let mut paths: Vec<_> = index_mgr.data.files.keys().collect();
+    let mut paths: Vec<_> = listing.paths().collect();
     paths.sort();
 
     for path in paths {
-        let entry = &index_mgr.data.files[path];
+        let entry = &listing.files[path];
         let size_str = humansize::format_size(entry.size, humansize::BINARY);
-        println!("{:<12} | {}", size_str, path);
+        let mark = if entry.immutable { "  (write-once)" } else { "" };
+        println!("{:<12} | {}{}", size_str, path, mark);
     }
 
     println!();
+    // On stderr, so the listing stays as scripts expect
+    if let Some(line) = Checkup::quick(&vault_path, &key, &listing).ok().flatten().and_then(|c| checkup::nudge(&c.findings(&vault))) {
+        eprintln!("{}", line);
+    }
     Ok(())
 }
 
-pub fn do_get(src: String, out: PathBuf, vault: String) -> Result<()> {
+pub fn do_get(src: String, out: PathBuf, vault: String, jobs: Option<usize>) -> Result<()> {
     let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
     let index_mgr = IndexManager::load(vault_path.clone(), &key)?;
     let block_mgr = BlockManager::new(&vault_path)?;
@@ -166,18 +473,24 @@ pub fn do_get(src: String, out: PathBuf, vault: String) -> Result<()> {
             humansize::format_size(entry.size, humansize::BINARY)
         );
 
-        let mut full_data = Vec::with_capacity(entry.size as usize);
-        for block_id in &entry.blocks {
-            let mut chunk = block_mgr.read_block(block_id, &key)?;
-            full_data.append(&mut chunk);
-        }
-
         if let Some(parent) = out.parent() {
             fs::create_dir_all(parent)?;
         }
 
-        fs::write(&out, full_data)?;
+        // A block per job at a time, so the file never has to fit in memory
+        let jobs = jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get())).max(1);
+        let file = fs::File::create(&out)?;
+        let bar = Bar::new("Downloading");
+        bar.start(entry.size, 1);
+        let written = tokio::task::block_in_place(|| FileReader::new(&block_mgr, &key, entry).write_parallel(&file, jobs, &bar));
+        if let Err(e) = written {
+            drop(file);
+            let _ = fs::remove_file(&out);
+            return Err(e.into());
+        }
+        drop(bar);
         println!("Saved to {:?}", out);
+        activity::count(&vault_path, &key, Op::Get, 1, entry.size);
     } else {
         anyhow::bail!("File not found in vault: {}", src);
     }
@@ -188,16 +501,19 @@ pub fn do_get(src: String, out: PathBuf, vault: String) -> Result<()> {
 pub fn do_repair(vault: String) -> Result<()> {
     println!("Starting repair process...");
 
-    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe repair")?;
 
-    match IndexManager::load(vault_path, &key) {
+    match IndexManager::load(vault_path.clone(), &key) {
         Ok(mut index_mgr) => {
             println!(
                 "Valid index replica found (Rev: {}).",
                 index_mgr.data.revision
             );
             println!("🔄 Resyncing all replicas...");
+            index_mgr.refresh_placement(&BlockManager::new(&vault_path)?);
             index_mgr.save(&key)?;
+            repair_blocks(&vault_path, &key, &index_mgr)?;
             println!("Repair complete.");
             Ok(())
         }
@@ -208,77 +524,334 @@ pub fn do_repair(vault: String) -> Result<()> {
     }
 }
 
-pub fn do_clean(vault: String, dry_run: bool) -> Result<()> {
+/// Checks every copy of the blocks files and snapshots use, on a vault
+/// spread over volumes, rewriting damaged ones from a good copy and making
+/// up blocks short of `copies`. Padding is left alone: it opens under no key.
+fn repair_blocks(vault_path: &Path, key: &MasterKey, index_mgr: &IndexManager) -> Result<()> {
+    let storage = BlockManager::new(vault_path)?;
+    if storage.attached().len() < 2 {
+        return Ok(());
+    }
+    let blocks: BTreeSet<&str> = index_mgr.referenced_blocks().into_iter()
+        .filter(|id| !index_mgr.data.padding.contains(*id))
+        .collect();
+    println!("Checking {} block(s) on {} volume(s), {} copies each...", blocks.len(), storage.attached().len(), storage.copies());
+
+    let bar = Bar::new("Checking");
+    bar.start(0, blocks.len() as u64);
+    let (mut rewritten, mut added, mut lost) = (0, 0, Vec::new());
+    for id in blocks {
+        // Stopping between blocks leaves every copy whole
+        progress::check(&bar)?;
+        bar.advance(0, 1);
+        let repaired = storage.repair_block(id, key)?;
+        for volume in &repaired.rewritten {
+            audit::record(vault_path, key, Operation::BlockRepair {
+                block: id.to_string(),
+                volume: volume.clone(),
+                reason: "damaged copy rewritten".to_string(),
+            })?;
+        }
+        for volume in &repaired.added {
+            audit::record(vault_path, key, Operation::BlockRepair {
+                block: id.to_string(),
+                volume: volume.clone(),
+                reason: "missing copy added".to_string(),
+            })?;
+        }
+        rewritten += repaired.rewritten.len();
+        added += repaired.added.len();
+        if repaired.lost {
+            lost.push(id);
+        }
+    }
+    drop(bar);
+
+    if rewritten + added == 0 && lost.is_empty() {
+        println!("   Every block copy is intact.");
+    }
+    if rewritten > 0 {
+        println!("   Rewrote {} damaged block copy(ies) from a good one.", rewritten);
+    }
+    if added > 0 {
+        println!("   Made {} new block copy(ies) to reach {} per block.", added, storage.copies());
+    }
+    if !lost.is_empty() {
+        error!("{} block(s) have no copy that opens: {}", lost.len(), lost.join(", "));
+        println!("   Files using them can't be read in full; restore them from a backup.");
+    }
+    Ok(())
+}
+
+pub fn do_clean(vault: String, dry_run: bool, grace: Duration, purge_snapshots: Option<Duration>) -> Result<()> {
     println!("Starting Garbage Collection...");
     if dry_run {
         println!("DRY RUN: No files will be deleted.");
     }
 
     // 1. Unlock and Load Index
-    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
-    let index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe clean")?;
+    let mut index_mgr = IndexManager::load(vault_path.clone(), &key)?;
 
-    // 2. Build Set of Valid Blocks
-    println!("Analyzing Index...");
-    let mut valid_blocks = HashSet::new();
-    for entry in index_mgr.data.files.values() {
-        for block in &entry.blocks {
-            valid_blocks.insert(block.clone());
+    // 2. Drop snapshots past the age asked for, and automatic ones the
+    // retention policy no longer keeps; their blocks are then orphans
+    // like any other. A dry run only drops them from memory.
+    let before = purge_snapshots.map(|age| now_secs().saturating_sub(age.as_secs()));
+    let policy = retention::past(&index_mgr.data, now_secs(), false);
+    let old: Vec<(String, u64)> = index_mgr.data.snapshots.iter()
+        .filter(|(name, s)| before.is_some_and(|before| s.created < before) || policy.contains(name))
+        .map(|(name, s)| (name.clone(), s.created))
+        .collect();
+    for (name, created) in &old {
+        let created = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(*created));
+        let why = if policy.contains(name) { ", retention policy" } else { "" };
+        match dry_run {
+            true => println!("   [DRY] Would delete snapshot: {} (taken {}{})", name, created, why),
+            false => println!("   Deleting snapshot: {} (taken {}{})", name, created, why),
+        }
+        index_mgr.delete_snapshot(name);
+    }
+    if !dry_run && !old.is_empty() {
+        index_mgr.save(&key)?;
+    }
+
+    // 3. Drop expired entries, shredding the blocks only they used
+    let expired = index_mgr.purge_expired();
+    let mut shredded: u64 = 0;
+    if !expired.is_empty() {
+        let still_used: HashSet<String> = index_mgr.referenced_blocks().into_iter().map(str::to_string).collect();
+        let doomed: BTreeSet<&String> = expired.iter()
+            .flat_map(|e| e.blocks.iter())
+            .filter(|id| !still_used.contains(*id))
+            .collect();
+        for entry in &expired {
+            match dry_run {
+                true => println!("   [DRY] Would remove expired: {}", entry.path),
+                false => println!("   Removing expired: {}", entry.path),
+            }
+        }
+        if !dry_run {
+            // The index lets go of the blocks before they are gone
+            index_mgr.save(&key)?;
+            let storage = BlockManager::new(&vault_path)?;
+            for id in &doomed {
+                storage.shred_block(id)?;
+            }
         }
+        shredded = doomed.len() as u64;
     }
+
+    // 4. Build Set of Valid Blocks
+    println!("Analyzing Index...");
+    // Snapshots keep their blocks alive too
+    let valid_blocks = index_mgr.referenced_blocks();
     println!(
         "   Found {} active blocks referenced in Index.",
         valid_blocks.len()
     );
-
-    // 3. Scan Disk for Orphans
+    // What keeps the blocks no live file uses, so it is clear why they stay
+    let live: HashSet<&str> = index_mgr.data.files.values().flat_map(|e| e.blocks.iter().map(String::as_str)).collect();
+    let in_snapshots: HashSet<&str> = index_mgr.data.snapshots.values()
+        .flat_map(|s| s.files.values())
+        .flat_map(|e| e.blocks.iter().map(String::as_str))
+        .filter(|id| !live.contains(id))
+        .collect();
+    let (mut snapshot_count, mut snapshot_bytes): (u64, u64) = (0, 0);
+    let (mut padding_count, mut padding_bytes): (u64, u64) = (0, 0);
+
+    // 5. Scan Disk for Orphans
     let mut reclaimed_bytes: u64 = 0;
     let mut deleted_count: u64 = 0;
     let mut kept_count: u64 = 0;
+    let mut recent_count: u64 = 0;
+
+    // Blocks a writer has put down but not yet saved an index for look
+    // like orphans too; only ones older than the grace window are taken
+    let cutoff = SystemTime::now().checked_sub(grace).unwrap_or(UNIX_EPOCH);
+
+    // Every volume, not just the vault folder
+    let storage = BlockManager::new(&vault_path)?;
+    let blocks = storage.list_blocks()?;
+    let bar = Bar::new("Scanning");
+
+    for (id, _) in blocks {
+        // Stopping between blocks leaves every one either there or gone
+        progress::check(&bar)?;
+        bar.advance(0, 1);
+        // Each copy takes its own space, on whichever volume holds it
+        let copies: Vec<fs::Metadata> = storage.copy_paths(&id).iter()
+            .filter_map(|path| fs::metadata(path).ok())
+            .collect();
+        let len: u64 = copies.iter().map(fs::Metadata::len).sum();
+
+        if valid_blocks.contains(id.as_str()) {
+            kept_count += 1;
+            if in_snapshots.contains(id.as_str()) {
+                snapshot_count += 1;
+                snapshot_bytes += len;
+            } else if index_mgr.data.padding.contains(id.as_str()) {
+                padding_count += 1;
+                padding_bytes += len;
+            }
+            continue;
+        }
 
+        // ORPHAN DETECTED. A copy written within the window keeps them all.
+        if copies.iter().any(|meta| meta.modified().is_ok_and(|m| m > cutoff)) {
+            recent_count += 1;
+            continue;
+        }
+        if !dry_run {
+            storage.delete_block(&id).context("Failed to delete orphan block")?;
+        }
+        reclaimed_bytes += len;
+        deleted_count += 1;
+        bar.advance(len, 0);
 
-    let read_dir = fs::read_dir(&vault_path).context("Failed to read vault directory")?;
-
-    for entry in read_dir {
-        let entry = entry?;
-        let path = entry.path();
-
-        // Filter for files starting with "blk_" and ending with ".bin"
-        if path.is_file() {
-            if let Some(name) = path.file_name().and_then(OsStr::to_str) {
-                if name.starts_with("blk_") && name.ends_with(".bin") {
-                    // Extract ID: blk_XYZ.bin -> XYZ
-                    let id_part = &name[4..name.len() - 4];
-
-                    if !valid_blocks.contains(id_part) {
-                        // ORPHAN DETECTED
-                        let len = entry.metadata()?.len();
-                        if !dry_run {
-                            fs::remove_file(&path)
-                                .context("Failed to delete orphan block")?;
-                        }
-                        reclaimed_bytes += len;
-                        deleted_count += 1;
-
-                        if dry_run {
-                            println!("   [DRY] Would delete orphan: {}", name);
-                        }
-                    } else {
-                        kept_count += 1;
-                    }
-                }
-            }
+        if dry_run {
+            bar.println(format_args!("   [DRY] Would delete orphan: blk_{}.bin", id));
         }
     }
+    drop(bar);
 
     println!("---------------------------------------------------");
     println!("GC Complete.");
     println!("   Active Blocks: {}", kept_count);
+    if snapshot_count > 0 {
+        println!(
+            "      Held Only by Snapshots: {} ({}; --purge-snapshots-older-than frees them)",
+            snapshot_count,
+            humansize::format_size(snapshot_bytes, humansize::BINARY)
+        );
+    }
+    if padding_count > 0 {
+        println!("      Padding: {} ({})", padding_count, humansize::format_size(padding_bytes, humansize::BINARY));
+    }
     println!("   Orphans Removed: {}", deleted_count);
+    if recent_count > 0 {
+        println!("   Recent Orphans Kept: {} (younger than {})", recent_count, humantime::format_duration(grace));
+    }
+    if shredded > 0 {
+        let done = if dry_run { "To Shred" } else { "Shredded" };
+        println!("   Expired Blocks {}: {}", done, shredded);
+    }
     println!(
         "   Space Reclaimed: {}",
         humansize::format_size(reclaimed_bytes, humansize::BINARY)
     );
 
+    if !dry_run {
+        index_mgr.announce(&key, vec![Change::GcRun { blocks: deleted_count, bytes: reclaimed_bytes }])?;
+        let _ = audit::record(&vault_path, &key, Operation::Clean { blocks: deleted_count, bytes: reclaimed_bytes });
+        activity::count(&vault_path, &key, Op::Clean, deleted_count, reclaimed_bytes);
+    }
+    Ok(())
+}
+
+/// `lethe upgrade`: moves a vault from before content ids onto them, by
+/// renaming the blocks it uses after what they hold, and from before the
+/// split index onto it
+pub fn do_upgrade(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe upgrade")?;
+    let mut header = VaultHeader::load(&vault_path)?;
+    let content_ids = header.features.contains(header::FEATURE_CONTENT_IDS);
+    let split = header.features.contains(header::FEATURE_SPLIT_INDEX);
+    if content_ids && split {
+        println!("The vault is already on the current format. Nothing to do.");
+        return Ok(());
+    }
+
+    // Either layout opens, so the header can say so before the index is rewritten
+    if !split {
+        header.features.insert(header::FEATURE_SPLIT_INDEX.to_string());
+        header.save(&vault_path)?;
+    }
+    let mut index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    let mut old = Vec::new();
+    if !content_ids {
+        let storage = BlockManager::new(&vault_path)?;
+        println!("Renaming blocks after their content...");
+        old = tokio::task::block_in_place(|| index_mgr.adopt_content_ids(&storage, &key))?;
+    }
+
+    // The index moves over before the header says so and the old names go
+    index_mgr.save(&key)?;
+    if !content_ids {
+        header.features.insert(header::FEATURE_CONTENT_IDS.to_string());
+        header.save(&vault_path)?;
+        let storage = BlockManager::new(&vault_path)?;
+        for id in &old {
+            storage.delete_block(id)?;
+        }
+        println!("Upgrade complete: {} block(s) renamed.", old.len());
+    }
+    if !split {
+        println!("Upgrade complete: the index keeps names and sizes apart from block maps, so `lethe ls` reads only those.");
+    }
+    println!("Builds of Lethe from before these changes can no longer open this vault.");
+    if !content_ids {
+        println!("Remotes get the blocks again under their new names on the next push.");
+    }
+    Ok(())
+}
+
+/// `lethe chunk-size`: shows, or sets, the average size new files are cut
+/// into. Files already stored keep their chunks.
+pub fn do_chunk_size(vault: String, size: Option<String>) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let describe = |average: u32| match average {
+        0 => format!("{} (the default)", humansize::format_size(chunker::DEFAULT_AVERAGE, humansize::BINARY)),
+        n => humansize::format_size(n, humansize::BINARY),
+    };
+    let Some(size) = size else {
+        let index_mgr = IndexManager::load(vault_path, &key)?;
+        println!("New files are cut into chunks of {} on average.", describe(index_mgr.data.chunk_size));
+        return Ok(());
+    };
+    let average = match size.as_str() {
+        "default" => 0,
+        size => super::volumes::parse_size(size).map_err(anyhow::Error::msg)?,
+    };
+
+    let _claim = claim(&vault_path, "lethe chunk-size")?;
+    let mut index_mgr = IndexManager::load(vault_path, &key)?;
+    index_mgr.set_chunk_size(u32::try_from(average).unwrap_or(u32::MAX))?;
+    index_mgr.save(&key)?;
+    println!("New files are cut into chunks of {} on average.", describe(index_mgr.data.chunk_size));
+    println!("   Files already stored keep their chunks, which chunks cut to another size don't deduplicate against.");
+    Ok(())
+}
+
+pub fn do_windows_names(vault: String, setting: Option<String>) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let describe = |on: bool| match on {
+        true => "New paths must be names Windows can open.",
+        false => "New paths may be any names.",
+    };
+    let Some(setting) = setting else {
+        let index_mgr = IndexManager::load(vault_path, &key)?;
+        println!("{}", describe(index_mgr.data.windows_names));
+        return Ok(());
+    };
+
+    let _claim = claim(&vault_path, "lethe windows-names")?;
+    let mut index_mgr = IndexManager::load(vault_path, &key)?;
+    index_mgr.set_windows_names(setting == "on");
+    index_mgr.save(&key)?;
+    println!("{}", describe(index_mgr.data.windows_names));
+    if index_mgr.data.windows_names {
+        let mut problems: Vec<(&String, String)> = index_mgr.data.files.keys()
+            .filter_map(|path| index::windows_problem(path).map(|why| (path, why)))
+            .collect();
+        problems.sort();
+        for (path, why) in &problems {
+            println!("   {}: {}", path, why);
+        }
+        if !problems.is_empty() {
+            println!("   {} path(s) already stored won't open on Windows; they stay usable here, rename them to bring them over.", problems.len());
+        }
+    }
     Ok(())
 }
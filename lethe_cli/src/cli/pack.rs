@@ -0,0 +1,47 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::PathBuf;
+
+use lethe_core::pack::{self, Pack};
+
+use super::ops::unlock_vault;
+use crate::container::create_private_dir;
+use crate::daemon::claim::claim;
+
+pub fn do_pack(out: PathBuf, vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    // A mount saves and holds still while its files are copied
+    let _claim = claim(&vault_path, "lethe pack")?;
+    let entries = tokio::task::block_in_place(|| pack::create(&vault_path, &key, &out))?;
+
+    let blocks = entries.iter().filter(|e| e.name.starts_with("blk_")).count();
+    let size = fs::metadata(&out).map(|m| m.len()).unwrap_or(0);
+    println!("Packed {} block(s) and the index into {} ({}).", blocks, out.display(), humansize::format_size(size, humansize::BINARY));
+    println!("   `lethe unpack {} --into <folder>` turns it back into a vault; it asks for the vault password.", out.display());
+    Ok(())
+}
+
+pub fn do_unpack(file: PathBuf, into: PathBuf) -> Result<()> {
+    if fs::read_dir(&into).is_ok_and(|mut d| d.next().is_some()) {
+        anyhow::bail!("{:?} isn't empty. Unpack into a new folder.", into);
+    }
+    // Turned away before the password if it is no pack at all
+    pack::read_header(&file)?;
+    let password = rpassword::prompt_password("Enter Vault Password: ")?;
+    let (mut opened, _) = tokio::task::block_in_place(|| Pack::open(&file, &password))?;
+
+    // Unpacked next to `into` first, so a failure never leaves half a vault there
+    let partial = into.with_extension("partial");
+    let _ = fs::remove_dir_all(&partial);
+    create_private_dir(&partial)?;
+    if let Err(e) = tokio::task::block_in_place(|| opened.extract(&partial)) {
+        let _ = fs::remove_dir_all(&partial);
+        return Err(e);
+    }
+    let _ = fs::remove_dir(&into);
+    fs::rename(&partial, &into).with_context(|| format!("Failed to move the vault into {:?}", into))?;
+
+    let blocks = opened.entries.iter().filter(|e| e.name.starts_with("blk_")).count();
+    println!("Unpacked {} block(s) into {}. Open it with --vault {}.", blocks, into.display(), into.display());
+    Ok(())
+}
@@ -0,0 +1,142 @@
+//! A one-line progress bar on stderr for the CLI's long commands, and
+//! Ctrl+C as a clean cancel for them (see `lethe_core::progress`).
+//!
+//! The first Ctrl+C while a bar is up asks the work to stop at the next
+//! block or file; what was finished stays done. A second one, or one with
+//! no bar up, exits at once as Ctrl+C always did.
+
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, Once};
+use std::time::{Duration, Instant};
+
+use lethe_core::progress::ProgressSink;
+
+const REDRAW: Duration = Duration::from_millis(100);
+
+static CANCEL: AtomicBool = AtomicBool::new(false);
+static BARS: AtomicUsize = AtomicUsize::new(0);
+static WATCH: Once = Once::new();
+
+/// Takes over Ctrl+C, once per process
+fn watch_ctrl_c() {
+    WATCH.call_once(|| {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else { return };
+        runtime.spawn(async {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if BARS.load(Ordering::SeqCst) == 0 || CANCEL.swap(true, Ordering::SeqCst) {
+                    std::process::exit(130);
+                }
+                eprintln!("\nCancelling after the current block; Ctrl+C again to quit now.");
+            }
+        });
+    });
+}
+
+pub struct Bar {
+    label: &'static str,
+    total_bytes: AtomicU64,
+    total_items: AtomicU64,
+    bytes: AtomicU64,
+    items: AtomicU64,
+    started: Instant,
+    /// When the line was last drawn, and whether it is showing
+    drawn: Mutex<(Option<Instant>, bool)>,
+    tty: bool,
+}
+
+impl Bar {
+    pub fn new(label: &'static str) -> Self {
+        watch_ctrl_c();
+        BARS.fetch_add(1, Ordering::SeqCst);
+        Self {
+            label,
+            total_bytes: AtomicU64::new(0),
+            total_items: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            items: AtomicU64::new(0),
+            started: Instant::now(),
+            drawn: Mutex::new((None, false)),
+            tty: io::stderr().is_terminal(),
+        }
+    }
+
+    fn line(&self) -> String {
+        let (bytes, items) = (self.bytes.load(Ordering::Relaxed), self.items.load(Ordering::Relaxed));
+        let (total_bytes, total_items) = (self.total_bytes.load(Ordering::Relaxed), self.total_items.load(Ordering::Relaxed));
+        let size = |n| humansize::format_size(n, humansize::BINARY);
+        let mut line = format!("{} ", self.label);
+        match bytes.saturating_mul(100).checked_div(total_bytes) {
+            Some(percent) => line += &format!("{:>3}%  {} of {}", percent, size(bytes), size(total_bytes)),
+            None => line += &size(bytes),
+        }
+        match total_items {
+            0 => line += &format!(", {} done", items),
+            n => line += &format!(", {} of {} done", items, n),
+        }
+        let secs = self.started.elapsed().as_secs_f64();
+        if secs >= 1.0 && bytes > 0 {
+            line += &format!(", {}/s", size((bytes as f64 / secs) as u64));
+        }
+        line
+    }
+
+    fn draw(&self, force: bool) {
+        if !self.tty {
+            return;
+        }
+        // Whoever holds the line is drawing it already
+        let Ok(mut drawn) = self.drawn.try_lock() else { return };
+        if !force && drawn.0.is_some_and(|at| at.elapsed() < REDRAW) {
+            return;
+        }
+        let _ = write!(io::stderr(), "\r{}\x1b[K", self.line());
+        *drawn = (Some(Instant::now()), true);
+    }
+
+    /// Prints a line of output above the bar
+    pub fn println(&self, text: impl std::fmt::Display) {
+        let mut drawn = self.drawn.lock().unwrap_or_else(|e| e.into_inner());
+        if drawn.1 {
+            let _ = write!(io::stderr(), "\r\x1b[K");
+            drawn.1 = false;
+        }
+        println!("{}", text);
+        drop(drawn);
+        self.draw(true);
+    }
+
+    /// Takes the bar off the screen
+    pub fn clear(&self) {
+        let mut drawn = self.drawn.lock().unwrap_or_else(|e| e.into_inner());
+        if drawn.1 {
+            let _ = write!(io::stderr(), "\r\x1b[K");
+            drawn.1 = false;
+        }
+    }
+}
+
+impl ProgressSink for Bar {
+    fn start(&self, bytes: u64, items: u64) {
+        self.total_bytes.store(bytes, Ordering::Relaxed);
+        self.total_items.store(items, Ordering::Relaxed);
+        self.draw(true);
+    }
+
+    fn advance(&self, bytes: u64, items: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.items.fetch_add(items, Ordering::Relaxed);
+        self.draw(false);
+    }
+
+    fn cancelled(&self) -> bool {
+        CANCEL.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Bar {
+    fn drop(&mut self) {
+        self.clear();
+        BARS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
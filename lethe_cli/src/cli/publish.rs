@@ -0,0 +1,144 @@
+//! `lethe publish`: decrypts one subtree into a plain folder, to go on a
+//! web server or anywhere else in the open.
+//!
+//! Everything at or below `--prefix` lands under `--out` at its path below
+//! the prefix, with its modified time; a prefix under `/.snapshots/<name>`
+//! publishes from that snapshot. A folder without an `index.html` of its
+//! own gets one listing what is in it, so the folder can be served as a
+//! static site as it is (`--no-index` leaves them out). `--out` must be
+//! new or empty, so nothing stale goes out with it. Every run is recorded
+//! in the audit log: which subtree left the vault, where to, and how much.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use lethe_core::audit::{self, Operation};
+use lethe_core::index::IndexManager;
+use lethe_core::progress::{self, ProgressSink};
+use lethe_core::storage::BlockManager;
+
+use super::ops::unlock_outer;
+use super::progress::Bar;
+use super::restore;
+
+const INDEX_PAGE: &str = "index.html";
+
+fn size(bytes: u64) -> String {
+    humansize::format_size(bytes, humansize::BINARY)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+/// `name` as one segment of a relative link
+fn link(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Writes a listing into every folder under `out` without a page of its own
+fn write_index_pages(out: &Path) -> Result<usize> {
+    let mut written = 0;
+    for dir in WalkDir::new(out).into_iter().filter_map(|e| e.ok()).filter(|e| e.file_type().is_dir()) {
+        let page = dir.path().join(INDEX_PAGE);
+        if page.exists() {
+            continue;
+        }
+        let mut entries: Vec<(bool, String)> = fs::read_dir(dir.path())?
+            .filter_map(|e| e.ok())
+            .map(|e| (!e.path().is_dir(), e.file_name().to_string_lossy().into_owned()))
+            .collect();
+        entries.sort();
+        let title = match dir.path().strip_prefix(out).unwrap_or(dir.path()).to_string_lossy() {
+            rel if rel.is_empty() => "/".to_string(),
+            rel => format!("/{}/", rel.replace('\\', "/")),
+        };
+        let mut html = format!("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body><h1>{0}</h1>\n<ul>\n", escape(&title));
+        if dir.depth() > 0 {
+            html.push_str("<li><a href=\"../\">../</a></li>\n");
+        }
+        for (is_file, name) in &entries {
+            let slash = if *is_file { "" } else { "/" };
+            html.push_str(&format!("<li><a href=\"{}{}\">{}{}</a></li>\n", link(name), slash, escape(name), slash));
+        }
+        html.push_str("</ul></body></html>\n");
+        fs::write(&page, html).with_context(|| format!("Failed to write {:?}", page))?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+pub fn do_publish(prefix: String, out: PathBuf, vault: String, no_index: bool) -> Result<()> {
+    let prefix = format!("/{}", prefix.trim_matches('/'));
+    let taken = fs::read_dir(&out).map(|mut dir| dir.next().is_some()).unwrap_or(out.exists());
+    if taken {
+        anyhow::bail!("{:?} isn't empty. Publish into a new or empty folder, so nothing stale goes out with it.", out);
+    }
+    // A hidden vault keeps no audit log to record it in
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    let block_mgr = BlockManager::new(&vault_path)?;
+
+    let selected = restore::select(&index_mgr, &prefix);
+    if selected.is_empty() {
+        anyhow::bail!("Nothing in the vault at {}", prefix);
+    }
+    let mut files = Vec::new();
+    for (path, entry) in &selected {
+        // A prefix naming a file publishes just that file
+        let below = match path.strip_prefix(prefix.trim_end_matches('/')) {
+            Some(rest) if !rest.is_empty() => rest.to_string(),
+            // The folder named is `--out` itself
+            _ if entry.is_dir => continue,
+            _ => path.rsplit('/').next().unwrap_or(path).to_string(),
+        };
+        let target = restore::target(&out, &below)?;
+        if entry.is_dir {
+            fs::create_dir_all(&target).with_context(|| format!("Failed to create {:?}", target))?;
+        } else {
+            files.push((path.as_str(), *entry, target));
+        }
+    }
+    fs::create_dir_all(&out).with_context(|| format!("Failed to create {:?}", out))?;
+    let total: u64 = files.iter().map(|(_, e, _)| e.size).sum();
+    println!("Publishing {} file(s) from {}, {}, to {:?}...", files.len(), prefix, size(total), out);
+
+    let bar = Bar::new("Publishing");
+    bar.start(total, files.len() as u64);
+    let (mut published, mut bytes) = (0, 0);
+    let written = tokio::task::block_in_place(|| -> Result<()> {
+        for (path, entry, target) in &files {
+            restore::restore_file(&block_mgr, &key, entry, target, &bar).with_context(|| format!("Failed to publish {}", path))?;
+            published += 1;
+            bytes += entry.size;
+        }
+        Ok(())
+    });
+    drop(bar);
+
+    // Whatever reached the folder has left the vault, finished or not
+    if published > 0 {
+        let operation = Operation::Publish { prefix: prefix.clone(), out: std::path::absolute(&out).unwrap_or(out.clone()).display().to_string(), files: published, bytes };
+        audit::record(&vault_path, &key, operation).context("Failed to record the publish in the audit log")?;
+    }
+    if let Err(e) = written {
+        if progress::is_cancelled(&e) {
+            anyhow::bail!("Cancelled; {} file(s) were published to {:?} before that, and the audit log says so", published, out);
+        }
+        return Err(e);
+    }
+    let pages = if no_index { 0 } else { write_index_pages(&out)? };
+    println!("Published {} file(s), {}, to {:?}.", published, size(bytes), out);
+    if pages > 0 {
+        println!("   Added a listing page to {} folder(s) without an {}.", pages, INDEX_PAGE);
+    }
+    println!("   They are plain files now: anyone who gets the folder can read them. See `lethe audit` for the record.");
+    Ok(())
+}
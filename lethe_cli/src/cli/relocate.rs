@@ -0,0 +1,130 @@
+//! `lethe relocate` moves a vault folder, and with it what outside the
+//! folder names it by path: the device id and device keys in the config
+//! directory (see `lethe_core::device` and `lethe_core::keyring`), the
+//! installed Sentinel service, and a key held by `lethe agent`. After a
+//! plain `mv` an enrolled copy no longer finds its device key, and the
+//! service starts on a folder that is gone.
+
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::path::Path;
+use std::time::Duration;
+
+use lethe_core::device;
+use lethe_core::keyring;
+use lethe_core::vault_lock::{self, VaultLock};
+use lethe_core::volumes::Volumes;
+
+use super::ops::vault_dir;
+use crate::daemon::agent::{self, AgentRequest};
+use crate::daemon::ipc::{self, Request};
+use crate::daemon::{registry, service};
+
+pub async fn do_relocate(old: String, new: String) -> Result<()> {
+    if Path::new(&old).is_file() {
+        anyhow::bail!("{} is a container, which is one file: move it as any other, while nothing has it open.", old);
+    }
+    let old_path = fs::canonicalize(vault_dir(&old)?)?;
+    let new = Path::new(&new);
+    if new.exists() {
+        anyhow::bail!("{:?} already exists; name a folder to create.", new);
+    }
+    let name = new.file_name().with_context(|| format!("{:?} doesn't name a folder", new))?;
+    let parent = new.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let new_path = fs::canonicalize(parent).with_context(|| format!("No such folder: {:?}", parent))?.join(name);
+    if new_path.starts_with(&old_path) {
+        anyhow::bail!("{:?} is inside the vault folder", new);
+    }
+    for volume in &Volumes::load(&old_path)?.list[1..] {
+        if new_path.starts_with(&volume.path) {
+            anyhow::bail!("{:?} is inside volume {:?} ({:?})", new, volume.id, volume.path);
+        }
+    }
+
+    // Whatever runs on the vault holds on to the old path
+    if ipc::send(&registry::syncd_id(&old_path), Request::SyncStatus).await.is_ok() {
+        anyhow::bail!("lethe syncd is running for the vault. Stop it first (`lethe syncd stop --vault {}`).", old);
+    }
+    let id = registry::instance_id(&old_path);
+    let sentinel = ipc::live_sentinels().await.into_iter().find(|r| r.id == id);
+    if let Some(target) = sentinel.as_ref().and_then(|r| r.target.as_ref()) {
+        anyhow::bail!("The vault is mounted at {}. Lock it first (`lethe daemon lock --vault {}`).", target, old);
+    }
+    let lock = VaultLock::try_acquire(&old_path, "lethe relocate")?.ok_or_else(|| vault_lock::in_use(&old_path))?;
+    if let Some(record) = &sentinel {
+        ipc::send(&record.id, Request::Panic).await.context("The vault's Sentinel didn't stop")?;
+        for _ in 0..50 {
+            if !vault_lock::pid_alive(record.pid) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        println!("Stopped the vault's Sentinel.");
+    }
+    drop(lock);
+
+    match fs::rename(&old_path, &new_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            println!("{:?} is on another disk; copying...", new_path);
+            if let Err(e) = tokio::task::block_in_place(|| copy_tree(&old_path, &new_path)) {
+                let _ = fs::remove_dir_all(&new_path);
+                return Err(e.context(format!("Failed to copy the vault to {:?}; it is still at {:?}", new_path, old_path)));
+            }
+            fs::remove_dir_all(&old_path)
+                .with_context(|| format!("The vault was copied to {:?}, but {:?} could not be removed", new_path, old_path))?;
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to move {:?} to {:?}", old_path, new_path)),
+    }
+    println!("Moved the vault to {:?}.", new_path);
+
+    // Bookkeeping must not undo the move, so problems are only reported
+    match device::relocate(&old_path, &new_path) {
+        Ok(true) => println!("   It keeps its device id."),
+        Ok(false) => {}
+        Err(e) => println!("   WARNING: The device id was not carried over ({}); changes will look like another device's.", e),
+    }
+    match keyring::relocate(&old_path, &new_path) {
+        Ok(0) => {}
+        Ok(n) => println!("   Carried over {} device key(s).", n),
+        Err(e) => println!("   WARNING: The device keys were not carried over: {:#}. Run `lethe devices join` again here.", e),
+    }
+    let _ = agent::send(AgentRequest::Forget { id: Some(id) });
+    let mut stopped = sentinel.is_some();
+    match service::relocate(&old_path, &new_path) {
+        Ok(Some(done)) => {
+            println!("   {}", done);
+            stopped = false;
+        }
+        Ok(None) => {}
+        Err(e) => {
+            println!("   WARNING: The installed service still names the old folder: {:#}", e);
+            println!("   Run `lethe daemon install --vault {}` again.", new_path.display());
+        }
+    }
+    if stopped {
+        println!("   Start its Sentinel again with `lethe daemon run --vault {}`.", new_path.display());
+    }
+    Ok(())
+}
+
+/// Copies the folder at `from` to `to`, keeping the modification times
+/// `lethe clean --grace` goes by
+fn copy_tree(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir(to).with_context(|| format!("Failed to create {:?}", to))?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let (source, target) = (entry.path(), to.join(entry.file_name()));
+        if entry.file_type()?.is_dir() {
+            copy_tree(&source, &target)?;
+            continue;
+        }
+        fs::copy(&source, &target).with_context(|| format!("Failed to copy {:?}", source))?;
+        let file = File::options().write(true).open(&target)?;
+        if let Ok(modified) = entry.metadata()?.modified() {
+            file.set_modified(modified)?;
+        }
+        file.sync_all()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,166 @@
+//! `lethe restore`: brings back a list of vault paths in one unlock, for
+//! recovery runbooks that would otherwise be hundreds of `lethe get`s.
+//!
+//! The manifest holds one vault path per line; blank lines and lines
+//! starting with `#` are skipped. A folder brings everything below it, and
+//! a path under `/.snapshots/<name>` restores from that snapshot. Files
+//! land under `--out` at their vault path, with their modified time, and
+//! are streamed a block at a time by `--jobs` threads. A file already
+//! there with the same size and time is left alone, so a run that was cut
+//! short can simply be started again.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+
+use lethe_core::index::{is_snapshot_path, is_within, FileEntry, IndexManager};
+use lethe_core::progress::{self, ProgressSink};
+use lethe_core::reader::FileReader;
+use lethe_core::storage::BlockManager;
+
+use super::ops::unlock_vault;
+use super::progress::Bar;
+
+/// The vault paths a manifest lists, each with a leading slash
+fn read_manifest(path: &Path) -> Result<Vec<String>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+    Ok(text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| format!("/{}", line.trim_matches('/')))
+        .collect())
+}
+
+/// Entries at or below `src`, by vault path
+pub(crate) fn select<'a>(index: &'a IndexManager, src: &str) -> Vec<(String, &'a FileEntry)> {
+    if is_snapshot_path(src) {
+        let Some((snapshot, inner)) = index.snapshot_for(src) else { return Vec::new() };
+        // The path of `inner` in the snapshot, as the vault shows it
+        let base = src.strip_suffix(inner.trim_end_matches('/')).unwrap_or(src).trim_end_matches('/').to_string();
+        return snapshot.files.iter()
+            .filter(|(path, e)| is_within(path, &inner) && !e.is_expired())
+            .map(|(path, e)| (format!("{}{}", base, path), e))
+            .collect();
+    }
+    index.data.files.iter()
+        .filter(|(path, e)| is_within(path, src) && !e.is_expired())
+        .map(|(path, e)| (path.clone(), e))
+        .collect()
+}
+
+/// Where the vault path `path` lands under `out`
+pub(crate) fn target(out: &Path, path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path.trim_start_matches('/'));
+    if path.contains('\\') || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        anyhow::bail!("Unsafe vault path: {}", path);
+    }
+    Ok(out.join(relative))
+}
+
+/// Already restored by an earlier run
+fn unchanged(target: &Path, entry: &FileEntry) -> bool {
+    fs::metadata(target).is_ok_and(|m| {
+        m.len() == entry.size && m.modified().ok() == Some(UNIX_EPOCH + Duration::from_secs(entry.modified))
+    })
+}
+
+pub(crate) fn restore_file(block_mgr: &BlockManager, key: &lethe_core::crypto::MasterKey, entry: &FileEntry, target: &Path, bar: &Bar) -> Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let written = (|| -> Result<()> {
+        let mut file = fs::File::create(target)?;
+        FileReader::new(block_mgr, key, entry).copy_to(&mut file, bar)?;
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(entry.modified))?;
+        Ok(())
+    })();
+    if written.is_err() {
+        // Never leave a partial file that a rerun would take for done
+        let _ = fs::remove_file(target);
+    }
+    written
+}
+
+pub fn do_restore(manifest: PathBuf, out: PathBuf, vault: String, jobs: Option<usize>) -> Result<()> {
+    let sources = read_manifest(&manifest)?;
+    if sources.is_empty() {
+        anyhow::bail!("{:?} lists no vault paths", manifest);
+    }
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    let block_mgr = BlockManager::new(&vault_path)?;
+
+    let mut missing = Vec::new();
+    let mut selected: BTreeMap<String, &FileEntry> = BTreeMap::new();
+    for src in &sources {
+        let found = select(&index_mgr, src);
+        if found.is_empty() {
+            missing.push(src.clone());
+        }
+        selected.extend(found);
+    }
+
+    let mut files = Vec::new();
+    for (path, entry) in &selected {
+        let target = target(&out, path)?;
+        if entry.is_dir {
+            fs::create_dir_all(&target).with_context(|| format!("Failed to create {:?}", target))?;
+        } else {
+            files.push((path.as_str(), *entry, target));
+        }
+    }
+    let total: u64 = files.iter().map(|(_, e, _)| e.size).sum();
+    let jobs = jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get())).clamp(1, files.len().max(1));
+    println!("Restoring {} file(s), {} in all, with {} job(s)...", files.len(), humansize::format_size(total, humansize::BINARY), jobs);
+
+    let bar = Bar::new("Restoring");
+    bar.start(total, files.len() as u64);
+    let next = AtomicUsize::new(0);
+    let skipped = AtomicUsize::new(0);
+    let failed = Mutex::new(Vec::new());
+    tokio::task::block_in_place(|| std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| while let Some((path, entry, target)) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                if bar.cancelled() {
+                    break;
+                }
+                if unchanged(target, entry) {
+                    skipped.fetch_add(1, Ordering::Relaxed);
+                    bar.advance(entry.size, 1);
+                    continue;
+                }
+                match restore_file(&block_mgr, &key, entry, target, &bar) {
+                    Ok(()) => bar.println(format_args!("   {}  ({})", path, humansize::format_size(entry.size, humansize::BINARY))),
+                    Err(e) if progress::is_cancelled(&e) => break,
+                    Err(e) => failed.lock().unwrap_or_else(|e| e.into_inner()).push(format!("{}: {:#}", path, e)),
+                }
+            });
+        }
+    }));
+
+    let cancelled = bar.cancelled();
+    drop(bar);
+    if cancelled {
+        anyhow::bail!("Cancelled; what was restored is in {:?}, and running the same restore again carries on", out);
+    }
+    let failed = failed.into_inner().unwrap_or_else(|e| e.into_inner());
+    let skipped = skipped.into_inner();
+    if skipped > 0 {
+        println!("   {} file(s) were already restored.", skipped);
+    }
+    for src in &missing {
+        println!("NOT FOUND: {}", src);
+    }
+    for failure in &failed {
+        println!("FAILED: {}", failure);
+    }
+    if !missing.is_empty() || !failed.is_empty() {
+        anyhow::bail!("{} path(s) not found and {} file(s) failed; the rest is in {:?}", missing.len(), failed.len(), out);
+    }
+    println!("Restore complete: {:?}", out);
+    Ok(())
+}
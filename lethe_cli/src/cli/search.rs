@@ -0,0 +1,104 @@
+//! `lethe index-content` and `lethe grep`: searching inside text files
+//! through the content index (`lethe_core::search`).
+//!
+//! The index says which files hold every word of the query; grep then
+//! decrypts only those and prints the lines holding the whole query,
+//! ignoring case. A hidden vault gets no content index: a file of its
+//! words in the outer folder would give it away.
+
+use anyhow::Result;
+use std::io::{BufRead, BufReader};
+
+use lethe_core::crypto::MasterKey;
+use lethe_core::index::{FileEntry, IndexManager};
+use lethe_core::reader::FileReader;
+use lethe_core::search::{self, ContentIndex};
+use lethe_core::storage::BlockManager;
+
+use super::ops::unlock_vault;
+use super::progress::Bar;
+
+/// Longest line shown, in characters
+const MAX_LINE: usize = 200;
+
+pub fn do_index_content(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    if index_mgr.is_hidden() {
+        anyhow::bail!("A hidden vault can't have a content index; it would give the hidden vault away.");
+    }
+    let storage = BlockManager::new(&vault_path)?;
+    let mut content = ContentIndex::load(&vault_path, &key)?.unwrap_or_default();
+    let bar = Bar::new("Indexing");
+    let built = tokio::task::block_in_place(|| content.update(&index_mgr, &storage, &key, &bar));
+    drop(bar);
+    let built = built?;
+    content.save(&vault_path, &key)?;
+    println!(
+        "Content index at revision {}: {} text file(s), {} read, {} unchanged, {} skipped as binary or over {}.",
+        content.revision,
+        content.text_files(),
+        built.read,
+        built.unchanged,
+        built.skipped,
+        humansize::format_size(search::MAX_TEXT, humansize::BINARY),
+    );
+    Ok(())
+}
+
+/// The lines of `entry` holding `needle`, numbered from 1; the first
+/// only if `first`
+fn matching_lines(storage: &BlockManager, key: &MasterKey, entry: &FileEntry, needle: &str, first: bool) -> Result<Vec<(usize, String)>> {
+    let mut lines = Vec::new();
+    for (n, line) in BufReader::new(FileReader::new(storage, key, entry)).split(b'\n').enumerate() {
+        let line = String::from_utf8_lossy(&line?).into_owned();
+        if line.to_lowercase().contains(needle) {
+            lines.push((n + 1, line));
+            if first {
+                break;
+            }
+        }
+    }
+    Ok(lines)
+}
+
+pub fn do_grep(query: String, vault: String, files_only: bool) -> Result<()> {
+    if search::words(&query).next().is_none() {
+        anyhow::bail!("Search for at least one word");
+    }
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    let Some(content) = ContentIndex::load(&vault_path, &key)? else {
+        anyhow::bail!("The vault has no content index yet. Build it with `lethe index-content --vault {}`.", vault);
+    };
+    if content.revision != index_mgr.data.revision {
+        println!(
+            "Note: the content index is from revision {} and the vault is at {}; run `lethe index-content` to search newer files.",
+            content.revision, index_mgr.data.revision
+        );
+    }
+    let storage = BlockManager::new(&vault_path)?;
+    let needle = query.to_lowercase();
+    let mut found = 0;
+    for path in content.candidates(&query) {
+        // Gone or changed since the index was built
+        let Some(entry) = index_mgr.get_file(path) else { continue };
+        let lines = tokio::task::block_in_place(|| matching_lines(&storage, &key, entry, &needle, files_only))?;
+        if !files_only {
+            for (n, line) in &lines {
+                let line: String = line.trim_end().chars().take(MAX_LINE).collect();
+                println!("{}:{}: {}", path, n, line);
+            }
+        }
+        if !lines.is_empty() {
+            found += 1;
+            if files_only {
+                println!("{}", path);
+            }
+        }
+    }
+    if found == 0 {
+        anyhow::bail!("No file holds {:?}", query);
+    }
+    Ok(())
+}
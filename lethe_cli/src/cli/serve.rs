@@ -0,0 +1,222 @@
+use anyhow::Result;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[cfg(any(windows, feature = "server"))]
+use anyhow::Context;
+#[cfg(any(windows, feature = "server"))]
+use crate::accounts;
+#[cfg(any(windows, feature = "server"))]
+use crate::cli::ops::{resolve_vault_path, unlock_vault};
+#[cfg(any(windows, feature = "server"))]
+use crate::dav::LetheState;
+#[cfg(any(windows, feature = "server"))]
+use lethe_core::header;
+#[cfg(any(windows, feature = "server"))]
+use lethe_core::index::IndexManager;
+#[cfg(any(windows, feature = "server"))]
+use lethe_core::storage::BlockManager;
+#[cfg(any(windows, feature = "server"))]
+use lethe_core::vault_lock::VaultLock;
+#[cfg(any(windows, feature = "server"))]
+use crate::daemon::{retention, scrub, sentinel, Activity};
+#[cfg(any(windows, feature = "server"))]
+use crate::server::{self, auth::Users, replica, session::Session, webui, ServerOptions, RESERVED_PREFIX};
+#[cfg(any(windows, feature = "server"))]
+use crate::sync::remote;
+
+/// Flags of `lethe serve`
+pub struct ServeArgs {
+    pub vault: Option<String>,
+    pub listen: String,
+    pub web_ui: bool,
+    /// Make thumbnails of images and PDFs for the web UI
+    pub previews: bool,
+    /// Accounts file for `--users`
+    pub users: Option<PathBuf>,
+    /// Serve read-only, following the vault at this remote
+    pub replica_of: Option<String>,
+    pub pull_every: Duration,
+}
+
+/// Serves a vault over HTTP without mounting it locally. It starts locked;
+/// clients unlock it with the password and can lock it again.
+#[cfg(any(windows, feature = "server"))]
+pub async fn do_serve(args: ServeArgs) -> Result<()> {
+    let addr: std::net::SocketAddr = args.listen.parse()
+        .with_context(|| format!("Invalid listen address: {}", args.listen))?;
+
+    let vault_path = resolve_vault_path(args.vault.as_deref())?;
+    if !header::exists(&vault_path) {
+        anyhow::bail!("Invalid vault path: {:?}. (Did you run 'lethe init'?)", vault_path);
+    }
+
+    if let Some(spec) = &args.replica_of {
+        // Fails early on a spec `lethe pull` wouldn't take
+        remote::open(spec, crate::sync::bwlimit::BwLimit::unlimited())?;
+    }
+
+    let session = Session::new(vault_path.clone(), Activity::new()).with_previews(args.previews);
+    let users = match &args.users {
+        Some(file) => {
+            let list = accounts::load(file)?;
+            if list.is_empty() {
+                anyhow::bail!("{:?} defines no users", file);
+            }
+            println!("Loaded {} account(s) from {:?}", list.len(), file);
+            Some(Users::new(list))
+        }
+        // A vault with users of its own never serves without them
+        None if session.has_users() => {
+            println!("Vault has users; requests need one of their accounts and follow its ACLs.");
+            Some(Users::vault(session.clone()))
+        }
+        None => None,
+    };
+    if users.is_none() && header::VaultHeader::load(&vault_path)?.wipe_after.is_some() {
+        anyhow::bail!(
+            "The vault is destroyed after too many failed unlocks, and served without accounts anyone who reaches it could spend them. Pass --users, or add vault users with `lethe users add --vault`."
+        );
+    }
+
+    if !addr.ip().is_loopback() {
+        println!("WARNING: {} is reachable from the network. Passwords and files cross it unencrypted.", addr);
+        if users.is_none() {
+            println!("WARNING: Once unlocked, anyone who can reach it has full access. Pass --users to require accounts.");
+        }
+    }
+
+    let mut routes = server::session_routes(session.clone(), ServerOptions { web_ui: args.web_ui });
+    spawn_scrub(session.clone(), vault_path.clone());
+    if let Some(spec) = &args.replica_of {
+        routes = replica::read_only(spec, routes);
+        replica::spawn_follower(session.clone(), vault_path, spec.clone(), args.pull_every);
+    } else {
+        // A replica takes the primary's snapshots with each pull
+        spawn_retention(session.clone(), vault_path);
+    }
+    if let Some(users) = users {
+        routes = server::auth::protect(users, routes);
+    }
+
+    println!("WebDAV Server running at http://{}", addr);
+    if let Some(spec) = &args.replica_of {
+        println!("Read-only replica of {}, pulled every {}s while unlocked.", spec, args.pull_every.as_secs());
+    }
+    println!("Vault is locked. Unlock with POST http://{}/{}/unlock {{\"password\": ...}}", addr, RESERVED_PREFIX);
+    if session.has_users() {
+        println!("   Vault users add their own name: {{\"user\": ..., \"password\": ...}}");
+    }
+    if args.web_ui {
+        println!("Web UI at http://{}{}", addr, webui::ui_path());
+    }
+    println!("   (Press Ctrl+C to Lock & Quit)");
+
+    let (_, server) = warp::serve(routes)
+        .try_bind_with_graceful_shutdown(addr, sentinel::shutdown_signal())
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    server.await;
+
+    session.lock().await;
+    println!("\nServer stopped.");
+    Ok(())
+}
+
+/// Checks a slice of the vault's blocks every hour the session is
+/// unlocked (see `daemon::scrub`)
+#[cfg(any(windows, feature = "server"))]
+fn spawn_scrub(session: Session, vault_path: PathBuf) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(scrub::EVERY);
+        loop {
+            ticks.tick().await;
+            let Some(state) = session.current() else { continue };
+            let (path, key) = (vault_path.clone(), state.key.clone());
+            match tokio::task::spawn_blocking(move || scrub::slice(&path, &key)).await {
+                Ok(Ok(done)) if done.damaged > 0 => log::warn!("Scrub: {} damaged block(s) so far; run `lethe repair`", done.damaged),
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => log::warn!("Scrub failed: {:#}", e),
+                Err(e) => log::warn!("Scrub failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Applies the retention policy while the session is unlocked (see
+/// `daemon::retention`). The index is at hand, so it is checked every
+/// minute rather than hourly, and soon after an unlock.
+#[cfg(any(windows, feature = "server"))]
+fn spawn_retention(session: Session, vault_path: PathBuf) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            ticks.tick().await;
+            let Some(state) = session.current() else { continue };
+            let mut index = state.index.write().await;
+            if let Err(e) = tokio::task::block_in_place(|| retention::enforce(&mut index, &vault_path, &state.key)) {
+                log::warn!("Retention failed: {:#}", e);
+            }
+        }
+    });
+}
+
+#[cfg(not(any(windows, feature = "server")))]
+pub async fn do_serve(args: ServeArgs) -> Result<()> {
+    let _ = (args.vault, args.listen, args.web_ui, args.previews, args.users, args.replica_of, args.pull_every);
+    anyhow::bail!("This build has no embedded server. Rebuild with `cargo build --features server`.")
+}
+
+/// Serves an unlocked vault as one S3 bucket until Ctrl+C
+#[cfg(any(windows, feature = "server"))]
+pub async fn do_s3_serve(vault: Option<String>, listen: String, bucket: String, access_key: String) -> Result<()> {
+    let addr: std::net::SocketAddr = listen.parse()
+        .with_context(|| format!("Invalid listen address: {}", listen))?;
+    if !server::s3::valid_bucket(&bucket) {
+        anyhow::bail!("Invalid bucket name {:?}: use 3-63 of a-z, 0-9, '.' and '-'.", bucket);
+    }
+
+    if access_key.is_empty() || access_key.contains('/') {
+        anyhow::bail!("The access key must be non-empty and can't contain '/'.");
+    }
+    let secret = match std::env::var("LETHE_S3_SECRET") {
+        Ok(secret) => secret,
+        Err(_) => tokio::task::block_in_place(|| rpassword::prompt_password("S3 Secret Key: "))?,
+    };
+    if secret.is_empty() {
+        anyhow::bail!("The S3 secret key can't be empty.");
+    }
+    let credentials = server::sigv4::Credentials { access_key, secret: zeroize::Zeroizing::new(secret) };
+
+    let vault_path = resolve_vault_path(vault.as_deref())?;
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault_path.to_string_lossy()))?;
+    let _held = VaultLock::acquire(&vault_path, "lethe s3-serve")?;
+    let index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    let block_mgr = BlockManager::new(&vault_path)?;
+    let state = LetheState::new(index_mgr, block_mgr, key, Activity::new());
+
+    if !addr.ip().is_loopback() {
+        println!("WARNING: {} is reachable from the network over plain HTTP. Requests are signed,", addr);
+        println!("WARNING: but what they carry is not encrypted; put a TLS proxy in front of it.");
+    }
+
+    println!("S3 gateway running at http://{} (bucket: {})", addr, bucket);
+    println!("   Sign requests with access key {:?}. Use path-style requests (e.g. rclone `force_path_style = true`).", credentials.access_key);
+    println!("   (Press Ctrl+C to Lock & Quit)");
+
+    let (_, server) = warp::serve(server::s3::routes(state.clone(), bucket, credentials))
+        .try_bind_with_graceful_shutdown(addr, sentinel::shutdown_signal())
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    server.await;
+
+    if let Err(e) = state.flush_index().await {
+        log::error!("Failed to save the index before stopping: {}", e);
+    }
+    println!("\nServer stopped.");
+    Ok(())
+}
+
+#[cfg(not(any(windows, feature = "server")))]
+pub async fn do_s3_serve(vault: Option<String>, listen: String, bucket: String, access_key: String) -> Result<()> {
+    let _ = (vault, listen, bucket, access_key);
+    anyhow::bail!("This build has no embedded server. Rebuild with `cargo build --features server`.")
+}
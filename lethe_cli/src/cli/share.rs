@@ -0,0 +1,90 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
+
+use lethe_core::index::{is_within, FileEntry, IndexManager};
+use lethe_core::share::{self, ShareBundle};
+use lethe_core::storage::BlockManager;
+
+use super::ops::unlock_vault;
+
+/// Entries at or below `src`, keyed by their path relative to its parent:
+/// sharing `/docs` gives `docs/...`, sharing `/docs/a.pdf` gives `a.pdf`
+fn select<'a>(index: &'a IndexManager, src: &str) -> Result<Vec<(String, &'a FileEntry)>> {
+    let src = format!("/{}", src.trim_matches('/'));
+    let parent = match src.rsplit_once('/') {
+        Some((parent, _)) => format!("{}/", parent),
+        None => "/".to_string(),
+    };
+
+    let selected: Vec<(String, &FileEntry)> = index.data.files.iter()
+        .filter(|(path, _)| is_within(path, &src))
+        .map(|(path, entry)| (path[parent.len()..].to_string(), entry))
+        .collect();
+    if selected.is_empty() {
+        anyhow::bail!("Not found in vault: {}", src);
+    }
+    Ok(selected)
+}
+
+pub fn do_share(paths: Vec<String>, out: PathBuf, vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    let block_mgr = BlockManager::new(&vault_path)?;
+
+    // Two selections may not land on the same bundle path
+    let mut entries: BTreeMap<String, &FileEntry> = BTreeMap::new();
+    for src in &paths {
+        for (path, entry) in select(&index_mgr, src)? {
+            match entries.get(&path) {
+                Some(existing) if existing.path != entry.path => {
+                    anyhow::bail!("{} and {} would both be shared as {}", existing.path, entry.path, path);
+                }
+                _ => {
+                    entries.insert(path, entry);
+                }
+            }
+        }
+    }
+    let entries: Vec<(String, &FileEntry)> = entries.into_iter().collect();
+
+    let passphrase = rpassword::prompt_password("Set Share Passphrase: ")?;
+    let confirm = rpassword::prompt_password("Confirm Passphrase: ")?;
+    if passphrase != confirm {
+        anyhow::bail!("Passphrases do not match.");
+    }
+    if passphrase.is_empty() {
+        anyhow::bail!("Passphrase cannot be empty.");
+    }
+
+    println!("Sealing {} entries...", entries.len());
+    let manifest = tokio::task::block_in_place(|| share::create(&out, &entries, &block_mgr, &key, &passphrase))?;
+    let files = manifest.entries.iter().filter(|e| !e.is_dir).count();
+    let bytes: u64 = manifest.entries.iter().map(|e| e.size).sum();
+    println!("Shared {} file(s) ({}) in {:?}", files, humansize::format_size(bytes, humansize::BINARY), out);
+    println!("   Send the passphrase separately: the bundle plus the passphrase opens these files, nothing else.");
+    Ok(())
+}
+
+pub fn do_open_share(bundle: PathBuf, out: PathBuf, list: bool) -> Result<()> {
+    let passphrase = rpassword::prompt_password("Enter Share Passphrase: ")?;
+    let share = tokio::task::block_in_place(|| ShareBundle::open(&bundle, &passphrase))?;
+
+    let created = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(share.manifest.created));
+    println!("Bundle created {}:", created);
+    for entry in &share.manifest.entries {
+        if entry.is_dir {
+            println!("   {}/", entry.path);
+        } else {
+            println!("   {}  ({})", entry.path, humansize::format_size(entry.size, humansize::BINARY));
+        }
+    }
+    if list {
+        return Ok(());
+    }
+
+    let written = share.extract(&out)?;
+    println!("Extracted {} to {:?}", humansize::format_size(written, humansize::BINARY), out);
+    Ok(())
+}
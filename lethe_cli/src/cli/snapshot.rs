@@ -0,0 +1,109 @@
+use anyhow::Result;
+use std::time::{Duration, UNIX_EPOCH};
+
+use lethe_core::index::{IndexManager, SNAPSHOT_DIR};
+use lethe_core::retention::{Retention, AUTO_PREFIX};
+
+use super::ops::unlock_vault;
+use crate::daemon::claim::claim;
+
+pub fn do_snapshot_create(vault: String, name: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let _claim = claim(&vault_path, "lethe snapshot create")?;
+    let mut index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+
+    index_mgr.create_snapshot(&name)?;
+    index_mgr.save(&key)?;
+    println!("Snapshot {} created. Browse it at {}/{} in the mount.", name, SNAPSHOT_DIR, name);
+    Ok(())
+}
+
+pub fn do_snapshot_list(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let index_mgr = IndexManager::load(vault_path, &key)?;
+
+    if index_mgr.data.snapshots.is_empty() {
+        println!("No snapshots.");
+        return Ok(());
+    }
+
+    println!("{:<24} | {:<22} | {:<8}", "NAME", "CREATED", "FILES");
+    println!("{:-<60}", "-");
+    for (name, snapshot) in &index_mgr.data.snapshots {
+        let created = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(snapshot.created));
+        let files = snapshot.files.values().filter(|e| !e.is_dir).count();
+        println!("{:<24} | {:<22} | {:<8}", name, created.to_string(), files);
+    }
+    Ok(())
+}
+
+pub fn do_snapshot_delete(vault: String, name: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let _claim = claim(&vault_path, "lethe snapshot delete")?;
+    let mut index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+
+    if !index_mgr.delete_snapshot(&name) {
+        anyhow::bail!("No snapshot named {}", name);
+    }
+    index_mgr.save(&key)?;
+    println!("Snapshot {} deleted. Run `lethe clean` to reclaim its blocks.", name);
+    Ok(())
+}
+
+/// `lethe snapshot retention`: shows the policy, or changes the parts
+/// given and shows the result
+pub fn do_snapshot_retention(vault: String, every: Option<Duration>, keep: Option<u32>, max_age: Option<Duration>) -> Result<()> {
+    if every.is_some_and(|every| !every.is_zero() && every < Duration::from_secs(60 * 60)) {
+        anyhow::bail!("Automatic snapshots are at most hourly");
+    }
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    if every.is_none() && keep.is_none() && max_age.is_none() {
+        let index_mgr = IndexManager::load(vault_path, &key)?;
+        describe(&index_mgr.data.retention);
+        return Ok(());
+    }
+
+    let _claim = claim(&vault_path, "lethe snapshot retention")?;
+    let mut index_mgr = IndexManager::load(vault_path, &key)?;
+    let mut policy = index_mgr.data.retention.clone();
+    policy.every = every.map_or(policy.every, |every| every.as_secs());
+    policy.keep = keep.unwrap_or(policy.keep);
+    policy.max_age = max_age.map_or(policy.max_age, |age| age.as_secs());
+    index_mgr.set_retention(policy)?;
+    index_mgr.save(&key)?;
+    describe(&index_mgr.data.retention);
+    if index_mgr.data.retention.is_set() {
+        println!("   The Sentinel and `lethe serve` apply it while the vault is unlocked; `lethe clean` too.");
+    }
+    Ok(())
+}
+
+fn describe(policy: &Retention) {
+    let period = |secs: u64| match secs {
+        secs if secs % 86_400 == 0 => format!("{} day(s)", secs / 86_400),
+        secs if secs % 3_600 == 0 => format!("{} hour(s)", secs / 3_600),
+        secs => humantime::format_duration(Duration::from_secs(secs)).to_string(),
+    };
+    if !policy.is_set() {
+        println!("No retention policy: snapshots are only taken and deleted by hand.");
+        return;
+    }
+    match policy.every {
+        0 => println!("No automatic snapshots."),
+        every => println!("A snapshot ({}<date>-<time>) is taken every {}, counted from midnight UTC.", AUTO_PREFIX, period(every)),
+    }
+    match (policy.keep, policy.max_age) {
+        (0, 0) => println!("Automatic snapshots are kept until deleted."),
+        (keep, 0) => println!("The newest {} automatic snapshot(s) are kept.", keep),
+        (0, age) => println!("Automatic snapshots are kept for {}.", period(age)),
+        (keep, age) => println!("The newest {} automatic snapshot(s) are kept, none longer than {}.", keep, period(age)),
+    }
+}
+
+/// A duration such as `1d`, or `off` for none
+pub fn parse_period(value: &str) -> std::result::Result<Duration, String> {
+    match value {
+        "off" => Ok(Duration::ZERO),
+        value => humantime::parse_duration(value).map_err(|e| e.to_string()),
+    }
+}
@@ -0,0 +1,270 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use lethe_core::activity::{self, Op};
+use lethe_core::crypto::MasterKey;
+use lethe_core::growth;
+use lethe_core::index::{self, IndexManager};
+use lethe_core::merge;
+use lethe_core::storage::BlockManager;
+
+use super::checkup::{self, Checkup};
+use super::ops::{unlock_outer, unlock_vault};
+use super::syncd::SyncConfig;
+use crate::sync::bwlimit::BwLimit;
+use crate::sync::health::{self, Health};
+use crate::sync::{outbox, peer, remote};
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// Days of history a forecast goes by
+const FORECAST_DAYS: u64 = 30;
+
+/// Days shown by `--history`
+const HISTORY_ROWS: usize = 60;
+
+fn size(bytes: u64) -> String {
+    humansize::format_size(bytes, humansize::BINARY)
+}
+
+fn ago(at: Option<u64>) -> String {
+    match at {
+        // To the minute; the seconds are noise at this scale
+        Some(at) => {
+            let minutes = health::now().saturating_sub(at) / 60;
+            match minutes {
+                0 => "just now".to_string(),
+                m => format!("{} ago", humantime::format_duration(Duration::from_secs(m * 60))),
+            }
+        }
+        None => "never".to_string(),
+    }
+}
+
+/// Names of the replicas set up to be synced with, as `health` names them
+fn configured(vault_path: &std::path::Path, key: &MasterKey) -> Vec<(&'static str, String)> {
+    let mut names = Vec::new();
+    let config = SyncConfig::load(vault_path).unwrap_or_default();
+    for addr in &config.peers {
+        names.push(("peer", peer::address(addr)));
+    }
+    let backup = outbox::remote(vault_path, key);
+    let specs = config.remotes.iter().map(|spec| remote::open(spec, BwLimit::unlimited()));
+    let backup = backup.map(|target| target.open(BwLimit::unlimited()));
+    for remote in specs.chain(backup).flatten() {
+        names.push(("remote", remote.describe()));
+    }
+    names
+}
+
+pub fn do_stats(vault: String, stale_after: u64, forget: Option<String>) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    if let Some(name) = forget {
+        if !health::forget(&vault_path, &key, &name)? {
+            anyhow::bail!("{} isn't tracked. See `lethe stats`.", name);
+        }
+        println!("No longer tracking {}. It is tracked again the next time it is synced with.", name);
+        return Ok(());
+    }
+
+    let index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    let live = index_mgr.data.files.iter().filter(|(path, _)| !index::is_snapshot_path(path));
+    let (mut files, mut bytes) = (0, 0);
+    for (_, entry) in live.filter(|(_, entry)| !entry.is_dir) {
+        files += 1;
+        bytes += entry.size;
+    }
+    let blocks = BlockManager::new(&vault_path)?.list_blocks()?;
+    let on_disk: u64 = blocks.iter().map(|(_, len)| len).sum();
+    let conflicts = index_mgr.data.files.keys().filter(|p| merge::conflict_original(p).is_some()).count();
+
+    println!("Vault:     {} (revision {})", vault_path.display(), index_mgr.data.revision);
+    println!("Files:     {}, {}", files, size(bytes));
+    println!("Blocks:    {} on disk, {}", blocks.len(), size(on_disk));
+    println!("Snapshots: {}", index_mgr.data.snapshots.len());
+    match conflicts {
+        0 => println!("Conflicts: none"),
+        n => println!("Conflicts: {} (see `lethe conflicts list`)", n),
+    }
+    let health = Health::load(&vault_path, &key)?;
+    if let Some(checkup) = Checkup::take(&vault_path, &key, &index_mgr)? {
+        let findings = checkup.findings(&vault);
+        println!("Health:    {}", checkup::verdict(&findings));
+        for finding in &findings {
+            println!("   {}; {}", finding.problem, finding.advice);
+        }
+        println!("Drilled:   {} (see `lethe drill`)", ago(health.drilled));
+    }
+
+    let untried: Vec<(&str, String)> = configured(&vault_path, &key).into_iter()
+        .filter(|(_, name)| !health.replicas.contains_key(name))
+        .collect();
+    println!();
+    if health.replicas.is_empty() && untried.is_empty() {
+        println!("Replicas:  none. Nothing protects this copy if the disk dies; see `lethe push` and `lethe sync-peer`.");
+        return Ok(());
+    }
+    println!("Replicas:");
+    for (name, replica) in &health.replicas {
+        let scrubbed = match replica.kind.as_str() {
+            "remote" => format!(", scrubbed {}", ago(replica.verified)),
+            _ => String::new(),
+        };
+        println!("   {:<7} {:<40} reached {}{}", replica.kind, name, ago(replica.reached), scrubbed);
+        if let Some(error) = &replica.last_error {
+            println!("   {:<7} {:<40} last try failed: {}", "", "", error);
+        }
+    }
+    for (kind, name) in &untried {
+        println!("   {:<7} {:<40} not synced with yet", kind, name);
+    }
+
+    if health::report(&vault_path, &key, stale_after) > 0 {
+        println!("   Scrub a remote with `lethe scrub <remote> --vault {}`.", vault);
+        println!("   Stop tracking one that is gone with `lethe stats --forget <name> --vault {}`.", vault);
+    }
+    Ok(())
+}
+
+/// `lethe stats --history`: the size of the vault over time and, from how
+/// fast it grew lately, when it fills `capacity`
+pub fn do_stats_history(vault: String, capacity: Option<u64>) -> Result<()> {
+    // A hidden vault's history would be a file of its own next to the outer one's
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    // The vault as it is now ends the history
+    let current = growth::measure(&index_mgr, &BlockManager::new(&vault_path)?)?;
+    growth::record(&vault_path, &key, current)?;
+    let samples = growth::load(&vault_path, &key)?;
+
+    println!("{:<22} {:>9} {:>10} {:>12} {:>12}", "TIME", "REVISION", "FILES", "SIZE", "ON DISK");
+    // A row a day at most, and the latest
+    let mut shown: Vec<&growth::Sample> = Vec::new();
+    for sample in &samples {
+        match shown.last_mut() {
+            Some(last) if last.time / DAY == sample.time / DAY => *last = sample,
+            _ => shown.push(sample),
+        }
+    }
+    for sample in shown.iter().skip(shown.len().saturating_sub(HISTORY_ROWS)) {
+        let time = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(sample.time));
+        println!("{:<22} {:>9} {:>10} {:>12} {:>12}", time, sample.revision, sample.files, size(sample.bytes), size(sample.on_disk));
+    }
+    println!();
+
+    let space = match capacity {
+        Some(capacity) => capacity,
+        None => crate::volume::space(&vault_path, current.on_disk).total(),
+    };
+    let since = current.time.saturating_sub(FORECAST_DAYS * DAY);
+    let Some(daily) = growth::daily_growth(&samples, since) else {
+        println!("Not enough history for a forecast yet. Samples are taken at most once an hour as the vault changes.");
+        return Ok(());
+    };
+    println!("Growth:    {}/day on disk, over the last {} days", signed_size(daily), FORECAST_DAYS);
+    let left = space.saturating_sub(current.on_disk);
+    let to = match capacity {
+        Some(_) => "the capacity given",
+        None => "the disk",
+    };
+    println!("Space:     {} of {} used", size(current.on_disk), size(space));
+    if daily <= 0.0 {
+        println!("Forecast:  not growing; {} left", size(left));
+    } else {
+        let days = left as f64 / daily;
+        let full = UNIX_EPOCH + Duration::from_secs(current.time) + Duration::from_secs_f64((days * DAY as f64).min(1e10));
+        let date = humantime::format_rfc3339_seconds(full).to_string();
+        println!("Forecast:  {} fills {} in about {} days ({})", size(left), to, days.round(), &date[..10]);
+    }
+    Ok(())
+}
+
+fn signed_size(bytes: f64) -> String {
+    match bytes < 0.0 {
+        true => format!("-{}", size(-bytes as u64)),
+        false => format!("+{}", size(bytes as u64)),
+    }
+}
+
+#[derive(Default)]
+struct Compression {
+    files: u64,
+    blocks: u64,
+    raw: u64,
+    stored: u64,
+}
+
+/// `lethe stats --compression`: raw against compressed size of the live
+/// files, by extension
+pub fn do_stats_compression(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let index_mgr = IndexManager::load(vault_path, &key)?;
+
+    let mut by_type: BTreeMap<String, Compression> = BTreeMap::new();
+    let mut unrecorded = 0;
+    let live = index_mgr.data.files.iter().filter(|(path, e)| !e.is_dir && !e.is_expired() && !index::is_snapshot_path(path));
+    for (path, entry) in live {
+        if entry.stored.len() != entry.blocks.len() || entry.blocks.is_empty() {
+            unrecorded += 1;
+            continue;
+        }
+        let extension = Path::new(path).extension()
+            .map(|e| format!(".{}", e.to_string_lossy().to_lowercase()))
+            .unwrap_or_else(|| "(none)".to_string());
+        let totals = by_type.entry(extension).or_default();
+        totals.files += 1;
+        totals.blocks += entry.blocks.len() as u64;
+        totals.raw += entry.size;
+        totals.stored += entry.stored.iter().sum::<u64>();
+    }
+    if by_type.is_empty() {
+        println!("No files with compression figures yet. They are recorded as files are stored.");
+        return Ok(());
+    }
+
+    let mut rows: Vec<(String, Compression)> = by_type.into_iter().collect();
+    rows.sort_by_key(|(_, row)| std::cmp::Reverse(row.raw));
+    let mut all = Compression::default();
+    for (_, row) in &rows {
+        all.files += row.files;
+        all.blocks += row.blocks;
+        all.raw += row.raw;
+        all.stored += row.stored;
+    }
+    println!("{:<12} {:>7} {:>12} {:>12} {:>7} {:>11}", "TYPE", "FILES", "SIZE", "STORED", "RATIO", "AVG BLOCK");
+    for (label, row) in rows.iter().map(|(ext, row)| (ext.as_str(), row)).chain([("all", &all)]) {
+        let ratio = match row.stored {
+            0 => "-".to_string(),
+            stored => format!("{:.2}x", row.raw as f64 / stored as f64),
+        };
+        println!("{:<12} {:>7} {:>12} {:>12} {:>7} {:>11}", label, row.files, size(row.raw), size(row.stored), ratio, size(row.raw / row.blocks.max(1)));
+    }
+    if unrecorded > 0 {
+        println!("\n{} file(s) stored before these figures were kept aren't counted.", unrecorded);
+    }
+    Ok(())
+}
+
+/// `lethe stats --activity`: the operation counters of this copy
+pub fn do_stats_activity(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let Some(counters) = activity::load(&vault_path, &key)? else {
+        println!("No activity counted yet. This copy counts from its next unlock on.");
+        return Ok(());
+    };
+    let time = |at: u64| humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(at)).to_string();
+    println!("{:<14} {:>8} {:>10} {:>12}  {:<22} {:<22}", "OPERATION", "TIMES", "FILES", "DATA", "FIRST", "LAST");
+    for op in Op::ALL {
+        let Some(counter) = counters.get(op) else { continue };
+        let (files, data) = match op {
+            Op::Put | Op::Get => (counter.files.to_string(), size(counter.bytes)),
+            Op::Clean => (format!("{} blk", counter.files), size(counter.bytes)),
+            _ => ("-".to_string(), "-".to_string()),
+        };
+        println!("{:<14} {:>8} {:>10} {:>12}  {:<22} {:<22}", op.name(), counter.count, files, data, time(counter.first), time(counter.last));
+    }
+    println!("Kept in the vault folder and never sent anywhere; each copy of the vault counts its own.");
+    Ok(())
+}
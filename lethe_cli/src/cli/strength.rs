@@ -0,0 +1,46 @@
+//! How hard a new vault password is to guess, told when it is set.
+//!
+//! Everything in a vault rests on its password: anyone with a copy of the
+//! folder can try passwords offline for as long as they like. zxcvbn rates
+//! it from 0 to 4 by the guesses it would take, counting dictionary words,
+//! names, dates, keyboard runs and the like, and the time is given at the
+//! rate of a well-equipped attacker against a slow hash such as Argon2id.
+//! Below `GOOD` there is a warning; below `password_strength` in
+//! `lethe.conf` (see `crate::settings`) the password is refused.
+
+use anyhow::Result;
+
+use crate::settings::Settings;
+
+/// The least score that gets no warning
+pub const GOOD: u8 = 3;
+
+const WORDS: [&str; 5] = ["very weak", "weak", "fair", "strong", "very strong"];
+
+/// Rates `password`, and refuses it if it is below the configured minimum
+pub fn check(password: &str) -> Result<()> {
+    let least = Settings::load()?.password_strength;
+    let estimate = zxcvbn::zxcvbn(password, &[]);
+    let score = u8::from(estimate.score());
+    println!(
+        "Password strength: {} ({} of 4). Guessing it offline would take {}.",
+        WORDS[score as usize], score, estimate.crack_times().offline_slow_hashing_1e4_per_second()
+    );
+    if score < GOOD {
+        if let Some(feedback) = estimate.feedback() {
+            if let Some(warning) = feedback.warning() {
+                println!("   {}", warning);
+            }
+            for suggestion in feedback.suggestions() {
+                println!("   {}", suggestion);
+            }
+        }
+    }
+    if score < least {
+        anyhow::bail!("The password is {}, and lethe.conf asks for at least {} of 4. Choose a stronger one.", WORDS[score as usize], least);
+    }
+    if score < GOOD {
+        println!("Warning: anyone with a copy of the vault can try passwords for as long as they like.");
+    }
+    Ok(())
+}
@@ -0,0 +1,172 @@
+use anyhow::{Context, Result};
+use std::net::SocketAddr;
+
+use super::ops::unlock_outer;
+use crate::daemon::claim::claim;
+use crate::sync::bwlimit::BwLimit;
+use crate::sync::outbox::{self, Outbox, Replay, Target};
+use crate::sync::bundle::{self, Ledger};
+use crate::sync::{health, peer, remote};
+
+/// Syncs with another machine holding the same vault: dial `peer_addr`,
+/// or wait for peers on `listen`.
+pub async fn do_sync_peer(vault: String, peer_addr: Option<String>, listen: Option<String>, bwlimit: Option<BwLimit>) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    if let Some(limit) = &bwlimit {
+        println!("Bandwidth limit: {}", limit);
+    }
+    let local = peer::Local { vault_path, key, bwlimit: bwlimit.unwrap_or_else(BwLimit::unlimited) };
+
+    match (peer_addr, listen) {
+        (Some(addr), None) => peer::dial(&addr, &local).await,
+        (None, Some(listen)) => {
+            let addr: SocketAddr = listen.parse()
+                .with_context(|| format!("Invalid listen address: {}", listen))?;
+            peer::listen(addr, &local).await
+        }
+        _ => anyhow::bail!("Give either a peer address or --listen"),
+    }
+}
+
+/// Replicates the vault to `target`, e.g. `ssh://host/backups/vault`
+pub fn do_push(target: String, index: Option<String>, vault: String, force: bool, bwlimit: Option<BwLimit>) -> Result<()> {
+    let target = Target { remote: target, index };
+    let remote = target.open(bwlimit.clone().unwrap_or_else(BwLimit::unlimited))?;
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe push")?;
+    println!("Pushing to {}...", remote.describe());
+    announce(&bwlimit);
+    remote::push(remote.as_ref(), &vault_path, &key, force)?;
+    outbox::pushed_by_hand(&vault_path, &target, &key)
+}
+
+/// Brings the vault up to date from a copy made with `do_push`
+pub fn do_pull(target: String, index: Option<String>, vault: String, force: bool, bwlimit: Option<BwLimit>) -> Result<()> {
+    let remote = remote::open_split(&target, index.as_deref(), bwlimit.clone().unwrap_or_else(BwLimit::unlimited))?;
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe pull")?;
+    println!("Pulling from {}...", remote.describe());
+    announce(&bwlimit);
+    remote::pull(remote.as_ref(), &vault_path, &key, force)
+}
+
+fn announce(bwlimit: &Option<BwLimit>) {
+    if let Some(limit) = bwlimit {
+        println!("   Bandwidth limit: {}", limit);
+    }
+}
+
+// --- Backup Remote ---
+
+pub fn do_remote_set(target: String, index: Option<String>, vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe remote set")?;
+    let target = Target { remote: target, index };
+    // Catches a mistyped spec now rather than at the first write
+    target.open(BwLimit::unlimited())?;
+    outbox::set_remote(&vault_path, &key, Some(&target))?;
+    println!("Writes to this vault now push to {}.", target);
+    println!("   The next write, or `lethe remote flush`, sends what it already holds.");
+    Ok(())
+}
+
+pub fn do_remote_show(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let Some(target) = outbox::remote(&vault_path, &key) else {
+        println!("No remote is set. Add one with `lethe remote set`.");
+        return Ok(());
+    };
+    println!("Remote: {}", target);
+    println!("   {}", Outbox::load(&vault_path, &key)?.describe());
+    Ok(())
+}
+
+pub fn do_remote_clear(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe remote clear")?;
+    if let Some(Ok(remote)) = outbox::remote(&vault_path, &key).map(|t| t.open(BwLimit::unlimited())) {
+        health::forget(&vault_path, &key, &remote.describe())?;
+    }
+    outbox::set_remote(&vault_path, &key, None)?;
+    println!("Writes no longer push anywhere.");
+    Ok(())
+}
+
+pub fn do_remote_flush(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe remote flush")?;
+    match outbox::replay(&vault_path, &key)? {
+        Replay::Off => anyhow::bail!("No remote is set. Add one with `lethe remote set`."),
+        Replay::Current => println!("Nothing is queued; the remote is up to date."),
+        Replay::Sent => {}
+        Replay::Queued(e) => anyhow::bail!("The push failed and stays queued: {}", e),
+    }
+    Ok(())
+}
+
+// --- Bundles ---
+
+pub fn do_bundle_create(since: Option<u64>, out: std::path::PathBuf, vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let header = tokio::task::block_in_place(|| bundle::create(&vault_path, &key, since, &out))?;
+    let size = std::fs::metadata(&out).map(|m| m.len()).unwrap_or(0);
+    println!("Wrote {} ({}): {}", out.display(), humansize::format_size(size, humansize::BINARY), bundle::describe(&header));
+    println!("   Apply it elsewhere with `lethe bundle apply`. Next time, `--since {}` carries only what changed after it.", header.revision);
+    Ok(())
+}
+
+pub fn do_bundle_apply(path: std::path::PathBuf, vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe bundle apply")?;
+    println!("Applying {}: {}", path.display(), bundle::describe(&bundle::inspect(&path, &key)?));
+    tokio::task::block_in_place(|| bundle::apply(&path, &vault_path, &key))?;
+    println!("Bundle applied.");
+    Ok(())
+}
+
+pub fn do_bundle_list(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let ledger = Ledger::load(&vault_path, &key)?;
+    if ledger.created.is_empty() && ledger.applied.is_empty() {
+        println!("No bundles made or applied here yet.");
+        return Ok(());
+    }
+    if !ledger.created.is_empty() {
+        let revisions: Vec<String> = ledger.created.keys().map(|r| r.to_string()).collect();
+        println!("Made here at revision: {}", revisions.join(", "));
+    }
+    for (source, revision) in &ledger.applied {
+        println!("   Applied from {}: up to revision {}", &source[..8.min(source.len())], revision);
+    }
+    Ok(())
+}
+
+// --- Scrub ---
+
+/// Checks a copy made with `do_push` without changing it
+pub fn do_scrub(target: String, index: Option<String>, vault: String, deep: bool) -> Result<()> {
+    let remote = remote::open_split(&target, index.as_deref(), BwLimit::unlimited())?;
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    println!("Scrubbing {}{}...", remote.describe(), if deep { ", reading every block" } else { "" });
+    let scrub = remote::scrub(remote.as_ref(), &vault_path, &key, deep)?;
+    if scrub.behind > 0 {
+        println!("   {} block(s) of this copy aren't there yet; the next push sends them.", scrub.behind);
+    }
+    if scrub.is_clean() {
+        println!("Scrub complete. Nothing is missing{}.", if deep { " or damaged" } else { "" });
+        return Ok(());
+    }
+
+    if scrub.missing > 0 {
+        println!("WARNING: {} block(s) its index needs are missing.", scrub.missing);
+    }
+    if !scrub.damaged.is_empty() {
+        println!("WARNING: {} block(s) don't decrypt:", scrub.damaged.len());
+        for id in &scrub.damaged {
+            println!("   blk_{}.bin", id);
+        }
+        println!("   Delete them on the remote so a push sends them again.");
+    }
+    println!("   Pull first if the remote has changes of its own, then `lethe push --force` from a healthy copy.");
+    anyhow::bail!("{} can't be pulled in full", remote.describe())
+}
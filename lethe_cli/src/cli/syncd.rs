@@ -0,0 +1,470 @@
+//! `lethe syncd`: stays resident and keeps the vault in step with local
+//! folders, peers and remotes, so nobody has to run put, sync-peer, push
+//! and pull by hand.
+//!
+//! `syncd.conf` in the vault folder lists what to keep in step, one
+//! tab-separated line each: `folder <local path> <vault path>`, `peer
+//! <address>` or `remote <spec>`. It is read again every round, so
+//! `lethe syncd folder` and friends take effect without a restart.
+//!
+//! Folders are looked at every `--interval` seconds (see `sync::folder`);
+//! peers and remotes every `--every` minutes and right after a folder
+//! brought something in. A remote is pulled, which merges, then pushed;
+//! a set backup remote (`lethe remote set`) gets its queued push too.
+//! Conflicts end up as conflict copies either way.
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, Notify};
+
+use lethe_core::crypto::MasterKey;
+use lethe_core::index::IndexManager;
+use lethe_core::merge;
+
+use super::ops::{resolve_vault_path, unlock_outer, vault_dir};
+use crate::daemon::claim::claim;
+use crate::daemon::ipc::{self, Command, Request, Response, SyncItem, SyncStatus};
+use crate::daemon::registry;
+use crate::daemon::sentinel;
+use crate::sync::bwlimit::BwLimit;
+use crate::sync::folder::{self, Folder, State};
+use crate::sync::outbox::{self, Replay};
+use crate::sync::{health, peer, remote};
+
+pub const SYNCD_FILE: &str = "syncd.conf";
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// --- Config ---
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SyncConfig {
+    pub folders: Vec<Folder>,
+    pub peers: Vec<String>,
+    pub remotes: Vec<String>,
+}
+
+impl SyncConfig {
+    pub fn load(vault: &Path) -> Result<Self> {
+        let contents = match fs::read_to_string(vault.join(SYNCD_FILE)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).context("Failed to read the sync settings"),
+        };
+        let mut config = Self::default();
+        for line in contents.lines().filter(|l| !l.trim().is_empty() && !l.starts_with('#')) {
+            match line.split('\t').collect::<Vec<_>>()[..] {
+                ["folder", local, vault] => config.folders.push(Folder { local: PathBuf::from(local), vault: vault.to_string() }),
+                ["peer", addr] => config.peers.push(addr.to_string()),
+                ["remote", spec] => config.remotes.push(spec.to_string()),
+                _ => anyhow::bail!("Invalid line in {}: {:?}", SYNCD_FILE, line),
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn save(&self, vault: &Path) -> Result<()> {
+        let path = vault.join(SYNCD_FILE);
+        if self.is_empty() {
+            if path.exists() {
+                fs::remove_file(&path).context("Failed to remove the sync settings")?;
+            }
+            return Ok(());
+        }
+        let mut contents = String::new();
+        for folder in &self.folders {
+            contents.push_str(&format!("folder\t{}\t{}\n", folder.local.display(), folder.vault));
+        }
+        for addr in &self.peers {
+            contents.push_str(&format!("peer\t{}\n", addr));
+        }
+        for spec in &self.remotes {
+            contents.push_str(&format!("remote\t{}\n", spec));
+        }
+        fs::write(path, contents).context("Failed to write the sync settings")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.folders.is_empty() && self.peers.is_empty() && self.remotes.is_empty()
+    }
+}
+
+/// `Documents/` -> `/Documents`
+fn vault_folder(path: &str) -> String {
+    format!("/{}", path.trim_matches('/'))
+}
+
+fn overlaps(a: &str, b: &str) -> bool {
+    let (a, b) = (format!("{}/", a.trim_end_matches('/')), format!("{}/", b.trim_end_matches('/')));
+    a.starts_with(&b) || b.starts_with(&a)
+}
+
+// --- Status ---
+
+type Shared = Arc<Mutex<SyncStatus>>;
+
+/// Records how the last round went for one folder, peer or remote
+fn note(status: &Shared, kind: &str, name: &str, result: &Result<String>) {
+    let mut status = status.lock().unwrap();
+    let at = match status.items.iter().position(|i| i.kind == kind && i.name == name) {
+        Some(at) => at,
+        None => {
+            status.items.push(SyncItem { kind: kind.to_string(), name: name.to_string(), converged: None, last: String::new(), failing: false });
+            status.items.len() - 1
+        }
+    };
+    let item = &mut status.items[at];
+    match result {
+        Ok(last) => {
+            item.converged = Some(now_secs());
+            item.last = last.clone();
+            item.failing = false;
+        }
+        Err(e) => {
+            warn!("Sync with {} {} failed: {:#}", kind, name, e);
+            item.last = format!("{:#}", e);
+            item.failing = true;
+        }
+    }
+}
+
+/// Drops the items no longer configured and recounts conflict copies
+fn refresh(status: &Shared, config: &SyncConfig, vault_path: &Path, key: &MasterKey) {
+    let conflicts = IndexManager::load(vault_path.to_path_buf(), key)
+        .map(|index| index.data.files.keys().filter(|p| merge::conflict_original(p).is_some()).count());
+    let mut status = status.lock().unwrap();
+    status.items.retain(|item| match item.kind.as_str() {
+        "folder" => config.folders.iter().any(|f| f.local.display().to_string() == item.name),
+        "peer" => config.peers.contains(&item.name),
+        "remote" => config.remotes.contains(&item.name) || outbox::remote(vault_path, key).is_some_and(|t| t.to_string() == item.name),
+        _ => false,
+    });
+    if let Ok(conflicts) = conflicts {
+        status.conflicts = conflicts;
+    }
+}
+
+/// Answers the control channel while rounds run
+async fn answer(mut rx: mpsc::Receiver<Command>, status: Shared, stop: Arc<Notify>) {
+    while let Some(cmd) = rx.recv().await {
+        let response = match cmd.request {
+            Request::SyncStatus => Response::Sync(status.lock().unwrap().clone()),
+            Request::Lock | Request::Panic => {
+                stop.notify_one();
+                Response::Ok("lethe syncd is stopping.".to_string())
+            }
+            _ => Response::Error("This is `lethe syncd`; it mounts nothing.".to_string()),
+        };
+        let _ = cmd.reply.send(response);
+    }
+}
+
+// --- Rounds ---
+
+fn describe(outcome: &folder::Outcome) -> String {
+    if outcome.is_empty() {
+        return "in step".to_string();
+    }
+    let mut parts = Vec::new();
+    for (count, what) in [(outcome.stored, "stored"), (outcome.written, "written out"), (outcome.deleted, "deleted")] {
+        if count > 0 {
+            parts.push(format!("{} {}", count, what));
+        }
+    }
+    if !outcome.conflicts.is_empty() {
+        parts.push(format!("{} conflict(s)", outcome.conflicts.len()));
+    }
+    parts.join(", ")
+}
+
+/// Looks for changes in every folder. Returns whether any went into the
+/// vault.
+fn folders_round(vault_path: &Path, key: &MasterKey, config: &SyncConfig, status: &Shared) -> bool {
+    let mut state = match State::load(vault_path, key) {
+        Ok(state) => state,
+        Err(e) => {
+            warn!("Folders not synced: {:#}", e);
+            return false;
+        }
+    };
+    let mut changed = false;
+    for folder in &config.folders {
+        let name = folder.local.display().to_string();
+        let result = folder::sync(vault_path, key, folder, &mut state);
+        if let Ok(outcome) = &result {
+            if !outcome.is_empty() {
+                info!("{} <-> {}: {}", name, folder.vault, describe(outcome));
+                for copy in &outcome.conflicts {
+                    println!("WARNING: {} changed on both sides. Kept the local version as {}", name, copy);
+                }
+            }
+            changed |= outcome.saved;
+        }
+        note(status, "folder", &name, &result.map(|o| describe(&o)));
+    }
+    changed
+}
+
+/// Pulls then pushes, so both copies end up the same
+fn converge(vault_path: &Path, key: &MasterKey, spec: &str) -> Result<String> {
+    let remote = remote::open(spec, BwLimit::unlimited())?;
+    let _claim = claim(vault_path, "lethe syncd")?;
+    let names = remote.list()?;
+    let before = IndexManager::load(vault_path.to_path_buf(), key)?.data.revision;
+    if remote::holds_vault(&names) {
+        remote::pull(remote.as_ref(), vault_path, key, false)?;
+    }
+    remote::push(remote.as_ref(), vault_path, key, false)?;
+    let pulled = IndexManager::load(vault_path.to_path_buf(), key)?.data.revision > before;
+    Ok(if pulled { "pulled changes, in step".to_string() } else { "in step".to_string() })
+}
+
+async fn remotes_round(local: &peer::Local, config: &SyncConfig, status: &Shared) {
+    let (vault_path, key) = (&local.vault_path, &local.key);
+    for addr in &config.peers {
+        let result = peer::dial(addr, local).await.map(|()| "in step".to_string());
+        note(status, "peer", addr, &result);
+    }
+    for spec in &config.remotes {
+        note(status, "remote", spec, &converge(vault_path, key, spec));
+    }
+    if let Some(target) = outbox::remote(vault_path, key) {
+        let result = claim(vault_path, "lethe syncd")
+            .and_then(|_claim| outbox::replay(vault_path, key))
+            .and_then(|replay| match replay {
+                Replay::Queued(e) => anyhow::bail!("push queued: {}", e),
+                _ => Ok("backup in step".to_string()),
+            });
+        note(status, "remote", &target.to_string(), &result);
+    }
+}
+
+// --- COMMAND HANDLERS ---
+
+pub async fn do_syncd_run(vault: String, interval: u64, every: u64, stale_after: u64) -> Result<()> {
+    if interval == 0 || every == 0 {
+        anyhow::bail!("--interval and --every must be at least 1");
+    }
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    if SyncConfig::load(&vault_path)?.is_empty() && outbox::remote(&vault_path, &key).is_none() {
+        anyhow::bail!("Nothing to keep in step yet. Add a folder, peer or remote with `lethe syncd folder|peer|remote`.");
+    }
+    let server = ipc::Server::bind(&registry::syncd_id(&vault_path)).await
+        .context("Is `lethe syncd` already running for this vault?")?;
+    let (tx, rx) = mpsc::channel::<Command>(8);
+    let listener = tokio::spawn(server.run(tx));
+    let status: Shared = Arc::new(Mutex::new(SyncStatus {
+        vault: vault_path.display().to_string(),
+        started: now_secs(),
+        items: Vec::new(),
+        conflicts: 0,
+    }));
+    let stop = Arc::new(Notify::new());
+    let responder = tokio::spawn(answer(rx, status.clone(), stop.clone()));
+
+    println!("lethe syncd running for {:?} (Ctrl+C to stop)", vault_path);
+    let local = peer::Local { vault_path, key, bwlimit: BwLimit::unlimited() };
+    let mut folder_tick = tokio::time::interval(Duration::from_secs(interval));
+    let mut remote_tick = tokio::time::interval(Duration::from_secs(every * 60));
+    folder_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    remote_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut warned: Option<Instant> = None;
+
+    loop {
+        tokio::select! {
+            _ = sentinel::shutdown_signal() => break,
+            _ = stop.notified() => break,
+            _ = folder_tick.tick() => {
+                let config = load_or_warn(&local.vault_path);
+                if folders_round(&local.vault_path, &local.key, &config, &status) {
+                    remotes_round(&local, &config, &status).await;
+                    remote_tick.reset();
+                }
+                refresh(&status, &config, &local.vault_path, &local.key);
+            }
+            _ = remote_tick.tick() => {
+                let config = load_or_warn(&local.vault_path);
+                remotes_round(&local, &config, &status).await;
+                // What came in goes out to the folders right away
+                folders_round(&local.vault_path, &local.key, &config, &status);
+                refresh(&status, &config, &local.vault_path, &local.key);
+                // Once a day is enough to be noticed in the log
+                if warned.is_none_or(|at| at.elapsed() >= Duration::from_secs(24 * 60 * 60))
+                    && health::report(&local.vault_path, &local.key, stale_after) > 0
+                {
+                    warned = Some(Instant::now());
+                }
+            }
+        }
+    }
+
+    listener.abort();
+    responder.abort();
+    println!("lethe syncd stopped.");
+    Ok(())
+}
+
+fn load_or_warn(vault_path: &Path) -> SyncConfig {
+    SyncConfig::load(vault_path).unwrap_or_else(|e| {
+        warn!("{:#}", e);
+        SyncConfig::default()
+    })
+}
+
+pub fn do_syncd_folder(path: PathBuf, into: Option<String>, vault: String) -> Result<()> {
+    let vault_path = vault_dir(&vault)?;
+    let local = fs::canonicalize(&path).with_context(|| format!("{:?} is not there", path))?;
+    if !local.is_dir() {
+        anyhow::bail!("{:?} is not a folder", local);
+    }
+    let vault_canonical = fs::canonicalize(&vault_path)?;
+    if local.starts_with(&vault_canonical) || vault_canonical.starts_with(&local) {
+        anyhow::bail!("{:?} overlaps the vault folder", local);
+    }
+    let into = vault_folder(&into.unwrap_or_else(|| local.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()));
+
+    let mut config = SyncConfig::load(&vault_path)?;
+    for other in &config.folders {
+        if local.starts_with(&other.local) || other.local.starts_with(&local) || overlaps(&into, &other.vault) {
+            anyhow::bail!("Overlaps the folder already kept in step: {} <-> {}", other.local.display(), other.vault);
+        }
+    }
+    println!("{} <-> {} will be kept in step.", local.display(), into);
+    config.folders.push(Folder { local, vault: into });
+    config.save(&vault_path)?;
+    println!("   Files on both sides that differ become conflict copies in the first round.");
+    Ok(())
+}
+
+pub fn do_syncd_peer(addr: String, vault: String) -> Result<()> {
+    let vault_path = vault_dir(&vault)?;
+    let mut config = SyncConfig::load(&vault_path)?;
+    if config.peers.contains(&addr) {
+        anyhow::bail!("{} is already kept in step", addr);
+    }
+    config.peers.push(addr.clone());
+    config.save(&vault_path)?;
+    println!("Peer {} will be kept in step. It needs `lethe sync-peer --listen` running.", addr);
+    Ok(())
+}
+
+pub fn do_syncd_remote(spec: String, vault: String) -> Result<()> {
+    let vault_path = vault_dir(&vault)?;
+    remote::open(&spec, BwLimit::unlimited())?;
+    let mut config = SyncConfig::load(&vault_path)?;
+    if config.remotes.contains(&spec) {
+        anyhow::bail!("{} is already kept in step", spec);
+    }
+    config.remotes.push(spec.clone());
+    config.save(&vault_path)?;
+    println!("Remote {} will be pulled from and pushed to.", spec);
+    Ok(())
+}
+
+pub fn do_syncd_forget(what: String, vault: String) -> Result<()> {
+    let vault_path = vault_dir(&vault)?;
+    let mut config = SyncConfig::load(&vault_path)?;
+    let local = fs::canonicalize(&what).ok();
+    let before = config.folders.len() + config.peers.len() + config.remotes.len();
+    let (forgotten, kept): (Vec<_>, Vec<_>) = config.folders.drain(..)
+        .partition(|f| Some(&f.local) == local.as_ref() || f.local.display().to_string() == what || f.vault == what);
+    config.folders = kept;
+    let peer = config.peers.contains(&what);
+    let remote = config.remotes.contains(&what);
+    config.peers.retain(|p| *p != what);
+    config.remotes.retain(|r| *r != what);
+    if config.folders.len() + config.peers.len() + config.remotes.len() == before {
+        anyhow::bail!("{} isn't kept in step. See `lethe syncd list`.", what);
+    }
+    // What is kept about it is sealed
+    let (_, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    config.save(&vault_path)?;
+    if peer {
+        health::forget(&vault_path, &key, &peer::address(&what))?;
+    }
+    if let Some(Ok(remote)) = remote.then(|| remote::open(&what, BwLimit::unlimited())) {
+        health::forget(&vault_path, &key, &remote.describe())?;
+    }
+
+    // The state names files; drop it along with the folder
+    if !forgotten.is_empty() {
+        let mut state = State::load(&vault_path, &key)?;
+        for folder in &forgotten {
+            state.forget(&folder.local);
+        }
+        state.save(&vault_path, &key)?;
+    }
+    println!("{} is no longer kept in step. Nothing was deleted on either side.", what);
+    Ok(())
+}
+
+pub fn do_syncd_list(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let config = SyncConfig::load(&vault_path)?;
+    let backup = outbox::remote(&vault_path, &key);
+    if config.is_empty() && backup.is_none() {
+        println!("Nothing is kept in step. Add a folder, peer or remote with `lethe syncd folder|peer|remote`.");
+        return Ok(());
+    }
+    for folder in &config.folders {
+        println!("   folder  {} <-> {}", folder.local.display(), folder.vault);
+    }
+    for addr in &config.peers {
+        println!("   peer    {}", addr);
+    }
+    for spec in &config.remotes {
+        println!("   remote  {}", spec);
+    }
+    if let Some(target) = backup {
+        println!("   remote  {} (backup, push only)", target);
+    }
+    Ok(())
+}
+
+pub async fn do_syncd_status(vault: Option<String>) -> Result<()> {
+    let vault_path = resolve_vault_path(vault.as_deref())?;
+    let response = ipc::send(&registry::syncd_id(&vault_path), Request::SyncStatus).await
+        .with_context(|| format!("lethe syncd is not running for {:?}", vault_path))?;
+    let status = match response {
+        Response::Sync(status) => status,
+        Response::Error(msg) => anyhow::bail!("{}", msg),
+        Response::Ok(_) | Response::Status(_) => anyhow::bail!("Unexpected response from lethe syncd"),
+    };
+
+    let ago = |t: u64| humantime::format_duration(Duration::from_secs(now_secs().saturating_sub(t))).to_string();
+    println!("Vault:     {}", status.vault);
+    println!("Running:   {}", ago(status.started));
+    match status.conflicts {
+        0 => println!("Conflicts: none"),
+        n => println!("Conflicts: {} (see `lethe conflicts list`)", n),
+    }
+    if status.items.is_empty() {
+        println!("   Nothing synced yet.");
+    }
+    for item in &status.items {
+        let when = item.converged.map(|t| format!("{} ago", ago(t))).unwrap_or_else(|| "never".to_string());
+        let state = if item.failing { "FAILING" } else { "ok" };
+        println!("   {:<7} {:<40} {:<8} last in step {}: {}", item.kind, item.name, state, when, item.last);
+    }
+    Ok(())
+}
+
+pub async fn do_syncd_stop(vault: Option<String>) -> Result<()> {
+    let vault_path = resolve_vault_path(vault.as_deref())?;
+    match ipc::send(&registry::syncd_id(&vault_path), Request::Lock).await
+        .with_context(|| format!("lethe syncd is not running for {:?}", vault_path))?
+    {
+        Response::Ok(msg) => {
+            println!("{}", msg);
+            Ok(())
+        }
+        Response::Error(msg) => anyhow::bail!("{}", msg),
+        Response::Status(_) | Response::Sync(_) => anyhow::bail!("Unexpected response from lethe syncd"),
+    }
+}
@@ -0,0 +1,165 @@
+use anyhow::Result;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+use lethe_core::access::{self, Access, AclRule, VaultUser};
+use lethe_core::crypto::CryptoEngine;
+use lethe_core::index::IndexManager;
+
+use super::ops::unlock_outer;
+use crate::accounts::{self, Account};
+use crate::daemon::claim::claim;
+
+pub fn do_users_add(file: PathBuf, name: String, read: Vec<String>, write: Vec<String>) -> Result<()> {
+    if !accounts::valid_name(&name) {
+        anyhow::bail!("Invalid name {:?}: use letters, digits, '-' and '_'.", name);
+    }
+    if read.is_empty() && write.is_empty() {
+        anyhow::bail!("Give the account at least one --read or --write folder.");
+    }
+    if file.exists() && accounts::load(&file)?.iter().any(|a| a.name == name) {
+        anyhow::bail!("{} already exists in {:?}", name, file);
+    }
+
+    let password = rpassword::prompt_password(format!("Set Password for {}: ", name))?;
+    let confirm = rpassword::prompt_password("Confirm Password: ")?;
+    if password != confirm {
+        anyhow::bail!("Passwords do not match.");
+    }
+    if password.is_empty() {
+        anyhow::bail!("Password cannot be empty.");
+    }
+
+    let hash = tokio::task::block_in_place(|| CryptoEngine::hash_password(&password))?;
+    let account = Account {
+        name,
+        password: hash,
+        read: read.iter().map(|p| accounts::normalize_prefix(p)).collect(),
+        write: write.iter().map(|p| accounts::normalize_prefix(p)).collect(),
+    };
+    accounts::append(&file, &account)?;
+
+    println!("Added {} to {:?}", account.name, file);
+    Ok(())
+}
+
+pub fn do_users_list(file: PathBuf) -> Result<()> {
+    let accounts = accounts::load(&file)?;
+    if accounts.is_empty() {
+        println!("No accounts in {:?}", file);
+        return Ok(());
+    }
+
+    println!("{:<16} | {:<24} | {:<24}", "USER", "READ", "WRITE");
+    println!("{:-<70}", "-");
+    for a in accounts {
+        println!("{:<16} | {:<24} | {:<24}", a.name, a.read.join(", "), a.write.join(", "));
+    }
+    Ok(())
+}
+
+// --- Vault users ---
+
+fn prompt_new_password(name: &str) -> Result<String> {
+    let password = rpassword::prompt_password(format!("Set Password for {}: ", name))?;
+    let confirm = rpassword::prompt_password("Confirm Password: ")?;
+    if password != confirm {
+        anyhow::bail!("Passwords do not match.");
+    }
+    if password.is_empty() {
+        anyhow::bail!("Password cannot be empty.");
+    }
+    Ok(password)
+}
+
+/// Adds `name` to the rule on `prefix`, creating it if needed
+fn grant(index_mgr: &mut IndexManager, prefix: &str, name: &str, access: Access) {
+    let (mut read, mut write) = match index_mgr.data.acls.get(&access::normalize(prefix)) {
+        Some(rule) if !rule.removed => (rule.read.clone(), rule.write.clone()),
+        _ => Default::default(),
+    };
+    match access {
+        Access::Read => read.insert(name.to_string()),
+        Access::Write => write.insert(name.to_string()),
+    };
+    index_mgr.set_acl(prefix, read, write);
+}
+
+pub fn do_vault_user_add(vault: String, name: String, read: Vec<String>, write: Vec<String>) -> Result<()> {
+    if !access::valid_name(&name) {
+        anyhow::bail!("Invalid name {:?}: use letters, digits, '.', '-' and '_'.", name);
+    }
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe users add")?;
+    let mut index_mgr = IndexManager::load(vault_path, &key)?;
+    if access::users(&index_mgr.data).any(|(n, _)| *n == name) {
+        anyhow::bail!("{} is already a user of this vault", name);
+    }
+
+    let password = prompt_new_password(&name)?;
+    let user = tokio::task::block_in_place(|| VaultUser::new(&password, &key))?;
+    index_mgr.set_user(&name, user);
+    for prefix in &read {
+        grant(&mut index_mgr, prefix, &name, Access::Read);
+    }
+    for prefix in &write {
+        grant(&mut index_mgr, prefix, &name, Access::Write);
+    }
+    index_mgr.save(&key)?;
+
+    println!("Added {} to the vault. They unlock `lethe serve` with their own password.", name);
+    if read.is_empty() && write.is_empty() {
+        println!("   {} can't open anything yet; grant folders with `lethe acl set`.", name);
+    }
+    Ok(())
+}
+
+pub fn do_vault_users_list(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let index_mgr = IndexManager::load(vault_path, &key)?;
+    let users: Vec<_> = access::users(&index_mgr.data).collect();
+    if users.is_empty() {
+        println!("This vault has no users.");
+        return Ok(());
+    }
+
+    println!("{:<16} | {:<24} | {:<24}", "USER", "READ", "WRITE");
+    println!("{:-<70}", "-");
+    for (name, _) in users {
+        let granted = |pick: fn(&AclRule) -> &BTreeSet<String>| -> String {
+            index_mgr.data.acls.iter()
+                .filter(|(_, rule)| !rule.removed && (pick(rule).contains(name.as_str()) || pick(rule).contains(access::EVERYONE)))
+                .map(|(prefix, _)| prefix.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        println!("{:<16} | {:<24} | {:<24}", name, granted(|r| &r.read), granted(|r| &r.write));
+    }
+    Ok(())
+}
+
+pub fn do_vault_user_remove(name: String, vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _claim = claim(&vault_path, "lethe users remove")?;
+    let mut index_mgr = IndexManager::load(vault_path, &key)?;
+    if !index_mgr.remove_user(&name) {
+        anyhow::bail!("{} is not a user of this vault", name);
+    }
+
+    // Drop them from the rules too, so a new user of that name starts clean
+    let rules: Vec<(String, AclRule)> = index_mgr.data.acls.iter()
+        .filter(|(_, rule)| !rule.removed && (rule.read.contains(&name) || rule.write.contains(&name)))
+        .map(|(prefix, rule)| (prefix.clone(), rule.clone()))
+        .collect();
+    for (prefix, mut rule) in rules {
+        rule.read.remove(&name);
+        rule.write.remove(&name);
+        index_mgr.set_acl(&prefix, rule.read, rule.write);
+    }
+    index_mgr.save(&key)?;
+
+    println!("Removed {}.", name);
+    println!("   The server no longer lets them in. Their slot held the vault key itself, so treat");
+    println!("   anything they could reach, and any copy of the vault folder they kept, as theirs.");
+    Ok(())
+}
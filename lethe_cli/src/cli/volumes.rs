@@ -0,0 +1,167 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use lethe_core::index::IndexManager;
+use lethe_core::storage::BlockManager;
+use lethe_core::vault_lock::VaultLock;
+use lethe_core::volumes::{self, Policy, Volumes};
+
+use super::ops::{unlock_outer, unlock_vault, vault_dir};
+
+/// `500G`, `1.5T`, `800M` or a plain byte count
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, unit) = match s.char_indices().find(|(_, c)| c.is_ascii_alphabetic()) {
+        Some((at, _)) => s.split_at(at),
+        None => (s, ""),
+    };
+    let scale: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("Unknown unit in {:?} (use K, M, G or T)", s)),
+    };
+    let value: f64 = number.trim().parse()
+        .map_err(|_| format!("Invalid size {:?} (e.g. 500G)", s))?;
+    if !value.is_finite() || value <= 0.0 {
+        return Err(format!("Invalid size {:?}", s));
+    }
+    Ok((value * scale as f64) as u64)
+}
+
+fn size(bytes: u64) -> String {
+    humansize::format_size(bytes, humansize::BINARY)
+}
+
+/// Changing the layout under a running mount would hide blocks from it,
+/// so these commands wait for it to stop rather than pause it
+fn hold(vault_path: &std::path::Path) -> Result<VaultLock> {
+    VaultLock::acquire(vault_path, "lethe volumes")
+        .context("Volumes can't change while the vault is in use. Unmount it first.")
+}
+
+pub fn do_volumes_add(path: PathBuf, name: Option<String>, capacity: Option<u64>, vault: String) -> Result<()> {
+    let vault_path = vault_dir(&vault)?;
+    let _held = hold(&vault_path)?;
+    let mut layout = Volumes::load(&vault_path)?;
+    let name = name.unwrap_or_else(|| {
+        path.file_name().map(|n| n.to_string_lossy().trim_start_matches('.').to_string())
+            .filter(|n| volumes::valid_id(n) && layout.get(n).is_none())
+            .unwrap_or_else(|| format!("volume{}", layout.list.len()))
+    });
+
+    let volume = layout.add(&name, &path, capacity)?;
+    println!("Added volume {:?} at {}.", volume.id, volume.path.display());
+    layout.save(&vault_path)?;
+    println!("   New blocks are spread over {} volume(s) ({}); existing blocks stay where they are.", layout.list.len(), layout.policy);
+    Ok(())
+}
+
+pub fn do_volumes_list(vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_vault(&vault))?;
+    let layout = Volumes::load(&vault_path)?;
+    let storage = BlockManager::new(&vault_path)?;
+    let mut index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    index_mgr.refresh_placement(&storage);
+
+    // Files the index places, at least in part, on each volume
+    let mut files: BTreeMap<&str, usize> = BTreeMap::new();
+    for entry in index_mgr.data.files.values() {
+        let mut on: Vec<&str> = entry.blocks.iter().filter_map(|b| index_mgr.data.placement.get(b).map(String::as_str)).collect();
+        on.sort_unstable();
+        on.dedup();
+        for volume in on {
+            *files.entry(volume).or_default() += 1;
+        }
+    }
+
+    println!("Placement: {}", layout.policy);
+    println!("Copies:    {} of each block", layout.copies);
+    for volume in &layout.list {
+        let capacity = volume.capacity.map(|c| format!(" of {}", size(c))).unwrap_or_default();
+        if layout.attached(volume) {
+            let blocks = storage.blocks_on(&volume.id);
+            let bytes: u64 = blocks.iter().map(|(_, len)| len).sum();
+            println!("   {:<12} {:<32} {} block(s), {}{}", volume.id, volume.path.display(), blocks.len(), size(bytes), capacity);
+        } else {
+            let blocks = index_mgr.data.placement.values().filter(|v| **v == volume.id).count();
+            println!("   {:<12} {:<32} DETACHED: {} block(s){}", volume.id, volume.path.display(), blocks, capacity);
+            if let Some(count) = files.get(volume.id.as_str()) {
+                println!("   {:<12} {} file(s) can't be read until it is back", "", count);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn do_volumes_remove(name: String, vault: String) -> Result<()> {
+    let (vault_path, key) = tokio::task::block_in_place(|| unlock_outer(&vault))?;
+    let _held = hold(&vault_path)?;
+    if name == volumes::MAIN {
+        anyhow::bail!("The vault folder can't be removed");
+    }
+    let mut layout = Volumes::load(&vault_path)?;
+    let volume = layout.get(&name).ok_or_else(|| anyhow::anyhow!("No volume named {:?}. See `lethe volumes list`.", name))?;
+    if !layout.attached(volume) {
+        anyhow::bail!("Volume {:?} isn't attached. Plug in {} first so its blocks can be moved.", name, volume.path.display());
+    }
+
+    let storage = BlockManager::new(&vault_path)?;
+    let (blocks, bytes) = storage.evacuate(&name)?;
+    layout.remove(&name)?;
+    layout.save(&vault_path)?;
+
+    let mut index_mgr = IndexManager::load(vault_path.clone(), &key)?;
+    if index_mgr.refresh_placement(&BlockManager::new(&vault_path)?) {
+        index_mgr.save(&key)?;
+    }
+    println!("Moved {} block(s) ({}) off {:?}; it is no longer used.", blocks, size(bytes), name);
+    Ok(())
+}
+
+pub fn do_volumes_policy(policy: Policy, vault: String) -> Result<()> {
+    let vault_path = vault_dir(&vault)?;
+    let _held = hold(&vault_path)?;
+    let mut layout = Volumes::load(&vault_path)?;
+    layout.policy = policy;
+    layout.save(&vault_path)?;
+    println!("New blocks are now placed by {}.", policy);
+    Ok(())
+}
+
+pub fn do_volumes_capacity(name: String, capacity: String, vault: String) -> Result<()> {
+    let capacity = match capacity.as_str() {
+        "off" => None,
+        s => Some(parse_size(s).map_err(anyhow::Error::msg)?),
+    };
+    let vault_path = vault_dir(&vault)?;
+    let _held = hold(&vault_path)?;
+    let mut layout = Volumes::load(&vault_path)?;
+    let volume = layout.list.iter_mut().find(|v| v.id == name)
+        .ok_or_else(|| anyhow::anyhow!("No volume named {:?}. See `lethe volumes list`.", name))?;
+    volume.capacity = capacity;
+    layout.save(&vault_path)?;
+    match capacity {
+        Some(bytes) => println!("Volume {:?} now takes up to {} of blocks.", name, size(bytes)),
+        None => println!("Volume {:?} now takes blocks without a limit.", name),
+    }
+    Ok(())
+}
+
+pub fn do_volumes_copies(copies: usize, vault: String) -> Result<()> {
+    let vault_path = vault_dir(&vault)?;
+    let _held = hold(&vault_path)?;
+    let mut layout = Volumes::load(&vault_path)?;
+    if copies > layout.list.len() {
+        anyhow::bail!("{} copies need {} volumes; the vault has {}. Add more with `lethe volumes add`.", copies, copies, layout.list.len());
+    }
+    layout.copies = copies;
+    layout.save(&vault_path)?;
+    println!("New blocks are now kept on {} volume(s).", copies);
+    if copies > 1 {
+        println!("   Run `lethe repair --vault {}` to copy the blocks already stored.", vault);
+    }
+    Ok(())
+}
@@ -0,0 +1,175 @@
+//! Containers this process has open (see `lethe_core::container`).
+//!
+//! A container is worked on as a vault folder. `resolve_vault_path` maps
+//! the file to a working folder named after it, `unlock_vault` unpacks it
+//! there, and `close_all` packs it back once the command is done. The
+//! working folder is in the runtime directory (a tmpfs on most Linux
+//! systems) and shared by every process that has the container open: each
+//! holds a shared lock on `<folder>.lock`, and the last one out removes the
+//! folder. After a crash the folder stays, and the next open carries on
+//! from it rather than from the older container.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use lethe_core::container::Container;
+use lethe_core::crypto::MasterKey;
+use lethe_core::header;
+
+struct Session {
+    file: PathBuf,
+    opened: Option<Opened>,
+}
+
+struct Opened {
+    container: Container,
+    key: MasterKey,
+    lock: File,
+    seen: Stamps,
+    /// Left behind by a crash, so possibly newer than the container
+    leftover: bool,
+}
+
+/// Size and change time of each file in a folder
+type Stamps = BTreeMap<PathBuf, (u64, Option<SystemTime>)>;
+
+/// Containers by working folder
+static SESSIONS: Mutex<BTreeMap<PathBuf, Session>> = Mutex::new(BTreeMap::new());
+
+/// The working folder for the container at `file`
+pub fn work_dir(file: &Path) -> Result<PathBuf> {
+    let file = fs::canonicalize(file).with_context(|| format!("Failed to resolve {:?}", file))?;
+    let base = dirs::runtime_dir().unwrap_or_else(std::env::temp_dir);
+    Ok(base.join(format!("lethe-{:016x}", fxhash::hash64(&file))))
+}
+
+/// Where `lethe init --container` builds the vault before packing it
+pub fn init_dir() -> PathBuf {
+    let base = dirs::runtime_dir().unwrap_or_else(std::env::temp_dir);
+    base.join(format!("lethe-init-{}", std::process::id()))
+}
+
+fn lock_path(work: &Path) -> PathBuf {
+    work.with_extension("lock")
+}
+
+/// Notes that `work` stands for the container at `file`, for `open`
+pub fn register(file: &Path, work: &Path) {
+    let mut sessions = SESSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    sessions.entry(work.to_path_buf()).or_insert_with(|| Session { file: file.to_path_buf(), opened: None });
+}
+
+/// Whether `work` stands for a container this process hasn't opened yet
+pub fn pending(work: &Path) -> bool {
+    let sessions = SESSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    sessions.get(work).is_some_and(|s| s.opened.is_none())
+}
+
+/// Opens the container `work` stands for with `password`, unpacking it
+/// into `work` unless another process already has
+pub fn open(work: &Path, password: &str) -> Result<()> {
+    let mut sessions = SESSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    let session = sessions.get_mut(work).context("Not a container")?;
+    let (container, key) = Container::open(&session.file, password)?;
+
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path(work))
+        .context("Failed to open the container lock")?;
+    let mut leftover = false;
+    // The last process out may remove the folder between our two locks
+    for _ in 0..3 {
+        // Alone with the folder: unpack it, or find what a crash left
+        match lock.try_lock() {
+            Ok(()) => {
+                leftover = work.exists();
+                let unpacked = match leftover {
+                    true => Ok(()),
+                    false => unpack(&container, &key, work),
+                };
+                lock.unlock()?;
+                unpacked?;
+            }
+            Err(TryLockError::WouldBlock) => {}
+            Err(TryLockError::Error(e)) => return Err(e).context("Failed to lock the container"),
+        }
+        lock.lock_shared().context("Failed to lock the container")?;
+        if header::exists(work) {
+            let seen = stamps(work)?;
+            session.opened = Some(Opened { container, key, lock, seen, leftover });
+            return Ok(());
+        }
+        lock.unlock()?;
+    }
+    anyhow::bail!("{:?} keeps being closed by another process", session.file)
+}
+
+/// Unpacks next to `work` first, so a crash never leaves half a vault there
+fn unpack(container: &Container, key: &MasterKey, work: &Path) -> Result<()> {
+    let partial = work.with_extension("partial");
+    let _ = fs::remove_dir_all(&partial);
+    create_private_dir(&partial)?;
+    if let Err(e) = container.extract(key, &partial) {
+        let _ = fs::remove_dir_all(&partial);
+        return Err(e);
+    }
+    fs::rename(&partial, work).context("Failed to unpack the container")
+}
+
+pub fn create_private_dir(dir: &Path) -> Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir).with_context(|| format!("Failed to create {:?}", dir))
+}
+
+fn stamps(dir: &Path) -> Result<Stamps> {
+    let mut found = Stamps::new();
+    for entry in walkdir::WalkDir::new(dir) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        // Hidden vault writes put the mtime back; the ctime still moves
+        #[cfg(unix)]
+        let changed = {
+            use std::os::unix::fs::MetadataExt;
+            let nanos = std::time::Duration::new(meta.ctime().max(0) as u64, meta.ctime_nsec().max(0) as u32);
+            Some(std::time::UNIX_EPOCH + nanos)
+        };
+        #[cfg(not(unix))]
+        let changed = meta.modified().ok();
+        found.insert(entry.path().to_path_buf(), (meta.len(), changed));
+    }
+    Ok(found)
+}
+
+/// Packs every container this process opened that changed, and removes
+/// its working folder if no other process has it open. A container that
+/// fails to pack keeps its folder, for the next open to pick up.
+pub fn close_all() {
+    let mut sessions = SESSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    for (work, session) in std::mem::take(&mut *sessions) {
+        let Some(mut opened) = session.opened else { continue };
+        let packed = match stamps(&work) {
+            Ok(now) if now == opened.seen && !opened.leftover => Ok(()),
+            _ => opened.container.pack(&opened.key, &work),
+        };
+        if let Err(e) = packed {
+            eprintln!("Failed to pack {:?}: {:#}. Its vault stays in {:?} until the next open.", session.file, e, work);
+            continue;
+        }
+        let _ = opened.lock.unlock();
+        if opened.lock.try_lock().is_ok() {
+            let _ = fs::remove_dir_all(&work);
+        }
+    }
+}
@@ -0,0 +1,423 @@
+//! `lethe agent`: keeps vault keys after one unlock, as ssh-agent does
+//! with SSH keys, so a run of CLI commands derives the key once.
+//!
+//! `unlock_vault` asks a running agent for the vault's key before it
+//! prompts, and hands the key over after a password unlock. A key is kept
+//! for `--ttl` from that unlock, then zeroed. It is held under the vault's
+//! id and header salt, so a vault initialised again at the same path
+//! misses rather than getting a stale key.
+//!
+//! Keys live in one mlocked allocation (unix) of a process that can't be
+//! ptraced or dump core (Linux). The socket in the runtime directory is
+//! 0600 and each connection's peer must be this user; on Windows the
+//! pipe's default security lets only its owner and administrators write.
+//! Containers are left out: opening one needs the password itself.
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use zeroize::Zeroize;
+
+use lethe_core::crypto::MasterKey;
+use lethe_core::header::VaultHeader;
+
+use super::{registry, sentinel};
+
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+
+/// Keys held at once
+const SLOTS: usize = 64;
+
+/// Requests are a key at most
+const MAX_FRAME: u32 = 64 * 1024;
+
+/// A client that stops talking is dropped after this
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+type KeyBytes = [u8; 32];
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(windows)]
+fn pipe_name() -> String {
+    let user = std::env::var("USERNAME").unwrap_or_default();
+    format!(r"\\.\pipe\lethe-agent-{}", user)
+}
+
+#[cfg(unix)]
+fn socket_path() -> Result<PathBuf> {
+    Ok(registry::runtime_dir()?.join("agent.sock"))
+}
+
+// --- Protocol ---
+
+#[derive(Serialize, Deserialize)]
+pub enum AgentRequest {
+    /// The key for vault `id`, if held and derived with `salt`
+    Get { id: String, salt: String },
+    Add { id: String, vault: String, salt: String, key: KeyBytes },
+    /// Drops the key for vault `id`, or every key
+    Forget { id: Option<String> },
+    List,
+    Stop,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum AgentResponse {
+    Key(KeyBytes),
+    Missing,
+    Held(Vec<HeldKey>),
+    Ok(String),
+    Error(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HeldKey {
+    pub vault: String,
+    /// Unix timestamp at which the key is dropped
+    pub expires: u64,
+}
+
+/// Wipes key bytes that passed through a request or response
+fn wipe_request(request: &mut AgentRequest) {
+    if let AgentRequest::Add { key, .. } = request {
+        key.zeroize();
+    }
+}
+
+// --- Keys ---
+
+struct Entry {
+    vault: String,
+    salt: String,
+    slot: usize,
+    expires: u64,
+}
+
+/// The keys an agent holds, in fixed slots of one locked allocation
+struct Keys {
+    slots: Box<[KeyBytes; SLOTS]>,
+    entries: HashMap<String, Entry>,
+    ttl: Duration,
+}
+
+impl Keys {
+    fn new(ttl: Duration) -> Self {
+        let slots = Box::new([[0u8; 32]; SLOTS]);
+        #[cfg(unix)]
+        {
+            // SAFETY: locks the pages of an allocation owned by `slots`
+            let locked = unsafe { libc::mlock(slots.as_ptr().cast(), std::mem::size_of::<[KeyBytes; SLOTS]>()) };
+            if locked != 0 {
+                warn!("Could not lock key memory ({}); keys may be swapped out", std::io::Error::last_os_error());
+            }
+        }
+        Self { slots, entries: HashMap::new(), ttl }
+    }
+
+    fn get(&self, id: &str, salt: &str) -> Option<KeyBytes> {
+        let entry = self.entries.get(id)?;
+        (entry.salt == salt && entry.expires > now_secs()).then(|| self.slots[entry.slot])
+    }
+
+    fn add(&mut self, id: String, vault: String, salt: String, key: &KeyBytes) -> Result<()> {
+        let slot = match self.entries.remove(&id) {
+            Some(old) => old.slot,
+            None => (0..SLOTS).find(|s| !self.entries.values().any(|e| e.slot == *s))
+                .context("The agent holds as many keys as it can; forget one first")?,
+        };
+        self.slots[slot].copy_from_slice(key);
+        let expires = now_secs() + self.ttl.as_secs().max(1);
+        self.entries.insert(id, Entry { vault, salt, slot, expires });
+        Ok(())
+    }
+
+    fn forget(&mut self, id: Option<&str>) -> usize {
+        let ids: Vec<String> = self.entries.keys().filter(|k| id.is_none_or(|id| id == *k)).cloned().collect();
+        for id in &ids {
+            if let Some(entry) = self.entries.remove(id) {
+                self.slots[entry.slot].zeroize();
+            }
+        }
+        ids.len()
+    }
+
+    /// Zeroes keys past their time
+    fn sweep(&mut self) {
+        let now = now_secs();
+        let expired: Vec<String> = self.entries.iter().filter(|(_, e)| e.expires <= now).map(|(id, _)| id.clone()).collect();
+        for id in expired {
+            info!("Key for {} expired", self.entries[&id].vault);
+            self.forget(Some(&id));
+        }
+    }
+
+    fn held(&self) -> Vec<HeldKey> {
+        let mut held: Vec<HeldKey> = self.entries.values()
+            .map(|e| HeldKey { vault: e.vault.clone(), expires: e.expires })
+            .collect();
+        held.sort_by(|a, b| a.vault.cmp(&b.vault));
+        held
+    }
+
+    /// Answers `request`; true alongside means stop
+    fn handle(&mut self, mut request: AgentRequest) -> (AgentResponse, bool) {
+        let response = match &request {
+            AgentRequest::Get { id, salt } => match self.get(id, salt) {
+                Some(key) => AgentResponse::Key(key),
+                None => AgentResponse::Missing,
+            },
+            AgentRequest::Add { id, vault, salt, key } => match self.add(id.clone(), vault.clone(), salt.clone(), key) {
+                Ok(()) => AgentResponse::Ok(format!("Holding the key for {}", vault)),
+                Err(e) => AgentResponse::Error(e.to_string()),
+            },
+            AgentRequest::Forget { id } => AgentResponse::Ok(format!("Forgot {} key(s)", self.forget(id.as_deref()))),
+            AgentRequest::List => AgentResponse::Held(self.held()),
+            AgentRequest::Stop => AgentResponse::Ok("Agent stopped; keys forgotten".to_string()),
+        };
+        let stop = matches!(request, AgentRequest::Stop);
+        wipe_request(&mut request);
+        (response, stop)
+    }
+}
+
+impl Drop for Keys {
+    fn drop(&mut self) {
+        self.slots.zeroize();
+        #[cfg(unix)]
+        // SAFETY: unlocks what `new` locked, before the allocation is freed
+        unsafe { libc::munlock(self.slots.as_ptr().cast(), std::mem::size_of::<[KeyBytes; SLOTS]>()) };
+    }
+}
+
+// --- Server ---
+
+/// Keeps other processes of this user from reading the agent's memory
+fn harden() {
+    #[cfg(target_os = "linux")]
+    // SAFETY: prctl with plain integer arguments
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+        warn!("Could not keep the agent from being traced ({})", std::io::Error::last_os_error());
+    }
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(stream: &mut R) -> Result<AgentRequest> {
+    let len = stream.read_u32().await?;
+    if len > MAX_FRAME {
+        anyhow::bail!("Agent frame too large ({} bytes)", len);
+    }
+    let mut body = vec![0u8; len as usize];
+    stream.read_exact(&mut body).await?;
+    let request = serde_cbor::from_slice(&body).context("Malformed agent request");
+    body.zeroize();
+    request
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(stream: &mut W, response: &AgentResponse) -> Result<()> {
+    let mut body = serde_cbor::to_vec(response).context("Failed to encode agent response")?;
+    let sent = async {
+        stream.write_u32(body.len() as u32).await?;
+        stream.write_all(&body).await?;
+        stream.flush().await
+    }.await;
+    body.zeroize();
+    Ok(sent?)
+}
+
+/// Serves one request from `stream`; true means stop
+async fn serve_conn<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, keys: &mut Keys) -> Result<bool> {
+    let request = tokio::time::timeout(CLIENT_TIMEOUT, read_frame(stream)).await
+        .context("Agent client went quiet")??;
+    let (mut response, stop) = keys.handle(request);
+    let sent = tokio::time::timeout(CLIENT_TIMEOUT, write_frame(stream, &response)).await;
+    if let AgentResponse::Key(key) = &mut response {
+        key.zeroize();
+    }
+    sent.context("Agent client went quiet")??;
+    Ok(stop)
+}
+
+/// Whether the peer of `stream` runs as this user
+#[cfg(unix)]
+pub(crate) fn same_user(stream: &UnixStream) -> bool {
+    // SAFETY: geteuid can't fail
+    let uid = unsafe { libc::geteuid() };
+    stream.peer_cred().map(|cred| cred.uid() == uid).unwrap_or(false)
+}
+
+/// Holds keys for `ttl` each until stopped (Ctrl+C, SIGTERM or `lethe agent stop`)
+pub async fn run(ttl: Duration) -> Result<()> {
+    harden();
+    let mut keys = Keys::new(ttl);
+    let mut sweep = tokio::time::interval(Duration::from_secs(1));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = socket_path()?;
+        if path.exists() {
+            if UnixStream::connect(&path).await.is_ok() {
+                anyhow::bail!("A Lethe agent is already running ({:?})", path);
+            }
+            // Left behind by an agent that crashed
+            std::fs::remove_file(&path).context("Failed to remove stale agent socket")?;
+        }
+        let listener = UnixListener::bind(&path).context("Failed to bind agent socket")?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        let _cleanup = RemoveOnDrop(path);
+        info!("Lethe agent listening");
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let mut stream = match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            warn!("Agent accept failed: {}", e);
+                            continue;
+                        }
+                    };
+                    if !same_user(&stream) {
+                        warn!("Refused an agent connection from another user");
+                        continue;
+                    }
+                    match serve_conn(&mut stream, &mut keys).await {
+                        Ok(true) => break,
+                        Ok(false) => {}
+                        Err(e) => debug!("Agent connection failed: {}", e),
+                    }
+                }
+                _ = sweep.tick() => keys.sweep(),
+                _ = sentinel::shutdown_signal() => break,
+            }
+        }
+    }
+
+    #[cfg(windows)]
+    {
+        let name = pipe_name();
+        let mut pipe: NamedPipeServer = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(&name)
+            .context("A Lethe agent is already running")?;
+        info!("Lethe agent listening");
+
+        loop {
+            let connected = tokio::select! {
+                connected = pipe.connect() => connected,
+                _ = sweep.tick() => {
+                    keys.sweep();
+                    continue;
+                }
+                _ = sentinel::shutdown_signal() => break,
+            };
+            let next = ServerOptions::new().create(&name).context("Failed to create next pipe instance")?;
+            let mut conn = std::mem::replace(&mut pipe, next);
+            if let Err(e) = connected {
+                warn!("Agent accept failed: {}", e);
+                continue;
+            }
+            match serve_conn(&mut conn, &mut keys).await {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) => debug!("Agent connection failed: {}", e),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+struct RemoveOnDrop(PathBuf);
+
+#[cfg(unix)]
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+// --- Client ---
+
+/// Sends one request to the running agent and waits for its answer
+pub fn send(mut request: AgentRequest) -> Result<AgentResponse> {
+    #[cfg(unix)]
+    let mut stream = {
+        let stream = std::os::unix::net::UnixStream::connect(socket_path()?)
+            .context("No running Lethe agent found")?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        stream
+    };
+
+    #[cfg(windows)]
+    let mut stream = std::fs::OpenOptions::new().read(true).write(true).open(pipe_name())
+        .context("No running Lethe agent found")?;
+
+    let mut body = serde_cbor::to_vec(&request).context("Failed to encode agent request")?;
+    wipe_request(&mut request);
+    let sent = stream.write_all(&(body.len() as u32).to_be_bytes()).and_then(|_| stream.write_all(&body));
+    body.zeroize();
+    sent?;
+
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME {
+        anyhow::bail!("Agent frame too large ({} bytes)", len);
+    }
+    let mut body = vec![0u8; len as usize];
+    stream.read_exact(&mut body)?;
+    let response = serde_cbor::from_slice(&body).context("Malformed agent response");
+    body.zeroize();
+    response
+}
+
+/// The id and header salt a vault's key is held under
+fn vault_id(vault: &Path) -> Result<(String, String)> {
+    let salt = VaultHeader::load(vault)?.salt().to_string();
+    Ok((registry::instance_id(vault), salt))
+}
+
+/// The key for `vault` from a running agent, if it holds one
+pub fn cached_key(vault: &Path) -> Option<MasterKey> {
+    let (id, salt) = vault_id(vault).ok()?;
+    match send(AgentRequest::Get { id, salt }) {
+        Ok(AgentResponse::Key(mut bytes)) => {
+            let key = MasterKey::new(bytes);
+            bytes.zeroize();
+            Some(key)
+        }
+        Ok(_) => None,
+        Err(e) => {
+            debug!("No key from the agent: {:#}", e);
+            None
+        }
+    }
+}
+
+/// Hands the key for `vault` to a running agent, if there is one
+pub fn offer_key(vault: &Path, key: &MasterKey) {
+    let Ok((id, salt)) = vault_id(vault) else { return };
+    let vault = std::fs::canonicalize(vault).unwrap_or_else(|_| vault.to_path_buf()).display().to_string();
+    match send(AgentRequest::Add { id, vault, salt, key: *key.as_bytes() }) {
+        Ok(AgentResponse::Error(msg)) => warn!("The agent didn't take the key: {}", msg),
+        Ok(_) => {}
+        Err(e) => debug!("Key not handed to an agent: {:#}", e),
+    }
+}
@@ -0,0 +1,75 @@
+//! Exclusive use of a vault for one CLI command.
+//!
+//! Whatever writes the index holds the vault lock (`lethe_core::vault_lock`).
+//! A command that finds a mount holding it asks the mount's Sentinel to
+//! step aside: the Sentinel saves, lets go of the lock and holds off the
+//! filesystem until the command is done, then reloads the index the
+//! command left. Any other holder (`lethe serve`, a sync, another command)
+//! is named in the error instead.
+
+use anyhow::Result;
+use log::{info, warn};
+use std::path::Path;
+
+use lethe_core::vault_lock::{self, VaultLock};
+
+use super::ipc::Handoff;
+use super::registry;
+
+/// Held for the length of a command. Dropping it hands a paused mount
+/// its vault back.
+pub struct Claim {
+    lock: Option<VaultLock>,
+    handoff: Option<Handoff>,
+    /// Log what the mount says rather than print it
+    quiet: bool,
+}
+
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(future))
+}
+
+/// Takes `vault` for `command` (e.g. "lethe put"), pausing its mount if
+/// one has it
+pub fn claim(vault: &Path, command: &str) -> Result<Claim> {
+    take(vault, command, false)
+}
+
+/// `claim` for a command whose stdout carries a protocol
+pub fn claim_quietly(vault: &Path, command: &str) -> Result<Claim> {
+    take(vault, command, true)
+}
+
+fn take(vault: &Path, command: &str, quiet: bool) -> Result<Claim> {
+    if let Some(lock) = VaultLock::try_acquire(vault, command)? {
+        return Ok(Claim { lock: Some(lock), handoff: None, quiet });
+    }
+
+    let Ok(handoff) = block_on(Handoff::request(&registry::instance_id(vault))) else {
+        return Err(vault_lock::in_use(vault));
+    };
+    // The Sentinel lets go before it answers; anyone else is too late
+    let Some(lock) = VaultLock::try_acquire(vault, command)? else {
+        return Err(vault_lock::in_use(vault));
+    };
+    if quiet {
+        info!("{}", handoff.message);
+    } else {
+        println!("{}", handoff.message);
+    }
+    Ok(Claim { lock: Some(lock), handoff: Some(handoff), quiet })
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        // The lock goes first, so the mount can take it straight back
+        self.lock.take();
+        if let Some(handoff) = self.handoff.take() {
+            match block_on(handoff.resume()) {
+                Ok(message) if self.quiet => info!("{}", message),
+                Ok(message) => println!("{}", message),
+                Err(e) => warn!("{}", e),
+            }
+        }
+    }
+}
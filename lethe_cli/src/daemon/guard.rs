@@ -0,0 +1,126 @@
+use log::{info, warn};
+use std::process::{Command, Stdio};
+use super::registry::MountRecord;
+
+#[cfg(unix)]
+use std::path::Path;
+
+/// Undoes what a dead Sentinel left behind: unmounts its target and, if the
+/// Sentinel created the mountpoint directory, removes it again.
+pub fn release(record: &MountRecord) {
+    let target = match &record.target {
+        Some(t) => t,
+        None => return,
+    };
+
+    if force_unmount(target) {
+        info!("Released stale mount {} ({})", target, record.vault);
+    }
+
+    #[cfg(unix)]
+    if record.owns_dir {
+        remove_mountpoint(Path::new(target));
+    }
+}
+
+fn quiet(program: &str, args: &[&str]) -> bool {
+    Command::new(program).args(args)
+        .stdout(Stdio::null()).stderr(Stdio::null())
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
+}
+
+/// Detaches `target` even if the filesystem behind it is gone
+pub fn force_unmount(target: &str) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        // A dead FUSE process leaves "Transport endpoint is not connected";
+        // the lazy variant still detaches those.
+        if quiet("fusermount", &["-u", target]) || quiet("fusermount", &["-uz", target]) {
+            return true;
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        if quiet("diskutil", &["unmount", "force", target]) {
+            return true;
+        }
+    }
+
+    #[cfg(unix)]
+    {
+        quiet("umount", &[target])
+    }
+
+    #[cfg(windows)]
+    {
+        quiet("net", &["use", target, "/delete", "/y"])
+    }
+}
+
+/// Removes a mountpoint directory we created. Only succeeds when empty,
+/// so a directory that still has files in it is never touched.
+#[cfg(unix)]
+pub fn remove_mountpoint(path: &Path) {
+    match std::fs::remove_dir(path) {
+        Ok(()) => info!("Removed mountpoint {:?}", path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Left mountpoint {:?} in place: {}", path, e),
+    }
+}
+
+/// Lethe FUSE mounts the kernel still knows about, recorded or not
+#[cfg(target_os = "linux")]
+pub fn kernel_mounts() -> Vec<String> {
+    let table = std::fs::read_to_string("/proc/self/mounts").unwrap_or_default();
+    table.lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let source = fields.next()?;
+            let target = fields.next()?;
+            (source == "lethe").then(|| unescape_mount_path(target))
+        })
+        .collect()
+}
+
+/// Lethe FUSE mounts the kernel still knows about, recorded or not
+#[cfg(target_os = "macos")]
+pub fn kernel_mounts() -> Vec<String> {
+    // Lines look like: "lethe on /Users/me/LetheMount (macfuse, ...)"
+    let output = match Command::new("mount").output() {
+        Ok(o) => String::from_utf8_lossy(&o.stdout).into_owned(),
+        Err(_) => return Vec::new(),
+    };
+    output.lines()
+        .filter_map(|line| line.strip_prefix("lethe on "))
+        .filter_map(|rest| rest.rsplit_once(" (").map(|(target, _)| target.to_string()))
+        .collect()
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+pub fn kernel_mounts() -> Vec<String> {
+    Vec::new()
+}
+
+/// /proc/mounts encodes space, tab, newline and backslash as octal escapes
+#[cfg(target_os = "linux")]
+fn unescape_mount_path(raw: &str) -> String {
+    let bytes = raw.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let digits = std::str::from_utf8(&bytes[i + 1..i + 4]).unwrap_or("");
+            if let Ok(code) = u8::from_str_radix(digits, 8) {
+                out.push(code);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
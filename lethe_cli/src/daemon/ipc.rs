@@ -0,0 +1,375 @@
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+
+use std::path::Path;
+use super::guard;
+use super::registry::{self, MountRecord};
+use super::scrub::ScrubStatus;
+use lethe_core::cache::CacheStats;
+use lethe_core::vault_lock;
+
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeServer, ServerOptions};
+
+/// Frames larger than this are rejected before allocating
+const MAX_FRAME: u32 = 1024 * 1024;
+
+#[cfg(windows)]
+fn pipe_name(id: &str) -> String {
+    format!(r"\\.\pipe\lethe-sentinel-{}", id)
+}
+
+// --- Protocol ---
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    Status,
+    Lock,
+    Unlock { password: String },
+    /// Flush and detach if `target` names this Sentinel's mountpoint or vault
+    Unmount { target: String },
+    Panic,
+    /// Save, let go of the vault lock and leave the index alone until
+    /// `Resume`, so a CLI command can write it
+    Handoff,
+    /// Reload the index and take the vault back. The control channel sends
+    /// it for the client when a handoff connection closes.
+    Resume,
+    /// What a `lethe syncd` has been doing
+    SyncStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    Status(DaemonStatus),
+    Sync(SyncStatus),
+    Ok(String),
+    Error(String),
+}
+
+/// Snapshot of a running Sentinel
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DaemonStatus {
+    pub vault: String,
+    pub mountpoint: Option<String>,
+    /// Unix timestamp of the last unlock (None while locked)
+    pub unlocked_since: Option<u64>,
+    /// Unix timestamp of the last filesystem operation
+    pub last_activity: u64,
+    /// Open files holding unsaved writes
+    pub dirty_buffers: usize,
+    /// The mount's block cache, if it has one
+    #[serde(default)]
+    pub cache: Option<CacheStats>,
+    /// The background scrub, once it has checked a slice
+    #[serde(default)]
+    pub scrub: Option<ScrubStatus>,
+}
+
+impl DaemonStatus {
+    /// Whether `target` refers to this Sentinel's mountpoint or vault
+    pub fn matches(&self, target: &str) -> bool {
+        let norm = |s: &str| {
+            let s = s.trim_end_matches(['/', '\\']).replace('\\', "/");
+            if cfg!(windows) { s.to_lowercase() } else { s }
+        };
+        let target = norm(target);
+        norm(&self.vault) == target
+            || self.mountpoint.as_deref().map(norm).as_deref() == Some(target.as_str())
+    }
+}
+
+/// Snapshot of a running `lethe syncd`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncStatus {
+    pub vault: String,
+    /// Unix timestamp of the start
+    pub started: u64,
+    /// Folders, peers and remotes, in the order they are synced
+    pub items: Vec<SyncItem>,
+    /// Conflict copies in the vault, waiting for `lethe conflicts resolve`
+    pub conflicts: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncItem {
+    /// `folder`, `peer` or `remote`
+    pub kind: String,
+    pub name: String,
+    /// Unix timestamp of the last round that went through
+    pub converged: Option<u64>,
+    /// What the last round did, or why it failed
+    pub last: String,
+    pub failing: bool,
+}
+
+/// A request handed from the listener to the Sentinel loop
+pub struct Command {
+    pub request: Request,
+    pub reply: oneshot::Sender<Response>,
+}
+
+async fn write_frame<W, T>(stream: &mut W, msg: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let body = serde_cbor::to_vec(msg).context("Failed to encode IPC message")?;
+    stream.write_u32(body.len() as u32).await?;
+    stream.write_all(&body).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_frame<R, T>(stream: &mut R) -> Result<T>
+where
+    R: AsyncRead + Unpin,
+    T: for<'de> Deserialize<'de>,
+{
+    let len = stream.read_u32().await?;
+    if len > MAX_FRAME {
+        anyhow::bail!("IPC frame too large ({} bytes)", len);
+    }
+    let mut body = vec![0u8; len as usize];
+    stream.read_exact(&mut body).await?;
+    serde_cbor::from_slice(&body).context("Malformed IPC message")
+}
+
+async fn forward(tx: &mpsc::Sender<Command>, request: Request) -> Result<Response> {
+    let (reply, rx) = oneshot::channel();
+    tx.send(Command { request, reply }).await
+        .map_err(|_| anyhow::anyhow!("Sentinel is shutting down"))?;
+
+    Ok(rx.await.unwrap_or_else(|_| Response::Error("Sentinel is shutting down".to_string())))
+}
+
+async fn serve_conn<S>(mut stream: S, tx: mpsc::Sender<Command>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request: Request = read_frame(&mut stream).await?;
+    let handoff = matches!(request, Request::Handoff);
+    let response = forward(&tx, request).await?;
+    let granted = handoff && matches!(response, Response::Ok(_));
+    let sent = write_frame(&mut stream, &response).await;
+
+    if granted {
+        // The client has the vault until it asks to resume or hangs up,
+        // crashing included
+        if sent.is_ok() {
+            let _ = read_frame::<_, Request>(&mut stream).await;
+        }
+        let response = forward(&tx, Request::Resume).await?;
+        let _ = write_frame(&mut stream, &response).await;
+    }
+    sent
+}
+
+// --- Server ---
+
+/// The listening end of the Sentinel control channel
+pub struct Server {
+    #[cfg(unix)]
+    listener: UnixListener,
+    #[cfg(unix)]
+    path: PathBuf,
+    #[cfg(windows)]
+    pipe: NamedPipeServer,
+    #[cfg(windows)]
+    name: String,
+}
+
+impl Server {
+    /// Claims the control endpoint for instance `id`.
+    /// Fails if another Sentinel already serves the same vault.
+    pub async fn bind(id: &str) -> Result<Self> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let path = socket_path(id)?;
+            if path.exists() {
+                if UnixStream::connect(&path).await.is_ok() {
+                    anyhow::bail!("This vault is already served by a running Sentinel ({:?})", path);
+                }
+                // Left behind by a crashed Sentinel
+                std::fs::remove_file(&path).context("Failed to remove stale control socket")?;
+            }
+
+            let listener = UnixListener::bind(&path).context("Failed to bind control socket")?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+            Ok(Self { listener, path })
+        }
+
+        #[cfg(windows)]
+        {
+            let name = pipe_name(id);
+            let pipe = ServerOptions::new()
+                .first_pipe_instance(true)
+                .create(&name)
+                .context("This vault is already served by a running Sentinel")?;
+            Ok(Self { pipe, name })
+        }
+    }
+
+    /// Accepts connections forever, forwarding each request to `tx`.
+    pub async fn run(self, tx: mpsc::Sender<Command>) {
+        #[cfg(unix)]
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, _)) => {
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve_conn(stream, tx).await {
+                            warn!("IPC connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("IPC accept failed: {}", e),
+            }
+        }
+
+        #[cfg(windows)]
+        {
+            let mut pipe = self.pipe;
+            loop {
+                if let Err(e) = pipe.connect().await {
+                    warn!("IPC accept failed: {}", e);
+                    continue;
+                }
+                let next = match ServerOptions::new().create(&self.name) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        warn!("Failed to create next pipe instance: {}", e);
+                        return;
+                    }
+                };
+                let conn = std::mem::replace(&mut pipe, next);
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_conn(conn, tx).await {
+                        warn!("IPC connection failed: {}", e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+fn socket_path(id: &str) -> Result<PathBuf> {
+    Ok(registry::runtime_dir()?.join(format!("{}.sock", id)))
+}
+
+// --- Client ---
+
+#[cfg(unix)]
+type ClientStream = UnixStream;
+#[cfg(windows)]
+type ClientStream = tokio::net::windows::named_pipe::NamedPipeClient;
+
+async fn connect(id: &str) -> Result<ClientStream> {
+    #[cfg(unix)]
+    let stream = UnixStream::connect(socket_path(id)?).await
+        .context("No running Lethe Sentinel found")?;
+
+    #[cfg(windows)]
+    let stream = ClientOptions::new().open(pipe_name(id))
+        .context("No running Lethe Sentinel found")?;
+
+    Ok(stream)
+}
+
+/// Sends one request to Sentinel `id` and waits for its answer.
+pub async fn send(id: &str, request: Request) -> Result<Response> {
+    let mut stream = connect(id).await?;
+    write_frame(&mut stream, &request).await?;
+    read_frame(&mut stream).await
+}
+
+/// A Sentinel standing aside for this process. Dropping it without
+/// `resume` closes the connection, which resumes it as well.
+pub struct Handoff {
+    stream: ClientStream,
+    /// What the Sentinel said when it stepped aside
+    pub message: String,
+}
+
+impl Handoff {
+    /// Asks Sentinel `id` to save and let go of its vault
+    pub async fn request(id: &str) -> Result<Self> {
+        let mut stream = connect(id).await?;
+        write_frame(&mut stream, &Request::Handoff).await?;
+        match read_frame(&mut stream).await? {
+            Response::Ok(message) => Ok(Self { stream, message }),
+            Response::Error(msg) => anyhow::bail!("{}", msg),
+            Response::Status(_) | Response::Sync(_) => anyhow::bail!("Unexpected response from Sentinel"),
+        }
+    }
+
+    /// Hands the vault back and waits for the Sentinel to reload it
+    pub async fn resume(mut self) -> Result<String> {
+        write_frame(&mut self.stream, &Request::Resume).await?;
+        match read_frame(&mut self.stream).await? {
+            Response::Ok(message) => Ok(message),
+            Response::Error(msg) => anyhow::bail!("{}", msg),
+            Response::Status(_) | Response::Sync(_) => anyhow::bail!("Unexpected response from Sentinel"),
+        }
+    }
+}
+
+/// Registered Sentinels that still answer. Records left by crashed
+/// processes are pruned and whatever they had mounted is released.
+pub async fn live_sentinels() -> Vec<MountRecord> {
+    let mut live = Vec::new();
+    for record in registry::list() {
+        match send(&record.id, Request::Status).await {
+            Ok(_) => live.push(record),
+            // Alive but unreachable (control channel disabled): leave it be
+            Err(_) if vault_lock::pid_alive(record.pid) => {}
+            Err(_) => {
+                guard::release(&record);
+                registry::forget(&record.id);
+                #[cfg(unix)]
+                if let Ok(path) = socket_path(&record.id) {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+        }
+    }
+    live
+}
+
+/// Picks the Sentinel serving `vault`, or the only one running if omitted.
+pub async fn find_sentinel(vault: Option<&Path>) -> Result<MountRecord> {
+    let live = live_sentinels().await;
+    if let Some(vault) = vault {
+        let id = registry::instance_id(vault);
+        return live.into_iter().find(|r| r.id == id)
+            .with_context(|| format!("No running Lethe Sentinel for {:?}", vault));
+    }
+
+    match live.len() {
+        0 => anyhow::bail!("No running Lethe Sentinel found"),
+        1 => Ok(live.into_iter().next().unwrap()),
+        _ => {
+            let vaults: Vec<_> = live.iter().map(|r| r.vault.as_str()).collect();
+            anyhow::bail!("Several Sentinels are running ({}); pass --vault", vaults.join(", "))
+        }
+    }
+}
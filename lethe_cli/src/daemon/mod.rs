@@ -0,0 +1,11 @@
+pub mod agent;
+pub mod claim;
+pub mod guard;
+pub mod ipc;
+pub mod registry;
+pub mod retention;
+pub mod scrub;
+pub mod sentinel;
+pub mod service;
+
+pub use sentinel::{Activity, SentinelConfig};
@@ -0,0 +1,129 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// One running Sentinel, as recorded in the runtime directory
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MountRecord {
+    /// Stable per-vault id; also names the control socket/pipe
+    pub id: String,
+    pub pid: u32,
+    pub vault: String,
+    /// Drive letter or mountpoint while unlocked
+    pub target: Option<String>,
+    /// Loopback WebDAV port (Windows only)
+    pub port: Option<u16>,
+    /// The Sentinel created the mountpoint directory and removes it again
+    #[serde(default)]
+    pub owns_dir: bool,
+}
+
+/// Directory holding registry records and control sockets
+pub fn runtime_dir() -> Result<PathBuf> {
+    let dir = dirs::runtime_dir()
+        .map(|p| p.join("lethe"))
+        .or_else(|| dirs::home_dir().map(|p| p.join(".lethe").join("run")))
+        .context("Could not determine runtime directory")?;
+    fs::create_dir_all(&dir).context("Failed to create runtime directory")?;
+    Ok(dir)
+}
+
+/// Derives the instance id from the vault's absolute path, so the same
+/// vault always maps to the same Sentinel.
+pub fn instance_id(vault: &Path) -> String {
+    let canonical = fs::canonicalize(vault).unwrap_or_else(|_| vault.to_path_buf());
+    format!("{:016x}", fxhash::hash64(&canonical.to_string_lossy().to_lowercase()))
+}
+
+/// Names the control socket of the `lethe syncd` for `vault`, apart from
+/// its Sentinel's
+pub fn syncd_id(vault: &Path) -> String {
+    format!("{}-sync", instance_id(vault))
+}
+
+fn record_path(id: &str) -> Result<PathBuf> {
+    Ok(runtime_dir()?.join(format!("{}.mount", id)))
+}
+
+/// All recorded Sentinels. Records may be stale if a process crashed;
+/// callers that fail to reach one should `forget` it.
+pub fn list() -> Vec<MountRecord> {
+    let dir = match runtime_dir() {
+        Ok(d) => d,
+        Err(_) => return Vec::new(),
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut records: Vec<MountRecord> = entries
+        .flatten()
+        .filter(|e| e.path().extension().map(|x| x == "mount").unwrap_or(false))
+        .filter_map(|e| fs::read(e.path()).ok())
+        .filter_map(|bytes| serde_cbor::from_slice(&bytes).ok())
+        .collect();
+    records.sort_by(|a, b| a.vault.cmp(&b.vault));
+    records
+}
+
+/// Removes a stale record
+pub fn forget(id: &str) {
+    if let Ok(path) = record_path(id) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Mountpoints and drive letters claimed by other Sentinels
+pub fn claimed_targets() -> HashSet<String> {
+    list().into_iter().filter_map(|r| r.target).collect()
+}
+
+/// Ports claimed by other Sentinels
+#[cfg(windows)]
+pub fn claimed_ports() -> HashSet<u16> {
+    list().into_iter().filter_map(|r| r.port).collect()
+}
+
+/// This process's registry entry. Removed again on drop.
+#[derive(Debug)]
+pub struct Registration {
+    path: PathBuf,
+    pub record: MountRecord,
+}
+
+impl Registration {
+    pub fn create(id: &str, vault: &Path) -> Result<Self> {
+        let record = MountRecord {
+            id: id.to_string(),
+            pid: std::process::id(),
+            vault: vault.display().to_string(),
+            target: None,
+            port: None,
+            owns_dir: false,
+        };
+        let reg = Self { path: record_path(id)?, record };
+        reg.write()?;
+        Ok(reg)
+    }
+
+    pub fn set_mounted(&mut self, target: Option<String>, port: Option<u16>, owns_dir: bool) -> Result<()> {
+        self.record.target = target;
+        self.record.port = port;
+        self.record.owns_dir = owns_dir;
+        self.write()
+    }
+
+    fn write(&self) -> Result<()> {
+        let bytes = serde_cbor::to_vec(&self.record)?;
+        fs::write(&self.path, bytes).context("Failed to write mount registry")
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,59 @@
+//! Applying the vault's retention policy (see `lethe_core::retention`)
+//! while it is unlocked: the Sentinel and `lethe serve` check every hour
+//! whether an automatic snapshot is due or one has aged out, take or
+//! delete it, and delete the blocks nothing uses any more. The mount
+//! stands aside meanwhile, as it does for a push.
+
+use anyhow::Result;
+use log::info;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lethe_core::crypto::MasterKey;
+use lethe_core::index::IndexManager;
+use lethe_core::retention;
+use lethe_core::storage::BlockManager;
+
+/// How often the policy is checked
+pub const EVERY: Duration = Duration::from_secs(60 * 60);
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Whether the index on disk has anything due, so a mount only stands
+/// aside when there is
+pub fn pending(vault: &Path, key: &MasterKey) -> Result<bool> {
+    let index_mgr = IndexManager::load_read_only(vault.to_path_buf(), key)?;
+    Ok(!index_mgr.is_hidden() && !retention::plan(&index_mgr.data, now_secs()).is_empty())
+}
+
+/// Carries out what the policy has due in `index_mgr`, saves it and
+/// deletes the blocks only dropped snapshots held. The caller holds the
+/// vault.
+pub fn enforce(index_mgr: &mut IndexManager, vault: &Path, key: &MasterKey) -> Result<()> {
+    if index_mgr.is_hidden() {
+        return Ok(());
+    }
+    let plan = retention::plan(&index_mgr.data, now_secs());
+    if plan.is_empty() {
+        return Ok(());
+    }
+    let freed = retention::apply(index_mgr, &plan)?;
+    // The index lets go of the blocks before they are gone
+    index_mgr.save(key)?;
+    if let Some(name) = &plan.take {
+        info!("Retention: took snapshot {}", name);
+    }
+    for name in &plan.drop {
+        info!("Retention: deleted snapshot {}", name);
+    }
+    let storage = BlockManager::new(vault)?;
+    for id in &freed {
+        storage.delete_block(id)?;
+    }
+    if !freed.is_empty() {
+        info!("Retention: deleted {} block(s) only those snapshots held", freed.len());
+    }
+    Ok(())
+}
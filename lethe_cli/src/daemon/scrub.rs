@@ -0,0 +1,164 @@
+//! The background scrub: while a vault is unlocked, the Sentinel and
+//! `lethe serve` check a slice of its blocks every hour, so bit rot on a
+//! vault nobody runs `lethe repair` on still shows up.
+//!
+//! Each slice opens every attached copy of the blocks after the cursor in
+//! `replicas.bin` (see `sync::health::Scrub`), one at a time with a pause
+//! in between so the mount keeps the disk. A pass over every block takes
+//! about a week, in step with `health::STALE_DAYS`. Damage found is
+//! logged, audited once per block, and kept until a slice finds the block
+//! whole again; a pass that ends with none counts as a verify for
+//! `lethe checkup`. A block written during a pass may wait for the next.
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use lethe_core::audit::{self, Operation};
+use lethe_core::crypto::MasterKey;
+use lethe_core::index::IndexManager;
+use lethe_core::storage::BlockManager;
+
+use crate::sync::health::{self, Health};
+
+/// How long after an unlock the first slice waits
+const FIRST: Duration = Duration::from_secs(5 * 60);
+
+/// How often a slice is checked
+pub const EVERY: Duration = Duration::from_secs(60 * 60);
+
+/// Slices one pass is spread over: a week of hours
+const SLICES: usize = health::STALE_DAYS as usize * 24;
+
+/// Fewest blocks a slice checks, so a small vault finishes sooner
+const MIN_SLICE: usize = 64;
+
+/// Pause between two blocks
+const BREATHER: Duration = Duration::from_millis(20);
+
+/// Where the scrub is, for `lethe daemon status`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScrubStatus {
+    /// Blocks the vault holds, and how many of them this pass has checked
+    pub blocks: usize,
+    pub checked: usize,
+    /// Blocks found damaged or missing and not yet whole again
+    pub damaged: usize,
+    /// Unix timestamp of the last pass that reached the end
+    pub finished: Option<u64>,
+}
+
+/// The scrub of an unlocked vault, stopped when dropped
+pub struct Scrubber {
+    task: JoinHandle<()>,
+    status: watch::Receiver<Option<ScrubStatus>>,
+}
+
+impl Scrubber {
+    pub fn start(vault: PathBuf, key: Arc<MasterKey>) -> Self {
+        let (tx, status) = watch::channel(None);
+        Self { task: tokio::spawn(every(vault, key, tx)), status }
+    }
+
+    /// Where the last slice left it, None before the first
+    pub fn status(&self) -> Option<ScrubStatus> {
+        self.status.borrow().clone()
+    }
+}
+
+impl Drop for Scrubber {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Checks a slice every hour for as long as the task runs, sending where
+/// it got to `status`
+async fn every(vault: PathBuf, key: Arc<MasterKey>, status: watch::Sender<Option<ScrubStatus>>) {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + FIRST, EVERY);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticks.tick().await;
+        let (path, key) = (vault.clone(), key.clone());
+        match tokio::task::spawn_blocking(move || slice(&path, &key)).await {
+            Ok(Ok(done)) => {
+                status.send_replace(Some(done));
+            }
+            Ok(Err(e)) => warn!("Scrub failed: {:#}", e),
+            Err(e) => warn!("Scrub failed: {}", e),
+        }
+    }
+}
+
+/// Checks the next slice of the blocks the index on disk uses
+pub fn slice(vault: &Path, key: &MasterKey) -> Result<ScrubStatus> {
+    // The mount holds its own; this one is only read
+    let index_mgr = IndexManager::load_read_only(vault.to_path_buf(), key)?;
+    // Padding never opens, and a hidden vault's blocks are padding here
+    let mut blocks: Vec<String> = index_mgr.referenced_blocks().into_iter()
+        .filter(|id| !index_mgr.data.padding.contains(*id))
+        .map(str::to_string)
+        .collect();
+    blocks.sort_unstable();
+
+    let storage = BlockManager::new(vault)?;
+    let mut health = Health::load(vault, key)?;
+    let scrub = &mut health.scrub;
+    let now = health::now();
+    let from = match &scrub.cursor {
+        Some(cursor) => blocks.partition_point(|id| id <= cursor),
+        None => 0,
+    };
+    // Blocks no longer used aren't damage anyone loses
+    scrub.damaged.retain(|id, _| blocks.binary_search(id).is_ok());
+
+    let take = blocks.len().div_ceil(SLICES).max(MIN_SLICE);
+    let to = (from + take).min(blocks.len());
+    for id in &blocks[from..to] {
+        std::thread::sleep(BREATHER);
+        let (good, bad) = storage.check_block(id, key);
+        let found = match (good, bad.is_empty()) {
+            (_, false) => format!("damaged on volume(s) {}", bad.join(", ")),
+            // Possibly on a volume that isn't attached
+            (0, true) if !storage.detached().is_empty() => continue,
+            (0, true) => "missing".to_string(),
+            (_, true) => {
+                if scrub.damaged.remove(id).is_some() {
+                    info!("Scrub: block {} is whole again", id);
+                }
+                continue;
+            }
+        };
+        if scrub.damaged.contains_key(id) {
+            continue;
+        }
+        warn!("Scrub: block {} is {}; `lethe repair` rewrites it from a good copy, `lethe pull` fetches it from a remote", id, found);
+        scrub.damaged.insert(id.clone(), now);
+        let _ = audit::record(vault, key, Operation::BlockDamage { block: id.clone(), found });
+    }
+
+    if to < blocks.len() {
+        scrub.cursor = Some(blocks[to - 1].clone());
+    } else {
+        scrub.cursor = None;
+        scrub.finished = Some(now);
+        if scrub.damaged.is_empty() {
+            health.verified = Some(now);
+        } else {
+            warn!("Scrub: pass finished with {} damaged block(s)", scrub.damaged.len());
+        }
+    }
+    let status = ScrubStatus {
+        blocks: blocks.len(),
+        checked: to,
+        damaged: health.scrub.damaged.len(),
+        finished: health.scrub.finished,
+    };
+    health.save(vault, key)?;
+    Ok(status)
+}
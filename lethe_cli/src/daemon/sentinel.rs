@@ -0,0 +1,293 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{debug, info, warn};
+use tokio::sync::watch;
+
+/// How often the Sentinel checks the lock triggers
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// If the wall clock jumps this much further than the monotonic clock
+/// between two polls, the machine was suspended in between.
+const SUSPEND_SLACK: Duration = Duration::from_secs(30);
+
+/// Why the Sentinel decided to lock the vault
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockReason {
+    Idle,
+    SessionLocked,
+    Suspended,
+}
+
+impl fmt::Display for LockReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockReason::Idle => write!(f, "idle timeout reached"),
+            LockReason::SessionLocked => write!(f, "workstation locked"),
+            LockReason::Suspended => write!(f, "system resumed from sleep"),
+        }
+    }
+}
+
+/// Which triggers the Sentinel listens to
+#[derive(Debug, Clone, Copy)]
+pub struct SentinelConfig {
+    /// Lock after this long without filesystem activity (None = never)
+    pub idle_timeout: Option<Duration>,
+    /// Lock when the OS session locks or the machine sleeps
+    pub watch_session: bool,
+}
+
+impl SentinelConfig {
+    /// Builds the config from the `--idle-timeout` / `--no-auto-lock` flags
+    pub fn from_args(idle_minutes: Option<u64>, no_auto_lock: bool) -> Self {
+        Self {
+            idle_timeout: idle_minutes.filter(|m| *m > 0).map(|m| Duration::from_secs(m * 60)),
+            watch_session: !no_auto_lock,
+        }
+    }
+}
+
+/// Usage gauges shared between the mount layer and the Sentinel.
+#[derive(Debug, Clone)]
+pub struct Activity(Arc<ActivityInner>);
+
+#[derive(Debug)]
+struct ActivityInner {
+    /// Unix timestamp of the last filesystem operation
+    last: AtomicU64,
+    /// Open files holding unsaved writes
+    dirty: AtomicUsize,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self(Arc::new(ActivityInner {
+            last: AtomicU64::new(now_secs()),
+            dirty: AtomicUsize::new(0),
+        }))
+    }
+
+    /// Record that the mount was just used
+    pub fn touch(&self) {
+        self.0.last.store(now_secs(), Ordering::Relaxed);
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        Duration::from_secs(now_secs().saturating_sub(self.0.last.load(Ordering::Relaxed)))
+    }
+
+    pub fn last_active(&self) -> u64 {
+        self.0.last.load(Ordering::Relaxed)
+    }
+
+    pub fn add_dirty(&self) {
+        self.0.dirty.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn remove_dirty(&self) {
+        let _ = self.0.dirty.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    }
+
+    pub fn dirty(&self) -> usize {
+        self.0.dirty.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Resolves once any enabled lock trigger fires.
+pub async fn watch(config: SentinelConfig, activity: Activity) -> LockReason {
+    let mut ticker = tokio::time::interval(POLL_INTERVAL);
+    let mut last_wall = SystemTime::now();
+    let mut last_mono = Instant::now();
+
+    loop {
+        ticker.tick().await;
+
+        if config.watch_session {
+            // Monotonic time stops while suspended, wall time does not
+            let wall_delta = SystemTime::now().duration_since(last_wall).unwrap_or_default();
+            let mono_delta = last_mono.elapsed();
+            if wall_delta > mono_delta + SUSPEND_SLACK {
+                return LockReason::Suspended;
+            }
+
+            if session_locked().await {
+                return LockReason::SessionLocked;
+            }
+        }
+        last_wall = SystemTime::now();
+        last_mono = Instant::now();
+
+        if let Some(limit) = config.idle_timeout {
+            if activity.idle_for() >= limit {
+                return LockReason::Idle;
+            }
+        }
+    }
+}
+
+/// Set once the process is asked to stop; see `shutdown_signal`
+static SHUTDOWN: OnceLock<watch::Sender<bool>> = OnceLock::new();
+
+/// Resolves once the process is asked to stop: Ctrl+C, and on Unix also
+/// SIGTERM, SIGHUP and SIGQUIT, on Windows closing the console, logging
+/// off or shutting down (which is also what `taskkill` without `/F`
+/// sends). A service manager stopping us or a closed terminal then still
+/// saves, unmounts and wipes the key.
+///
+/// The handlers are installed on the first call and stay for the life of
+/// the process, so a signal that arrives while the caller is busy between
+/// two waits isn't lost: every later call resolves at once. A second
+/// signal while shutting down quits on the spot, for a flush that hangs.
+pub async fn shutdown_signal() {
+    let mut stopping = SHUTDOWN.get_or_init(listen).subscribe();
+    let _ = stopping.wait_for(|stop| *stop).await;
+}
+
+fn listen() -> watch::Sender<bool> {
+    let (tx, _) = watch::channel(false);
+    match signals() {
+        Ok(mut next) => {
+            tokio::spawn(async move {
+                let name = next.recv().await;
+                info!("Shutting down on {}", name);
+                if let Some(tx) = SHUTDOWN.get() {
+                    tx.send_replace(true);
+                }
+                let name = next.recv().await;
+                warn!("{} while shutting down; quitting without cleanup", name);
+                std::process::exit(130);
+            });
+        }
+        Err(e) => warn!("Termination signals not handled: {}", e),
+    }
+    tx
+}
+
+/// The signals that stop the process, registered as soon as this returns
+struct Signals {
+    #[cfg(unix)]
+    unix: [tokio::signal::unix::Signal; 4],
+    #[cfg(windows)]
+    windows: (
+        tokio::signal::windows::CtrlC,
+        tokio::signal::windows::CtrlBreak,
+        tokio::signal::windows::CtrlClose,
+        tokio::signal::windows::CtrlLogoff,
+        tokio::signal::windows::CtrlShutdown,
+    ),
+}
+
+fn signals() -> std::io::Result<Signals> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        Ok(Signals {
+            unix: [
+                signal(SignalKind::interrupt())?,
+                signal(SignalKind::terminate())?,
+                signal(SignalKind::hangup())?,
+                signal(SignalKind::quit())?,
+            ],
+        })
+    }
+
+    #[cfg(windows)]
+    {
+        use tokio::signal::windows::{ctrl_break, ctrl_c, ctrl_close, ctrl_logoff, ctrl_shutdown};
+
+        Ok(Signals { windows: (ctrl_c()?, ctrl_break()?, ctrl_close()?, ctrl_logoff()?, ctrl_shutdown()?) })
+    }
+}
+
+impl Signals {
+    /// Waits for the next one and names it
+    async fn recv(&mut self) -> &'static str {
+        #[cfg(unix)]
+        {
+            let [int, term, hup, quit] = &mut self.unix;
+            tokio::select! {
+                _ = int.recv() => "Ctrl+C",
+                _ = term.recv() => "SIGTERM",
+                _ = hup.recv() => "SIGHUP",
+                _ = quit.recv() => "SIGQUIT",
+            }
+        }
+
+        #[cfg(windows)]
+        {
+            let (c, brk, close, logoff, shutdown) = &mut self.windows;
+            tokio::select! {
+                _ = c.recv() => "Ctrl+C",
+                _ = brk.recv() => "Ctrl+Break",
+                _ = close.recv() => "console close",
+                _ = logoff.recv() => "logoff",
+                _ = shutdown.recv() => "system shutdown",
+            }
+        }
+    }
+}
+
+// --- Platform Session Probes ---
+// Each probe shells out to a stock OS tool. A probe that fails to run is
+// treated as "unlocked" so a missing tool never locks the vault spuriously.
+
+#[cfg(target_os = "linux")]
+async fn session_locked() -> bool {
+    let session = std::env::var("XDG_SESSION_ID").unwrap_or_else(|_| "self".to_string());
+    probe("loginctl", &["show-session", &session, "-p", "LockedHint", "--value"])
+        .await
+        .map(|out| out.trim() == "yes")
+        .unwrap_or(false)
+}
+
+#[cfg(target_os = "macos")]
+async fn session_locked() -> bool {
+    probe("ioreg", &["-n", "Root", "-d1"])
+        .await
+        .map(|out| out.contains("\"CGSSessionScreenIsLocked\" = Yes"))
+        .unwrap_or(false)
+}
+
+#[cfg(windows)]
+async fn session_locked() -> bool {
+    // LogonUI only runs while the lock screen is shown
+    probe("tasklist", &["/FI", "IMAGENAME eq LogonUI.exe", "/NH"])
+        .await
+        .map(|out| out.contains("LogonUI.exe"))
+        .unwrap_or(false)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+async fn session_locked() -> bool {
+    false
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+async fn probe(program: &str, args: &[&str]) -> Option<String> {
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .output()
+        .await
+        .ok()?;
+
+    if !output.status.success() {
+        debug!("Session probe '{}' exited with {}", program, output.status);
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,314 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Name used for the systemd unit, launchd label, and scheduled task
+pub const SERVICE_NAME: &str = "lethe-sentinel";
+
+/// Where a service-mode Sentinel writes its log
+pub fn log_path() -> Result<PathBuf> {
+    dirs::data_local_dir()
+        .map(|p| p.join("lethe").join("sentinel.log"))
+        .context("Could not determine local data directory")
+}
+
+/// Sends logs to the service log file and, on Windows, drops the console.
+pub fn enter_service_mode(logger: &mut env_logger::Builder) -> Result<()> {
+    let path = log_path()?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open log file {:?}", path))?;
+
+    logger
+        .filter_level(log::LevelFilter::Info)
+        .target(env_logger::Target::Pipe(Box::new(file)));
+
+    #[cfg(windows)]
+    detach_console();
+
+    Ok(())
+}
+
+/// Arguments baked into the installed service's command line
+pub struct ServiceSpec {
+    pub vault: PathBuf,
+    pub mountpoint: Option<String>,
+    pub idle_timeout: Option<u64>,
+    pub no_auto_lock: bool,
+}
+
+impl ServiceSpec {
+    fn args(&self) -> Vec<String> {
+        let mut args = vec![
+            "daemon".to_string(),
+            "run".to_string(),
+            "--service".to_string(),
+            "--vault".to_string(),
+            self.vault.display().to_string(),
+        ];
+        if let Some(m) = &self.mountpoint {
+            args.push("--mountpoint".to_string());
+            args.push(m.clone());
+        }
+        if let Some(t) = self.idle_timeout {
+            args.push("--idle-timeout".to_string());
+            args.push(t.to_string());
+        }
+        if self.no_auto_lock {
+            args.push("--no-auto-lock".to_string());
+        }
+        args
+    }
+}
+
+fn run(program: &str, args: &[&str]) -> Result<()> {
+    let status = Command::new(program).args(args).status()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !status.success() {
+        anyhow::bail!("{} {} failed ({})", program, args.join(" "), status);
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn write_file(path: &std::path::Path, contents: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, contents).with_context(|| format!("Failed to write {:?}", path))
+}
+
+// =========================================================
+//  LINUX (systemd user unit)
+// =========================================================
+// A user unit rather than a system one: the FUSE mount has to live in the
+// user's session to be visible in their file manager.
+
+#[cfg(target_os = "linux")]
+fn unit_path() -> Result<PathBuf> {
+    dirs::config_dir()
+        .map(|p| p.join("systemd").join("user").join(format!("{}.service", SERVICE_NAME)))
+        .context("Could not determine config directory")
+}
+
+#[cfg(target_os = "linux")]
+pub fn install(spec: &ServiceSpec) -> Result<String> {
+    let exe = std::env::current_exe()?;
+    let exec = std::iter::once(exe.display().to_string())
+        .chain(spec.args())
+        .map(|a| unit_arg(&a))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let unit = format!(
+        "[Unit]\nDescription=Lethe Sentinel\n\n\
+         [Service]\nType=simple\nExecStart={}\nRestart=on-failure\n\n\
+         [Install]\nWantedBy=default.target\n",
+        exec
+    );
+    let path = unit_path()?;
+    write_file(&path, &unit)?;
+
+    run("systemctl", &["--user", "daemon-reload"])?;
+    run("systemctl", &["--user", "enable", "--now", SERVICE_NAME])?;
+    Ok(format!(
+        "Installed {:?}. Run 'loginctl enable-linger' to start it at boot instead of at login.",
+        path
+    ))
+}
+
+/// Points the installed service at `new` if it runs the vault at `old`,
+/// and restarts it; what was done, or None if it runs another vault
+#[cfg(target_os = "linux")]
+pub fn relocate(old: &Path, new: &Path) -> Result<Option<String>> {
+    let path = unit_path()?;
+    let Ok(unit) = std::fs::read_to_string(&path) else { return Ok(None) };
+    let vault = |p: &Path| format!("{} {}", unit_arg("--vault"), unit_arg(&p.display().to_string()));
+    if !unit.contains(&vault(old)) {
+        return Ok(None);
+    }
+    write_file(&path, &unit.replace(&vault(old), &vault(new)))?;
+    run("systemctl", &["--user", "daemon-reload"])?;
+    run("systemctl", &["--user", "restart", SERVICE_NAME])?;
+    Ok(Some(format!("Pointed {:?} at the new folder and restarted it.", path)))
+}
+
+/// One word of `ExecStart=`, quoted, with quotes and backslashes escaped.
+/// systemd would otherwise take `%` for a specifier and `$` for a
+/// variable, so those are doubled.
+#[cfg(target_os = "linux")]
+fn unit_arg(arg: &str) -> String {
+    let escaped = arg.replace('\\', "\\\\").replace('"', "\\\"").replace('%', "%%").replace('$', "$$");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(target_os = "linux")]
+pub fn uninstall() -> Result<String> {
+    let path = unit_path()?;
+    let _ = run("systemctl", &["--user", "disable", "--now", SERVICE_NAME]);
+    if path.exists() {
+        std::fs::remove_file(&path)?;
+    }
+    run("systemctl", &["--user", "daemon-reload"])?;
+    Ok(format!("Removed {:?}.", path))
+}
+
+// =========================================================
+//  MACOS (launchd agent)
+// =========================================================
+
+#[cfg(target_os = "macos")]
+fn plist_path() -> Result<PathBuf> {
+    dirs::home_dir()
+        .map(|p| p.join("Library").join("LaunchAgents").join("com.lethe.sentinel.plist"))
+        .context("Could not determine home directory")
+}
+
+#[cfg(target_os = "macos")]
+pub fn install(spec: &ServiceSpec) -> Result<String> {
+    let exe = std::env::current_exe()?;
+    let program_args: String = std::iter::once(exe.display().to_string())
+        .chain(spec.args())
+        .map(|a| format!("        {}\n", plist_string(&a)))
+        .collect();
+
+    let plist = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n<dict>\n\
+         \x20   <key>Label</key>\n    <string>com.lethe.sentinel</string>\n\
+         \x20   <key>ProgramArguments</key>\n    <array>\n{}    </array>\n\
+         \x20   <key>RunAtLoad</key>\n    <true/>\n\
+         \x20   <key>KeepAlive</key>\n    <true/>\n\
+         </dict>\n</plist>\n",
+        program_args
+    );
+    let path = plist_path()?;
+    write_file(&path, &plist)?;
+
+    let path_str = path.display().to_string();
+    run("launchctl", &["load", "-w", &path_str])?;
+    Ok(format!("Installed {:?}.", path))
+}
+
+/// Points the installed service at `new` if it runs the vault at `old`,
+/// and reloads it; what was done, or None if it runs another vault
+#[cfg(target_os = "macos")]
+pub fn relocate(old: &Path, new: &Path) -> Result<Option<String>> {
+    let path = plist_path()?;
+    let Ok(plist) = std::fs::read_to_string(&path) else { return Ok(None) };
+    let vault = |p: &Path| format!("{}\n        {}", plist_string("--vault"), plist_string(&p.display().to_string()));
+    if !plist.contains(&vault(old)) {
+        return Ok(None);
+    }
+    let path_str = path.display().to_string();
+    let _ = run("launchctl", &["unload", "-w", &path_str]);
+    write_file(&path, &plist.replace(&vault(old), &vault(new)))?;
+    run("launchctl", &["load", "-w", &path_str])?;
+    Ok(Some(format!("Pointed {:?} at the new folder and reloaded it.", path)))
+}
+
+/// `<string>` holding `s`, with what XML reads as markup escaped
+#[cfg(target_os = "macos")]
+fn plist_string(s: &str) -> String {
+    format!("<string>{}</string>", s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;"))
+}
+
+#[cfg(target_os = "macos")]
+pub fn uninstall() -> Result<String> {
+    let path = plist_path()?;
+    if path.exists() {
+        let path_str = path.display().to_string();
+        let _ = run("launchctl", &["unload", "-w", &path_str]);
+        std::fs::remove_file(&path)?;
+    }
+    Ok(format!("Removed {:?}.", path))
+}
+
+// =========================================================
+//  WINDOWS (scheduled task at logon)
+// =========================================================
+// The drive mapping must happen inside the user's logon session, which a
+// session-0 service cannot do, so a logon task stands in for a service.
+
+#[cfg(windows)]
+pub fn install(spec: &ServiceSpec) -> Result<String> {
+    let exe = std::env::current_exe()?;
+    let command = std::iter::once(exe.display().to_string())
+        .chain(spec.args())
+        .map(|a| format!("\"{}\"", a))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    run("schtasks", &["/Create", "/F", "/TN", SERVICE_NAME, "/SC", "ONLOGON", "/RL", "LIMITED", "/TR", &command])?;
+    let _ = run("schtasks", &["/Run", "/TN", SERVICE_NAME]);
+    Ok(format!("Installed scheduled task '{}'.", SERVICE_NAME))
+}
+
+/// Points the installed task at `new` if it runs the vault at `old`, and
+/// starts it; what was done, or None if it runs another vault
+#[cfg(windows)]
+pub fn relocate(old: &Path, new: &Path) -> Result<Option<String>> {
+    let Ok(output) = Command::new("schtasks").args(["/Query", "/TN", SERVICE_NAME, "/XML"]).output() else {
+        return Ok(None);
+    };
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let xml = String::from_utf8_lossy(&output.stdout);
+    let element = |name: &str| -> Option<String> {
+        let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+        let end = start + xml[start..].find(&format!("</{}>", name))?;
+        Some(xml[start..end].replace("&quot;", "\"").replace("&lt;", "<").replace("&gt;", ">").replace("&apos;", "'").replace("&amp;", "&"))
+    };
+    let (Some(program), Some(arguments)) = (element("Command"), element("Arguments")) else { return Ok(None) };
+    let vault = |p: &Path| format!("\"--vault\" \"{}\"", p.display());
+    if !arguments.contains(&vault(old)) {
+        return Ok(None);
+    }
+    let program = format!("\"{}\"", program.trim_matches('"'));
+    let command = format!("{} {}", program, arguments.replace(&vault(old), &vault(new)));
+    run("schtasks", &["/Create", "/F", "/TN", SERVICE_NAME, "/SC", "ONLOGON", "/RL", "LIMITED", "/TR", &command])?;
+    let _ = run("schtasks", &["/Run", "/TN", SERVICE_NAME]);
+    Ok(Some(format!("Pointed scheduled task '{}' at the new folder and started it.", SERVICE_NAME)))
+}
+
+#[cfg(windows)]
+pub fn uninstall() -> Result<String> {
+    let _ = run("schtasks", &["/End", "/TN", SERVICE_NAME]);
+    run("schtasks", &["/Delete", "/F", "/TN", SERVICE_NAME])?;
+    Ok(format!("Removed scheduled task '{}'.", SERVICE_NAME))
+}
+
+/// Detaches from the console so the logon task runs without a window
+#[cfg(windows)]
+fn detach_console() {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn FreeConsole() -> i32;
+    }
+    // SAFETY: FreeConsole takes no arguments and only affects this process
+    unsafe {
+        FreeConsole();
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn install(_spec: &ServiceSpec) -> Result<String> {
+    anyhow::bail!("Service installation is not supported on this platform.")
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn relocate(_old: &Path, _new: &Path) -> Result<Option<String>> {
+    Ok(None)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn uninstall() -> Result<String> {
+    anyhow::bail!("Service installation is not supported on this platform.")
+}
@@ -1,8 +1,12 @@
-use std::io::{Cursor, Read, Seek, SeekFrom, Write};
-use std::time::SystemTime;
+use std::io::SeekFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use bytes::{Buf, Bytes};
 use dav_server::fs::{DavFile, DavMetaData, FsError, FsFuture, FsResult};
+use tracing::Instrument;
+use lethe_core::plaintext::Zeroizing;
 use super::state::LetheState;
+use crate::spill::FileBuffer;
+use crate::vfs::VaultVfs;
 
 #[derive(Debug, Clone)]
 pub struct LetheMetaData {
@@ -21,29 +25,54 @@ impl DavMetaData for LetheMetaData {
 
 #[derive(Debug)]
 pub struct LetheDavFile {
-    pub buffer: Cursor<Vec<u8>>, 
+    /// The file's content, read from the vault as needed; writes are held
+    /// up to a limit and spilled past it (see `spill`)
+    pub buffer: FileBuffer,
+    /// Where the next read or write goes
+    pub pos: u64,
     pub path: String,
     pub state: LetheState,
     pub is_dirty: bool,
+    /// Opened to append: every write goes at the end, wherever `pos` is
+    pub append: bool,
+}
+
+impl Drop for LetheDavFile {
+    fn drop(&mut self) {
+        // Unflushed writes are discarded with the handle
+        if self.is_dirty {
+            self.state.activity.remove_dirty();
+        }
+    }
 }
 
 impl DavFile for LetheDavFile {
+    #[tracing::instrument(name = "dav.read", skip(self), fields(path = %self.path, pos = self.pos))]
     fn read_bytes(&mut self, count: usize) -> FsFuture<'_, Bytes> {
-        let mut buf = vec![0u8; count];
-        match self.buffer.read(&mut buf) {
-            Ok(n) => {
-                buf.truncate(n);
-                Box::pin(async move { Ok(Bytes::from(buf)) })
+        // Blocks are decrypted as the read reaches them
+        match tokio::task::block_in_place(|| self.buffer.read_at(self.pos, count)) {
+            Ok(data) => {
+                self.pos += data.len() as u64;
+                let bytes = Bytes::copy_from_slice(&data);
+                Box::pin(async move { Ok(bytes) })
             }
             Err(_) => Box::pin(async { Err(FsError::GeneralFailure) }),
         }
     }
 
+    #[tracing::instrument(name = "dav.write", skip_all, fields(path = %self.path, pos = self.pos, len = buf.remaining()))]
     fn write_buf(&mut self, mut buf: Box<dyn Buf + Send>) -> FsFuture<'_, ()> {
-        let mut chunk = vec![0u8; buf.remaining()];
+        let mut chunk = Zeroizing::new(vec![0u8; buf.remaining()]);
         buf.copy_to_slice(&mut chunk);
-        match self.buffer.write_all(&chunk) {
+        if self.append {
+            self.pos = self.buffer.size();
+        }
+        match tokio::task::block_in_place(|| self.buffer.write_at(self.pos, &chunk)) {
             Ok(_) => {
+                self.pos += chunk.len() as u64;
+                if !self.is_dirty {
+                    self.state.activity.add_dirty();
+                }
                 self.is_dirty = true;
                 Box::pin(async { Ok(()) })
             }
@@ -56,40 +85,66 @@ impl DavFile for LetheDavFile {
     }
 
     fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
-        let res = self.buffer.seek(pos).map_err(|_| FsError::GeneralFailure);
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.buffer.size().checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        let res = match target {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(FsError::GeneralFailure),
+        };
         Box::pin(async move { res })
     }
 
     fn flush(&mut self) -> FsFuture<'_, ()> {
-        let path = self.path.clone();
-        let data = self.buffer.get_ref().clone();
-        let state = self.state.clone();
         let is_dirty = self.is_dirty;
+        if is_dirty {
+            self.is_dirty = false;
+            self.state.activity.remove_dirty();
+        }
+        let span = tracing::info_span!("dav.flush", path = %self.path);
 
         Box::pin(async move {
             if !is_dirty { return Ok(()); }
-            let size = data.len() as u64;
-            let block_id = match state.storage.write_block(&data, &state.key) {
-                Ok(id) => id,
-                Err(_) => return Err(FsError::GeneralFailure),
+            let state = self.state.clone();
+            let backing = state.backing();
+            // Streamed from the buffer into blocks, then read back from those.
+            // The blocks are written with the index shared, so listings and
+            // other reads go on meanwhile; a hidden vault's need it held.
+            let staged = {
+                let index = state.index.read().await;
+                tokio::task::block_in_place(|| index.stage(&mut self.buffer, &backing))?
             };
-            let mut index = state.index.lock().await;
-            index.add_file(path, vec![block_id], size);
-            match index.save(&state.key) {
-                Ok(_) => Ok(()),
-                Err(_) => Err(FsError::GeneralFailure),
-            }
-        })
+            let mut index = state.index.write().await;
+            tokio::task::block_in_place(|| {
+                index.write_back(&self.path, &mut self.buffer, staged, &backing)?;
+                state.save(&mut index).map_err(|_| FsError::GeneralFailure)
+            })?;
+            drop(index);
+            crate::server::preview::spawn_make(&state, self.path.clone());
+            Ok(())
+        }.instrument(span))
     }
 
     fn metadata(&mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
-        let len = self.buffer.get_ref().len() as u64;
-        let modified = SystemTime::now();
+        let len = self.buffer.size();
         let etag = format!("\"mem-{:x}\"", len);
+        let path = self.path.clone();
+        let state = self.state.clone();
+        let is_dirty = self.is_dirty;
         Box::pin(async move {
+            // Unsaved writes count as modified now
+            let modified = match state.index.read().await.get_file(&path) {
+                Some(e) if !is_dirty => UNIX_EPOCH + Duration::from_secs(e.modified),
+                _ => SystemTime::now(),
+            };
             Ok(Box::new(LetheMetaData {
                 len, modified, is_dir: false, etag
             }) as Box<dyn DavMetaData>)
         })
     }
-}
\ No newline at end of file
+}
@@ -1,22 +1,139 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use anyhow::Result;
+use tokio::sync::{Notify, RwLock};
+use lethe_core::cache::BlockCache;
+use lethe_core::config::DEFAULT_READ_AHEAD;
 use lethe_core::index::IndexManager;
 use lethe_core::storage::BlockManager;
 use lethe_core::crypto::MasterKey;
+use lethe_core::nonblocking::Vault;
+use lethe_core::VaultConfig;
+use crate::cli::mount::DEFAULT_BUFFER_LIMIT;
+use crate::daemon::Activity;
+use crate::vfs::{self, Backing};
 
-#[derive(Clone, Debug)] 
+#[derive(Clone, Debug)]
 pub struct LetheState {
-    pub index: Arc<Mutex<IndexManager>>,
+    pub index: Arc<RwLock<IndexManager>>,
     pub storage: Arc<BlockManager>,
     pub key: Arc<MasterKey>,
+    pub activity: Activity,
+    /// Fired by a client asking the server to lock (drop the key)
+    pub lock: Arc<Notify>,
+    /// Saves are left to the index writer (see `spawn_index_writer`)
+    deferred: Arc<AtomicBool>,
+    /// The index has changes the writer has yet to save
+    unsaved: Arc<AtomicBool>,
+    /// Blocks read ahead of each file read in order
+    read_ahead: usize,
+    /// Decrypted blocks shared by every open file
+    pub cache: Option<Arc<BlockCache>>,
+    /// Uploads get a preview (see `server::preview`)
+    previews: bool,
 }
 
 impl LetheState {
-    pub fn new(index: IndexManager, storage: BlockManager, key: MasterKey) -> Self {
+    pub fn new(index: IndexManager, storage: BlockManager, key: MasterKey, activity: Activity) -> Self {
         Self {
-            index: Arc::new(Mutex::new(index)),
+            index: Arc::new(RwLock::new(index)),
             storage: Arc::new(storage),
             key: Arc::new(key),
+            activity,
+            lock: Arc::new(Notify::new()),
+            deferred: Arc::new(AtomicBool::new(false)),
+            unsaved: Arc::new(AtomicBool::new(false)),
+            read_ahead: DEFAULT_READ_AHEAD,
+            cache: vfs::block_cache(&VaultConfig::default()),
+            previews: false,
         }
     }
-}
\ No newline at end of file
+
+    /// Makes previews of images and PDFs as they are uploaded
+    pub fn with_previews(mut self, previews: bool) -> Self {
+        self.previews = previews;
+        self
+    }
+
+    pub fn previews(&self) -> bool {
+        self.previews
+    }
+
+    /// Reads ahead and caches as `config` says instead of by default; the
+    /// Windows mount's flags
+    #[cfg(windows)]
+    pub fn tuned(mut self, config: &VaultConfig) -> Self {
+        self.read_ahead = config.read_ahead;
+        self.cache = vfs::block_cache(config);
+        self
+    }
+
+    /// Saves `index` now, or marks it for the index writer if one runs
+    pub fn save(&self, index: &mut IndexManager) -> Result<()> {
+        if self.deferred.load(Ordering::Relaxed) {
+            self.unsaved.store(true, Ordering::Relaxed);
+            return Ok(());
+        }
+        index.save(&self.key)
+    }
+
+    /// Coalesces saves: changes made through `save` are written at most
+    /// once per `every`, for as long as the state lives. Zero saves each
+    /// change as it is made.
+    pub fn spawn_index_writer(&self, every: Duration) {
+        if every.is_zero() {
+            return;
+        }
+        self.deferred.store(true, Ordering::Relaxed);
+        let index = Arc::downgrade(&self.index);
+        let (key, unsaved) = (self.key.clone(), self.unsaved.clone());
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(every);
+            loop {
+                ticks.tick().await;
+                let Some(index) = index.upgrade() else { break };
+                if unsaved.swap(false, Ordering::Relaxed) {
+                    let mut index = index.write().await;
+                    if let Err(e) = tokio::task::block_in_place(|| index.save(&key)) {
+                        log::error!("Failed to save the index: {}", e);
+                        unsaved.store(true, Ordering::Relaxed);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Saves what the index writer has yet to, e.g. before locking
+    pub async fn flush_index(&self) -> Result<()> {
+        if !self.unsaved.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let saved = self.vault().save().await;
+        if saved.is_err() {
+            self.unsaved.store(true, Ordering::Relaxed);
+        }
+        saved
+    }
+
+    /// Where open files are read from and written to (see `vfs`)
+    pub fn backing(&self) -> Backing {
+        Backing {
+            storage: self.storage.clone(),
+            key: self.key.clone(),
+            buffer_limit: DEFAULT_BUFFER_LIMIT,
+            read_ahead: self.read_ahead,
+            cache: self.cache.clone(),
+        }
+    }
+
+    /// The same vault, for the `nonblocking` operations
+    pub fn vault(&self) -> Vault {
+        Vault { index: self.index.clone(), storage: self.storage.clone(), key: self.key.clone() }
+    }
+
+    /// Resolves once a client has asked for the vault to be locked
+    pub async fn lock_requested(&self) {
+        self.lock.notified().await
+    }
+}
@@ -0,0 +1,218 @@
+#![cfg(unix)]
+
+//! Other vaults shown inside a FUSE mount, under `/remote/<name>`.
+//!
+//! Each is reached through the JSON API of its `lethe serve` (see
+//! `server::api`), signing in with HTTP Basic. Files are fetched whole
+//! when opened and uploaded whole when saved, streamed through the open
+//! file's buffer so large ones spill to disk rather than memory. The
+//! remote file table is listed at most once per `LISTING_TTL` and again
+//! after every change made through here.
+//!
+//! `https://` remotes are checked against the usual web roots; over
+//! `http://` the password and files cross the network in the clear.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::json;
+
+use lethe_core::index::is_below;
+
+use crate::cli::mount::Attached;
+
+/// Folder of the mount the attached vaults appear in
+pub const FEDERATION_DIR: &str = "/remote";
+
+const LISTING_TTL: Duration = Duration::from_secs(2);
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A failed API call; `status` is None when the server wasn't reached
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Option<u16>,
+    pub message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "HTTP {}: {}", status, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+fn io_error(e: impl fmt::Display) -> ApiError {
+    ApiError { status: None, message: e.to_string() }
+}
+
+/// A file or folder in an attached vault
+#[derive(Debug, Clone)]
+pub struct RemoteEntry {
+    pub size: u64,
+    pub modified: u64,
+    pub is_dir: bool,
+}
+
+/// Path -> entry, as the remote lists it
+pub type Listing = HashMap<String, RemoteEntry>;
+
+pub struct Federated {
+    pub remote: Attached,
+    agent: ureq::Agent,
+    listing: Mutex<Option<(Instant, Arc<Listing>)>>,
+}
+
+impl Federated {
+    /// Signs in and lists the remote once, so a wrong password or address
+    /// fails the mount instead of every later call
+    pub fn connect(remote: Attached) -> anyhow::Result<Self> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(TIMEOUT)
+            .timeout_read(TIMEOUT)
+            .timeout_write(TIMEOUT)
+            .build();
+        let federated = Self { remote, agent, listing: Mutex::new(None) };
+        federated.listing().map_err(|e| anyhow::anyhow!("Could not attach {}: {}", federated.remote, e))?;
+        Ok(federated)
+    }
+
+    /// The remote file table, listed again once stale
+    pub fn listing(&self) -> Result<Arc<Listing>, ApiError> {
+        let mut cached = self.listing.lock().unwrap();
+        if let Some((at, listing)) = cached.as_ref() {
+            if at.elapsed() < LISTING_TTL {
+                return Ok(listing.clone());
+            }
+        }
+        let listing = Arc::new(self.list()?);
+        *cached = Some((Instant::now(), listing.clone()));
+        Ok(listing)
+    }
+
+    fn invalidate(&self) {
+        *self.listing.lock().unwrap() = None;
+    }
+
+    /// The entry at `path`; folders nobody created explicitly count too
+    pub fn stat(&self, path: &str) -> Result<Option<RemoteEntry>, ApiError> {
+        if path == "/" {
+            return Ok(Some(RemoteEntry { size: 0, modified: 0, is_dir: true }));
+        }
+        let listing = self.listing()?;
+        if let Some(entry) = listing.get(path) {
+            return Ok(Some(entry.clone()));
+        }
+        Ok(listing.keys().any(|p| is_below(p, path))
+            .then_some(RemoteEntry { size: 0, modified: 0, is_dir: true }))
+    }
+
+    /// Names directly below `dir`, and whether each is a folder
+    pub fn children(&self, dir: &str) -> Result<Vec<(String, bool)>, ApiError> {
+        let listing = self.listing()?;
+        let prefix = if dir == "/" { "/".to_string() } else { format!("{}/", dir) };
+        let mut children: HashMap<String, bool> = HashMap::new();
+        for (path, entry) in listing.iter() {
+            let Some(rest) = path.strip_prefix(&prefix) else { continue };
+            match rest.split_once('/') {
+                Some((name, _)) => { children.insert(name.to_string(), true); }
+                None if !rest.is_empty() => { children.entry(rest.to_string()).or_insert(entry.is_dir); }
+                None => {}
+            }
+        }
+        let mut children: Vec<(String, bool)> = children.into_iter().collect();
+        children.sort();
+        Ok(children)
+    }
+
+    /// The file's content, read from the reply as the caller goes
+    pub fn get(&self, path: &str) -> Result<impl Read, ApiError> {
+        let reply = self.request("GET", &files_path(path)).call().map_err(api_error)?;
+        Ok(reply.into_reader())
+    }
+
+    /// Uploads `size` bytes from `body` as the file's new content
+    pub fn put(&self, path: &str, body: impl Read, size: u64) -> Result<(), ApiError> {
+        self.request("PUT", &files_path(path))
+            .set("Content-Type", "application/octet-stream")
+            .set("Content-Length", &size.to_string())
+            .send(body)
+            .map_err(api_error)?;
+        self.invalidate();
+        Ok(())
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), ApiError> {
+        self.request("PATCH", &files_path(from)).send_json(json!({ "path": to })).map_err(api_error)?;
+        self.invalidate();
+        Ok(())
+    }
+
+    pub fn delete(&self, path: &str) -> Result<(), ApiError> {
+        self.request("DELETE", &files_path(path)).call().map_err(api_error)?;
+        self.invalidate();
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Listing, ApiError> {
+        let reply = self.request("GET", "/api/v1/files").query("prefix", "/").call().map_err(api_error)?;
+        let listed: Vec<Listed> = reply.into_json().map_err(|e| io_error(format!("The file list is malformed: {}", e)))?;
+        Ok(listed.into_iter()
+            .map(|l| (l.path, RemoteEntry { size: l.size, modified: l.modified, is_dir: l.is_dir }))
+            .collect())
+    }
+
+    /// A signed-in request to `target` on the remote
+    fn request(&self, method: &str, target: &str) -> ureq::Request {
+        let r = &self.remote;
+        let credentials = STANDARD.encode(format!("{}:{}", r.user, r.password));
+        self.agent.request(method, &format!("{}{}", r.base_url(), target))
+            .set("Authorization", &format!("Basic {}", credentials))
+    }
+}
+
+/// The `GET /api/v1/files` form of an entry
+#[derive(Deserialize)]
+struct Listed {
+    path: String,
+    size: u64,
+    modified: u64,
+    is_dir: bool,
+}
+
+/// A reply outside 2xx keeps its status and body; anything else wasn't
+/// answered
+fn api_error(e: ureq::Error) -> ApiError {
+    match e {
+        ureq::Error::Status(status, reply) => {
+            let message = reply.into_string().unwrap_or_default().trim().to_string();
+            ApiError { status: Some(status), message }
+        }
+        ureq::Error::Transport(e) => io_error(e),
+    }
+}
+
+/// The API path of a vault path, percent-encoded
+fn files_path(path: &str) -> String {
+    let mut out = String::from("/api/v1/files");
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        out.push('/');
+        for b in segment.bytes() {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                out.push(b as char);
+            } else {
+                out.push_str(&format!("%{:02X}", b));
+            }
+        }
+    }
+    out
+}
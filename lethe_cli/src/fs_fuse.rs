@@ -1,68 +1,390 @@
 #![cfg(unix)]
 
+use fuser::consts::{FOPEN_DIRECT_IO, FUSE_POSIX_LOCKS};
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    ReplyWrite, ReplyCreate, ReplyEmpty, ReplyOpen, Request, TimeOrNow,
+    FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    ReplyWrite, ReplyCreate, ReplyEmpty, ReplyLock, ReplyOpen, ReplyStatfs, Request, TimeOrNow,
 };
 use std::ffi::OsStr;
-use std::time::{Duration, UNIX_EPOCH, SystemTime};
+use std::io::Read;
+use std::time::{Duration, Instant, UNIX_EPOCH, SystemTime};
 use std::collections::{HashMap, HashSet};
-use lethe_core::index::IndexManager;
-use lethe_core::storage::BlockManager;
-use lethe_core::crypto::MasterKey;
+use std::sync::{Arc, Mutex, Weak};
+use lethe_core::index::{is_snapshot_path, is_within, IndexManager};
+use lethe_core::plaintext::Zeroizing;
+use crate::cli::mount::KernelCache;
+use crate::daemon::Activity;
+use crate::federation::{ApiError, Federated, FEDERATION_DIR};
+use crate::locks::{Lock, LockTable};
+use crate::spill::FileBuffer;
+use crate::vfs::{child_path, Backing, Node, OpenMode, VaultVfs, VfsError};
+use crate::volume;
 
 // --- CROSS PLATFORM ERROR CODES ---
-use libc::{ENOENT, EACCES, ENOTEMPTY};
+use libc::{c_int, EACCES, EAGAIN, EEXIST, EINVAL, EIO, EISDIR, EPERM, EROFS, ENOENT, ENOTDIR, ENOTEMPTY, EXDEV, F_RDLCK, F_UNLCK, F_WRLCK, O_ACCMODE, O_EXCL, O_RDONLY, O_TRUNC};
 
-const TTL: Duration = Duration::from_secs(1);
+/// `FUSE_WRITEBACK_CACHE` (ABI 7.23); fuser only exports it behind a feature
+const FUSE_WRITEBACK_CACHE: u32 = 1 << 16;
+
+/// Block size reported to statfs; capacity figures are counted in these
+const STATFS_BSIZE: u64 = 4096;
 
 pub struct LetheFS {
     pub index: IndexManager,
-    pub storage: BlockManager,
-    pub key: MasterKey,
+    /// Blocks and key; spilled pages go in the vault folder
+    pub backing: Backing,
     pub inode_map: HashMap<u64, String>,
-    pub write_buffer: HashMap<u64, Vec<u8>>,
+    /// Open files, decrypted; wiped as they are closed or dropped
+    pub write_buffer: HashMap<u64, FileBuffer>,
+    /// Inodes whose buffer holds writes not yet persisted
+    pub dirty: HashSet<u64>,
+    /// mtimes set via setattr on dirty buffers, applied once persisted
+    pub pending_mtime: HashMap<u64, u64>,
+    /// When each dirty buffer was last written to
+    pub written_at: HashMap<u64, Instant>,
+    pub activity: Activity,
+    pub cache: KernelCache,
+    /// Vaults attached under `/remote` (see `federation`)
+    pub remotes: Vec<Federated>,
+    /// Index saves are coalesced to one per this long (zero: every change)
+    pub save_every: Duration,
+    /// Since when the index has had changes not yet saved
+    pub unsaved_since: Option<Instant>,
+    /// POSIX locks applications hold on open files, and `F_SETLKW`s
+    /// waiting for one
+    pub locks: LockTable<ReplyEmpty>,
+    /// Open file handles
+    pub handles: HashMap<u64, Handle>,
+    /// Content of files deleted or replaced while open, by the id their
+    /// handles share, until the last of those closes (see `orphan`)
+    pub unlinked: HashMap<u64, FileBuffer>,
+    /// Last file handle given out
+    pub last_fh: u64,
+}
+
+/// What a file handle was opened on
+pub struct Handle {
+    ino: u64,
+    /// Set once the file is deleted or replaced: its key in `unlinked`
+    unlinked: Option<u64>,
+}
+
+fn to_time(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn vfs_errno(e: VfsError) -> c_int {
+    match e {
+        VfsError::NotFound => ENOENT,
+        VfsError::Exists => EEXIST,
+        VfsError::NotEmpty => ENOTEMPTY,
+        VfsError::IsDir => EISDIR,
+        VfsError::NotDir => ENOTDIR,
+        VfsError::ReadOnly => EROFS,
+        VfsError::Immutable => EPERM,
+        VfsError::BadName => EINVAL,
+        VfsError::Io => EIO,
+    }
+}
+
+fn errno(e: &ApiError) -> c_int {
+    match e.status {
+        Some(404) => ENOENT,
+        Some(401) | Some(403) => EACCES,
+        Some(409) => EEXIST,
+        _ => {
+            log::warn!("Attached vault: {}", e);
+            EIO
+        }
+    }
 }
 
 impl LetheFS {
     fn resolve_path(&self, parent_ino: u64, name: &OsStr) -> Option<String> {
         let parent_path = self.inode_map.get(&parent_ino)?;
-        let name_str = name.to_string_lossy();
-        
-        Some(if parent_path == "/" {
-            format!("/{}", name_str)
-        } else {
-            format!("{}/{}", parent_path, name_str)
-        })
+        Some(child_path(parent_path, &name.to_string_lossy()))
+    }
+
+    /// `/remote` itself, while vaults are attached
+    fn is_federation_root(&self, path: &str) -> bool {
+        !self.remotes.is_empty() && path == FEDERATION_DIR
+    }
+
+    /// `/remote` and below, which attached vaults shadow
+    fn in_federation(&self, path: &str) -> bool {
+        !self.remotes.is_empty() && is_within(path, FEDERATION_DIR)
+    }
+
+    /// The attached vault `path` is in, and the path within it
+    fn federated(&self, path: &str) -> Option<(&Federated, String)> {
+        let rest = path.strip_prefix(FEDERATION_DIR)?.strip_prefix('/')?;
+        let (name, inner) = rest.split_once('/').unwrap_or((rest, ""));
+        let remote = self.remotes.iter().find(|r| r.remote.name == name)?;
+        Some((remote, format!("/{}", inner)))
+    }
+
+    fn is_remote(&self, ino: u64) -> bool {
+        self.inode_map.get(&ino).is_some_and(|p| self.federated(p).is_some())
+    }
+
+    fn mark_dirty(&mut self, ino: u64) {
+        if self.dirty.insert(ino) {
+            self.activity.add_dirty();
+        }
+    }
+
+    fn clear_dirty(&mut self, ino: u64) -> bool {
+        self.written_at.remove(&ino);
+        let was_dirty = self.dirty.remove(&ino);
+        if was_dirty {
+            self.activity.remove_dirty();
+        }
+        was_dirty
+    }
+
+    /// A new handle on `ino`
+    fn open_handle(&mut self, ino: u64) -> u64 {
+        self.last_fh += 1;
+        self.handles.insert(self.last_fh, Handle { ino, unlinked: None });
+        self.last_fh
+    }
+
+    /// Handles open on `ino` that still refer to the file at its path
+    fn open_on(&self, ino: u64) -> impl Iterator<Item = u64> + '_ {
+        self.handles.iter().filter(move |(_, h)| h.ino == ino && h.unlinked.is_none()).map(|(fh, _)| *fh)
+    }
+
+    /// The content a handle reads and writes, if the file is gone from
+    /// its path since it was opened
+    fn unlinked_buffer(&mut self, fh: u64) -> Option<&mut FileBuffer> {
+        let id = self.handles.get(&fh)?.unlinked?;
+        self.unlinked.get_mut(&id)
+    }
+
+    /// Keeps the buffer of `ino` for the handles still open on it, once
+    /// the file is deleted or replaced: as with unlink on any POSIX file
+    /// system, they read and write it until the last closes, and then it
+    /// is gone. It is copied out of its blocks first, so `lethe clean`
+    /// can't take them from under it. Unsaved writes to it are dropped.
+    fn orphan(&mut self, ino: u64) {
+        let Some(mut buffer) = self.write_buffer.remove(&ino) else { return };
+        self.pending_mtime.remove(&ino);
+        self.clear_dirty(ino);
+        let open: Vec<u64> = self.open_on(ino).collect();
+        let Some(&id) = open.first() else { return };
+        if let Err(e) = buffer.detach() {
+            log::warn!("Failed to copy a deleted file that is still open out of its blocks: {}", e);
+        }
+        for fh in open {
+            if let Some(handle) = self.handles.get_mut(&fh) {
+                handle.unlinked = Some(id);
+            }
+        }
+        self.unlinked.insert(id, buffer);
+    }
+
+    /// `orphan` for a file a rename from `from` replaced at `to`
+    fn orphan_replaced(&mut self, from: &str, to: &str) {
+        let replaced = fxhash::hash64(to);
+        if replaced != fxhash::hash64(from) {
+            self.orphan(replaced);
+        }
+    }
+
+    /// A deleted file still open under `ino`, as fstat sees it
+    fn unlinked_attr(&self, ino: u64) -> Option<FileAttr> {
+        let id = self.handles.values().find(|h| h.ino == ino)?.unlinked?;
+        let size = self.unlinked.get(&id)?.size();
+        Some(FileAttr { nlink: 0, ..self.attr_file(ino, size, now_secs()) })
+    }
+
+    /// A buffer holding `data`
+    fn buffer(&self, mut content: impl Read) -> Result<FileBuffer, c_int> {
+        let mut buffer = FileBuffer::new(self.backing.buffer_limit, self.index.root_path());
+        let mut chunk = Zeroizing::new(vec![0u8; 64 * 1024]);
+        let mut at = 0u64;
+        loop {
+            let n = content.read(&mut chunk).map_err(|e| {
+                log::error!("Failed to read an open file: {}", e);
+                EIO
+            })?;
+            if n == 0 {
+                return Ok(buffer);
+            }
+            buffer.write_at(at, &chunk[..n]).map_err(|e| {
+                log::error!("Failed to spill an open file: {}", e);
+                EIO
+            })?;
+            at += n as u64;
+        }
+    }
+
+    /// A buffer over the file at `path`, live or from a snapshot. Local
+    /// files are read from their blocks as needed; an attached vault's is
+    /// fetched whole into the buffer.
+    fn open_buffer(&self, path: &str, mode: OpenMode) -> Result<FileBuffer, c_int> {
+        if let Some((remote, inner)) = self.federated(path) {
+            if mode.write && mode.truncate {
+                return self.buffer(std::io::empty());
+            }
+            return match remote.get(&inner) {
+                Ok(content) => self.buffer(content),
+                Err(e) if e.status == Some(404) && mode.write && mode.create => self.buffer(std::io::empty()),
+                Err(e) => Err(errno(&e)),
+            };
+        }
+        self.index.open_file(path, mode, &self.backing).map_err(vfs_errno)
+    }
+
+    /// Writes a buffer out as blocks and records it in the index (without
+    /// saving). A local file's buffer then reads from the new blocks, which
+    /// frees what it held.
+    fn persist(&mut self, ino: u64, buffer: &mut FileBuffer) -> bool {
+        if let Some(path) = self.inode_map.get(&ino).cloned() {
+            if let Some((remote, inner)) = self.federated(&path) {
+                let size = buffer.size();
+                let saved = remote.put(&inner, buffer.reader(), size)
+                    .inspect_err(|e| log::error!("Saving {} to {} failed: {}", inner, remote.remote, e))
+                    .is_ok();
+                self.pending_mtime.remove(&ino);
+                return saved;
+            }
+            let staged = self.index.stage(buffer, &self.backing);
+            if staged.and_then(|staged| self.index.write_back(&path, buffer, staged, &self.backing)).is_ok() {
+                if let Some(mtime) = self.pending_mtime.remove(&ino) {
+                    self.index.set_modified(&path, mtime);
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Saves buffers that have had no writes for `idle`, keeping them open
+    #[tracing::instrument(skip_all)]
+    pub fn flush_idle(&mut self, idle: Duration) {
+        let due: Vec<u64> = self.written_at.iter()
+            .filter(|(_, at)| at.elapsed() >= idle)
+            .map(|(ino, _)| *ino)
+            .collect();
+
+        let mut changed = false;
+        for ino in due {
+            // Out of the map while it is saved, and back once done
+            let Some(mut buffer) = self.write_buffer.remove(&ino) else { continue };
+            let local = !self.is_remote(ino);
+            if self.persist(ino, &mut buffer) {
+                self.clear_dirty(ino);
+                changed |= local;
+            }
+            self.write_buffer.insert(ino, buffer);
+        }
+        if changed {
+            self.save_index();
+        }
+    }
+
+    /// Saves the index, or leaves it for `save_due` if saves are coalesced
+    fn save_index(&mut self) {
+        if self.save_every.is_zero() {
+            if let Err(e) = self.index.save(&self.backing.key) {
+                log::error!("Failed to save the index: {}", e);
+            }
+            return;
+        }
+        self.unsaved_since.get_or_insert_with(Instant::now);
+    }
+
+    /// Saves the index if it has waited `save_every` since its first
+    /// unsaved change
+    pub fn save_due(&mut self) {
+        if self.unsaved_since.is_some_and(|since| since.elapsed() >= self.save_every) {
+            self.flush_index();
+        }
+    }
+
+    /// Saves any changes to the index now, e.g. on unmount or a handoff
+    #[tracing::instrument(skip_all)]
+    pub fn flush_index(&mut self) {
+        if self.unsaved_since.take().is_some() {
+            if let Err(e) = self.index.save(&self.backing.key) {
+                log::error!("Failed to save the index: {}", e);
+                self.unsaved_since = Some(Instant::now());
+            }
+        }
+    }
+
+    fn open_flags(&self) -> u32 {
+        if self.cache.direct_io { FOPEN_DIRECT_IO } else { 0 }
     }
 
     fn get_file_attr(&self, path: &str, ino: u64) -> FileAttr {
-        if path == "/" { return self.attr_dir(ino); }
+        if is_snapshot_path(path) { return self.snapshot_attr(path, ino); }
+        if self.is_federation_root(path) { return FileAttr { perm: 0o555, ..self.attr_dir(ino, 0) }; }
+        if let Some((remote, inner)) = self.federated(path) {
+            return self.remote_attr(remote, &inner, ino);
+        }
+
+        let node = self.index.node(path);
 
         if let Some(buffer) = self.write_buffer.get(&ino) {
-            return self.attr_file(ino, buffer.len() as u64);
+            // Unsaved writes count as modified now
+            let mtime = match self.pending_mtime.get(&ino) {
+                Some(t) => *t,
+                None if self.dirty.contains(&ino) => now_secs(),
+                None => node.map(|n| n.modified()).unwrap_or_else(now_secs),
+            };
+            return self.attr_file(ino, buffer.size(), mtime);
         }
 
-        if let Some(entry) = self.index.get_file(path) {
-            return self.attr_file(ino, entry.size);
+        match node {
+            // Write-once files say so in their permissions
+            Some(Node::File(e)) if e.immutable => FileAttr { perm: 0o444, ..self.attr_file(ino, e.size, e.modified) },
+            Some(node) if !node.is_dir() => self.attr_file(ino, node.size(), node.modified()),
+            Some(node) => self.attr_dir(ino, node.modified()),
+            None => self.attr_dir(ino, self.index.dir_modified(path)),
         }
+    }
+
+    fn remote_attr(&self, remote: &Federated, inner: &str, ino: u64) -> FileAttr {
+        if let Some(buffer) = self.write_buffer.get(&ino) {
+            return self.attr_file(ino, buffer.size(), now_secs());
+        }
+        match remote.stat(inner) {
+            Ok(Some(e)) if !e.is_dir => self.attr_file(ino, e.size, e.modified),
+            Ok(Some(e)) => self.attr_dir(ino, e.modified),
+            _ => self.attr_dir(ino, 0),
+        }
+    }
 
-        self.attr_dir(ino)
+    /// Snapshots are read-only, and say so in their permissions
+    fn snapshot_attr(&self, path: &str, ino: u64) -> FileAttr {
+        match self.index.node(path) {
+            Some(Node::File(e)) => FileAttr { perm: 0o444, ..self.attr_file(ino, e.size, e.modified) },
+            Some(Node::Dir { modified }) => FileAttr { perm: 0o555, ..self.attr_dir(ino, modified) },
+            None => FileAttr { perm: 0o555, ..self.attr_dir(ino, 0) },
+        }
     }
 
-    fn attr_dir(&self, ino: u64) -> FileAttr {
+    fn attr_dir(&self, ino: u64, mtime: u64) -> FileAttr {
+        let mtime = to_time(mtime);
         FileAttr {
             ino, size: 0, blocks: 0,
-            atime: UNIX_EPOCH, mtime: UNIX_EPOCH, ctime: UNIX_EPOCH, crtime: UNIX_EPOCH,
+            atime: mtime, mtime, ctime: mtime, crtime: mtime,
             kind: FileType::Directory, perm: 0o755, nlink: 2, 
             uid: 1000, gid: 1000, rdev: 0, flags: 0, blksize: 512,
         }
     }
 
-    fn attr_file(&self, ino: u64, size: u64) -> FileAttr {
+    fn attr_file(&self, ino: u64, size: u64, mtime: u64) -> FileAttr {
+        let mtime = to_time(mtime);
         FileAttr {
             ino, size, blocks: 1,
-            atime: UNIX_EPOCH, mtime: UNIX_EPOCH, ctime: UNIX_EPOCH, crtime: UNIX_EPOCH,
+            atime: mtime, mtime, ctime: mtime, crtime: mtime,
             kind: FileType::RegularFile, perm: 0o644, nlink: 1,
             uid: 1000, gid: 1000, rdev: 0, flags: 0, blksize: 512,
         }
@@ -70,18 +392,68 @@ impl LetheFS {
 }
 
 impl Filesystem for LetheFS {
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+        if self.cache.writeback && config.add_capabilities(FUSE_WRITEBACK_CACHE).is_err() {
+            log::warn!("Kernel does not support the FUSE writeback cache; writes go through uncached");
+        }
+        if config.add_capabilities(FUSE_POSIX_LOCKS).is_err() {
+            log::warn!("Kernel does not pass POSIX locks to FUSE; it keeps them itself");
+        }
+        Ok(())
+    }
+
+    // 0. DESTROY (Unmount) - flush whatever is still open
+    fn destroy(&mut self) {
+        let pending: Vec<u64> = self.dirty.iter().copied().collect();
+        let mut changed = false;
+        for ino in pending {
+            self.clear_dirty(ino);
+            if let Some(mut data) = self.write_buffer.remove(&ino) {
+                let local = !self.is_remote(ino);
+                changed |= self.persist(ino, &mut data) && local;
+            }
+        }
+        // Files only read are still open; wipe them now the vault locks
+        self.write_buffer.clear();
+        for waiter in self.locks.clear_all() {
+            waiter.error(EIO);
+        }
+        if changed {
+            self.save_index();
+        }
+        self.flush_index();
+    }
+
     // 1. LOOKUP
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.activity.touch();
         if let Some(path) = self.resolve_path(parent, name) {
             let ino = fxhash::hash64(&path);
-            
-            // Allow lookup if it exists in map, buffer, OR index
-            if self.inode_map.contains_key(&ino) || 
-               self.write_buffer.contains_key(&ino) || 
-               self.index.get_file(&path).is_some() {
-                
+
+            if self.in_federation(&path) {
+                let found = match self.federated(&path) {
+                    _ if self.is_federation_root(&path) || self.write_buffer.contains_key(&ino) => Ok(true),
+                    Some((remote, inner)) => remote.stat(&inner).map(|e| e.is_some()).map_err(|e| errno(&e)),
+                    None => Ok(false),
+                };
+                match found {
+                    Ok(true) => {
+                        self.inode_map.insert(ino, path.clone());
+                        reply.entry(&self.cache.entry_ttl, &self.get_file_attr(&path, ino), 0);
+                    }
+                    Ok(false) => reply.error(ENOENT),
+                    Err(code) => reply.error(code),
+                }
+                return;
+            }
+
+            // Allow lookup if it exists in the index, or (outside snapshots)
+            // is already known or being written
+            let known = !is_snapshot_path(&path) && (self.inode_map.contains_key(&ino) || self.write_buffer.contains_key(&ino));
+            if known || self.index.node(&path).is_some() {
+
                 self.inode_map.insert(ino, path.clone());
-                reply.entry(&TTL, &self.get_file_attr(&path, ino), 0);
+                reply.entry(&self.cache.entry_ttl, &self.get_file_attr(&path, ino), 0);
                 return;
             }
         }
@@ -91,44 +463,78 @@ impl Filesystem for LetheFS {
     // 2. GET ATTR
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         if let Some(path) = self.inode_map.get(&ino).cloned() {
-            reply.attr(&TTL, &self.get_file_attr(&path, ino));
+            reply.attr(&self.cache.attr_ttl, &self.get_file_attr(&path, ino));
         } else if ino == 1 {
-            reply.attr(&TTL, &self.get_file_attr("/", 1));
+            reply.attr(&self.cache.attr_ttl, &self.get_file_attr("/", 1));
+        } else if let Some(attr) = self.unlinked_attr(ino) {
+            reply.attr(&self.cache.attr_ttl, &attr);
         } else {
             reply.error(ENOENT);
         }
     }
 
-    // 3. SET ATTR (Resize/Truncate)
+    // 3. SET ATTR (Resize/Truncate/Touch)
     fn setattr(
         &mut self, _req: &Request, ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>,
-        size: Option<u64>, _atime: Option<TimeOrNow>, _mtime: Option<TimeOrNow>, _ctime: Option<SystemTime>,
-        _fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>,
+        size: Option<u64>, _atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>, _ctime: Option<SystemTime>,
+        fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>,
         _flags: Option<u32>, reply: ReplyAttr,
     ) {
+        self.activity.touch();
+        if let Some(buffer) = fh.and_then(|fh| self.unlinked_buffer(fh)) {
+            if let Err(e) = size.map_or(Ok(()), |size| buffer.set_len(size)) {
+                log::error!("Failed to resize an open file: {}", e);
+                reply.error(EIO);
+                return;
+            }
+            match self.unlinked_attr(ino) {
+                Some(attr) => reply.attr(&self.cache.attr_ttl, &attr),
+                None => reply.error(ENOENT),
+            }
+            return;
+        }
         if let Some(path) = self.inode_map.get(&ino).cloned() {
+            if (is_snapshot_path(&path) || self.is_federation_root(&path)) && (size.is_some() || mtime.is_some()) {
+                reply.error(EROFS);
+                return;
+            }
+            if (size.is_some() || mtime.is_some()) && self.index.get_file(&path).is_some_and(|e| e.immutable) {
+                reply.error(EPERM);
+                return;
+            }
             if let Some(new_size) = size {
                 // Ensure buffer exists before resizing
                 if !self.write_buffer.contains_key(&ino) {
                     // Load existing data if we are resizing a file that isn't open
-                    if let Some(entry) = self.index.get_file(&path) {
-                         let mut full_data = Vec::new();
-                         for block_id in &entry.blocks {
-                             if let Ok(mut chunk) = self.storage.read_block(block_id, &self.key) {
-                                 full_data.append(&mut chunk);
-                             }
-                         }
-                         self.write_buffer.insert(ino, full_data);
-                    } else {
-                         self.write_buffer.insert(ino, Vec::new());
+                    match self.open_buffer(&path, OpenMode { write: true, create: true, ..OpenMode::default() }) {
+                        Ok(buffer) => { self.write_buffer.insert(ino, buffer); }
+                        Err(code) => { reply.error(code); return; }
                     }
                 }
 
                 if let Some(buffer) = self.write_buffer.get_mut(&ino) {
-                     buffer.resize(new_size as usize, 0);
+                    if let Err(e) = buffer.set_len(new_size) {
+                        log::error!("Failed to resize an open file: {}", e);
+                        reply.error(EIO);
+                        return;
+                    }
+                }
+                self.mark_dirty(ino);
+            }
+
+            if let Some(mtime) = mtime {
+                let secs = match mtime {
+                    TimeOrNow::Now => now_secs(),
+                    TimeOrNow::SpecificTime(t) => t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+                };
+                // An open file would overwrite it on release, so hold it until then
+                if self.dirty.contains(&ino) {
+                    self.pending_mtime.insert(ino, secs);
+                } else if self.index.set_modified(&path, secs) {
+                    self.save_index();
                 }
             }
-            reply.attr(&TTL, &self.get_file_attr(&path, ino));
+            reply.attr(&self.cache.attr_ttl, &self.get_file_attr(&path, ino));
         } else {
             reply.error(ENOENT);
         }
@@ -136,6 +542,7 @@ impl Filesystem for LetheFS {
 
     // 4. READ DIR
     fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        self.activity.touch();
         let dir_path = match self.inode_map.get(&ino) {
             Some(p) => p.clone(),
             None => { reply.error(ENOENT); return; }
@@ -145,36 +552,37 @@ impl Filesystem for LetheFS {
             (ino, FileType::Directory, ".".to_string()),
             (ino, FileType::Directory, "..".to_string()),
         ];
-        let mut seen = HashSet::new();
-
-        for full_path in self.index.data.files.keys() {
-            if let Some(rest) = full_path.strip_prefix(&dir_path) {
-                let clean_rest = rest.trim_start_matches('/');
-                
-                if clean_rest.is_empty() { continue; }
-
-                let name = clean_rest.split('/').next().unwrap_or("");
-                
-                if !name.is_empty() && !seen.contains(name) {
-                    
-                    let child_full_path = if dir_path == "/" {
-                        format!("/{}", name)
-                    } else {
-                        format!("{}/{}", dir_path, name)
-                    };
-
-                    if full_path.starts_with(&child_full_path) {
-                        seen.insert(name.to_string());
-                        
-                        let is_file = self.index.get_file(&child_full_path).map(|e| !e.is_dir).unwrap_or(false);
-                        let kind = if is_file { FileType::RegularFile } else { FileType::Directory };
-                        
-                        let child_ino = fxhash::hash64(&child_full_path);
-                        
-                        entries.push((child_ino, kind, name.to_string()));
-                    }
-                }
+
+        if self.in_federation(&dir_path) {
+            let children = match self.federated(&dir_path) {
+                Some((remote, inner)) => remote.children(&inner).map_err(|e| errno(&e)),
+                None if self.is_federation_root(&dir_path) => Ok(self.remotes.iter().map(|r| (r.remote.name.clone(), true)).collect()),
+                None => Err(ENOENT),
+            };
+            let children = match children {
+                Ok(children) => children,
+                Err(code) => { reply.error(code); return; }
+            };
+            for (name, is_dir) in children {
+                let kind = if is_dir { FileType::Directory } else { FileType::RegularFile };
+                entries.push((fxhash::hash64(&child_path(&dir_path, &name)), kind, name));
+            }
+            for (i, (inode, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+                if reply.add(inode, (i + 1) as i64, kind, name) { break; }
             }
+            reply.ok();
+            return;
+        }
+        // Attached vaults shadow anything local at /remote
+        let shadowed = (dir_path == "/" && !self.remotes.is_empty()).then_some(&FEDERATION_DIR[1..]);
+        if shadowed.is_some() {
+            entries.push((fxhash::hash64(FEDERATION_DIR), FileType::Directory, FEDERATION_DIR[1..].to_string()));
+        }
+
+        for (name, node) in self.index.list(&dir_path) {
+            if shadowed == Some(name.as_str()) { continue; }
+            let kind = if node.is_dir() { FileType::Directory } else { FileType::RegularFile };
+            entries.push((fxhash::hash64(&child_path(&dir_path, &name)), kind, name));
         }
 
         for (i, (inode, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
@@ -184,25 +592,27 @@ impl Filesystem for LetheFS {
     }
 
     // 5. OPEN
-    fn open(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.activity.touch();
+        let snapshot = self.inode_map.get(&ino).is_some_and(|p| is_snapshot_path(p));
+        if snapshot && flags & O_ACCMODE != O_RDONLY {
+            reply.error(EROFS);
+            return;
+        }
         if self.write_buffer.contains_key(&ino) {
-            reply.opened(0, 0);
+            reply.opened(self.open_handle(ino), self.open_flags());
             return;
         }
 
         if let Some(path) = self.inode_map.get(&ino).cloned() {
-            if let Some(entry) = self.index.get_file(&path) {
-                let mut full_data = Vec::new();
-                for block_id in &entry.blocks {
-                    if let Ok(mut chunk) = self.storage.read_block(block_id, &self.key) {
-                        full_data.append(&mut chunk);
-                    }
+            let write = flags & O_ACCMODE != O_RDONLY;
+            let mode = OpenMode { write, create: write, truncate: flags & O_TRUNC != 0, ..OpenMode::default() };
+            match self.open_buffer(&path, mode) {
+                Ok(buffer) => {
+                    self.write_buffer.insert(ino, buffer);
+                    reply.opened(self.open_handle(ino), self.open_flags());
                 }
-                self.write_buffer.insert(ino, full_data);
-                reply.opened(0, 0);
-            } else {
-                self.write_buffer.insert(ino, Vec::new());
-                reply.opened(0, 0);
+                Err(code) => reply.error(code),
             }
         } else {
             reply.error(ENOENT);
@@ -210,23 +620,55 @@ impl Filesystem for LetheFS {
     }
 
     // 6. CREATE
-    fn create(&mut self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, _umask: u32, _flags: i32, reply: ReplyCreate) {
+    fn create(&mut self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, _umask: u32, flags: i32, reply: ReplyCreate) {
+        self.activity.touch();
         if let Some(path) = self.resolve_path(parent, name) {
+            if self.in_federation(&path) && self.federated(&path).is_none_or(|(_, inner)| inner == "/") {
+                reply.error(EACCES);
+                return;
+            }
+            let mode = OpenMode { write: true, create: true, create_new: flags & O_EXCL != 0, truncate: true };
+            let buffer = match self.open_buffer(&path, mode) {
+                Ok(buffer) => buffer,
+                Err(code) => { reply.error(code); return; }
+            };
             let ino = fxhash::hash64(&path);
             self.inode_map.insert(ino, path.clone());
-            self.write_buffer.insert(ino, Vec::new());
-            reply.created(&TTL, &self.get_file_attr(&path, ino), 0, 0, 0);
+            self.write_buffer.insert(ino, buffer);
+            self.mark_dirty(ino);
+            let fh = self.open_handle(ino);
+            reply.created(&self.cache.entry_ttl, &self.get_file_attr(&path, ino), 0, fh, self.open_flags());
         } else {
             reply.error(ENOENT);
         }
     }
 
     // 7. WRITE
-    fn write(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, data: &[u8], _wflags: u32, _flags: i32, _lock: Option<u64>, reply: ReplyWrite) {
+    fn write(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], _wflags: u32, _flags: i32, _lock: Option<u64>, reply: ReplyWrite) {
+        self.activity.touch();
+        // A deleted file has nowhere to be saved
+        if let Some(buffer) = self.unlinked_buffer(fh) {
+            match buffer.write_at(offset as u64, data) {
+                Ok(()) => reply.written(data.len() as u32),
+                Err(e) => {
+                    log::error!("Failed to spill an open file: {}", e);
+                    reply.error(EIO);
+                }
+            }
+            return;
+        }
+        if self.inode_map.get(&ino).is_some_and(|p| is_snapshot_path(p)) {
+            reply.error(EROFS);
+            return;
+        }
         if let Some(buffer) = self.write_buffer.get_mut(&ino) {
-            let end = offset as usize + data.len();
-            if end > buffer.len() { buffer.resize(end, 0); }
-            buffer[offset as usize..end].copy_from_slice(data);
+            if let Err(e) = buffer.write_at(offset as u64, data) {
+                log::error!("Failed to spill an open file: {}", e);
+                reply.error(EIO);
+                return;
+            }
+            self.mark_dirty(ino);
+            self.written_at.insert(ino, Instant::now());
             reply.written(data.len() as u32);
         } else {
             reply.error(ENOENT);
@@ -234,27 +676,33 @@ impl Filesystem for LetheFS {
     }
 
     // 8. READ
-    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, _flags: i32, _lock: Option<u64>, reply: ReplyData) {
-        if let Some(buffer) = self.write_buffer.get(&ino) {
-             let end = std::cmp::min((offset as u64 + size as u64) as usize, buffer.len());
-             if offset as usize >= buffer.len() { reply.data(&[]); } 
-             else { reply.data(&buffer[offset as usize..end]); }
+    fn read(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, size: u32, _flags: i32, _lock: Option<u64>, reply: ReplyData) {
+        self.activity.touch();
+        let buffer = match self.handles.get(&fh).and_then(|handle| handle.unlinked) {
+            Some(id) => self.unlinked.get_mut(&id),
+            None => self.write_buffer.get_mut(&ino),
+        };
+        if let Some(buffer) = buffer {
+             match buffer.read_at(offset as u64, size as usize) {
+                 Ok(data) => reply.data(&data),
+                 Err(e) => {
+                     log::error!("Failed to read back an open file: {}", e);
+                     reply.error(EIO);
+                 }
+             }
              return;
         }
         
         if let Some(path) = self.inode_map.get(&ino) {
-             if let Some(entry) = self.index.get_file(path) {
-                let mut full_data = Vec::new();
-                for block_id in &entry.blocks {
-                    if let Ok(mut chunk) = self.storage.read_block(block_id, &self.key) {
-                        full_data.append(&mut chunk);
+             match self.open_buffer(path, OpenMode::default()) {
+                Ok(mut buffer) => match buffer.read_at(offset as u64, size as usize) {
+                    Ok(data) => reply.data(&data),
+                    Err(e) => {
+                        log::error!("Failed to read {}: {}", path, e);
+                        reply.error(EIO);
                     }
-                }
-                let end = std::cmp::min((offset as u64 + size as u64) as usize, full_data.len());
-                if offset as usize >= full_data.len() { reply.data(&[]); } 
-                else { reply.data(&full_data[offset as usize..end]); }
-             } else {
-                 reply.error(ENOENT);
+                },
+                Err(code) => reply.error(code),
              }
         } else {
             reply.error(ENOENT);
@@ -262,13 +710,40 @@ impl Filesystem for LetheFS {
     }
 
     // 9. RELEASE
-    fn release(&mut self, _req: &Request, ino: u64, _fh: u64, _flags: i32, _lock: Option<u64>, _flush: bool, reply: ReplyEmpty) {
-        if let Some(data) = self.write_buffer.remove(&ino) {
-            if let Some(path) = self.inode_map.get(&ino).cloned() {
-                if let Ok(block_id) = self.storage.write_block(&data, &self.key) {
-                    self.index.add_file(path.clone(), vec![block_id], data.len() as u64);
-                    let _ = self.index.save(&self.key);
+    fn release(&mut self, _req: &Request, ino: u64, fh: u64, _flags: i32, lock: Option<u64>, _flush: bool, reply: ReplyEmpty) {
+        if let Some(owner) = lock {
+            for waiter in self.locks.release(ino, owner) {
+                waiter.ok();
+            }
+        }
+        // A deleted file is gone for good once its last handle closes
+        if let Some(id) = self.handles.remove(&fh).and_then(|handle| handle.unlinked) {
+            if !self.handles.values().any(|handle| handle.unlinked == Some(id)) {
+                self.unlinked.remove(&id);
+            }
+            reply.ok();
+            return;
+        }
+        // Saved, but kept for the handles still open on it
+        if self.open_on(ino).next().is_some() {
+            if let Some(mut data) = self.write_buffer.remove(&ino) {
+                let local = !self.is_remote(ino);
+                if self.dirty.contains(&ino) && self.persist(ino, &mut data) {
+                    self.clear_dirty(ino);
+                    if local {
+                        self.save_index();
+                    }
                 }
+                self.write_buffer.insert(ino, data);
+            }
+            reply.ok();
+            return;
+        }
+        if let Some(mut data) = self.write_buffer.remove(&ino) {
+            // Buffers opened only for reading have nothing to persist
+            let local = !self.is_remote(ino);
+            if self.clear_dirty(ino) && self.persist(ino, &mut data) && local {
+                self.save_index();
             }
         }
         reply.ok();
@@ -276,33 +751,92 @@ impl Filesystem for LetheFS {
 
     // 10. UNLINK
     fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.activity.touch();
         if let Some(path) = self.resolve_path(parent, name) {
-            if self.index.data.files.remove(&path).is_some() {
-                let ino = fxhash::hash64(&path);
-                self.inode_map.remove(&ino);
-                self.write_buffer.remove(&ino);
-                let _ = self.index.save(&self.key);
-                reply.ok();
-            } else {
-                reply.error(ENOENT);
+            if self.in_federation(&path) {
+                let deleted = match self.federated(&path) {
+                    Some((remote, inner)) if inner != "/" => remote.delete(&inner).map_err(|e| errno(&e)),
+                    _ => Err(EACCES),
+                };
+                match deleted {
+                    Ok(()) => {
+                        let ino = fxhash::hash64(&path);
+                        self.inode_map.remove(&ino);
+                        self.orphan(ino);
+                        reply.ok();
+                    }
+                    Err(code) => reply.error(code),
+                }
+                return;
+            }
+            match self.index.remove_file(&path) {
+                Ok(()) => {
+                    let ino = fxhash::hash64(&path);
+                    self.inode_map.remove(&ino);
+                    self.orphan(ino);
+                    self.save_index();
+                    reply.ok();
+                }
+                Err(e) => reply.error(vfs_errno(e)),
             }
         } else {
             reply.error(ENOENT);
         }
     }
 
+    // 10b. MKDIR
+    fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, _umask: u32, reply: ReplyEntry) {
+        self.activity.touch();
+        let Some(path) = self.resolve_path(parent, name) else {
+            reply.error(ENOENT);
+            return;
+        };
+        // An attached vault's folders are implied by the files in them
+        if self.in_federation(&path) {
+            reply.error(EACCES);
+            return;
+        }
+        match self.index.make_dir(&path) {
+            Ok(()) => {
+                let ino = fxhash::hash64(&path);
+                self.inode_map.insert(ino, path.clone());
+                self.save_index();
+                reply.entry(&self.cache.entry_ttl, &self.get_file_attr(&path, ino), 0);
+            }
+            Err(e) => reply.error(vfs_errno(e)),
+        }
+    }
+
     // 11. RMDIR
     fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.activity.touch();
         if let Some(dir_path) = self.resolve_path(parent, name) {
-            let is_empty = !self.index.data.files.keys().any(|k| {
-                 k.starts_with(&dir_path) && k.len() > dir_path.len() && k.chars().nth(dir_path.len()) == Some('/')
-            });
-            if is_empty {
-                let ino = fxhash::hash64(&dir_path);
-                self.inode_map.remove(&ino);
-                reply.ok();
-            } else {
-                reply.error(ENOTEMPTY); 
+            if self.in_federation(&dir_path) {
+                let deleted = match self.federated(&dir_path) {
+                    Some((remote, inner)) if inner != "/" => match remote.delete(&inner) {
+                        // A folder only implied by the files in it is gone with them
+                        Err(e) if e.status == Some(404) => Ok(()),
+                        Err(e) if e.status == Some(409) => Err(ENOTEMPTY),
+                        other => other.map_err(|e| errno(&e)),
+                    },
+                    _ => Err(EACCES),
+                };
+                match deleted {
+                    Ok(()) => {
+                        self.inode_map.remove(&fxhash::hash64(&dir_path));
+                        reply.ok();
+                    }
+                    Err(code) => reply.error(code),
+                }
+                return;
+            }
+            match self.index.remove_dir(&dir_path) {
+                Ok(()) => {
+                    self.inode_map.remove(&fxhash::hash64(&dir_path));
+                    self.save_index();
+                    reply.ok();
+                }
+                Err(e) => reply.error(vfs_errno(e)),
             }
         } else {
             reply.error(ENOENT);
@@ -311,25 +845,260 @@ impl Filesystem for LetheFS {
 
     // 12. RENAME
     fn rename(&mut self, _req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, _flags: u32, reply: ReplyEmpty) {
+        self.activity.touch();
         let old_path_opt = self.resolve_path(parent, name);
         let new_path_opt = self.resolve_path(newparent, newname);
 
         if let (Some(old_path), Some(new_path)) = (old_path_opt, new_path_opt) {
-            if let Some(entry) = self.index.data.files.remove(&old_path) {
-                self.index.data.files.insert(new_path.clone(), entry);
-                
-                let old_ino = fxhash::hash64(&old_path);
-                let new_ino = fxhash::hash64(&new_path);
-                self.inode_map.remove(&old_ino);
-                self.inode_map.insert(new_ino, new_path);
-
-                let _ = self.index.save(&self.key);
-                reply.ok();
-            } else {
-                reply.error(ENOENT);
+            if self.in_federation(&old_path) || self.in_federation(&new_path) {
+                let moved = match (self.federated(&old_path), self.federated(&new_path)) {
+                    (Some((from, old)), Some((to, new))) if old != "/" && new != "/" => {
+                        if from.remote.name != to.remote.name {
+                            Err(EXDEV)
+                        } else {
+                            from.rename(&old, &new).map_err(|e| errno(&e))
+                        }
+                    }
+                    (Some(_), None) | (None, Some(_)) if !self.is_federation_root(&old_path) && !self.is_federation_root(&new_path) => Err(EXDEV),
+                    _ => Err(EACCES),
+                };
+                match moved {
+                    Ok(()) => {
+                        self.orphan_replaced(&old_path, &new_path);
+                        self.inode_map.remove(&fxhash::hash64(&old_path));
+                        self.inode_map.insert(fxhash::hash64(&new_path), new_path);
+                        reply.ok();
+                    }
+                    Err(code) => reply.error(code),
+                }
+                return;
+            }
+            // A folder moves with everything in it
+            match self.index.rename(&old_path, &new_path) {
+                Ok(()) => {
+                    self.orphan_replaced(&old_path, &new_path);
+                    self.inode_map.retain(|_, path| !is_within(path, &old_path));
+                    self.inode_map.insert(fxhash::hash64(&new_path), new_path);
+                    self.save_index();
+                    reply.ok();
+                }
+                Err(e) => reply.error(vfs_errno(e)),
             }
         } else {
             reply.error(ENOENT);
         }
     }
-}
\ No newline at end of file
+
+    // 13. STATFS - capacity for df, Finder and file managers
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let space = volume::space(self.index.root_path(), self.index.used_bytes());
+        let blocks = space.total().div_ceil(STATFS_BSIZE);
+        let free = space.free / STATFS_BSIZE;
+        let files = self.index.data.files.len() as u64;
+        reply.statfs(blocks, free, free, files, u32::MAX as u64, STATFS_BSIZE as u32, 255, STATFS_BSIZE as u32);
+    }
+
+    // 14. LOCKS (fcntl)
+    fn getlk(&mut self, _req: &Request, ino: u64, _fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, reply: ReplyLock) {
+        let lock = Lock { owner: lock_owner, pid, start, end, write: typ == F_WRLCK };
+        match self.locks.conflict(ino, &lock) {
+            Some(held) => reply.locked(held.start, held.end, if held.write { F_WRLCK } else { F_RDLCK }, held.pid),
+            None => reply.locked(start, end, F_UNLCK, 0),
+        }
+    }
+
+    fn setlk(&mut self, _req: &Request, ino: u64, _fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+        let lock = Lock { owner: lock_owner, pid, start, end, write: typ == F_WRLCK };
+        match typ {
+            F_UNLCK => {
+                for waiter in self.locks.unlock(ino, lock_owner, start, end) {
+                    waiter.ok();
+                }
+                reply.ok();
+            }
+            F_RDLCK | F_WRLCK if sleep => {
+                // Answered when granted, which may be now
+                if let Some(reply) = self.locks.wait(ino, lock, reply) {
+                    reply.ok();
+                }
+            }
+            F_RDLCK | F_WRLCK => match self.locks.set(ino, lock) {
+                Ok(()) => reply.ok(),
+                Err(_) => reply.error(EAGAIN),
+            },
+            _ => reply.error(EINVAL),
+        }
+    }
+
+    // 15. FSYNC - the file's writes and the index, saved now
+    fn fsync(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        // A deleted file has nowhere to be saved
+        if self.handles.get(&fh).is_some_and(|handle| handle.unlinked.is_some()) {
+            reply.ok();
+            return;
+        }
+        let local = !self.is_remote(ino);
+        if let Some(mut buffer) = self.write_buffer.remove(&ino) {
+            let saved = !self.dirty.contains(&ino) || self.persist(ino, &mut buffer);
+            self.write_buffer.insert(ino, buffer);
+            if !saved {
+                reply.error(EIO);
+                return;
+            }
+            if self.clear_dirty(ino) && local {
+                self.unsaved_since.get_or_insert_with(Instant::now);
+            }
+        }
+        self.fsyncdir(req, ino, fh, datasync, reply);
+    }
+
+    fn fsyncdir(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        // Saved even if saves are coalesced; a failed save stays due
+        self.flush_index();
+        match self.unsaved_since {
+            Some(_) => reply.error(EIO),
+            None => reply.ok(),
+        }
+    }
+}
+
+// --- Background flush ---
+
+/// `LetheFS` behind a lock, so a timer thread can save idle buffers while
+/// the FUSE session owns the filesystem
+pub struct SharedFS(pub Arc<Mutex<LetheFS>>);
+
+impl SharedFS {
+    pub fn new(fs: LetheFS) -> Self {
+        Self(Arc::new(Mutex::new(fs)))
+    }
+
+    /// Until the filesystem is dropped, saves buffers idle for `idle` (if
+    /// given) and the index once `save_every` has passed since a change
+    pub fn spawn_flusher(&self, idle: Option<Duration>, save_every: Duration) {
+        let fs: Weak<Mutex<LetheFS>> = Arc::downgrade(&self.0);
+        let tick = [idle, Some(save_every)].into_iter().flatten()
+            .filter(|d| !d.is_zero())
+            .fold(Duration::from_secs(1), Duration::min);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(tick);
+            let Some(fs) = fs.upgrade() else { break };
+            let mut fs = fs.lock().unwrap();
+            if let Some(idle) = idle {
+                fs.flush_idle(idle);
+            }
+            fs.save_due();
+        });
+    }
+
+    fn fs(&self) -> std::sync::MutexGuard<'_, LetheFS> {
+        self.0.lock().unwrap()
+    }
+}
+
+impl Filesystem for SharedFS {
+    #[tracing::instrument(skip_all)]
+    fn init(&mut self, req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+        self.fs().init(req, config)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn destroy(&mut self) {
+        self.fs().destroy()
+    }
+
+    #[tracing::instrument(skip(self, req, reply))]
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.fs().lookup(req, parent, name, reply)
+    }
+
+    #[tracing::instrument(skip(self, req, reply))]
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        self.fs().getattr(req, ino, reply)
+    }
+
+    #[tracing::instrument(skip_all, fields(ino = ino, size = ?size))]
+    fn setattr(
+        &mut self, req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>,
+        size: Option<u64>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>, ctime: Option<SystemTime>,
+        fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>,
+        flags: Option<u32>, reply: ReplyAttr,
+    ) {
+        self.fs().setattr(req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime, flags, reply)
+    }
+
+    #[tracing::instrument(skip(self, req, fh, reply))]
+    fn readdir(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        self.fs().readdir(req, ino, fh, offset, reply)
+    }
+
+    #[tracing::instrument(skip(self, req, reply))]
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.fs().open(req, ino, flags, reply)
+    }
+
+    #[tracing::instrument(skip(self, req, mode, umask, flags, reply))]
+    fn create(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, umask: u32, flags: i32, reply: ReplyCreate) {
+        self.fs().create(req, parent, name, mode, umask, flags, reply)
+    }
+
+    #[tracing::instrument(skip_all, fields(ino = ino, offset = offset, len = data.len()))]
+    fn write(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, data: &[u8], wflags: u32, flags: i32, lock: Option<u64>, reply: ReplyWrite) {
+        self.fs().write(req, ino, fh, offset, data, wflags, flags, lock, reply)
+    }
+
+    #[tracing::instrument(skip_all, fields(ino = ino, offset = offset, size = size))]
+    fn read(&mut self, req: &Request, ino: u64, fh: u64, offset: i64, size: u32, flags: i32, lock: Option<u64>, reply: ReplyData) {
+        self.fs().read(req, ino, fh, offset, size, flags, lock, reply)
+    }
+
+    #[tracing::instrument(skip(self, req, fh, flags, lock, flush, reply))]
+    fn release(&mut self, req: &Request, ino: u64, fh: u64, flags: i32, lock: Option<u64>, flush: bool, reply: ReplyEmpty) {
+        self.fs().release(req, ino, fh, flags, lock, flush, reply)
+    }
+
+    #[tracing::instrument(skip(self, req, reply))]
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.fs().unlink(req, parent, name, reply)
+    }
+
+    #[tracing::instrument(skip(self, req, mode, umask, reply))]
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, umask: u32, reply: ReplyEntry) {
+        self.fs().mkdir(req, parent, name, mode, umask, reply)
+    }
+
+    #[tracing::instrument(skip(self, req, reply))]
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.fs().rmdir(req, parent, name, reply)
+    }
+
+    #[tracing::instrument(skip(self, req, flags, reply))]
+    fn rename(&mut self, req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32, reply: ReplyEmpty) {
+        self.fs().rename(req, parent, name, newparent, newname, flags, reply)
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
+        self.fs().statfs(req, ino, reply)
+    }
+
+    #[tracing::instrument(skip(self, req, fh, reply))]
+    fn getlk(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, reply: ReplyLock) {
+        self.fs().getlk(req, ino, fh, lock_owner, start, end, typ, pid, reply)
+    }
+
+    #[tracing::instrument(skip(self, req, fh, reply))]
+    fn setlk(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+        self.fs().setlk(req, ino, fh, lock_owner, start, end, typ, pid, sleep, reply)
+    }
+
+    #[tracing::instrument(skip(self, req, fh, reply))]
+    fn fsync(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.fs().fsync(req, ino, fh, datasync, reply)
+    }
+
+    #[tracing::instrument(skip(self, req, fh, reply))]
+    fn fsyncdir(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.fs().fsyncdir(req, ino, fh, datasync, reply)
+    }
+}
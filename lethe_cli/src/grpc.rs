@@ -0,0 +1,221 @@
+//! The vault over gRPC (`lethe grpc`, the `grpc` feature), for local
+//! programs that would rather call a typed API than run the CLI. The
+//! service is `proto/lethe.proto`.
+//!
+//! Files go both ways as streams: a get sends a file's blocks as they are
+//! read, and a put stages blocks as its messages arrive, with the index
+//! shared meanwhile, then commits them. Neither holds a whole file in
+//! memory. Every change is saved as it is made.
+
+use std::io::{self, Read};
+
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use zeroize::Zeroizing;
+
+use lethe_core::index::{is_snapshot_path, is_within};
+use lethe_core::nonblocking::{self, Vault};
+use lethe_core::progress::Silent;
+
+pub mod proto {
+    tonic::include_proto!("lethe.v1");
+}
+
+use proto::put_request::Part;
+use proto::vault_server::{self, VaultServer};
+use proto::{
+    Chunk, DeleteRequest, DeleteResponse, Entry, GetRequest, ListRequest, ListResponse, PutRequest,
+    SnapshotRequest, SnapshotResponse,
+};
+
+/// Most file data in one message; clients take up to 4 MiB by default
+const CHUNK: usize = 1024 * 1024;
+
+/// Messages waiting on either side of a transfer, so a slow client or
+/// slow storage holds back the other by only so much
+const QUEUED: usize = 8;
+
+/// The unlocked vault, served
+pub struct Service {
+    vault: Vault,
+}
+
+impl Service {
+    pub fn new(vault: Vault) -> VaultServer<Self> {
+        VaultServer::new(Self { vault })
+    }
+
+    /// Stores what `reader` yields at `path` and saves the index
+    async fn store(&self, path: String, reader: Incoming) -> anyhow::Result<()> {
+        // A hidden vault's blocks go where it left room for them, one file at a time
+        if self.vault.index.read().await.is_hidden() {
+            return self.vault.with_index(move |index, storage, key| {
+                index.check_mutable(&path)?;
+                index.store_reader(storage, key, path, reader, &Silent)?;
+                index.save(key)
+            }).await;
+        }
+        let checked = path.clone();
+        let staged = self.vault.read_index(move |index, storage, key| {
+            index.check_mutable(&checked)?;
+            index.stage_reader(storage, key, reader)
+        }).await?;
+        self.vault.with_index(move |index, _, key| {
+            index.check_mutable(&path)?;
+            index.commit(path, staged);
+            index.save(key)
+        }).await
+    }
+}
+
+/// `docs/a.pdf`, `/docs/a.pdf/` -> `/docs/a.pdf`
+fn vault_path(path: &str) -> String {
+    format!("/{}", path.trim_matches('/'))
+}
+
+fn internal(e: anyhow::Error) -> Status {
+    Status::internal(format!("{:#}", e))
+}
+
+#[tonic::async_trait]
+impl vault_server::Vault for Service {
+    async fn list(&self, request: Request<ListRequest>) -> Result<Response<ListResponse>, Status> {
+        let prefix = vault_path(&request.into_inner().prefix);
+        let index = self.vault.index.read().await;
+        let mut entries: Vec<Entry> = index.data.files.iter()
+            .filter(|(path, e)| is_within(path, &prefix) && !e.is_expired())
+            .map(|(path, e)| Entry { path: path.clone(), size: e.size, modified: e.modified, is_dir: e.is_dir })
+            .collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Response::new(ListResponse { entries }))
+    }
+
+    type GetStream = ReceiverStream<Result<Chunk, Status>>;
+
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<Self::GetStream>, Status> {
+        let path = vault_path(&request.into_inner().path);
+        let blocks = match self.vault.index.read().await.get_file(&path) {
+            Some(e) if !e.is_dir => e.blocks.clone(),
+            Some(_) => return Err(Status::invalid_argument(format!("{} is a folder", path))),
+            None => return Err(Status::not_found(format!("{} not found", path))),
+        };
+
+        let (tx, rx) = mpsc::channel(QUEUED);
+        let (storage, key) = (self.vault.storage.clone(), self.vault.key.clone());
+        tokio::spawn(async move {
+            for block in blocks {
+                let data = match nonblocking::read_block(storage.clone(), block, key.clone()).await {
+                    Ok(data) => Zeroizing::new(data),
+                    Err(e) => {
+                        let _ = tx.send(Err(internal(e))).await;
+                        return;
+                    }
+                };
+                for piece in data.chunks(CHUNK) {
+                    // The client went away
+                    if tx.send(Ok(Chunk { data: piece.to_vec() })).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn put(&self, request: Request<Streaming<PutRequest>>) -> Result<Response<Entry>, Status> {
+        let mut messages = request.into_inner();
+        let path = match messages.message().await? {
+            Some(PutRequest { part: Some(Part::Path(path)) }) => vault_path(&path),
+            _ => return Err(Status::invalid_argument("The first message must carry the path")),
+        };
+        if is_snapshot_path(&path) {
+            return Err(Status::permission_denied("Snapshots are read-only"));
+        }
+        {
+            let index = self.vault.index.read().await;
+            if index.get_file(&path).is_some_and(|e| e.is_dir) {
+                return Err(Status::already_exists(format!("{} is a folder", path)));
+            }
+            index.check_new_path(&path).map_err(|e| Status::invalid_argument(format!("{:#}", e)))?;
+            index.check_mutable(&path).map_err(|e| Status::permission_denied(format!("{:#}", e)))?;
+        }
+
+        let (tx, rx) = mpsc::channel(QUEUED);
+        let feed = async move {
+            loop {
+                let data = match messages.message().await {
+                    Ok(Some(PutRequest { part: Some(Part::Data(data)) })) => Ok(Zeroizing::new(data)),
+                    Ok(Some(_)) => Err(io::Error::new(io::ErrorKind::InvalidData, "Only the first message may carry a path")),
+                    // End of file: dropping `tx` tells the reader
+                    Ok(None) => return,
+                    Err(status) => Err(io::Error::other(status.message().to_string())),
+                };
+                let failed = data.is_err();
+                // The store gave up, or the client did
+                if tx.send(data).await.is_err() || failed {
+                    return;
+                }
+            }
+        };
+        let reader = Incoming { messages: rx, data: Zeroizing::new(Vec::new()), at: 0 };
+        let ((), stored) = tokio::join!(feed, self.store(path.clone(), reader));
+        stored.map_err(internal)?;
+
+        let index = self.vault.index.read().await;
+        let entry = index.get_file(&path).ok_or_else(|| Status::internal(format!("{} went missing", path)))?;
+        Ok(Response::new(Entry { path: path.clone(), size: entry.size, modified: entry.modified, is_dir: false }))
+    }
+
+    async fn delete(&self, request: Request<DeleteRequest>) -> Result<Response<DeleteResponse>, Status> {
+        let path = vault_path(&request.into_inner().path);
+        if is_snapshot_path(&path) {
+            return Err(Status::permission_denied("Snapshots are read-only"));
+        }
+        let mut index = self.vault.index.write().await;
+        if index.has_children(&path) {
+            return Err(Status::failed_precondition(format!("{} is not empty", path)));
+        }
+        index.check_mutable(&path).map_err(|e| Status::permission_denied(format!("{:#}", e)))?;
+        if index.data.files.remove(&path).is_none() {
+            return Err(Status::not_found(format!("{} not found", path)));
+        }
+        index.touch_parent(&path);
+        tokio::task::block_in_place(|| index.save(&self.vault.key)).map_err(internal)?;
+        Ok(Response::new(DeleteResponse {}))
+    }
+
+    async fn snapshot(&self, request: Request<SnapshotRequest>) -> Result<Response<SnapshotResponse>, Status> {
+        let name = request.into_inner().name;
+        let mut index = self.vault.index.write().await;
+        index.create_snapshot(&name).map_err(|e| Status::invalid_argument(format!("{:#}", e)))?;
+        tokio::task::block_in_place(|| index.save(&self.vault.key)).map_err(internal)?;
+        let created = index.data.snapshots.get(&name).map(|s| s.created).unwrap_or(0);
+        Ok(Response::new(SnapshotResponse { name, created }))
+    }
+}
+
+/// The data of a put as its messages arrive, for the blocking pool
+struct Incoming {
+    messages: mpsc::Receiver<io::Result<Zeroizing<Vec<u8>>>>,
+    data: Zeroizing<Vec<u8>>,
+    at: usize,
+}
+
+impl Read for Incoming {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.at == self.data.len() {
+            match self.messages.blocking_recv() {
+                Some(data) => {
+                    self.data = data?;
+                    self.at = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.data.len() - self.at);
+        buf[..n].copy_from_slice(&self.data[self.at..self.at + n]);
+        self.at += n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,144 @@
+//! POSIX record locks (`fcntl` `F_SETLK`, `F_SETLKW`, `F_GETLK`) taken on
+//! files in the FUSE mount.
+//!
+//! The kernel hands them to the mount, which keeps them here, in memory
+//! only: they last as long as the mount, like locks on any other file
+//! system last as long as the machine is up. A lock belongs to an owner
+//! (the kernel's id for a process's open files) and covers a range of
+//! bytes, inclusive at both ends; reading locks may overlap each other,
+//! a writing lock overlaps nothing of another owner. An owner locking a
+//! range it already holds changes it in place, splitting what it had.
+//!
+//! `F_SETLKW` waits, and the mount must not: the request is parked with
+//! its reply and answered once whatever was in its way is unlocked.
+//! Deadlocks between two waiting owners aren't detected.
+
+use std::collections::HashMap;
+
+/// A held lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lock {
+    pub owner: u64,
+    pub pid: u32,
+    pub start: u64,
+    pub end: u64,
+    pub write: bool,
+}
+
+impl Lock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    /// Whether `other` can't be held alongside this
+    fn conflicts(&self, other: &Lock) -> bool {
+        self.owner != other.owner && (self.write || other.write) && self.overlaps(other.start, other.end)
+    }
+}
+
+/// The locks held on a mount's files, and the requests waiting for one;
+/// `W` is how a waiting request is answered
+pub struct LockTable<W> {
+    /// Inode -> locks on it
+    held: HashMap<u64, Vec<Lock>>,
+    /// Requests to lock an inode, in the order they came
+    waiting: Vec<(u64, Lock, W)>,
+}
+
+impl<W> Default for LockTable<W> {
+    fn default() -> Self {
+        Self { held: HashMap::new(), waiting: Vec::new() }
+    }
+}
+
+impl<W> LockTable<W> {
+    /// A lock of another owner that `lock` can't be held alongside, if any
+    pub fn conflict(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        self.held.get(&ino)?.iter().find(|held| held.conflicts(lock)).copied()
+    }
+
+    /// Takes `lock`, or says what is in its way
+    pub fn set(&mut self, ino: u64, lock: Lock) -> Result<(), Lock> {
+        if let Some(conflict) = self.conflict(ino, &lock) {
+            return Err(conflict);
+        }
+        self.clear(ino, lock.owner, lock.start, lock.end);
+        self.held.entry(ino).or_default().push(lock);
+        Ok(())
+    }
+
+    /// Takes `lock` once nothing is in its way: now, or when what is has
+    /// been unlocked and `waiter` is handed back by `unlock`
+    pub fn wait(&mut self, ino: u64, lock: Lock, waiter: W) -> Option<W> {
+        match self.set(ino, lock) {
+            Ok(()) => Some(waiter),
+            Err(_) => {
+                self.waiting.push((ino, lock, waiter));
+                None
+            }
+        }
+    }
+
+    /// Lets go of `owner`'s locks between `start` and `end`. Returns the
+    /// waiting requests that got their lock as a result.
+    pub fn unlock(&mut self, ino: u64, owner: u64, start: u64, end: u64) -> Vec<W> {
+        self.clear(ino, owner, start, end);
+        self.grant(ino)
+    }
+
+    /// Lets go of everything `owner` holds or waits for on `ino`, as when
+    /// it closes the file. Returns the requests of others that got their
+    /// lock as a result; `owner`'s own are dropped unanswered.
+    pub fn release(&mut self, ino: u64, owner: u64) -> Vec<W> {
+        self.waiting.retain(|(i, lock, _)| *i != ino || lock.owner != owner);
+        self.unlock(ino, owner, 0, u64::MAX)
+    }
+
+    /// Drops every lock and hands back every waiting request, e.g. on unmount
+    pub fn clear_all(&mut self) -> Vec<W> {
+        self.held.clear();
+        self.waiting.drain(..).map(|(_, _, waiter)| waiter).collect()
+    }
+
+    /// Removes `owner`'s locks between `start` and `end` on `ino`, keeping
+    /// the parts of them outside it
+    fn clear(&mut self, ino: u64, owner: u64, start: u64, end: u64) {
+        let Some(held) = self.held.get_mut(&ino) else { return };
+        let mut kept = Vec::with_capacity(held.len());
+        for lock in held.drain(..) {
+            if lock.owner != owner || !lock.overlaps(start, end) {
+                kept.push(lock);
+                continue;
+            }
+            if lock.start < start {
+                kept.push(Lock { end: start - 1, ..lock });
+            }
+            if lock.end > end {
+                kept.push(Lock { start: end + 1, ..lock });
+            }
+        }
+        if kept.is_empty() {
+            self.held.remove(&ino);
+        } else {
+            *held = kept;
+        }
+    }
+
+    /// Gives waiting requests on `ino` their lock where nothing is in the
+    /// way any more, first come first served
+    fn grant(&mut self, ino: u64) -> Vec<W> {
+        let mut granted = Vec::new();
+        let mut i = 0;
+        while i < self.waiting.len() {
+            let (waiting_ino, lock, _) = &self.waiting[i];
+            if *waiting_ino == ino && self.conflict(ino, lock).is_none() {
+                let (_, lock, waiter) = self.waiting.remove(i);
+                let _ = self.set(ino, lock);
+                granted.push(waiter);
+            } else {
+                i += 1;
+            }
+        }
+        granted
+    }
+}
@@ -1,30 +1,207 @@
+mod accounts;
 mod cli;
+mod container;
+mod daemon;
+mod settings;
+mod spill;
+mod sync;
+mod trace;
+mod vfs;
+mod volume;
 
-// Only compile the WebDAV module on Windows
-#[cfg(windows)]
+// The WebDAV server backs mounts on Windows; elsewhere it is opt-in
+#[cfg(any(windows, feature = "server"))]
 mod dav;
+#[cfg(any(windows, feature = "server"))]
+mod server;
+// `lethe grpc` is opt-in everywhere
+#[cfg(feature = "grpc")]
+mod grpc;
 
 // Only compile the FUSE module on Unix
 #[cfg(unix)]
 mod fs_fuse;
+#[cfg(unix)]
+mod federation;
+#[cfg(unix)]
+mod locks;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
-use cli::{Cli, Commands};
+use cli::{AclAction, AgentAction, BundleAction, Cli, Commands, ConflictsAction, DaemonAction, DevicesAction, HiddenAction, RemoteAction, SnapshotAction, SyncdAction, UsersAction, VolumesAction};
+use daemon::SentinelConfig;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
-    let cli = Cli::parse();
+    // git-annex runs special remotes as `git-annex-remote-<type>`
+    let program = std::env::args_os().next().map(std::path::PathBuf::from);
+    let cli = match program.as_deref().and_then(|p| p.file_stem()) {
+        Some(name) if name == cli::annex::PROGRAM => Cli::parse_from(["lethe", "annex-remote"]),
+        Some(name) if name == cli::agent::PROGRAM => {
+            let args = std::env::args_os().skip(1);
+            Cli::parse_from(["lethe", "agent", "run"].into_iter().map(Into::into).chain(args))
+        }
+        _ => Cli::parse(),
+    };
+
+    let mut logger = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"));
+    if let Commands::Daemon { action: DaemonAction::Run { service: true, .. } } = &cli.command {
+        daemon::service::enter_service_mode(&mut logger)?;
+    }
+    logger.init();
+    let _trace = cli.trace_file.as_deref().map(trace::to_file).transpose()?;
 
-    match cli.command {
-        Commands::Init { path } => cli::ops::do_init(path),
-        Commands::Put { file, dest, vault } => cli::ops::do_put(file, dest, vault),
+    let result = match cli.command {
+        Commands::Init { path, container, wipe_after } => cli::ops::do_init(path, container, wipe_after),
+        Commands::Put { file, dest, vault, expire, immutable } => cli::ops::do_put(file, dest, vault, expire, immutable),
         Commands::Ls { vault } => cli::ops::do_ls(vault),
-        Commands::Get { src, out, vault } => cli::ops::do_get(src, out, vault),
+        Commands::Get { src, out, vault, jobs } => cli::ops::do_get(src, out, vault, jobs),
+        Commands::Restore { manifest, out, vault, jobs } => cli::restore::do_restore(manifest, out, vault, jobs),
+        Commands::Publish { prefix, out, vault, no_index } => cli::publish::do_publish(prefix, out, vault, no_index),
+        Commands::Drill { sample, vault, tmp } => cli::drill::do_drill(vault, sample, tmp),
         Commands::Repair { vault } => cli::ops::do_repair(vault),
-        Commands::Mount { vault, mountpoint } => cli::mount::do_mount(vault, mountpoint).await,
-        Commands::Panic => cli::mount::do_panic(),
-        Commands::Clean { vault, dry_run } => cli::ops::do_clean(vault, dry_run),
-    }
-}
\ No newline at end of file
+        Commands::Release { path, vault } => cli::ops::do_release(path, vault),
+        Commands::Mount {
+            vault, mountpoint, idle_timeout, no_auto_lock, web_ui, flush_after, save_every,
+            attr_ttl, entry_ttl, direct_io, writeback_cache, buffer_mb, read_ahead, cache_mb, cache_disk_mb, attach,
+        } => {
+            let flush_after = Some(Duration::from_secs(flush_after)).filter(|d| !d.is_zero());
+            let cache = cli::mount::KernelCache {
+                attr_ttl: Duration::try_from_secs_f64(attr_ttl).context("--attr-ttl must be zero or more seconds")?,
+                entry_ttl: Duration::try_from_secs_f64(entry_ttl).context("--entry-ttl must be zero or more seconds")?,
+                direct_io,
+                writeback: writeback_cache,
+            };
+            let buffer_limit = buffer_mb.saturating_mul(1024 * 1024);
+            let save_every = Duration::from_secs(save_every);
+            let config = lethe_core::VaultConfig { read_ahead, cache_mb, cache_disk_mb, ..Default::default() };
+            let opts = cli::mount::MountOptions { mountpoint, web_ui, flush_after, save_every, cache, buffer_limit, config, attach };
+            cli::mount::do_mount(vault, opts, SentinelConfig::from_args(idle_timeout, no_auto_lock)).await
+        }
+        Commands::Serve { vault, listen, web_ui, previews, users, replica_of, pull_every } => {
+            let pull_every = Duration::from_secs(pull_every.max(1));
+            cli::serve::do_serve(cli::serve::ServeArgs { vault, listen, web_ui, previews, users, replica_of, pull_every }).await
+        }
+        Commands::S3Serve { vault, listen, bucket, access_key } => cli::serve::do_s3_serve(vault, listen, bucket, access_key).await,
+        Commands::Grpc { vault, socket, listen } => cli::grpc::do_grpc(vault, socket, listen).await,
+        Commands::Users { action } => match action {
+            UsersAction::Add { file: Some(file), name, read, write, .. } => cli::users::do_users_add(file, name, read, write),
+            UsersAction::Add { vault, name, read, write, .. } => {
+                cli::users::do_vault_user_add(vault.unwrap_or_default(), name, read, write)
+            }
+            UsersAction::List { file: Some(file), .. } => cli::users::do_users_list(file),
+            UsersAction::List { vault, .. } => cli::users::do_vault_users_list(vault.unwrap_or_default()),
+            UsersAction::Remove { name, vault } => cli::users::do_vault_user_remove(name, vault),
+        },
+        Commands::Acl { action } => match action {
+            AclAction::Set { prefix, read, write, vault } => cli::acl::do_acl_set(prefix, read, write, vault),
+            AclAction::Clear { prefix, vault } => cli::acl::do_acl_clear(prefix, vault),
+            AclAction::List { vault } => cli::acl::do_acl_list(vault),
+            AclAction::Check { user, path, vault } => cli::acl::do_acl_check(user, path, vault),
+        },
+        Commands::Pad { mb, entries, vault } => cli::hidden::do_pad(vault, mb, entries),
+        Commands::Hidden { action } => match action {
+            HiddenAction::Create { mb, vault } => cli::hidden::do_hidden_create(vault, mb),
+        },
+        Commands::Snapshot { action } => match action {
+            SnapshotAction::Create { name, vault } => cli::snapshot::do_snapshot_create(vault, name),
+            SnapshotAction::List { vault } => cli::snapshot::do_snapshot_list(vault),
+            SnapshotAction::Delete { name, vault } => cli::snapshot::do_snapshot_delete(vault, name),
+            SnapshotAction::Retention { every, keep, max_age, vault } => cli::snapshot::do_snapshot_retention(vault, every, keep, max_age),
+        },
+        Commands::SyncPeer { peer, listen, vault, bwlimit } => cli::sync::do_sync_peer(vault, peer, listen, bwlimit).await,
+        Commands::Push { remote, vault, index, force, bwlimit } => cli::sync::do_push(remote, index, vault, force, bwlimit),
+        Commands::Pull { remote, vault, index, force, bwlimit } => cli::sync::do_pull(remote, index, vault, force, bwlimit),
+        Commands::Scrub { remote, vault, index, deep } => cli::sync::do_scrub(remote, index, vault, deep),
+        Commands::Remote { action } => match action {
+            RemoteAction::Set { remote, index, vault } => cli::sync::do_remote_set(remote, index, vault),
+            RemoteAction::Show { vault } => cli::sync::do_remote_show(vault),
+            RemoteAction::Clear { vault } => cli::sync::do_remote_clear(vault),
+            RemoteAction::Flush { vault } => cli::sync::do_remote_flush(vault),
+        },
+        Commands::Syncd { action } => match action {
+            SyncdAction::Run { vault, interval, every, stale_after } => cli::syncd::do_syncd_run(vault, interval, every, stale_after).await,
+            SyncdAction::Folder { path, into, vault } => cli::syncd::do_syncd_folder(path, into, vault),
+            SyncdAction::Peer { addr, vault } => cli::syncd::do_syncd_peer(addr, vault),
+            SyncdAction::Remote { remote, vault } => cli::syncd::do_syncd_remote(remote, vault),
+            SyncdAction::Forget { what, vault } => cli::syncd::do_syncd_forget(what, vault),
+            SyncdAction::List { vault } => cli::syncd::do_syncd_list(vault),
+            SyncdAction::Status { vault } => cli::syncd::do_syncd_status(vault).await,
+            SyncdAction::Stop { vault } => cli::syncd::do_syncd_stop(vault).await,
+        },
+        Commands::Volumes { action } => match action {
+            VolumesAction::Add { path, name, capacity, vault } => cli::volumes::do_volumes_add(path, name, capacity, vault),
+            VolumesAction::List { vault } => cli::volumes::do_volumes_list(vault),
+            VolumesAction::Remove { name, vault } => cli::volumes::do_volumes_remove(name, vault),
+            VolumesAction::Policy { policy, vault } => cli::volumes::do_volumes_policy(policy, vault),
+            VolumesAction::Capacity { name, capacity, vault } => cli::volumes::do_volumes_capacity(name, capacity, vault),
+            VolumesAction::Copies { n, vault } => cli::volumes::do_volumes_copies(n as usize, vault),
+        },
+        Commands::Bundle { action } => match action {
+            BundleAction::Create { since, out, vault } => cli::sync::do_bundle_create(since, out, vault),
+            BundleAction::Apply { bundle, vault } => cli::sync::do_bundle_apply(bundle, vault),
+            BundleAction::List { vault } => cli::sync::do_bundle_list(vault),
+        },
+        Commands::Pack { out, vault } => cli::pack::do_pack(out, vault),
+        Commands::Unpack { pack, into } => cli::pack::do_unpack(pack, into),
+        Commands::Share { paths, out, vault } => cli::share::do_share(paths, out, vault),
+        Commands::OpenShare { bundle, out, list } => cli::share::do_open_share(bundle, out, list),
+        Commands::Events { follow, new, json, vault } => cli::events::do_events(vault, follow, new, json).await,
+        Commands::AnnexRemote => cli::annex::do_annex_remote(),
+        Commands::Manifest { out, vault } => cli::manifest::do_manifest(vault, out),
+        Commands::Verify { manifest, key, dirs } => cli::manifest::do_verify(manifest, key, dirs),
+        Commands::Audit { vault } => cli::audit::do_audit(vault),
+        Commands::Stats { vault, history: true, capacity, .. } => cli::stats::do_stats_history(vault, capacity),
+        Commands::Stats { vault, compression: true, .. } => cli::stats::do_stats_compression(vault),
+        Commands::Stats { vault, activity: true, .. } => cli::stats::do_stats_activity(vault),
+        Commands::Stats { vault, stale_after, forget, .. } => cli::stats::do_stats(vault, stale_after, forget),
+        Commands::IndexContent { vault } => cli::search::do_index_content(vault),
+        Commands::Grep { query, vault, files_only } => cli::search::do_grep(query, vault, files_only),
+        Commands::Dupes { vault, link } => cli::dupes::do_dupes(vault, link),
+        Commands::Open { forensic, vault } => cli::open::do_open(vault, forensic),
+        Commands::Inspect { path, decrypt, vault } => cli::inspect::do_inspect(vault, path, decrypt),
+        Commands::Conflicts { action } => match action {
+            ConflictsAction::List { vault } => cli::conflicts::do_conflicts_list(vault),
+            ConflictsAction::Resolve { copy, keep, vault } => cli::conflicts::do_conflicts_resolve(copy, keep, vault),
+        },
+        Commands::Devices { action } => match action {
+            DevicesAction::Enable { name, vault } => cli::devices::do_devices_enable(vault, name),
+            DevicesAction::List { vault } => cli::devices::do_devices_list(vault),
+            DevicesAction::Enroll { name, vault } => cli::devices::do_devices_enroll(name, vault),
+            DevicesAction::Join { code, vault } => cli::devices::do_devices_join(code, vault),
+            DevicesAction::Revoke { device, vault } => cli::devices::do_devices_revoke(device, vault),
+        },
+        Commands::Gui { vault, mountpoint } => cli::gui::do_gui(vault, mountpoint),
+        Commands::Mounts { verbose } => cli::mount::do_mounts(verbose).await,
+        Commands::Unmount { target } => cli::mount::do_unmount(target).await,
+        Commands::Panic => cli::mount::do_panic().await,
+        Commands::Relocate { old, new } => cli::relocate::do_relocate(old, new).await,
+        Commands::Clean { vault, dry_run, grace, purge_snapshots_older_than } => cli::ops::do_clean(vault, dry_run, grace, purge_snapshots_older_than),
+        Commands::Upgrade { vault } => cli::ops::do_upgrade(vault),
+        Commands::ChunkSize { size, vault } => cli::ops::do_chunk_size(vault, size),
+        Commands::WindowsNames { setting, vault } => cli::ops::do_windows_names(vault, setting),
+        Commands::Agent { action } => match action {
+            AgentAction::Run { ttl } => cli::agent::do_agent_run(ttl).await,
+            AgentAction::List => cli::agent::do_agent_list(),
+            AgentAction::Forget { vault } => cli::agent::do_agent_forget(vault),
+            AgentAction::Stop => cli::agent::do_agent_stop(),
+        },
+        Commands::Daemon { action } => match action {
+            DaemonAction::Run { vault, mountpoint, idle_timeout, no_auto_lock, .. } => {
+                cli::daemon::do_daemon_run(vault, mountpoint, SentinelConfig::from_args(idle_timeout, no_auto_lock)).await
+            }
+            DaemonAction::Install { vault, mountpoint, idle_timeout, no_auto_lock } => {
+                cli::daemon::do_daemon_install(vault, mountpoint, idle_timeout, no_auto_lock)
+            }
+            DaemonAction::Uninstall => cli::daemon::do_daemon_uninstall(),
+            DaemonAction::Status { vault } => cli::daemon::do_daemon_status(vault).await,
+            DaemonAction::Lock { vault } => cli::daemon::do_daemon_lock(vault).await,
+            DaemonAction::Unlock { vault } => cli::daemon::do_daemon_unlock(vault).await,
+        },
+    };
+
+    // Changes made inside a container only reach its file here
+    container::close_all();
+    result
+}
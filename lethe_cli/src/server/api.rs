@@ -0,0 +1,359 @@
+//! Versioned JSON API for apps that don't speak WebDAV.
+//!
+//! - `GET    /api/v1/files?prefix=/docs` - list entries
+//! - `GET    /api/v1/files/<path>`       - download a file
+//! - `PUT    /api/v1/files/<path>`       - upload (replaces) a file
+//! - `PATCH  /api/v1/files/<path>`       - rename, body `{"path": "/new"}`
+//! - `DELETE /api/v1/files/<path>`       - delete a file or empty folder
+//! - `GET    /api/v1/previews/<path>`    - thumbnail of an image or PDF,
+//!   with `--previews` (see `preview`)
+//! - `GET    /api/v1/zip?prefix=/photos` - download a folder as a zip,
+//!   streamed as blocks are read (see `zip`)
+//! - `GET    /api/v1/blocks/stats`       - block storage usage
+//! - `POST   /api/v1/lock`               - lock the vault
+//!
+//! These routes sit in the same filter tree as WebDAV, so whatever guards
+//! the DAV endpoint guards them too. Under `lethe serve` the lock endpoint
+//! drops the key but keeps serving; see `session`.
+
+use serde::{Deserialize, Serialize};
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::path::Tail;
+use warp::{Filter, Rejection, Reply};
+
+use lethe_core::index::{is_below, is_snapshot_path, is_within};
+
+use super::preview;
+use super::zip::{self, Member};
+use crate::dav::LetheState;
+
+pub(super) type ApiReply = Result<Box<dyn Reply>, Rejection>;
+
+#[derive(Serialize)]
+struct ApiError {
+    error: String,
+}
+
+/// `{"error": msg}`, for replies that need more than a status
+pub(super) fn error_body(msg: impl Into<String>) -> warp::reply::Json {
+    warp::reply::json(&ApiError { error: msg.into() })
+}
+
+pub(super) fn error(status: StatusCode, msg: impl Into<String>) -> ApiReply {
+    Ok(Box::new(warp::reply::with_status(error_body(msg), status)))
+}
+
+pub(super) fn json<T: Serialize>(value: &T) -> ApiReply {
+    Ok(Box::new(warp::reply::json(value)))
+}
+
+#[derive(Serialize)]
+struct Entry {
+    path: String,
+    size: u64,
+    modified: u64,
+    is_dir: bool,
+}
+
+#[derive(Serialize)]
+struct Ack {
+    ok: bool,
+}
+
+pub(super) fn ack() -> ApiReply {
+    json(&Ack { ok: true })
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    prefix: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ZipQuery {
+    prefix: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RenameBody {
+    path: String,
+}
+
+#[derive(Serialize)]
+struct BlockStats {
+    blocks: usize,
+    referenced: usize,
+    orphaned: usize,
+    bytes_on_disk: u64,
+    orphaned_bytes: u64,
+    plaintext_bytes: u64,
+}
+
+/// Decodes `%XX` escapes; invalid ones are kept as-is
+pub(super) fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// `Tail` is still percent-encoded; vault paths are stored decoded
+fn vault_path(tail: &Tail) -> String {
+    format!("/{}", percent_decode(tail.as_str()).trim_end_matches('/'))
+}
+
+// --- Handlers ---
+
+async fn list_files(query: ListQuery, state: LetheState) -> ApiReply {
+    state.activity.touch();
+    let prefix = query.prefix.unwrap_or_else(|| "/".to_string());
+    let index = state.index.read().await;
+    let mut entries: Vec<Entry> = index.data.files.iter()
+        .filter(|(path, e)| is_within(path, &prefix) && !e.is_expired())
+        .map(|(path, e)| Entry { path: path.clone(), size: e.size, modified: e.modified, is_dir: e.is_dir })
+        .collect();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    json(&entries)
+}
+
+async fn get_file(tail: Tail, state: LetheState) -> ApiReply {
+    state.activity.touch();
+    let path = vault_path(&tail);
+    let blocks = match state.index.read().await.get_file(&path) {
+        Some(e) if !e.is_dir => e.blocks.clone(),
+        Some(_) => return error(StatusCode::BAD_REQUEST, format!("{} is a folder", path)),
+        None => return error(StatusCode::NOT_FOUND, format!("{} not found", path)),
+    };
+
+    let data = match state.vault().read_blocks(blocks).await {
+        Ok(data) => data,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    Ok(Box::new(warp::reply::with_header(data, "content-type", "application/octet-stream")))
+}
+
+async fn get_zip(query: ZipQuery, state: LetheState) -> ApiReply {
+    state.activity.touch();
+    let prefix = format!("/{}", query.prefix.as_deref().unwrap_or("/").trim_matches('/'));
+    // Entries are named from the folder's own name down; the whole vault has none
+    let (base, name) = prefix.rsplit_once('/').unwrap_or(("", ""));
+    let mut members: Vec<Member> = {
+        let index = state.index.read().await;
+        if index.get_file(&prefix).is_some_and(|e| !e.is_dir) {
+            return error(StatusCode::BAD_REQUEST, format!("{} is a file", prefix));
+        }
+        // Snapshots only go in when asked for by name, not with the whole vault
+        let snapshots = is_snapshot_path(&prefix);
+        index.data.files.iter()
+            .filter(|(path, e)| is_below(path, &prefix) && !e.is_expired() && (snapshots || !is_snapshot_path(path)))
+            .map(|(path, e)| {
+                let name = &path[base.len() + 1..];
+                match e.is_dir {
+                    true => Member { name: format!("{}/", name), size: 0, modified: e.modified, blocks: Vec::new() },
+                    false => Member { name: name.to_string(), size: e.size, modified: e.modified, blocks: e.blocks.clone() },
+                }
+            })
+            .collect()
+    };
+    // Folders needn't have an entry of their own
+    if members.is_empty() && prefix != "/" && state.index.read().await.get_file(&prefix).is_none() {
+        return error(StatusCode::NOT_FOUND, format!("{} not found", prefix));
+    }
+    members.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+    let body = zip::stream(members, state.storage.clone(), state.key.clone());
+    let reply = warp::reply::with_header(warp::reply::Response::new(body), "content-type", "application/zip");
+    let name = if name.is_empty() { "vault" } else { name };
+    Ok(Box::new(warp::reply::with_header(reply, "content-disposition", attachment(&format!("{}.zip", name)))))
+}
+
+/// `Content-Disposition` for a download named `name`: an ASCII stand-in
+/// for old clients, the real name for the rest (RFC 6266)
+fn attachment(name: &str) -> String {
+    let plain: String = name.chars()
+        .map(|c| if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' { c } else { '_' })
+        .collect();
+    let encoded: String = name.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", plain, encoded)
+}
+
+async fn put_file(tail: Tail, body: bytes::Bytes, state: LetheState) -> ApiReply {
+    state.activity.touch();
+    let path = vault_path(&tail);
+    if is_snapshot_path(&path) {
+        return error(StatusCode::FORBIDDEN, "Snapshots are read-only");
+    }
+    {
+        let index = state.index.read().await;
+        if index.get_file(&path).map(|e| e.is_dir).unwrap_or(false) {
+            return error(StatusCode::CONFLICT, format!("{} is a folder", path));
+        }
+        if let Err(e) = index.check_new_path(&path) {
+            return error(StatusCode::BAD_REQUEST, format!("{:#}", e));
+        }
+        if let Err(e) = index.check_mutable(&path) {
+            return error(StatusCode::FORBIDDEN, format!("{:#}", e));
+        }
+    }
+    if let Err(e) = state.vault().store_file(path.clone(), body.to_vec()).await {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    }
+    let modified = state.index.read().await.get_file(&path).map(|e| e.modified).unwrap_or(0);
+    preview::spawn_make(&state, path.clone());
+    json(&Entry { path, size: body.len() as u64, modified, is_dir: false })
+}
+
+async fn get_preview(tail: Tail, state: LetheState) -> ApiReply {
+    state.activity.touch();
+    if !state.previews() {
+        return error(StatusCode::NOT_FOUND, "Previews are off; serve with --previews");
+    }
+    let path = vault_path(&tail);
+    match preview::preview(&state, &path).await {
+        Ok(Some(jpeg)) => Ok(Box::new(warp::reply::with_header(
+            warp::reply::with_header(jpeg, "content-type", "image/jpeg"),
+            "cache-control",
+            "private, max-age=3600",
+        ))),
+        Ok(None) => error(StatusCode::NOT_FOUND, format!("{} has no preview", path)),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)),
+    }
+}
+
+async fn rename_file(tail: Tail, body: RenameBody, state: LetheState) -> ApiReply {
+    state.activity.touch();
+    let from = vault_path(&tail);
+    let to = format!("/{}", body.path.trim_matches('/'));
+    if is_snapshot_path(&from) || is_snapshot_path(&to) {
+        return error(StatusCode::FORBIDDEN, "Snapshots are read-only");
+    }
+    let mut index = state.index.write().await;
+    if index.get_file(&to).is_some() {
+        return error(StatusCode::CONFLICT, format!("{} already exists", to));
+    }
+    if let Err(e) = index.check_new_path(&to) {
+        return error(StatusCode::BAD_REQUEST, format!("{:#}", e));
+    }
+    if let Err(e) = index.check_mutable(&from) {
+        return error(StatusCode::FORBIDDEN, format!("{:#}", e));
+    }
+
+    if !index.rename_tree(&from, &to) {
+        return error(StatusCode::NOT_FOUND, format!("{} not found", from));
+    }
+    if let Err(e) = index.save(&state.key) {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    }
+    ack()
+}
+
+async fn delete_file(tail: Tail, state: LetheState) -> ApiReply {
+    state.activity.touch();
+    let path = vault_path(&tail);
+    if is_snapshot_path(&path) {
+        return error(StatusCode::FORBIDDEN, "Snapshots are read-only");
+    }
+    let mut index = state.index.write().await;
+    if index.has_children(&path) {
+        return error(StatusCode::CONFLICT, format!("{} is not empty", path));
+    }
+    if let Err(e) = index.check_mutable(&path) {
+        return error(StatusCode::FORBIDDEN, format!("{:#}", e));
+    }
+    if index.data.files.remove(&path).is_none() {
+        return error(StatusCode::NOT_FOUND, format!("{} not found", path));
+    }
+    index.touch_parent(&path);
+    if let Err(e) = index.save(&state.key) {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    }
+    ack()
+}
+
+async fn block_stats(state: LetheState) -> ApiReply {
+    state.activity.touch();
+    let on_disk = match state.storage.list_blocks() {
+        Ok(b) => b,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+
+    let index = state.index.read().await;
+    let referenced = index.referenced_blocks();
+    let orphans: Vec<&(String, u64)> = on_disk.iter().filter(|(id, _)| !referenced.contains(id.as_str())).collect();
+
+    json(&BlockStats {
+        blocks: on_disk.len(),
+        referenced: referenced.len(),
+        orphaned: orphans.len(),
+        bytes_on_disk: on_disk.iter().map(|(_, len)| len).sum(),
+        orphaned_bytes: orphans.iter().map(|(_, len)| len).sum(),
+        plaintext_bytes: index.used_bytes(),
+    })
+}
+
+async fn lock_vault(state: LetheState) -> ApiReply {
+    state.lock.notify_one();
+    ack()
+}
+
+// --- Routes ---
+
+fn v1(segment: &'static str) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path("api").and(warp::path("v1")).and(warp::path(segment))
+}
+
+/// `with_state` yields the vault to act on, or rejects if there is none
+pub fn routes(with_state: BoxedFilter<(LetheState,)>) -> BoxedFilter<(Box<dyn Reply>,)> {
+    let list = v1("files").and(warp::path::end()).and(warp::get())
+        .and(warp::query::<ListQuery>()).and(with_state.clone())
+        .and_then(list_files);
+    let get = v1("files").and(warp::get()).and(warp::path::tail()).and(with_state.clone())
+        .and_then(get_file);
+    let previews = v1("previews").and(warp::get()).and(warp::path::tail()).and(with_state.clone())
+        .and_then(get_preview);
+    let put = v1("files").and(warp::put()).and(warp::path::tail())
+        .and(warp::body::bytes()).and(with_state.clone())
+        .and_then(put_file);
+    let rename = v1("files").and(warp::patch()).and(warp::path::tail())
+        .and(warp::body::json::<RenameBody>()).and(with_state.clone())
+        .and_then(rename_file);
+    let delete = v1("files").and(warp::delete()).and(warp::path::tail()).and(with_state.clone())
+        .and_then(delete_file);
+    let zip = v1("zip").and(warp::path::end()).and(warp::get())
+        .and(warp::query::<ZipQuery>()).and(with_state.clone())
+        .and_then(get_zip);
+    let stats = v1("blocks").and(warp::path("stats")).and(warp::path::end())
+        .and(warp::get()).and(with_state.clone())
+        .and_then(block_stats);
+    let lock = v1("lock").and(warp::path::end())
+        .and(warp::post()).and(with_state)
+        .and_then(lock_vault);
+
+    list.or(get).unify()
+        .or(previews).unify()
+        .or(put).unify()
+        .or(rename).unify()
+        .or(delete).unify()
+        .or(zip).unify()
+        .or(stats).unify()
+        .or(lock).unify()
+        .boxed()
+}
@@ -0,0 +1,264 @@
+//! HTTP Basic accounts for `lethe serve`: those of a `--users` file, or
+//! the vault's own users, checked against its ACLs.
+//!
+//! The guard sits in front of every other route. It answers 401/403 itself
+//! and otherwise rejects, so an allowed request falls through untouched.
+//!
+//! - WebDAV reads (GET, HEAD, PROPFIND) need read access to the path,
+//!   everything else write access; MOVE and COPY also check `Destination`.
+//! - `/_lethe/ui` and `/_lethe/unlock` need any valid account (unlocking
+//!   still takes the vault password).
+//! - `/_lethe/lock` and the JSON API need write access to `/`.
+//!
+//! Vault users sign in with their slot password, even while the vault is
+//! locked. Their rules live in the index, so until it is unlocked only
+//! the account is checked and the session's 503 answers everything else.
+//!
+//! A sign-in that passed Argon2 is remembered for `SIGN_IN_TTL`, so every
+//! request doesn't pay for it again (see `SignIns`).
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use headers::authorization::Basic;
+use headers::{Authorization, HeaderMapExt};
+use warp::filters::BoxedFilter;
+use warp::http::{HeaderMap, Method, StatusCode};
+use warp::path::FullPath;
+use warp::{Filter, Reply};
+use zeroize::Zeroizing;
+
+use lethe_core::access::{self, Access, VaultUser};
+use lethe_core::index::is_within;
+use lethe_core::crypto::{CryptoEngine, MasterKey};
+
+use super::api::{self, percent_decode, ApiReply};
+use super::session::Session;
+use super::RESERVED_PREFIX;
+use crate::accounts::Account;
+
+const REALM: &str = "Basic realm=\"Lethe\"";
+
+/// How long a checked password is taken without Argon2
+const SIGN_IN_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Sign-ins that already passed Argon2, by user name. Each is a keyed hash
+/// of the name, the password and the account as it was then, under a key
+/// that only exists in memory, so the passwords themselves aren't kept and
+/// a changed or removed account no longer matches. The session clears it
+/// when it locks.
+#[derive(Clone)]
+pub struct SignIns(Arc<Mutex<Remembered>>);
+
+struct Remembered {
+    key: MasterKey,
+    tags: HashMap<String, (String, Instant)>,
+}
+
+impl SignIns {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Remembered { key: CryptoEngine::random_key(), tags: HashMap::new() })))
+    }
+
+    fn tag(&self, name: &str, password: &str, account: &[u8]) -> String {
+        let mut data = Zeroizing::new(Vec::with_capacity(16 + name.len() + password.len() + account.len()));
+        for part in [name.as_bytes(), password.as_bytes()] {
+            data.extend_from_slice(&(part.len() as u64).to_le_bytes());
+            data.extend_from_slice(part);
+        }
+        data.extend_from_slice(account);
+        CryptoEngine::keyed_hash(&data, &self.0.lock().unwrap().key)
+    }
+
+    fn holds(&self, name: &str, tag: &str) -> bool {
+        let remembered = self.0.lock().unwrap();
+        remembered.tags.get(name).is_some_and(|(t, at)| t == tag && at.elapsed() < SIGN_IN_TTL)
+    }
+
+    fn insert(&self, name: String, tag: String) {
+        let mut remembered = self.0.lock().unwrap();
+        remembered.tags.retain(|_, (_, at)| at.elapsed() < SIGN_IN_TTL);
+        remembered.tags.insert(name, (tag, Instant::now()));
+    }
+
+    /// Forgets every sign-in, so the next request of each checks its password
+    pub fn clear(&self) {
+        self.0.lock().unwrap().tags.clear();
+    }
+}
+
+#[derive(Clone)]
+enum Directory {
+    File(Arc<Vec<Account>>),
+    Vault(Session),
+}
+
+#[derive(Clone)]
+pub struct Users {
+    directory: Directory,
+    verified: SignIns,
+}
+
+impl Users {
+    pub fn new(accounts: Vec<Account>) -> Self {
+        Self { directory: Directory::File(Arc::new(accounts)), verified: SignIns::new() }
+    }
+
+    /// The users kept in the served vault
+    pub fn vault(session: Session) -> Self {
+        let verified = session.sign_ins();
+        Self { directory: Directory::Vault(session), verified }
+    }
+
+    /// Resolves Basic credentials to a user name
+    async fn authenticate(&self, headers: &HeaderMap) -> Option<String> {
+        let auth = headers.typed_get::<Authorization<Basic>>()?;
+        let name = auth.username().to_string();
+        let password = Zeroizing::new(auth.password().to_string());
+
+        // The account as it is now: a remembered sign-in only holds for it
+        enum Found {
+            File(String),
+            Vault(VaultUser),
+        }
+        let found = match &self.directory {
+            Directory::File(accounts) => Found::File(accounts.iter().find(|a| a.name == name)?.password.clone()),
+            Directory::Vault(session) => Found::Vault(session.user(&name).await?),
+        };
+        let account = match &found {
+            Found::File(hash) => hash.as_bytes().to_vec(),
+            Found::Vault(user) => [user.salt.as_bytes(), &user.slot].concat(),
+        };
+        let tag = self.verified.tag(&name, &password, &account);
+        if self.verified.holds(&name, &tag) {
+            return Some(name);
+        }
+
+        // Argon2 is deliberately slow; keep it off the executor
+        let ok = match found {
+            Found::File(hash) => tokio::task::spawn_blocking(move || CryptoEngine::verify_password(&password, &hash)).await,
+            Found::Vault(user) => tokio::task::spawn_blocking(move || user.unlock(&password).is_ok()).await,
+        };
+        if !ok.unwrap_or(false) {
+            return None;
+        }
+        self.verified.insert(name.clone(), tag);
+        Some(name)
+    }
+
+    async fn permits(&self, name: &str, need: &Need) -> bool {
+        let (path, access) = match need {
+            Need::Account => return true,
+            Need::Read(path) => (path, Access::Read),
+            Need::Write(path) => (path, Access::Write),
+        };
+        match &self.directory {
+            Directory::File(accounts) => accounts.iter().find(|a| a.name == name).is_some_and(|a| allowed(a, need)),
+            Directory::Vault(session) => match session.current() {
+                Some(state) => {
+                    let index = state.index.read().await;
+                    access::allows(&index.data, name, path, access)
+                }
+                None => true,
+            },
+        }
+    }
+}
+
+// --- Permissions ---
+
+enum Need {
+    Account,
+    Read(String),
+    Write(String),
+}
+
+/// Resolves `.` and `..` so `/shared/../private` can't slip past a prefix
+fn clean_path(raw: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in raw.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            p => parts.push(p),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+fn covers(prefixes: &[String], path: &str) -> bool {
+    prefixes.iter().any(|p| is_within(path, p))
+}
+
+fn allowed(account: &Account, need: &Need) -> bool {
+    match need {
+        Need::Account => true,
+        Need::Read(path) => covers(&account.read, path) || covers(&account.write, path),
+        Need::Write(path) => covers(&account.write, path),
+    }
+}
+
+/// `Destination` is an absolute URL; only its path matters
+fn destination(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get("destination")?.to_str().ok()?;
+    let path = match value.find("://") {
+        Some(scheme) => {
+            let rest = &value[scheme + 3..];
+            &rest[rest.find('/').unwrap_or(rest.len())..]
+        }
+        None => value,
+    };
+    Some(clean_path(&percent_decode(path)))
+}
+
+fn needs(method: &Method, full: &str, headers: &HeaderMap) -> Vec<Need> {
+    let path = clean_path(&percent_decode(full));
+    let ui = format!("/{}/ui", RESERVED_PREFIX);
+    let unlock = format!("/{}/unlock", RESERVED_PREFIX);
+    if path == ui || path == unlock || method == Method::OPTIONS {
+        return vec![Need::Account];
+    }
+    if path.starts_with(&format!("/{}/", RESERVED_PREFIX)) || path.starts_with("/api/") {
+        return vec![Need::Write("/".to_string())];
+    }
+
+    let dest = destination(headers);
+    match method.as_str() {
+        "GET" | "HEAD" | "PROPFIND" => vec![Need::Read(path)],
+        "COPY" => vec![Need::Read(path), Need::Write(dest.unwrap_or_else(|| "/".to_string()))],
+        "MOVE" => vec![Need::Write(path), Need::Write(dest.unwrap_or_else(|| "/".to_string()))],
+        _ => vec![Need::Write(path)],
+    }
+}
+
+// --- Guard ---
+
+async fn check(method: Method, full: FullPath, headers: HeaderMap, users: Users) -> ApiReply {
+    let Some(name) = users.authenticate(&headers).await else {
+        let reply = warp::reply::with_status(api::error_body("Authentication required"), StatusCode::UNAUTHORIZED);
+        return Ok(Box::new(warp::reply::with_header(reply, "www-authenticate", REALM)));
+    };
+
+    let mut permitted = true;
+    for need in needs(&method, full.as_str(), &headers) {
+        permitted &= users.permits(&name, &need).await;
+    }
+    if permitted {
+        return Err(warp::reject::not_found());
+    }
+    api::error(StatusCode::FORBIDDEN, format!("{} may not {} {}", name, method, full.as_str()))
+}
+
+/// Puts the account check in front of `routes`
+pub fn protect(users: Users, routes: BoxedFilter<(Box<dyn Reply>,)>) -> BoxedFilter<(Box<dyn Reply>,)> {
+    let guard = warp::method()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(warp::any().map(move || users.clone()))
+        .and_then(check);
+
+    guard.or(routes).unify().boxed()
+}
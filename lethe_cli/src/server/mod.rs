@@ -0,0 +1,69 @@
+//! The embedded HTTP server: WebDAV for the OS, the JSON API, and the
+//! optional browser file manager, all routed on the same port.
+
+use dav_server::fs::DavFileSystem;
+use warp::filters::BoxedFilter;
+use warp::{Filter, Reply};
+
+use crate::dav::LetheState;
+#[cfg(windows)]
+use crate::dav::LetheWebDav;
+
+pub mod api;
+pub mod auth;
+pub mod preview;
+pub mod replica;
+pub mod s3;
+pub mod sigv4;
+pub mod session;
+pub mod webui;
+pub mod zip;
+
+use session::{Session, SessionDav};
+
+/// Everything mounted under this prefix is Lethe's own, not vault content
+pub const RESERVED_PREFIX: &str = "_lethe";
+
+/// What the server exposes besides WebDAV
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServerOptions {
+    pub web_ui: bool,
+}
+
+/// Builds the full route tree for one unlocked vault, for the Windows mount
+#[cfg(windows)]
+pub fn routes(state: LetheState, opts: ServerOptions) -> BoxedFilter<(Box<dyn Reply>,)> {
+    let fs = Box::new(LetheWebDav { state: state.clone() });
+    let with_state = warp::any().map(move || state.clone()).boxed();
+    with_ui(vault_routes(with_state, fs), opts)
+}
+
+/// Builds the route tree for `lethe serve`, which unlocks per session
+pub fn session_routes(session: Session, opts: ServerOptions) -> BoxedFilter<(Box<dyn Reply>,)> {
+    let fs = Box::new(SessionDav { session: session.clone() });
+    let vault = vault_routes(session.state_filter(), fs);
+    with_ui(session::routes(session).or(vault).unify().boxed(), opts)
+}
+
+fn vault_routes(with_state: BoxedFilter<(LetheState,)>, fs: Box<dyn DavFileSystem>) -> BoxedFilter<(Box<dyn Reply>,)> {
+    let handler = dav_server::DavHandler::builder()
+        .filesystem(fs)
+        .locksystem(dav_server::memls::MemLs::new())
+        .build_handler();
+
+    let dav = dav_server::warp::dav_handler(handler)
+        .map(|reply| Box::new(reply) as Box<dyn Reply>)
+        .boxed();
+
+    // The API is matched first so /api/v1/... never falls through to DAV
+    api::routes(with_state).or(dav).unify().boxed()
+}
+
+/// The UI page stays reachable while locked so it can offer the unlock form
+fn with_ui(routes: BoxedFilter<(Box<dyn Reply>,)>, opts: ServerOptions) -> BoxedFilter<(Box<dyn Reply>,)> {
+    if opts.web_ui {
+        webui::routes().or(routes).unify().boxed()
+    } else {
+        routes
+    }
+}
@@ -0,0 +1,164 @@
+//! Thumbnails for the web UI, with `lethe serve --previews`.
+//!
+//! `GET /api/v1/previews/<path>` answers a JPEG no larger than `EDGE`
+//! pixels a side for JPEG, PNG, GIF and WebP images, and for PDFs that
+//! carry a JPEG (scans, mostly), the first one in them. Each is made once,
+//! when the file is uploaded through the server or first asked for, and
+//! kept in the vault folder under `previews/`, sealed (Nonce + Data) under
+//! a key derived from the master key and named by a keyed hash of the
+//! file's blocks: a changed file gets a new one, and unlocking drops those
+//! no file has any more. Files that get none are noted the same way, so
+//! they aren't decrypted again on every listing. A hidden vault gets none:
+//! the folder would give it away.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use lethe_core::crypto::{CryptoEngine, MasterKey};
+use lethe_core::index::{is_snapshot_path, FileEntry, IndexManager};
+use lethe_core::plaintext::Zeroizing;
+use lethe_core::reader::FileReader;
+use lethe_core::storage::BlockManager;
+
+use crate::container::create_private_dir;
+use crate::dav::LetheState;
+
+pub const PREVIEW_DIR: &str = "previews";
+
+/// Longest side of a preview, in pixels
+pub const EDGE: u32 = 256;
+
+/// Larger files get no preview; they are decrypted whole to make one
+const MAX_SOURCE: u64 = 64 * 1024 * 1024;
+
+const QUALITY: u8 = 80;
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Image,
+    Pdf,
+}
+
+fn kind(path: &str) -> Option<Kind> {
+    let ext = Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "jpg" | "jpeg" | "png" | "gif" | "webp" => Some(Kind::Image),
+        "pdf" => Some(Kind::Pdf),
+        _ => None,
+    }
+}
+
+fn preview_key(key: &MasterKey) -> MasterKey {
+    CryptoEngine::derive_subkey(key, b"lethe previews")
+}
+
+/// Where the preview of `entry`'s content is kept
+fn location(vault: &Path, entry: &FileEntry, key: &MasterKey) -> PathBuf {
+    let name = CryptoEngine::keyed_hash(entry.blocks.join("\n").as_bytes(), &preview_key(key));
+    vault.join(PREVIEW_DIR).join(name)
+}
+
+/// Files of `index` that may have a preview
+fn candidates(index: &IndexManager) -> impl Iterator<Item = (&String, &FileEntry)> {
+    index.data.files.iter().filter(|(path, e)| {
+        !e.is_dir && !e.is_expired() && e.size <= MAX_SOURCE && !is_snapshot_path(path) && kind(path).is_some()
+    })
+}
+
+/// The preview of the file at `path`, made and kept if there is none yet.
+/// None for a file that gets none.
+pub async fn preview(state: &LetheState, path: &str) -> Result<Option<Vec<u8>>> {
+    let (entry, vault) = {
+        let index = state.index.read().await;
+        if index.is_hidden() {
+            return Ok(None);
+        }
+        let entry = candidates(&index).find(|(p, _)| *p == path).map(|(_, e)| e.clone());
+        (entry, index.root_path().clone())
+    };
+    let (Some(entry), Some(kind)) = (entry, kind(path)) else { return Ok(None) };
+    let (storage, key) = (state.storage.clone(), state.key.clone());
+    tokio::task::spawn_blocking(move || make(&vault, &storage, &key, &entry, kind)).await?
+}
+
+/// Makes the preview of a file just uploaded, in the background
+pub fn spawn_make(state: &LetheState, path: String) {
+    if !state.previews() || kind(&path).is_none() {
+        return;
+    }
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = preview(&state, &path).await {
+            log::warn!("Could not make a preview of {}: {:#}", path, e);
+        }
+    });
+}
+
+fn make(vault: &Path, storage: &BlockManager, key: &MasterKey, entry: &FileEntry, kind: Kind) -> Result<Option<Vec<u8>>> {
+    let file = location(vault, entry, key);
+    if let Ok(sealed) = fs::read(&file) {
+        let (nonce, ciphertext) = sealed.split_at(24.min(sealed.len()));
+        let thumb = CryptoEngine::decrypt(ciphertext, nonce, &preview_key(key)).context("Preview doesn't open")?;
+        return Ok((!thumb.is_empty()).then_some(thumb));
+    }
+    let mut data = Zeroizing::new(Vec::with_capacity(entry.size as usize));
+    FileReader::new(storage, key, entry).read_to_end(&mut data)?;
+    // An empty preview notes that there is none to make
+    let thumb = render(&data, kind).unwrap_or_else(|e| {
+        log::debug!("No preview: {:#}", e);
+        Vec::new()
+    });
+    let (ciphertext, mut sealed) = CryptoEngine::encrypt(&thumb, &preview_key(key))?;
+    sealed.extend_from_slice(&ciphertext);
+    create_private_dir(&vault.join(PREVIEW_DIR))?;
+    let tmp = file.with_extension("tmp");
+    fs::write(&tmp, sealed).context("Failed to write a preview")?;
+    fs::rename(&tmp, &file)?;
+    Ok((!thumb.is_empty()).then_some(thumb))
+}
+
+/// A JPEG of the image in `data`, shrunk to fit `EDGE`
+fn render(data: &[u8], kind: Kind) -> Result<Vec<u8>> {
+    let image = match kind {
+        Kind::Image => image::load_from_memory(data)?,
+        Kind::Pdf => image::load_from_memory_with_format(first_jpeg(data)?, image::ImageFormat::Jpeg)?,
+    };
+    let thumb = image.thumbnail(EDGE, EDGE).into_rgb8();
+    let mut out = Cursor::new(Vec::new());
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, QUALITY).encode_image(&thumb)?;
+    Ok(out.into_inner())
+}
+
+/// The first `/DCTDecode` (JPEG) stream of a PDF
+fn first_jpeg(pdf: &[u8]) -> Result<&[u8]> {
+    let find = |from: usize, what: &[u8]| pdf[from..].windows(what.len()).position(|w| w == what).map(|at| from + at);
+    let filter = find(0, b"/DCTDecode").context("The PDF holds no JPEG")?;
+    let mut start = find(filter, b"stream").context("The PDF's JPEG has no stream")? + b"stream".len();
+    if pdf[start..].starts_with(b"\r") {
+        start += 1;
+    }
+    if pdf[start..].starts_with(b"\n") {
+        start += 1;
+    }
+    let end = find(start, b"endstream").context("The PDF's JPEG stream has no end")?;
+    Ok(&pdf[start..end])
+}
+
+/// Removes the previews of content no file has any more
+pub fn prune(index: &IndexManager, key: &MasterKey) -> Result<usize> {
+    let dir = index.root_path().join(PREVIEW_DIR);
+    let Ok(entries) = fs::read_dir(&dir) else { return Ok(0) };
+    let live: HashSet<PathBuf> = candidates(index).map(|(_, e)| location(index.root_path(), e, key)).collect();
+    let mut removed = 0;
+    for entry in entries.flatten() {
+        if !live.contains(&entry.path()) {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
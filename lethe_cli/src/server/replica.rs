@@ -0,0 +1,94 @@
+//! `lethe serve --replica-of <remote>`: a warm standby of a vault kept on
+//! another machine.
+//!
+//! While unlocked, the replica pulls from the remote the primary pushes
+//! to (any spec `lethe pull` takes) every `--pull-every`, and swaps the
+//! new index in once it has the blocks for it; clients see the primary's
+//! changes a pull later. It serves read-only: anything that would write
+//! is refused, so the copy never drifts from the primary's. Should the
+//! primary be lost, stop the replica and serve the folder as usual to
+//! take over.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use warp::filters::BoxedFilter;
+use warp::http::{Method, StatusCode};
+use warp::path::FullPath;
+use warp::{Filter, Reply};
+
+use lethe_core::crypto::MasterKey;
+use lethe_core::index::IndexManager;
+
+use super::api::{self, ApiReply};
+use super::session::Session;
+use super::RESERVED_PREFIX;
+use crate::sync::bwlimit::BwLimit;
+use crate::sync::remote;
+
+/// Requests a replica answers: reads, and locking or unlocking it
+fn allowed(method: &Method, path: &str) -> bool {
+    match method.as_str() {
+        "GET" | "HEAD" | "PROPFIND" | "OPTIONS" => true,
+        "POST" => {
+            path == format!("/{}/unlock", RESERVED_PREFIX)
+                || path == format!("/{}/lock", RESERVED_PREFIX)
+                || path == "/api/v1/lock"
+        }
+        _ => false,
+    }
+}
+
+async fn check(method: Method, full: FullPath, primary: Arc<String>) -> ApiReply {
+    if allowed(&method, full.as_str()) {
+        return Err(warp::reject::not_found());
+    }
+    api::error(StatusCode::FORBIDDEN, format!("This is a read-only replica of {}", primary))
+}
+
+/// Puts the read-only check in front of `routes`
+pub fn read_only(primary: &str, routes: BoxedFilter<(Box<dyn Reply>,)>) -> BoxedFilter<(Box<dyn Reply>,)> {
+    let primary = Arc::new(primary.to_string());
+    let guard = warp::method()
+        .and(warp::path::full())
+        .and(warp::any().map(move || primary.clone()))
+        .and_then(check);
+    guard.or(routes).unify().boxed()
+}
+
+/// Pulls from `spec` every `every` while the session is unlocked, for as
+/// long as the server runs
+pub fn spawn_follower(session: Session, vault_path: PathBuf, spec: String, every: Duration) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(every);
+        loop {
+            ticks.tick().await;
+            let Some(state) = session.current() else { continue };
+            let (path, from, key) = (vault_path.clone(), spec.clone(), state.key.clone());
+            let revision = state.index.read().await.data.revision;
+            let pulled = tokio::task::spawn_blocking(move || follow(&path, &from, &key, revision)).await;
+            match pulled {
+                Ok(Ok(Some(index_mgr))) => {
+                    log::info!("Replica now at index revision {}", index_mgr.data.revision);
+                    *state.index.write().await = index_mgr;
+                }
+                Ok(Ok(None)) => {}
+                Ok(Err(e)) => log::warn!("Replica pull from {} failed: {:#}", spec, e),
+                Err(e) => log::warn!("Replica pull from {} failed: {}", spec, e),
+            }
+        }
+    });
+}
+
+/// Pulls once; the new index if it moved past `revision`
+fn follow(vault_path: &std::path::Path, spec: &str, key: &MasterKey, revision: u64) -> Result<Option<IndexManager>> {
+    let remote = remote::open(spec, BwLimit::unlimited())?;
+    remote::pull(remote.as_ref(), vault_path, key, false)?;
+    // Only the listing, to tell whether the whole index is worth loading
+    if IndexManager::read_listing(vault_path, key)?.revision == revision {
+        return Ok(None);
+    }
+    IndexManager::load(vault_path.to_path_buf(), key).map(Some)
+}
@@ -0,0 +1,731 @@
+//! `lethe s3-serve`: a minimal S3 API over one unlocked vault, for tools
+//! that already speak S3 (rclone, backup agents).
+//!
+//! The vault appears as a single bucket; object keys are vault paths
+//! without the leading `/`. Path-style requests only
+//! (`http://host:port/<bucket>/<key>`). Supported:
+//!
+//! - `GET /` - ListBuckets
+//! - `HEAD`/`PUT /<bucket>`, `GET /<bucket>?location`
+//! - `GET /<bucket>` - ListObjects (v1, and v2 with `list-type=2`)
+//! - `GET`/`HEAD /<bucket>/<key>` - GetObject (with a single `Range`) / HeadObject
+//! - `PUT /<bucket>/<key>` - PutObject, or CopyObject with `x-amz-copy-source`
+//! - `DELETE /<bucket>/<key>`, `POST /<bucket>?delete` - DeleteObject(s)
+//! - multipart uploads (create, upload part, complete, abort)
+//!
+//! Every request must be signed (Signature V4, see `sigv4`) with the access
+//! key and secret the gateway was started with. A body is read whole, so
+//! it may be at most `MAX_BODY`; larger objects go up in parts. Parts are
+//! not kept in memory: each upload appends them to a `FileBuffer`, which
+//! seals what it can't hold into a scratch file in the vault folder, and
+//! completing it streams them into the vault. Snapshots are not listed and
+//! cannot be written.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use bytes::Bytes;
+use rand::RngCore;
+use warp::filters::BoxedFilter;
+use warp::http::{HeaderMap, Method, Response, StatusCode};
+use warp::path::FullPath;
+use warp::{Filter, Reply};
+
+use lethe_core::crypto::CryptoEngine;
+use lethe_core::index::{is_snapshot_path, FileEntry, IndexManager};
+use lethe_core::nonblocking;
+use lethe_core::progress::Silent;
+
+use super::api::{percent_decode, ApiReply};
+use super::sigv4::{self, Credentials};
+use crate::dav::LetheState;
+use crate::spill::{FileBuffer, PAGE};
+
+const XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+const MAX_KEYS: usize = 1000;
+
+/// Largest request body: a PutObject or one part. rclone sends objects up
+/// to 200 MiB in one piece by default, the AWS CLI parts past 8 MiB.
+pub const MAX_BODY: u64 = 256 * 1024 * 1024;
+/// Multipart uploads in progress at once
+const MAX_UPLOADS: usize = 64;
+/// An upload no part has come for in this long is dropped
+const STALE: Duration = Duration::from_secs(24 * 60 * 60);
+/// Parts of an upload held in memory before the rest are sealed to disk
+const UPLOAD_MEMORY: usize = 4 * PAGE;
+
+/// Bucket names S3 clients accept: 3-63 of `a-z 0-9 . -`
+pub fn valid_bucket(name: &str) -> bool {
+    (3..=63).contains(&name.len())
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'.' || b == b'-')
+}
+
+#[derive(Clone)]
+struct S3 {
+    state: LetheState,
+    bucket: String,
+    credentials: Arc<Credentials>,
+    /// Multipart uploads in progress, by upload id
+    uploads: Arc<Mutex<HashMap<String, Arc<Mutex<Upload>>>>>,
+}
+
+struct Upload {
+    key: String,
+    /// The parts as they came, one after another
+    buffer: FileBuffer,
+    /// Where each part is in `buffer`, and its length
+    parts: BTreeMap<u32, (u64, u64)>,
+    used: Instant,
+}
+
+/// The parts an upload is completed with, read one after another
+struct Parts {
+    upload: Arc<Mutex<Upload>>,
+    ranges: VecDeque<(u64, u64)>,
+}
+
+impl Read for Parts {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some((start, len)) = self.ranges.front_mut() {
+            if *len == 0 {
+                self.ranges.pop_front();
+                continue;
+            }
+            let want = (*len).min(buf.len().min(PAGE) as u64) as usize;
+            let data = self.upload.lock().unwrap().buffer.read_at(*start, want)?;
+            if data.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "A part is shorter than it was"));
+            }
+            buf[..data.len()].copy_from_slice(&data);
+            *start += data.len() as u64;
+            *len -= data.len() as u64;
+            return Ok(data.len());
+        }
+        Ok(0)
+    }
+}
+
+// --- Replies ---
+
+fn respond(status: StatusCode, headers: Vec<(&'static str, String)>, body: Vec<u8>) -> ApiReply {
+    let mut response = Response::builder().status(status);
+    for (name, value) in headers {
+        response = response.header(name, value);
+    }
+    match response.body(body) {
+        Ok(r) => Ok(Box::new(r)),
+        Err(e) => s3_error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", &e.to_string()),
+    }
+}
+
+fn xml(status: StatusCode, body: String) -> ApiReply {
+    let body = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", body);
+    let reply = warp::reply::with_header(body, "content-type", "application/xml");
+    Ok(Box::new(warp::reply::with_status(reply, status)))
+}
+
+fn s3_error(status: StatusCode, code: &str, message: &str) -> ApiReply {
+    xml(status, format!("<Error><Code>{}</Code><Message>{}</Message></Error>", code, escape(message)))
+}
+
+fn no_such_key(key: &str) -> ApiReply {
+    s3_error(StatusCode::NOT_FOUND, "NoSuchKey", &format!("{} does not exist", key))
+}
+
+fn read_only() -> ApiReply {
+    s3_error(StatusCode::FORBIDDEN, "AccessDenied", "Snapshots are read-only")
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+/// Text of every `<tag>...</tag>` in `body`, in order
+fn tag_values(body: &str, tag: &str) -> Vec<String> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    let mut values = Vec::new();
+    let mut rest = body;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else { break };
+        values.push(unescape(&rest[..end]));
+        rest = &rest[end + close.len()..];
+    }
+    values
+}
+
+/// Percent-encodes a key for `encoding-type=url`, keeping `/`
+fn url_encode(key: &str) -> String {
+    key.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+fn iso_time(secs: u64) -> String {
+    humantime::format_rfc3339_millis(UNIX_EPOCH + Duration::from_secs(secs)).to_string()
+}
+
+/// `Tue, 15 Nov 1994 08:12:31 GMT`
+fn http_date(secs: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    // 2024-06-01T12:00:00.000Z
+    let iso = iso_time(secs);
+    let month: usize = iso[5..7].parse().unwrap_or(1);
+    format!(
+        "{}, {} {} {} {} GMT",
+        DAYS[((secs / 86_400) % 7) as usize], &iso[8..10], MONTHS[month - 1], &iso[..4], &iso[11..19]
+    )
+}
+
+/// Multipart-style ETag from the entry's chunks: stable while the content
+/// is, and never mistaken for an MD5 by clients that check those
+fn etag(entry: &FileEntry, s3: &S3) -> String {
+    let parts = if entry.hashes.is_empty() { entry.blocks.len() } else { entry.hashes.len() };
+    format!("\"{}-{}\"", entry.content_tag(&s3.state.key), parts.max(1))
+}
+
+// --- Requests ---
+
+fn parse_query(raw: &str) -> HashMap<String, String> {
+    raw.split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| match p.split_once('=') {
+            Some((k, v)) => (percent_decode(k), percent_decode(v)),
+            None => (percent_decode(p), String::new()),
+        })
+        .collect()
+}
+
+/// Vault path for an object key; None for keys that can't be one
+fn key_path(key: &str) -> Option<String> {
+    let trimmed = key.strip_suffix('/').unwrap_or(key);
+    if trimmed.is_empty() || trimmed.split('/').any(|s| s.is_empty() || s == "." || s == "..") {
+        return None;
+    }
+    Some(format!("/{}", trimmed))
+}
+
+async fn handle(
+    method: Method,
+    full: FullPath,
+    query: String,
+    headers: HeaderMap,
+    body: Bytes,
+    s3: S3,
+) -> ApiReply {
+    s3.state.activity.touch();
+    let checked = sigv4::check(&s3.credentials, &method, full.as_str(), &query, &headers, &body);
+    let body = match checked.and_then(|payload| payload.open(body)) {
+        Ok(body) => body,
+        Err(denied) => return s3_error(denied.status, denied.code, &denied.message),
+    };
+    let query = parse_query(&query);
+    let (bucket, key) = match full.as_str().trim_start_matches('/').split_once('/') {
+        Some((bucket, key)) => (percent_decode(bucket), percent_decode(key)),
+        None => (percent_decode(full.as_str().trim_start_matches('/')), String::new()),
+    };
+
+    if bucket.is_empty() {
+        return match method {
+            Method::GET => list_buckets(&s3).await,
+            _ => s3_error(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed", "Only ListBuckets works here"),
+        };
+    }
+    if bucket != s3.bucket {
+        return s3_error(StatusCode::NOT_FOUND, "NoSuchBucket", &format!("This server only has bucket {}", s3.bucket));
+    }
+
+    if key.is_empty() {
+        return match method {
+            Method::HEAD | Method::PUT => respond(StatusCode::OK, vec![], vec![]),
+            Method::GET if query.contains_key("location") => {
+                xml(StatusCode::OK, format!("<LocationConstraint xmlns=\"{}\"/>", XMLNS))
+            }
+            Method::GET => list_objects(&s3, &query).await,
+            Method::POST if query.contains_key("delete") => delete_objects(&s3, &body).await,
+            _ => s3_error(StatusCode::NOT_IMPLEMENTED, "NotImplemented", "Unsupported bucket operation"),
+        };
+    }
+
+    let Some(path) = key_path(&key) else {
+        return s3_error(StatusCode::BAD_REQUEST, "InvalidArgument", &format!("{:?} can't be stored as a vault path", key));
+    };
+    match method {
+        Method::GET => get_object(&s3, &key, &path, &headers, true).await,
+        Method::HEAD => get_object(&s3, &key, &path, &headers, false).await,
+        Method::PUT if query.contains_key("uploadId") => upload_part(&s3, &query, body).await,
+        Method::PUT if headers.contains_key("x-amz-copy-source") => copy_object(&s3, &path, &headers).await,
+        Method::PUT => put_object(&s3, &key, &path, body).await,
+        Method::DELETE if query.contains_key("uploadId") => abort_upload(&s3, &query),
+        Method::DELETE => delete_object(&s3, &path).await,
+        Method::POST if query.contains_key("uploads") => create_upload(&s3, &key).await,
+        Method::POST if query.contains_key("uploadId") => complete_upload(&s3, &key, &path, &query, &body).await,
+        _ => s3_error(StatusCode::NOT_IMPLEMENTED, "NotImplemented", "Unsupported object operation"),
+    }
+}
+
+// --- Buckets ---
+
+async fn list_buckets(s3: &S3) -> ApiReply {
+    let created = s3.state.index.read().await.data.files.values().map(|e| e.modified).min().unwrap_or(0);
+    xml(StatusCode::OK, format!(
+        "<ListAllMyBucketsResult xmlns=\"{}\"><Owner><ID>lethe</ID><DisplayName>lethe</DisplayName></Owner>\
+         <Buckets><Bucket><Name>{}</Name><CreationDate>{}</CreationDate></Bucket></Buckets></ListAllMyBucketsResult>",
+        XMLNS, s3.bucket, iso_time(created)
+    ))
+}
+
+enum Listed {
+    Object { key: String, size: u64, modified: u64, etag: String },
+    Prefix(String),
+}
+
+async fn list_objects(s3: &S3, query: &HashMap<String, String>) -> ApiReply {
+    let v2 = query.get("list-type").map(String::as_str) == Some("2");
+    let prefix = query.get("prefix").cloned().unwrap_or_default();
+    let delimiter = query.get("delimiter").cloned().filter(|d| !d.is_empty());
+    let max_keys = query.get("max-keys").and_then(|m| m.parse().ok()).unwrap_or(MAX_KEYS).min(MAX_KEYS);
+    let url = query.get("encoding-type").map(String::as_str) == Some("url");
+    let start = if v2 {
+        query.get("continuation-token").or_else(|| query.get("start-after")).cloned()
+    } else {
+        query.get("marker").cloned()
+    }.unwrap_or_default();
+
+    let index = s3.state.index.read().await;
+    let mut files: Vec<(&str, &FileEntry)> = index.data.files.iter()
+        .filter(|(path, e)| !e.is_dir && !e.is_expired() && !is_snapshot_path(path))
+        .map(|(path, e)| (&path[1..], e))
+        .filter(|(key, _)| key.starts_with(prefix.as_str()) && *key > start.as_str())
+        .collect();
+    files.sort_by_key(|(key, _)| *key);
+
+    // 1. Objects, with keys past the delimiter rolled up into prefixes
+    let mut listed: Vec<Listed> = Vec::new();
+    let mut truncated = false;
+    for (key, entry) in files {
+        let rolled = delimiter.as_ref().and_then(|d| {
+            key[prefix.len()..].find(d.as_str()).map(|at| key[..prefix.len() + at + d.len()].to_string())
+        });
+        let item = match rolled {
+            Some(p) if p.as_str() <= start.as_str() => continue,
+            Some(p) if matches!(listed.last(), Some(Listed::Prefix(last)) if *last == p) => continue,
+            Some(p) => Listed::Prefix(p),
+            None => Listed::Object { key: key.to_string(), size: entry.size, modified: entry.modified, etag: etag(entry, s3) },
+        };
+        if listed.len() == max_keys {
+            truncated = true;
+            break;
+        }
+        listed.push(item);
+    }
+
+    // 2. XML
+    let enc = |s: &str| escape(&if url { url_encode(s) } else { s.to_string() });
+    let mut body = format!("<ListBucketResult xmlns=\"{}\"><Name>{}</Name><Prefix>{}</Prefix>", XMLNS, s3.bucket, enc(&prefix));
+    if let Some(d) = &delimiter {
+        body.push_str(&format!("<Delimiter>{}</Delimiter>", enc(d)));
+    }
+    if url {
+        body.push_str("<EncodingType>url</EncodingType>");
+    }
+    body.push_str(&format!("<MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>", max_keys, truncated));
+    let last = listed.last().map(|l| match l {
+        Listed::Object { key, .. } => key.clone(),
+        Listed::Prefix(p) => p.clone(),
+    });
+    if v2 {
+        body.push_str(&format!("<KeyCount>{}</KeyCount>", listed.len()));
+        if let Some(token) = query.get("continuation-token") {
+            body.push_str(&format!("<ContinuationToken>{}</ContinuationToken>", escape(token)));
+        }
+        if truncated {
+            body.push_str(&format!("<NextContinuationToken>{}</NextContinuationToken>", escape(&last.unwrap_or_default())));
+        }
+    } else {
+        body.push_str(&format!("<Marker>{}</Marker>", enc(&start)));
+        if truncated {
+            body.push_str(&format!("<NextMarker>{}</NextMarker>", enc(&last.unwrap_or_default())));
+        }
+    }
+    for item in &listed {
+        match item {
+            Listed::Object { key, size, modified, etag } => body.push_str(&format!(
+                "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag><Size>{}</Size>\
+                 <StorageClass>STANDARD</StorageClass></Contents>",
+                enc(key), iso_time(*modified), escape(etag), size
+            )),
+            Listed::Prefix(p) => body.push_str(&format!("<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>", enc(p))),
+        }
+    }
+    body.push_str("</ListBucketResult>");
+    xml(StatusCode::OK, body)
+}
+
+// --- Objects ---
+
+/// `bytes=a-b`, `bytes=a-` or `bytes=-n` against `size`; Err if unsatisfiable
+fn parse_range(header: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else { return Ok(None) };
+    // Several ranges would need multipart/byteranges; send it all instead
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let (from, to) = spec.split_once('-').ok_or(())?;
+    let (start, end) = match (from.trim(), to.trim()) {
+        ("", n) => {
+            let n: u64 = n.parse().map_err(|_| ())?;
+            (size.saturating_sub(n), size.saturating_sub(1))
+        }
+        (a, "") => (a.parse().map_err(|_| ())?, size.saturating_sub(1)),
+        (a, b) => (a.parse().map_err(|_| ())?, b.parse::<u64>().map_err(|_| ())?.min(size.saturating_sub(1))),
+    };
+    if size == 0 || start > end || start >= size {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+async fn get_object(s3: &S3, key: &str, path: &str, headers: &HeaderMap, with_body: bool) -> ApiReply {
+    let index = s3.state.index.read().await;
+    let entry = match index.get_file(path) {
+        Some(e) if !e.is_dir => e,
+        _ => return no_such_key(key),
+    };
+
+    let range = headers.get("range").and_then(|r| r.to_str().ok());
+    let range = match range.map(|r| parse_range(r, entry.size)) {
+        Some(Err(())) => {
+            return s3_error(StatusCode::RANGE_NOT_SATISFIABLE, "InvalidRange", "The requested range is not satisfiable")
+        }
+        Some(Ok(r)) => r,
+        None => None,
+    };
+    let (status, start, end) = match range {
+        Some((start, end)) => (StatusCode::PARTIAL_CONTENT, start, end + 1),
+        None => (StatusCode::OK, 0, entry.size),
+    };
+
+    let mut headers = vec![
+        ("content-type", "application/octet-stream".to_string()),
+        ("content-length", (end - start).to_string()),
+        ("last-modified", http_date(entry.modified)),
+        ("etag", etag(entry, s3)),
+        ("accept-ranges", "bytes".to_string()),
+    ];
+    if status == StatusCode::PARTIAL_CONTENT {
+        headers.push(("content-range", format!("bytes {}-{}/{}", start, end - 1, entry.size)));
+    }
+    if !with_body {
+        return respond(status, headers, vec![]);
+    }
+
+    let blocks = entry.blocks.clone();
+    drop(index);
+    let (storage, vault_key) = (s3.state.storage.clone(), s3.state.key.clone());
+    let read = nonblocking::run(move || {
+        let mut data = Vec::new();
+        for block_id in &blocks {
+            data.extend(storage.read_block(block_id, &vault_key)?);
+            // Blocks past the range aren't needed
+            if data.len() as u64 >= end {
+                break;
+            }
+        }
+        Ok(data)
+    }).await;
+    let mut data = match read {
+        Ok(data) => data,
+        Err(e) => return s3_error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", &e.to_string()),
+    };
+    data.truncate(end as usize);
+    data.drain(..start as usize);
+    respond(status, headers, data)
+}
+
+/// Stores what `data` yields at `path` and saves; the reply carries the
+/// new ETag
+async fn store(s3: &S3, key: &str, path: &str, data: impl Read + Send + 'static) -> Result<String, ApiReply> {
+    if is_snapshot_path(path) {
+        return Err(read_only());
+    }
+    let folder = key.ends_with('/');
+    {
+        let index = s3.state.index.read().await;
+        if index.get_file(path).map(|e| e.is_dir).unwrap_or(false) && !folder {
+            return Err(s3_error(StatusCode::CONFLICT, "InvalidObjectState", &format!("{} is a folder", path)));
+        }
+        if let Err(e) = index.check_new_path(path) {
+            return Err(s3_error(StatusCode::BAD_REQUEST, "InvalidArgument", &format!("{:#}", e)));
+        }
+        if let Err(e) = index.check_mutable(path) {
+            return Err(s3_error(StatusCode::FORBIDDEN, "AccessDenied", &format!("{:#}", e)));
+        }
+    }
+    let target = path.to_string();
+    let vault = s3.state.vault();
+    let result = if folder {
+        // Folder marker, as written by consoles and some sync tools
+        vault.with_index(move |index, _, vault_key| {
+            index.add_dir(target);
+            index.save(vault_key)
+        }).await
+    } else if s3.state.index.read().await.is_hidden() {
+        // A hidden vault's blocks go where it left room for them, one file at a time
+        vault.with_index(move |index, storage, vault_key| {
+            index.store_reader(storage, vault_key, target, data, &Silent)?;
+            index.save(vault_key)
+        }).await
+    } else {
+        // Blocks are written with the index shared, then recorded
+        match vault.read_index(move |index, storage, vault_key| index.stage_reader(storage, vault_key, data)).await {
+            Ok(staged) => vault.with_index(move |index, _, vault_key| {
+                index.check_mutable(&target)?;
+                index.commit(target, staged);
+                index.save(vault_key)
+            }).await,
+            Err(e) => Err(e),
+        }
+    };
+    if let Err(e) = result {
+        return Err(s3_error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", &e.to_string()));
+    }
+    Ok(s3.state.index.read().await.get_file(path).map(|e| etag(e, s3)).unwrap_or_default())
+}
+
+async fn put_object(s3: &S3, key: &str, path: &str, body: Bytes) -> ApiReply {
+    match store(s3, key, path, io::Cursor::new(body)).await {
+        Ok(etag) => respond(StatusCode::OK, vec![("etag", etag)], vec![]),
+        Err(reply) => reply,
+    }
+}
+
+async fn copy_object(s3: &S3, path: &str, headers: &HeaderMap) -> ApiReply {
+    let source = headers.get("x-amz-copy-source").and_then(|v| v.to_str().ok()).unwrap_or("");
+    let source = percent_decode(source.split('?').next().unwrap_or(""));
+    let source_key = match source.trim_start_matches('/').split_once('/') {
+        Some((bucket, key)) if bucket == s3.bucket => key.to_string(),
+        _ => return s3_error(StatusCode::BAD_REQUEST, "InvalidArgument", "Copy source must be in this bucket"),
+    };
+    let Some(source_path) = key_path(&source_key) else { return no_such_key(&source_key) };
+    if is_snapshot_path(path) {
+        return read_only();
+    }
+
+    let mut index = s3.state.index.write().await;
+    let entry = match index.get_file(&source_path) {
+        Some(e) if !e.is_dir => e.clone(),
+        _ => return no_such_key(&source_key),
+    };
+    if let Err(e) = index.check_new_path(path) {
+        return s3_error(StatusCode::BAD_REQUEST, "InvalidArgument", &format!("{:#}", e));
+    }
+    if let Err(e) = index.check_mutable(path) {
+        return s3_error(StatusCode::FORBIDDEN, "AccessDenied", &format!("{:#}", e));
+    }
+    // Same blocks under a second name; nothing is re-encrypted
+    index.add_file(path.to_string(), entry.blocks.clone(), entry.size);
+    if let Some(copy) = index.data.files.get_mut(path) {
+        copy.hashes = entry.hashes.clone();
+        copy.sizes = entry.sizes.clone();
+        copy.stored = entry.stored.clone();
+    }
+    if let Err(e) = index.save(&s3.state.key) {
+        return s3_error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", &e.to_string());
+    }
+    let (etag, modified) = match index.get_file(path) {
+        Some(e) => (etag(e, s3), e.modified),
+        None => (String::new(), 0),
+    };
+    xml(StatusCode::OK, format!(
+        "<CopyObjectResult><LastModified>{}</LastModified><ETag>{}</ETag></CopyObjectResult>",
+        iso_time(modified), escape(&etag)
+    ))
+}
+
+/// Removes a file (or empty folder); false if there was nothing to remove
+fn remove(index: &mut IndexManager, path: &str) -> bool {
+    if index.has_children(path) {
+        return false;
+    }
+    if index.data.files.remove(path).is_none() {
+        return false;
+    }
+    index.touch_parent(path);
+    true
+}
+
+async fn delete_object(s3: &S3, path: &str) -> ApiReply {
+    if is_snapshot_path(path) {
+        return read_only();
+    }
+    let mut index = s3.state.index.write().await;
+    if let Err(e) = index.check_mutable(path) {
+        return s3_error(StatusCode::FORBIDDEN, "AccessDenied", &format!("{:#}", e));
+    }
+    // S3 deletes succeed whether or not the key existed
+    if remove(&mut index, path) {
+        if let Err(e) = index.save(&s3.state.key) {
+            return s3_error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", &e.to_string());
+        }
+    }
+    respond(StatusCode::NO_CONTENT, vec![], vec![])
+}
+
+async fn delete_objects(s3: &S3, body: &[u8]) -> ApiReply {
+    let body = String::from_utf8_lossy(body);
+    let quiet = tag_values(&body, "Quiet").first().map(|q| q == "true").unwrap_or(false);
+
+    let mut index = s3.state.index.write().await;
+    let mut result = String::new();
+    let mut changed = false;
+    for key in tag_values(&body, "Key") {
+        match key_path(&key) {
+            Some(path) if is_snapshot_path(&path) => result.push_str(&format!(
+                "<Error><Key>{}</Key><Code>AccessDenied</Code><Message>Snapshots are read-only</Message></Error>",
+                escape(&key)
+            )),
+            Some(path) if index.check_mutable(&path).is_err() => result.push_str(&format!(
+                "<Error><Key>{}</Key><Code>AccessDenied</Code><Message>Write-once</Message></Error>",
+                escape(&key)
+            )),
+            path => {
+                changed |= path.map(|p| remove(&mut index, &p)).unwrap_or(false);
+                if !quiet {
+                    result.push_str(&format!("<Deleted><Key>{}</Key></Deleted>", escape(&key)));
+                }
+            }
+        }
+    }
+    if changed {
+        if let Err(e) = index.save(&s3.state.key) {
+            return s3_error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", &e.to_string());
+        }
+    }
+    xml(StatusCode::OK, format!("<DeleteResult xmlns=\"{}\">{}</DeleteResult>", XMLNS, result))
+}
+
+// --- Multipart uploads ---
+
+async fn create_upload(s3: &S3, key: &str) -> ApiReply {
+    let mut id = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut id);
+    let id: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+    let dir = s3.state.index.read().await.root_path().clone();
+    {
+        let mut uploads = s3.uploads.lock().unwrap();
+        // Abandoned by clients that never completed or aborted them
+        uploads.retain(|_, upload| upload.lock().unwrap().used.elapsed() < STALE);
+        if uploads.len() >= MAX_UPLOADS {
+            return s3_error(StatusCode::SERVICE_UNAVAILABLE, "SlowDown", "Too many multipart uploads in progress");
+        }
+        let upload = Upload { key: key.to_string(), buffer: FileBuffer::new(UPLOAD_MEMORY, &dir), parts: BTreeMap::new(), used: Instant::now() };
+        uploads.insert(id.clone(), Arc::new(Mutex::new(upload)));
+    }
+    xml(StatusCode::OK, format!(
+        "<InitiateMultipartUploadResult xmlns=\"{}\"><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId>\
+         </InitiateMultipartUploadResult>",
+        XMLNS, s3.bucket, escape(key), id
+    ))
+}
+
+fn no_such_upload() -> ApiReply {
+    s3_error(StatusCode::NOT_FOUND, "NoSuchUpload", "The upload does not exist or was already completed")
+}
+
+fn upload(s3: &S3, query: &HashMap<String, String>) -> Option<Arc<Mutex<Upload>>> {
+    query.get("uploadId").and_then(|id| s3.uploads.lock().unwrap().get(id).cloned())
+}
+
+async fn upload_part(s3: &S3, query: &HashMap<String, String>, body: Bytes) -> ApiReply {
+    let Some(number) = query.get("partNumber").and_then(|n| n.parse::<u32>().ok()).filter(|n| (1..=10_000).contains(n)) else {
+        return s3_error(StatusCode::BAD_REQUEST, "InvalidArgument", "partNumber must be 1-10000");
+    };
+    let Some(upload) = upload(s3, query) else { return no_such_upload() };
+    let digest = CryptoEngine::chunk_hash(&body, &s3.state.key);
+    // A part sent again is appended anew; the earlier copy is just not read
+    let written = tokio::task::block_in_place(|| {
+        let mut upload = upload.lock().unwrap();
+        let start = upload.buffer.size();
+        upload.buffer.write_at(start, &body)?;
+        upload.parts.insert(number, (start, body.len() as u64));
+        upload.used = Instant::now();
+        io::Result::Ok(())
+    });
+    if let Err(e) = written {
+        return s3_error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", &e.to_string());
+    }
+    respond(StatusCode::OK, vec![("etag", format!("\"{}-1\"", &digest[..32]))], vec![])
+}
+
+fn abort_upload(s3: &S3, query: &HashMap<String, String>) -> ApiReply {
+    let removed = query.get("uploadId").and_then(|id| s3.uploads.lock().unwrap().remove(id));
+    match removed {
+        Some(_) => respond(StatusCode::NO_CONTENT, vec![], vec![]),
+        None => no_such_upload(),
+    }
+}
+
+async fn complete_upload(s3: &S3, key: &str, path: &str, query: &HashMap<String, String>, body: &[u8]) -> ApiReply {
+    let Some(upload) = upload(s3, query) else { return no_such_upload() };
+
+    // Parts in the order the client lists them; unlisted ones are dropped
+    let body = String::from_utf8_lossy(body);
+    let mut ranges = VecDeque::new();
+    {
+        let upload = upload.lock().unwrap();
+        if upload.key != key {
+            return no_such_upload();
+        }
+        for number in tag_values(&body, "PartNumber") {
+            match number.trim().parse().ok().and_then(|n: u32| upload.parts.get(&n)) {
+                Some(&range) => ranges.push_back(range),
+                None => return s3_error(StatusCode::BAD_REQUEST, "InvalidPart", &format!("Part {} was never uploaded", number)),
+            }
+        }
+    }
+    if let Some(id) = query.get("uploadId") {
+        s3.uploads.lock().unwrap().remove(id);
+    }
+
+    match store(s3, key, path, Parts { upload, ranges }).await {
+        Ok(etag) => xml(StatusCode::OK, format!(
+            "<CompleteMultipartUploadResult xmlns=\"{}\"><Bucket>{}</Bucket><Key>{}</Key><ETag>{}</ETag>\
+             </CompleteMultipartUploadResult>",
+            XMLNS, s3.bucket, escape(key), escape(&etag)
+        )),
+        Err(reply) => reply,
+    }
+}
+
+// --- Routes ---
+
+/// Every request goes to `handle`; S3 picks operations by method and query
+pub fn routes(state: LetheState, bucket: String, credentials: Credentials) -> BoxedFilter<(Box<dyn Reply>,)> {
+    let s3 = S3 { state, bucket, credentials: Arc::new(credentials), uploads: Arc::new(Mutex::new(HashMap::new())) };
+    let query = warp::query::raw().or(warp::any().map(String::new)).unify();
+    // Clients send a length with every body; a request without one has none
+    let no_body = warp::header::headers_cloned().and_then(|headers: HeaderMap| async move {
+        match headers.contains_key("content-length") || headers.contains_key("transfer-encoding") {
+            true => Err(warp::reject()),
+            false => Ok(Bytes::new()),
+        }
+    });
+    let body = warp::body::content_length_limit(MAX_BODY).and(warp::body::bytes()).or(no_body).unify();
+    warp::method()
+        .and(warp::path::full())
+        .and(query)
+        .and(warp::header::headers_cloned())
+        .and(body)
+        .and(warp::any().map(move || s3.clone()))
+        .and_then(handle)
+        .boxed()
+}
@@ -0,0 +1,387 @@
+//! Per-session unlock for `lethe serve`.
+//!
+//! The server starts locked and holds no key. `POST /_lethe/unlock` with
+//! `{"password": "..."}` derives the key and loads the index, and with
+//! `{"user": "...", "password": "..."}` opens that vault user's slot
+//! instead. `POST
+//! /_lethe/lock` (or `/api/v1/lock`) drops them again. While locked, every
+//! vault route answers 503.
+//!
+//! Unlocks are taken one at a time, and after a few wrong passwords in a
+//! row each further one doubles the wait before the next is tried (429).
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use dav_server::davpath::DavPath;
+use dav_server::fs::{
+    DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError, FsFuture, FsResult, FsStream, OpenOptions,
+    ReadDirMeta,
+};
+use serde::Deserialize;
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+use lethe_core::access::{self, VaultUser};
+use lethe_core::activity;
+use lethe_core::audit::{self, Operation};
+use lethe_core::crypto::MasterKey;
+use lethe_core::index::IndexManager;
+use lethe_core::storage::BlockManager;
+use lethe_core::vault_lock::VaultLock;
+
+use super::api::{self, ApiReply};
+use super::auth::SignIns;
+use super::preview;
+use super::RESERVED_PREFIX;
+use crate::cli::mount::DEFAULT_SAVE_EVERY;
+use crate::cli::ops::unlock_key;
+use crate::daemon::Activity;
+use crate::dav::{LetheState, LetheWebDav};
+
+/// Upper bound for the unlock body; it only carries a password
+const MAX_UNLOCK_BODY: u64 = 4096;
+
+/// Wrong passwords in a row before unlocks are spaced out
+const FREE_FAILURES: u32 = 3;
+/// Longest wait between unlocks
+const MAX_WAIT: Duration = Duration::from_secs(300);
+
+/// Spaces out unlock attempts
+#[derive(Default)]
+struct Throttle {
+    /// Wrong passwords since the last unlock that worked
+    failures: u32,
+    /// No attempt is taken before this
+    until: Option<Instant>,
+    /// An attempt is deriving its key
+    busy: bool,
+}
+
+impl Throttle {
+    /// Takes an attempt, or says how long until one is taken
+    fn start(throttle: &Arc<Mutex<Self>>) -> Result<Attempt, Duration> {
+        let mut this = throttle.lock().unwrap();
+        if this.busy {
+            return Err(Duration::from_secs(1));
+        }
+        if let Some(wait) = this.until.and_then(|until| until.checked_duration_since(Instant::now())) {
+            return Err(wait);
+        }
+        this.busy = true;
+        Ok(Attempt { throttle: throttle.clone(), worked: false })
+    }
+
+    fn finish(&mut self, worked: bool) {
+        self.busy = false;
+        if worked {
+            *self = Self::default();
+            return;
+        }
+        self.failures += 1;
+        if let Some(over) = self.failures.checked_sub(FREE_FAILURES) {
+            let wait = Duration::from_secs(1u64 << over.min(16)).min(MAX_WAIT);
+            self.until = Some(Instant::now() + wait);
+        }
+    }
+}
+
+/// An unlock being tried. It counts as failed unless marked as worked,
+/// also when the client goes away halfway.
+struct Attempt {
+    throttle: Arc<Mutex<Throttle>>,
+    worked: bool,
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        self.throttle.lock().unwrap().finish(self.worked);
+    }
+}
+
+#[derive(Deserialize)]
+struct UnlockBody {
+    /// A vault user, whose own password then stands in for the vault's
+    #[serde(default)]
+    user: Option<String>,
+    password: String,
+}
+
+/// The served vault, unlocked or not
+#[derive(Clone)]
+pub struct Session {
+    vault_path: Arc<PathBuf>,
+    activity: Activity,
+    current: Arc<RwLock<Option<LetheState>>>,
+    /// Unlocked states make previews (see `preview`)
+    previews: bool,
+    throttle: Arc<Mutex<Throttle>>,
+    /// Vault users' checked passwords, forgotten on lock (see `auth`)
+    sign_ins: SignIns,
+}
+
+impl Session {
+    pub fn new(vault_path: PathBuf, activity: Activity) -> Self {
+        Self {
+            vault_path: Arc::new(vault_path),
+            activity,
+            current: Arc::new(RwLock::new(None)),
+            previews: false,
+            throttle: Arc::new(Mutex::new(Throttle::default())),
+            sign_ins: SignIns::new(),
+        }
+    }
+
+    pub fn sign_ins(&self) -> SignIns {
+        self.sign_ins.clone()
+    }
+
+    /// Makes previews of uploads once unlocked
+    pub fn with_previews(mut self, previews: bool) -> Self {
+        self.previews = previews;
+        self
+    }
+
+    /// The unlocked state, if any
+    pub fn current(&self) -> Option<LetheState> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.current.read().unwrap().is_some()
+    }
+
+    /// A vault user's record: from the index while unlocked, else from `users.bin`
+    pub async fn user(&self, name: &str) -> Option<VaultUser> {
+        let Some(state) = self.current() else {
+            return access::read_users_file(&self.vault_path).ok()?.remove(name);
+        };
+        let index = state.index.read().await;
+        index.data.users.get(name).filter(|u| !u.removed).cloned()
+    }
+
+    /// Whether the locked vault offers user slots
+    pub fn has_users(&self) -> bool {
+        access::read_users_file(&self.vault_path).is_ok_and(|users| !users.is_empty())
+    }
+
+    /// Drops the key. Open WebDAV handles keep theirs until they close.
+    pub async fn lock(&self) -> bool {
+        self.sign_ins.clear();
+        let Some(state) = self.current.write().unwrap().take() else {
+            return false;
+        };
+        if let Err(e) = state.flush_index().await {
+            log::error!("Failed to save the index before locking: {}", e);
+        }
+        // Wakes the watcher spawned by `unlock` so it lets go of its copy
+        state.lock.notify_one();
+        println!("Vault Locked.");
+        true
+    }
+
+    async fn unlock(&self, user: Option<String>, password: String) -> Result<()> {
+        let slot = match &user {
+            Some(name) => Some(self.user(name).await.ok_or_else(|| anyhow::anyhow!("Wrong user name or password"))?),
+            None => None,
+        };
+
+        // Argon2 is deliberately slow; keep it off the executor
+        let path = self.vault_path.as_ref().clone();
+        let (index_mgr, key, held) = tokio::task::spawn_blocking(move || -> Result<(IndexManager, MasterKey, VaultLock)> {
+            let key = match &slot {
+                Some(slot) => slot.unlock(&password)?,
+                None => unlock_key(&path, &password)?,
+            };
+            activity::unlocked(&path, &key);
+            // No one else writes the index while this session has it
+            let held = VaultLock::acquire(&path, "lethe serve")?;
+            let index_mgr = IndexManager::load(path.clone(), &key)?;
+            // `users.bin` may be older than the index; the index decides
+            if let Some(name) = &user {
+                if !access::users(&index_mgr.data).any(|(n, _)| n == name) {
+                    anyhow::bail!("Wrong user name or password");
+                }
+            }
+            let command = match &user {
+                Some(name) => format!("lethe serve (user {})", name),
+                None => "lethe serve".to_string(),
+            };
+            let _ = audit::record(&path, &key, Operation::Unlock { command });
+            Ok((index_mgr, key, held))
+        }).await??;
+        let block_mgr = BlockManager::new(self.vault_path.as_ref())?;
+
+        let state = LetheState::new(index_mgr, block_mgr, key, self.activity.clone()).with_previews(self.previews);
+        state.spawn_index_writer(DEFAULT_SAVE_EVERY);
+        if self.previews {
+            let pruned = state.clone();
+            tokio::spawn(async move {
+                let index = pruned.index.read().await;
+                match tokio::task::block_in_place(|| preview::prune(&index, &pruned.key)) {
+                    Ok(0) => {}
+                    Ok(n) => log::info!("Dropped {} preview(s) of files since changed or removed", n),
+                    Err(e) => log::warn!("Could not drop old previews: {:#}", e),
+                }
+            });
+        }
+        {
+            let mut current = self.current.write().unwrap();
+            if current.is_some() {
+                anyhow::bail!("Vault is already unlocked");
+            }
+            *current = Some(state.clone());
+        }
+        println!("Vault Unlocked.");
+
+        // `/api/v1/lock` only fires the state's notifier
+        let session = self.clone();
+        tokio::spawn(async move {
+            // Goes with the key, however the session ends
+            let _held = held;
+            state.lock_requested().await;
+            if let Err(e) = state.flush_index().await {
+                log::error!("Failed to save the index before locking: {}", e);
+            }
+            if session.clear(&state) {
+                println!("Vault Locked.");
+            }
+        });
+        Ok(())
+    }
+
+    /// Clears `state` unless a newer session has replaced it
+    fn clear(&self, state: &LetheState) -> bool {
+        let mut current = self.current.write().unwrap();
+        match current.as_ref() {
+            Some(s) if Arc::ptr_eq(&s.lock, &state.lock) => {
+                *current = None;
+                self.sign_ins.clear();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Extracts the unlocked state; rejects while locked
+    pub fn state_filter(&self) -> BoxedFilter<(LetheState,)> {
+        let session = self.clone();
+        warp::any()
+            .and_then(move || {
+                let current = session.current();
+                async move { current.ok_or_else(warp::reject::not_found) }
+            })
+            .boxed()
+    }
+}
+
+// --- Handlers ---
+
+async fn unlock(body: UnlockBody, session: Session) -> ApiReply {
+    if session.is_unlocked() {
+        return api::error(StatusCode::CONFLICT, "Vault is already unlocked");
+    }
+    let mut attempt = match Throttle::start(&session.throttle) {
+        Ok(attempt) => attempt,
+        Err(wait) => {
+            let reply = api::error_body("Too many failed unlocks; try again later");
+            let reply = warp::reply::with_status(reply, StatusCode::TOO_MANY_REQUESTS);
+            return Ok(Box::new(warp::reply::with_header(reply, "retry-after", wait.as_secs().max(1).to_string())));
+        }
+    };
+    match session.unlock(body.user, body.password).await {
+        Ok(()) => {
+            attempt.worked = true;
+            api::ack()
+        }
+        Err(e) => api::error(StatusCode::UNAUTHORIZED, e.to_string()),
+    }
+}
+
+async fn lock(session: Session) -> ApiReply {
+    session.lock().await;
+    api::ack()
+}
+
+async fn locked(session: Session) -> ApiReply {
+    if session.is_unlocked() {
+        return Err(warp::reject::not_found());
+    }
+    api::error(StatusCode::SERVICE_UNAVAILABLE, "Vault is locked")
+}
+
+// --- Routes ---
+
+/// Unlock/lock endpoints plus the 503 guard; must sit in front of the vault routes
+pub fn routes(session: Session) -> BoxedFilter<(Box<dyn Reply>,)> {
+    let with_session = warp::any().map(move || session.clone());
+
+    let unlock = warp::post()
+        .and(warp::path(RESERVED_PREFIX)).and(warp::path("unlock")).and(warp::path::end())
+        .and(warp::body::content_length_limit(MAX_UNLOCK_BODY))
+        .and(warp::body::json::<UnlockBody>())
+        .and(with_session.clone())
+        .and_then(unlock);
+    let lock = warp::post()
+        .and(warp::path(RESERVED_PREFIX)).and(warp::path("lock")).and(warp::path::end())
+        .and(with_session.clone())
+        .and_then(lock);
+    let guard = with_session.and_then(locked);
+
+    unlock.or(lock).unify()
+        .or(guard).unify()
+        .boxed()
+}
+
+// --- WebDAV ---
+
+/// Routes each WebDAV call to whichever state is unlocked right now
+#[derive(Clone)]
+pub struct SessionDav {
+    pub session: Session,
+}
+
+impl SessionDav {
+    fn fs(&self) -> FsResult<LetheWebDav> {
+        self.session.current()
+            .map(|state| LetheWebDav { state })
+            .ok_or(FsError::Forbidden)
+    }
+}
+
+impl DavFileSystem for SessionDav {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        Box::pin(async move { self.fs()?.open(path, options).await })
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a DavPath, meta: ReadDirMeta) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>> {
+        Box::pin(async move { self.fs()?.read_dir(path, meta).await })
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        Box::pin(async move { self.fs()?.metadata(path).await })
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        Box::pin(async move { self.fs()?.create_dir(path).await })
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        Box::pin(async move { self.fs()?.remove_dir(path).await })
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        Box::pin(async move { self.fs()?.remove_file(path).await })
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        Box::pin(async move { self.fs()?.rename(from, to).await })
+    }
+
+    fn get_quota<'a>(&'a self) -> FsFuture<'a, (u64, Option<u64>)> {
+        Box::pin(async move { self.fs()?.get_quota().await })
+    }
+}
@@ -0,0 +1,388 @@
+//! AWS Signature Version 4, as `lethe s3-serve` checks it.
+//!
+//! Each request must be signed with the gateway's access key and secret,
+//! either in an `Authorization: AWS4-HMAC-SHA256 ...` header or as a
+//! presigned URL (`X-Amz-Signature` in the query). The signed payload hash
+//! is checked against the body; `UNSIGNED-PAYLOAD` is taken as it is, and
+//! an `aws-chunked` body signed as `STREAMING-AWS4-HMAC-SHA256-PAYLOAD`
+//! has each chunk's signature checked as it is decoded.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use warp::http::{HeaderMap, Method, StatusCode};
+use zeroize::Zeroizing;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+/// How far a request's date may be from the server's clock
+const SKEW: Duration = Duration::from_secs(15 * 60);
+/// Longest a presigned URL may be valid for, as in S3
+const MAX_EXPIRES: u64 = 7 * 24 * 60 * 60;
+/// SHA-256 of nothing, for the chunk signatures
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// The one key pair requests may be signed with
+pub struct Credentials {
+    pub access_key: String,
+    pub secret: Zeroizing<String>,
+}
+
+/// Why a request was refused, as an S3 error
+#[derive(Debug)]
+pub struct Denied {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+}
+
+fn denied(code: &'static str, message: impl Into<String>) -> Denied {
+    Denied { status: StatusCode::FORBIDDEN, code, message: message.into() }
+}
+
+fn malformed(message: impl Into<String>) -> Denied {
+    Denied { status: StatusCode::BAD_REQUEST, code: "AuthorizationHeaderMalformed", message: message.into() }
+}
+
+fn mismatch() -> Denied {
+    denied("SignatureDoesNotMatch", "The request signature does not match")
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A signature's 32 bytes from its 64 hex digits
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() != 64 {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Percent-decodes to bytes; malformed escapes are kept as they are
+fn decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(b) = s.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    out
+}
+
+/// Percent-encodes all but `A-Z a-z 0-9 - _ . ~` (and `/` if `keep_slash`)
+fn encode(bytes: &[u8], keep_slash: bool) -> String {
+    bytes.iter().map(|&b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        b'/' if keep_slash => "/".to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+/// `20240601T120000Z` as seconds since the epoch
+fn parse_amz_date(date: &str) -> Option<u64> {
+    if date.len() != 16 || !date.is_ascii() {
+        return None;
+    }
+    let rfc3339 = format!(
+        "{}-{}-{}T{}:{}:{}Z",
+        &date[..4], &date[4..6], &date[6..8], &date[9..11], &date[11..13], &date[13..15]
+    );
+    let time = humantime::parse_rfc3339(&rfc3339).ok()?;
+    Some(time.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// What a request was signed with
+struct Signed {
+    access_key: String,
+    /// `20240601/us-east-1/s3/aws4_request`
+    scope: String,
+    date: String,
+    signed_headers: Vec<String>,
+    signature: String,
+}
+
+impl Signed {
+    /// From `Credential=.../..., SignedHeaders=a;b, Signature=...`
+    fn from_header(value: &str, headers: &HeaderMap) -> Result<Self, Denied> {
+        let Some(fields) = value.strip_prefix(ALGORITHM) else {
+            return Err(malformed(format!("Only {} signatures are accepted", ALGORITHM)));
+        };
+        let (mut credential, mut signed_headers, mut signature) = (None, None, None);
+        for field in fields.split(',') {
+            match field.trim().split_once('=') {
+                Some(("Credential", v)) => credential = Some(v),
+                Some(("SignedHeaders", v)) => signed_headers = Some(v),
+                Some(("Signature", v)) => signature = Some(v),
+                _ => {}
+            }
+        }
+        let (Some(credential), Some(signed_headers), Some(signature)) = (credential, signed_headers, signature) else {
+            return Err(malformed("The Authorization header lacks Credential, SignedHeaders or Signature"));
+        };
+        let date = headers.get("x-amz-date").and_then(|v| v.to_str().ok())
+            .ok_or_else(|| denied("AccessDenied", "Signed requests must carry x-amz-date"))?;
+        Self::new(credential, date, signed_headers, signature)
+    }
+
+    fn new(credential: &str, date: &str, signed_headers: &str, signature: &str) -> Result<Self, Denied> {
+        let Some((access_key, scope)) = credential.split_once('/') else {
+            return Err(malformed("Credential must be <access key>/<date>/<region>/s3/aws4_request"));
+        };
+        let parts: Vec<&str> = scope.split('/').collect();
+        if parts.len() != 4 || parts[2] != "s3" || parts[3] != "aws4_request" {
+            return Err(malformed("Credential must be <access key>/<date>/<region>/s3/aws4_request"));
+        }
+        if !date.starts_with(parts[0]) {
+            return Err(malformed("The credential's date is not the request's"));
+        }
+        Ok(Self {
+            access_key: access_key.to_string(),
+            scope: scope.to_string(),
+            date: date.to_string(),
+            signed_headers: signed_headers.split(';').map(str::to_ascii_lowercase).collect(),
+            signature: signature.to_string(),
+        })
+    }
+
+    fn signing_key(&self, secret: &str) -> Zeroizing<Vec<u8>> {
+        let mut key = Zeroizing::new(format!("AWS4{}", secret).into_bytes());
+        for part in self.scope.split('/') {
+            key = Zeroizing::new(hmac(&key, part.as_bytes()));
+        }
+        key
+    }
+
+    /// Checks `signature` over `string_to_sign` in constant time
+    fn verify(key: &[u8], string_to_sign: &str, signature: &str) -> Result<(), Denied> {
+        let given = unhex(signature).ok_or_else(mismatch)?;
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes any key length");
+        mac.update(string_to_sign.as_bytes());
+        mac.verify_slice(&given).map_err(|_| mismatch())
+    }
+}
+
+/// Sorted `name=value` pairs, both encoded; `skip` is left out
+fn canonical_query(query: &str, skip: Option<&str>) -> String {
+    let mut pairs: Vec<(String, String)> = query.split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| p.split_once('=').unwrap_or((p, "")))
+        .filter(|(name, _)| Some(decode(name).as_slice()) != skip.map(str::as_bytes))
+        .map(|(name, value)| (encode(&decode(name), false), encode(&decode(value), false)))
+        .collect();
+    pairs.sort();
+    pairs.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("&")
+}
+
+/// `name:value\n` for each signed header, values trimmed and joined
+fn canonical_headers(names: &[String], headers: &HeaderMap) -> Result<String, Denied> {
+    if !names.iter().any(|n| n == "host") {
+        return Err(denied("AccessDenied", "The host header must be signed"));
+    }
+    let mut out = String::new();
+    for name in names {
+        let values: Vec<String> = headers.get_all(name.as_str()).iter()
+            .map(|v| v.to_str().map(|v| v.split_whitespace().collect::<Vec<_>>().join(" ")))
+            .collect::<Result<_, _>>()
+            .map_err(|_| malformed(format!("Header {} is not text", name)))?;
+        if values.is_empty() {
+            return Err(denied("AccessDenied", format!("Signed header {} is missing", name)));
+        }
+        out.push_str(&format!("{}:{}\n", name, values.join(",")));
+    }
+    Ok(out)
+}
+
+/// What is left to check of the body once the request's signature is
+pub enum Payload {
+    /// Checked already, or unsigned
+    Plain,
+    /// `aws-chunked`, with each chunk's signature to check if it is signed
+    Chunked(Option<Chain>),
+}
+
+/// The signatures of an `aws-chunked` body: each chunk signs its data and
+/// the signature before it, starting from the request's
+pub struct Chain {
+    key: Zeroizing<Vec<u8>>,
+    date: String,
+    scope: String,
+    previous: String,
+}
+
+impl Chain {
+    fn next(&mut self, kind: &str, hashed: &str, signature: &str) -> Result<(), Denied> {
+        let string_to_sign = format!("{}\n{}\n{}\n{}\n{}", kind, self.date, self.scope, self.previous, hashed);
+        Signed::verify(&self.key, &string_to_sign, signature)?;
+        self.previous = signature.to_string();
+        Ok(())
+    }
+}
+
+/// Checks a request's signature, and the body's hash if it was signed
+pub fn check(
+    credentials: &Credentials,
+    method: &Method,
+    path: &str,
+    query: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Payload, Denied> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let param = |name: &str| {
+        query.split('&').filter_map(|p| p.split_once('=')).find(|(n, _)| *n == name)
+            .map(|(_, v)| String::from_utf8_lossy(&decode(v)).into_owned())
+    };
+
+    // 1. Who signed it, when, and how the body is covered
+    let presigned = param("X-Amz-Signature").is_some();
+    let (signed, payload_hash) = if presigned {
+        if param("X-Amz-Algorithm").as_deref() != Some(ALGORITHM) {
+            return Err(malformed(format!("Only {} signatures are accepted", ALGORITHM)));
+        }
+        let field = |name: &str| param(name).ok_or_else(|| malformed(format!("The query lacks {}", name)));
+        let signed = Signed::new(&field("X-Amz-Credential")?, &field("X-Amz-Date")?, &field("X-Amz-SignedHeaders")?, &field("X-Amz-Signature")?)?;
+        let expires: u64 = field("X-Amz-Expires")?.parse().map_err(|_| malformed("X-Amz-Expires must be a number"))?;
+        if expires > MAX_EXPIRES {
+            return Err(malformed("X-Amz-Expires must be at most 7 days"));
+        }
+        let date = parse_amz_date(&signed.date).ok_or_else(|| malformed("X-Amz-Date must be like 20240601T120000Z"))?;
+        if now > date + expires || date > now + SKEW.as_secs() {
+            return Err(denied("AccessDenied", "Request has expired"));
+        }
+        (signed, "UNSIGNED-PAYLOAD".to_string())
+    } else {
+        let Some(authorization) = header("authorization") else {
+            return Err(denied("AccessDenied", "Requests must be signed with the gateway's access key"));
+        };
+        let signed = Signed::from_header(authorization, headers)?;
+        let date = parse_amz_date(&signed.date).ok_or_else(|| malformed("x-amz-date must be like 20240601T120000Z"))?;
+        if now.abs_diff(date) > SKEW.as_secs() {
+            return Err(denied("RequestTimeTooSkewed", "The request's time is too far from the server's"));
+        }
+        // Without the header, the body itself must be what was signed
+        let payload_hash = header("x-amz-content-sha256").map(str::to_string).unwrap_or_else(|| sha256_hex(body));
+        (signed, payload_hash)
+    };
+    if signed.access_key != credentials.access_key {
+        return Err(denied("InvalidAccessKeyId", "The access key is not this gateway's"));
+    }
+
+    // 2. The signature over the canonical request
+    let path = if path.is_empty() { "/" } else { path };
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method.as_str(),
+        encode(&decode(path), true),
+        canonical_query(query, presigned.then_some("X-Amz-Signature")),
+        canonical_headers(&signed.signed_headers, headers)?,
+        signed.signed_headers.join(";"),
+        payload_hash,
+    );
+    let string_to_sign = format!("{}\n{}\n{}\n{}", ALGORITHM, signed.date, signed.scope, sha256_hex(canonical_request.as_bytes()));
+    let key = signed.signing_key(&credentials.secret);
+    Signed::verify(&key, &string_to_sign, &signed.signature)?;
+
+    // 3. The body, as far as it can be checked now
+    match payload_hash.as_str() {
+        "UNSIGNED-PAYLOAD" => Ok(Payload::Plain),
+        "STREAMING-UNSIGNED-PAYLOAD-TRAILER" => Ok(Payload::Chunked(None)),
+        "STREAMING-AWS4-HMAC-SHA256-PAYLOAD" | "STREAMING-AWS4-HMAC-SHA256-PAYLOAD-TRAILER" => {
+            Ok(Payload::Chunked(Some(Chain { key, date: signed.date, scope: signed.scope, previous: signed.signature })))
+        }
+        hash if hash.eq_ignore_ascii_case(&sha256_hex(body)) => Ok(Payload::Plain),
+        _ => Err(Denied {
+            status: StatusCode::BAD_REQUEST,
+            code: "XAmzContentSHA256Mismatch",
+            message: "The body does not match x-amz-content-sha256".to_string(),
+        }),
+    }
+}
+
+impl Payload {
+    /// The body's content: `aws-chunked` undone, chunk signatures checked
+    pub fn open(self, body: Bytes) -> Result<Bytes, Denied> {
+        match self {
+            Payload::Plain => Ok(body),
+            Payload::Chunked(chain) => decode_aws_chunked(&body, chain).map(Bytes::from),
+        }
+    }
+}
+
+fn incomplete() -> Denied {
+    Denied { status: StatusCode::BAD_REQUEST, code: "IncompleteBody", message: "Malformed aws-chunked body".to_string() }
+}
+
+/// Undoes `Content-Encoding: aws-chunked`, which SDKs use for streaming
+/// uploads: `<hex size>[;chunk-signature=...]\r\n<data>\r\n` ... `0...\r\n`,
+/// then trailers, the last of them signed if the chunks are
+fn decode_aws_chunked(body: &[u8], mut chain: Option<Chain>) -> Result<Vec<u8>, Denied> {
+    let line = |rest: &[u8]| -> Result<(String, usize), Denied> {
+        let end = rest.windows(2).position(|w| w == b"\r\n").ok_or_else(incomplete)?;
+        let text = std::str::from_utf8(&rest[..end]).map_err(|_| incomplete())?;
+        Ok((text.to_string(), end + 2))
+    };
+    let mut out = Vec::with_capacity(body.len());
+    let mut rest = body;
+    loop {
+        let (header, used) = line(rest)?;
+        rest = &rest[used..];
+        let mut fields = header.split(';');
+        let size = usize::from_str_radix(fields.next().unwrap_or("").trim(), 16).map_err(|_| incomplete())?;
+        let data = rest.get(..size).ok_or_else(incomplete)?;
+        if let Some(chain) = &mut chain {
+            let signature = fields.find_map(|f| f.trim().strip_prefix("chunk-signature=")).ok_or_else(mismatch)?;
+            chain.next("AWS4-HMAC-SHA256-PAYLOAD", &format!("{}\n{}", EMPTY_SHA256, sha256_hex(data)), signature)?;
+        }
+        if size == 0 {
+            break;
+        }
+        out.extend_from_slice(data);
+        rest = rest.get(size + 2..).ok_or_else(incomplete)?;
+    }
+
+    // Trailers (checksums), each `name:value\r\n`, until an empty line
+    let mut trailers = String::new();
+    let mut trailer_signature = None;
+    while !rest.is_empty() {
+        let (trailer, used) = line(rest)?;
+        rest = &rest[used..];
+        match trailer.split_once(':') {
+            Some(("x-amz-trailer-signature", signature)) => trailer_signature = Some(signature.trim().to_string()),
+            Some((name, value)) => trailers.push_str(&format!("{}:{}\n", name.trim(), value.trim())),
+            None => break,
+        }
+    }
+    if let Some(chain) = &mut chain {
+        if let Some(signature) = trailer_signature {
+            chain.next("AWS4-HMAC-SHA256-TRAILER", &sha256_hex(trailers.as_bytes()), &signature)?;
+        } else if !trailers.is_empty() {
+            return Err(mismatch());
+        }
+    }
+    Ok(out)
+}
+
@@ -0,0 +1,23 @@
+use warp::filters::BoxedFilter;
+use warp::{Filter, Reply};
+
+use super::RESERVED_PREFIX;
+
+/// The whole UI is one self-contained page that talks WebDAV back to the
+/// same server (PROPFIND/PUT/GET/DELETE/MOVE/MKCOL), plus the unlock and
+/// lock endpoints when serving.
+const INDEX_HTML: &str = include_str!("webui.html");
+
+/// Where the UI is served, relative to the server root
+pub fn ui_path() -> String {
+    format!("/{}/ui", RESERVED_PREFIX)
+}
+
+pub fn routes() -> BoxedFilter<(Box<dyn Reply>,)> {
+    warp::get()
+        .and(warp::path(RESERVED_PREFIX))
+        .and(warp::path("ui"))
+        .and(warp::path::end())
+        .map(|| Box::new(warp::reply::html(INDEX_HTML)) as Box<dyn Reply>)
+        .boxed()
+}
@@ -0,0 +1,255 @@
+//! Zip archives of a subtree, written as they are sent (`GET /api/v1/zip`).
+//!
+//! Nothing is compressed: blocks were already, and what zstd left alone
+//! (photos, video) deflate wouldn't shrink either. Sizes come from the
+//! index, so each local header has them; the CRC is only known once the
+//! data is through, and follows it in a data descriptor. Files of 4 GiB
+//! or more, offsets past that and more than 65535 entries use Zip64.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::sync::mpsc;
+use warp::hyper::Body;
+
+use lethe_core::crypto::MasterKey;
+use lethe_core::nonblocking;
+use lethe_core::storage::BlockManager;
+
+/// Sizes and offsets from here on go in Zip64 fields
+const LIMIT: u64 = 0xFFFF_FFFF;
+
+/// Data descriptor follows, names are UTF-8
+const FLAGS: u16 = 0x0008 | 0x0800;
+
+/// Chunks waiting to be sent, so blocks are read ahead of a slow client
+/// by only so much
+const QUEUED: usize = 8;
+
+/// One file or folder of the archive
+pub struct Member {
+    /// Path in the archive, a folder's ending in `/`
+    pub name: String,
+    pub size: u64,
+    /// Unix timestamp
+    pub modified: u64,
+    /// Blocks to read the file from, empty for a folder
+    pub blocks: Vec<String>,
+}
+
+/// What has been written so far, for the central directory
+#[derive(Default)]
+struct Archive {
+    offset: u64,
+    central: Vec<u8>,
+    entries: u64,
+    /// Where the CRC of the member being written goes in `central`
+    crc_at: usize,
+}
+
+impl Archive {
+    /// Local header of `member`, before its data
+    fn start(&mut self, member: &Member) -> Vec<u8> {
+        let zip64 = member.size >= LIMIT;
+        let (time, date) = dos_time(member.modified);
+        let mut extra = timestamp(member.modified);
+        if zip64 {
+            // Also marks the data descriptor's sizes as 8 bytes each
+            extra.extend(field(0x0001, &[member.size.to_le_bytes(), member.size.to_le_bytes()].concat()));
+        }
+        let mut header = Vec::with_capacity(30 + member.name.len() + extra.len());
+        header.extend(0x0403_4b50u32.to_le_bytes());
+        header.extend(version(zip64).to_le_bytes());
+        header.extend(FLAGS.to_le_bytes());
+        header.extend(0u16.to_le_bytes()); // stored
+        header.extend(time.to_le_bytes());
+        header.extend(date.to_le_bytes());
+        header.extend(0u32.to_le_bytes()); // CRC, in the descriptor
+        let size = if zip64 { LIMIT as u32 } else { 0 };
+        header.extend(size.to_le_bytes());
+        header.extend(size.to_le_bytes());
+        header.extend((member.name.len() as u16).to_le_bytes());
+        header.extend((extra.len() as u16).to_le_bytes());
+        header.extend(member.name.as_bytes());
+        header.extend(extra);
+        self.central_entry(member, zip64);
+        self.offset += header.len() as u64;
+        header
+    }
+
+    /// Data descriptor of the member just written, filling in its CRC
+    fn finish_member(&mut self, member: &Member, crc: u32) -> Vec<u8> {
+        let at = self.crc_at;
+        self.central[at..at + 4].copy_from_slice(&crc.to_le_bytes());
+
+        let mut descriptor = Vec::with_capacity(24);
+        descriptor.extend(0x0807_4b50u32.to_le_bytes());
+        descriptor.extend(crc.to_le_bytes());
+        if member.size >= LIMIT {
+            descriptor.extend(member.size.to_le_bytes());
+            descriptor.extend(member.size.to_le_bytes());
+        } else {
+            descriptor.extend((member.size as u32).to_le_bytes());
+            descriptor.extend((member.size as u32).to_le_bytes());
+        }
+        self.offset += member.size + descriptor.len() as u64;
+        descriptor
+    }
+
+    fn central_entry(&mut self, member: &Member, zip64: bool) {
+        let folder = member.name.ends_with('/');
+        let (time, date) = dos_time(member.modified);
+        let far = self.offset >= LIMIT;
+        let mut wide = Vec::new();
+        if zip64 {
+            wide.extend(member.size.to_le_bytes());
+            wide.extend(member.size.to_le_bytes());
+        }
+        if far {
+            wide.extend(self.offset.to_le_bytes());
+        }
+        let mut extra = timestamp(member.modified);
+        if !wide.is_empty() {
+            extra.extend(field(0x0001, &wide));
+        }
+        let size = if zip64 { LIMIT as u32 } else { member.size as u32 };
+        // Unix permissions in the high half; MS-DOS directory bit for folders
+        let attributes = if folder { (0o040755u32 << 16) | 0x10 } else { 0o100644u32 << 16 };
+
+        self.crc_at = self.central.len() + 16;
+        let central = &mut self.central;
+        central.extend(0x0201_4b50u32.to_le_bytes());
+        central.extend((3u16 << 8 | 45).to_le_bytes()); // made on Unix
+        central.extend(version(zip64 || far).to_le_bytes());
+        central.extend(FLAGS.to_le_bytes());
+        central.extend(0u16.to_le_bytes());
+        central.extend(time.to_le_bytes());
+        central.extend(date.to_le_bytes());
+        central.extend(0u32.to_le_bytes()); // CRC, once known
+        central.extend(size.to_le_bytes());
+        central.extend(size.to_le_bytes());
+        central.extend((member.name.len() as u16).to_le_bytes());
+        central.extend((extra.len() as u16).to_le_bytes());
+        central.extend(0u16.to_le_bytes()); // comment
+        central.extend(0u16.to_le_bytes()); // disk
+        central.extend(0u16.to_le_bytes()); // internal attributes
+        central.extend(attributes.to_le_bytes());
+        central.extend((if far { LIMIT } else { self.offset } as u32).to_le_bytes());
+        central.extend(member.name.as_bytes());
+        central.extend(extra);
+        self.entries += 1;
+    }
+
+    /// The central directory and its end records
+    fn end(self) -> Vec<u8> {
+        let (start, size, count) = (self.offset, self.central.len() as u64, self.entries);
+        let mut end = self.central;
+        if start >= LIMIT || size >= LIMIT || count >= 0xFFFF {
+            let record = start + size;
+            end.extend(0x0606_4b50u32.to_le_bytes());
+            end.extend(44u64.to_le_bytes());
+            end.extend((3u16 << 8 | 45).to_le_bytes());
+            end.extend(45u16.to_le_bytes());
+            end.extend(0u32.to_le_bytes());
+            end.extend(0u32.to_le_bytes());
+            end.extend(count.to_le_bytes());
+            end.extend(count.to_le_bytes());
+            end.extend(size.to_le_bytes());
+            end.extend(start.to_le_bytes());
+            end.extend(0x0706_4b50u32.to_le_bytes());
+            end.extend(0u32.to_le_bytes());
+            end.extend(record.to_le_bytes());
+            end.extend(1u32.to_le_bytes());
+        }
+        end.extend(0x0605_4b50u32.to_le_bytes());
+        end.extend(0u16.to_le_bytes());
+        end.extend(0u16.to_le_bytes());
+        end.extend((count.min(0xFFFF) as u16).to_le_bytes());
+        end.extend((count.min(0xFFFF) as u16).to_le_bytes());
+        end.extend((size.min(LIMIT) as u32).to_le_bytes());
+        end.extend((start.min(LIMIT) as u32).to_le_bytes());
+        end.extend(0u16.to_le_bytes()); // comment
+        end
+    }
+}
+
+fn version(zip64: bool) -> u16 {
+    if zip64 { 45 } else { 20 }
+}
+
+fn field(tag: u16, data: &[u8]) -> Vec<u8> {
+    let mut field = Vec::with_capacity(4 + data.len());
+    field.extend(tag.to_le_bytes());
+    field.extend((data.len() as u16).to_le_bytes());
+    field.extend(data);
+    field
+}
+
+/// The extended timestamp field: the modification time to the second,
+/// which the MS-DOS fields can't hold
+fn timestamp(modified: u64) -> Vec<u8> {
+    let mut data = vec![1u8];
+    data.extend((modified.min(u32::MAX as u64) as u32).to_le_bytes());
+    field(0x5455, &data)
+}
+
+/// MS-DOS time and date of a Unix timestamp, in UTC, from 1980 on
+fn dos_time(secs: u64) -> (u16, u16) {
+    let days = secs / 86_400;
+    let rest = secs % 86_400;
+    // Days to a civil date (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    if year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let year = year.min(2107);
+    let time = (((rest / 3600) << 11) | ((rest % 3600 / 60) << 5) | (rest % 60 / 2)) as u16;
+    let date = (((year - 1980) << 9) | (month << 5) | day) as u16;
+    (time, date)
+}
+
+/// The archive of `members` as a response body. Blocks are read as the
+/// client takes the data; one that fails cuts the response off, so the
+/// download fails rather than holding a damaged file.
+pub fn stream(members: Vec<Member>, storage: Arc<BlockManager>, key: Arc<MasterKey>) -> Body {
+    let (tx, rx) = mpsc::channel::<std::io::Result<Bytes>>(QUEUED);
+    tokio::spawn(async move {
+        if let Err(e) = write(members, storage, key, &tx).await {
+            log::warn!("Zip download cut off: {:#}", e);
+            let _ = tx.send(Err(std::io::Error::other(e.to_string()))).await;
+        }
+    });
+    let chunks = futures_util::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|chunk| (chunk, rx)) });
+    Body::wrap_stream(chunks)
+}
+
+async fn write(members: Vec<Member>, storage: Arc<BlockManager>, key: Arc<MasterKey>, tx: &mpsc::Sender<std::io::Result<Bytes>>) -> anyhow::Result<()> {
+    let send = |chunk: Vec<u8>| async move {
+        tx.send(Ok(Bytes::from(chunk))).await.map_err(|_| anyhow::anyhow!("the client went away"))
+    };
+    let mut archive = Archive::default();
+    for member in &members {
+        send(archive.start(member)).await?;
+        let mut crc = crc32fast::Hasher::new();
+        let mut sent = 0;
+        for block in &member.blocks {
+            let data = nonblocking::read_block(storage.clone(), block.clone(), key.clone()).await?;
+            crc.update(&data);
+            sent += data.len() as u64;
+            send(data).await?;
+        }
+        if sent != member.size {
+            anyhow::bail!("{} holds {} bytes, not the {} the index says", member.name, sent, member.size);
+        }
+        send(archive.finish_member(member, crc.finalize())).await?;
+    }
+    send(archive.end()).await
+}
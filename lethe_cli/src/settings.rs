@@ -0,0 +1,60 @@
+//! Per-user settings, from `lethe.conf` in the config directory
+//! (`~/.config/lethe/lethe.conf` on Linux): one `<name> <value>` line
+//! each, `#` starting a comment. Only what no vault should carry lives
+//! here; a vault's own settings stay in its folder.
+//!
+//! `mountpoint <path>` is where mounts go when not given `--mountpoint`:
+//! a folder on Linux and macOS (`~/` is the home directory), a drive
+//! letter on Windows. `password_strength <0-4>` is the least strength a
+//! new vault password must have (see `cli::strength`); the default, 0,
+//! only warns about weak ones.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::PathBuf;
+
+pub const SETTINGS_FILE: &str = "lethe.conf";
+
+#[derive(Debug, Default)]
+pub struct Settings {
+    pub mountpoint: Option<String>,
+    pub password_strength: u8,
+}
+
+/// Where the settings are read from
+pub fn path() -> Option<PathBuf> {
+    dirs::config_dir().map(|p| p.join("lethe").join(SETTINGS_FILE))
+}
+
+impl Settings {
+    /// The defaults when there is no file
+    pub fn load() -> Result<Self> {
+        let Some(path) = path() else { return Ok(Self::default()) };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", path)),
+        };
+        let mut settings = Self::default();
+        for line in contents.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let (name, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            match (name, value.trim()) {
+                ("mountpoint", value) if !value.is_empty() => settings.mountpoint = Some(expand_home(value)),
+                ("password_strength", value) => match value.parse() {
+                    Ok(score @ 0..=4) => settings.password_strength = score,
+                    _ => anyhow::bail!("password_strength in {:?} is a score from 0 to 4, not {:?}", path, value),
+                },
+                _ => anyhow::bail!("Invalid line in {:?}: {:?}", path, line),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+/// `~/x` -> `<home>/x`
+fn expand_home(value: &str) -> String {
+    match (value.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest).display().to_string(),
+        _ => value.to_string(),
+    }
+}
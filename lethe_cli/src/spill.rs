@@ -0,0 +1,469 @@
+//! Open files for the mounts, held in RAM up to a limit.
+//!
+//! A file is kept in pages of `PAGE` bytes. What was stored before it was
+//! opened is read from the vault as it is needed, and only the pages
+//! written to are held. Once more of them are in memory than the limit
+//! allows, the least recently used ones are encrypted into a scratch file
+//! in the vault folder, so copying a file larger than RAM onto the drive
+//! doesn't exhaust it. The scratch file is unlinked as soon as it is
+//! created (on Windows, deleted once closed) and its key exists only in
+//! memory: nothing of it outlives the mount, even after a crash.
+//!
+//! `CacheSpill` does the same for the mount's block cache: decrypted
+//! blocks it has no room left for in memory are sealed into a scratch file
+//! in the temp folder, up to `--cache-disk-mb`, so blocks played again
+//! come from the local disk rather than a slow vault drive.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use lethe_core::cache::Overflow;
+use lethe_core::crypto::{CryptoEngine, MasterKey};
+use lethe_core::plaintext::{self, Plaintext};
+use lethe_core::reader::FileReader;
+use lethe_core::storage::BlockManager;
+
+/// A file's content as stored in the vault
+pub type Stored = FileReader<Arc<BlockManager>, Arc<MasterKey>>;
+
+/// Size of a page, and of what is spilled at a time
+pub const PAGE: usize = 1024 * 1024;
+
+const NONCE: usize = 24;
+const TAG: usize = 16;
+/// Room a spilled page takes up in the scratch file
+const SLOT: u64 = (NONCE + PAGE + TAG) as u64;
+
+#[cfg(windows)]
+const FILE_FLAG_DELETE_ON_CLOSE: u32 = 0x0400_0000;
+
+struct Resident {
+    data: Plaintext,
+    used: u64,
+}
+
+/// Where pages go once the limit is reached
+struct Scratch {
+    file: File,
+    key: MasterKey,
+    /// Length of each spilled page's sealed form, by page number
+    sealed: HashMap<u64, usize>,
+}
+
+impl Scratch {
+    fn create(dir: &Path) -> io::Result<Self> {
+        let path = dir.join(format!(".spill-{:016x}", rand::random::<u64>()));
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+        // Windows can't unlink an open file, so it deletes it once closed
+        #[cfg(windows)]
+        std::os::windows::fs::OpenOptionsExt::custom_flags(&mut options, FILE_FLAG_DELETE_ON_CLOSE);
+        let file = options.open(&path)?;
+        #[cfg(unix)]
+        fs::remove_file(&path)?;
+        Ok(Self { file, key: CryptoEngine::random_key(), sealed: HashMap::new() })
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+
+    /// Seals `data` at `offset`. Returns the length of the ciphertext.
+    fn seal_at(&self, data: &[u8], offset: u64) -> io::Result<usize> {
+        let (ciphertext, nonce) = CryptoEngine::encrypt(data, &self.key).map_err(io::Error::other)?;
+        self.write_at(&nonce, offset)?;
+        self.write_at(&ciphertext, offset + NONCE as u64)?;
+        Ok(ciphertext.len())
+    }
+
+    fn open_at(&self, offset: u64, len: usize) -> io::Result<Plaintext> {
+        let mut sealed = vec![0u8; NONCE + len];
+        self.read_at(&mut sealed, offset)?;
+        let (nonce, ciphertext) = sealed.split_at(NONCE);
+        let data = CryptoEngine::decrypt(ciphertext, nonce, &self.key).map_err(io::Error::other)?;
+        Ok(Plaintext::new(data))
+    }
+
+    fn store(&mut self, page: u64, data: &[u8]) -> io::Result<()> {
+        let len = self.seal_at(data, page * SLOT)?;
+        self.sealed.insert(page, len);
+        Ok(())
+    }
+
+    fn load(&self, page: u64) -> io::Result<Option<Plaintext>> {
+        let Some(&len) = self.sealed.get(&page) else { return Ok(None) };
+        self.open_at(page * SLOT, len).map(Some)
+    }
+}
+
+/// Room a sealed block takes in the cache's scratch file
+struct Extent {
+    offset: u64,
+    room: u64,
+    /// Length of the block, and of its ciphertext
+    len: usize,
+    sealed: usize,
+    used: u64,
+}
+
+/// The disk tier of a mount's block cache (see `lethe_core::cache`)
+pub struct CacheSpill {
+    dir: PathBuf,
+    limit: u64,
+    inner: Mutex<Spilled>,
+}
+
+#[derive(Default)]
+struct Spilled {
+    /// Made on the first block spilled; None after it failed
+    scratch: Option<Scratch>,
+    failed: bool,
+    blocks: HashMap<String, Extent>,
+    /// Last use -> block id, oldest first
+    order: BTreeMap<u64, String>,
+    /// Extents given up by blocks dropped, for the next ones to reuse
+    free: Vec<(u64, u64)>,
+    end: u64,
+    bytes: u64,
+    tick: u64,
+}
+
+impl CacheSpill {
+    /// Keeps up to `limit` bytes of blocks in a scratch file in `dir`
+    pub fn new(dir: &Path, limit: u64) -> Self {
+        Self { dir: dir.to_path_buf(), limit, inner: Mutex::new(Spilled::default()) }
+    }
+}
+
+impl Spilled {
+    /// Drops the block used longest ago, keeping its room for reuse
+    fn drop_oldest(&mut self) -> bool {
+        let Some((_, oldest)) = self.order.pop_first() else { return false };
+        if let Some(extent) = self.blocks.remove(&oldest) {
+            self.bytes -= extent.len as u64;
+            self.free.push((extent.offset, extent.room));
+        }
+        true
+    }
+
+    /// Where `room` bytes fit: the smallest free extent large enough, or the end
+    fn place(&mut self, room: u64) -> (u64, u64) {
+        let best = self.free.iter().enumerate()
+            .filter(|(_, (_, have))| *have >= room)
+            .min_by_key(|(_, (_, have))| *have)
+            .map(|(i, _)| i);
+        match best {
+            Some(i) => self.free.swap_remove(i),
+            None => {
+                let offset = self.end;
+                self.end += room;
+                (offset, room)
+            }
+        }
+    }
+
+    fn store(&mut self, dir: &Path, limit: u64, block_id: &str, data: &[u8]) -> io::Result<()> {
+        while self.bytes + data.len() as u64 > limit && self.drop_oldest() {}
+        if self.scratch.is_none() {
+            self.scratch = Some(Scratch::create(dir)?);
+        }
+        let (offset, room) = self.place((NONCE + data.len() + TAG) as u64);
+        let Some(scratch) = &self.scratch else { return Ok(()) };
+        let sealed = match scratch.seal_at(data, offset) {
+            Ok(sealed) => sealed,
+            Err(e) => {
+                self.free.push((offset, room));
+                return Err(e);
+            }
+        };
+        self.tick += 1;
+        self.blocks.insert(block_id.to_string(), Extent { offset, room, len: data.len(), sealed, used: self.tick });
+        self.order.insert(self.tick, block_id.to_string());
+        self.bytes += data.len() as u64;
+        Ok(())
+    }
+}
+
+impl Overflow for CacheSpill {
+    fn put(&self, block_id: &str, data: &Plaintext) {
+        let mut spilled = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        // Brought back from here and pushed out again: the copy here still holds
+        if spilled.failed || data.len() as u64 > self.limit || spilled.blocks.contains_key(block_id) {
+            return;
+        }
+        if let Err(e) = spilled.store(&self.dir, self.limit, block_id, data) {
+            log::warn!("Block cache no longer spills to {}: {}", self.dir.display(), e);
+            spilled.failed = true;
+        }
+    }
+
+    fn get(&self, block_id: &str) -> Option<Plaintext> {
+        let mut spilled = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        spilled.tick += 1;
+        let tick = spilled.tick;
+        let extent = spilled.blocks.get_mut(block_id)?;
+        let (old, offset, sealed) = (std::mem::replace(&mut extent.used, tick), extent.offset, extent.sealed);
+        spilled.order.remove(&old);
+        spilled.order.insert(tick, block_id.to_string());
+        match spilled.scratch.as_ref()?.open_at(offset, sealed) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                log::warn!("Spilled block unreadable: {}", e);
+                None
+            }
+        }
+    }
+
+    fn usage(&self) -> (usize, u64, u64) {
+        let spilled = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        (spilled.blocks.len(), spilled.bytes, self.limit)
+    }
+}
+
+/// The content of an open file
+pub struct FileBuffer {
+    len: u64,
+    /// What the file held when opened, for pages not written to since
+    base: Option<Stored>,
+    /// How much of `base` still counts; a truncate cuts it short
+    base_len: u64,
+    /// Pages in memory. They may be shorter than `PAGE` (the last one, or
+    /// after a truncate); what is missing up to `len` reads as zeros.
+    pages: HashMap<u64, Resident>,
+    /// Pages in memory at most
+    limit: usize,
+    /// Folder the scratch file goes in
+    dir: PathBuf,
+    scratch: Option<Scratch>,
+    clock: u64,
+}
+
+impl FileBuffer {
+    /// An empty file; at most `limit` bytes of it stay in memory
+    pub fn new(limit: usize, dir: &Path) -> Self {
+        Self {
+            len: 0,
+            base: None,
+            base_len: 0,
+            pages: HashMap::new(),
+            limit: (limit / PAGE).max(1),
+            dir: dir.to_path_buf(),
+            scratch: None,
+            clock: 0,
+        }
+    }
+
+    /// A file holding what `stored` does, read from the vault as needed
+    pub fn with_base(limit: usize, dir: &Path, stored: Stored) -> Self {
+        let len = stored.len();
+        Self { len, base_len: len, base: Some(stored), ..Self::new(limit, dir) }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    fn page_start(page: u64) -> u64 {
+        page * PAGE as u64
+    }
+
+    /// Copies out `size` bytes from `offset`, or fewer at the end
+    pub fn read_at(&mut self, offset: u64, size: usize) -> io::Result<Plaintext> {
+        let end = self.len.min(offset.saturating_add(size as u64));
+        let mut out = plaintext::with_capacity(end.saturating_sub(offset) as usize);
+        let mut pos = offset;
+        while pos < end {
+            let page = pos / PAGE as u64;
+            let from = (pos - Self::page_start(page)) as usize;
+            let to = (end - Self::page_start(page)).min(PAGE as u64) as usize;
+            let spilled;
+            let data: &[u8] = match self.pages.get(&page) {
+                Some(resident) => &resident.data,
+                None => match self.scratch.as_ref().map(|s| s.load(page)).transpose()?.flatten() {
+                    Some(data) => {
+                        spilled = data;
+                        &spilled
+                    }
+                    // Never written to: as stored
+                    None => {
+                        self.read_base(pos, Self::page_start(page) + to as u64, &mut out)?;
+                        pos = Self::page_start(page) + to as u64;
+                        continue;
+                    }
+                },
+            };
+            let have = data.len().clamp(from, to);
+            out.extend_from_slice(&data[from..have]);
+            let filled = out.len();
+            out.resize(filled + (to - have), 0);
+            pos = Self::page_start(page) + to as u64;
+        }
+        Ok(out)
+    }
+
+    /// Appends `start..end` of what was stored to `out`, zeros past `base_len`
+    fn read_base(&mut self, start: u64, end: u64, out: &mut Plaintext) -> io::Result<()> {
+        let filled = out.len();
+        plaintext::resize(out, filled + (end - start) as usize);
+        let stop = end.min(self.base_len);
+        let Some(base) = &mut self.base else { return Ok(()) };
+        let mut pos = start;
+        while pos < stop {
+            let at = filled + (pos - start) as usize;
+            let n = base.read_at(pos, &mut out[at..filled + (stop - start) as usize])?;
+            if n == 0 {
+                break;
+            }
+            pos += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at `offset`, zero-filling any gap past the end
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut pos = offset;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let page = pos / PAGE as u64;
+            let from = (pos - Self::page_start(page)) as usize;
+            let n = rest.len().min(PAGE - from);
+            let resident = self.resident(page)?;
+            plaintext::write_at(&mut resident.data, from, &rest[..n]);
+            rest = &rest[n..];
+            pos += n as u64;
+            self.evict()?;
+        }
+        self.len = self.len.max(pos);
+        Ok(())
+    }
+
+    /// Cuts the file short or extends it with zeros
+    pub fn set_len(&mut self, len: u64) -> io::Result<()> {
+        if len < self.len {
+            self.base_len = self.base_len.min(len);
+            let last = len.div_ceil(PAGE as u64);
+            self.pages.retain(|&page, _| page < last);
+            if let Some(scratch) = &mut self.scratch {
+                scratch.sealed.retain(|&page, _| page < last);
+            }
+            // The page the end now falls in keeps nothing past it
+            let partial = (len % PAGE as u64) as usize;
+            if partial != 0 {
+                let resident = self.resident(len / PAGE as u64)?;
+                if resident.data.len() > partial {
+                    plaintext::resize(&mut resident.data, partial);
+                }
+            }
+        }
+        self.len = len;
+        self.evict()
+    }
+
+    /// Page `page` in memory, brought back from the scratch file or read
+    /// from the vault if need be
+    fn resident(&mut self, page: u64) -> io::Result<&mut Resident> {
+        self.clock += 1;
+        if !self.pages.contains_key(&page) {
+            let spilled = match &mut self.scratch {
+                Some(scratch) => {
+                    let data = scratch.load(page)?;
+                    scratch.sealed.remove(&page);
+                    data
+                }
+                None => None,
+            };
+            let data = match spilled {
+                Some(data) => data,
+                None => {
+                    let start = Self::page_start(page);
+                    let mut data = plaintext::with_capacity(0);
+                    if start < self.base_len {
+                        self.read_base(start, (start + PAGE as u64).min(self.base_len), &mut data)?;
+                    }
+                    data
+                }
+            };
+            self.pages.insert(page, Resident { data, used: self.clock });
+        }
+        let resident = self.pages.get_mut(&page).expect("just inserted");
+        resident.used = self.clock;
+        Ok(resident)
+    }
+
+    /// Spills the least recently used pages until the limit is kept
+    fn evict(&mut self) -> io::Result<()> {
+        while self.pages.len() > self.limit {
+            let Some(page) = self.pages.iter().min_by_key(|(_, r)| r.used).map(|(page, _)| *page) else { break };
+            if self.scratch.is_none() {
+                self.scratch = Some(Scratch::create(&self.dir)?);
+            }
+            let resident = self.pages.remove(&page).expect("just found");
+            if let Some(scratch) = &mut self.scratch {
+                scratch.store(page, &resident.data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies what is still only in the vault into the buffer (memory, or
+    /// the scratch file past the limit), so it reads the same once the
+    /// blocks it came from may be gone, e.g. for a file deleted while open
+    pub fn detach(&mut self) -> io::Result<()> {
+        if self.base.is_none() {
+            return Ok(());
+        }
+        for page in 0..self.base_len.div_ceil(PAGE as u64) {
+            let spilled = self.scratch.as_ref().is_some_and(|s| s.sealed.contains_key(&page));
+            if !self.pages.contains_key(&page) && !spilled {
+                self.resident(page)?;
+                self.evict()?;
+            }
+        }
+        self.base = None;
+        self.base_len = 0;
+        Ok(())
+    }
+
+    /// Reads the whole file from the start, a page at a time
+    pub fn reader(&mut self) -> Reader<'_> {
+        Reader { buffer: self, pos: 0 }
+    }
+}
+
+/// Sizes only: the content stays out of logs
+impl fmt::Debug for FileBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileBuffer")
+            .field("len", &self.len)
+            .field("resident", &self.pages.len())
+            .field("spilled", &self.scratch.as_ref().map_or(0, |s| s.sealed.len()))
+            .finish()
+    }
+}
+
+pub struct Reader<'a> {
+    buffer: &'a mut FileBuffer,
+    pos: u64,
+}
+
+impl Read for Reader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let to_page_end = PAGE - (self.pos % PAGE as u64) as usize;
+        let data = self.buffer.read_at(self.pos, buf.len().min(to_page_end))?;
+        buf[..data.len()].copy_from_slice(&data);
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+}
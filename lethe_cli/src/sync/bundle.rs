@@ -0,0 +1,310 @@
+//! `lethe bundle`: vault updates carried between copies on a USB stick,
+//! for machines that never see each other on a network.
+//!
+//! A bundle holds the whole index (replicas are small), the keyring and
+//! the blocks the other copy may lack. `--since <revision>` names an
+//! earlier bundle from this copy, and only blocks of entries changed
+//! after it go in. Applying works like a pull: blocks are verified as
+//! they land, the indexes are merged and conflicts are kept as copies.
+//!
+//! Layout: `MAGIC`, then frames of u32 length + Nonce + Data, sealed with
+//! a key derived from the vault key, so only a holder of the vault key
+//! can make one and any change to it fails to open. The first frame is
+//! the header, then the index as stored on disk (so once devices are
+//! enabled it opens only for enrolled copies), the keyring (empty without
+//! devices), and one frame per block, each led by its sequence number.
+//!
+//! `bundles.bin`, next to the index replicas and sealed like them (see
+//! `super::sealed`), remembers the clock at each bundle this copy made (for `--since`) and the newest revision applied
+//! from every other copy. A bundle no newer than that is refused, so an
+//! old stick can't roll anything back.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use lethe_core::crypto::{CryptoEngine, MasterKey};
+use lethe_core::device;
+use lethe_core::index::{Clock, Dot, IndexManager, VaultIndex};
+use lethe_core::keyring::{self, Keyring, KEYRING_FILE};
+use lethe_core::merge;
+use lethe_core::storage::BlockManager;
+
+use super::sealed;
+
+const MAGIC: &[u8; 8] = b"LETHEUPD";
+const VERSION: u32 = 1;
+const LEDGER_FILE: &str = "bundles.bin";
+/// Frames are bounded before they are read
+const MAX_FRAME: u32 = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Header {
+    pub version: u32,
+    /// Random, for telling bundles apart
+    pub id: String,
+    /// Device id and host name of the copy that made it
+    pub source: String,
+    pub source_name: String,
+    /// That copy's index revision when it made it
+    pub revision: u64,
+    /// The earlier bundle it picks up from, if any
+    pub since: Option<u64>,
+    pub created: u64,
+    pub blocks: u32,
+}
+
+/// What this copy remembers about bundles
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Ledger {
+    /// Revision -> clock, for every bundle made here
+    pub created: BTreeMap<u64, Clock>,
+    /// Device id -> newest revision applied from it
+    pub applied: BTreeMap<String, u64>,
+}
+
+impl Ledger {
+    pub fn load(vault: &Path, key: &MasterKey) -> Result<Self> {
+        Ok(sealed::load(vault, LEDGER_FILE, key)?.unwrap_or_default())
+    }
+
+    fn save(&self, vault: &Path, key: &MasterKey) -> Result<()> {
+        sealed::save(vault, LEDGER_FILE, key, self)
+    }
+}
+
+fn bundle_key(key: &MasterKey) -> MasterKey {
+    CryptoEngine::derive_subkey(key, b"lethe update bundle")
+}
+
+fn write_frame<W: Write>(out: &mut W, plain: &[u8], key: &MasterKey) -> Result<()> {
+    let (ciphertext, nonce) = CryptoEngine::encrypt(plain, key)?;
+    let len = u32::try_from(nonce.len() + ciphertext.len()).context("Frame too large for a bundle")?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(&nonce)?;
+    out.write_all(&ciphertext)?;
+    Ok(())
+}
+
+fn read_frame<R: Read>(input: &mut R, key: &MasterKey) -> Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    input.read_exact(&mut len).context("Bundle is truncated")?;
+    let len = u32::from_le_bytes(len);
+    if !(24..=MAX_FRAME).contains(&len) {
+        anyhow::bail!("Bundle is damaged (bad frame length)");
+    }
+    let mut frame = vec![0u8; len as usize];
+    input.read_exact(&mut frame).context("Bundle is truncated")?;
+    let (nonce, ciphertext) = frame.split_at(24);
+    CryptoEngine::decrypt(ciphertext, nonce, key).context("Bundle is damaged or was made for another vault")
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn seen(clock: &Clock, dot: &Option<Dot>) -> bool {
+    match dot {
+        Some(dot) => clock.get(&dot.device).is_some_and(|&c| c >= dot.counter),
+        None => true,
+    }
+}
+
+/// Blocks of every entry, snapshots included, that `since` hasn't seen;
+/// all of them without `since`
+fn changed_blocks<'a>(index: &'a VaultIndex, since: Option<&Clock>) -> Vec<&'a str> {
+    let entries = index.files.values().chain(index.snapshots.values().flat_map(|s| s.files.values()));
+    let mut blocks: Vec<&str> = entries
+        .filter(|e| since.is_none_or(|clock| !seen(clock, &e.dot)))
+        .flat_map(|e| e.blocks.iter().map(String::as_str))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    blocks.sort();
+    blocks
+}
+
+/// Writes a bundle of this copy to `out` and returns its header
+pub fn create(vault_path: &Path, key: &MasterKey, since: Option<u64>, out: &Path) -> Result<Header> {
+    let mut ledger = Ledger::load(vault_path, key)?;
+    let since_clock = match since {
+        Some(rev) => Some(ledger.created.get(&rev).with_context(|| {
+            format!("No bundle was made here at revision {}. `lethe bundle list` shows the ones that were.", rev)
+        })?),
+        None => None,
+    };
+    let index_mgr = IndexManager::load(vault_path.to_path_buf(), key)?;
+    let storage = BlockManager::new(vault_path)?;
+    let blocks = changed_blocks(&index_mgr.data, since_clock);
+
+    let mut id = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut id);
+    let vault_id = device::id(vault_path);
+    let header = Header {
+        version: VERSION,
+        id: id.iter().map(|b| format!("{:02x}", b)).collect(),
+        source: vault_id,
+        source_name: device::name(),
+        revision: index_mgr.data.revision,
+        since,
+        created: now(),
+        blocks: blocks.len() as u32,
+    };
+
+    // 1. Header, index, keyring
+    let bundle_key = bundle_key(key);
+    let tmp = out.with_extension("part");
+    let mut writer = BufWriter::new(File::create(&tmp).context("Failed to create bundle")?);
+    writer.write_all(MAGIC)?;
+    write_frame(&mut writer, &serde_cbor::to_vec(&header)?, &bundle_key)?;
+    write_frame(&mut writer, &index_mgr.seal(key)?, &bundle_key)?;
+    let ring = vault_path.join(KEYRING_FILE);
+    let ring = if ring.exists() { fs::read(&ring).context("Failed to read keyring")? } else { Vec::new() };
+    write_frame(&mut writer, &ring, &bundle_key)?;
+
+    // 2. Blocks as they sit on disk
+    for (seq, id) in blocks.iter().enumerate() {
+        let stored = fs::read(storage.block_path(id)?).with_context(|| format!("Block {} is missing", id))?;
+        let mut frame = (seq as u32).to_le_bytes().to_vec();
+        frame.push(id.len() as u8);
+        frame.extend_from_slice(id.as_bytes());
+        frame.extend(stored);
+        write_frame(&mut writer, &frame, &bundle_key)?;
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp, out).context("Failed to write bundle")?;
+
+    ledger.created.insert(header.revision, index_mgr.data.clock.clone());
+    ledger.save(vault_path, key)?;
+    super::audit_sync(vault_path, key, "bundle create", &out.display().to_string());
+    Ok(header)
+}
+
+/// The index in a bundle, opened with whichever of our keys seals it
+fn open_index(sealed: &[u8], vault_path: &Path, keyring: Option<&Keyring>, key: &MasterKey) -> Result<(VaultIndex, Option<(Keyring, keyring::IndexKey)>)> {
+    let newer = super::newer_keyring(vault_path, keyring, key)?;
+    let local;
+    let index_key = match &newer {
+        Some((_, index_key)) => Some(index_key),
+        None => {
+            local = keyring::index_key(vault_path, key)?;
+            local.as_ref()
+        }
+    };
+    let keys: Vec<&MasterKey> = match index_key {
+        Some(k) => std::iter::once(&k.current).chain(&k.older).chain(std::iter::once(key)).collect(),
+        None => vec![key],
+    };
+    let index = keys.iter().find_map(|k| IndexManager::open_sealed(sealed, k).ok())
+        .context("The bundle's index doesn't open here. Is this copy enrolled?")?;
+    Ok((index, newer))
+}
+
+/// Describes a bundle without applying it
+pub fn inspect(bundle: &Path, key: &MasterKey) -> Result<Header> {
+    let mut reader = BufReader::new(File::open(bundle).with_context(|| format!("Failed to open {:?}", bundle))?);
+    read_header(&mut reader, &bundle_key(key))
+}
+
+fn read_header<R: Read>(reader: &mut R, bundle_key: &MasterKey) -> Result<Header> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic).context("Not a Lethe bundle")?;
+    if &magic != MAGIC {
+        anyhow::bail!("Not a Lethe bundle");
+    }
+    let header: Header = serde_cbor::from_slice(&read_frame(reader, bundle_key)?).context("Bundle header is corrupted")?;
+    if header.version > VERSION {
+        anyhow::bail!("Bundle format {} is newer than this build understands", header.version);
+    }
+    Ok(header)
+}
+
+/// Merges a bundle into the vault. Nothing changes unless every block
+/// the merged index needs is there in the end.
+pub fn apply(bundle: &Path, vault_path: &Path, key: &MasterKey) -> Result<Header> {
+    let bundle_key = bundle_key(key);
+    let mut reader = BufReader::new(File::open(bundle).with_context(|| format!("Failed to open {:?}", bundle))?);
+    let header = read_header(&mut reader, &bundle_key)?;
+
+    let mut ledger = Ledger::load(vault_path, key)?;
+    if header.source == device::id(vault_path) {
+        anyhow::bail!("This bundle was made by this copy.");
+    }
+    if let Some(&applied) = ledger.applied.get(&header.source) {
+        if header.revision <= applied {
+            anyhow::bail!(
+                "Already applied revision {} from {}; this bundle is revision {}. Refusing to replay it.",
+                applied, header.source_name, header.revision
+            );
+        }
+    }
+
+    // 1. Index and keyring
+    let sealed_index = read_frame(&mut reader, &bundle_key)?;
+    let ring = read_frame(&mut reader, &bundle_key)?;
+    let ring = if ring.is_empty() { None } else { Some(Keyring::open_sealed(&ring, key)?) };
+    let (theirs, newer) = open_index(&sealed_index, vault_path, ring.as_ref(), key)?;
+    let (mut index_mgr, newer_keyring) = super::load_index(vault_path, newer, key)?;
+    if let Some(ring) = &newer_keyring {
+        super::adopt_keyring(&index_mgr, ring, key)?;
+    }
+    let merged = merge::merge(&index_mgr.data, &theirs);
+
+    // 2. Blocks, verified as they land
+    let storage = BlockManager::new(vault_path)?;
+    let mut received = 0;
+    for seq in 0..header.blocks {
+        let frame = read_frame(&mut reader, &bundle_key)?;
+        let (at, rest) = frame.split_at_checked(4).context("Bundle is damaged (short block frame)")?;
+        if u32::from_le_bytes(at.try_into()?) != seq {
+            anyhow::bail!("Bundle is damaged (blocks out of order)");
+        }
+        let (&len, rest) = rest.split_first().context("Bundle is damaged (short block frame)")?;
+        let (id, stored) = rest.split_at_checked(len as usize).context("Bundle is damaged (short block frame)")?;
+        let id = std::str::from_utf8(id).context("Bundle is damaged (bad block id)")?;
+        if storage.has_block(id) {
+            continue;
+        }
+        fs::write(storage.partial_path(id)?, stored).context("Failed to store block")?;
+        storage.commit_partial(id, key)?;
+        received += 1;
+    }
+    println!("   Received {} new block(s) of {}", received, header.blocks);
+
+    let missing = merged.index.referenced_blocks().into_iter().filter(|id| !storage.has_block(id)).count();
+    if missing > 0 {
+        anyhow::bail!(
+            "This copy lacks {} block(s) the bundle's index needs; the index was left alone. \
+             Make the bundle again with an older --since, or without one.",
+            missing
+        );
+    }
+
+    // 3. The index
+    if merged.differs_from(&index_mgr.data) {
+        super::report_conflicts(&merged.conflicts);
+        index_mgr.replace(merged.index, key)?;
+    } else {
+        println!("Already up to date.");
+    }
+    ledger.applied.insert(header.source.clone(), header.revision);
+    ledger.save(vault_path, key)?;
+    super::audit_sync(vault_path, key, "bundle apply", &format!("{} (revision {})", header.source_name, header.revision));
+    Ok(header)
+}
+
+/// Header fields worth showing, in one line
+pub fn describe(header: &Header) -> String {
+    let created = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(header.created));
+    let since = header.since.map(|rev| format!(", changes since revision {}", rev)).unwrap_or_default();
+    format!(
+        "revision {} from {}, made {}, {} block(s){}",
+        header.revision, header.source_name, created, header.blocks, since
+    )
+}
@@ -0,0 +1,335 @@
+//! `--bwlimit` for push, pull and sync-peer.
+//!
+//! Either one rate in bytes per second (`2M`, with an optional K, M or G
+//! suffix, binary units) or a timetable of `HH:MM,rate` entries in local
+//! time, each holding until the next one:
+//!
+//! ```text
+//! --bwlimit "08:00,512K 19:00,off"     slow during the day, unlimited at night
+//! --bwlimit "09:00,0 18:00,off"        pause during work hours
+//! ```
+//!
+//! `off` lifts the limit and `0` pauses transfers until the next entry.
+//! The limit applies to blocks, in each direction separately.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Bytes moved between two checks of the limit
+const STEP: usize = 64 * 1024;
+/// How far a transfer may get ahead after sitting idle
+const BURST: Duration = Duration::from_secs(1);
+/// A pause is re-checked at least this often (the clock may jump)
+const PAUSE_POLL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub struct BwLimit {
+    /// By minute of the day, ascending; never empty
+    slots: Vec<(u32, Option<u64>)>,
+}
+
+impl BwLimit {
+    pub fn unlimited() -> Self {
+        Self { slots: vec![(0, None)] }
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.slots.iter().all(|(_, rate)| rate.is_none())
+    }
+
+    /// Bytes per second in force at `minute`; None when unlimited. Before
+    /// the first entry of the day, yesterday's last one still holds.
+    fn rate_at(&self, minute: u32) -> Option<u64> {
+        self.slots.iter().rev()
+            .find(|(start, _)| *start <= minute)
+            .or(self.slots.last())
+            .and_then(|(_, rate)| *rate)
+    }
+
+    pub fn rate_now(&self) -> Option<u64> {
+        self.rate_at(local_minute())
+    }
+
+    /// Time until the next entry starts, if there is more than one
+    fn until_next(&self, minute: u32) -> Option<Duration> {
+        if self.slots.len() < 2 {
+            return None;
+        }
+        let next = self.slots.iter()
+            .map(|(start, _)| *start)
+            .find(|start| *start > minute)
+            .unwrap_or(self.slots[0].0 + 24 * 60);
+        Some(Duration::from_secs(u64::from(next - minute) * 60))
+    }
+
+    /// `HH:MM` of the entry that ends the current one
+    fn next_start(&self, minute: u32) -> Option<String> {
+        let next = self.slots.iter().map(|(start, _)| *start).find(|start| *start > minute)
+            .or_else(|| self.slots.first().map(|(start, _)| *start))?;
+        Some(format!("{:02}:{:02}", next / 60, next % 60))
+    }
+}
+
+impl FromStr for BwLimit {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let spec = spec.trim();
+        if !spec.contains(',') {
+            return match parse_rate(spec)? {
+                Some(0) => Err("A --bwlimit of 0 never finishes; pause with a timetable instead".to_string()),
+                rate => Ok(Self { slots: vec![(0, rate)] }),
+            };
+        }
+
+        let mut slots = Vec::new();
+        for entry in spec.split_whitespace() {
+            let (time, rate) = entry.split_once(',')
+                .ok_or_else(|| format!("Expected HH:MM,rate, got {:?}", entry))?;
+            slots.push((parse_time(time)?, parse_rate(rate)?));
+        }
+        slots.sort_by_key(|(start, _)| *start);
+        if slots.windows(2).any(|w| w[0].0 == w[1].0) {
+            return Err("Two timetable entries start at the same time".to_string());
+        }
+        Ok(Self { slots })
+    }
+}
+
+impl fmt::Display for BwLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rate = |r: &Option<u64>| match r {
+            None => "off".to_string(),
+            Some(0) => "paused".to_string(),
+            Some(r) => format!("{}/s", humansize::format_size(*r, humansize::BINARY)),
+        };
+        match self.slots.as_slice() {
+            [(_, r)] => write!(f, "{}", rate(r)),
+            slots => {
+                let entries: Vec<String> = slots.iter()
+                    .map(|(start, r)| format!("{:02}:{:02} {}", start / 60, start % 60, rate(r)))
+                    .collect();
+                write!(f, "{}", entries.join(", "))
+            }
+        }
+    }
+}
+
+/// `512K`, `2M`, `1.5G`, `off` (None) or a plain byte count
+fn parse_rate(s: &str) -> Result<Option<u64>, String> {
+    if s.eq_ignore_ascii_case("off") {
+        return Ok(None);
+    }
+    let (number, unit) = match s.char_indices().find(|(_, c)| c.is_ascii_alphabetic()) {
+        Some((at, _)) => s.split_at(at),
+        None => (s, ""),
+    };
+    let scale: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => return Err(format!("Unknown unit in {:?} (use K, M or G)", s)),
+    };
+    let value: f64 = number.trim().parse()
+        .map_err(|_| format!("Invalid rate {:?} (e.g. 512K, 2M, off)", s))?;
+    if !value.is_finite() || value < 0.0 {
+        return Err(format!("Invalid rate {:?}", s));
+    }
+    Ok(Some((value * scale as f64) as u64))
+}
+
+/// `HH:MM` -> minute of the day
+fn parse_time(s: &str) -> Result<u32, String> {
+    let invalid = || format!("Invalid time {:?} (use HH:MM)", s);
+    let (h, m) = s.split_once(':').ok_or_else(invalid)?;
+    let (h, m): (u32, u32) = (h.parse().map_err(|_| invalid())?, m.parse().map_err(|_| invalid())?);
+    if h > 23 || m > 59 {
+        return Err(invalid());
+    }
+    Ok(h * 60 + m)
+}
+
+// --- Pacing ---
+
+/// Keeps one stream of transfers under a `BwLimit`
+pub struct Throttle {
+    limit: BwLimit,
+    rate: Option<u64>,
+    since: Instant,
+    moved: u64,
+    paused_notice: bool,
+}
+
+impl Throttle {
+    pub fn new(limit: BwLimit) -> Self {
+        Self { limit, rate: None, since: Instant::now(), moved: 0, paused_notice: false }
+    }
+
+    pub fn limit(&self) -> &BwLimit {
+        &self.limit
+    }
+
+    /// Zero once `len` more bytes may go (and counts them), otherwise how
+    /// long to wait before asking again
+    fn wait_for(&mut self, len: usize) -> Duration {
+        let minute = local_minute();
+        let rate = self.limit.rate_at(minute);
+        if rate != self.rate {
+            self.rate = rate;
+            self.since = Instant::now();
+            self.moved = 0;
+        }
+
+        match rate {
+            None => Duration::ZERO,
+            Some(0) => {
+                if !self.paused_notice {
+                    self.paused_notice = true;
+                    let until = self.limit.next_start(minute).unwrap_or_default();
+                    println!("   Paused by --bwlimit until {}", until);
+                }
+                let until = self.limit.until_next(minute).unwrap_or(PAUSE_POLL);
+                until.min(PAUSE_POLL).max(Duration::from_secs(1))
+            }
+            Some(rate) => {
+                self.paused_notice = false;
+                let due = Duration::from_secs_f64(self.moved as f64 / rate as f64);
+                let elapsed = self.since.elapsed();
+                if due > elapsed {
+                    return due - elapsed;
+                }
+                if elapsed > due + BURST {
+                    self.since = Instant::now();
+                    self.moved = 0;
+                }
+                self.moved += len as u64;
+                Duration::ZERO
+            }
+        }
+    }
+
+    /// Blocks until `len` more bytes may go
+    pub fn pace(&mut self, len: usize) {
+        loop {
+            let wait = self.wait_for(len);
+            if wait.is_zero() {
+                return;
+            }
+            std::thread::sleep(wait);
+        }
+    }
+
+    pub async fn pace_async(&mut self, len: usize) {
+        loop {
+            let wait = self.wait_for(len);
+            if wait.is_zero() {
+                return;
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// `fs::copy`, paced
+pub fn copy_file(from: &Path, to: &Path, throttle: &mut Throttle) -> io::Result<u64> {
+    if throttle.limit.is_unlimited() {
+        return std::fs::copy(from, to);
+    }
+    let mut reader = File::open(from)?;
+    let mut writer = File::create(to)?;
+    let mut buf = vec![0u8; STEP];
+    let mut copied = 0;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        throttle.pace(n);
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
+    }
+    writer.sync_all()?;
+    Ok(copied)
+}
+
+/// `tokio::io::copy` of at most `len` bytes, paced
+pub async fn copy_async<R, W>(reader: &mut R, writer: &mut W, len: u64, throttle: &mut Throttle) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut limited = reader.take(len);
+    if throttle.limit.is_unlimited() {
+        return tokio::io::copy(&mut limited, writer).await;
+    }
+    let mut buf = vec![0u8; STEP];
+    let mut copied = 0;
+    loop {
+        let n = limited.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        throttle.pace_async(n).await;
+        writer.write_all(&buf[..n]).await?;
+        copied += n as u64;
+    }
+    writer.flush().await?;
+    Ok(copied)
+}
+
+// --- Local time ---
+
+#[cfg_attr(windows, allow(dead_code))]
+fn utc_minute() -> u32 {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    ((secs / 60) % (24 * 60)) as u32
+}
+
+#[cfg(unix)]
+fn local_minute() -> u32 {
+    // SAFETY: time accepts a null pointer; localtime_r gets valid pointers
+    // and is thread-safe
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return utc_minute();
+    }
+    (tm.tm_hour * 60 + tm.tm_min) as u32
+}
+
+#[cfg(windows)]
+fn local_minute() -> u32 {
+    #[repr(C)]
+    struct SystemTimeParts {
+        year: u16,
+        month: u16,
+        day_of_week: u16,
+        day: u16,
+        hour: u16,
+        minute: u16,
+        second: u16,
+        milliseconds: u16,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetLocalTime(time: *mut SystemTimeParts);
+    }
+
+    // SAFETY: SystemTimeParts matches SYSTEMTIME and the pointer is valid
+    let mut parts: SystemTimeParts = unsafe { std::mem::zeroed() };
+    unsafe { GetLocalTime(&mut parts) };
+    u32::from(parts.hour) * 60 + u32::from(parts.minute)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn local_minute() -> u32 {
+    utc_minute()
+}
@@ -0,0 +1,358 @@
+//! Remotes behind HTTP: S3 buckets and WebDAV shares, reached with the
+//! system `curl` the way `ssh://` remotes use `ssh` and `sftp`.
+//!
+//! - `s3:bucket/prefix` signs requests with `AWS_ACCESS_KEY_ID` and
+//!   `AWS_SECRET_ACCESS_KEY` (and `AWS_SESSION_TOKEN` if set), in
+//!   `AWS_REGION` (default us-east-1). `AWS_ENDPOINT_URL` points it at
+//!   another S3-compatible service, addressed path-style.
+//! - `webdav:https://host/dav` takes its login from `~/.netrc`.
+//!
+//! Credentials go to curl on stdin, never on its command line. Each batch
+//! is a single curl run; S3 writes are atomic on their own, WebDAV ones go
+//! under a temporary name and are moved into place like everywhere else.
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+
+use super::bwlimit::Throttle;
+use super::remote::Remote;
+
+/// Transfers per curl run
+const BATCH: usize = 64;
+
+/// One request in a curl config
+struct Request {
+    method: Option<&'static str>,
+    url: String,
+    headers: Vec<String>,
+    upload: Option<PathBuf>,
+    output: Option<PathBuf>,
+}
+
+impl Request {
+    fn new(url: String) -> Self {
+        Self { method: None, url, headers: Vec::new(), upload: None, output: None }
+    }
+}
+
+/// Quotes a value for a curl config line
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Escapes everything but unreserved characters and, if `slash`, '/'
+fn encode(s: &str, slash: bool) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            b'/' if slash => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The text of every `<tag>` (with any namespace prefix) in `xml`
+fn elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(at) = rest.find('<') {
+        rest = &rest[at + 1..];
+        let Some(end) = rest.find('>') else { break };
+        let name = &rest[..end];
+        let local = name.rsplit(':').next().unwrap_or(name);
+        rest = &rest[end + 1..];
+        if local == tag {
+            let close = rest.find("</").unwrap_or(rest.len());
+            found.push(&rest[..close]);
+        }
+    }
+    found
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+/// Runs curl over `requests`, with `common` (auth, mostly) for each; stops
+/// at the first that fails. Returns what went to stdout.
+fn curl(requests: &[Request], common: &[String], throttle: &RefCell<Throttle>, fail: bool) -> Result<Vec<u8>> {
+    // Waits out a pause, then hands the current rate to curl
+    let mut throttle = throttle.borrow_mut();
+    throttle.pace(0);
+    let rate = throttle.limit().rate_now();
+
+    let mut config = String::new();
+    for (i, request) in requests.iter().enumerate() {
+        if i > 0 {
+            config.push_str("next\n");
+        }
+        config.push_str("silent\nshow-error\n");
+        if fail {
+            config.push_str("fail\n");
+        }
+        for line in common {
+            config.push_str(line);
+            config.push('\n');
+        }
+        if let Some(rate) = rate {
+            config.push_str(&format!("limit-rate = {}\n", rate.max(1)));
+        }
+        if let Some(method) = request.method {
+            config.push_str(&format!("request = {}\n", method));
+        }
+        for header in &request.headers {
+            config.push_str(&format!("header = {}\n", quote(header)));
+        }
+        if let Some(local) = &request.upload {
+            config.push_str(&format!("upload-file = {}\n", quote(&local.to_string_lossy())));
+        }
+        if let Some(local) = &request.output {
+            config.push_str(&format!("output = {}\n", quote(&local.to_string_lossy())));
+        }
+        config.push_str(&format!("url = {}\n", quote(&request.url)));
+    }
+
+    let mut child = Command::new("curl")
+        .args(["--fail-early", "-K", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to run curl (is it installed?)")?;
+    child.stdin.take().context("curl has no stdin")?.write_all(config.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        anyhow::bail!("curl failed ({})", output.status);
+    }
+    Ok(output.stdout)
+}
+
+// --- S3 ---
+
+pub struct S3 {
+    bucket: String,
+    /// Key prefix of the vault folder, without slashes at either end
+    prefix: String,
+    throttle: RefCell<Throttle>,
+}
+
+impl S3 {
+    pub fn parse(rest: &str, throttle: RefCell<Throttle>) -> Result<Self> {
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        let prefix = prefix.trim_matches('/').to_string();
+        if bucket.is_empty() || prefix.is_empty() {
+            anyhow::bail!("Expected s3:bucket/prefix, got s3:{}", rest);
+        }
+        Ok(Self { bucket: bucket.to_string(), prefix, throttle })
+    }
+
+    fn region() -> String {
+        std::env::var("AWS_REGION").or_else(|_| std::env::var("AWS_DEFAULT_REGION")).unwrap_or_else(|_| "us-east-1".to_string())
+    }
+
+    /// URL of the bucket, ending in '/'
+    fn base(&self) -> String {
+        match std::env::var("AWS_ENDPOINT_URL") {
+            Ok(endpoint) => format!("{}/{}/", endpoint.trim_end_matches('/'), self.bucket),
+            Err(_) => format!("https://{}.s3.{}.amazonaws.com/", self.bucket, Self::region()),
+        }
+    }
+
+    fn url(&self, name: &str) -> String {
+        format!("{}{}", self.base(), encode(&format!("{}/{}", self.prefix, name), true))
+    }
+
+    fn auth(&self) -> Result<Vec<String>> {
+        let id = std::env::var("AWS_ACCESS_KEY_ID").context("Set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY for s3: remotes")?;
+        let secret = std::env::var("AWS_SECRET_ACCESS_KEY").context("Set AWS_SECRET_ACCESS_KEY for s3: remotes")?;
+        let mut lines = vec![
+            format!("aws-sigv4 = {}", quote(&format!("aws:amz:{}:s3", Self::region()))),
+            format!("user = {}", quote(&format!("{}:{}", id, secret))),
+        ];
+        if let Ok(token) = std::env::var("AWS_SESSION_TOKEN") {
+            lines.push(format!("header = {}", quote(&format!("x-amz-security-token: {}", token))));
+        }
+        Ok(lines)
+    }
+}
+
+impl Remote for S3 {
+    fn describe(&self) -> String {
+        format!("s3:{}/{}", self.bucket, self.prefix)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let auth = self.auth()?;
+        let prefix = format!("{}/", self.prefix);
+        let mut names = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut url = format!("{}?list-type=2&prefix={}", self.base(), encode(&prefix, false));
+            if let Some(token) = &token {
+                url.push_str(&format!("&continuation-token={}", encode(token, false)));
+            }
+            let body = curl(&[Request::new(url)], &auth, &self.throttle, true)
+                .with_context(|| format!("Could not list {}", self.describe()))?;
+            let body = String::from_utf8_lossy(&body);
+            for key in elements(&body, "Key") {
+                // Only the folder's own files, not those of deeper prefixes
+                if let Some(name) = unescape(key).strip_prefix(&prefix).filter(|n| !n.is_empty() && !n.contains('/')) {
+                    names.push(name.to_string());
+                }
+            }
+            match elements(&body, "NextContinuationToken").first() {
+                Some(next) if elements(&body, "IsTruncated").first() == Some(&"true") => token = Some(unescape(next)),
+                _ => break,
+            }
+        }
+        Ok(names)
+    }
+
+    fn download(&self, files: &[(String, PathBuf)]) -> Result<()> {
+        let auth = self.auth()?;
+        for batch in files.chunks(BATCH) {
+            let requests: Vec<Request> = batch.iter()
+                .map(|(name, local)| Request { output: Some(local.clone()), ..Request::new(self.url(name)) })
+                .collect();
+            curl(&requests, &auth, &self.throttle, true).with_context(|| format!("Download from {} failed", self.describe()))?;
+        }
+        Ok(())
+    }
+
+    fn upload(&self, files: &[(PathBuf, String)]) -> Result<()> {
+        let auth = self.auth()?;
+        for batch in files.chunks(BATCH) {
+            let requests: Vec<Request> = batch.iter()
+                .map(|(local, name)| Request { upload: Some(local.clone()), ..Request::new(self.url(name)) })
+                .collect();
+            curl(&requests, &auth, &self.throttle, true).with_context(|| format!("Upload to {} failed", self.describe()))?;
+        }
+        Ok(())
+    }
+}
+
+// --- WebDAV ---
+
+pub struct WebDav {
+    /// URL of the vault folder, ending in '/'
+    base: String,
+    throttle: RefCell<Throttle>,
+}
+
+impl WebDav {
+    pub fn parse(rest: &str, throttle: RefCell<Throttle>) -> Result<Self> {
+        let Some((_, after)) = rest.split_once("://").filter(|(scheme, _)| *scheme == "https" || *scheme == "http") else {
+            anyhow::bail!("Expected webdav:https://host/path, got webdav:{}", rest);
+        };
+        if after.split('/').next().unwrap_or("").is_empty() {
+            anyhow::bail!("Expected webdav:https://host/path, got webdav:{}", rest);
+        }
+        Ok(Self { base: format!("{}/", rest.trim_end_matches('/')), throttle })
+    }
+
+    fn url(&self, name: &str) -> String {
+        format!("{}{}", self.base, encode(name, false))
+    }
+
+    fn common() -> Vec<String> {
+        vec!["netrc-optional".to_string()]
+    }
+}
+
+impl Remote for WebDav {
+    fn describe(&self) -> String {
+        // Leaves out a login given in the URL
+        match self.base.split_once("://") {
+            Some((scheme, rest)) => {
+                let rest = match rest.split_once('/') {
+                    Some((authority, path)) => format!("{}/{}", authority.rsplit('@').next().unwrap_or(authority), path),
+                    None => rest.to_string(),
+                };
+                format!("webdav:{}://{}", scheme, rest.trim_end_matches('/'))
+            }
+            None => format!("webdav:{}", self.base),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let request = Request {
+            method: Some("PROPFIND"),
+            headers: vec!["Depth: 1".to_string()],
+            ..Request::new(self.base.clone())
+        };
+        // Without `fail`, so a folder that isn't there yet reads as empty
+        let mut common = Self::common();
+        common.push(r#"write-out = "\n%{http_code}""#.to_string());
+        let body = curl(&[request], &common, &self.throttle, false)
+            .with_context(|| format!("Could not list {}", self.describe()))?;
+        let body = String::from_utf8_lossy(&body);
+        let (body, status) = body.rsplit_once('\n').unwrap_or(("", &body));
+        match status.trim() {
+            "404" => return Ok(Vec::new()),
+            "207" => {}
+            status => anyhow::bail!("Could not list {} (HTTP {})", self.describe(), status),
+        }
+        // Entries ending in '/' are folders, the listed one among them
+        Ok(elements(body, "href").into_iter()
+            .map(|href| unescape(href.trim()))
+            .filter(|href| !href.ends_with('/'))
+            .filter_map(|href| href.rsplit('/').next().map(decode))
+            .collect())
+    }
+
+    fn download(&self, files: &[(String, PathBuf)]) -> Result<()> {
+        for batch in files.chunks(BATCH) {
+            let requests: Vec<Request> = batch.iter()
+                .map(|(name, local)| Request { output: Some(local.clone()), ..Request::new(self.url(name)) })
+                .collect();
+            curl(&requests, &Self::common(), &self.throttle, true).with_context(|| format!("Download from {} failed", self.describe()))?;
+        }
+        Ok(())
+    }
+
+    fn upload(&self, files: &[(PathBuf, String)]) -> Result<()> {
+        // Fails harmlessly when the folder is already there
+        let mkcol = Request { method: Some("MKCOL"), ..Request::new(self.base.clone()) };
+        curl(&[mkcol], &Self::common(), &self.throttle, false).with_context(|| format!("Could not reach {}", self.describe()))?;
+
+        for batch in files.chunks(BATCH) {
+            let mut requests = Vec::new();
+            for (local, name) in batch {
+                let tmp = self.url(&format!("{}.tmp", name));
+                requests.push(Request { upload: Some(local.clone()), ..Request::new(tmp.clone()) });
+                requests.push(Request {
+                    method: Some("MOVE"),
+                    headers: vec![format!("Destination: {}", self.url(name)), "Overwrite: T".to_string()],
+                    ..Request::new(tmp)
+                });
+            }
+            curl(&requests, &Self::common(), &self.throttle, true).with_context(|| format!("Upload to {} failed", self.describe()))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,366 @@
+//! Two-way mirroring of a local folder and a folder in the vault, for
+//! `lethe syncd`.
+//!
+//! Each round compares both sides with how they looked after the last one
+//! (`syncd.state`, sealed, since it lists every path). A file changed on
+//! one side is copied to the other, one deleted is deleted there too. A
+//! file changed on both keeps the vault's version at its path and stores
+//! the local one as a conflict copy beside it, the way a merge does, so
+//! `lethe conflicts` picks it up. The first round has nothing to compare
+//! with: a file on both sides that differs counts as changed on both.
+//!
+//! Only files are mirrored; empty folders and symlinks are left alone.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use lethe_core::crypto::{CryptoEngine, MasterKey};
+use lethe_core::device;
+use lethe_core::index::{self, FileEntry, IndexManager, VaultIndex};
+use lethe_core::merge;
+use lethe_core::progress::Silent;
+use lethe_core::reader::FileReader;
+use lethe_core::storage::BlockManager;
+
+use crate::daemon::claim::claim;
+
+pub const STATE_FILE: &str = "syncd.state";
+
+/// Suffix of a file being written out, renamed once complete
+const PART: &str = ".lethe-part";
+
+/// Read from each side at a time when comparing a file on both
+const COMPARE_BUFFER: usize = 1024 * 1024;
+
+/// A file as it was after the last round; only kept while on both sides
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct Seen {
+    size: u64,
+    /// Local mtime in nanoseconds
+    mtime: u64,
+    /// `fingerprint` of the vault entry
+    vault: u64,
+}
+
+/// What every mirrored folder looked like, by local path
+#[derive(Serialize, Deserialize, Default)]
+pub struct State {
+    folders: BTreeMap<String, BTreeMap<String, Seen>>,
+}
+
+fn state_key(key: &MasterKey) -> MasterKey {
+    CryptoEngine::derive_subkey(key, b"lethe syncd state")
+}
+
+impl State {
+    /// The state kept in `vault`; empty before the first round
+    pub fn load(vault: &Path, key: &MasterKey) -> Result<Self> {
+        let sealed = match fs::read(vault.join(STATE_FILE)) {
+            Ok(sealed) => sealed,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).context("Failed to read the sync state"),
+        };
+        if sealed.len() < 24 {
+            anyhow::bail!("{} is corrupted", STATE_FILE);
+        }
+        let (nonce, ciphertext) = sealed.split_at(24);
+        let plain = CryptoEngine::decrypt(ciphertext, nonce, &state_key(key))
+            .with_context(|| format!("{} doesn't open", STATE_FILE))?;
+        serde_cbor::from_slice(&plain).context("Malformed sync state")
+    }
+
+    pub fn save(&self, vault: &Path, key: &MasterKey) -> Result<()> {
+        let (ciphertext, mut sealed) = CryptoEngine::encrypt(&serde_cbor::to_vec(self)?, &state_key(key))?;
+        sealed.extend_from_slice(&ciphertext);
+        let tmp = vault.join(format!("{}.tmp", STATE_FILE));
+        fs::write(&tmp, sealed).context("Failed to write the sync state")?;
+        fs::rename(&tmp, vault.join(STATE_FILE))?;
+        Ok(())
+    }
+
+    /// Forgets a folder that is no longer mirrored
+    pub fn forget(&mut self, local: &Path) {
+        self.folders.remove(&local.display().to_string());
+    }
+}
+
+/// A local folder and the vault folder it mirrors
+#[derive(Debug, Clone, PartialEq)]
+pub struct Folder {
+    pub local: PathBuf,
+    /// `/Documents`, or `/` for the whole vault
+    pub vault: String,
+}
+
+impl Folder {
+    fn vault_path(&self, relative: &str) -> String {
+        format!("{}/{}", self.vault.trim_end_matches('/'), relative)
+    }
+
+    fn local_path(&self, relative: &str) -> PathBuf {
+        relative.split('/').fold(self.local.clone(), |path, part| path.join(part))
+    }
+}
+
+/// What a round did
+#[derive(Default, Debug)]
+pub struct Outcome {
+    /// Local files copied into the vault
+    pub stored: usize,
+    /// Vault files written out
+    pub written: usize,
+    /// Files deleted on either side
+    pub deleted: usize,
+    /// Conflict copies made in the vault
+    pub conflicts: Vec<String>,
+    /// Whether the vault changed, i.e. peers and remotes have news
+    pub saved: bool,
+}
+
+impl Outcome {
+    pub fn is_empty(&self) -> bool {
+        self.stored == 0 && self.written == 0 && self.deleted == 0 && self.conflicts.is_empty()
+    }
+}
+
+enum Step {
+    Store,
+    Write,
+    DeleteInVault,
+    DeleteLocal,
+    /// Changed on both sides; a conflict unless they are the same
+    Compare,
+    /// Gone on both sides
+    Forget,
+}
+
+/// Identifies a version of a vault entry
+fn fingerprint(entry: &FileEntry) -> u64 {
+    fxhash::hash64(&entry.blocks)
+}
+
+fn stat(meta: &fs::Metadata) -> (u64, u64) {
+    let mtime = meta.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    (meta.len(), mtime)
+}
+
+/// Files under `root` by relative path ('/'-separated), with size and mtime
+fn local_files(root: &Path) -> Result<BTreeMap<String, (u64, u64)>> {
+    let mut files = BTreeMap::new();
+    for entry in WalkDir::new(root).min_depth(1) {
+        let entry = entry.with_context(|| format!("Failed to read {:?}", root))?;
+        if !entry.file_type().is_file() || entry.file_name().to_string_lossy().ends_with(PART) {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(root)?;
+        let Some(relative) = relative.to_str() else {
+            warn!("Skipping {:?}: the vault only holds UTF-8 names", entry.path());
+            continue;
+        };
+        files.insert(relative.replace('\\', "/"), stat(&entry.metadata()?));
+    }
+    Ok(files)
+}
+
+/// Vault files under `folder`, by relative path
+fn vault_files<'a>(index: &'a VaultIndex, folder: &Folder) -> BTreeMap<String, &'a FileEntry> {
+    let prefix = format!("{}/", folder.vault.trim_end_matches('/'));
+    index.files.iter()
+        .filter(|(path, entry)| !entry.is_dir && !index::is_snapshot_path(path))
+        .filter_map(|(path, entry)| path.strip_prefix(&prefix).map(|relative| (relative.to_string(), entry)))
+        .collect()
+}
+
+fn plan(
+    local: &BTreeMap<String, (u64, u64)>,
+    vault: &BTreeMap<String, &FileEntry>,
+    seen: &BTreeMap<String, Seen>,
+) -> Vec<(String, Step)> {
+    let paths: BTreeSet<&String> = local.keys().chain(vault.keys()).chain(seen.keys()).collect();
+    let mut steps = Vec::new();
+    for path in paths {
+        let before = seen.get(path);
+        let on_disk = local.get(path).copied();
+        let in_vault = vault.get(path).map(|e| fingerprint(e));
+        let local_changed = on_disk != before.map(|s| (s.size, s.mtime));
+        let vault_changed = in_vault != before.map(|s| s.vault);
+        let step = match (local_changed, vault_changed, on_disk.is_some(), in_vault.is_some()) {
+            (false, false, _, _) => continue,
+            (true, false, true, _) => Step::Store,
+            (true, false, false, _) => Step::DeleteInVault,
+            (false, true, _, true) => Step::Write,
+            (false, true, _, false) => Step::DeleteLocal,
+            (true, true, true, true) => Step::Compare,
+            // A change beats a deletion
+            (true, true, true, false) => Step::Store,
+            (true, true, false, true) => Step::Write,
+            (true, true, false, false) => Step::Forget,
+        };
+        steps.push((path.clone(), step));
+    }
+    steps
+}
+
+/// Reads into `buf` until it is full or the reader runs out
+fn fill(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Whether `file` holds what `entry` does. Both are read side by side, a
+/// buffer at a time, so neither has to fit in memory.
+fn same_content(file: &Path, storage: &BlockManager, key: &MasterKey, entry: &FileEntry) -> Result<bool> {
+    let mut ours = fs::File::open(file).with_context(|| format!("Failed to read {:?}", file))?;
+    if ours.metadata()?.len() != entry.size {
+        return Ok(false);
+    }
+    let mut theirs = FileReader::new(storage, key, entry);
+    let (mut a, mut b) = (vec![0u8; COMPARE_BUFFER], vec![0u8; COMPARE_BUFFER]);
+    loop {
+        let n = fill(&mut ours, &mut a).with_context(|| format!("Failed to read {:?}", file))?;
+        let m = fill(&mut theirs, &mut b)?;
+        if a[..n] != b[..m] {
+            return Ok(false);
+        }
+        if n == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Writes out under a temporary name first, so a half-written file is
+/// never taken for a change
+fn write_local(path: &Path, mut content: impl Read) -> Result<(u64, u64)> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Failed to create {:?}", parent))?;
+    }
+    let mut part = path.as_os_str().to_owned();
+    part.push(PART);
+    fs::File::create(&part)
+        .and_then(|mut file| io::copy(&mut content, &mut file))
+        .with_context(|| format!("Failed to write {:?}", path))?;
+    fs::rename(&part, path)?;
+    Ok(stat(&fs::metadata(path)?))
+}
+
+/// One round for `folder`. It plans against the index on disk first and
+/// only takes the vault when there is something to do.
+pub fn sync(vault_path: &Path, key: &MasterKey, folder: &Folder, state: &mut State) -> Result<Outcome> {
+    // An unplugged disk must not read as everything deleted
+    if !folder.local.is_dir() {
+        anyhow::bail!("{:?} is not there", folder.local);
+    }
+    let name = folder.local.display().to_string();
+    let seen = state.folders.get(&name).cloned().unwrap_or_default();
+    {
+        let index = IndexManager::load(vault_path.to_path_buf(), key)?;
+        if plan(&local_files(&folder.local)?, &vault_files(&index.data, folder), &seen).is_empty() {
+            return Ok(Outcome::default());
+        }
+    }
+
+    let _claim = claim(vault_path, "lethe syncd")?;
+    let mut index_mgr = IndexManager::load(vault_path.to_path_buf(), key)?;
+    let storage = BlockManager::new(vault_path)?;
+    let local = local_files(&folder.local)?;
+    let steps = {
+        let vault = vault_files(&index_mgr.data, folder);
+        plan(&local, &vault, &seen)
+    };
+
+    let mut seen = seen;
+    let mut outcome = Outcome::default();
+    let mut saved = false;
+    for (relative, step) in steps {
+        let path = folder.vault_path(&relative);
+        let file = folder.local_path(&relative);
+        match step {
+            Step::Store => {
+                if let Err(e) = index_mgr.check_new_path(&path).and_then(|()| index_mgr.check_mutable(&path)) {
+                    warn!("Not syncing {:?}: {:#}", file, e);
+                    continue;
+                }
+                let source = fs::File::open(&file).with_context(|| format!("Failed to read {:?}", file))?;
+                index_mgr.store_reader(&storage, key, path.clone(), source, &Silent)?;
+                let (size, mtime) = local[&relative];
+                index_mgr.set_modified(&path, mtime / 1_000_000_000);
+                let vault = fingerprint(&index_mgr.data.files[&path]);
+                seen.insert(relative, Seen { size, mtime, vault });
+                outcome.stored += 1;
+                saved = true;
+            }
+            Step::Write => {
+                let entry = &index_mgr.data.files[&path];
+                let (size, mtime) = write_local(&file, FileReader::new(&storage, key, entry))?;
+                seen.insert(relative, Seen { size, mtime, vault: fingerprint(entry) });
+                outcome.written += 1;
+            }
+            Step::DeleteInVault => {
+                // Forgotten, so the next round writes it back
+                if let Err(e) = index_mgr.check_mutable(&path) {
+                    warn!("Not deleting {} from the vault: {:#}", path, e);
+                    seen.remove(&relative);
+                    continue;
+                }
+                index_mgr.data.files.remove(&path);
+                index_mgr.touch_parent(&path);
+                seen.remove(&relative);
+                outcome.deleted += 1;
+                saved = true;
+            }
+            Step::DeleteLocal => {
+                match fs::remove_file(&file) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e).with_context(|| format!("Failed to delete {:?}", file)),
+                }
+                seen.remove(&relative);
+                outcome.deleted += 1;
+            }
+            Step::Compare => {
+                let entry = index_mgr.data.files[&path].clone();
+                if !same_content(&file, &storage, key, &entry)? {
+                    let (_, mtime) = local[&relative];
+                    let copy = merge::free_conflict_path(&path, &device::name(), mtime / 1_000_000_000, &index_mgr.data.files);
+                    let source = fs::File::open(&file).with_context(|| format!("Failed to read {:?}", file))?;
+                    index_mgr.store_reader(&storage, key, copy.clone(), source, &Silent)?;
+                    write_local(&file, FileReader::new(&storage, key, &entry))?;
+                    outcome.conflicts.push(copy);
+                    saved = true;
+                }
+                let (size, mtime) = stat(&fs::metadata(&file)?);
+                seen.insert(relative, Seen { size, mtime, vault: fingerprint(&entry) });
+            }
+            Step::Forget => {
+                seen.remove(&relative);
+            }
+        }
+    }
+
+    if saved {
+        index_mgr.save(key)?;
+        outcome.saved = true;
+    }
+    state.folders.insert(name, seen);
+    state.save(vault_path, key)?;
+    Ok(outcome)
+}
@@ -0,0 +1,213 @@
+//! When each replica of the vault was last reached and last scrubbed.
+//!
+//! A copy that nobody looks at can rot unnoticed: a backup disk fills up,
+//! a bucket's credentials expire, a peer is retired. Every push, pull,
+//! scrub and dialed sync-peer notes the outcome here (`replicas.bin`, next
+//! to the index replicas and sealed like them, see `super::sealed`), and
+//! `lethe stats` and `lethe syncd` warn about a replica not reached, or a
+//! remote not scrubbed, for too long.
+//!
+//! Replicas are named as the remote describes itself (`remote::describe`)
+//! or by the peer address that was dialed. Listening peers aren't tracked:
+//! all we learn of them is an address that may change.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use lethe_core::crypto::MasterKey;
+
+use super::remote::Remote;
+use super::sealed;
+
+pub const HEALTH_FILE: &str = "replicas.bin";
+
+/// Days a replica may go unreached or unscrubbed before it is reported
+pub const STALE_DAYS: u64 = 7;
+
+const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Replica {
+    /// "remote" or "peer"
+    pub kind: String,
+    /// When it was first tried, which is what a replica never reached is
+    /// overdue from
+    pub first_seen: u64,
+    pub reached: Option<u64>,
+    /// Last scrub that found nothing missing or damaged
+    pub verified: Option<u64>,
+    /// Why the last attempt failed, cleared once it is reached again
+    pub last_error: Option<String>,
+}
+
+impl Replica {
+    /// What is overdue, if anything
+    pub fn overdue(&self, days: u64, now: u64) -> Vec<String> {
+        let limit = days * DAY;
+        let mut late = Vec::new();
+        let reached = self.reached.unwrap_or(self.first_seen);
+        if now.saturating_sub(reached) > limit {
+            late.push(match self.reached {
+                Some(_) => format!("not reached for {} day(s)", now.saturating_sub(reached) / DAY),
+                None => "never reached".to_string(),
+            });
+        }
+        // Peers send what they hold verified on arrival; only remotes sit still
+        let verified = self.verified.unwrap_or(self.first_seen);
+        if self.kind == "remote" && now.saturating_sub(verified) > limit {
+            late.push(match self.verified {
+                Some(_) => format!("not scrubbed for {} day(s)", now.saturating_sub(verified) / DAY),
+                None => "never scrubbed".to_string(),
+            });
+        }
+        late
+    }
+}
+
+/// Every replica this copy has synced with, by name
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Health {
+    pub replicas: BTreeMap<String, Replica>,
+    /// Last `lethe verify` that found every block of this copy there and
+    /// intact (see `cli::checkup`)
+    #[serde(default)]
+    pub verified: Option<u64>,
+    /// Last `lethe drill` that restored its whole sample intact
+    #[serde(default)]
+    pub drilled: Option<u64>,
+    /// How far the background scrub got (see `daemon::scrub`)
+    #[serde(default)]
+    pub scrub: Scrub,
+}
+
+/// The background scrub's pass over this copy's blocks
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Scrub {
+    /// Last block checked; the next slice starts after it
+    pub cursor: Option<String>,
+    /// When the last pass reached the end
+    pub finished: Option<u64>,
+    /// Blocks found damaged or missing, with when each was first found
+    pub damaged: BTreeMap<String, u64>,
+}
+
+pub fn now() -> u64 {
+    UNIX_EPOCH.elapsed().map(|d| d.as_secs()).unwrap_or_default()
+}
+
+impl Health {
+    pub fn load(vault: &Path, key: &MasterKey) -> Result<Self> {
+        Ok(sealed::load(vault, HEALTH_FILE, key)?.unwrap_or_default())
+    }
+
+    pub fn save(&self, vault: &Path, key: &MasterKey) -> Result<()> {
+        sealed::save(vault, HEALTH_FILE, key, self)
+    }
+
+    /// Replicas with something overdue, and what
+    pub fn overdue(&self, days: u64) -> Vec<(&str, &Replica, Vec<String>)> {
+        let now = now();
+        self.replicas.iter()
+            .map(|(name, replica)| (name.as_str(), replica, replica.overdue(days, now)))
+            .filter(|(_, _, late)| !late.is_empty())
+            .collect()
+    }
+}
+
+/// Applies `change` to the entry for `name`. Bookkeeping must never fail
+/// the sync it describes, so problems are only logged.
+fn update(vault: &Path, key: &MasterKey, kind: &str, name: &str, change: impl FnOnce(&mut Replica, u64)) {
+    let result = Health::load(vault, key).and_then(|mut health| {
+        let now = now();
+        let replica = health.replicas.entry(name.to_string()).or_insert_with(|| Replica {
+            kind: kind.to_string(),
+            first_seen: now,
+            reached: None,
+            verified: None,
+            last_error: None,
+        });
+        change(replica, now);
+        health.save(vault, key)
+    });
+    if let Err(e) = result {
+        warn!("Could not note how {} {} is doing: {:#}", kind, name, e);
+    }
+}
+
+pub fn reached(vault: &Path, key: &MasterKey, kind: &str, name: &str) {
+    update(vault, key, kind, name, |replica, now| {
+        replica.reached = Some(now);
+        replica.last_error = None;
+    });
+}
+
+pub fn failed(vault: &Path, key: &MasterKey, kind: &str, name: &str, error: &anyhow::Error) {
+    update(vault, key, kind, name, |replica, _| replica.last_error = Some(format!("{:#}", error)));
+}
+
+pub fn verified(vault: &Path, key: &MasterKey, name: &str) {
+    update(vault, key, "remote", name, |replica, now| replica.verified = Some(now));
+}
+
+/// Notes that this copy's own blocks were just verified
+pub fn copy_verified(vault: &Path, key: &MasterKey) {
+    let result = Health::load(vault, key).and_then(|mut health| {
+        health.verified = Some(now());
+        health.save(vault, key)
+    });
+    if let Err(e) = result {
+        warn!("Could not note that the vault was verified: {:#}", e);
+    }
+}
+
+/// Notes that a restore drill just passed
+pub fn copy_drilled(vault: &Path, key: &MasterKey) {
+    let result = Health::load(vault, key).and_then(|mut health| {
+        health.drilled = Some(now());
+        health.save(vault, key)
+    });
+    if let Err(e) = result {
+        warn!("Could not note the restore drill: {:#}", e);
+    }
+}
+
+/// Stops tracking `name`; true if it was tracked
+pub fn forget(vault: &Path, key: &MasterKey, name: &str) -> Result<bool> {
+    let mut health = Health::load(vault, key)?;
+    if health.replicas.remove(name).is_none() {
+        return Ok(false);
+    }
+    health.save(vault, key)?;
+    Ok(true)
+}
+
+/// Lists the remote, noting whether it answered
+pub fn list(vault: &Path, key: &MasterKey, remote: &dyn Remote) -> Result<Vec<String>> {
+    let listed = remote.list();
+    match &listed {
+        Ok(_) => reached(vault, key, "remote", &remote.describe()),
+        Err(e) => failed(vault, key, "remote", &remote.describe(), e),
+    }
+    listed
+}
+
+/// Prints a warning for every overdue replica; returns how many there were
+pub fn report(vault: &Path, key: &MasterKey, days: u64) -> usize {
+    let health = match Health::load(vault, key) {
+        Ok(health) => health,
+        Err(e) => {
+            warn!("{:#}", e);
+            return 0;
+        }
+    };
+    let overdue = health.overdue(days);
+    for (name, replica, late) in &overdue {
+        println!("WARNING: {} {}: {}.", replica.kind, name, late.join(" and "));
+    }
+    overdue.len()
+}
@@ -0,0 +1,79 @@
+//! Keeping two copies of a vault in step.
+//!
+//! Both copies hold the same key, so blocks travel exactly as they sit on
+//! disk and are only checked (decrypted) on arrival, never re-encrypted.
+//! Indexes are merged path by path (see `lethe_core::merge`), and once
+//! devices are enabled the keyring travels along: a copy can only sync
+//! while enrolled in the keyring both sides settle on.
+
+pub mod bundle;
+pub mod bwlimit;
+pub mod cloud;
+pub mod folder;
+pub mod health;
+pub mod outbox;
+pub mod peer;
+pub mod remote;
+pub mod sealed;
+
+use anyhow::Result;
+use std::path::Path;
+
+use lethe_core::audit::{self, Operation};
+use lethe_core::crypto::MasterKey;
+use lethe_core::index::IndexManager;
+use lethe_core::keyring::{self, IndexKey, Keyring};
+use lethe_core::merge::Conflict;
+
+/// Lists files both copies changed and where the older version went
+pub fn report_conflicts(conflicts: &[Conflict]) {
+    if conflicts.is_empty() {
+        return;
+    }
+    println!("WARNING: {} file(s) changed on both sides. Kept both versions:", conflicts.len());
+    for c in conflicts {
+        println!("   {}  (other version: {})", c.path, c.copy);
+    }
+    println!("   Review them with `lethe conflicts list`.");
+}
+
+/// Notes a finished sync in the audit log (see `lethe_core::audit`)
+pub fn audit_sync(vault: &Path, key: &MasterKey, kind: &str, with: &str) {
+    let operation = Operation::Sync { kind: kind.to_string(), with: with.to_string() };
+    if let Err(e) = audit::record(vault, key, operation) {
+        log::warn!("Could not write the audit log: {}", e);
+    }
+}
+
+/// The keyring both copies should hold when it isn't the one this copy
+/// has, with the index keys it opens for us. Fails if it doesn't open:
+/// this copy was never enrolled there, or was revoked.
+pub fn newer_keyring(vault: &Path, theirs: Option<&Keyring>, key: &MasterKey) -> Result<Option<(Keyring, IndexKey)>> {
+    let ours = Keyring::load(vault, key)?;
+    let Some(ring) = keyring::reconcile(ours.as_ref(), theirs)? else {
+        return Ok(None);
+    };
+    if ours.as_ref() == Some(&ring) {
+        return Ok(None);
+    }
+    let index_key = ring.unlock(&keyring::device_keys(vault), key).ok_or_else(keyring::not_enrolled)?;
+    Ok(Some((ring, index_key)))
+}
+
+/// This copy's index, opened with the keyring from `newer_keyring` if
+/// there is one, which is handed back to save once the other side is
+/// trusted
+pub fn load_index(vault: &Path, newer: Option<(Keyring, IndexKey)>, key: &MasterKey) -> Result<(IndexManager, Option<Keyring>)> {
+    match newer {
+        Some((ring, index_key)) => Ok((IndexManager::load_with(vault.to_path_buf(), key, Some(index_key))?, Some(ring))),
+        None => Ok((IndexManager::load(vault.to_path_buf(), key)?, None)),
+    }
+}
+
+/// Saves a keyring from `load_index`, then rewrites the index under it
+pub fn adopt_keyring(index_mgr: &IndexManager, ring: &Keyring, key: &MasterKey) -> Result<()> {
+    ring.save(index_mgr.root_path(), key)?;
+    index_mgr.rewrite(key)?;
+    println!("   Device keyring updated ({} enrolled device(s)).", ring.slots.len());
+    Ok(())
+}
@@ -0,0 +1,224 @@
+//! A vault's backup remote, and the push still owed to it.
+//!
+//! With a remote set (`lethe remote set`), `lethe put` and a running mount
+//! push their changes there as they go. When the remote can't be reached
+//! the write still succeeds and the push is queued: `outbox.bin`, next to
+//! the index replicas, records the revision the remote is owed, and the
+//! next write, the mount (every minute) or `lethe remote flush` tries
+//! again.
+//!
+//! `remote.bin` holds the remote and, if the index lives apart from the
+//! blocks, the remote for the index. Both files are sealed like the index
+//! replicas (see `super::sealed`), as the remote's address and user name
+//! are nobody else's business.
+//!
+//! Only the revision is queued, not a list of blocks: a push works out
+//! which blocks the remote lacks when it runs, so the queue can't drift
+//! from what is on disk. Blocks still go first and the index last.
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use lethe_core::crypto::MasterKey;
+use lethe_core::index::IndexManager;
+
+use super::bwlimit::BwLimit;
+use super::remote;
+use super::sealed;
+
+pub const REMOTE_FILE: &str = "remote.bin";
+pub const OUTBOX_FILE: &str = "outbox.bin";
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Outbox {
+    /// Local revision the remote last took
+    pub pushed: u64,
+    pub pending: Option<Pending>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Pending {
+    /// Local revision the remote is owed
+    pub revision: u64,
+    /// When the first push that failed was queued
+    pub since: u64,
+    pub attempts: u32,
+    pub last_error: String,
+}
+
+/// Where the copy at a vault pushes to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Target {
+    pub remote: String,
+    /// A second remote for the index replicas (see `remote::open_split`)
+    pub index: Option<String>,
+}
+
+impl Target {
+    pub fn open(&self, limit: BwLimit) -> Result<Box<dyn remote::Remote>> {
+        remote::open_split(&self.remote, self.index.as_deref(), limit)
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.index {
+            Some(index) => write!(f, "{} (index on {})", self.remote, index),
+            None => write!(f, "{}", self.remote),
+        }
+    }
+}
+
+/// What `replay` did
+pub enum Replay {
+    /// No remote is set
+    Off,
+    /// The remote already has this revision
+    Current,
+    Sent,
+    /// The push failed and waits in the outbox
+    Queued(String),
+}
+
+fn now() -> u64 {
+    UNIX_EPOCH.elapsed().map(|d| d.as_secs()).unwrap_or_default()
+}
+
+// --- Remote ---
+
+/// The remote set for the copy at `vault`, if any
+pub fn remote(vault: &Path, key: &MasterKey) -> Option<Target> {
+    sealed::load(vault, REMOTE_FILE, key).unwrap_or_else(|e| {
+        warn!("{:#}", e);
+        None
+    })
+}
+
+/// Sets or clears the remote. Either way, what was queued is dropped.
+pub fn set_remote(vault: &Path, key: &MasterKey, target: Option<&Target>) -> Result<()> {
+    let path = vault.join(REMOTE_FILE);
+    match target {
+        Some(target) => {
+            target.open(BwLimit::unlimited())?;
+            sealed::save(vault, REMOTE_FILE, key, target)?;
+        }
+        None if path.exists() => fs::remove_file(&path).context("Failed to remove the remote setting")?,
+        None => {}
+    }
+    // Owed to the old remote, if any; the new one starts from scratch
+    let outbox = vault.join(OUTBOX_FILE);
+    if outbox.exists() {
+        fs::remove_file(outbox).context("Failed to remove the outbox")?;
+    }
+    Ok(())
+}
+
+// --- Outbox ---
+
+impl Outbox {
+    pub fn load(vault: &Path, key: &MasterKey) -> Result<Self> {
+        Ok(sealed::load(vault, OUTBOX_FILE, key)?.unwrap_or_default())
+    }
+
+    fn save(&self, vault: &Path, key: &MasterKey) -> Result<()> {
+        sealed::save(vault, OUTBOX_FILE, key, self)
+    }
+
+    /// Human-readable state, for `lethe remote show`
+    pub fn describe(&self) -> String {
+        match &self.pending {
+            None if self.pushed == 0 => "nothing pushed yet".to_string(),
+            None => format!("up to date (pushed revision {})", self.pushed),
+            Some(p) => {
+                let since = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(p.since));
+                format!(
+                    "revision {} queued since {}, {} failed attempt(s). Last error: {}",
+                    p.revision, since, p.attempts, p.last_error
+                )
+            }
+        }
+    }
+}
+
+/// Whether the remote is owed a revision the vault on disk has
+pub fn owed(vault: &Path, key: &MasterKey) -> Result<bool> {
+    if remote(vault, key).is_none() {
+        return Ok(false);
+    }
+    let outbox = Outbox::load(vault, key)?;
+    let index = IndexManager::load(vault.to_path_buf(), key)?;
+    // A hidden vault doesn't sync; its revisions mean nothing to the outbox
+    if index.is_hidden() {
+        return Ok(false);
+    }
+    Ok(outbox.pending.is_some() || index.data.revision > outbox.pushed)
+}
+
+/// Pushes whatever the remote hasn't taken, queueing it if that fails.
+/// Call it with the vault held.
+pub fn replay(vault: &Path, key: &MasterKey) -> Result<Replay> {
+    let Some(target) = remote(vault, key) else {
+        return Ok(Replay::Off);
+    };
+    let mut outbox = Outbox::load(vault, key)?;
+    let index = IndexManager::load(vault.to_path_buf(), key)?;
+    if index.is_hidden() {
+        return Ok(Replay::Off);
+    }
+    let revision = index.data.revision;
+    if outbox.pending.is_none() && revision <= outbox.pushed {
+        return Ok(Replay::Current);
+    }
+
+    let pushed = target.open(BwLimit::unlimited())
+        .and_then(|remote| {
+            println!("Pushing to {}...", remote.describe());
+            remote::push(remote.as_ref(), vault, key, false)
+        });
+    let replay = match pushed {
+        Ok(()) => {
+            outbox.pushed = revision;
+            outbox.pending = None;
+            Replay::Sent
+        }
+        Err(e) => {
+            let error = format!("{:#}", e);
+            let pending = outbox.pending.get_or_insert_with(|| Pending {
+                revision,
+                since: now(),
+                attempts: 0,
+                last_error: String::new(),
+            });
+            pending.revision = revision;
+            pending.attempts += 1;
+            pending.last_error = error.clone();
+            Replay::Queued(error)
+        }
+    };
+    outbox.save(vault, key)?;
+    Ok(replay)
+}
+
+/// Records a push made by hand with `lethe push`, if it went to the set
+/// remote
+pub fn pushed_by_hand(vault: &Path, target: &Target, key: &MasterKey) -> Result<()> {
+    if remote(vault, key).as_ref() != Some(target) {
+        return Ok(());
+    }
+    let revision = IndexManager::load(vault.to_path_buf(), key)?.data.revision;
+    Outbox { pushed: revision, pending: None }.save(vault, key)
+}
+
+/// `replay` after a write, reporting a queued push instead of failing it
+pub fn after_write(vault: &Path, key: &MasterKey) -> Result<()> {
+    if let Replay::Queued(error) = replay(vault, key)? {
+        println!("WARNING: The remote could not be reached: {}", error);
+        println!("   The push is queued. It is retried on the next write, by a running mount, or with `lethe remote flush`.");
+    }
+    Ok(())
+}
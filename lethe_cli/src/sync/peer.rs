@@ -0,0 +1,535 @@
+//! `lethe sync-peer`: direct sync between two machines holding the same vault.
+//!
+//! One side listens, the other dials. Over a single TCP connection they:
+//!
+//! 1. Swap salts, keyrings, capabilities and random challenges, then
+//!    prove to each other that they hold the vault key by sealing the
+//!    other side's challenge with it. Once devices are enabled the proof
+//!    uses the index key of the keyring both settle on, so only enrolled
+//!    devices get further.
+//! 2. Swap their sealed indexes and the ids of the blocks on disk, and both
+//!    compute the same merge of the two indexes.
+//! 3. Send each other the blocks the merged index needs, resuming any
+//!    block an interrupted run left half-received.
+//! 4. Verify every received block and, once all have arrived, save the
+//!    merged index. An interrupted sync leaves the index untouched.
+//!
+//! File contents and names never cross the wire unencrypted; block ids and
+//! sizes do. `--bwlimit` paces the blocks each way.
+//!
+//! The capabilities say which vault format and features each build reads
+//! and each copy uses, so builds of different ages can sync: the index
+//! goes whole to a build from before the split layout, and a copy whose
+//! blocks or format the other build couldn't take is refused by name
+//! before anything is sent. Builds from before capabilities send none and
+//! are taken to know no more than the first chunked vaults.
+
+use std::collections::{BTreeSet, HashSet};
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use lethe_core::crypto::{CryptoEngine, MasterKey};
+use lethe_core::header::{self, VaultHeader};
+use lethe_core::index::IndexManager;
+use lethe_core::keyring::Keyring;
+use lethe_core::merge;
+use lethe_core::storage::BlockManager;
+
+use super::bwlimit::{self, BwLimit, Throttle};
+use super::health;
+use crate::daemon::claim::claim;
+use crate::daemon::sentinel;
+
+pub const DEFAULT_PORT: u16 = 4919;
+
+const PROTOCOL: u32 = 2;
+const CHALLENGE_LEN: usize = 32;
+
+/// Frame limits before and after the peer has proven it holds the key.
+/// Block contents are streamed after their header, outside any frame.
+const HANDSHAKE_FRAME: u32 = 64 * 1024;
+const CONTROL_FRAME: u32 = 256 * 1024 * 1024;
+
+// --- Protocol ---
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Message {
+    /// `keyring` is sealed with the vault key, absent until devices are
+    /// enabled; `capabilities` is absent from builds before them
+    Hello {
+        protocol: u32,
+        salt: String,
+        challenge: Vec<u8>,
+        keyring: Option<Vec<u8>>,
+        #[serde(default)]
+        capabilities: Option<Capabilities>,
+    },
+    /// The other side's challenge, sealed with the vault key or, once
+    /// devices are enabled, the index key (Nonce + Data)
+    Proof(Vec<u8>),
+    /// Sealed index plus every block id on disk
+    Offer { index: Vec<u8>, blocks: Vec<String> },
+    /// Blocks to send, each with the bytes the asker already holds
+    Want(Vec<(String, u64)>),
+    /// Header for `len` raw bytes of block `id`, starting at `offset`
+    Block { id: String, offset: u64, len: u64 },
+    /// An asked-for block is gone
+    Missing(String),
+    Done,
+    Refused(String),
+}
+
+/// What one side's build reads and its copy of the vault uses
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Capabilities {
+    /// Newest vault format the build reads
+    reads: u16,
+    /// Vault features the build knows (`header::KNOWN_FEATURES`)
+    known: BTreeSet<String>,
+    /// The copy's format, cipher suite and features, from its header
+    format: u16,
+    cipher: String,
+    uses: BTreeSet<String>,
+}
+
+/// Features that stay with each copy and change nothing sent
+const LOCAL_FEATURES: &[&str] = &[header::FEATURE_WIPE];
+
+impl Capabilities {
+    fn local(header: &VaultHeader) -> Self {
+        Self {
+            reads: header::FORMAT_VERSION,
+            known: header::KNOWN_FEATURES.iter().map(|f| f.to_string()).collect(),
+            format: header.format,
+            cipher: header.cipher.clone(),
+            uses: header.features.clone(),
+        }
+    }
+
+    /// A build from before capabilities were swapped
+    fn legacy() -> Self {
+        let chunked = BTreeSet::from([header::FEATURE_CHUNKED.to_string()]);
+        Self {
+            reads: 1,
+            known: chunked.clone(),
+            format: 1,
+            cipher: header::CIPHER_SUITE.to_string(),
+            uses: chunked,
+        }
+    }
+
+    /// Features `self` uses that `peer` doesn't know, bar local ones
+    fn unknown_to<'a>(&'a self, peer: &'a Capabilities) -> impl Iterator<Item = &'a str> {
+        self.uses.iter()
+            .map(String::as_str)
+            .filter(|f| !peer.known.contains(*f) && !LOCAL_FEATURES.contains(f))
+    }
+}
+
+/// How the two sides will talk, once both can
+struct Agreed {
+    /// Send the index split, as the peer's build reads it
+    split_index: bool,
+}
+
+/// The common ground of two copies, or why there is none. Both sides
+/// reach the same answer, each about its own copy.
+fn negotiate(ours: &Capabilities, theirs: &Capabilities) -> Result<Agreed> {
+    if ours.cipher != theirs.cipher {
+        anyhow::bail!("This copy is encrypted with {} and the peer's with {}; they can't share blocks", ours.cipher, theirs.cipher);
+    }
+    for (copy, build, reads, format) in [("This", "the peer's", theirs.reads, ours.format), ("The peer's", "this", ours.reads, theirs.format)] {
+        if format > reads {
+            anyhow::bail!("{} copy is in vault format {}, and {} build of Lethe reads up to format {}. Update Lethe there.", copy, format, build, reads);
+        }
+    }
+    let refused = |copy: &Capabilities, other: &Capabilities| -> Vec<String> {
+        copy.unknown_to(other).filter(|f| *f != header::FEATURE_SPLIT_INDEX).map(str::to_string).collect()
+    };
+    let (ours_unknown, theirs_unknown) = (refused(ours, theirs), refused(theirs, ours));
+    if !ours_unknown.is_empty() {
+        anyhow::bail!("This copy uses {}, which the peer's build of Lethe doesn't know. Update Lethe there.", ours_unknown.join(", "));
+    }
+    if !theirs_unknown.is_empty() {
+        anyhow::bail!("The peer's copy uses {}, which this build of Lethe doesn't know. Update Lethe here.", theirs_unknown.join(", "));
+    }
+    Ok(Agreed { split_index: theirs.known.contains(header::FEATURE_SPLIT_INDEX) })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Dialer,
+    Listener,
+}
+
+impl Role {
+    /// Bound into each proof, so a proof can't be reflected back at its sender
+    fn tag(self) -> &'static [u8] {
+        match self {
+            Role::Dialer => b"lethe-sync/1 dialer",
+            Role::Listener => b"lethe-sync/1 listener",
+        }
+    }
+
+    fn peer(self) -> Role {
+        match self {
+            Role::Dialer => Role::Listener,
+            Role::Listener => Role::Dialer,
+        }
+    }
+}
+
+/// One side's vault, unlocked
+pub struct Local {
+    pub vault_path: PathBuf,
+    pub key: MasterKey,
+    pub bwlimit: BwLimit,
+}
+
+#[derive(Default)]
+struct Transfer {
+    blocks: usize,
+    bytes: u64,
+    missing: usize,
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(stream: &mut W, msg: &Message) -> Result<()> {
+    let body = serde_cbor::to_vec(msg).context("Failed to encode sync message")?;
+    stream.write_u32(body.len() as u32).await?;
+    stream.write_all(&body).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(stream: &mut R, limit: u32) -> Result<Message> {
+    let len = stream.read_u32().await.context("Peer closed the connection")?;
+    if len > limit {
+        anyhow::bail!("Sync frame too large ({} bytes)", len);
+    }
+    let mut body = vec![0u8; len as usize];
+    stream.read_exact(&mut body).await?;
+    serde_cbor::from_slice(&body).context("Malformed sync message")
+}
+
+/// Sends `msg` and returns the peer's message for the same step. The dialer
+/// always writes first, so two large frames never wait on each other.
+async fn exchange(stream: &mut TcpStream, role: Role, msg: &Message, limit: u32) -> Result<Message> {
+    match role {
+        Role::Dialer => {
+            write_frame(stream, msg).await?;
+            read_frame(stream, limit).await
+        }
+        Role::Listener => {
+            let reply = read_frame(stream, limit).await?;
+            write_frame(stream, msg).await?;
+            Ok(reply)
+        }
+    }
+}
+
+fn unexpected(msg: Message) -> anyhow::Error {
+    match msg {
+        Message::Refused(reason) => anyhow::anyhow!("Peer refused: {}", reason),
+        other => anyhow::anyhow!("Unexpected message from peer: {:?}", other),
+    }
+}
+
+fn prove(challenge: &[u8], role: Role, key: &MasterKey) -> Result<Vec<u8>> {
+    let (ciphertext, mut sealed) = CryptoEngine::encrypt(&[role.tag(), challenge].concat(), key)?;
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+fn verify(sealed: &[u8], challenge: &[u8], role: Role, key: &MasterKey) -> bool {
+    if sealed.len() < 24 {
+        return false;
+    }
+    let (nonce, ciphertext) = sealed.split_at(24);
+    CryptoEngine::decrypt(ciphertext, nonce, key)
+        .map(|plain| plain == [role.tag(), challenge].concat())
+        .unwrap_or(false)
+}
+
+// --- Session ---
+
+async fn session(mut stream: TcpStream, role: Role, local: &Local) -> Result<()> {
+    let _claim = claim(&local.vault_path, "lethe sync-peer")?;
+    let key = &local.key;
+    let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+    let storage = BlockManager::new(&local.vault_path)?;
+    let vault_header = VaultHeader::load(&local.vault_path)?;
+    let salt = vault_header.salt().to_string();
+    let capabilities = Capabilities::local(&vault_header);
+
+    // 1. Introduce ourselves, then prove we hold the key
+    let mut challenge = vec![0u8; CHALLENGE_LEN];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    let hello = Message::Hello {
+        protocol: PROTOCOL,
+        salt: salt.clone(),
+        challenge: challenge.clone(),
+        keyring: Keyring::load(&local.vault_path, key)?.map(|ring| ring.seal(key)).transpose()?,
+        capabilities: Some(capabilities.clone()),
+    };
+    let (their_challenge, their_keyring, their_capabilities) = match exchange(&mut stream, role, &hello, HANDSHAKE_FRAME).await? {
+        Message::Hello { protocol, .. } if protocol != PROTOCOL => {
+            anyhow::bail!("Peer speaks sync protocol {}, this build speaks {}", protocol, PROTOCOL)
+        }
+        Message::Hello { salt: theirs, .. } if theirs != salt => {
+            anyhow::bail!("Peer holds a different vault")
+        }
+        Message::Hello { challenge, keyring, capabilities, .. } => (challenge, keyring, capabilities.unwrap_or_else(Capabilities::legacy)),
+        other => return Err(unexpected(other)),
+    };
+    let agreed = match negotiate(&capabilities, &their_capabilities) {
+        Ok(agreed) => agreed,
+        Err(e) => {
+            // The same reason, as the peer sees it
+            let theirs = negotiate(&their_capabilities, &capabilities).err().unwrap_or_else(|| anyhow::anyhow!("{}", e));
+            let _ = exchange(&mut stream, role, &Message::Refused(theirs.to_string()), HANDSHAKE_FRAME).await;
+            return Err(e);
+        }
+    };
+    let newer: Vec<&str> = their_capabilities.uses.iter()
+        .map(String::as_str)
+        .filter(|f| !capabilities.uses.contains(*f) && [header::FEATURE_CONTENT_IDS, header::FEATURE_SPLIT_INDEX].contains(f))
+        .collect();
+    if !newer.is_empty() {
+        println!("Note: the peer's copy uses {} and this one doesn't; `lethe upgrade` here brings it level.", newer.join(", "));
+    }
+    if capabilities.uses.contains(header::FEATURE_SPLIT_INDEX) && !agreed.split_index {
+        println!("Note: the peer's build of Lethe reads only whole indexes; sending it one.");
+    }
+    let their_keyring = their_keyring
+        .map(|sealed| Keyring::open_sealed(&sealed, key))
+        .transpose()
+        .context("Peer sent an unreadable keyring")?;
+
+    // Our index opens under the keyring both sides settle on, or we aren't
+    // an enrolled device and stop here
+    let opened = super::newer_keyring(&local.vault_path, their_keyring.as_ref(), key)
+        .and_then(|newer| super::load_index(&local.vault_path, newer, key));
+    let (mut index_mgr, newer_keyring) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            let refusal = Message::Refused("This device is not enrolled in the vault's keyring".to_string());
+            let _ = exchange(&mut stream, role, &refusal, HANDSHAKE_FRAME).await;
+            return Err(e);
+        }
+    };
+    let proof_key = index_mgr.sealing_key(key);
+
+    let proof = Message::Proof(prove(&their_challenge, role, proof_key)?);
+    let trusted = match exchange(&mut stream, role, &proof, HANDSHAKE_FRAME).await? {
+        Message::Proof(sealed) => verify(&sealed, &challenge, role.peer(), proof_key),
+        other => return Err(unexpected(other)),
+    };
+    if let (true, Some(ring)) = (trusted, &newer_keyring) {
+        super::adopt_keyring(&index_mgr, ring, key)?;
+    }
+
+    // 2. Swap indexes (or tell an impostor why we stop here)
+    let have: HashSet<String> = storage.list_blocks()?.into_iter().map(|(id, _)| id).collect();
+    let offer = if trusted {
+        let index = if agreed.split_index { index_mgr.seal(key)? } else { index_mgr.seal_whole(key)? };
+        Message::Offer { index, blocks: have.iter().cloned().collect() }
+    } else {
+        Message::Refused("Key proof failed".to_string())
+    };
+    let reply = exchange(&mut stream, role, &offer, CONTROL_FRAME).await?;
+    if !trusted {
+        anyhow::bail!("Peer could not prove it holds the vault key (or is an enrolled device)");
+    }
+    let (remote, theirs) = match reply {
+        Message::Offer { index, blocks } => {
+            let remote = IndexManager::open_sealed(&index, index_mgr.sealing_key(key))
+                .context("Peer sent an unreadable index")?;
+            (remote, blocks.into_iter().collect::<HashSet<String>>())
+        }
+        other => return Err(unexpected(other)),
+    };
+
+    // 3. Ask for whatever the merged index needs and we lack
+    let merged = merge::merge(&index_mgr.data, &remote);
+    let mut want = Vec::new();
+    let mut lost = 0;
+    for id in merged.index.referenced_blocks() {
+        if have.contains(id) {
+            continue;
+        }
+        if !theirs.contains(id) {
+            lost += 1;
+            continue;
+        }
+        let held = tokio::fs::metadata(storage.partial_path(id)?).await.map(|m| m.len()).unwrap_or(0);
+        want.push((id.to_string(), held));
+    }
+    if lost > 0 {
+        println!("WARNING: {} block(s) referenced by the index exist on neither side.", lost);
+    }
+
+    let their_want = match exchange(&mut stream, role, &Message::Want(want.clone()), CONTROL_FRAME).await? {
+        Message::Want(w) => w,
+        other => return Err(unexpected(other)),
+    };
+
+    // 4. Transfer both directions at once
+    let (mut reader, mut writer) = stream.split();
+    let (sent, received) = tokio::join!(
+        send_blocks(&mut writer, &storage, their_want, Throttle::new(local.bwlimit.clone())),
+        receive_blocks(&mut reader, &storage, &want, key, Throttle::new(local.bwlimit.clone())),
+    );
+    let (sent, received) = (sent?, received?);
+    if sent.blocks > 0 {
+        println!("   Sent {} block(s) ({})", sent.blocks, humansize::format_size(sent.bytes, humansize::BINARY));
+    }
+    if received.blocks > 0 {
+        println!("   Received {} block(s) ({})", received.blocks, humansize::format_size(received.bytes, humansize::BINARY));
+    }
+
+    // 5. Save the merged index
+    if !merged.differs_from(&index_mgr.data) {
+        println!("Sync complete. Nothing new from the peer.");
+        super::audit_sync(&local.vault_path, key, "sync-peer", &peer);
+        return Ok(());
+    }
+    if received.missing > 0 {
+        anyhow::bail!("Peer lost {} block(s) mid-sync; index left unchanged", received.missing);
+    }
+    super::report_conflicts(&merged.conflicts);
+    let before = index_mgr.data.files.len();
+    index_mgr.replace(merged.index, key)?;
+    println!("Sync complete. Merged the peer's changes ({} -> {} entries).", before, index_mgr.data.files.len());
+    super::audit_sync(&local.vault_path, key, "sync-peer", &peer);
+    Ok(())
+}
+
+async fn send_blocks<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    storage: &BlockManager,
+    want: Vec<(String, u64)>,
+    mut throttle: Throttle,
+) -> Result<Transfer> {
+    let mut sent = Transfer::default();
+    for (id, offset) in want {
+        let mut file = match File::open(storage.block_path(&id)?).await {
+            Ok(f) => f,
+            Err(_) => {
+                sent.missing += 1;
+                write_frame(writer, &Message::Missing(id)).await?;
+                continue;
+            }
+        };
+        let size = file.metadata().await?.len();
+        // A partial longer than the block isn't a prefix of it; start over
+        let offset = if offset > size { 0 } else { offset };
+        file.seek(SeekFrom::Start(offset)).await?;
+
+        let len = size - offset;
+        write_frame(writer, &Message::Block { id, offset, len }).await?;
+        let copied = bwlimit::copy_async(&mut file, writer, len, &mut throttle).await?;
+        if copied != len {
+            anyhow::bail!("Block shrank while it was being sent");
+        }
+        sent.blocks += 1;
+        sent.bytes += len;
+    }
+    write_frame(writer, &Message::Done).await?;
+    Ok(sent)
+}
+
+async fn receive_blocks<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    storage: &BlockManager,
+    want: &[(String, u64)],
+    key: &MasterKey,
+    mut throttle: Throttle,
+) -> Result<Transfer> {
+    let mut pending: HashSet<&str> = want.iter().map(|(id, _)| id.as_str()).collect();
+    let mut received = Transfer::default();
+    loop {
+        match read_frame(reader, CONTROL_FRAME).await? {
+            Message::Block { id, offset, len } => {
+                if !pending.remove(id.as_str()) {
+                    anyhow::bail!("Peer sent block {} that was not asked for", id);
+                }
+                let partial = storage.partial_path(&id)?;
+                let mut file = OpenOptions::new().create(true).write(true).truncate(false).open(&partial).await
+                    .context("Failed to open partial block")?;
+                file.set_len(offset).await?;
+                file.seek(SeekFrom::Start(offset)).await?;
+
+                let copied = bwlimit::copy_async(reader, &mut file, len, &mut throttle).await?;
+                file.sync_all().await?;
+                drop(file);
+                if copied != len {
+                    anyhow::bail!("Connection lost while receiving a block; run the sync again to resume");
+                }
+
+                tokio::task::block_in_place(|| storage.commit_partial(&id, key))?;
+                received.blocks += 1;
+                received.bytes += len;
+            }
+            Message::Missing(id) => {
+                pending.remove(id.as_str());
+                received.missing += 1;
+            }
+            Message::Done => break,
+            other => return Err(unexpected(other)),
+        }
+    }
+    received.missing += pending.len();
+    Ok(received)
+}
+
+// --- Entry points ---
+
+/// `host` or `host:port`, with the default port filled in
+pub fn address(addr: &str) -> String {
+    if addr.contains(':') { addr.to_string() } else { format!("{}:{}", addr, DEFAULT_PORT) }
+}
+
+/// Connects to a listening peer and syncs once
+pub async fn dial(addr: &str, local: &Local) -> Result<()> {
+    let addr = address(addr);
+    let result = async {
+        let stream = TcpStream::connect(&addr).await
+            .with_context(|| format!("Could not reach peer at {}", addr))?;
+        println!("Syncing with {}...", addr);
+        session(stream, Role::Dialer, local).await
+    }.await;
+    match &result {
+        Ok(()) => health::reached(&local.vault_path, &local.key, "peer", &addr),
+        Err(e) => health::failed(&local.vault_path, &local.key, "peer", &addr, e),
+    }
+    result
+}
+
+/// Accepts peers one at a time until Ctrl+C
+pub async fn listen(addr: SocketAddr, local: &Local) -> Result<()> {
+    let listener = TcpListener::bind(addr).await
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    println!("Waiting for peers on {} (Ctrl+C to stop)", addr);
+
+    let shutdown = sentinel::shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = &mut shutdown => break,
+        };
+        println!("Syncing with {}...", peer);
+        if let Err(e) = session(stream, Role::Listener, local).await {
+            println!("Sync with {} failed: {:#}", peer, e);
+        }
+    }
+    Ok(())
+}
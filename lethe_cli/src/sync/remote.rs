@@ -0,0 +1,681 @@
+//! `lethe push` / `lethe pull`: replication to a plain copy of the vault
+//! somewhere else, for off-site backups.
+//!
+//! The remote is just a vault directory (header, index replicas, keyring,
+//! blocks) and never sees the key. Its spec starts with the backend that
+//! holds it (see `BACKENDS`):
+//!
+//! - `ssh://[user@]host[:port]/path` or `sftp:[user@]host:/path`, reached
+//!   with the system `ssh` and `sftp` (`ssh://host/~/vault` or
+//!   `sftp:host:vault` for a path under the remote home)
+//! - `s3:bucket/prefix` and `webdav:https://host/path` (see `cloud`)
+//! - any other directory, e.g. a NAS share or a synced cloud folder
+//!
+//! Blocks go first and the index last, each under a temporary name that is
+//! renamed once complete, so an interrupted run leaves the far side at its
+//! previous revision. Running it again skips the blocks that made it.
+//!
+//! The index can live apart from the blocks (`--index`, or `lethe remote
+//! set --index`): the index replicas, keyring and header then go to a
+//! second remote as well, say a small host you trust more, and are read
+//! from there first. The block remote keeps its own copy of them, so
+//! either remote on its own still holds a vault that can be pulled.
+//!
+//! A push never merges: it refuses to overwrite a copy holding changes this
+//! one hasn't seen. A pull merges them in. `lethe scrub` checks that a
+//! remote still holds every block its index names, without changing it.
+//!
+//! `--bwlimit` paces folder copies directly and is passed to `sftp -l`,
+//! re-read at every batch so a timetable takes effect between batches.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+use rand::RngCore;
+
+use lethe_core::crypto::{CryptoEngine, MasterKey};
+use lethe_core::header::{VaultHeader, HEADER_FILE, LEGACY_SALT_FILE};
+use lethe_core::index::{IndexManager, VaultIndex};
+use lethe_core::keyring::{self, IndexKey, Keyring, KEYRING_FILE};
+use lethe_core::merge;
+use lethe_core::storage::BlockManager;
+
+use super::bwlimit::{self, BwLimit, Throttle};
+use super::cloud::{WebDav, S3};
+use super::health;
+
+/// Files per `sftp` session / progress line
+const BATCH: usize = 64;
+
+const INDEX_REPLICAS: usize = 3;
+
+/// Somewhere a copy of the vault directory lives
+pub trait Remote {
+    fn describe(&self) -> String;
+    /// File names in the remote vault directory (empty if it doesn't exist yet)
+    fn list(&self) -> Result<Vec<String>>;
+    /// Copies remote files to local paths
+    fn download(&self, files: &[(String, PathBuf)]) -> Result<()>;
+    /// Copies local files in under the given names, replacing existing ones
+    fn upload(&self, files: &[(PathBuf, String)]) -> Result<()>;
+}
+
+// --- Backends ---
+
+/// Opens a spec with the backend's prefix taken off
+type Opener = fn(&str, RefCell<Throttle>) -> Result<Box<dyn Remote>>;
+
+/// A kind of place a remote can be, picked by the prefix of its spec
+struct Backend {
+    prefix: &'static str,
+    usage: &'static str,
+    open: Opener,
+}
+
+const BACKENDS: &[Backend] = &[
+    Backend { prefix: "ssh://", usage: "ssh://[user@]host[:port]/path", open: open_ssh },
+    Backend { prefix: "sftp:", usage: "sftp:[user@]host:/path", open: open_sftp },
+    Backend { prefix: "s3:", usage: "s3:bucket/prefix", open: open_s3 },
+    Backend { prefix: "webdav:", usage: "webdav:https://host/path", open: open_webdav },
+    Backend { prefix: "file://", usage: "file:///path", open: open_dir },
+];
+
+fn open_ssh(rest: &str, throttle: RefCell<Throttle>) -> Result<Box<dyn Remote>> {
+    Ok(Box::new(Ssh::parse(rest, throttle)?))
+}
+
+fn open_sftp(rest: &str, throttle: RefCell<Throttle>) -> Result<Box<dyn Remote>> {
+    Ok(Box::new(Ssh::parse_scp(rest, throttle)?))
+}
+
+fn open_s3(rest: &str, throttle: RefCell<Throttle>) -> Result<Box<dyn Remote>> {
+    Ok(Box::new(S3::parse(rest, throttle)?))
+}
+
+fn open_webdav(rest: &str, throttle: RefCell<Throttle>) -> Result<Box<dyn Remote>> {
+    Ok(Box::new(WebDav::parse(rest, throttle)?))
+}
+
+fn open_dir(rest: &str, throttle: RefCell<Throttle>) -> Result<Box<dyn Remote>> {
+    Ok(Box::new(Dir { root: PathBuf::from(rest), throttle }))
+}
+
+/// What a remote spec may look like, for help and errors
+pub fn usage() -> String {
+    let specs: Vec<&str> = BACKENDS.iter().map(|b| b.usage).collect();
+    format!("{} or a folder", specs.join(", "))
+}
+
+/// The `name:` a spec starts with, if it looks like a backend rather than
+/// a path (a Windows drive letter or an existing folder doesn't)
+fn scheme(spec: &str) -> Option<&str> {
+    let (name, _) = spec.split_once(':')?;
+    let named = name.len() > 1 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-');
+    (named && !Path::new(spec).exists()).then_some(name)
+}
+
+/// Whether `spec` names a remote rather than a folder on this machine
+pub fn is_remote(spec: &str) -> bool {
+    BACKENDS.iter().any(|b| b.prefix != "file://" && spec.starts_with(b.prefix))
+}
+
+/// Parses a remote spec (see the module docs)
+pub fn open(spec: &str, limit: BwLimit) -> Result<Box<dyn Remote>> {
+    let throttle = RefCell::new(Throttle::new(limit));
+    if let Some(backend) = BACKENDS.iter().find(|b| spec.starts_with(b.prefix)) {
+        return (backend.open)(&spec[backend.prefix.len()..], throttle);
+    }
+    if let Some(name) = scheme(spec) {
+        anyhow::bail!("Unknown backend {:?} in {:?}. Expected {}.", name, spec, usage());
+    }
+    open_dir(spec, throttle)
+}
+
+// --- Directory ---
+
+struct Dir {
+    root: PathBuf,
+    throttle: RefCell<Throttle>,
+}
+
+impl Remote for Dir {
+    fn describe(&self) -> String {
+        self.root.display().to_string()
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        if !self.root.exists() {
+            return Ok(Vec::new());
+        }
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.root).with_context(|| format!("Failed to read {:?}", self.root))? {
+            if let Some(name) = entry?.file_name().to_str() {
+                names.push(name.to_string());
+            }
+        }
+        Ok(names)
+    }
+
+    fn download(&self, files: &[(String, PathBuf)]) -> Result<()> {
+        let mut throttle = self.throttle.borrow_mut();
+        for (name, local) in files {
+            bwlimit::copy_file(&self.root.join(name), local, &mut throttle)
+                .with_context(|| format!("Failed to copy {}", name))?;
+        }
+        Ok(())
+    }
+
+    fn upload(&self, files: &[(PathBuf, String)]) -> Result<()> {
+        fs::create_dir_all(&self.root).with_context(|| format!("Failed to create {:?}", self.root))?;
+        let mut throttle = self.throttle.borrow_mut();
+        for (local, name) in files {
+            let tmp = self.root.join(format!("{}.tmp", name));
+            bwlimit::copy_file(local, &tmp, &mut throttle).with_context(|| format!("Failed to copy {}", name))?;
+            fs::rename(&tmp, self.root.join(name))?;
+        }
+        Ok(())
+    }
+}
+
+// --- Split ---
+
+/// Blocks on one remote; the index, keyring and salt on both, read from
+/// the index remote first
+struct Split {
+    blocks: Box<dyn Remote>,
+    index: Box<dyn Remote>,
+}
+
+/// Opens `spec`, with the index also on `index` if given
+pub fn open_split(spec: &str, index: Option<&str>, limit: BwLimit) -> Result<Box<dyn Remote>> {
+    let blocks = open(spec, limit.clone())?;
+    match index {
+        Some(index) => Ok(Box::new(Split { blocks, index: open(index, limit)? })),
+        None => Ok(blocks),
+    }
+}
+
+fn is_block(name: &str) -> bool {
+    name.starts_with("blk_")
+}
+
+impl Remote for Split {
+    fn describe(&self) -> String {
+        format!("{} (index on {})", self.blocks.describe(), self.index.describe())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut names = self.blocks.list()?;
+        for name in self.index.list()? {
+            if !is_block(&name) && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+
+    fn download(&self, files: &[(String, PathBuf)]) -> Result<()> {
+        let (blocks, meta): (Vec<_>, Vec<_>) = files.iter().cloned().partition(|(name, _)| is_block(name));
+        if !meta.is_empty() && self.index.download(&meta).is_err() {
+            self.blocks.download(&meta)
+                .with_context(|| format!("Neither {} nor {} has the index", self.index.describe(), self.blocks.describe()))?;
+        }
+        if !blocks.is_empty() {
+            self.blocks.download(&blocks)?;
+        }
+        Ok(())
+    }
+
+    fn upload(&self, files: &[(PathBuf, String)]) -> Result<()> {
+        let (blocks, meta): (Vec<_>, Vec<_>) = files.iter().cloned().partition(|(_, name)| is_block(name));
+        if !blocks.is_empty() {
+            self.blocks.upload(&blocks)?;
+        }
+        if !meta.is_empty() {
+            self.blocks.upload(&meta)?;
+            self.index.upload(&meta)?;
+        }
+        Ok(())
+    }
+}
+
+// --- SSH ---
+
+struct Ssh {
+    /// `[user@]host`
+    target: String,
+    port: Option<u16>,
+    path: String,
+    throttle: RefCell<Throttle>,
+}
+
+impl Ssh {
+    fn parse(rest: &str, throttle: RefCell<Throttle>) -> Result<Self> {
+        let (authority, path) = rest.split_once('/')
+            .with_context(|| format!("Expected ssh://[user@]host[:port]/path, got ssh://{}", rest))?;
+        let (target, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port.parse().with_context(|| format!("Invalid SSH port: {}", port))?;
+                (host.to_string(), Some(port))
+            }
+            None => (authority.to_string(), None),
+        };
+        // `ssh://host/~/vault` is relative to the remote home
+        let path = match path.strip_prefix("~/") {
+            Some(home) => home.trim_end_matches('/').to_string(),
+            None => format!("/{}", path.trim_end_matches('/')),
+        };
+        if target.is_empty() || path.is_empty() {
+            anyhow::bail!("Expected ssh://[user@]host[:port]/path, got ssh://{}", rest);
+        }
+        Ok(Self { target, port, path, throttle })
+    }
+
+    /// `[user@]host:path`, as scp and rclone write it; a path without a
+    /// leading '/' is under the remote home
+    fn parse_scp(rest: &str, throttle: RefCell<Throttle>) -> Result<Self> {
+        let (target, path) = rest.split_once(':')
+            .with_context(|| format!("Expected sftp:[user@]host:/path, got sftp:{}", rest))?;
+        let path = path.strip_prefix("~/").unwrap_or(path).trim_end_matches('/').to_string();
+        if target.is_empty() || path.is_empty() || target.contains('/') {
+            anyhow::bail!("Expected sftp:[user@]host:/path, got sftp:{} (use ssh://host:port/path for a port)", rest);
+        }
+        Ok(Self { target: target.to_string(), port: None, path, throttle })
+    }
+
+    fn remote_file(&self, name: &str) -> String {
+        format!("{}/{}", self.path, name)
+    }
+
+    /// Runs an `sftp` batch; any failing command aborts it
+    fn sftp(&self, commands: &str) -> Result<()> {
+        let mut cmd = Command::new("sftp");
+        if let Some(port) = self.port {
+            cmd.arg("-P").arg(port.to_string());
+        }
+        // Waits out a pause, then hands the current rate to sftp (Kbit/s)
+        let mut throttle = self.throttle.borrow_mut();
+        throttle.pace(0);
+        if let Some(rate) = throttle.limit().rate_now() {
+            cmd.arg("-l").arg((rate * 8 / 1000).max(1).to_string());
+        }
+        let mut child = cmd.args(["-q", "-b", "-"]).arg(&self.target)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .context("Failed to run sftp (is OpenSSH installed?)")?;
+        child.stdin.take().context("sftp has no stdin")?.write_all(commands.as_bytes())?;
+        if !child.wait()?.success() {
+            anyhow::bail!("sftp to {} failed", self.target);
+        }
+        Ok(())
+    }
+}
+
+/// Single-quotes `s` for the remote POSIX shell
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Double-quotes a path for an sftp batch line
+fn sftp_quote(path: &str) -> String {
+    format!("\"{}\"", path.replace('\\', "/").replace('"', "\\\""))
+}
+
+impl Remote for Ssh {
+    fn describe(&self) -> String {
+        format!("{}:{}", self.target, self.path)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let dir = shell_quote(&self.path);
+        let mut cmd = Command::new("ssh");
+        if let Some(port) = self.port {
+            cmd.arg("-p").arg(port.to_string());
+        }
+        let output = cmd.arg(&self.target)
+            .arg(format!("if [ -d {dir} ]; then ls -1a -- {dir}; fi"))
+            .stderr(Stdio::inherit())
+            .output()
+            .context("Failed to run ssh (is OpenSSH installed?)")?;
+        if !output.status.success() {
+            anyhow::bail!("Could not list {} on {}", self.path, self.target);
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).lines()
+            .filter(|l| *l != "." && *l != "..")
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn download(&self, files: &[(String, PathBuf)]) -> Result<()> {
+        let mut batch = String::new();
+        for (name, local) in files {
+            let local = local.to_string_lossy();
+            batch.push_str(&format!("get {} {}\n", sftp_quote(&self.remote_file(name)), sftp_quote(&local)));
+        }
+        self.sftp(&batch)
+    }
+
+    fn upload(&self, files: &[(PathBuf, String)]) -> Result<()> {
+        // A leading '-' lets the batch go on when the folder already exists
+        let mut batch = format!("-mkdir {}\n", sftp_quote(&self.path));
+        for (local, name) in files {
+            let tmp = self.remote_file(&format!("{}.tmp", name));
+            batch.push_str(&format!("put {} {}\n", sftp_quote(&local.to_string_lossy()), sftp_quote(&tmp)));
+            batch.push_str(&format!("rename {} {}\n", sftp_quote(&tmp), sftp_quote(&self.remote_file(name))));
+        }
+        self.sftp(&batch)
+    }
+}
+
+// --- Replication ---
+
+/// Local working directory, removed again on drop
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new() -> Result<Self> {
+        let dir = std::env::temp_dir().join(format!("lethe-{:016x}", rand::thread_rng().next_u64()));
+        fs::create_dir_all(&dir).context("Failed to create a temporary directory")?;
+        Ok(Self(dir))
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn block_name(id: &str) -> String {
+    format!("blk_{}.bin", id)
+}
+
+fn remote_blocks(names: &[String]) -> HashSet<&str> {
+    names.iter()
+        .filter_map(|n| n.strip_prefix("blk_").and_then(|n| n.strip_suffix(".bin")))
+        .collect()
+}
+
+/// Whether a remote listing holds a vault, with a header or from before one
+pub fn holds_vault(names: &[String]) -> bool {
+    names.iter().any(|n| n == HEADER_FILE || n == LEGACY_SALT_FILE)
+}
+
+/// The salt of the vault a downloaded header or `salt.loader` belongs to
+fn remote_salt(scratch: &Path, names: &[String]) -> Result<String> {
+    if names.iter().any(|n| n == HEADER_FILE) {
+        let bytes = fs::read(scratch.join(HEADER_FILE)).context("Failed to read remote header")?;
+        return Ok(VaultHeader::parse(&bytes)?.salt().to_string());
+    }
+    let salt = fs::read_to_string(scratch.join(LEGACY_SALT_FILE)).context("Failed to read remote salt")?;
+    Ok(salt.trim().to_string())
+}
+
+/// The remote copy of the vault, as far as a sync needs it
+struct Fetched {
+    index: VaultIndex,
+    keyring: Option<Keyring>,
+    /// The keyring to settle on, when it isn't this copy's own
+    newer_keyring: Option<(Keyring, IndexKey)>,
+}
+
+/// Fetches and opens the remote index; None if the remote holds no vault yet
+fn remote_index(remote: &dyn Remote, names: &[String], vault_path: &Path, key: &MasterKey) -> Result<Option<Fetched>> {
+    if !holds_vault(names) {
+        return Ok(None);
+    }
+    let scratch = Scratch::new()?;
+
+    // 1. Same vault?
+    let header = if names.iter().any(|n| n == HEADER_FILE) { HEADER_FILE } else { LEGACY_SALT_FILE };
+    let mut files = vec![(header.to_string(), scratch.0.join(header))];
+    for i in 0..INDEX_REPLICAS {
+        let name = format!("meta_{}.bin", i);
+        if names.contains(&name) {
+            files.push((name.clone(), scratch.0.join(name)));
+        }
+    }
+    if names.iter().any(|n| n == KEYRING_FILE) {
+        files.push((KEYRING_FILE.to_string(), scratch.0.join(KEYRING_FILE)));
+    }
+    remote.download(&files)?;
+    let remote_salt = remote_salt(&scratch.0, names)
+        .with_context(|| format!("{} has an unreadable vault header", remote.describe()))?;
+    if VaultHeader::load(vault_path)?.salt() != remote_salt {
+        anyhow::bail!("{} holds a different vault", remote.describe());
+    }
+
+    // 2. The keyring both copies settle on; only its devices may sync
+    let keyring = Keyring::load(&scratch.0, key)
+        .with_context(|| format!("{} has an unreadable keyring", remote.describe()))?;
+    let newer_keyring = super::newer_keyring(vault_path, keyring.as_ref(), key)?;
+
+    // 3. Newest replica that opens
+    let local_key;
+    let index_key = match &newer_keyring {
+        Some((_, index_key)) => Some(index_key),
+        None => {
+            local_key = keyring::index_key(vault_path, key)?;
+            local_key.as_ref()
+        }
+    };
+    let index = IndexManager::read_replicas(&scratch.0, key, index_key)
+        .with_context(|| format!("{} has no readable index", remote.describe()))?;
+    Ok(Some(Fetched { index, keyring, newer_keyring }))
+}
+
+/// Uploads whatever the local index needs, then the index itself
+pub fn push(remote: &dyn Remote, vault_path: &Path, key: &MasterKey, force: bool) -> Result<()> {
+    let names = health::list(vault_path, key, remote)?;
+
+    let (index_mgr, theirs, their_keyring) = match remote_index(remote, &names, vault_path, key)? {
+        Some(fetched) => {
+            let (index_mgr, newer_keyring) = super::load_index(vault_path, fetched.newer_keyring, key)?;
+            if let Some(ring) = &newer_keyring {
+                super::adopt_keyring(&index_mgr, ring, key)?;
+            }
+            (index_mgr, Some(fetched.index), fetched.keyring)
+        }
+        None => (IndexManager::load(vault_path.to_path_buf(), key)?, None, None),
+    };
+    let keyring = Keyring::load(vault_path, key)?;
+    let keyring_stale = keyring.is_some() && keyring != their_keyring;
+
+    match theirs {
+        // Copies from before vector clocks only have their revision to go by
+        Some(theirs) if !force && (!index_mgr.data.covers(&theirs)
+            || (theirs.clock.is_empty() && theirs.revision > index_mgr.data.revision)) => {
+            anyhow::bail!(
+                "{} has changes this copy hasn't seen. Pull first, or pass --force to overwrite them.",
+                remote.describe()
+            );
+        }
+        Some(theirs) if theirs.covers(&index_mgr.data) && !force && !keyring_stale => {
+            println!("{} is already up to date.", remote.describe());
+            return Ok(());
+        }
+        Some(_) => {}
+        None => println!("Creating a new copy at {}", remote.describe()),
+    }
+    // New copies, and copies made before the header, get this one's
+    if !names.iter().any(|n| n == HEADER_FILE) {
+        remote.upload(&[(vault_path.join(HEADER_FILE), HEADER_FILE.to_string())])?;
+    }
+
+    // 1. Blocks the remote lacks
+    let theirs = remote_blocks(&names);
+    let storage = BlockManager::new(vault_path)?;
+    let mut missing: Vec<(PathBuf, String)> = Vec::new();
+    for id in index_mgr.referenced_blocks() {
+        if !theirs.contains(id) {
+            missing.push((storage.block_path(id)?, block_name(id)));
+        }
+    }
+    missing.sort();
+
+    let total = missing.len();
+    for (done, batch) in missing.chunks(BATCH).enumerate() {
+        remote.upload(batch)?;
+        println!("   Uploaded {}/{} blocks", (done * BATCH + batch.len()).min(total), total);
+    }
+
+    // 2. The keyring, then the index, once every block it names is there
+    if keyring_stale {
+        remote.upload(&[(vault_path.join(KEYRING_FILE), KEYRING_FILE.to_string())])?;
+    }
+    let scratch = Scratch::new()?;
+    let sealed = index_mgr.seal(key)?;
+    let mut replicas = Vec::new();
+    for i in 0..INDEX_REPLICAS {
+        let name = format!("meta_{}.bin", i);
+        let local = scratch.0.join(&name);
+        fs::write(&local, &sealed)?;
+        replicas.push((local, name));
+    }
+    remote.upload(&replicas)?;
+
+    println!("Push complete.");
+    super::audit_sync(vault_path, key, "push", &remote.describe());
+    Ok(())
+}
+
+/// Merges the remote index in, after downloading the blocks it needs.
+/// With `force` the remote index replaces the local one instead.
+pub fn pull(remote: &dyn Remote, vault_path: &Path, key: &MasterKey, force: bool) -> Result<()> {
+    let names = health::list(vault_path, key, remote)?;
+
+    let Some(fetched) = remote_index(remote, &names, vault_path, key)? else {
+        anyhow::bail!("No vault found at {}", remote.describe());
+    };
+    let (mut index_mgr, newer_keyring) = super::load_index(vault_path, fetched.newer_keyring, key)?;
+    if let Some(ring) = &newer_keyring {
+        super::adopt_keyring(&index_mgr, ring, key)?;
+    }
+    let theirs = fetched.index;
+    let (theirs, conflicts) = if force {
+        println!("WARNING: Replacing the local index with the remote one (--force).");
+        (theirs, Vec::new())
+    } else {
+        let merged = merge::merge(&index_mgr.data, &theirs);
+        if !merged.differs_from(&index_mgr.data) {
+            println!("Already up to date.");
+            return Ok(());
+        }
+        (merged.index, merged.conflicts)
+    };
+
+    // 1. Blocks we lack, verified as they land
+    let available = remote_blocks(&names);
+    let storage = BlockManager::new(vault_path)?;
+    let mut missing: Vec<String> = Vec::new();
+    let mut lost = 0;
+    for id in theirs.referenced_blocks() {
+        if storage.has_block(id) {
+            continue;
+        }
+        if available.contains(id) {
+            missing.push(id.to_string());
+        } else {
+            lost += 1;
+        }
+    }
+    if lost > 0 {
+        anyhow::bail!("{} is missing {} block(s) its index needs; nothing was changed", remote.describe(), lost);
+    }
+    missing.sort();
+
+    let total = missing.len();
+    for (done, batch) in missing.chunks(BATCH).enumerate() {
+        let mut files = Vec::new();
+        for id in batch {
+            files.push((block_name(id), storage.partial_path(id)?));
+        }
+        remote.download(&files)?;
+        for id in batch {
+            storage.commit_partial(id, key)?;
+        }
+        println!("   Downloaded {}/{} blocks", (done * BATCH + batch.len()).min(total), total);
+    }
+
+    // 2. The index
+    super::report_conflicts(&conflicts);
+    index_mgr.replace(theirs, key)?;
+    println!("Pull complete.");
+    super::audit_sync(vault_path, key, "pull", &remote.describe());
+    Ok(())
+}
+
+/// What `scrub` found
+#[derive(Default)]
+pub struct Scrub {
+    /// Blocks the remote index needs
+    pub blocks: usize,
+    /// Of those, not on the remote
+    pub missing: usize,
+    /// With `deep`: on the remote but failing to decrypt
+    pub damaged: Vec<String>,
+    /// Blocks this copy needs that the remote lacks; the next push sends them
+    pub behind: usize,
+}
+
+impl Scrub {
+    pub fn is_clean(&self) -> bool {
+        self.missing == 0 && self.damaged.is_empty()
+    }
+}
+
+/// Checks that the remote still holds a vault that can be pulled: the
+/// index opens and every block it names is there. With `deep` every
+/// block is downloaded and decrypted as well. Changes nothing on either
+/// side.
+pub fn scrub(remote: &dyn Remote, vault_path: &Path, key: &MasterKey, deep: bool) -> Result<Scrub> {
+    let names = health::list(vault_path, key, remote)?;
+    let Some(fetched) = remote_index(remote, &names, vault_path, key)? else {
+        anyhow::bail!("No vault found at {}", remote.describe());
+    };
+
+    let available = remote_blocks(&names);
+    let needed: Vec<&str> = fetched.index.referenced_blocks().into_iter().collect();
+    let mut present: Vec<&str> = needed.iter().copied().filter(|id| available.contains(id)).collect();
+    present.sort_unstable();
+    let local = IndexManager::load(vault_path.to_path_buf(), key)?;
+    let mut scrub = Scrub {
+        blocks: needed.len(),
+        missing: needed.len() - present.len(),
+        damaged: Vec::new(),
+        behind: local.referenced_blocks().iter().filter(|id| !available.contains(*id)).count(),
+    };
+    println!("   Its index names {} block(s).", scrub.blocks);
+
+    if deep {
+        let scratch = Scratch::new()?;
+        let total = present.len();
+        for (done, batch) in present.chunks(BATCH).enumerate() {
+            let files: Vec<(String, PathBuf)> = batch.iter().map(|id| (block_name(id), scratch.0.join(block_name(id)))).collect();
+            remote.download(&files)?;
+            for (id, (_, path)) in batch.iter().zip(&files) {
+                let buffer = fs::read(path).unwrap_or_default();
+                let sound = buffer.len() >= 24 && {
+                    let (nonce, ciphertext) = buffer.split_at(24);
+                    CryptoEngine::decrypt(ciphertext, nonce, key).is_ok()
+                };
+                if !sound {
+                    scrub.damaged.push(id.to_string());
+                }
+                let _ = fs::remove_file(path);
+            }
+            println!("   Checked {}/{} blocks", (done * BATCH + batch.len()).min(total), total);
+        }
+    }
+
+    if scrub.is_clean() {
+        health::verified(vault_path, key, &remote.describe());
+    }
+    Ok(scrub)
+}
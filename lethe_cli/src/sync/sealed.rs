@@ -0,0 +1,58 @@
+//! Small files kept next to the index replicas about how the copy syncs,
+//! sealed like them (Nonce + Data) under a key derived from the vault key
+//! for each file, as the audit log is. The vault folder then tells nobody
+//! where it syncs to or how that has gone.
+//!
+//! Only the outer vault keeps them. Under a hidden vault's key they read
+//! as missing and writes are dropped, since a file the outer password
+//! can't open would give the hidden vault away.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use lethe_core::crypto::{CryptoEngine, MasterKey};
+use lethe_core::hidden;
+use lethe_core::storage::BlockManager;
+
+fn is_hidden(vault: &Path, key: &MasterKey) -> Result<bool> {
+    Ok(hidden::holds(&BlockManager::new(vault)?, key))
+}
+
+/// What `save` last wrote to `file`; None if it isn't there
+pub fn load<T: DeserializeOwned>(vault: &Path, file: &str, key: &MasterKey) -> Result<Option<T>> {
+    let sealed = match fs::read(vault.join(file)) {
+        Ok(sealed) => sealed,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", file)),
+    };
+    if is_hidden(vault, key)? {
+        return Ok(None);
+    }
+    if sealed.len() < 24 {
+        anyhow::bail!("{} is corrupted", file);
+    }
+    let (nonce, ciphertext) = sealed.split_at(24);
+    let plain = CryptoEngine::decrypt(ciphertext, nonce, &file_key(key, file))
+        .with_context(|| format!("{} doesn't open", file))?;
+    serde_cbor::from_slice(&plain).map(Some).with_context(|| format!("{} is corrupted", file))
+}
+
+pub fn save<T: Serialize>(vault: &Path, file: &str, key: &MasterKey, value: &T) -> Result<()> {
+    if is_hidden(vault, key)? {
+        return Ok(());
+    }
+    let (ciphertext, mut sealed) = CryptoEngine::encrypt(&serde_cbor::to_vec(value)?, &file_key(key, file))?;
+    sealed.extend_from_slice(&ciphertext);
+    let tmp = vault.join(format!("{}.tmp", file));
+    fs::write(&tmp, sealed).with_context(|| format!("Failed to write {}", file))?;
+    fs::rename(&tmp, vault.join(file))?;
+    Ok(())
+}
+
+fn file_key(key: &MasterKey, file: &str) -> MasterKey {
+    CryptoEngine::derive_subkey(key, format!("lethe {}", file).as_bytes())
+}
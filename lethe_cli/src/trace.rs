@@ -0,0 +1,28 @@
+//! `--trace-file`: the spans around unlocking, block IO, index saves and
+//! every FUSE or WebDAV request, written out as folded stacks. Feed the
+//! file to `inferno-flamegraph` (or `flamegraph.pl`) for a flamegraph.
+//!
+//! Without it no subscriber is installed and the spans cost next to nothing.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tracing_flame::{FlameLayer, FlushGuard};
+use tracing_subscriber::prelude::*;
+
+/// Writes out what is still buffered when dropped
+pub type Guard = FlushGuard<BufWriter<File>>;
+
+/// Records spans to `path` until the guard is dropped
+pub fn to_file(path: &Path) -> Result<Guard> {
+    let (layer, guard) = FlameLayer::with_file(path)
+        .with_context(|| format!("Failed to create {:?}", path))?;
+    // Mounts serve requests on several threads; one stack per operation reads better
+    tracing_subscriber::registry()
+        .with(layer.with_threads_collapsed(true))
+        .try_init()
+        .context("Failed to start tracing")?;
+    Ok(guard)
+}
@@ -0,0 +1,331 @@
+//! The vault as a filesystem, for the mount frontends: FUSE on unix and
+//! the WebDAV server (which backs mounts on Windows). What a path is, what
+//! a folder lists, what opening a file reads and how a write reaches the
+//! index are decided here once, on top of `IndexManager`. Each frontend
+//! keeps its own handles, locking and index saves, and maps `VfsError` to
+//! its protocol's codes.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use lethe_core::cache::BlockCache;
+use lethe_core::crypto::MasterKey;
+use lethe_core::index::{child_name, is_below, is_snapshot_path, FileEntry, IndexManager, Staged, SnapshotNode, SNAPSHOT_DIR};
+use lethe_core::progress::Silent;
+use lethe_core::reader::FileReader;
+use lethe_core::storage::BlockManager;
+use lethe_core::VaultConfig;
+
+use crate::spill::{CacheSpill, FileBuffer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    NotFound,
+    Exists,
+    NotEmpty,
+    IsDir,
+    NotDir,
+    /// Snapshots can't be changed
+    ReadOnly,
+    /// A write-once entry (see `IndexManager::check_mutable`)
+    Immutable,
+    /// A name the vault doesn't allow (see `IndexManager::check_new_path`)
+    BadName,
+    /// Reading or writing blocks failed; logged where it happened
+    Io,
+}
+
+/// What a path names
+#[derive(Debug, Clone, Copy)]
+pub enum Node<'a> {
+    File(&'a FileEntry),
+    Dir { modified: u64 },
+}
+
+impl Node<'_> {
+    pub fn is_dir(&self) -> bool {
+        matches!(self, Node::Dir { .. })
+    }
+
+    pub fn size(&self) -> u64 {
+        match self {
+            Node::File(e) => e.size,
+            Node::Dir { .. } => 0,
+        }
+    }
+
+    /// Unix timestamp
+    pub fn modified(&self) -> u64 {
+        match self {
+            Node::File(e) => e.modified,
+            Node::Dir { modified } => *modified,
+        }
+    }
+}
+
+/// How a file is opened, as std's `OpenOptions`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpenMode {
+    pub write: bool,
+    pub create: bool,
+    pub create_new: bool,
+    pub truncate: bool,
+}
+
+/// Where open files are read from and written to
+#[derive(Clone)]
+pub struct Backing {
+    pub storage: Arc<BlockManager>,
+    pub key: Arc<MasterKey>,
+    /// Bytes of each open file kept in RAM before the rest spills (see `spill`)
+    pub buffer_limit: usize,
+    /// Blocks read ahead of each file read in order
+    pub read_ahead: usize,
+    /// Decrypted blocks shared by every open file
+    pub cache: Option<Arc<BlockCache>>,
+}
+
+impl Backing {
+    pub fn new(storage: BlockManager, key: MasterKey, buffer_limit: usize, config: &VaultConfig) -> Self {
+        Self {
+            storage: Arc::new(storage),
+            key: Arc::new(key),
+            buffer_limit,
+            read_ahead: config.read_ahead,
+            cache: block_cache(config),
+        }
+    }
+}
+
+/// The cache `config` asks for, if any. Its disk tier goes in the temp
+/// folder (`TMPDIR`, or `TEMP` on Windows).
+pub fn block_cache(config: &VaultConfig) -> Option<Arc<BlockCache>> {
+    if config.cache_mb == 0 {
+        return None;
+    }
+    let cache = BlockCache::new(config.cache_mb.saturating_mul(1024 * 1024));
+    Some(Arc::new(match config.cache_disk_mb {
+        0 => cache,
+        mb => cache.with_overflow(CacheSpill::new(&std::env::temp_dir(), (mb as u64).saturating_mul(1024 * 1024))),
+    }))
+}
+
+/// Whether `path` may be created, logging why not
+fn check_new(index: &IndexManager, path: &str) -> Result<(), VfsError> {
+    index.check_new_path(path).map_err(|e| {
+        log::warn!("{:#}", e);
+        VfsError::BadName
+    })
+}
+
+/// Whether `path`, and anything below it, may be changed, logging why not
+fn check_mutable(index: &IndexManager, path: &str) -> Result<(), VfsError> {
+    index.check_mutable(path).map_err(|e| {
+        log::warn!("{:#}", e);
+        VfsError::Immutable
+    })
+}
+
+/// `name` in the folder `dir`
+pub fn child_path(dir: &str, name: &str) -> String {
+    match dir.trim_end_matches('/') {
+        "" => format!("/{}", name),
+        dir => format!("{}/{}", dir, name),
+    }
+}
+
+pub trait VaultVfs {
+    /// The file or folder at `path`, live or in a snapshot. A folder exists
+    /// if made with `make_dir` or if anything is in it.
+    fn node(&self, path: &str) -> Option<Node<'_>>;
+
+    /// What is in the folder `dir`, by name
+    fn list(&self, dir: &str) -> Vec<(String, Node<'_>)>;
+
+    /// A buffer over the file at `path`. Nothing is read yet: blocks are
+    /// decrypted as reads reach them.
+    fn open_file(&self, path: &str, mode: OpenMode, backing: &Backing) -> Result<FileBuffer, VfsError>;
+
+    /// Writes the blocks of `buffer` with the index shared, for
+    /// `write_back`. None for a hidden vault, which needs it held.
+    fn stage(&self, buffer: &mut FileBuffer, backing: &Backing) -> Result<Option<Staged>, VfsError>;
+
+    /// Records `buffer` as the file at `path`, from `staged` if it was, and
+    /// points the buffer at the new blocks, which frees what it held. The
+    /// caller saves the index.
+    fn write_back(&mut self, path: &str, buffer: &mut FileBuffer, staged: Option<Staged>, backing: &Backing) -> Result<(), VfsError>;
+
+    fn make_dir(&mut self, path: &str) -> Result<(), VfsError>;
+
+    fn remove_file(&mut self, path: &str) -> Result<(), VfsError>;
+
+    /// Removes a folder with nothing in it
+    fn remove_dir(&mut self, path: &str) -> Result<(), VfsError>;
+
+    /// Moves a file, or a folder with everything in it
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), VfsError>;
+}
+
+impl VaultVfs for IndexManager {
+    fn node(&self, path: &str) -> Option<Node<'_>> {
+        if path == "/" {
+            return Some(Node::Dir { modified: self.dir_modified("/") });
+        }
+        if is_snapshot_path(path) {
+            return match self.snapshot_node(path)? {
+                SnapshotNode::File(e) => Some(Node::File(e)),
+                SnapshotNode::Dir { modified } => Some(Node::Dir { modified }),
+            };
+        }
+        match self.get_file(path) {
+            Some(e) if !e.is_dir => Some(Node::File(e)),
+            Some(_) => Some(Node::Dir { modified: self.dir_modified(path) }),
+            None => self.has_children(path).then(|| Node::Dir { modified: self.dir_modified(path) }),
+        }
+    }
+
+    fn list(&self, dir: &str) -> Vec<(String, Node<'_>)> {
+        let dir = match dir.trim_end_matches('/') {
+            "" => "/",
+            dir => dir,
+        };
+        let names: Vec<String> = if is_snapshot_path(dir) {
+            self.snapshot_children(dir).into_iter().map(|(name, _)| name).collect()
+        } else {
+            let mut names = Vec::new();
+            if dir == "/" && !self.data.snapshots.is_empty() {
+                names.push(SNAPSHOT_DIR[1..].to_string());
+            }
+            let mut seen = HashSet::new();
+            for path in self.paths() {
+                if let Some(name) = child_name(path, dir) {
+                    if seen.insert(name) {
+                        names.push(name.to_string());
+                    }
+                }
+            }
+            names
+        };
+        names.into_iter()
+            .filter_map(|name| self.node(&child_path(dir, &name)).map(|node| (name, node)))
+            .collect()
+    }
+
+    fn open_file(&self, path: &str, mode: OpenMode, backing: &Backing) -> Result<FileBuffer, VfsError> {
+        // As std's OpenOptions: truncating or creating means nothing
+        // without writing
+        let entry = match self.node(path) {
+            _ if mode.write && is_snapshot_path(path) => return Err(VfsError::ReadOnly),
+            Some(Node::Dir { .. }) => return Err(VfsError::IsDir),
+            // Exclusive create, e.g. a lock file some clients rely on
+            Some(Node::File(_)) if mode.write && mode.create_new => return Err(VfsError::Exists),
+            Some(Node::File(e)) if mode.write && e.immutable => return Err(VfsError::Immutable),
+            Some(Node::File(_)) if mode.write && mode.truncate => None,
+            Some(Node::File(e)) => Some(e),
+            None if mode.write && (mode.create || mode.create_new) => {
+                check_new(self, path)?;
+                None
+            }
+            None => return Err(VfsError::NotFound),
+        };
+        Ok(match entry {
+            Some(entry) => {
+                let stored = FileReader::new(backing.storage.clone(), backing.key.clone(), entry)
+                    .read_ahead(backing.read_ahead)
+                    .cached(backing.cache.clone());
+                FileBuffer::with_base(backing.buffer_limit, self.root_path(), stored)
+            }
+            None => FileBuffer::new(backing.buffer_limit, self.root_path()),
+        })
+    }
+
+    fn stage(&self, buffer: &mut FileBuffer, backing: &Backing) -> Result<Option<Staged>, VfsError> {
+        if self.is_hidden() {
+            return Ok(None);
+        }
+        self.stage_reader(&backing.storage, &backing.key, buffer.reader())
+            .map(Some)
+            .map_err(|e| {
+                log::error!("Saving a file failed: {:#}", e);
+                VfsError::Io
+            })
+    }
+
+    fn write_back(&mut self, path: &str, buffer: &mut FileBuffer, staged: Option<Staged>, backing: &Backing) -> Result<(), VfsError> {
+        if is_snapshot_path(path) {
+            return Err(VfsError::ReadOnly);
+        }
+        check_mutable(self, path)?;
+        let stored = match staged {
+            Some(staged) => Ok(self.commit(path.to_string(), staged)),
+            None => self.store_reader(&backing.storage, &backing.key, path.to_string(), buffer.reader(), &Silent),
+        };
+        if let Err(e) = stored {
+            log::error!("Saving {} failed: {:#}", path, e);
+            return Err(VfsError::Io);
+        }
+        *buffer = self.open_file(path, OpenMode::default(), backing)?;
+        Ok(())
+    }
+
+    fn make_dir(&mut self, path: &str) -> Result<(), VfsError> {
+        if is_snapshot_path(path) {
+            return Err(VfsError::ReadOnly);
+        }
+        if self.node(path).is_some() {
+            return Err(VfsError::Exists);
+        }
+        check_new(self, path)?;
+        self.add_dir(path.to_string());
+        Ok(())
+    }
+
+    fn remove_file(&mut self, path: &str) -> Result<(), VfsError> {
+        if is_snapshot_path(path) {
+            return Err(VfsError::ReadOnly);
+        }
+        match self.node(path) {
+            Some(Node::File(_)) => {}
+            Some(Node::Dir { .. }) => return Err(VfsError::IsDir),
+            None => return Err(VfsError::NotFound),
+        }
+        check_mutable(self, path)?;
+        self.data.files.remove(path);
+        self.touch_parent(path);
+        Ok(())
+    }
+
+    fn remove_dir(&mut self, path: &str) -> Result<(), VfsError> {
+        if is_snapshot_path(path) || path == "/" {
+            return Err(VfsError::ReadOnly);
+        }
+        match self.node(path) {
+            Some(Node::Dir { .. }) if self.has_children(path) => return Err(VfsError::NotEmpty),
+            Some(Node::Dir { .. }) => {}
+            Some(Node::File(_)) => return Err(VfsError::NotDir),
+            None => return Err(VfsError::NotFound),
+        }
+        self.data.files.remove(path);
+        self.touch_parent(path);
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), VfsError> {
+        if is_snapshot_path(from) || is_snapshot_path(to) {
+            return Err(VfsError::ReadOnly);
+        }
+        check_new(self, to)?;
+        // Nor may a rename replace one
+        check_mutable(self, from)?;
+        check_mutable(self, to)?;
+        // What is in a folder moves with it, and may get too long a path
+        let (from_dir, to_dir) = (from.trim_end_matches('/'), to.trim_end_matches('/'));
+        for path in self.data.files.keys().filter(|path| is_below(path, from_dir)) {
+            check_new(self, &format!("{}{}", to_dir, &path[from_dir.len()..]))?;
+        }
+        match self.rename_tree(from, to) {
+            true => Ok(()),
+            false => Err(VfsError::NotFound),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::path::Path;
+
+/// Name shown for the mounted drive in Explorer / Finder
+pub fn volume_label(vault_path: &Path) -> String {
+    let name = vault_path.file_name()
+        .map(|n| n.to_string_lossy().trim_start_matches('.').to_string())
+        .unwrap_or_default();
+
+    // The default vault (~/.lethe_vault) keeps the plain name
+    if name.is_empty() || name == "lethe_vault" {
+        "Lethe Vault".to_string()
+    } else {
+        format!("Lethe ({})", name)
+    }
+}
+
+/// Capacity figures reported to the OS for a mounted vault
+#[derive(Debug, Clone, Copy)]
+pub struct Space {
+    /// Plaintext bytes stored in the vault (from the index)
+    pub used: u64,
+    /// Bytes still free on the disk holding the vault
+    pub free: u64,
+}
+
+impl Space {
+    /// The vault can grow into whatever the underlying disk has left, so
+    /// its capacity is what it already uses plus that.
+    pub fn total(&self) -> u64 {
+        self.used.saturating_add(self.free)
+    }
+}
+
+pub fn space(vault_path: &Path, used: u64) -> Space {
+    Space { used, free: disk_free(vault_path).unwrap_or(0) }
+}
+
+// =========================================================
+//  PLATFORM DISK QUERIES
+// =========================================================
+
+#[cfg(unix)]
+fn disk_free(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: c_path is NUL-terminated and stat is a valid out pointer
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)] // field widths differ between platforms
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+fn disk_free(path: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetDiskFreeSpaceExW(dir: *const u16, avail: *mut u64, total: *mut u64, total_free: *mut u64) -> i32;
+    }
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(std::iter::once(0)).collect();
+    let (mut avail, mut total, mut total_free) = (0u64, 0u64, 0u64);
+    // SAFETY: wide is NUL-terminated and all out pointers are valid
+    let ok = unsafe { GetDiskFreeSpaceExW(wide.as_ptr(), &mut avail, &mut total, &mut total_free) };
+    (ok != 0).then_some(avail)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn disk_free(_path: &Path) -> Option<u64> {
+    None
+}
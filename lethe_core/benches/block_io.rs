@@ -0,0 +1,73 @@
+//! Block reads one at a time (`read_block`) against batched (`read_blocks`).
+//!
+//! `cargo bench -p lethe_core --features io-uring --bench block_io` for the
+//! io_uring path; without the feature the batch is read file by file and
+//! only the decryption runs side by side. BLOCKS sets how many blocks;
+//! COLD=1 drops the page cache before each round (Linux, as root), which
+//! is where batching the reads pays most.
+
+use std::time::{Duration, Instant};
+
+use lethe_core::crypto::CryptoEngine;
+use lethe_core::plaintext::Zeroizing;
+use lethe_core::storage::BlockManager;
+use rand::RngCore;
+
+const ROUNDS: u32 = 5;
+
+fn timed(cold: bool, mut f: impl FnMut()) -> Duration {
+    let mut best = Duration::MAX;
+    for _ in 0..ROUNDS {
+        if cold {
+            let _ = std::process::Command::new("sync").status();
+            if let Err(e) = std::fs::write("/proc/sys/vm/drop_caches", "1") {
+                panic!("COLD=1 needs to drop the page cache: {}", e);
+            }
+        }
+        let start = Instant::now();
+        f();
+        best = best.min(start.elapsed());
+    }
+    best
+}
+
+fn main() -> anyhow::Result<()> {
+    let count: usize = std::env::var("BLOCKS").ok().and_then(|n| n.parse().ok()).unwrap_or(2000);
+    let cold = std::env::var("COLD").is_ok_and(|v| v == "1");
+    let dir = std::env::temp_dir().join(format!("lethe-bench-{}", std::process::id()));
+    let storage = BlockManager::new(&dir)?;
+    let key = CryptoEngine::random_key();
+    println!("io_uring: {}", cfg!(all(feature = "io-uring", target_os = "linux")));
+
+    for size in [4 * 1024, 64 * 1024, 1024 * 1024] {
+        let count = (count * 4 * 1024 / size).max(16);
+        let mut ids = Vec::with_capacity(count);
+        let mut data = vec![0u8; size];
+        for _ in 0..count {
+            rand::thread_rng().fill_bytes(&mut data);
+            ids.push(storage.write_block(&data, &key)?);
+        }
+
+        let one_by_one = timed(cold, || {
+            for id in &ids {
+                // Wiped on drop, as `read_blocks` results are
+                Zeroizing::new(storage.read_block(id, &key).expect("read"));
+            }
+        });
+        let batched = timed(cold, || {
+            for batch in ids.chunks(64) {
+                storage.read_blocks(batch, &key).expect("read");
+            }
+        });
+        println!(
+            "{:>5} KiB x {:>5}: read_block {:>9.2?}  read_blocks {:>9.2?}  ({:.1}x)",
+            size / 1024, count, one_by_one, batched, one_by_one.as_secs_f64() / batched.as_secs_f64(),
+        );
+        for id in &ids {
+            storage.delete_block(id)?;
+        }
+    }
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,144 @@
+//! Vault users and per-prefix ACLs, enforced by `lethe serve`.
+//!
+//! Users and rules live in the index, so they travel with every sync.
+//! Each user has a slot: the master key sealed with a key derived from
+//! that user's own password, so a server can be unlocked and used without
+//! the vault password. `users.bin`, next to the index replicas, repeats
+//! the slots in the clear so a locked server can find them; once the
+//! index is open its copy is the one that counts.
+//!
+//! A path is governed by the rule on its longest covering prefix, so
+//! `/shared/hr` can be closer than `/shared`. Once users exist, a path no
+//! rule covers is off limits to all of them. The rules are enforced by
+//! the server, not by encryption: a user holding the vault folder and
+//! their password could open all of it.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{CryptoEngine, MasterKey};
+use crate::index::{self, VaultIndex};
+use crate::keyring::{key_from, now, open, seal};
+
+pub const USERS_FILE: &str = "users.bin";
+
+/// Stands for every user in a rule
+pub const EVERYONE: &str = "*";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VaultUser {
+    /// Salt of the key derived from the user's password
+    pub salt: String,
+    /// The master key sealed with that key (Nonce + Data)
+    pub slot: Vec<u8>,
+    pub modified: u64,
+    /// Kept rather than dropped, so the removal syncs
+    #[serde(default)]
+    pub removed: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct AclRule {
+    pub read: BTreeSet<String>,
+    /// Write access implies read access
+    pub write: BTreeSet<String>,
+    pub modified: u64,
+    #[serde(default)]
+    pub removed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+impl VaultUser {
+    pub fn new(password: &str, key: &MasterKey) -> Result<Self> {
+        let (wrapping, salt) = CryptoEngine::derive_key(password)?;
+        let slot = seal(key.as_bytes(), &wrapping)?;
+        Ok(Self { salt, slot, modified: now(), removed: false })
+    }
+
+    /// The master key, if `password` is this user's
+    pub fn unlock(&self, password: &str) -> Result<MasterKey> {
+        let (wrapping, _) = CryptoEngine::derive_key_with_salt(password, &self.salt)?;
+        open(&self.slot, &wrapping).ok()
+            .and_then(|plain| key_from(&plain))
+            .context("Wrong user name or password")
+    }
+}
+
+/// Bare names only; `*` is taken
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+/// Canonical form of a rule prefix: leading slash, no trailing one
+pub fn normalize(prefix: &str) -> String {
+    format!("/{}", prefix.trim_matches('/'))
+}
+
+/// Users currently defined, without the removed ones
+pub fn users(index: &VaultIndex) -> impl Iterator<Item = (&String, &VaultUser)> {
+    index.users.iter().filter(|(_, u)| !u.removed)
+}
+
+/// The rule governing `path`: the one on its longest covering prefix
+pub fn rule_for<'a>(index: &'a VaultIndex, path: &str) -> Option<(&'a String, &'a AclRule)> {
+    index.acls.iter()
+        .filter(|(prefix, rule)| !rule.removed && covers(prefix, path))
+        .max_by_key(|(prefix, _)| prefix.len())
+}
+
+fn covers(prefix: &str, path: &str) -> bool {
+    index::is_within(path, prefix)
+}
+
+/// Whether `user` may read or write `path`
+pub fn allows(index: &VaultIndex, user: &str, path: &str, access: Access) -> bool {
+    if index.users.get(user).is_none_or(|u| u.removed) {
+        return false;
+    }
+    let Some((_, rule)) = rule_for(index, path) else {
+        return false;
+    };
+    let listed = |names: &BTreeSet<String>| names.contains(user) || names.contains(EVERYONE);
+    match access {
+        Access::Read => listed(&rule.read) || listed(&rule.write),
+        Access::Write => listed(&rule.write),
+    }
+}
+
+// --- users.bin ---
+
+/// Writes the slots of `index`'s users next to its replicas, or removes
+/// the file once there are none
+pub fn write_users_file(vault: &Path, index: &VaultIndex) -> Result<()> {
+    let path = vault.join(USERS_FILE);
+    let active: BTreeMap<&String, &VaultUser> = users(index).collect();
+    if active.is_empty() {
+        if path.exists() {
+            fs::remove_file(&path).context("Failed to remove users file")?;
+        }
+        return Ok(());
+    }
+    let tmp = vault.join(format!("{}.tmp", USERS_FILE));
+    fs::write(&tmp, serde_cbor::to_vec(&active)?).context("Failed to write users file")?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// The slots a locked vault offers; empty if it has no users
+pub fn read_users_file(vault: &Path) -> Result<BTreeMap<String, VaultUser>> {
+    let path = vault.join(USERS_FILE);
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let bytes = fs::read(&path).context("Failed to read users file")?;
+    serde_cbor::from_slice(&bytes).context("Users file is corrupted")
+}
@@ -0,0 +1,166 @@
+//! Activity counters: how often each operation was run on the copy of a
+//! vault at a path, and when first and last (`lethe stats --activity`).
+//! They stay in the vault folder; nothing is ever sent anywhere.
+//!
+//! `activity.bin` holds them, sealed (Nonce + Data of the CBOR) under a key
+//! derived from the master key. An unlock with a key that opens neither it
+//! nor the vault, nor a hidden vault in it, failed; with no key to seal
+//! that under, it is counted in the plain `activity.failures` until the
+//! next unlock that works folds it in. That file tells no more than
+//! `unlock.failures` does. Counting never fails a command. Like the audit
+//! log, each copy keeps its own, and a hidden vault keeps none.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{CryptoEngine, MasterKey};
+use crate::hidden;
+use crate::index::IndexManager;
+use crate::keyring::{self, now, open, seal};
+use crate::storage::BlockManager;
+
+pub const ACTIVITY_FILE: &str = "activity.bin";
+pub const FAILURES_FILE: &str = "activity.failures";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Unlock,
+    FailedUnlock,
+    Put,
+    Get,
+    /// `lethe clean`
+    Clean,
+    Mount,
+}
+
+impl Op {
+    pub const ALL: [Op; 6] = [Op::Unlock, Op::FailedUnlock, Op::Put, Op::Get, Op::Clean, Op::Mount];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Unlock => "unlock",
+            Op::FailedUnlock => "failed unlock",
+            Op::Put => "put",
+            Op::Get => "get",
+            Op::Clean => "clean",
+            Op::Mount => "mount",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct Counter {
+    pub count: u64,
+    /// Files it went through, and their bytes; blocks for `clean`
+    pub files: u64,
+    pub bytes: u64,
+    pub first: u64,
+    pub last: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Activity {
+    /// `Op::name` -> its counter
+    pub counters: BTreeMap<String, Counter>,
+}
+
+impl Activity {
+    pub fn get(&self, op: Op) -> Option<&Counter> {
+        self.counters.get(op.name())
+    }
+
+    fn add(&mut self, op: Op, count: u64, files: u64, bytes: u64, first: u64, last: u64) {
+        let counter = self.counters.entry(op.name().to_string()).or_default();
+        if counter.count == 0 {
+            counter.first = first;
+        }
+        counter.count += count;
+        counter.files += files;
+        counter.bytes += bytes;
+        counter.last = counter.last.max(last);
+    }
+}
+
+fn activity_key(key: &MasterKey) -> MasterKey {
+    CryptoEngine::derive_subkey(key, b"lethe activity counters")
+}
+
+/// None before the first unlock that counted
+pub fn load(vault: &Path, key: &MasterKey) -> Result<Option<Activity>> {
+    let sealed = match fs::read(vault.join(ACTIVITY_FILE)) {
+        Ok(sealed) => sealed,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context("Failed to read the activity counters"),
+    };
+    let plain = open(&sealed, &activity_key(key)).context("Activity counters don't open. Wrong password?")?;
+    serde_cbor::from_slice(&plain).map(Some).context("Activity counters are corrupted")
+}
+
+fn save(vault: &Path, key: &MasterKey, activity: &Activity) -> Result<()> {
+    let sealed = seal(&serde_cbor::to_vec(activity)?, &activity_key(key))?;
+    let tmp = vault.join(format!("{}.tmp", ACTIVITY_FILE));
+    fs::write(&tmp, sealed).context("Failed to write the activity counters")?;
+    fs::rename(&tmp, vault.join(ACTIVITY_FILE))?;
+    Ok(())
+}
+
+/// Failed unlocks not yet folded in: how many, the first and the last
+pub fn pending_failures(vault: &Path) -> (u64, u64, u64) {
+    let text = fs::read_to_string(vault.join(FAILURES_FILE)).unwrap_or_default();
+    let mut numbers = text.split_whitespace().map(|n| n.parse().unwrap_or(0));
+    let mut next = || numbers.next().unwrap_or(0);
+    (next(), next(), next())
+}
+
+/// Counts a failed unlock of `vault`
+pub fn failed(vault: &Path) {
+    let (count, first, _) = pending_failures(vault);
+    let at = now();
+    let first = if count == 0 { at } else { first };
+    let _ = fs::write(vault.join(FAILURES_FILE), format!("{} {} {}", count + 1, first, at));
+}
+
+/// Counts an unlock of `vault` with `key`, a failed one if the key opens
+/// nothing there
+pub fn unlocked(vault: &Path, key: &MasterKey) {
+    let activity = match load(vault, key) {
+        Ok(Some(activity)) => Some(activity),
+        Ok(None) if opens_index(vault, key) => Some(Activity::default()),
+        _ => None,
+    };
+    let Some(mut activity) = activity else {
+        if !BlockManager::new(vault).is_ok_and(|storage| hidden::holds(&storage, key)) {
+            failed(vault);
+        }
+        return;
+    };
+    let (failures, first, last) = pending_failures(vault);
+    if failures > 0 {
+        activity.add(Op::FailedUnlock, failures, 0, 0, first, last);
+    }
+    let at = now();
+    activity.add(Op::Unlock, 1, 0, 0, at, at);
+    if save(vault, key, &activity).is_ok() && failures > 0 {
+        let _ = fs::remove_file(vault.join(FAILURES_FILE));
+    }
+}
+
+fn opens_index(vault: &Path, key: &MasterKey) -> bool {
+    keyring::index_key(vault, key)
+        .and_then(|index_key| IndexManager::read_replicas(vault, key, index_key.as_ref()))
+        .is_ok()
+}
+
+/// Counts `op` over `files` files of `bytes`. Does nothing in a vault
+/// without counters yet, or a hidden one.
+pub fn count(vault: &Path, key: &MasterKey, op: Op, files: u64, bytes: u64) {
+    if let Ok(Some(mut activity)) = load(vault, key) {
+        let at = now();
+        activity.add(op, 1, files, bytes, at, at);
+        let _ = save(vault, key, &activity);
+    }
+}
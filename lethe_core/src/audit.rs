@@ -0,0 +1,214 @@
+//! Audit trail: an append-only, encrypted record of what was done with the
+//! copy of a vault at a path (unlocks, mounts, deletions, key rotations,
+//! syncs), so that access nobody remembers shows up.
+//!
+//! `audit.log` holds one sealed frame per operation: u32 length, Nonce +
+//! Data of the CBOR `Record`, and the length again so the last frame can
+//! be found from the end. The key is derived from the master key. Every
+//! record carries a keyed hash of the frame before it, so a frame taken
+//! out, altered or reordered breaks the chain when the log is read;
+//! frames cut off the end can't be told from nothing having happened.
+//! Unlike the event log it never starts over, and like it, each copy
+//! keeps its own: records don't sync.
+
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{CryptoEngine, MasterKey};
+use crate::device;
+use crate::events::Change;
+use crate::index::IndexManager;
+use crate::keyring::{self, now, open, seal};
+
+pub const AUDIT_FILE: &str = "audit.log";
+
+/// Frames larger than this are corrupt, not read
+const MAX_FRAME: u32 = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Operation {
+    /// The vault password was given to `command`
+    Unlock { command: String },
+    Mount { target: String },
+    Unmount { target: String },
+    Delete { path: String },
+    SnapshotDelete { name: String },
+    /// `lethe clean` removed unreferenced blocks
+    Clean { blocks: u64, bytes: u64 },
+    /// The index was sealed under a new key
+    Rekey { reason: String },
+    /// Changes went to or came from another copy
+    Sync { kind: String, with: String },
+    /// `lethe repair` rewrote a damaged copy of a block on `volume`, or
+    /// copied it there to make up its copies
+    BlockRepair { block: String, volume: String, reason: String },
+    /// The background scrub found a block damaged or missing (`found`
+    /// says which, and on what volumes)
+    BlockDamage { block: String, found: String },
+    /// `lethe publish` decrypted the subtree at `prefix` into `out`
+    Publish { prefix: String, out: String, files: u64, bytes: u64 },
+    /// `lethe release` let `files` write-once files at `path` change again
+    Release { path: String, files: u64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Record {
+    pub time: u64,
+    /// This copy's id (see `device::id`) and the machine's name. A vault
+    /// folder opened elsewhere shows up under another id.
+    pub device: String,
+    pub device_name: String,
+    pub operation: Operation,
+    /// Keyed hash of the frame before, empty for the first
+    prev: String,
+}
+
+/// A record as read back
+pub struct Entry {
+    pub record: Record,
+    /// False if the frame before it is missing, altered or out of place
+    pub chained: bool,
+}
+
+pub struct Trail {
+    pub entries: Vec<Entry>,
+    /// Frames that did not open, i.e. were altered
+    pub unreadable: usize,
+}
+
+impl Trail {
+    /// Whether anything suggests the log was tampered with
+    pub fn intact(&self) -> bool {
+        self.unreadable == 0 && self.entries.iter().all(|e| e.chained)
+    }
+}
+
+fn audit_key(key: &MasterKey) -> MasterKey {
+    CryptoEngine::derive_subkey(key, b"lethe audit log")
+}
+
+/// The operations worth auditing among a save's changes
+pub(crate) fn deletions(changes: &[Change]) -> Vec<Operation> {
+    changes.iter()
+        .filter_map(|change| match change {
+            Change::FileDeleted { path } => Some(Operation::Delete { path: path.clone() }),
+            Change::SnapshotDeleted { name } => Some(Operation::SnapshotDelete { name: name.clone() }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Appends `operation` to the log of `vault`. Refuses a key the log (or,
+/// before the first record, the index) doesn't open with, so a mistyped
+/// password leaves nothing behind.
+pub fn record(vault: &Path, key: &MasterKey, operation: Operation) -> Result<()> {
+    let audit_key = audit_key(key);
+    let path = vault.join(AUDIT_FILE);
+    let prev = match last_frame(&path)? {
+        Some(frame) => {
+            open(&frame, &audit_key).context("Audit log doesn't open. Wrong password?")?;
+            CryptoEngine::keyed_hash(&frame, &audit_key)
+        }
+        None => {
+            let index_key = keyring::index_key(vault, key)?;
+            IndexManager::read_replicas(vault, key, index_key.as_ref())?;
+            String::new()
+        }
+    };
+
+    let record = Record {
+        time: now(),
+        device: device::id(vault),
+        device_name: device::name(),
+        operation,
+        prev,
+    };
+    let sealed = seal(&serde_cbor::to_vec(&record)?, &audit_key)?;
+    let len = (sealed.len() as u32).to_be_bytes();
+    let mut frame = len.to_vec();
+    frame.extend_from_slice(&sealed);
+    frame.extend_from_slice(&len);
+
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)
+        .context("Failed to open the audit log")?;
+    file.write_all(&frame).context("Failed to write the audit log")?;
+    Ok(())
+}
+
+/// `record` for each of `operations`, in order
+pub fn record_all(vault: &Path, key: &MasterKey, operations: Vec<Operation>) -> Result<()> {
+    for operation in operations {
+        record(vault, key, operation)?;
+    }
+    Ok(())
+}
+
+/// The sealed bytes of the last frame, found through its trailing length
+fn last_frame(path: &Path) -> Result<Option<Vec<u8>>> {
+    let Ok(mut file) = fs::File::open(path) else {
+        return Ok(None);
+    };
+    let size = file.metadata()?.len();
+    if size == 0 {
+        return Ok(None);
+    }
+    if size < 8 {
+        anyhow::bail!("Audit log is corrupted");
+    }
+    let mut len = [0u8; 4];
+    file.seek(SeekFrom::End(-4))?;
+    file.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME || len as u64 + 8 > size {
+        anyhow::bail!("Audit log is corrupted");
+    }
+    let mut frame = vec![0u8; len as usize];
+    file.seek(SeekFrom::End(-4 - len as i64))?;
+    file.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+// --- Reading ---
+
+/// Every record in the log of `vault`, oldest first, checked against the
+/// chain
+pub fn read(vault: &Path, key: &MasterKey) -> Result<Trail> {
+    let path = vault.join(AUDIT_FILE);
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e).context("Failed to read the audit log"),
+    };
+    let audit_key = audit_key(key);
+
+    let mut trail = Trail { entries: Vec::new(), unreadable: 0 };
+    let mut prev = String::new();
+    let mut pos = 0usize;
+    while pos < bytes.len() {
+        let corrupted = || anyhow::anyhow!("Audit log is corrupted at byte {}", pos);
+        let len = bytes.get(pos..pos + 4).ok_or_else(corrupted)?;
+        let len = u32::from_be_bytes(len.try_into().unwrap());
+        let end = pos + 4 + len as usize;
+        if len > MAX_FRAME || bytes.get(end..end + 4) != Some(&(len.to_be_bytes())[..]) {
+            return Err(corrupted());
+        }
+        let frame = &bytes[pos + 4..end];
+        match open(frame, &audit_key).ok().and_then(|plain| serde_cbor::from_slice::<Record>(&plain).ok()) {
+            Some(record) => {
+                let chained = record.prev == prev;
+                trail.entries.push(Entry { record, chained });
+            }
+            None => trail.unreadable += 1,
+        }
+        prev = CryptoEngine::keyed_hash(frame, &audit_key);
+        pos = end + 4;
+    }
+    if trail.entries.is_empty() && trail.unreadable > 0 {
+        anyhow::bail!("Audit log doesn't open. Wrong password?");
+    }
+    Ok(trail)
+}
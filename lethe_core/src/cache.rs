@@ -0,0 +1,177 @@
+//! Decrypted blocks kept for the readers of a mount, so a block read by
+//! one open file, or read again after its reader moved on, isn't fetched
+//! and decrypted twice.
+//!
+//! The cache holds at most a set number of bytes and drops the block used
+//! longest ago to make room. Blocks are handed out as `Arc`s; one that is
+//! dropped from the cache is wiped once the last reader lets go of it.
+//! With an `Overflow` the blocks pushed out go there rather than away, and
+//! a block not in memory is looked for there before it is read again.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use serde::{Deserialize, Serialize};
+
+use crate::plaintext::Plaintext;
+
+pub struct BlockCache {
+    limit: usize,
+    lru: Mutex<Lru>,
+    overflow: Option<Box<dyn Overflow>>,
+    hits: AtomicU64,
+    overflow_hits: AtomicU64,
+    misses: AtomicU64,
+    evicted: AtomicU64,
+}
+
+/// Somewhere slower than memory for the blocks a full cache pushes out,
+/// e.g. a scratch file on a local disk (`lethe mount --cache-disk-mb`)
+pub trait Overflow: Send + Sync {
+    /// Keeps `data` as the plaintext of `block_id`, making room as it
+    /// sees fit; a block it can't keep is simply not kept
+    fn put(&self, block_id: &str, data: &Plaintext);
+    fn get(&self, block_id: &str) -> Option<Plaintext>;
+    /// Blocks held, their plaintext size, and the most it holds
+    fn usage(&self) -> (usize, u64, u64);
+}
+
+#[derive(Default)]
+struct Lru {
+    /// Block id -> when it was last used, and its plaintext
+    blocks: HashMap<String, (u64, Arc<Plaintext>)>,
+    /// Last use -> block id, oldest first
+    order: BTreeMap<u64, String>,
+    bytes: usize,
+    tick: u64,
+}
+
+/// How a cache has done, for `lethe mounts --verbose`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evicted: u64,
+    pub blocks: usize,
+    pub bytes: usize,
+    pub limit: usize,
+    /// Blocks found in the overflow, and what it holds
+    #[serde(default)]
+    pub overflow_hits: u64,
+    #[serde(default)]
+    pub overflow_blocks: usize,
+    #[serde(default)]
+    pub overflow_bytes: u64,
+    #[serde(default)]
+    pub overflow_limit: u64,
+}
+
+impl BlockCache {
+    /// A cache of at most `limit` bytes of plaintext
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            lru: Mutex::new(Lru::default()),
+            overflow: None,
+            hits: AtomicU64::new(0),
+            overflow_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+        }
+    }
+
+    /// Sends the blocks this cache has no room for to `overflow`
+    pub fn with_overflow(mut self, overflow: impl Overflow + 'static) -> Self {
+        self.overflow = Some(Box::new(overflow));
+        self
+    }
+
+    pub fn get(&self, block_id: &str) -> Option<Arc<Plaintext>> {
+        if let Some(data) = self.get_resident(block_id) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Some(data);
+        }
+        match self.overflow.as_ref().and_then(|overflow| overflow.get(block_id)) {
+            Some(data) => {
+                self.overflow_hits.fetch_add(1, Ordering::Relaxed);
+                let data = Arc::new(data);
+                self.insert(block_id, data.clone());
+                Some(data)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    fn get_resident(&self, block_id: &str) -> Option<Arc<Plaintext>> {
+        let mut lru = self.lru.lock().unwrap_or_else(PoisonError::into_inner);
+        lru.tick += 1;
+        let tick = lru.tick;
+        let (used, data) = lru.blocks.get_mut(block_id)?;
+        let (old, data) = (std::mem::replace(used, tick), data.clone());
+        lru.order.remove(&old);
+        lru.order.insert(tick, block_id.to_string());
+        Some(data)
+    }
+
+    /// Keeps `data` as the plaintext of `block_id`. A block larger than the
+    /// whole cache isn't kept.
+    pub fn insert(&self, block_id: &str, data: Arc<Plaintext>) {
+        if data.len() > self.limit {
+            return;
+        }
+        let mut pushed_out = Vec::new();
+        let mut lru = self.lru.lock().unwrap_or_else(PoisonError::into_inner);
+        lru.tick += 1;
+        let tick = lru.tick;
+        let len = data.len();
+        if let Some((old, old_data)) = lru.blocks.insert(block_id.to_string(), (tick, data)) {
+            lru.order.remove(&old);
+            lru.bytes -= old_data.len();
+        }
+        lru.order.insert(tick, block_id.to_string());
+        lru.bytes += len;
+        while lru.bytes > self.limit {
+            let Some((_, oldest)) = lru.order.pop_first() else { break };
+            if let Some((_, data)) = lru.blocks.remove(&oldest) {
+                lru.bytes -= data.len();
+                self.evicted.fetch_add(1, Ordering::Relaxed);
+                pushed_out.push((oldest, data));
+            }
+        }
+        // Out of the lock: the overflow is slow, and readers needn't wait on it
+        drop(lru);
+        if let Some(overflow) = &self.overflow {
+            for (block_id, data) in pushed_out {
+                overflow.put(&block_id, &data);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (overflow_blocks, overflow_bytes, overflow_limit) = self.overflow.as_ref().map_or((0, 0, 0), |o| o.usage());
+        let lru = self.lru.lock().unwrap_or_else(PoisonError::into_inner);
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+            blocks: lru.blocks.len(),
+            bytes: lru.bytes,
+            limit: self.limit,
+            overflow_hits: self.overflow_hits.load(Ordering::Relaxed),
+            overflow_blocks,
+            overflow_bytes,
+            overflow_limit,
+        }
+    }
+}
+
+// Never the blocks themselves
+impl std::fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("BlockCache").field("stats", &self.stats()).finish()
+    }
+}
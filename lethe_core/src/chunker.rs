@@ -0,0 +1,143 @@
+//! Content-defined chunking.
+//!
+//! Files are cut where a rolling Gear hash of the last bytes hits a
+//! pattern, not at fixed offsets, so inserting or removing bytes only
+//! moves the cuts next to the edit. Every other chunk comes out the same
+//! and can be recognised by its hash.
+//!
+//! A vault can set the average size it cuts to (`Chunking`), e.g. larger
+//! for media. Files keep the sizes they were cut to, so changing it only
+//! affects what is written afterwards, though chunks cut to different
+//! sizes no longer match.
+
+use std::io::{self, Read};
+
+use crate::plaintext::{self, Plaintext};
+
+/// No chunk is cut shorter than this (except the last one)
+pub(crate) const MIN_CHUNK: usize = 256 * 1024;
+/// Chunks are force-cut at this size
+pub(crate) const MAX_CHUNK: usize = 4 * 1024 * 1024;
+/// A cut needs this many hash bits at zero: ~1 MiB past `MIN_CHUNK` on average
+const CUT_BITS: u32 = 20;
+/// Average chunk size unless a vault sets one
+pub const DEFAULT_AVERAGE: usize = MIN_CHUNK + (1 << CUT_BITS);
+/// Averages a vault can set
+pub const AVERAGES: std::ops::RangeInclusive<usize> = (64 * 1024)..=MAX_CHUNK;
+
+/// One random word per byte value, from SplitMix64 so it never changes
+/// between builds (changing it would stop old chunks from matching)
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x4c45_5448_4543_444b;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Where files are cut: no chunk shorter than `min` (but the last), and a
+/// cut where the hash has `bits` bits at zero, so about `2^bits` past it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunking {
+    min: usize,
+    bits: u32,
+}
+
+impl Default for Chunking {
+    fn default() -> Self {
+        Self { min: MIN_CHUNK, bits: CUT_BITS }
+    }
+}
+
+impl Chunking {
+    /// Chunks of about `average` bytes (clamped to `AVERAGES`), at most
+    /// `MAX_CHUNK`. `DEFAULT_AVERAGE` gives the default.
+    pub fn averaging(average: usize) -> Self {
+        let average = average.clamp(*AVERAGES.start(), *AVERAGES.end());
+        let min = average / 5;
+        // The power of two nearest the rest, on a log scale
+        let rest = (average - min) as f64;
+        Self { min, bits: rest.log2().round() as u32 }
+    }
+
+    /// Length of the next chunk at the start of `data`
+    fn cut_point(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min {
+            return data.len();
+        }
+        let end = data.len().min(MAX_CHUNK);
+        let mask = !(u64::MAX >> self.bits);
+        let mut hash: u64 = 0;
+        // The hash only depends on the last 64 bytes, so start just before min
+        for (i, &byte) in data.iter().enumerate().take(end).skip(self.min - 64) {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            if i >= self.min && hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+/// Splits `data` into chunks. Empty input gives no chunks.
+pub fn split(data: &[u8], chunking: Chunking) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let len = chunking.cut_point(rest);
+        chunks.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+    chunks
+}
+
+/// Cuts what `reader` gives into the same chunks `split` would, holding at
+/// most one `MAX_CHUNK` of it at a time
+pub struct Chunks<R> {
+    reader: R,
+    chunking: Chunking,
+    buf: Plaintext,
+    /// Length of the chunk handed out last, dropped from `buf` next time
+    taken: usize,
+    done: bool,
+}
+
+impl<R: Read> Chunks<R> {
+    pub fn new(reader: R, chunking: Chunking) -> Self {
+        Self { reader, chunking, buf: plaintext::with_capacity(MAX_CHUNK), taken: 0, done: false }
+    }
+
+    /// The next chunk; None at the end of the input
+    pub fn next_chunk(&mut self) -> io::Result<Option<&[u8]>> {
+        self.buf.drain(..self.taken);
+        self.taken = 0;
+        // A cut only looks `MAX_CHUNK` ahead, so that much is enough
+        while !self.done && self.buf.len() < MAX_CHUNK {
+            let filled = self.buf.len();
+            self.buf.resize(MAX_CHUNK, 0);
+            match self.reader.read(&mut self.buf[filled..]) {
+                Ok(n) => {
+                    self.buf.truncate(filled + n);
+                    self.done = n == 0;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => self.buf.truncate(filled),
+                Err(e) => {
+                    self.buf.truncate(filled);
+                    return Err(e);
+                }
+            }
+        }
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        self.taken = self.chunking.cut_point(&self.buf);
+        Ok(Some(&self.buf[..self.taken]))
+    }
+}
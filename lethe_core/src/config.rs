@@ -1,11 +1,26 @@
 use serde::{Deserialize, Serialize};
 
+/// Blocks a reader fetches past the one it needs once reads run from one
+/// block into the next; matches `--read-ahead`
+pub const DEFAULT_READ_AHEAD: usize = 3;
+
+/// Decrypted blocks a mount keeps for its readers, in MiB; matches `--cache-mb`
+pub const DEFAULT_CACHE_MB: usize = 64;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct VaultConfig {
     /// Size of each block in bytes (default: 65536)
     pub block_size: usize,
     /// Zstd compression level (1-22)
     pub compression_level: i32,
+    /// Blocks read ahead of sequential reads, per open file (0 = none)
+    pub read_ahead: usize,
+    /// MiB of decrypted blocks shared by a mount's open files (0 = no cache)
+    pub cache_mb: usize,
+    /// MiB of blocks the cache sets aside on a local disk, sealed under a
+    /// throwaway key, once `cache_mb` is full (0 = none)
+    pub cache_disk_mb: usize,
 }
 
 impl Default for VaultConfig {
@@ -13,6 +28,9 @@ impl Default for VaultConfig {
         Self {
             block_size: 65536, // 64KB
             compression_level: 3,
+            read_ahead: DEFAULT_READ_AHEAD,
+            cache_mb: DEFAULT_CACHE_MB,
+            cache_disk_mb: 0,
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,301 @@
+//! Containers: a whole vault folder packed into one file, for places where
+//! a `.lethe_vault` folder would stand out.
+//!
+//! The file is `carrier || archive || trailer`. The carrier is whatever the
+//! file held before, a video say, and is left as it was; a standalone
+//! container has none. The archive is the vault's files as records (u16
+//! name length, name, u64 length, bytes) ending in an empty name, sealed in
+//! `SEGMENT`-sized pieces, each under a key derived from the container key
+//! and its position so pieces can't be dropped or moved around. The
+//! trailer is a salt, then the archive length XORed with bytes derived
+//! from the key.
+//!
+//! Nothing after the carrier is plaintext or fixed, so without the password
+//! it reads as random bytes; the password alone finds where it starts.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rand::RngCore;
+
+use crate::crypto::{CryptoEngine, MasterKey};
+
+/// Plaintext bytes per sealed piece of the archive
+pub const SEGMENT: usize = 1 << 20;
+
+const SALT_LEN: usize = 16;
+const TRAILER_LEN: u64 = SALT_LEN as u64 + 8;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+
+const NOT_OPENED: &str = "Not a container, or wrong password";
+
+pub struct Container {
+    pub path: PathBuf,
+    /// Bytes in front of the archive that aren't ours
+    pub carrier_len: u64,
+    archive_len: u64,
+    salt: [u8; SALT_LEN],
+}
+
+fn container_key(password: &str, salt: &[u8]) -> Result<MasterKey> {
+    let (key, _) = CryptoEngine::derive_key_with_salt(password, &CryptoEngine::salt_from_bytes(salt)?)?;
+    Ok(CryptoEngine::derive_subkey(&key, b"lethe container"))
+}
+
+fn length_pad(key: &MasterKey) -> u64 {
+    let pad = CryptoEngine::derive_subkey(key, b"lethe container length");
+    u64::from_le_bytes(pad.as_bytes()[..8].try_into().expect("8 bytes"))
+}
+
+fn segment_key(key: &MasterKey, segment: u64) -> MasterKey {
+    CryptoEngine::derive_subkey(key, format!("lethe container segment {}", segment).as_bytes())
+}
+
+impl Container {
+    /// Opens the container at `path`. Returns it with the key its archive
+    /// is sealed with (not the vault's key).
+    pub fn open(path: &Path, password: &str) -> Result<(Self, MasterKey)> {
+        let mut file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+        let len = file.metadata()?.len();
+        if len < TRAILER_LEN {
+            anyhow::bail!(NOT_OPENED);
+        }
+        let mut trailer = [0u8; TRAILER_LEN as usize];
+        file.seek(SeekFrom::Start(len - TRAILER_LEN))?;
+        file.read_exact(&mut trailer)?;
+
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&trailer[..SALT_LEN]);
+        let key = container_key(password, &salt)?;
+        let archive_len = u64::from_le_bytes(trailer[SALT_LEN..].try_into().expect("8 bytes")) ^ length_pad(&key);
+        let carrier_len = (len - TRAILER_LEN).checked_sub(archive_len).context(NOT_OPENED)?;
+
+        let container = Self { path: path.to_path_buf(), carrier_len, archive_len, salt };
+        // Opening the first piece tells a wrong password from a right one
+        container.reader(&key).context(NOT_OPENED)?;
+        Ok((container, key))
+    }
+
+    /// Packs the files in `dir` into `path`, after whatever `path` already
+    /// holds, under a key derived from `password`
+    pub fn create(path: &Path, dir: &Path, password: &str) -> Result<(Self, MasterKey)> {
+        let mut salt = [0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let key = container_key(password, &salt)?;
+        let carrier_len = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+
+        let mut container = Self { path: path.to_path_buf(), carrier_len, archive_len: 0, salt };
+        container.pack(&key, dir)?;
+        Ok((container, key))
+    }
+
+    fn reader(&self, key: &MasterKey) -> Result<Opener<io::Take<File>>> {
+        let mut file = File::open(&self.path).with_context(|| format!("Failed to open {:?}", self.path))?;
+        file.seek(SeekFrom::Start(self.carrier_len))?;
+        Opener::new(file.take(self.archive_len), key)
+    }
+
+    /// Writes the files in the container into `dir`
+    pub fn extract(&self, key: &MasterKey, dir: &Path) -> Result<()> {
+        let mut archive = BufReader::new(self.reader(key)?);
+        loop {
+            let mut len = [0u8; 2];
+            archive.read_exact(&mut len).context("The container is cut short")?;
+            let name_len = u16::from_le_bytes(len) as usize;
+            if name_len == 0 {
+                return Ok(());
+            }
+            let mut name = vec![0u8; name_len];
+            archive.read_exact(&mut name).context("The container is cut short")?;
+            let name = String::from_utf8(name).context("The container names a file that isn't UTF-8")?;
+            let relative = Path::new(&name);
+            if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+                anyhow::bail!("The container names a file outside its folder: {}", name);
+            }
+
+            let mut len = [0u8; 8];
+            archive.read_exact(&mut len).context("The container is cut short")?;
+            let len = u64::from_le_bytes(len);
+
+            let target = dir.join(relative);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut out = File::create(&target).with_context(|| format!("Failed to write {:?}", target))?;
+            if io::copy(&mut (&mut archive).take(len), &mut out)? != len {
+                anyhow::bail!("The container is cut short");
+            }
+        }
+    }
+
+    /// Replaces the archive with the files now in `dir`. The carrier is
+    /// copied into a new file that then takes the old one's place, so a
+    /// crash part way leaves the old container whole.
+    pub fn pack(&mut self, key: &MasterKey, dir: &Path) -> Result<()> {
+        let name = self.path.file_name().context("A container has to be a file")?.to_string_lossy();
+        let temp = self.path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+        let packed = self.pack_into(key, dir, &temp);
+        if packed.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        let archive_len = packed?;
+
+        if let Ok(meta) = fs::metadata(&self.path) {
+            fs::set_permissions(&temp, meta.permissions())?;
+        }
+        fs::rename(&temp, &self.path).with_context(|| format!("Failed to replace {:?}", self.path))?;
+        self.archive_len = archive_len;
+        Ok(())
+    }
+
+    fn pack_into(&self, key: &MasterKey, dir: &Path, temp: &Path) -> Result<u64> {
+        let mut out = File::create(temp).with_context(|| format!("Failed to create {:?}", temp))?;
+        if self.carrier_len > 0 {
+            let carrier = File::open(&self.path)?.take(self.carrier_len);
+            if io::copy(&mut BufReader::new(carrier), &mut out)? != self.carrier_len {
+                anyhow::bail!("{:?} shrank while it was open", self.path);
+            }
+        }
+
+        let mut sealer = Sealer::new(BufWriter::new(&mut out), key);
+        for (relative, path) in files(dir)? {
+            let name = relative.as_bytes();
+            let name_len = u16::try_from(name.len()).with_context(|| format!("File name too long: {}", relative))?;
+            let mut file = File::open(&path).with_context(|| format!("Failed to read {:?}", path))?;
+            let len = file.metadata()?.len();
+            sealer.write_all(&name_len.to_le_bytes())?;
+            sealer.write_all(name)?;
+            sealer.write_all(&len.to_le_bytes())?;
+            if io::copy(&mut (&mut file).take(len), &mut sealer)? != len {
+                anyhow::bail!("{:?} changed while it was packed", path);
+            }
+        }
+        sealer.write_all(&0u16.to_le_bytes())?;
+        let archive_len = sealer.finish()?;
+
+        out.write_all(&self.salt)?;
+        out.write_all(&(archive_len ^ length_pad(key)).to_le_bytes())?;
+        out.sync_all()?;
+        Ok(archive_len)
+    }
+}
+
+/// Every file under `dir`, with its path relative to `dir` ('/'-separated)
+fn files(dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut found = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(folder) = pending.pop() {
+        for entry in fs::read_dir(&folder).with_context(|| format!("Failed to read {:?}", folder))? {
+            let path = entry?.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if path.is_file() {
+                let relative = path.strip_prefix(dir)?.to_string_lossy().replace('\\', "/");
+                found.push((relative, path));
+            }
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Seals what is written to it a `SEGMENT` at a time
+struct Sealer<W: Write> {
+    out: W,
+    key: MasterKey,
+    buf: Vec<u8>,
+    segment: u64,
+    written: u64,
+}
+
+impl<W: Write> Sealer<W> {
+    fn new(out: W, key: &MasterKey) -> Self {
+        Self { out, key: MasterKey::new(*key.as_bytes()), buf: Vec::with_capacity(SEGMENT), segment: 0, written: 0 }
+    }
+
+    fn seal(&mut self) -> io::Result<()> {
+        let (ciphertext, nonce) = CryptoEngine::encrypt(&self.buf, &segment_key(&self.key, self.segment))
+            .map_err(io::Error::other)?;
+        self.out.write_all(&nonce)?;
+        self.out.write_all(&ciphertext)?;
+        self.written += (nonce.len() + ciphertext.len()) as u64;
+        self.segment += 1;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Seals what is left. Returns the length of the archive.
+    fn finish(mut self) -> io::Result<u64> {
+        if !self.buf.is_empty() {
+            self.seal()?;
+        }
+        self.out.flush()?;
+        Ok(self.written)
+    }
+}
+
+impl<W: Write> Write for Sealer<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let take = data.len().min(SEGMENT - self.buf.len());
+        self.buf.extend_from_slice(&data[..take]);
+        if self.buf.len() == SEGMENT {
+            self.seal()?;
+        }
+        Ok(take)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads back what a `Sealer` wrote
+struct Opener<R: Read> {
+    inner: R,
+    key: MasterKey,
+    buf: Vec<u8>,
+    pos: usize,
+    segment: u64,
+}
+
+impl<R: Read> Opener<R> {
+    /// Opens the first piece straight away, so a wrong key fails here
+    fn new(inner: R, key: &MasterKey) -> Result<Self> {
+        let mut opener = Self { inner, key: MasterKey::new(*key.as_bytes()), buf: Vec::new(), pos: 0, segment: 0 };
+        opener.next()?;
+        Ok(opener)
+    }
+
+    /// Opens the next piece into `buf`; leaves it empty at the end
+    fn next(&mut self) -> Result<()> {
+        let mut sealed = Vec::with_capacity(NONCE_LEN + SEGMENT + TAG_LEN);
+        (&mut self.inner).take((NONCE_LEN + SEGMENT + TAG_LEN) as u64).read_to_end(&mut sealed)?;
+        self.pos = 0;
+        self.buf.clear();
+        if sealed.is_empty() {
+            return Ok(());
+        }
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            anyhow::bail!("The container is cut short");
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.buf = CryptoEngine::decrypt(ciphertext, nonce, &segment_key(&self.key, self.segment))?;
+        self.segment += 1;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Opener<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            self.next().map_err(io::Error::other)?;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
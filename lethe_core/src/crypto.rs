@@ -2,10 +2,13 @@ use chacha20poly1305::{
     aead::{Aead, KeyInit},
     XChaCha20Poly1305, XNonce
 };
+use blake2::digest::{consts::U32, Mac};
+use blake2::Blake2bMac;
 use argon2::{
-    password_hash::{rand_core::OsRng, SaltString},
-    Argon2, PasswordHasher
+    password_hash::{rand_core::OsRng, PasswordHash, SaltString},
+    Algorithm, Argon2, Params, PasswordHasher, PasswordVerifier, Version
 };
+use serde::{Deserialize, Serialize};
 use rand::RngCore;
 use zeroize::{Zeroize, ZeroizeOnDrop};
 use anyhow::{Result, Context};
@@ -28,6 +31,25 @@ impl MasterKey {
     }
 }
 
+/// Argon2id costs. `Default` is what new vaults get, and what every vault
+/// used before the costs were recorded.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
 pub struct CryptoEngine;
 
 impl CryptoEngine {
@@ -39,13 +61,37 @@ impl CryptoEngine {
 
     /// Uses an EXISTING salt to derive the key (For "Unlock")
     pub fn derive_key_with_salt(password: &str, salt_str: &str) -> Result<(MasterKey, String)> {
+        Self::derive_key_with_params(password, salt_str, &KdfParams::default())
+    }
+
+    /// Like `derive_key_with_salt`, at the costs a vault header names
+    pub fn derive_key_with_params(password: &str, salt_str: &str, params: &KdfParams) -> Result<(MasterKey, String)> {
         let salt = SaltString::from_b64(salt_str)
             .map_err(|e| anyhow::anyhow!("Invalid salt format: {}", e))?;
-        Self::derive_internal(password, &salt)
+        Self::derive_with(password, &salt, params)
+    }
+
+    /// A fresh salt, as `derive_key` would pick
+    pub fn new_salt() -> String {
+        SaltString::generate(&mut OsRng).as_str().to_string()
+    }
+
+    /// The salt string for raw salt bytes, for formats that store those
+    pub fn salt_from_bytes(bytes: &[u8]) -> Result<String> {
+        SaltString::encode_b64(bytes)
+            .map(|salt| salt.as_str().to_string())
+            .map_err(|e| anyhow::anyhow!("Invalid salt: {}", e))
     }
 
     fn derive_internal(password: &str, salt: &SaltString) -> Result<(MasterKey, String)> {
-        let argon2 = Argon2::default();
+        Self::derive_with(password, salt, &KdfParams::default())
+    }
+
+    #[tracing::instrument(name = "derive_key", skip_all)]
+    fn derive_with(password: &str, salt: &SaltString, params: &KdfParams) -> Result<(MasterKey, String)> {
+        let params = Params::new(params.memory_kib, params.iterations, params.parallelism, None)
+            .map_err(|e| anyhow::anyhow!("Invalid key derivation parameters: {}", e))?;
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
         let password_hash = argon2.hash_password(password.as_bytes(), salt)
             .map_err(|e| anyhow::anyhow!(e))?;
 
@@ -61,6 +107,62 @@ impl CryptoEngine {
         Ok((MasterKey::new(key_bytes), salt.as_str().to_string()))
     }
 
+    /// Hashes an account password into a PHC string (For server accounts)
+    pub fn hash_password(password: &str) -> Result<String> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default().hash_password(password.as_bytes(), &salt)
+            .map_err(|e| anyhow::anyhow!(e))?;
+        Ok(hash.to_string())
+    }
+
+    /// Checks a password against a PHC string from `hash_password`
+    pub fn verify_password(password: &str, phc: &str) -> bool {
+        match PasswordHash::new(phc) {
+            Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Keyed BLAKE2b-256 of `data` (hex), under `key` as given. Callers
+    /// pass a subkey for their purpose, never the vault key itself.
+    pub fn keyed_hash(data: &[u8], key: &MasterKey) -> String {
+        let mut mac = <Blake2bMac<U32> as Mac>::new_from_slice(key.as_bytes())
+            .expect("BLAKE2b takes 32-byte keys");
+        mac.update(data);
+        mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Hash of a chunk as the index keeps it, under a subkey of the vault
+    /// key. Equal chunks within one vault share a hash; it means nothing
+    /// without the key.
+    pub fn chunk_hash(data: &[u8], key: &MasterKey) -> String {
+        Self::keyed_hash(data, &Self::derive_subkey(key, b"lethe chunk hash"))
+    }
+
+    /// Content address of a block holding `data`, in a vault with content
+    /// ids. Same form as `chunk_hash` but under its own subkey, so the name
+    /// on disk is no hash found in the index.
+    pub fn block_id(data: &[u8], key: &MasterKey) -> String {
+        Self::keyed_hash(data, &Self::derive_subkey(key, b"lethe block id"))
+    }
+
+    /// A key for one purpose, derived from `key` and `context` (keyed BLAKE2b-256)
+    pub fn derive_subkey(key: &MasterKey, context: &[u8]) -> MasterKey {
+        let mut mac = <Blake2bMac<U32> as Mac>::new_from_slice(key.as_bytes())
+            .expect("BLAKE2b takes 32-byte keys");
+        mac.update(context);
+        let mut bytes = [0u8; KEY_SIZE];
+        bytes.copy_from_slice(&mac.finalize().into_bytes());
+        MasterKey::new(bytes)
+    }
+
+    /// A fresh random key
+    pub fn random_key() -> MasterKey {
+        let mut bytes = [0u8; KEY_SIZE];
+        OsRng.fill_bytes(&mut bytes);
+        MasterKey::new(bytes)
+    }
+
     pub fn encrypt(data: &[u8], key: &MasterKey) -> Result<(Vec<u8>, Vec<u8>)> {
         let cipher = XChaCha20Poly1305::new(key.as_bytes().into());
         let mut nonce_bytes = [0u8; NONCE_SIZE];
@@ -86,4 +188,4 @@ impl CryptoEngine {
         
         Ok(plaintext)
     }
-}
\ No newline at end of file
+}
\ No newline at end of file
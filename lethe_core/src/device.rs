@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use uuid::Uuid;
+
+/// Random id stamping index changes made through the copy of a vault at
+/// `vault`. Every copy gets its own (two copies on one machine are two
+/// devices), kept in the user's config directory so that copying a vault
+/// never copies its id. Falls back to a per-process id if that directory
+/// is unusable; changes then merely look like another device's.
+pub fn id(vault: &Path) -> String {
+    static CACHE: Mutex<Option<HashMap<PathBuf, String>>> = Mutex::new(None);
+
+    let vault = fs::canonicalize(vault).unwrap_or_else(|_| vault.to_path_buf());
+    let mut cache = CACHE.lock().unwrap();
+    let cache = cache.get_or_insert_with(HashMap::new);
+    if let Some(id) = cache.get(&vault) {
+        return id.clone();
+    }
+
+    let id = load_or_create(&vault).unwrap_or_else(|| Uuid::new_v4().to_string());
+    cache.insert(vault, id.clone());
+    id
+}
+
+/// This machine's host name, as shown in conflict copies
+pub fn name() -> String {
+    static NAME: OnceLock<String> = OnceLock::new();
+    NAME.get_or_init(host_name).clone()
+}
+
+fn host_name() -> String {
+    let from_env = std::env::var("COMPUTERNAME").or_else(|_| std::env::var("HOSTNAME")).ok();
+    let from_file = || fs::read_to_string("/etc/hostname").ok();
+    let from_command = || {
+        std::process::Command::new("hostname").output().ok()
+            .map(|o| String::from_utf8_lossy(&o.stdout).into_owned())
+    };
+    from_env.or_else(from_file).or_else(from_command)
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Keeps the id of the copy that was at `old` for `new`, where it now
+/// is, so a moved vault goes on as the same device; true if it had one
+pub fn relocate(old: &Path, new: &Path) -> std::io::Result<bool> {
+    let Some(path) = dirs::config_dir().map(|dir| dir.join("lethe").join("devices")) else {
+        return Ok(false);
+    };
+    let existing = match fs::read_to_string(&path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let (old, new) = (old.to_string_lossy(), fs::canonicalize(new).unwrap_or_else(|_| new.to_path_buf()));
+    let mut moved = false;
+    let rewritten: String = existing.lines()
+        .map(|line| match line.split_once('\t') {
+            Some((id, p)) if p == old => {
+                moved = true;
+                format!("{}\t{}\n", id, new.to_string_lossy())
+            }
+            _ => format!("{}\n", line),
+        })
+        .collect();
+    if moved {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, rewritten)?;
+        fs::rename(&tmp, &path)?;
+    }
+    Ok(moved)
+}
+
+/// `devices` holds one `<id>\t<vault path>` line per copy
+fn load_or_create(vault: &Path) -> Option<String> {
+    let path = dirs::config_dir()?.join("lethe").join("devices");
+    let vault = vault.to_string_lossy();
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+    for line in existing.lines() {
+        if let Some((id, p)) = line.split_once('\t') {
+            if p == vault && Uuid::parse_str(id).is_ok() {
+                return Some(id.to_string());
+            }
+        }
+    }
+
+    let id = Uuid::new_v4().to_string();
+    fs::create_dir_all(path.parent()?).ok()?;
+    fs::write(&path, format!("{}{}\t{}\n", existing, id, vault)).ok()?;
+    Some(id)
+}
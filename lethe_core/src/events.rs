@@ -0,0 +1,234 @@
+//! Change notifications: what each save did to the vault, for notifiers
+//! and sync daemons that would otherwise re-read the index to find out.
+//!
+//! Saves append to `events.log` in the vault folder, one sealed frame per
+//! save (u32 length, then Nonce + Data of the CBOR `Event` list). Only the
+//! holder of the vault lock writes, so appends never interleave; part of
+//! a frame left by a save cut off mid-append is trimmed by the next. Past
+//! `MAX_LOG` the log is moved to `events.old` and started over; a reader
+//! further behind than that misses what was in between. Each copy of a
+//! vault keeps its own log: changes from a sync show up as the sync saves.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::MasterKey;
+use crate::index::{FileEntry, Snapshot};
+use crate::keyring::{self, now, open, seal};
+
+pub const EVENTS_FILE: &str = "events.log";
+const OLD_EVENTS_FILE: &str = "events.old";
+
+/// Size at which the log starts over
+const MAX_LOG: u64 = 1024 * 1024;
+
+/// Frames larger than this are corrupt, not read
+const MAX_FRAME: u32 = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Change {
+    FileAdded { path: String, size: u64, is_dir: bool },
+    FileModified { path: String, size: u64 },
+    FileDeleted { path: String },
+    /// Same blocks under a new path
+    FileRenamed { from: String, to: String },
+    SnapshotCreated { name: String },
+    SnapshotDeleted { name: String },
+    /// `lethe clean` removed unreferenced blocks
+    GcRun { blocks: u64, bytes: u64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Event {
+    pub time: u64,
+    /// Index revision the change was saved in
+    pub revision: u64,
+    /// Name of the device that saved it
+    pub device: String,
+    pub change: Change,
+}
+
+/// What turned `old` into `new`, files first, then snapshots
+pub(crate) fn diff(
+    old: &HashMap<String, FileEntry>,
+    new: &HashMap<String, FileEntry>,
+    old_snapshots: &BTreeSet<String>,
+    new_snapshots: &BTreeMap<String, Snapshot>,
+) -> Vec<Change> {
+    let mut removed: BTreeMap<&String, &FileEntry> = old.iter().filter(|(path, _)| !new.contains_key(*path)).collect();
+    let mut added: Vec<(&String, &FileEntry)> = new.iter().filter(|(path, _)| !old.contains_key(*path)).collect();
+    added.sort_by_key(|(path, _)| *path);
+
+    let mut changes = Vec::new();
+    for (path, entry) in added {
+        // A file that left one path with the same blocks moved here
+        let moved = (!entry.is_dir && !entry.blocks.is_empty())
+            .then(|| removed.iter().find(|(_, old)| !old.is_dir && old.blocks == entry.blocks).map(|(from, _)| (*from).clone()))
+            .flatten();
+        match moved {
+            Some(from) => {
+                removed.remove(&from);
+                changes.push(Change::FileRenamed { from, to: path.clone() });
+            }
+            None => changes.push(Change::FileAdded { path: path.clone(), size: entry.size, is_dir: entry.is_dir }),
+        }
+    }
+
+    let mut modified: Vec<(&String, &FileEntry)> = new.iter()
+        .filter(|(path, entry)| old.get(*path).is_some_and(|o| !o.is_dir && (o.size != entry.size || o.blocks != entry.blocks)))
+        .collect();
+    modified.sort_by_key(|(path, _)| *path);
+    changes.extend(modified.into_iter().map(|(path, entry)| Change::FileModified { path: path.clone(), size: entry.size }));
+    changes.extend(removed.into_keys().map(|path| Change::FileDeleted { path: path.clone() }));
+
+    changes.extend(new_snapshots.keys().filter(|n| !old_snapshots.contains(*n)).map(|name| Change::SnapshotCreated { name: name.clone() }));
+    changes.extend(old_snapshots.iter().filter(|n| !new_snapshots.contains_key(*n)).map(|name| Change::SnapshotDeleted { name: name.clone() }));
+    changes
+}
+
+/// Appends one save's changes to the log of `vault`
+pub fn append(vault: &Path, key: &MasterKey, revision: u64, device: &str, changes: Vec<Change>) -> Result<()> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let time = now();
+    let events: Vec<Event> = changes.into_iter()
+        .map(|change| Event { time, revision, device: device.to_string(), change })
+        .collect();
+    let sealed = seal(&serde_cbor::to_vec(&events)?, key)?;
+
+    let path = vault.join(EVENTS_FILE);
+    if fs::metadata(&path).is_ok_and(|m| m.len() >= MAX_LOG) {
+        fs::rename(&path, vault.join(OLD_EVENTS_FILE)).context("Failed to rotate the event log")?;
+    }
+    let mut frame = (sealed.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&sealed);
+    let mut file = OpenOptions::new().create(true).read(true).write(true).truncate(false).open(&path)
+        .context("Failed to open the event log")?;
+    // Part of a frame from a save cut off mid-append would swallow this one
+    let whole = whole_frames(&mut file)?;
+    if whole < file.metadata()?.len() {
+        file.set_len(whole).context("Failed to trim the event log")?;
+    }
+    file.seek(SeekFrom::Start(whole))?;
+    file.write_all(&frame).context("Failed to write the event log")?;
+    Ok(())
+}
+
+/// Bytes of the log taken up by whole frames, from the start
+fn whole_frames(file: &mut fs::File) -> std::io::Result<u64> {
+    let size = file.metadata()?.len();
+    let mut pos = 0;
+    let mut len = [0u8; 4];
+    while pos + 4 <= size {
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len);
+        if len > MAX_FRAME || pos + 4 + len as u64 > size {
+            break;
+        }
+        pos += 4 + len as u64;
+    }
+    Ok(pos)
+}
+
+// --- Reading ---
+
+/// Follows a vault's event log from where it left off
+pub struct Subscription<'a> {
+    vault: PathBuf,
+    key: &'a MasterKey,
+    /// Bytes of `events.log` already read
+    offset: u64,
+}
+
+impl<'a> Subscription<'a> {
+    /// From the oldest event still logged
+    pub fn from_start(vault: &Path, key: &'a MasterKey) -> Self {
+        Self { vault: vault.to_path_buf(), key, offset: 0 }
+    }
+
+    /// From the next event saved
+    pub fn from_now(vault: &Path, key: &'a MasterKey) -> Self {
+        // Where the next save will write, past any part of a frame it trims
+        let offset = fs::File::open(vault.join(EVENTS_FILE)).and_then(|mut file| whole_frames(&mut file)).unwrap_or(0);
+        Self { vault: vault.to_path_buf(), key, offset }
+    }
+
+    /// Events saved since the last call
+    pub fn poll(&mut self) -> Result<Vec<Event>> {
+        let path = self.vault.join(EVENTS_FILE);
+        let len = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        if len == self.offset {
+            return Ok(Vec::new());
+        }
+
+        // Rotated since: finish the old log first
+        let mut events = Vec::new();
+        if len < self.offset {
+            let old = self.vault.join(OLD_EVENTS_FILE);
+            let old_len = fs::metadata(&old).map(|m| m.len()).unwrap_or(0);
+            if old_len > self.offset {
+                events.extend(self.read(&old, self.offset)?.0);
+            }
+            self.offset = 0;
+        }
+        let (more, offset) = self.read(&path, self.offset)?;
+        events.extend(more);
+        self.offset = offset;
+        Ok(events)
+    }
+
+    /// Blocks until something is saved, checking every `interval`
+    pub fn wait(&mut self, interval: Duration) -> Result<Vec<Event>> {
+        loop {
+            let events = self.poll()?;
+            if !events.is_empty() {
+                return Ok(events);
+            }
+            std::thread::sleep(interval);
+        }
+    }
+
+    /// Whole frames of `path` from `offset`, and the offset after them.
+    /// A frame still being written is left for the next call.
+    fn read(&self, path: &Path, offset: u64) -> Result<(Vec<Event>, u64)> {
+        let mut file = fs::File::open(path).context("Failed to open the event log")?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        // Keys are looked up per read: a revoke rotates them mid-follow
+        let index_key = keyring::index_key(&self.vault, self.key)?;
+        let keys: Vec<&MasterKey> = match &index_key {
+            Some(k) => std::iter::once(&k.current).chain(&k.older).chain(std::iter::once(self.key)).collect(),
+            None => vec![self.key],
+        };
+
+        let mut events = Vec::new();
+        let mut pos = 0usize;
+        while bytes.len() - pos >= 4 {
+            let len = u32::from_be_bytes(bytes[pos..pos + 4].try_into().unwrap());
+            if len > MAX_FRAME {
+                anyhow::bail!("Event log is corrupted");
+            }
+            let end = pos + 4 + len as usize;
+            if end > bytes.len() {
+                break;
+            }
+            // A frame no key opens is skipped, not fatal
+            if let Some(plain) = keys.iter().find_map(|k| open(&bytes[pos + 4..end], k).ok()) {
+                let frame: Vec<Event> = serde_cbor::from_slice(&plain).context("Event log is corrupted")?;
+                events.extend(frame);
+            }
+            pos = end;
+        }
+        Ok((events, offset + pos as u64))
+    }
+}
@@ -0,0 +1,130 @@
+//! Growth history: how big the vault was at past revisions, for seeing how
+//! fast it grows and when the disk or backend holding it runs out
+//! (`lethe stats --history`).
+//!
+//! `growth.bin` holds the samples, sealed (Nonce + Data of the CBOR list)
+//! under a key derived from the master key: a vault's size over time says
+//! something about what it holds. A save takes a sample when the last one
+//! is an hour old or more, so a busy mount doesn't list its blocks on
+//! every save. Past `MAX_SAMPLES` every other one of the older half is
+//! dropped, which keeps years of history to a few tens of KiB at ever
+//! coarser steps. Like the audit log, each copy keeps its own.
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{CryptoEngine, MasterKey};
+use crate::index::{self, IndexManager};
+use crate::keyring::{now, open, seal};
+use crate::storage::BlockManager;
+
+pub const GROWTH_FILE: &str = "growth.bin";
+
+/// Seconds between samples taken on save
+pub const SAMPLE_EVERY: u64 = 60 * 60;
+
+const MAX_SAMPLES: usize = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub time: u64,
+    pub revision: u64,
+    /// Live files and their plaintext size
+    pub files: u64,
+    pub bytes: u64,
+    /// What the blocks take on disk, snapshots and padding included
+    pub on_disk: u64,
+}
+
+fn growth_key(key: &MasterKey) -> MasterKey {
+    CryptoEngine::derive_subkey(key, b"lethe growth history")
+}
+
+/// The samples of `vault`, oldest first
+pub fn load(vault: &Path, key: &MasterKey) -> Result<Vec<Sample>> {
+    let sealed = match fs::read(vault.join(GROWTH_FILE)) {
+        Ok(sealed) => sealed,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("Failed to read the growth history"),
+    };
+    let plain = open(&sealed, &growth_key(key)).context("Growth history doesn't open. Wrong password?")?;
+    serde_cbor::from_slice(&plain).context("Growth history is corrupted")
+}
+
+fn save(vault: &Path, key: &MasterKey, samples: &[Sample]) -> Result<()> {
+    let sealed = seal(&serde_cbor::to_vec(&samples)?, &growth_key(key))?;
+    let tmp = vault.join(format!("{}.tmp", GROWTH_FILE));
+    fs::write(&tmp, sealed).context("Failed to write the growth history")?;
+    fs::rename(&tmp, vault.join(GROWTH_FILE))?;
+    Ok(())
+}
+
+/// The vault as it stands
+pub fn measure(index: &IndexManager, storage: &BlockManager) -> Result<Sample> {
+    let live = index.data.files.iter().filter(|(path, e)| !e.is_dir && !index::is_snapshot_path(path));
+    let (mut files, mut bytes) = (0, 0);
+    for (_, entry) in live {
+        files += 1;
+        bytes += entry.size;
+    }
+    let on_disk = storage.list_blocks()?.iter().map(|(_, len)| len).sum();
+    Ok(Sample { time: now(), revision: index.data.revision, files, bytes, on_disk })
+}
+
+/// Adds `sample` to the history of `vault`, unless it repeats the last one
+pub fn record(vault: &Path, key: &MasterKey, sample: Sample) -> Result<()> {
+    let mut samples = load(vault, key)?;
+    let repeat = samples.last().is_some_and(|last| {
+        (last.revision, last.files, last.bytes, last.on_disk) == (sample.revision, sample.files, sample.bytes, sample.on_disk)
+    });
+    if repeat {
+        return Ok(());
+    }
+    samples.push(sample);
+    if samples.len() > MAX_SAMPLES {
+        let half = samples.len() / 2;
+        let mut i = 0;
+        samples.retain(|_| {
+            i += 1;
+            i > half || i % 2 == 0
+        });
+    }
+    save(vault, key, &samples)
+}
+
+/// Samples the vault behind `index` if the last sample is `SAMPLE_EVERY`
+/// old. Goes by the file's modified time, so most saves stop there.
+pub(crate) fn sample_if_due(index: &IndexManager, key: &MasterKey) -> Result<()> {
+    let vault = index.root_path();
+    let due = fs::metadata(vault.join(GROWTH_FILE))
+        .and_then(|m| m.modified())
+        .map_or(true, |at| at + Duration::from_secs(SAMPLE_EVERY) <= SystemTime::now());
+    if !due {
+        return Ok(());
+    }
+    record(vault, key, measure(index, &BlockManager::new(vault)?)?)
+}
+
+/// Bytes a day the vault grows on disk, fitted over the samples since
+/// `since` (least squares). None without two samples a while apart.
+pub fn daily_growth(samples: &[Sample], since: u64) -> Option<f64> {
+    let recent: Vec<&Sample> = samples.iter().filter(|s| s.time >= since).collect();
+    let (first, last) = (recent.first()?, recent.last()?);
+    if last.time.saturating_sub(first.time) < SAMPLE_EVERY {
+        return None;
+    }
+    let n = recent.len() as f64;
+    let days = |s: &Sample| (s.time - first.time) as f64 / 86_400.0;
+    let mean_t = recent.iter().map(|s| days(s)).sum::<f64>() / n;
+    let mean_b = recent.iter().map(|s| s.on_disk as f64).sum::<f64>() / n;
+    let (mut cov, mut var) = (0.0, 0.0);
+    for s in &recent {
+        cov += (days(s) - mean_t) * (s.on_disk as f64 - mean_b);
+        var += (days(s) - mean_t).powi(2);
+    }
+    Some(cov / var)
+}
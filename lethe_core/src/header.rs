@@ -0,0 +1,252 @@
+//! `vault.lethe`: the one plaintext file in a vault folder.
+//!
+//! It marks the folder as a Lethe vault and says how to open it: the format
+//! version, the key derivation and its costs, the cipher suite and the
+//! features the vault relies on. A build that finds a newer format or a
+//! feature it doesn't know refuses the vault rather than misread it.
+//!
+//! On disk it is `MAGIC`, the format version (u16, little-endian), then the
+//! rest as CBOR. The version comes first so that a later format can change
+//! everything after it and still be turned away with a clear message.
+//!
+//! Two copies, `HEADER_BACKUPS`, are kept next to it: without the salt
+//! no password opens the vault, however whole its blocks and index are.
+//! `load` goes by the bytes most copies agree on, and a copy that is
+//! missing or disagrees is rewritten on the next unlock.
+//!
+//! Vaults from before it only have `salt.loader`, the bare salt. That reads
+//! as a header with the costs and suite of the time, and is replaced by a
+//! real one on the next unlock.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{CryptoEngine, KdfParams, MasterKey};
+
+pub const HEADER_FILE: &str = "vault.lethe";
+/// Copies of the header, written with it
+pub const HEADER_BACKUPS: [&str; 2] = ["vault_1.lethe", "vault_2.lethe"];
+/// What vaults had before the header
+pub const LEGACY_SALT_FILE: &str = "salt.loader";
+
+pub const MAGIC: &[u8; 8] = b"LETHE\x00VH";
+/// The newest format this build reads, and the one it writes
+pub const FORMAT_VERSION: u16 = 1;
+
+pub const KDF_ARGON2ID: &str = "argon2id";
+/// XChaCha20-Poly1305 over zstd-compressed blocks
+pub const CIPHER_SUITE: &str = "xchacha20poly1305-zstd";
+
+/// Files are cut into content-defined chunks (see `chunker`)
+pub const FEATURE_CHUNKED: &str = "chunked";
+/// The vault destroys its header after `wipe_after` failed unlocks in a
+/// row (see `lockout`). A build that doesn't know it would not enforce it.
+pub const FEATURE_WIPE: &str = "wipe-after-failures";
+/// New blocks are named by a keyed hash of their content rather than a
+/// random UUID (see `storage`). Older builds would refuse those names.
+pub const FEATURE_CONTENT_IDS: &str = "content-ids";
+/// Index replicas keep names and sizes apart from block maps (see
+/// `IndexManager::seal`), so a listing decrypts only the former. Older
+/// builds would take the replicas for damaged.
+pub const FEATURE_SPLIT_INDEX: &str = "split-index";
+/// Some entries are write once (see `FileEntry::immutable`). A build that
+/// doesn't know it would let them be changed or deleted.
+pub const FEATURE_WRITE_ONCE: &str = "write-once";
+/// Features this build can open a vault with
+pub const KNOWN_FEATURES: &[&str] = &[FEATURE_CHUNKED, FEATURE_WIPE, FEATURE_CONTENT_IDS, FEATURE_SPLIT_INDEX, FEATURE_WRITE_ONCE];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Kdf {
+    pub algorithm: String,
+    pub salt: String,
+    pub params: KdfParams,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VaultHeader {
+    /// Read from the fixed part in front of the CBOR
+    #[serde(skip)]
+    pub format: u16,
+    pub kdf: Kdf,
+    pub cipher: String,
+    pub features: BTreeSet<String>,
+    /// Failed unlocks in a row that destroy the vault, if it opted in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wipe_after: Option<u32>,
+    /// Keyed hash that tells the vault key from any other, so only a
+    /// wrong password counts as a failed unlock (see `lockout`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_check: Option<String>,
+    /// Came from `salt.loader`; `save` replaces that
+    #[serde(skip)]
+    pub legacy: bool,
+    /// A copy is missing or disagrees with the rest; `save` rewrites them
+    #[serde(skip)]
+    pub stale: bool,
+}
+
+/// A hash of nothing under a subkey of `key`: reveals no more than an
+/// index replica does, and is read before anything else is
+fn key_check(key: &MasterKey) -> String {
+    CryptoEngine::keyed_hash(b"", &CryptoEngine::derive_subkey(key, b"lethe key check"))
+}
+
+/// The header and its backups, by file name
+fn copies() -> impl Iterator<Item = &'static str> {
+    std::iter::once(HEADER_FILE).chain(HEADER_BACKUPS)
+}
+
+impl VaultHeader {
+    /// The header of a new vault: a fresh salt, current costs and suite,
+    /// content ids and a split index. One read from `salt.loader` has UUID
+    /// blocks and a whole index already.
+    pub fn new() -> Self {
+        let mut header = Self::with_salt(CryptoEngine::new_salt());
+        header.features.insert(FEATURE_CONTENT_IDS.to_string());
+        header.features.insert(FEATURE_SPLIT_INDEX.to_string());
+        header
+    }
+
+    fn with_salt(salt: String) -> Self {
+        Self {
+            format: FORMAT_VERSION,
+            kdf: Kdf { algorithm: KDF_ARGON2ID.to_string(), salt, params: KdfParams::default() },
+            cipher: CIPHER_SUITE.to_string(),
+            features: BTreeSet::from([FEATURE_CHUNKED.to_string()]),
+            wipe_after: None,
+            key_check: None,
+            legacy: false,
+            stale: false,
+        }
+    }
+
+    /// Opts into destroying the vault after `attempts` failed unlocks
+    pub fn set_wipe_after(&mut self, attempts: u32) {
+        self.wipe_after = Some(attempts);
+        self.features.insert(FEATURE_WIPE.to_string());
+    }
+
+    /// Records what tells `key` from a wrong one
+    pub fn set_key_check(&mut self, key: &MasterKey) {
+        self.key_check = Some(key_check(key));
+    }
+
+    /// Whether `key` is the vault's, if the header can tell
+    pub fn checks_key(&self, key: &MasterKey) -> Option<bool> {
+        self.key_check.as_ref().map(|check| *check == key_check(key))
+    }
+
+    pub fn salt(&self) -> &str {
+        &self.kdf.salt
+    }
+
+    /// The header of the vault at `vault`, refusing one this build can't
+    /// open. Of copies that differ, the one most of them agree on wins, the
+    /// header's own among equals; one that doesn't parse is passed over.
+    pub fn load(vault: &Path) -> Result<Self> {
+        let read: Vec<Vec<u8>> = copies().filter_map(|name| fs::read(vault.join(name)).ok()).collect();
+        if read.is_empty() {
+            let salt = fs::read_to_string(vault.join(LEGACY_SALT_FILE))
+                .with_context(|| format!("No vault header in {:?}", vault))?;
+            return Ok(Self { legacy: true, ..Self::with_salt(salt.trim().to_string()) });
+        }
+        let mut tally: Vec<(&[u8], usize)> = Vec::new();
+        for bytes in &read {
+            match tally.iter_mut().find(|(seen, _)| *seen == bytes.as_slice()) {
+                Some((_, count)) => *count += 1,
+                None => tally.push((bytes, 1)),
+            }
+        }
+        tally.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        let mut first_error = None;
+        for (bytes, count) in tally {
+            match Self::parse(bytes) {
+                Ok(header) => return Ok(Self { stale: count <= HEADER_BACKUPS.len(), ..header }),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        let error = first_error.context("No vault header")?;
+        Err(error).with_context(|| format!("Can't open the vault at {:?}", vault))
+    }
+
+    /// Writes the header and its backups, and drops `salt.loader` once
+    /// they are in place
+    pub fn save(&self, vault: &Path) -> Result<()> {
+        let bytes = self.to_bytes()?;
+        for name in copies() {
+            let tmp = vault.join(format!("{}.tmp", name));
+            fs::write(&tmp, &bytes).context("Failed to write the vault header")?;
+            fs::rename(&tmp, vault.join(name))?;
+        }
+        if self.legacy {
+            let _ = fs::remove_file(vault.join(LEGACY_SALT_FILE));
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.extend(serde_cbor::to_vec(self).context("Failed to serialize the vault header")?);
+        Ok(bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let Some(rest) = bytes.strip_prefix(MAGIC.as_slice()) else {
+            anyhow::bail!("Not a Lethe vault header");
+        };
+        let (version, body) = rest.split_at_checked(2).context("Vault header is cut short")?;
+        let format = u16::from_le_bytes([version[0], version[1]]);
+        if format > FORMAT_VERSION {
+            anyhow::bail!(
+                "The vault is in format {}, and this build of Lethe reads up to format {}. Update Lethe to open it.",
+                format, FORMAT_VERSION
+            );
+        }
+        let header: Self = serde_cbor::from_slice(body).context("Vault header is corrupted")?;
+        header.check()?;
+        Ok(Self { format, ..header })
+    }
+
+    /// Refuses a key derivation, cipher suite or feature this build lacks
+    fn check(&self) -> Result<()> {
+        if self.kdf.algorithm != KDF_ARGON2ID {
+            anyhow::bail!("The vault derives its key with {}, which this build of Lethe doesn't support", self.kdf.algorithm);
+        }
+        if self.cipher != CIPHER_SUITE {
+            anyhow::bail!("The vault is encrypted with {}, which this build of Lethe doesn't support", self.cipher);
+        }
+        let unknown: Vec<&str> = self.features.iter()
+            .map(String::as_str)
+            .filter(|f| !KNOWN_FEATURES.contains(f))
+            .collect();
+        if !unknown.is_empty() {
+            anyhow::bail!("The vault uses {}, which this build of Lethe doesn't support. Update Lethe to open it.", unknown.join(", "));
+        }
+        Ok(())
+    }
+
+    /// The vault key for `password`
+    pub fn derive_key(&self, password: &str) -> Result<MasterKey> {
+        let (key, _) = CryptoEngine::derive_key_with_params(password, &self.kdf.salt, &self.kdf.params)?;
+        Ok(key)
+    }
+}
+
+impl Default for VaultHeader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether `vault` holds a vault, with a header (or a copy of one) or
+/// from before one
+pub fn exists(vault: &Path) -> bool {
+    copies().any(|name| vault.join(name).exists()) || vault.join(LEGACY_SALT_FILE).exists()
+}
@@ -0,0 +1,132 @@
+//! Hidden vaults: a second vault living in the padding of an outer one,
+//! opened by a different password at the same prompt.
+//!
+//! Padding blocks are `blk_*.bin` files of random bytes that the outer
+//! index keeps (`VaultIndex::padding`) although no file uses them; `lethe
+//! clean` leaves them alone and syncs carry them like any other block.
+//! Without the key a block is random bytes either way, so padding can't be
+//! told from data, and any vault may hold some (`lethe pad`).
+//!
+//! A hidden vault is made from padding and never adds a file of its own.
+//! Its blocks overwrite free padding blocks in place, padded with a zstd
+//! skippable frame to the exact size of the file they replace, whose mtime
+//! is put back. Its index replicas are padding blocks too, at ids derived
+//! from its key, so the password alone finds them. Outer writes only ever
+//! create new ids (a content id landing on a random one is as likely as
+//! guessing the key), so they can't land on a hidden block.
+//!
+//! Only this folder holds the hidden vault as it is. Syncs copy padding
+//! blocks once, when the other side lacks them, so other copies of the
+//! outer vault carry the hidden one as it was then, if at all.
+//!
+//! The inode change time (ctime, and the birth or change time on other
+//! systems) gives writes away. No call sets it, so every block the hidden
+//! vault fills shows when it was last written, and nothing else ever
+//! rewrites padding. Someone who can read the file system's metadata can
+//! see that padding changed since it was made, and when. Images taken at
+//! different times also show which blocks changed between them, and the
+//! three index blocks change with every save. The hidden vault holds up to
+//! a look at the folder's contents, as with a copy, a sync or a container,
+//! but not to a look at the disk it lives on.
+
+use std::collections::BTreeSet;
+
+use anyhow::{Context, Result};
+use rand::Rng;
+
+use crate::chunker::{MAX_CHUNK, MIN_CHUNK};
+use crate::crypto::{CryptoEngine, MasterKey};
+use crate::index::{IndexManager, VaultIndex};
+use crate::plaintext::Zeroizing;
+use crate::storage::BlockManager;
+
+/// Replicas of a hidden index, like the outer `meta_X.bin`
+const REPLICAS: usize = 3;
+
+/// Room for a chunk that doesn't compress, with the nonce and tag
+const MAX_BLOCK: u64 = MAX_CHUNK as u64 + 64 * 1024;
+
+/// Ids of the padding blocks that hold the index of the hidden vault
+/// sealed with `key`. They look like any other padding id in `storage`: a
+/// random v4 UUID, or with content ids 32 random bytes in hex.
+pub fn index_ids(storage: &BlockManager, key: &MasterKey) -> Vec<String> {
+    (0..REPLICAS)
+        .map(|i| {
+            let derived = CryptoEngine::derive_subkey(key, format!("lethe hidden index {}", i).as_bytes());
+            if storage.content_ids() {
+                return derived.as_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+            }
+            let mut bytes = [0u8; 16];
+            bytes.copy_from_slice(&derived.as_bytes()[..16]);
+            uuid::Builder::from_random_bytes(bytes).into_uuid().to_string()
+        })
+        .collect()
+}
+
+/// Whether `key` is the key of a hidden vault in `storage`
+pub fn holds(storage: &BlockManager, key: &MasterKey) -> bool {
+    index_ids(storage, key).iter().any(|id| storage.has_block(id))
+}
+
+/// A size for a padding block, spread like the blocks files are cut into
+fn padding_len(rng: &mut impl Rng) -> u64 {
+    let average = (1 << 20) as f64;
+    let past_min = (-(1.0 - rng.gen::<f64>()).ln() * average) as u64;
+    (MIN_CHUNK as u64 + past_min).min(MAX_BLOCK)
+}
+
+/// Writes at least `bytes` of padding. Returns the new ids, for the outer
+/// index to keep.
+pub fn pad(storage: &BlockManager, bytes: u64) -> Result<BTreeSet<String>> {
+    let mut rng = rand::thread_rng();
+    let mut ids = BTreeSet::new();
+    let mut written = 0;
+    while written < bytes {
+        let len = padding_len(&mut rng);
+        ids.insert(storage.write_padding(len)?);
+        written += len;
+    }
+    Ok(ids)
+}
+
+/// Makes a hidden vault of `bytes` in the vault `outer` belongs to, sealed
+/// with `key`. The outer index keeps the new padding and is saved.
+pub fn create(outer: &mut IndexManager, storage: &BlockManager, outer_key: &MasterKey, key: &MasterKey, bytes: u64) -> Result<()> {
+    if outer.is_hidden() {
+        anyhow::bail!("A hidden vault can't hold another one");
+    }
+    if holds(storage, key) {
+        anyhow::bail!("There is already a hidden vault with that password");
+    }
+
+    let index_ids = index_ids(storage, key);
+    for id in &index_ids {
+        storage.write_padding_as(id, MAX_BLOCK)?;
+    }
+    let slots = pad(storage, bytes)?;
+    outer.add_padding(index_ids.iter().cloned().chain(slots.iter().cloned()));
+    outer.save(outer_key)?;
+
+    let mut index = VaultIndex::new(outer.data.salt.clone());
+    index.padding = slots;
+    write_index(storage, key, &index)
+}
+
+/// The newest replica of the hidden index that opens
+pub(crate) fn read_index(storage: &BlockManager, key: &MasterKey) -> Result<VaultIndex> {
+    index_ids(storage, key).iter()
+        .filter_map(|id| storage.read_block(id, key).ok())
+        .filter_map(|plain| serde_cbor::from_slice::<VaultIndex>(&Zeroizing::new(plain)).ok())
+        .max_by_key(|index| index.revision)
+        .context("No valid index found. Vault corrupted or wrong password.")
+}
+
+pub(crate) fn write_index(storage: &BlockManager, key: &MasterKey, index: &VaultIndex) -> Result<()> {
+    let plain = Zeroizing::new(serde_cbor::to_vec(index).context("Failed to serialize index")?);
+    for id in index_ids(storage, key) {
+        storage.write_into(&id, &plain, key)
+            .context("The hidden vault's index no longer fits its block")?;
+    }
+    Ok(())
+}
+
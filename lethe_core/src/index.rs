@@ -1,31 +1,443 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::{self, File};
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
 use std::time::{SystemTime, UNIX_EPOCH};
 use anyhow::{Result, Context};
+use crate::access::{self, AclRule, VaultUser};
+use crate::audit;
+use crate::chunker;
 use crate::crypto::{CryptoEngine, MasterKey};
+use crate::device;
+use crate::events::{self, Change};
+use crate::growth;
+use crate::header::{self, VaultHeader};
+use crate::hidden;
+use crate::keyring::{self, IndexKey};
+use crate::mapped;
+use crate::plaintext::Zeroizing;
+use crate::progress::{self, ProgressSink, Silent};
+use crate::retention::Retention;
+use crate::storage::{self, BlockManager};
+use crate::vault_lock::VaultLock;
+use crate::volumes;
 
 /// The logical structure of a file inside the vault
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct FileEntry {
     pub path: String,       
     pub size: u64,          
     pub modified: u64,      // Unix timestamp
-    pub blocks: Vec<String>,// List of UUIDs: ["uuid1", "uuid2"]
+    pub blocks: Vec<String>,// Block ids, in file order
 
     #[serde(default)] 
     pub is_dir: bool,
+
+    /// The save that last changed this entry (None before vector clocks)
+    #[serde(default)]
+    pub dot: Option<Dot>,
+
+    /// When that save happened, by hybrid logical clock; orders concurrent
+    /// changes on merge. None before these were kept.
+    #[serde(default)]
+    pub hlc: Option<Hlc>,
+
+    /// `chunk_hash` of each block's plaintext, in the same order. Empty for
+    /// files written before chunking; those never share blocks.
+    #[serde(default)]
+    pub hashes: Vec<String>,
+
+    /// Plaintext length of each block, in the same order, so a read can
+    /// start mid-file. Empty for files stored before these were kept.
+    #[serde(default)]
+    pub sizes: Vec<u64>,
+
+    /// Compressed length of each block, in the same order, for
+    /// `lethe stats --compression`. Empty for files stored before these
+    /// were kept, and in a hidden vault, whose blocks all look full.
+    #[serde(default)]
+    pub stored: Vec<u64>,
+
+    /// When the entry expires (Unix timestamp). From then on it is hidden,
+    /// and `lethe clean` removes it and shreds its blocks.
+    #[serde(default)]
+    pub expires: Option<u64>,
+
+    /// Write once (`lethe put --immutable`): the entry can't be changed,
+    /// moved or deleted until `lethe release` clears this
+    #[serde(default)]
+    pub immutable: bool,
+}
+
+impl FileEntry {
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|t| t <= now_secs())
+    }
+
+    /// A tag for the content (32 hex), for ETags: a keyed hash of the
+    /// chunk hashes, or of the blocks for files stored before chunking. It
+    /// changes with any write that changes the content, however close
+    /// together, and tells nothing about the content without the key.
+    pub fn content_tag(&self, key: &MasterKey) -> String {
+        let parts = if self.hashes.is_empty() { &self.blocks } else { &self.hashes };
+        let mut digest = CryptoEngine::chunk_hash(parts.join(",").as_bytes(), key);
+        digest.truncate(32);
+        digest
+    }
+}
+
+/// One device's change: its id and its clock counter at the time
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Dot {
+    pub device: String,
+    pub counter: u64,
+}
+
+/// Device id -> number of saves from that device this index has seen
+pub type Clock = BTreeMap<String, u64>;
+
+/// A replica file, opened with the name of the key that opened it, or why
+/// none did
+pub type Replica = (PathBuf, Result<(VaultIndex, &'static str)>);
+
+/// A hybrid logical clock stamp: wall time, unless a stamp this copy has
+/// seen is later (another device's clock running ahead), then that stamp's
+/// time with the counter raised. A change is thus always stamped after
+/// every change its device has merged, however far clocks disagree.
+/// Stamps order by time, then counter, then device.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Hlc {
+    /// Unix time in milliseconds
+    pub wall: u64,
+    pub counter: u32,
+    pub device: String,
+}
+
+impl Hlc {
+    /// The stamp for a change `device` makes now, after `latest`
+    pub fn tick(latest: &Hlc, device: &str) -> Hlc {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        match now > latest.wall {
+            true => Hlc { wall: now, counter: 0, device: device.to_string() },
+            false => Hlc { wall: latest.wall, counter: latest.counter.saturating_add(1), device: device.to_string() },
+        }
+    }
+}
+
+/// A frozen copy of the file table. Its blocks stay referenced, so
+/// `lethe clean` keeps them until the snapshot is deleted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub created: u64,       // Unix timestamp
+    pub files: HashMap<String, FileEntry>,
 }
 
 /// The entire "Database" of the filesystem
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VaultIndex {
     pub version: u8,
-    pub revision: u64,      
+    pub revision: u64,      // Local save counter; picks the newest replica on load
     pub salt: String,       
     pub files: HashMap<String, FileEntry>, // Path -> File Info
+
+    #[serde(default)]
+    pub snapshots: BTreeMap<String, Snapshot>, // Name -> Snapshot
+
+    /// What this copy has seen of every device's changes. Copies of a vault
+    /// are compared and merged by this, not by `revision`.
+    #[serde(default)]
+    pub clock: Clock,
+
+    /// Device id -> host name, for naming conflict copies
+    #[serde(default)]
+    pub devices: BTreeMap<String, String>,
+
+    /// The latest stamp this copy has made or merged; the next save is
+    /// stamped after it
+    #[serde(default)]
+    pub hlc: Hlc,
+
+    /// User name -> account, for `lethe serve` (see `access`)
+    #[serde(default)]
+    pub users: BTreeMap<String, VaultUser>,
+
+    /// Path prefix -> who may read and write below it
+    #[serde(default)]
+    pub acls: BTreeMap<String, AclRule>,
+
+    /// Block id -> volume holding it, for blocks outside the vault folder
+    /// (see `volumes`). It describes this copy's disks; merges keep ours.
+    #[serde(default)]
+    pub placement: BTreeMap<String, String>,
+
+    /// Blocks kept though no file uses them (see `hidden`): the padding of
+    /// an outer vault, or in a hidden vault's index, the padding it may
+    /// write into
+    #[serde(default)]
+    pub padding: BTreeSet<String>,
+
+    /// Entries the index is kept as large as, whether or not the vault
+    /// holds that many (`lethe pad --entries`)
+    #[serde(default)]
+    pub entry_floor: u32,
+
+    /// Average size new files are cut into, in bytes (see `chunker`); 0
+    /// for the default
+    #[serde(default)]
+    pub chunk_size: u32,
+
+    /// New paths must open on Windows (see `windows_problem`)
+    #[serde(default)]
+    pub windows_names: bool,
+
+    /// Automatic snapshots and how long they are kept (see `retention`)
+    #[serde(default)]
+    pub retention: Retention,
+
+    /// Bytes that bring a replica up to `padded_len` (see `pad`). Rewritten
+    /// on every save; means nothing.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub filler: String,
+}
+
+/// Replicas are never smaller than this
+const MIN_INDEX_LEN: usize = 4096;
+
+/// A replica in the split layout (see `IndexManager::seal`) starts with this
+const SPLIT_MAGIC: &[u8; 8] = b"LETHE\x00IX";
+
+/// The cold section of a split replica: what only reading and writing
+/// file contents needs. The hot section is the rest of the `VaultIndex`.
+#[derive(Serialize, Deserialize, Default)]
+struct ColdSection {
+    /// Nonce of the hot section sealed with it (hex), so sections of two
+    /// saves can't be paired up
+    hot_nonce: String,
+    /// Block maps of the hot section's entries, in path order
+    maps: Vec<BlockMap>,
+    snapshots: BTreeMap<String, Snapshot>,
+    placement: BTreeMap<String, String>,
+    padding: BTreeSet<String>,
+    filler: String,
+}
+
+/// The parts of a `FileEntry` the hot section leaves out
+#[derive(Serialize, Deserialize, Default)]
+struct BlockMap {
+    blocks: Vec<String>,
+    hashes: Vec<String>,
+    sizes: Vec<u64>,
+    stored: Vec<u64>,
+}
+
+/// `index` as the hot and cold sections of a split replica, the cold one
+/// with a placeholder nonce of the real one's length
+fn split(index: &VaultIndex) -> (VaultIndex, ColdSection) {
+    let mut paths: Vec<&String> = index.files.keys().collect();
+    paths.sort();
+    let maps = paths.iter().map(|path| {
+        let entry = &index.files[*path];
+        BlockMap { blocks: entry.blocks.clone(), hashes: entry.hashes.clone(), sizes: entry.sizes.clone(), stored: entry.stored.clone() }
+    }).collect();
+    let files = index.files.iter().map(|(path, entry)| {
+        let bare = FileEntry {
+            path: entry.path.clone(),
+            size: entry.size,
+            modified: entry.modified,
+            blocks: Vec::new(),
+            is_dir: entry.is_dir,
+            dot: entry.dot.clone(),
+            hlc: entry.hlc.clone(),
+            hashes: Vec::new(),
+            sizes: Vec::new(),
+            stored: Vec::new(),
+            expires: entry.expires,
+            immutable: entry.immutable,
+        };
+        (path.clone(), bare)
+    }).collect();
+    let hot = VaultIndex {
+        version: index.version,
+        revision: index.revision,
+        salt: index.salt.clone(),
+        files,
+        snapshots: BTreeMap::new(),
+        clock: index.clock.clone(),
+        devices: index.devices.clone(),
+        hlc: index.hlc.clone(),
+        users: index.users.clone(),
+        acls: index.acls.clone(),
+        placement: BTreeMap::new(),
+        padding: BTreeSet::new(),
+        entry_floor: index.entry_floor,
+        chunk_size: index.chunk_size,
+        windows_names: index.windows_names,
+        retention: index.retention.clone(),
+        filler: String::new(),
+    };
+    let cold = ColdSection {
+        hot_nonce: "0".repeat(NONCE_HEX),
+        maps,
+        snapshots: index.snapshots.clone(),
+        placement: index.placement.clone(),
+        padding: index.padding.clone(),
+        filler: index.filler.clone(),
+    };
+    (hot, cold)
+}
+
+/// Reverses `split`
+fn join(mut hot: VaultIndex, cold: ColdSection) -> Result<VaultIndex> {
+    if cold.maps.len() != hot.files.len() {
+        anyhow::bail!("Index sections don't match");
+    }
+    let mut paths: Vec<String> = hot.files.keys().cloned().collect();
+    paths.sort();
+    for (path, map) in paths.iter().zip(cold.maps) {
+        if let Some(entry) = hot.files.get_mut(path) {
+            entry.blocks = map.blocks;
+            entry.hashes = map.hashes;
+            entry.sizes = map.sizes;
+            entry.stored = map.stored;
+        }
+    }
+    hot.snapshots = cold.snapshots;
+    hot.placement = cold.placement;
+    hot.padding = cold.padding;
+    hot.filler = cold.filler;
+    Ok(hot)
+}
+
+/// Hex digits of a nonce
+const NONCE_HEX: usize = 48;
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Nonce and ciphertext of `plain`
+fn seal_section(plain: &[u8], key: &MasterKey) -> Result<Vec<u8>> {
+    let (encrypted_data, nonce) = CryptoEngine::encrypt(plain, key)?;
+    let mut sealed = nonce;
+    sealed.extend_from_slice(&encrypted_data);
+    Ok(sealed)
+}
+
+fn open_section(sealed: &[u8], key: &MasterKey) -> Result<Vec<u8>> {
+    if sealed.len() < 24 {
+        return Err(anyhow::anyhow!("Index file too short"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(24);
+    CryptoEngine::decrypt(ciphertext, nonce, key)
+}
+
+/// The hot and cold sections of a split replica, still sealed
+fn sections(buffer: &[u8]) -> Result<(&[u8], &[u8])> {
+    let rest = &buffer[SPLIT_MAGIC.len()..];
+    let len = rest.get(..4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        .context("Index file too short")?;
+    let rest = &rest[4..];
+    if rest.len() < len {
+        return Err(anyhow::anyhow!("Index file too short"));
+    }
+    Ok(rest.split_at(len))
+}
+
+/// The entries of an index without their block maps, for listing them
+/// (see `IndexManager::read_listing`)
+#[derive(Debug)]
+pub struct Listing {
+    pub revision: u64,
+    pub files: HashMap<String, FileEntry>,
+    /// Read from a hidden vault, which has no replicas of its own
+    pub hidden: bool,
+}
+
+impl Listing {
+    /// Paths of the entries that haven't expired
+    pub fn paths(&self) -> impl Iterator<Item = &String> {
+        self.files.iter().filter(|(_, e)| !e.is_expired()).map(|(path, _)| path)
+    }
+}
+
+/// The size a replica of `len` bytes is padded to. Padmé sizes: at most
+/// about 12% more, and a size gives away little beyond its magnitude.
+fn padded_len(len: usize) -> usize {
+    let len = len.max(MIN_INDEX_LEN);
+    let exponent = usize::BITS - 1 - len.leading_zeros();
+    let bits = u32::BITS - exponent.leading_zeros();
+    let mask = (1usize << (exponent - bits)) - 1;
+    (len + mask) & !mask
+}
+
+/// Virtual read-only folder the mounts show snapshots under
+pub const SNAPSHOT_DIR: &str = "/.snapshots";
+
+/// True for `/.snapshots` and everything below it
+pub fn is_snapshot_path(path: &str) -> bool {
+    is_within(path, SNAPSHOT_DIR)
+}
+
+/// True if `path` is somewhere below the folder `dir`. Only whole names
+/// count: `/doc/a` is below `/doc`, `/documents` is not.
+pub fn is_below(path: &str, dir: &str) -> bool {
+    path.strip_prefix(dir.trim_end_matches('/'))
+        .is_some_and(|rest| rest.len() > 1 && rest.starts_with('/'))
+}
+
+/// `dir` itself or anything below it
+pub fn is_within(path: &str, dir: &str) -> bool {
+    path.trim_end_matches('/') == dir.trim_end_matches('/') || is_below(path, dir)
+}
+
+/// The child of `dir` that is or holds `path`, by name; None unless
+/// `path` is below `dir`
+pub fn child_name<'a>(path: &'a str, dir: &str) -> Option<&'a str> {
+    if !is_below(path, dir) {
+        return None;
+    }
+    path[dir.trim_end_matches('/').len() + 1..].split('/').next().filter(|name| !name.is_empty())
+}
+
+/// Names Windows keeps for devices, with any extension (`aux.txt` too)
+const WINDOWS_DEVICES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Longest path Explorer and most Windows programs open, counting the
+/// drive (`Z:`) and the terminating NUL
+pub const WINDOWS_MAX_PATH: usize = 260;
+
+/// Why `path` can't be opened on Windows, through the WebDAV drive or
+/// anywhere else, if it can't
+pub fn windows_problem(path: &str) -> Option<String> {
+    if path.encode_utf16().count() + 3 > WINDOWS_MAX_PATH {
+        return Some(format!("it is longer than the {} characters Windows programs handle", WINDOWS_MAX_PATH));
+    }
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        let bad = |c: char| matches!(c, '<' | '>' | ':' | '"' | '\\' | '|' | '?' | '*') || c.is_control();
+        if let Some(c) = name.chars().find(|c| bad(*c)) {
+            return Some(format!("{:?} holds {:?}", name, c));
+        }
+        if name.ends_with('.') || name.ends_with(' ') {
+            return Some(format!("{:?} ends in a dot or a space", name));
+        }
+        let stem = name.split('.').next().unwrap_or(name).trim_end();
+        if WINDOWS_DEVICES.iter().any(|device| device.eq_ignore_ascii_case(stem)) {
+            return Some(format!("{:?} is a device name on Windows", name));
+        }
+    }
+    None
+}
+
+/// What a path under `/.snapshots` points at
+#[derive(Debug, Clone, Copy)]
+pub enum SnapshotNode<'a> {
+    Dir { modified: u64 },
+    File(&'a FileEntry),
 }
 
 impl VaultIndex {
@@ -35,8 +447,58 @@ impl VaultIndex {
             revision: 0,
             salt,
             files: HashMap::new(),
+            snapshots: BTreeMap::new(),
+            clock: Clock::new(),
+            devices: BTreeMap::new(),
+            hlc: Hlc::default(),
+            users: BTreeMap::new(),
+            acls: BTreeMap::new(),
+            placement: BTreeMap::new(),
+            padding: BTreeSet::new(),
+            entry_floor: 0,
+            chunk_size: 0,
+            windows_names: false,
+            retention: Retention::default(),
+            filler: String::new(),
         }
     }
+
+    /// Every block the live tree or a snapshot still needs, and the padding
+    pub fn referenced_blocks(&self) -> HashSet<&str> {
+        self.files.values()
+            .chain(self.snapshots.values().flat_map(|s| s.files.values()))
+            .flat_map(|e| e.blocks.iter().map(String::as_str))
+            .chain(self.padding.iter().map(String::as_str))
+            .collect()
+    }
+
+    /// Whether the change `dot` happened before this copy's latest state.
+    /// Entries from before vector clocks count as unseen, so merging keeps them.
+    pub fn has_seen(&self, dot: &Option<Dot>) -> bool {
+        match dot {
+            Some(d) => self.clock.get(&d.device).is_some_and(|c| *c >= d.counter),
+            None => false,
+        }
+    }
+
+    /// Whether this copy has seen every change `other` has
+    pub fn covers(&self, other: &VaultIndex) -> bool {
+        other.clock.iter().all(|(device, counter)| self.clock.get(device).is_some_and(|c| c >= counter))
+    }
+}
+
+/// A file's blocks, written by `IndexManager::stage_reader` and not yet
+/// recorded in the index
+#[derive(Debug)]
+pub struct Staged {
+    blocks: Vec<String>,
+    hashes: Vec<String>,
+    sizes: Vec<u64>,
+    stored: Vec<u64>,
+    /// Blocks placed on a volume other than the vault folder
+    placement: Vec<(String, String)>,
+    /// New blocks written
+    pub written: usize,
 }
 
 /// Manages the loading, saving, and syncing of the Index
@@ -44,6 +506,28 @@ impl VaultIndex {
 pub struct IndexManager {
     root_path: PathBuf,
     pub data: VaultIndex,
+    /// The file table as last loaded or saved, to find what `save` changes
+    base: HashMap<String, FileEntry>,
+    /// Snapshot names as last loaded or saved, likewise
+    base_snapshots: BTreeSet<String>,
+    /// Chunk hash -> block id, built on the first `store_file`. Behind a
+    /// lock so files can be staged (`stage_reader`) with the index shared.
+    chunk_ids: Mutex<Option<HashMap<String, String>>>,
+    /// Seals the index instead of the master key once devices are enabled
+    index_key: Option<IndexKey>,
+    /// Users, rules or padding changed since the last save
+    access_changed: bool,
+    /// A hidden vault's index, kept in padding blocks (see `hidden`)
+    hidden: bool,
+    /// Replicas `load` found behind, missing or unreadable
+    stale_replicas: usize,
+    /// Replicas are written in the split layout (`header::FEATURE_SPLIT_INDEX`)
+    split: bool,
+}
+
+/// Whether the vault at `path` keeps its replicas in the split layout
+fn splits(path: &Path) -> bool {
+    VaultHeader::load(path).is_ok_and(|h| h.features.contains(header::FEATURE_SPLIT_INDEX))
 }
 
 impl IndexManager {
@@ -51,8 +535,16 @@ impl IndexManager {
     /// If index exists on disk, use load() instead.
     pub fn new_empty(path: PathBuf, salt: String) -> Self {
         Self {
+            split: splits(&path),
             root_path: path,
             data: VaultIndex::new(salt),
+            base: HashMap::new(),
+            base_snapshots: BTreeSet::new(),
+            chunk_ids: Mutex::new(None),
+            index_key: None,
+            access_changed: false,
+            hidden: false,
+            stale_replicas: 0,
         }
     }
 
@@ -61,41 +553,500 @@ impl IndexManager {
     }
 
     /// Tries to load the index from 3 replicas. 
-    /// Picks the one with the highest revision number that successfully decrypts.
+    /// Picks the one with the highest revision number that successfully decrypts,
+    /// and rewrites the others from it if they are older or broken.
+    /// A key that opens a hidden vault there loads that one instead.
     pub fn load(path: PathBuf, key: &MasterKey) -> Result<Self> {
-        let mut candidates = Vec::new();
+        Self::load_as(path, key, true)
+    }
 
-        for i in 0..3 {
-            let file_path = path.join(format!("meta_{}.bin", i));
-            if file_path.exists() {
-                if let Ok(index) = Self::read_and_decrypt(&file_path, key) {
-                    candidates.push(index);
-                }
+    /// `load` without `resync_replicas`, for looking at a vault without
+    /// writing to it (`lethe open --forensic`)
+    pub fn load_read_only(path: PathBuf, key: &MasterKey) -> Result<Self> {
+        Self::load_as(path, key, false)
+    }
+
+    fn load_as(path: PathBuf, key: &MasterKey, resync: bool) -> Result<Self> {
+        if path.is_dir() {
+            let storage = BlockManager::new(&path)?;
+            if hidden::holds(&storage, key) {
+                let index = hidden::read_index(&storage, key)?;
+                return Ok(Self { hidden: true, ..Self::from_loaded(path, index, None) });
             }
         }
+        let index_key = keyring::index_key(&path, key)?;
+        if resync {
+            return Self::load_with(path, key, index_key);
+        }
+        let best_index = Self::read_replicas(&path, key, index_key.as_ref())?;
+        Ok(Self::from_loaded(path, best_index, index_key))
+    }
+
+    /// `load` under the given index keys instead of the ones this copy's
+    /// keyring holds, e.g. those of a newer keyring from another copy
+    #[tracing::instrument(name = "load_index", skip_all)]
+    pub fn load_with(path: PathBuf, key: &MasterKey, index_key: Option<IndexKey>) -> Result<Self> {
+        let replicas = Self::replicas(&path, key, index_key.as_ref());
+        let stale_replicas = Self::resync_replicas(&path, key, index_key.as_ref(), &replicas);
+        let best_index = Self::newest(replicas)?;
+        Ok(Self { stale_replicas, ..Self::from_loaded(path, best_index, index_key) })
+    }
 
-        if candidates.is_empty() {
-            return Err(anyhow::anyhow!("No valid index found. Vault corrupted or wrong password."));
+    /// How many of the 3 replicas were behind, missing or unreadable when
+    /// the index was loaded, and rewritten then. A replica damaged again
+    /// and again points at a failing disk.
+    pub fn stale_replicas(&self) -> usize {
+        self.stale_replicas
+    }
+
+    /// Copies the newest of `replicas` over every one that is older, or
+    /// missing or unreadable, so a damaged replica doesn't wait for `lethe
+    /// repair`. A save in progress leaves replicas apart for a moment too,
+    /// so they are only rewritten with the vault lock held, as they are
+    /// once it is. Best effort: a replica that can't be rewritten now is
+    /// left for the next load. Returns how many were stale.
+    fn resync_replicas(path: &Path, key: &MasterKey, index_key: Option<&IndexKey>, replicas: &[Replica]) -> usize {
+        if Self::stale(replicas).is_none() {
+            return 0;
+        }
+        let reopened;
+        let (_lock, replicas) = if VaultLock::held_here(path) {
+            (None, replicas)
+        } else {
+            match VaultLock::try_acquire(path, "lethe (index replicas)") {
+                Ok(Some(lock)) => {
+                    reopened = Self::replicas(path, key, index_key);
+                    (Some(lock), &reopened[..])
+                }
+                // Whoever holds the vault may be saving; the next load looks again
+                _ => return 0,
+            }
+        };
+        let Some((winner, stale)) = Self::stale(replicas) else { return 0 };
+        let count = stale.len();
+        let Ok(sealed) = fs::read(&winner) else { return count };
+        for (file, why) in stale {
+            // Apart from the `.tmp` files a save writes through
+            let tmp = file.with_extension("resync.tmp");
+            match fs::write(&tmp, &sealed).and_then(|()| fs::rename(&tmp, &file)) {
+                Ok(()) => log::warn!("Index replica {:?} was {}; rewrote it from {:?}", file, why, winner),
+                Err(e) => log::warn!("Index replica {:?} is {}, and rewriting it failed: {}", file, why, e),
+            }
         }
+        count
+    }
 
-        candidates.sort_by(|a, b| b.revision.cmp(&a.revision));
+    /// The newest replica that opens, and the others behind it, missing or
+    /// unreadable with why; None if none is
+    fn stale(replicas: &[Replica]) -> Option<(PathBuf, Vec<(PathBuf, String)>)> {
+        let (winner, revision) = replicas.iter()
+            .filter_map(|(file, opened)| opened.as_ref().ok().map(|(index, _)| (file, index.revision)))
+            .min_by_key(|(_, revision)| std::cmp::Reverse(*revision))?;
+        let stale: Vec<(PathBuf, String)> = replicas.iter()
+            .filter_map(|(file, opened)| match opened {
+                Ok((index, _)) if index.revision >= revision => None,
+                Ok((index, _)) => Some((file.clone(), format!("at revision {} of {}", index.revision, revision))),
+                Err(e) => Some((file.clone(), format!("unreadable ({:#})", e))),
+            })
+            .collect();
+        (!stale.is_empty()).then(|| (winner.clone(), stale))
+    }
 
-        let best_index = candidates.remove(0);
-        
-        Ok(Self {
+    fn from_loaded(path: PathBuf, index: VaultIndex, index_key: Option<IndexKey>) -> Self {
+        Self {
+            split: splits(&path),
             root_path: path,
-            data: best_index,
-        })
+            base: index.files.clone(),
+            base_snapshots: index.snapshots.keys().cloned().collect(),
+            data: index,
+            chunk_ids: Mutex::new(None),
+            index_key,
+            access_changed: false,
+            hidden: false,
+            stale_replicas: 0,
+        }
+    }
+
+    /// Whether this is a hidden vault's index (see `hidden`). Such a vault
+    /// has no event or audit log, and doesn't sync.
+    pub fn is_hidden(&self) -> bool {
+        self.hidden
+    }
+
+    /// Keeps `ids` as padding
+    pub fn add_padding(&mut self, ids: impl IntoIterator<Item = String>) {
+        self.data.padding.extend(ids);
+        self.access_changed = true;
+    }
+
+    /// A hidden vault's padding that no file, snapshot or saved file uses
+    fn free_padding(&self) -> Vec<String> {
+        let used: HashSet<&str> = self.data.files.values()
+            .chain(self.data.snapshots.values().flat_map(|s| s.files.values()))
+            .chain(self.base.values())
+            .flat_map(|e| e.blocks.iter().map(String::as_str))
+            .collect();
+        self.data.padding.iter().filter(|id| !used.contains(id.as_str())).cloned().collect()
+    }
+
+    /// The newest replica in `path` that opens with the index keys or the
+    /// master key (replicas from before devices were enabled), e.g. of a
+    /// fetched copy
+    pub fn read_replicas(path: &Path, key: &MasterKey, index_key: Option<&IndexKey>) -> Result<VaultIndex> {
+        Self::newest(Self::replicas(path, key, index_key))
+    }
+
+    /// The first of the replicas with the highest revision that opened
+    fn newest(replicas: Vec<Replica>) -> Result<VaultIndex> {
+        replicas.into_iter()
+            .filter_map(|(_, opened)| opened.ok().map(|(index, _)| index))
+            .min_by_key(|index| std::cmp::Reverse(index.revision))
+            .ok_or_else(|| anyhow::anyhow!("No valid index found. Vault corrupted or wrong password."))
+    }
+
+    /// Every replica file in `path`, opened on its own, with the name of
+    /// the key that opened it, or why none did. For `lethe inspect`.
+    pub fn replicas(path: &Path, key: &MasterKey, index_key: Option<&IndexKey>) -> Vec<Replica> {
+        Self::open_replicas(path, key, index_key, Self::open_sealed)
+    }
+
+    /// Every replica file in `path` opened by `open` under each key in turn
+    fn open_replicas(
+        path: &Path,
+        key: &MasterKey,
+        index_key: Option<&IndexKey>,
+        open: fn(&[u8], &MasterKey) -> Result<VaultIndex>,
+    ) -> Vec<Replica> {
+        let mut keys: Vec<(&MasterKey, &'static str)> = Vec::new();
+        if let Some(k) = index_key {
+            keys.push((&k.current, "index key"));
+            keys.extend(k.older.iter().map(|older| (older, "older index key")));
+        }
+        keys.push((key, "master key"));
+
+        (0..3).map(|i| {
+            let file_path = path.join(format!("meta_{}.bin", i));
+            let opened = match mapped::read(&file_path) {
+                Ok(buffer) => {
+                    let mut last = anyhow::anyhow!("No key to try");
+                    keys.iter()
+                        .find_map(|(k, name)| match open(&buffer, k) {
+                            Ok(index) => Some((index, *name)),
+                            Err(e) => {
+                                last = e;
+                                None
+                            }
+                        })
+                        .ok_or(last)
+                }
+                Err(e) => Err(anyhow::Error::new(e).context("Failed to read replica")),
+            };
+            (file_path, opened)
+        }).collect()
+    }
+
+    /// The entries of the newest replica, read from its hot section alone
+    /// so the block maps of a large vault aren't decrypted just to list
+    /// it. Replicas from before the split layout are read whole. Nothing
+    /// is written, not even to resync the replicas.
+    #[tracing::instrument(skip_all)]
+    pub fn read_listing(path: &Path, key: &MasterKey) -> Result<Listing> {
+        let storage = BlockManager::new(path)?;
+        if hidden::holds(&storage, key) {
+            let index = hidden::read_index(&storage, key)?;
+            return Ok(Listing { revision: index.revision, files: index.files, hidden: true });
+        }
+        let index_key = keyring::index_key(path, key)?;
+        let hot = Self::open_replicas(path, key, index_key.as_ref(), Self::open_hot).into_iter()
+            .filter_map(|(_, opened)| opened.ok().map(|(index, _)| index))
+            .max_by_key(|index| index.revision)
+            .context("No valid index found. Vault corrupted or wrong password.")?;
+        Ok(Listing { revision: hot.revision, files: hot.files, hidden: false })
+    }
+
+    /// What `seal` encrypts with: the index key if devices are enabled
+    pub fn sealing_key<'a>(&'a self, key: &'a MasterKey) -> &'a MasterKey {
+        self.index_key.as_ref().map(|k| &k.current).unwrap_or(key)
+    }
+
+    /// Rewrites the replicas under a new index key (None: the master key)
+    pub fn reseal(&mut self, index_key: Option<IndexKey>, key: &MasterKey) -> Result<()> {
+        self.index_key = index_key;
+        self.rewrite(key)
+    }
+
+    /// Writes the replicas again as they are, e.g. after `load_with`
+    pub fn rewrite(&self, key: &MasterKey) -> Result<()> {
+        self.write_replicas(key)
     }
 
     /// Saves the current index state to all 3 replicas safely.
+    #[tracing::instrument(name = "save_index", skip_all, fields(revision = self.data.revision))]
     pub fn save(&mut self, key: &MasterKey) -> Result<()> {
         self.data.revision += 1; // Increment revision
+        let changes = events::diff(&self.base, &self.data.files, &self.base_snapshots, &self.data.snapshots);
+        self.stamp_changes();
+        self.pad()?;
+        self.write_replicas(key)?;
+        // The save has landed; a lost event must not report it as failed
+        let deletions = audit::deletions(&changes);
+        let _ = self.announce(key, changes);
+        if !self.hidden {
+            let _ = audit::record_all(&self.root_path, key, deletions);
+            let _ = growth::sample_if_due(self, key);
+        }
+        Ok(())
+    }
+
+    /// Logs `changes` as made in the current revision (see `events`). A
+    /// hidden vault logs nothing: its frames would stand out in the log.
+    pub fn announce(&self, key: &MasterKey, changes: Vec<Change>) -> Result<()> {
+        if self.hidden {
+            return Ok(());
+        }
+        events::append(&self.root_path, self.sealing_key(key), self.data.revision, &device::name(), changes)
+    }
+
+    /// Swaps in an index merged with or received from another copy of the
+    /// vault. Its entries keep their dots; nothing is stamped as ours.
+    /// Write-once entries from there mark this copy's header as holding
+    /// them, as `lethe put --immutable` does.
+    pub fn replace(&mut self, mut data: VaultIndex, key: &MasterKey) -> Result<()> {
+        if data.salt != self.data.salt {
+            return Err(anyhow::anyhow!("Index belongs to a different vault"));
+        }
+        if !self.hidden && data.files.values().any(|e| e.immutable) {
+            let mut header = VaultHeader::load(&self.root_path)?;
+            if header.features.insert(header::FEATURE_WRITE_ONCE.to_string()) {
+                header.save(&self.root_path)?;
+            }
+        }
+        data.revision = data.revision.max(self.data.revision) + 1;
+        let changes = events::diff(&self.base, &data.files, &self.base_snapshots, &data.snapshots);
+        self.base = data.files.clone();
+        self.base_snapshots = data.snapshots.keys().cloned().collect();
+        self.data = data;
+        *self.chunk_ids.get_mut().unwrap_or_else(PoisonError::into_inner) = None;
+        self.pad()?;
+        self.write_replicas(key)?;
+        let _ = self.announce(key, changes);
+        Ok(())
+    }
+
+    /// Ticks this device's clock and stamps every entry added or changed
+    /// since the last load or save with it. Entries from before vector
+    /// clocks are stamped too, so deleting them later syncs as a delete.
+    fn stamp_changes(&mut self) {
+        let changed: Vec<String> = self.data.files.iter()
+            .filter(|(path, entry)| match self.base.get(*path) {
+                Some(old) => entry.dot.is_none() || changed_since(old, entry),
+                None => true,
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        let removed = self.base.keys().any(|path| !self.data.files.contains_key(path));
 
-        let plain_data = serde_cbor::to_vec(&self.data)
-            .context("Failed to serialize index")?;
+        if !changed.is_empty() || removed || self.access_changed {
+            let device = device::id(&self.root_path);
+            let counter = self.data.clock.entry(device.clone()).or_insert(0);
+            *counter += 1;
+            let dot = Dot { device: device.clone(), counter: *counter };
+            let hlc = Hlc::tick(&self.data.hlc, &device);
+            self.data.hlc = hlc.clone();
+            self.data.devices.insert(device, device::name());
+            for path in changed {
+                if let Some(entry) = self.data.files.get_mut(&path) {
+                    entry.dot = Some(dot.clone());
+                    entry.hlc = Some(hlc.clone());
+                }
+            }
+        }
+        self.base = self.data.files.clone();
+        self.base_snapshots = self.data.snapshots.keys().cloned().collect();
+        self.access_changed = false;
+    }
+
+    /// Sets `filler` so the index serializes to exactly `padded_len` of
+    /// its size, counting the entries `entry_floor` asks for that the vault
+    /// doesn't have as about as big as the average one. Replica sizes then
+    /// step up now and then rather than with every file added.
+    fn pad(&mut self) -> Result<()> {
+        // A hidden index lives in a block of fixed size anyway
+        if self.hidden {
+            return Ok(());
+        }
+        self.data.filler.clear();
+        let bare = self.encoded_len()?;
+        let missing = (self.data.entry_floor as usize).saturating_sub(self.data.files.len());
+        let per_entry = bare / self.data.files.len().max(1);
+        let mut target = padded_len(bare + missing * per_entry);
+
+        // The filler's own field name and length prefix count too, so a
+        // few bytes short of a size can't be hit; the next size can
+        for _ in 0..2 {
+            let mut filler = target.saturating_sub(bare + 16);
+            for _ in 0..4 {
+                self.data.filler = "\0".repeat(filler);
+                let len = self.encoded_len()?;
+                if len == target {
+                    return Ok(());
+                }
+                filler = (filler + target).saturating_sub(len);
+            }
+            target = padded_len(target + 1);
+        }
+        Ok(())
+    }
+
+    /// Keeps the index as large as `entries` entries would make it
+    pub fn set_entry_floor(&mut self, entries: u32) {
+        self.data.entry_floor = entries;
+        self.access_changed = true;
+    }
+
+    /// How new files are cut into chunks
+    pub fn chunking(&self) -> chunker::Chunking {
+        match self.data.chunk_size {
+            0 => chunker::Chunking::default(),
+            average => chunker::Chunking::averaging(average as usize),
+        }
+    }
+
+    /// Sets the average size new files are cut into (0: the default).
+    /// Padding is sized like default chunks, so a vault with any keeps them.
+    pub fn set_chunk_size(&mut self, average: u32) -> Result<()> {
+        if self.hidden {
+            anyhow::bail!("A hidden vault's chunks go into padding, which is sized like default chunks");
+        }
+        if !self.data.padding.is_empty() {
+            anyhow::bail!("The vault has padding, which is sized like default chunks; other sizes would tell data apart from it");
+        }
+        if average != 0 && !chunker::AVERAGES.contains(&(average as usize)) {
+            anyhow::bail!("The chunk size must be between {} and {} KiB", chunker::AVERAGES.start() / 1024, chunker::AVERAGES.end() / 1024);
+        }
+        self.data.chunk_size = average;
+        self.access_changed = true;
+        Ok(())
+    }
+
+    /// Makes new paths keep to names Windows can open, or stops
+    pub fn set_windows_names(&mut self, on: bool) {
+        self.data.windows_names = on;
+        self.access_changed = true;
+    }
 
-        let (encrypted_data, nonce) = CryptoEngine::encrypt(&plain_data, key)?;
+    /// Replaces the retention policy, stamped now so the change carries
+    /// over to synced copies
+    pub fn set_retention(&mut self, mut policy: Retention) -> Result<()> {
+        if self.hidden {
+            anyhow::bail!("A hidden vault can't clean, so it has no retention policy");
+        }
+        policy.modified = now_secs();
+        self.data.retention = policy;
+        self.access_changed = true;
+        Ok(())
+    }
+
+    /// Fails for a new path Windows can't open, in a vault that keeps to
+    /// names it can (`windows_names`). Paths already there are let be.
+    pub fn check_new_path(&self, path: &str) -> Result<()> {
+        if !self.data.windows_names || self.data.files.contains_key(path) {
+            return Ok(());
+        }
+        match windows_problem(path) {
+            Some(why) => anyhow::bail!("{} can't be used: {}, and the vault keeps to names Windows can open (see `lethe windows-names`)", path, why),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails if `path`, or anything below it, is write once (see
+    /// `FileEntry::immutable`). Callers check before changing, moving or
+    /// removing entries; `store_reader` checks for itself.
+    pub fn check_mutable(&self, path: &str) -> Result<()> {
+        let locked = match self.data.files.get(path) {
+            Some(entry) if !entry.is_dir => entry.immutable.then_some(path),
+            _ => self.data.files.iter().find(|(below, e)| e.immutable && is_below(below, path)).map(|(below, _)| below.as_str()),
+        };
+        match locked {
+            Some(locked) => anyhow::bail!("{} is write-once; `lethe release` lifts that, with the vault password", locked),
+            None => Ok(()),
+        }
+    }
+
+    /// The index encrypted exactly as it is stored on disk (Nonce + Data).
+    /// In the split layout that is `SPLIT_MAGIC`, the length of the sealed
+    /// hot section (u32, big-endian), then the hot and the cold section,
+    /// each sealed on its own: names, sizes and the rest of the index in
+    /// the one, block maps, snapshots and padding in the other.
+    pub fn seal(&self, key: &MasterKey) -> Result<Vec<u8>> {
+        self.seal_as(key, self.split)
+    }
+
+    /// `seal` in the layout from before the split index, whatever the
+    /// vault's, for a sync peer that reads only that
+    pub fn seal_whole(&self, key: &MasterKey) -> Result<Vec<u8>> {
+        self.seal_as(key, false)
+    }
+
+    fn seal_as(&self, key: &MasterKey, in_sections: bool) -> Result<Vec<u8>> {
+        let key = self.sealing_key(key);
+        if !in_sections {
+            let plain_data = serde_cbor::to_vec(&self.data)
+                .context("Failed to serialize index")?;
+            return seal_section(&plain_data, key);
+        }
+        let (hot, mut cold) = split(&self.data);
+        let hot = seal_section(&serde_cbor::to_vec(&hot).context("Failed to serialize index")?, key)?;
+        cold.hot_nonce = to_hex(&hot[..24]);
+        let cold = seal_section(&serde_cbor::to_vec(&cold).context("Failed to serialize index")?, key)?;
+
+        let mut sealed = SPLIT_MAGIC.to_vec();
+        sealed.extend_from_slice(&(hot.len() as u32).to_be_bytes());
+        sealed.extend_from_slice(&hot);
+        sealed.extend_from_slice(&cold);
+        Ok(sealed)
+    }
+
+    /// Reverses `seal`, in either layout
+    pub fn open_sealed(buffer: &[u8], key: &MasterKey) -> Result<VaultIndex> {
+        if !buffer.starts_with(SPLIT_MAGIC) {
+            let plain_data = open_section(buffer, key)?;
+            let index: VaultIndex = serde_cbor::from_slice(&plain_data)?;
+            return Ok(index);
+        }
+        let (hot, cold) = sections(buffer)?;
+        let hot_index: VaultIndex = serde_cbor::from_slice(&open_section(hot, key)?)?;
+        let cold_section: ColdSection = serde_cbor::from_slice(&open_section(cold, key)?)?;
+        if cold_section.hot_nonce != to_hex(&hot[..24]) {
+            anyhow::bail!("Index sections don't match");
+        }
+        join(hot_index, cold_section)
+    }
+
+    /// The hot section of a split replica, or the whole of one from before
+    fn open_hot(buffer: &[u8], key: &MasterKey) -> Result<VaultIndex> {
+        if !buffer.starts_with(SPLIT_MAGIC) {
+            return Self::open_sealed(buffer, key);
+        }
+        let (hot, _) = sections(buffer)?;
+        Ok(serde_cbor::from_slice(&open_section(hot, key)?)?)
+    }
+
+    /// Bytes the index serializes to as `seal` lays it out
+    fn encoded_len(&self) -> Result<usize> {
+        if !self.split {
+            return Ok(serde_cbor::to_vec(&self.data).context("Failed to serialize index")?.len());
+        }
+        let (hot, cold) = split(&self.data);
+        let hot = serde_cbor::to_vec(&hot).context("Failed to serialize index")?.len();
+        Ok(hot + serde_cbor::to_vec(&cold).context("Failed to serialize index")?.len())
+    }
+
+    fn write_replicas(&self, key: &MasterKey) -> Result<()> {
+        if self.hidden {
+            return hidden::write_index(&BlockManager::new(&self.root_path)?, key, &self.data);
+        }
+        let sealed = self.seal(key)?;
 
         for i in 0..3 {
             let file_name = format!("meta_{}.bin", i);
@@ -104,57 +1055,455 @@ impl IndexManager {
             let tmp_path = self.root_path.join(&tmp_name);
 
             let mut file = File::create(&tmp_path)?;
-            file.write_all(&nonce)?;
-            file.write_all(&encrypted_data)?;
+            file.write_all(&sealed)?;
             
             fs::rename(&tmp_path, &target_path)?;
         }
 
-        Ok(())
+        access::write_users_file(&self.root_path, &self.data)
     }
 
-    // --- Helper Functions ---
-
-    fn read_and_decrypt(path: &Path, key: &MasterKey) -> Result<VaultIndex> {
-        let mut file = File::open(path)?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
+    /// Adds or replaces a user
+    pub fn set_user(&mut self, name: &str, user: VaultUser) {
+        self.data.users.insert(name.to_string(), user);
+        self.access_changed = true;
+    }
 
-        if buffer.len() < 24 {
-            return Err(anyhow::anyhow!("Index file too short"));
+    /// Removes a user, leaving their name in the rules
+    pub fn remove_user(&mut self, name: &str) -> bool {
+        match self.data.users.get_mut(name) {
+            Some(user) if !user.removed => {
+                user.removed = true;
+                user.modified = now_secs();
+                self.access_changed = true;
+                true
+            }
+            _ => false,
         }
+    }
 
-        let (nonce, ciphertext) = buffer.split_at(24);
-        
-        let plain_data = CryptoEngine::decrypt(ciphertext, nonce, key)?;
-        
-        let index: VaultIndex = serde_cbor::from_slice(&plain_data)?;
-        Ok(index)
+    /// Replaces the rule on `prefix`; an empty rule denies everyone
+    pub fn set_acl(&mut self, prefix: &str, read: BTreeSet<String>, write: BTreeSet<String>) {
+        let rule = AclRule { read, write, modified: now_secs(), removed: false };
+        self.data.acls.insert(access::normalize(prefix), rule);
+        self.access_changed = true;
+    }
+
+    /// Drops the rule on `prefix`, so the next shorter one governs it again
+    pub fn clear_acl(&mut self, prefix: &str) -> bool {
+        match self.data.acls.get_mut(&access::normalize(prefix)) {
+            Some(rule) if !rule.removed => {
+                *rule = AclRule { modified: now_secs(), removed: true, ..AclRule::default() };
+                self.access_changed = true;
+                true
+            }
+            _ => false,
+        }
     }
 
     pub fn add_file(&mut self, path: String, blocks: Vec<String>, size: u64) {
         let entry = FileEntry {
             path: path.clone(),
             size,
-            modified: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            modified: now_secs(),
             blocks,
             is_dir: false,
+            dot: None,
+            hlc: None,
+            hashes: Vec::new(),
+            sizes: Vec::new(),
+            stored: Vec::new(),
+            expires: None,
+            immutable: false,
         };
-        self.data.files.insert(path, entry);
+        if self.data.files.insert(path.clone(), entry).is_none() {
+            self.touch_parent(&path);
+        }
     }
 
     pub fn add_dir(&mut self, path: String) {
         let entry = FileEntry {
             path: path.clone(),
             size: 0,
-            modified: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            modified: now_secs(),
             blocks: vec![],
             is_dir: true,
+            dot: None,
+            hlc: None,
+            hashes: Vec::new(),
+            sizes: Vec::new(),
+            stored: Vec::new(),
+            expires: None,
+            immutable: false,
+        };
+        if self.data.files.insert(path.clone(), entry).is_none() {
+            self.touch_parent(&path);
+        }
+    }
+
+    /// Stores `data` as `path`, chunked. Chunks the vault already holds (in
+    /// any file or snapshot) reuse their block, so rewriting a big file
+    /// after a small edit only writes, and later syncs, the changed chunks.
+    /// Returns how many new blocks were written.
+    pub fn store_file(&mut self, storage: &BlockManager, key: &MasterKey, path: String, data: &[u8]) -> Result<usize> {
+        self.store_reader(storage, key, path, data, &Silent)
+    }
+
+    /// `store_file` for content that is read as it goes, so it never has to
+    /// be in memory all at once. Reports each chunk to `progress`, and the
+    /// file as one item once it is recorded.
+    #[tracing::instrument(skip(self, storage, key, reader, progress))]
+    pub fn store_reader(&mut self, storage: &BlockManager, key: &MasterKey, path: String, reader: impl Read, progress: &dyn ProgressSink) -> Result<usize> {
+        self.check_new_path(&path)?;
+        self.check_mutable(&path)?;
+        // A hidden vault reuses padding as files let go of it, so what an
+        // earlier store wrote may have been overwritten since
+        let free = self.hidden.then(|| self.free_padding());
+        if self.hidden {
+            *self.chunk_ids.get_mut().unwrap_or_else(PoisonError::into_inner) = None;
+        }
+        let staged = self.write_chunks(storage, key, reader, free, progress)?;
+        let written = self.commit(path, staged);
+        progress.advance(0, 1);
+        Ok(written)
+    }
+
+    /// Writes the blocks of a file without changing the index, so a large
+    /// upload needs only shared access to it; `commit` then records the
+    /// file. Not for hidden vaults, whose blocks come out of padding that
+    /// two stores at once could both take.
+    #[tracing::instrument(skip_all)]
+    pub fn stage_reader(&self, storage: &BlockManager, key: &MasterKey, reader: impl Read) -> Result<Staged> {
+        if self.hidden {
+            anyhow::bail!("A hidden vault stores files through store_reader");
+        }
+        self.write_chunks(storage, key, reader, None, &Silent)
+    }
+
+    /// Records a staged file as `path`. Returns how many new blocks it wrote.
+    pub fn commit(&mut self, path: String, staged: Staged) -> usize {
+        for (id, volume) in staged.placement {
+            self.data.placement.insert(id, volume);
+        }
+        self.add_file(path.clone(), staged.blocks, staged.sizes.iter().sum());
+        if let Some(entry) = self.data.files.get_mut(&path) {
+            entry.hashes = staged.hashes;
+            entry.sizes = staged.sizes;
+            // Only where every block's was found
+            if staged.stored.len() == entry.blocks.len() {
+                entry.stored = staged.stored;
+            }
+        }
+        staged.written
+    }
+
+    /// The block already holding the chunk `hash`, if any
+    fn known_block(&self, hash: &str) -> Option<String> {
+        let mut known = self.chunk_ids.lock().unwrap_or_else(PoisonError::into_inner);
+        known.get_or_insert_with(|| {
+            self.data.files.values()
+                .chain(self.data.snapshots.values().flat_map(|s| s.files.values()))
+                .flat_map(|e| e.hashes.iter().cloned().zip(e.blocks.iter().cloned()))
+                .collect()
+        }).get(hash).cloned()
+    }
+
+    fn write_chunks(&self, storage: &BlockManager, key: &MasterKey, reader: impl Read, mut free: Option<Vec<String>>, progress: &dyn ProgressSink) -> Result<Staged> {
+        let mut staged = Staged { blocks: Vec::new(), hashes: Vec::new(), sizes: Vec::new(), stored: Vec::new(), placement: Vec::new(), written: 0 };
+        let mut chunks = chunker::Chunks::new(reader, self.chunking());
+        while let Some(chunk) = chunks.next_chunk().context("Failed to read the file")? {
+            progress::check(progress)?;
+            staged.sizes.push(chunk.len() as u64);
+            let hash = CryptoEngine::chunk_hash(chunk, key);
+            let block_id = match self.known_block(&hash) {
+                // `lethe clean` may have removed it since
+                Some(id) if storage.has_block(&id) => id,
+                _ => {
+                    staged.written += 1;
+                    let id = match free.as_mut() {
+                        Some(free) => storage.write_padded(chunk, key, free)?,
+                        None => storage.write_block(chunk, key)?,
+                    };
+                    match storage.locate(&id) {
+                        Some(volume) if volume != volumes::MAIN => {
+                            staged.placement.push((id.clone(), volume.to_string()));
+                        }
+                        _ => {}
+                    }
+                    if let Some(known) = self.chunk_ids.lock().unwrap_or_else(PoisonError::into_inner).as_mut() {
+                        known.insert(hash.clone(), id.clone());
+                    }
+                    id
+                }
+            };
+            if free.is_none() {
+                staged.stored.extend(storage.stored_len(&block_id));
+            }
+            staged.blocks.push(block_id);
+            staged.hashes.push(hash);
+            progress.advance(chunk.len() as u64, 0);
+        }
+        Ok(staged)
+    }
+
+    /// Bumps the mtime of `path`'s parent directory after an entry was
+    /// added, removed or renamed in it. Implicit directories have no entry
+    /// of their own and follow their children instead (see `dir_modified`).
+    pub fn touch_parent(&mut self, path: &str) {
+        let parent = match path.rsplit_once('/') {
+            Some(("", _)) | None => return,
+            Some((parent, _)) => parent,
         };
-        self.data.files.insert(path, entry);
+        if let Some(entry) = self.data.files.get_mut(parent) {
+            if entry.is_dir {
+                entry.modified = now_secs();
+            }
+        }
     }
-    
+
+    /// Overrides a file's mtime (e.g. `touch -d`, `cp -p`, rsync)
+    pub fn set_modified(&mut self, path: &str, modified: u64) -> bool {
+        match self.data.files.get_mut(path) {
+            Some(entry) => {
+                entry.modified = modified;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Modification time of a directory: its own entry if it has one,
+    /// otherwise the newest entry below it.
+    pub fn dir_modified(&self, dir: &str) -> u64 {
+        if let Some(entry) = self.data.files.get(dir) {
+            return entry.modified;
+        }
+        self.data.files.iter()
+            .filter(|(path, _)| is_below(path, dir))
+            .map(|(_, e)| e.modified)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The entry at `path`, unless it has expired
     pub fn get_file(&self, path: &str) -> Option<&FileEntry> {
-        self.data.files.get(path)
+        self.data.files.get(path).filter(|e| !e.is_expired())
+    }
+
+    /// Paths of the entries that haven't expired
+    pub fn paths(&self) -> impl Iterator<Item = &String> {
+        self.data.files.iter().filter(|(_, e)| !e.is_expired()).map(|(path, _)| path)
+    }
+
+    /// Whether anything (unexpired) lies below the folder `dir`
+    pub fn has_children(&self, dir: &str) -> bool {
+        self.paths().any(|path| is_below(path, dir))
+    }
+
+    /// Moves `from`, and everything below it, to `to`. False if there was
+    /// nothing there.
+    pub fn rename_tree(&mut self, from: &str, to: &str) -> bool {
+        let moving: Vec<String> = self.data.files.keys().filter(|path| is_within(path, from)).cloned().collect();
+        if moving.is_empty() {
+            return false;
+        }
+        for path in moving {
+            if let Some(mut entry) = self.data.files.remove(&path) {
+                let dest = format!("{}{}", to, &path[from.len()..]);
+                entry.path = dest.clone();
+                self.data.files.insert(dest, entry);
+            }
+        }
+        self.touch_parent(from);
+        self.touch_parent(to);
+        true
+    }
+
+    /// Sets when the entry at `path` expires; None keeps it for good
+    pub fn set_expiry(&mut self, path: &str, expires: Option<u64>) -> bool {
+        match self.data.files.get_mut(path) {
+            Some(entry) => {
+                entry.expires = expires;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Makes the entry at `path` write once, or lets it change again
+    pub fn set_immutable(&mut self, path: &str, immutable: bool) -> bool {
+        match self.data.files.get_mut(path) {
+            Some(entry) => {
+                entry.immutable = immutable;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops expired entries, from the files and from every snapshot.
+    /// Returns them, for their blocks to be shredded once this is saved.
+    pub fn purge_expired(&mut self) -> Vec<FileEntry> {
+        let mut purged = Vec::new();
+        let files = std::iter::once(&mut self.data.files)
+            .chain(self.data.snapshots.values_mut().map(|s| &mut s.files));
+        for files in files {
+            let expired: Vec<String> = files.iter()
+                .filter(|(_, e)| e.is_expired())
+                .map(|(path, _)| path.clone())
+                .collect();
+            purged.extend(expired.iter().filter_map(|path| files.remove(path)));
+        }
+        if !purged.is_empty() {
+            *self.chunk_ids.get_mut().unwrap_or_else(PoisonError::into_inner) = None;
+        }
+        purged
+    }
+
+    /// Total plaintext size of all files in the vault
+    pub fn used_bytes(&self) -> u64 {
+        self.data.files.values().filter(|e| !e.is_dir).map(|e| e.size).sum()
+    }
+
+    /// Every block the live tree or a snapshot still needs
+    pub fn referenced_blocks(&self) -> HashSet<&str> {
+        self.data.referenced_blocks()
     }
-}
\ No newline at end of file
+
+    /// Gives every block the live tree and snapshots use its content id
+    /// (see `header::FEATURE_CONTENT_IDS`), linking it under the new name
+    /// first. Returns the old ids, for the caller to delete once the index
+    /// is saved; until then both names open. Padding can't be renamed: a
+    /// hidden vault in it would lose its blocks.
+    pub fn adopt_content_ids(&mut self, storage: &BlockManager, key: &MasterKey) -> Result<Vec<String>> {
+        if self.hidden || !self.data.padding.is_empty() {
+            anyhow::bail!("The vault has padding, which may hold a hidden vault that renaming would break");
+        }
+        let mut renamed: BTreeMap<String, String> = BTreeMap::new();
+        for id in self.data.referenced_blocks() {
+            if storage::is_content_id(id) {
+                continue;
+            }
+            let data = Zeroizing::new(storage.read_block(id, key)?);
+            let new_id = CryptoEngine::block_id(&data, key);
+            storage.link_block(id, &new_id)?;
+            renamed.insert(id.to_string(), new_id);
+        }
+
+        let entries = self.data.files.values_mut()
+            .chain(self.data.snapshots.values_mut().flat_map(|s| s.files.values_mut()));
+        for entry in entries {
+            for id in entry.blocks.iter_mut() {
+                if let Some(new_id) = renamed.get(id) {
+                    *id = new_id.clone();
+                }
+            }
+        }
+        let placement = std::mem::take(&mut self.data.placement);
+        self.data.placement = placement.into_iter()
+            .map(|(id, volume)| (renamed.get(&id).cloned().unwrap_or(id), volume))
+            .collect();
+        *self.chunk_ids.get_mut().unwrap_or_else(PoisonError::into_inner) = None;
+        Ok(renamed.into_keys().collect())
+    }
+
+    /// Brings `placement` in line with where `storage` finds the blocks.
+    /// Blocks on a detached volume keep their entry; entries of blocks no
+    /// longer referenced go. True if anything changed.
+    pub fn refresh_placement(&mut self, storage: &BlockManager) -> bool {
+        let referenced = self.data.referenced_blocks();
+        let mut placement = BTreeMap::new();
+        for id in referenced {
+            match storage.locate(id) {
+                Some(volumes::MAIN) => {}
+                Some(volume) => {
+                    placement.insert(id.to_string(), volume.to_string());
+                }
+                None => {
+                    if let Some(volume) = self.data.placement.get(id) {
+                        placement.insert(id.to_string(), volume.clone());
+                    }
+                }
+            }
+        }
+        let changed = placement != self.data.placement;
+        self.data.placement = placement;
+        changed
+    }
+
+    // --- Snapshots ---
+
+    pub fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() || name.contains('/') || name.starts_with('.') {
+            return Err(anyhow::anyhow!("Invalid snapshot name: {:?}", name));
+        }
+        if self.data.snapshots.contains_key(name) {
+            return Err(anyhow::anyhow!("Snapshot {:?} already exists", name));
+        }
+        let snapshot = Snapshot { created: now_secs(), files: self.data.files.clone() };
+        self.data.snapshots.insert(name.to_string(), snapshot);
+        Ok(())
+    }
+
+    pub fn delete_snapshot(&mut self, name: &str) -> bool {
+        self.data.snapshots.remove(name).is_some()
+    }
+
+    /// Splits `/.snapshots/<name>/<rest>` into the snapshot and `/<rest>`
+    pub fn snapshot_for(&self, path: &str) -> Option<(&Snapshot, String)> {
+        let rest = path.strip_prefix("/.snapshots/")?;
+        let (name, inner) = rest.split_once('/').unwrap_or((rest, ""));
+        let snapshot = self.data.snapshots.get(name)?;
+        Some((snapshot, format!("/{}", inner.trim_end_matches('/'))))
+    }
+
+    /// Resolves a path under `/.snapshots`
+    pub fn snapshot_node(&self, path: &str) -> Option<SnapshotNode<'_>> {
+        if path == SNAPSHOT_DIR {
+            let newest = self.data.snapshots.values().map(|s| s.created).max().unwrap_or(0);
+            return Some(SnapshotNode::Dir { modified: newest });
+        }
+        let (snapshot, inner) = self.snapshot_for(path)?;
+        if inner == "/" {
+            return Some(SnapshotNode::Dir { modified: snapshot.created });
+        }
+        match snapshot.files.get(&inner).filter(|e| !e.is_expired()) {
+            Some(e) if e.is_dir => Some(SnapshotNode::Dir { modified: e.modified }),
+            Some(e) => Some(SnapshotNode::File(e)),
+            None => {
+                snapshot.files.iter().any(|(k, e)| is_below(k, &inner) && !e.is_expired())
+                    .then_some(SnapshotNode::Dir { modified: snapshot.created })
+            }
+        }
+    }
+
+    /// Direct children of a folder under `/.snapshots`, as (name, is_dir)
+    pub fn snapshot_children(&self, dir: &str) -> Vec<(String, bool)> {
+        if dir == SNAPSHOT_DIR {
+            return self.data.snapshots.keys().map(|name| (name.clone(), true)).collect();
+        }
+        let Some((snapshot, inner)) = self.snapshot_for(dir) else {
+            return Vec::new();
+        };
+        let mut children: BTreeMap<String, bool> = BTreeMap::new();
+        for (path, entry) in snapshot.files.iter().filter(|(_, e)| !e.is_expired()) {
+            let Some(name) = child_name(path, &inner) else { continue };
+            // Anything deeper makes the child a folder
+            let deeper = path.len() > inner.trim_end_matches('/').len() + 1 + name.len();
+            match deeper {
+                true => { children.insert(name.to_string(), true); }
+                false => { children.entry(name.to_string()).or_insert(entry.is_dir); }
+            }
+        }
+        children.into_iter().collect()
+    }
+}
+
+fn changed_since(old: &FileEntry, new: &FileEntry) -> bool {
+    old.size != new.size || old.modified != new.modified || old.blocks != new.blocks || old.is_dir != new.is_dir
+        || old.expires != new.expires || old.immutable != new.immutable
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
\ No newline at end of file
@@ -0,0 +1,387 @@
+//! Device enrollment.
+//!
+//! A vault starts out readable by anyone holding its password. Once
+//! devices are enabled (`lethe devices enable`), its index is sealed with
+//! a random index key instead, and the keyring (`keyring.bin`, sealed with
+//! the master key) holds that key once per enrolled device: a slot. Each
+//! slot is wrapped with a key derived from the master key and a secret
+//! only that device keeps, in the user's config directory. Opening the
+//! index then takes both the password and an enrolled device.
+//!
+//! Device secrets are symmetric, so no device can wrap a new index key for
+//! another without learning its secret. Revoking a device therefore
+//! rotates the index key, keeps the revoking device's slot and issues
+//! fresh join codes for every other device still enrolled. Index updates
+//! sealed after that no longer open through the revoked slot.
+//!
+//! Blocks stay sealed with the master key: a revoked device keeps what it
+//! already holds. What it loses is the index saying which blocks make up
+//! which file, from its revocation on.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::crypto::{CryptoEngine, MasterKey};
+
+pub const KEYRING_FILE: &str = "keyring.bin";
+
+/// Join codes look like `lethe-device:<slot>:<secret>`
+const CODE_PREFIX: &str = "lethe-device:";
+const SECRET_SIZE: usize = 32;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Keyring {
+    /// Bumped by every revocation
+    pub epoch: u64,
+    /// Random id of the current index key, telling apart two copies that
+    /// rotated independently
+    pub generation: String,
+    /// By slot id
+    pub slots: BTreeMap<String, Slot>,
+    /// Every earlier index key, sealed with the current one, so replicas
+    /// not rewritten since a rotation still open. Empty before the first.
+    history: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Slot {
+    pub name: String,
+    pub enrolled: u64,
+    /// The index key sealed with the device's wrapping key (Nonce + Data)
+    wrapped: Vec<u8>,
+}
+
+/// The key the index is sealed with, and the ones it replaced
+#[derive(Debug)]
+pub struct IndexKey {
+    pub current: MasterKey,
+    pub older: Vec<MasterKey>,
+}
+
+/// What an enrolled device keeps to itself
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub struct DeviceKey {
+    pub slot: String,
+    secret: [u8; SECRET_SIZE],
+}
+
+impl DeviceKey {
+    fn generate() -> Self {
+        let mut secret = [0u8; SECRET_SIZE];
+        OsRng.fill_bytes(&mut secret);
+        Self { slot: Uuid::new_v4().to_string(), secret }
+    }
+
+    /// For `lethe devices join` on the device this key was issued to
+    pub fn code(&self) -> String {
+        let secret: String = self.secret.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("{}{}:{}", CODE_PREFIX, self.slot, secret)
+    }
+
+    pub fn from_code(code: &str) -> Result<Self> {
+        let invalid = || anyhow::anyhow!("Not a device code (expected {}<slot>:<secret>)", CODE_PREFIX);
+        let rest = code.trim().strip_prefix(CODE_PREFIX).ok_or_else(invalid)?;
+        let (slot, hex) = rest.split_once(':').ok_or_else(invalid)?;
+        if Uuid::parse_str(slot).is_err() || hex.len() != SECRET_SIZE * 2 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut secret = [0u8; SECRET_SIZE];
+        for (i, byte) in secret.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Self { slot: slot.to_string(), secret })
+    }
+
+    /// Opens this device's slot; needs the master key too
+    fn wrapping_key(&self, key: &MasterKey) -> MasterKey {
+        let mut context = [b"lethe-slot/1:".as_slice(), self.slot.as_bytes(), b":", &self.secret].concat();
+        let wrapping = CryptoEngine::derive_subkey(key, &context);
+        context.zeroize();
+        wrapping
+    }
+}
+
+impl Keyring {
+    /// A keyring whose only slot is a new device named `name`, with the
+    /// index key it guards and that device's key
+    pub fn create(name: &str, key: &MasterKey) -> Result<(Self, IndexKey, DeviceKey)> {
+        let mut ring = Self {
+            epoch: 1,
+            generation: Uuid::new_v4().to_string(),
+            slots: BTreeMap::new(),
+            history: Vec::new(),
+        };
+        let index_key = CryptoEngine::random_key();
+        let device = ring.enroll(name, &index_key, key)?;
+        Ok((ring, IndexKey { current: index_key, older: Vec::new() }, device))
+    }
+
+    /// Adds a slot for a new device and returns its key
+    pub fn enroll(&mut self, name: &str, index_key: &MasterKey, key: &MasterKey) -> Result<DeviceKey> {
+        let device = DeviceKey::generate();
+        self.add_slot(&device, name, index_key, key)?;
+        Ok(device)
+    }
+
+    fn add_slot(&mut self, device: &DeviceKey, name: &str, index_key: &MasterKey, key: &MasterKey) -> Result<()> {
+        let wrapped = seal(index_key.as_bytes(), &device.wrapping_key(key))?;
+        self.slots.insert(device.slot.clone(), Slot { name: name.to_string(), enrolled: now(), wrapped });
+        Ok(())
+    }
+
+    /// The first of `devices` whose slot opens, with the index key in it
+    pub fn find<'a>(&self, devices: &'a [DeviceKey], key: &MasterKey) -> Option<(&'a DeviceKey, MasterKey)> {
+        devices.iter().find_map(|device| {
+            let slot = self.slots.get(&device.slot)?;
+            let plain = open(&slot.wrapped, &device.wrapping_key(key)).ok()?;
+            Some((device, key_from(&plain)?))
+        })
+    }
+
+    /// The index keys, through whichever of `devices` is enrolled here
+    pub fn unlock(&self, devices: &[DeviceKey], key: &MasterKey) -> Option<IndexKey> {
+        let (_, current) = self.find(devices, key)?;
+        let mut older = Vec::new();
+        if !self.history.is_empty() {
+            let plain = open(&self.history, &current).ok()?;
+            let keys: Vec<Vec<u8>> = serde_cbor::from_slice(&plain).ok()?;
+            older = keys.iter().filter_map(|k| key_from(k)).collect();
+        }
+        Some(IndexKey { current, older })
+    }
+
+    /// Drops `slot` and rotates the index key. `me` keeps its slot; every
+    /// other device still enrolled gets a new one, returned with its name
+    /// so it can join again. Returns the new index key too.
+    pub fn revoke(&mut self, slot: &str, me: &DeviceKey, old: &IndexKey, key: &MasterKey) -> Result<(IndexKey, Vec<(String, DeviceKey)>)> {
+        if slot == me.slot {
+            anyhow::bail!("A device can't revoke itself; do it from another enrolled device");
+        }
+        if self.slots.remove(slot).is_none() {
+            anyhow::bail!("No device with slot {}", slot);
+        }
+
+        let index_key = CryptoEngine::random_key();
+        let mut older = vec![old.current.as_bytes().to_vec()];
+        older.extend(old.older.iter().map(|k| k.as_bytes().to_vec()));
+        self.history = seal(&serde_cbor::to_vec(&older)?, &index_key)?;
+        self.epoch += 1;
+        self.generation = Uuid::new_v4().to_string();
+
+        let mut issued = Vec::new();
+        for (id, slot) in std::mem::take(&mut self.slots) {
+            let device = if id == me.slot {
+                self.add_slot(me, &slot.name, &index_key, key)?;
+                id
+            } else {
+                let device = self.enroll(&slot.name, &index_key, key)?;
+                let id = device.slot.clone();
+                issued.push((slot.name, device));
+                id
+            };
+            // Still the same device, enrolled when it first was
+            if let Some(new) = self.slots.get_mut(&device) {
+                new.enrolled = slot.enrolled;
+            }
+        }
+
+        let older = older.iter().filter_map(|k| key_from(k)).collect();
+        Ok((IndexKey { current: index_key, older }, issued))
+    }
+
+    /// None while devices aren't enabled for the vault at `vault`
+    pub fn load(vault: &Path, key: &MasterKey) -> Result<Option<Self>> {
+        let path = vault.join(KEYRING_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let sealed = fs::read(&path).context("Failed to read keyring")?;
+        Self::open_sealed(&sealed, key).map(Some)
+    }
+
+    pub fn save(&self, vault: &Path, key: &MasterKey) -> Result<()> {
+        let tmp = vault.join(format!("{}.tmp", KEYRING_FILE));
+        fs::write(&tmp, self.seal(key)?).context("Failed to write keyring")?;
+        fs::rename(&tmp, vault.join(KEYRING_FILE))?;
+        Ok(())
+    }
+
+    /// The keyring encrypted exactly as it is stored on disk (Nonce + Data)
+    pub fn seal(&self, key: &MasterKey) -> Result<Vec<u8>> {
+        seal(&serde_cbor::to_vec(self).context("Failed to serialize keyring")?, key)
+    }
+
+    pub fn open_sealed(buffer: &[u8], key: &MasterKey) -> Result<Self> {
+        let plain = open(buffer, key).context("Keyring is unreadable. Wrong password?")?;
+        serde_cbor::from_slice(&plain).context("Keyring is corrupted")
+    }
+}
+
+/// The keyring two copies of a vault should both end up with. A later
+/// epoch wins outright (its rotation dropped the revoked slots); within
+/// one epoch the devices enrolled on either side add up.
+pub fn reconcile(ours: Option<&Keyring>, theirs: Option<&Keyring>) -> Result<Option<Keyring>> {
+    match (ours, theirs) {
+        (None, None) => Ok(None),
+        (Some(ring), None) | (None, Some(ring)) => Ok(Some(ring.clone())),
+        (Some(a), Some(b)) if a.epoch != b.epoch => Ok(Some(if a.epoch > b.epoch { a } else { b }.clone())),
+        (Some(a), Some(b)) if a.generation != b.generation => anyhow::bail!(
+            "Both copies of this vault rotated their device keys independently. \
+             Revoke a device again on the copy whose devices should stay; its keyring then wins."
+        ),
+        (Some(a), Some(b)) => {
+            let mut merged = a.clone();
+            for (id, slot) in &b.slots {
+                merged.slots.entry(id.clone()).or_insert_with(|| slot.clone());
+            }
+            Ok(Some(merged))
+        }
+    }
+}
+
+/// The index keys for the copy at `vault`; None while devices aren't
+/// enabled there, an error if this copy isn't enrolled
+pub fn index_key(vault: &Path, key: &MasterKey) -> Result<Option<IndexKey>> {
+    let Some(ring) = Keyring::load(vault, key)? else {
+        return Ok(None);
+    };
+    ring.unlock(&device_keys(vault), key).map(Some).ok_or_else(not_enrolled)
+}
+
+pub fn not_enrolled() -> anyhow::Error {
+    anyhow::anyhow!(
+        "This copy of the vault is not an enrolled device (or was revoked). \
+         Run `lethe devices enroll <name>` on an enrolled device, then `lethe devices join <code>` here."
+    )
+}
+
+// --- Local device keys ---
+
+/// `device-keys` holds one `<slot>\t<secret>\t<vault path>` line per key,
+/// newest last. It is the only place a device secret is stored.
+fn keys_file() -> Option<PathBuf> {
+    Some(dirs::config_dir()?.join("lethe").join("device-keys"))
+}
+
+fn vault_id(vault: &Path) -> String {
+    fs::canonicalize(vault).unwrap_or_else(|_| vault.to_path_buf()).to_string_lossy().into_owned()
+}
+
+/// Every device key kept for the copy at `vault`, newest first
+pub fn device_keys(vault: &Path) -> Vec<DeviceKey> {
+    let Some(path) = keys_file() else {
+        return Vec::new();
+    };
+    let vault = vault_id(vault);
+    let mut contents = fs::read_to_string(path).unwrap_or_default();
+    let mut keys: Vec<DeviceKey> = contents.lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, '\t');
+            let (slot, secret, p) = (parts.next()?, parts.next()?, parts.next()?);
+            if p != vault {
+                return None;
+            }
+            DeviceKey::from_code(&format!("{}{}:{}", CODE_PREFIX, slot, secret)).ok()
+        })
+        .collect();
+    contents.zeroize();
+    keys.reverse();
+    keys
+}
+
+/// Keeps `device` as a key of the copy at `vault`
+pub fn remember(vault: &Path, device: &DeviceKey) -> Result<()> {
+    let path = keys_file().context("No config directory to keep the device key in")?;
+    fs::create_dir_all(path.parent().unwrap())?;
+    let mut contents = fs::read_to_string(&path).unwrap_or_default();
+    let code = device.code();
+    let secret = code.rsplit(':').next().unwrap_or_default();
+    contents.push_str(&format!("{}\t{}\t{}\n", device.slot, secret, vault_id(vault)));
+    write_keys(&path, contents)
+}
+
+/// Moves the device keys of the copy that was at `old` over to `new`,
+/// where it now is; returns how many there were
+pub fn relocate(old: &Path, new: &Path) -> Result<usize> {
+    let Some(path) = keys_file() else {
+        return Ok(0);
+    };
+    let Ok(mut contents) = fs::read_to_string(&path) else {
+        return Ok(0);
+    };
+    let (old, new) = (old.to_string_lossy(), vault_id(new));
+    let mut moved = 0;
+    let mut rewritten = String::with_capacity(contents.len());
+    for line in contents.lines() {
+        let mut parts = line.splitn(3, '\t');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(slot), Some(secret), Some(p)) if p == old => {
+                rewritten.push_str(&format!("{}\t{}\t{}\n", slot, secret, new));
+                moved += 1;
+            }
+            _ => {
+                rewritten.push_str(line);
+                rewritten.push('\n');
+            }
+        }
+    }
+    contents.zeroize();
+    if moved == 0 {
+        rewritten.zeroize();
+        return Ok(0);
+    }
+    write_keys(&path, rewritten)?;
+    Ok(moved)
+}
+
+/// Replaces the keys file with `contents`, readable only by its owner
+fn write_keys(path: &Path, mut contents: String) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let written = options.open(&tmp)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .and_then(|_| fs::rename(&tmp, path));
+    contents.zeroize();
+    written.with_context(|| format!("Failed to write {:?}", path))
+}
+
+// --- Helper Functions ---
+
+/// Nonce + Data, as the index replicas are stored
+pub(crate) fn seal(data: &[u8], key: &MasterKey) -> Result<Vec<u8>> {
+    let (ciphertext, mut sealed) = CryptoEngine::encrypt(data, key)?;
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+pub(crate) fn open(sealed: &[u8], key: &MasterKey) -> Result<Vec<u8>> {
+    if sealed.len() < 24 {
+        anyhow::bail!("Sealed data too short");
+    }
+    let (nonce, ciphertext) = sealed.split_at(24);
+    CryptoEngine::decrypt(ciphertext, nonce, key)
+}
+
+pub(crate) fn key_from(bytes: &[u8]) -> Option<MasterKey> {
+    Some(MasterKey::new(bytes.try_into().ok()?))
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
@@ -1,6 +1,60 @@
+//! Without the default `fs` feature only what works on bytes in memory is
+//! built: the crypto, chunking and reading share bundles. That much needs
+//! no file system, so it builds for `wasm32-unknown-unknown` as well.
+
+#[cfg(feature = "fs")]
+pub mod access;
+#[cfg(feature = "fs")]
+pub mod activity;
+#[cfg(feature = "fs")]
+pub mod audit;
+pub mod cache;
+pub mod chunker;
 pub mod crypto;
+#[cfg(feature = "fs")]
+pub mod events;
+#[cfg(feature = "fs")]
+pub mod growth;
+#[cfg(feature = "fs")]
+pub mod header;
+#[cfg(feature = "fs")]
+pub mod hidden;
+#[cfg(feature = "fs")]
 pub mod storage;
+#[cfg(feature = "fs")]
 pub mod index;
 pub mod config;
+#[cfg(feature = "fs")]
+pub mod container;
+#[cfg(feature = "fs")]
+pub mod device;
+#[cfg(feature = "fs")]
+pub mod keyring;
+#[cfg(feature = "fs")]
+pub mod lockout;
+#[cfg(feature = "fs")]
+pub mod mapped;
+pub mod manifest;
+#[cfg(feature = "fs")]
+pub mod merge;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+#[cfg(feature = "fs")]
+pub mod pack;
+pub mod plaintext;
+pub mod progress;
+#[cfg(feature = "fs")]
+pub mod reader;
+#[cfg(feature = "fs")]
+pub mod retention;
+#[cfg(feature = "fs")]
+pub mod search;
+pub mod share;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+#[cfg(feature = "fs")]
+pub mod vault_lock;
+#[cfg(feature = "fs")]
+pub mod volumes;
 
 pub use config::VaultConfig;
\ No newline at end of file
@@ -0,0 +1,106 @@
+//! Opt-in destruction of a vault after too many failed unlocks in a row.
+//!
+//! A vault opts in at init (`VaultHeader::wipe_after`). `unlock.failures`
+//! counts the failed unlocks since the last one that worked; a right
+//! password, or a hidden vault's, resets it. When it reaches the limit the
+//! header, `salt.loader` and the keyring are overwritten and deleted.
+//! Without the salt no password derives the key again, so the blocks and
+//! replicas left behind can't be read by anyone, the owner included.
+//!
+//! A failure is a key that doesn't match `VaultHeader::key_check`, never
+//! one that merely can't read a damaged keyring or index. A hidden vault's
+//! key matches no check and counts unless its index is found. Headers from
+//! before the check get one on the next unlock that opens the vault; until
+//! then nothing is counted.
+//!
+//! This only guards against guessing through Lethe. The counter is a
+//! plain file, and copies, backups and remotes of the vault keep their
+//! own header.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::activity;
+use crate::crypto::MasterKey;
+use crate::header::{self, VaultHeader};
+use crate::hidden;
+use crate::index::IndexManager;
+use crate::keyring::{self, Keyring};
+use crate::storage::{self, BlockManager};
+
+pub const FAILURES_FILE: &str = "unlock.failures";
+
+/// Failed unlocks since the last one that worked
+pub fn failures(vault: &Path) -> u32 {
+    fs::read_to_string(vault.join(FAILURES_FILE))
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Whether `key` is that of a hidden vault in `vault`
+fn opens_hidden(vault: &Path, key: &MasterKey) -> bool {
+    BlockManager::new(vault).is_ok_and(|storage| hidden::holds(&storage, key))
+}
+
+/// Whether `key` opens the vault itself. Only for headers without a key
+/// check, to give them one.
+fn opens_outer(vault: &Path, key: &MasterKey) -> bool {
+    // On a device that isn't enrolled only the keyring tells
+    match Keyring::load(vault, key) {
+        Ok(Some(_)) => true,
+        Ok(None) => IndexManager::read_replicas(vault, key, None).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Counts an unlock of the vault at `vault` with `key` under the policy
+/// in `header`. A key that doesn't match the header's check is an error;
+/// the one that reaches the limit destroys the vault first.
+pub fn check(vault: &Path, header: &VaultHeader, key: &MasterKey) -> Result<()> {
+    let Some(limit) = header.wipe_after else {
+        return Ok(());
+    };
+    let right = match header.checks_key(key) {
+        Some(matches) => matches || opens_hidden(vault, key),
+        None if opens_outer(vault, key) => {
+            let mut header = header.clone();
+            header.set_key_check(key);
+            header.save(vault).context("Failed to add a key check to the vault header")?;
+            true
+        }
+        None if opens_hidden(vault, key) => true,
+        // Nothing tells a wrong password from a damaged vault; not counted
+        None => return Ok(()),
+    };
+    if right {
+        if failures(vault) > 0 {
+            fs::remove_file(vault.join(FAILURES_FILE)).context("Failed to reset the failed unlock count")?;
+        }
+        return Ok(());
+    }
+
+    let failed = failures(vault) + 1;
+    if failed >= limit {
+        wipe(vault)?;
+        anyhow::bail!("Wrong password. That was failed unlock {} of {}: the vault has been destroyed.", failed, limit);
+    }
+    fs::write(vault.join(FAILURES_FILE), failed.to_string()).context("Failed to record a failed unlock")?;
+    activity::failed(vault);
+    anyhow::bail!("Wrong password. The vault is destroyed after {} more failed unlock(s).", limit - failed)
+}
+
+/// Shreds everything a key is derived or unwrapped with
+fn wipe(vault: &Path) -> Result<()> {
+    let names = [header::HEADER_FILE, header::LEGACY_SALT_FILE, keyring::KEYRING_FILE].into_iter().chain(header::HEADER_BACKUPS);
+    for name in names {
+        let path = vault.join(name);
+        if path.exists() {
+            storage::shred(&path).with_context(|| format!("Failed to destroy {}", name))?;
+        }
+    }
+    let _ = fs::remove_file(vault.join(FAILURES_FILE));
+    Ok(())
+}
@@ -0,0 +1,112 @@
+//! Integrity manifests: a signed list of a vault's block files and their
+//! hashes, checkable by anyone holding the blocks but not the password
+//! (`lethe verify`), e.g. a storage provider or a backup machine.
+//!
+//! A block is listed by its id and the BLAKE2b-256 of its file, ciphertext
+//! and all, which tells nothing about what it holds. The list is signed
+//! with Ed25519, by a keypair derived from the vault key: every manifest
+//! of the vault has the same public key, and any number can be signed,
+//! from any copy, with nothing to keep track of.
+
+use blake2::digest::consts::U32;
+use blake2::{Blake2b, Digest};
+use ed25519_dalek::{Signature, Signer as _, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+
+use crate::crypto::{CryptoEngine, MasterKey};
+
+pub const MAGIC: &[u8; 8] = b"LETHE\x00M2";
+/// What manifests signed with one-time keys started with
+const OLD_MAGIC: &[u8; 8] = b"LETHE\x00MF";
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The public key a manifest names, if it is one
+fn verifying_key(text: &str) -> Option<VerifyingKey> {
+    if text.len() != 64 {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    VerifyingKey::from_bytes(&key).ok()
+}
+
+/// BLAKE2b-256 of a block file, as listed in a manifest
+pub fn file_hash(bytes: &[u8]) -> String {
+    hex(&Blake2b::<U32>::digest(bytes))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    /// Unix timestamp
+    pub created: u64,
+    /// The vault's Ed25519 public key (hex)
+    pub public_key: String,
+    /// Block id -> `file_hash` of its file
+    pub blocks: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Signed {
+    manifest: Manifest,
+    /// Ed25519 signature over the manifest's CBOR
+    signature: Vec<u8>,
+}
+
+/// Signs manifests with the keypair derived from a vault key
+pub struct Signer {
+    key: SigningKey,
+}
+
+impl Signer {
+    pub fn new(key: &MasterKey) -> Self {
+        let seed = CryptoEngine::derive_subkey(key, b"lethe manifest signing");
+        Self { key: SigningKey::from_bytes(seed.as_bytes()) }
+    }
+
+    /// The vault's public key (hex): what `lethe verify --key` checks for
+    pub fn public_key(&self) -> String {
+        hex(self.key.verifying_key().as_bytes())
+    }
+
+    /// Signs `blocks`. Returns the manifest file.
+    pub fn sign(&self, created: u64, blocks: BTreeMap<String, String>) -> Result<Vec<u8>> {
+        let manifest = Manifest { created, public_key: self.public_key(), blocks };
+        let signature = self.key.sign(&signed_bytes(&manifest)?).to_bytes().to_vec();
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(serde_cbor::to_vec(&Signed { manifest, signature }).context("Failed to serialize the manifest")?);
+        Ok(bytes)
+    }
+}
+
+/// What the signature covers
+fn signed_bytes(manifest: &Manifest) -> Result<Vec<u8>> {
+    let mut bytes = b"lethe manifest".to_vec();
+    bytes.extend(serde_cbor::to_vec(manifest).context("Failed to serialize the manifest")?);
+    Ok(bytes)
+}
+
+/// Reads a manifest file and checks its signature against the public key
+/// it names. Whether that key is the vault's is up to the caller.
+pub fn open(bytes: &[u8]) -> Result<Manifest> {
+    if bytes.starts_with(OLD_MAGIC) {
+        anyhow::bail!("The manifest is from an older Lethe, signed with one-time keys; make a new one with `lethe manifest`");
+    }
+    let body = bytes.strip_prefix(MAGIC.as_slice()).context("Not a Lethe manifest")?;
+    let Signed { manifest, signature } = serde_cbor::from_slice(body).context("The manifest is corrupted")?;
+
+    let (Some(public_key), Ok(signature)) = (verifying_key(&manifest.public_key), Signature::from_slice(&signature)) else {
+        anyhow::bail!("The manifest's signature is malformed");
+    };
+    if public_key.verify_strict(&signed_bytes(&manifest)?, &signature).is_err() {
+        anyhow::bail!("The manifest's signature doesn't match: it was altered, or not signed by its key");
+    }
+    Ok(manifest)
+}
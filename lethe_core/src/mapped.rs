@@ -0,0 +1,97 @@
+//! Reading large files through a memory map.
+//!
+//! A block or index replica is decrypted straight from the page cache
+//! instead of being copied into a buffer first, so a multi-megabyte block
+//! is held once (as plaintext) rather than twice. Small files, platforms
+//! without `mmap` and files that refuse to map are read as before. A
+//! mapped file must not shrink while mapped: blocks are replaced by rename
+//! and rewritten in place only at the same length, under the vault lock.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::Deref;
+use std::path::Path;
+
+/// Files smaller than this are read; mapping costs more than the copy
+const MAP_FROM: u64 = 1024 * 1024;
+
+/// The bytes of a file, mapped or read
+pub enum Contents {
+    #[cfg(unix)]
+    Mapped(Map),
+    Read(Vec<u8>),
+}
+
+impl Deref for Contents {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            #[cfg(unix)]
+            Contents::Mapped(map) => map,
+            Contents::Read(bytes) => bytes,
+        }
+    }
+}
+
+/// A read-only private mapping of a whole file, unmapped on drop
+#[cfg(unix)]
+pub struct Map {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only and owned by this value alone
+#[cfg(unix)]
+unsafe impl Send for Map {}
+#[cfg(unix)]
+unsafe impl Sync for Map {}
+
+#[cfg(unix)]
+impl Map {
+    fn new(file: &File, len: usize) -> io::Result<Self> {
+        use std::os::fd::AsRawFd;
+        // SAFETY: a fresh read-only mapping of an open file, `len` bytes long
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Read once, front to back
+        // SAFETY: advice on the range just mapped
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Self { ptr, len })
+    }
+}
+
+#[cfg(unix)]
+impl Deref for Map {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `len` readable bytes are mapped at `ptr` until drop
+        unsafe { std::slice::from_raw_parts(self.ptr.cast(), self.len) }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Map {
+    fn drop(&mut self) {
+        // SAFETY: unmaps exactly what `new` mapped
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// The whole of the file at `path`, mapped if it is large enough
+pub fn read(path: &Path) -> io::Result<Contents> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    #[cfg(unix)]
+    if len >= MAP_FROM {
+        if let Ok(map) = Map::new(&file, len as usize) {
+            return Ok(Contents::Mapped(map));
+        }
+    }
+    let mut bytes = Vec::with_capacity(len as usize);
+    (&file).read_to_end(&mut bytes)?;
+    Ok(Contents::Read(bytes))
+}
@@ -0,0 +1,228 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::index::{FileEntry, VaultIndex};
+
+/// Marks a conflict copy: `report (conflict from laptop 2024-06-01).docx`
+const CONFLICT_MARK: &str = " (conflict from ";
+
+/// Result of combining two copies of an index
+pub struct Merged {
+    pub index: VaultIndex,
+    /// Files both copies changed independently
+    pub conflicts: Vec<Conflict>,
+}
+
+/// A file changed on both sides. The newer version stays at `path`, the
+/// other is kept next to it at `copy`.
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub path: String,
+    pub copy: String,
+}
+
+impl Merged {
+    /// Whether adopting the merge changes `index`
+    pub fn differs_from(&self, index: &VaultIndex) -> bool {
+        self.index.clock != index.clock
+            || self.index.hlc != index.hlc
+            || self.index.files != index.files
+            || !self.index.snapshots.keys().eq(index.snapshots.keys())
+            || self.index.users != index.users
+            || self.index.acls != index.acls
+            || self.index.padding != index.padding
+            || self.index.entry_floor != index.entry_floor
+            || self.index.windows_names != index.windows_names
+            || self.index.retention != index.retention
+    }
+}
+
+/// Combines two copies of the same vault's index, path by path.
+///
+/// An entry one copy changed after the other last saw it wins; one the
+/// other copy already knew and dropped was deleted there. When both
+/// changed the same file, the later change stays and the other becomes a
+/// conflict copy. Both sides get the same result whichever is `ours`.
+pub fn merge(ours: &VaultIndex, theirs: &VaultIndex) -> Merged {
+    let paths: BTreeSet<&String> = ours.files.keys().chain(theirs.files.keys()).collect();
+    let mut files = HashMap::new();
+    let mut losers = Vec::new();
+
+    for path in paths {
+        let kept = match (ours.files.get(path), theirs.files.get(path)) {
+            (Some(a), Some(b)) if a == b => Some(a),
+            (Some(a), Some(b)) => {
+                let a_superseded = theirs.has_seen(&a.dot);
+                let b_superseded = ours.has_seen(&b.dot);
+                match (a_superseded, b_superseded) {
+                    (true, false) => Some(b),
+                    (false, true) => Some(a),
+                    _ => {
+                        let (winner, loser) = if is_newer(a, b) { (a, b) } else { (b, a) };
+                        if !same_content(a, b) && !loser.is_dir {
+                            losers.push((path.clone(), loser));
+                        }
+                        Some(winner)
+                    }
+                }
+            }
+            // Kept unless the other copy knew this version and dropped it
+            (Some(a), None) => (!theirs.has_seen(&a.dot)).then_some(a),
+            (None, Some(b)) => (!ours.has_seen(&b.dot)).then_some(b),
+            (None, None) => None,
+        };
+        if let Some(entry) = kept {
+            files.insert(path.clone(), entry.clone());
+        }
+    }
+
+    let mut devices = theirs.devices.clone();
+    devices.extend(ours.devices.iter().map(|(id, name)| (id.clone(), name.clone())));
+
+    // Losing versions move next to the winners. A copy keeps its dot, so
+    // deleting it on one side later deletes it everywhere.
+    let mut conflicts = Vec::new();
+    for (path, loser) in losers {
+        let device = loser.dot.as_ref()
+            .and_then(|d| devices.get(&d.device))
+            .map(String::as_str)
+            .unwrap_or("another device");
+        let copy = free_conflict_path(&path, device, loser.modified, &files);
+        files.insert(copy.clone(), FileEntry { path: copy.clone(), ..loser.clone() });
+        conflicts.push(Conflict { path, copy });
+    }
+
+    let mut clock = ours.clock.clone();
+    for (device, counter) in &theirs.clock {
+        let mine = clock.entry(device.clone()).or_insert(0);
+        *mine = (*mine).max(*counter);
+    }
+
+    // Snapshots are never edited, only added; a name taken on both sides
+    // keeps the later one
+    let mut snapshots = ours.snapshots.clone();
+    for (name, snapshot) in &theirs.snapshots {
+        match snapshots.get(name) {
+            Some(mine) if mine.created >= snapshot.created => {}
+            _ => {
+                snapshots.insert(name.clone(), snapshot.clone());
+            }
+        }
+    }
+
+    // Users and rules carry the time of their last change, removals included
+    let users = later_wins(&ours.users, &theirs.users, |u| (u.modified, u.removed));
+    let acls = later_wins(&ours.acls, &theirs.acls, |r| (r.modified, r.removed));
+
+    let index = VaultIndex {
+        version: ours.version.max(theirs.version),
+        revision: ours.revision.max(theirs.revision),
+        salt: ours.salt.clone(),
+        files,
+        snapshots,
+        clock,
+        devices,
+        hlc: ours.hlc.clone().max(theirs.hlc.clone()),
+        users,
+        acls,
+        placement: ours.placement.clone(),
+        // Padding is only ever added
+        padding: ours.padding.union(&theirs.padding).cloned().collect(),
+        entry_floor: ours.entry_floor.max(theirs.entry_floor),
+        // Either side's setting, as long as both pick the same
+        chunk_size: ours.chunk_size.max(theirs.chunk_size),
+        // On in either copy is on in both
+        windows_names: ours.windows_names || theirs.windows_names,
+        // The policy set last, on whichever copy
+        retention: std::cmp::max_by_key(&ours.retention, &theirs.retention, |r| (r.modified, r.every, r.keep, r.max_age)).clone(),
+        filler: String::new(),
+    };
+    Merged { index, conflicts }
+}
+
+/// Per key, the value changed last. Within the same second a removal
+/// wins, then the greater encoding, so both sides agree.
+fn later_wins<T, K>(ours: &BTreeMap<String, T>, theirs: &BTreeMap<String, T>, stamp: impl Fn(&T) -> K) -> BTreeMap<String, T>
+where
+    T: Clone + serde::Serialize,
+    K: Ord,
+{
+    let mut merged = ours.clone();
+    for (name, value) in theirs {
+        let take = match merged.get(name) {
+            None => true,
+            Some(mine) => match stamp(value).cmp(&stamp(mine)) {
+                Ordering::Greater => true,
+                Ordering::Less => false,
+                Ordering::Equal => serde_cbor::to_vec(value).ok() > serde_cbor::to_vec(mine).ok(),
+            },
+        };
+        if take {
+            merged.insert(name.clone(), value.clone());
+        }
+    }
+    merged
+}
+
+/// Folders only differ in mtime, which isn't worth a conflict
+fn same_content(a: &FileEntry, b: &FileEntry) -> bool {
+    match (a.is_dir, b.is_dir) {
+        (true, true) => true,
+        (false, false) => a.size == b.size && a.blocks == b.blocks,
+        _ => false,
+    }
+}
+
+/// Deterministic pick between two concurrent versions: the later save by
+/// hybrid clock, or for entries from before those, the later mtime
+fn is_newer(a: &FileEntry, b: &FileEntry) -> bool {
+    match (&a.hlc, &b.hlc) {
+        (Some(x), Some(y)) => x >= y,
+        _ => (a.modified, &a.dot).cmp(&(b.modified, &b.dot)) != Ordering::Less,
+    }
+}
+
+// --- Conflict copies ---
+
+/// `/docs/report.docx` -> `/docs/report (conflict from laptop 2024-06-01).docx`
+fn conflict_path(path: &str, device: &str, modified: u64, attempt: usize) -> String {
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    let device = device.replace(['/', '(', ')'], "-");
+    let suffix = if attempt > 1 { format!(" {}", attempt) } else { String::new() };
+    format!("{}/{}{}{} {}{}){}", dir, stem, CONFLICT_MARK, device, date(modified), suffix, ext)
+}
+
+/// The first conflict copy name for `path` that `files` doesn't hold yet
+pub fn free_conflict_path(path: &str, device: &str, modified: u64, files: &HashMap<String, FileEntry>) -> String {
+    (1..)
+        .map(|attempt| conflict_path(path, device, modified, attempt))
+        .find(|candidate| !files.contains_key(candidate))
+        .unwrap()
+}
+
+/// The path a conflict copy was made for, if `path` is one
+pub fn conflict_original(path: &str) -> Option<String> {
+    let (dir, name) = path.rsplit_once('/')?;
+    let start = name.find(CONFLICT_MARK)?;
+    let end = start + name[start..].find(')')?;
+    Some(format!("{}/{}{}", dir, &name[..start], &name[end + 1..]))
+}
+
+/// `YYYY-MM-DD` (UTC) for a Unix timestamp
+pub(crate) fn date(secs: u64) -> String {
+    // Civil-from-days, after Howard Hinnant's date algorithms
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
@@ -0,0 +1,146 @@
+//! `async` counterparts of the vault operations, for servers and GUIs on a
+//! tokio runtime (the `async` feature).
+//!
+//! Block IO, compression and encryption are blocking work; done inside an
+//! async handler they stall every other request on that executor thread.
+//! Everything here runs the blocking call on tokio's blocking pool
+//! instead and awaits it. The index sits behind a tokio `RwLock`: lookups
+//! share it, one task changes it at a time, block reads happen with it
+//! released, and a stored file's blocks are written with it shared.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::sync::RwLock;
+
+use crate::crypto::MasterKey;
+use crate::index::IndexManager;
+use crate::plaintext::{self, Zeroizing};
+use crate::storage::BlockManager;
+
+/// Runs `f` on the blocking pool and waits for it
+pub async fn run<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await.context("Vault task panicked")?
+}
+
+// --- Storage ---
+
+pub async fn read_block(storage: Arc<BlockManager>, block_id: String, key: Arc<MasterKey>) -> Result<Vec<u8>> {
+    run(move || storage.read_block(&block_id, &key)).await
+}
+
+pub async fn write_block(storage: Arc<BlockManager>, data: Vec<u8>, key: Arc<MasterKey>) -> Result<String> {
+    run(move || storage.write_block(&data, &key)).await
+}
+
+pub async fn list_blocks(storage: Arc<BlockManager>) -> Result<Vec<(String, u64)>> {
+    run(move || storage.list_blocks()).await
+}
+
+// --- Index ---
+
+pub async fn load_index(path: PathBuf, key: Arc<MasterKey>) -> Result<IndexManager> {
+    run(move || IndexManager::load(path, &key)).await
+}
+
+// --- Vault ---
+
+/// An unlocked vault: its index, block storage and key, cheap to clone
+/// into every task that needs them
+#[derive(Clone, Debug)]
+pub struct Vault {
+    pub index: Arc<RwLock<IndexManager>>,
+    pub storage: Arc<BlockManager>,
+    pub key: Arc<MasterKey>,
+}
+
+impl Vault {
+    /// Loads the index and opens the block storage at `path`
+    pub async fn open(path: PathBuf, key: MasterKey) -> Result<Self> {
+        let key = Arc::new(key);
+        let unlocked = key.clone();
+        let (index, storage) = run(move || {
+            let storage = BlockManager::new(&path)?;
+            Ok((IndexManager::load(path, &unlocked)?, storage))
+        }).await?;
+        Ok(Self { index: Arc::new(RwLock::new(index)), storage: Arc::new(storage), key })
+    }
+
+    /// Runs `f` on the blocking pool with the index held
+    pub async fn with_index<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut IndexManager, &BlockManager, &MasterKey) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut index = self.index.clone().write_owned().await;
+        let (storage, key) = (self.storage.clone(), self.key.clone());
+        run(move || f(&mut index, &storage, &key)).await
+    }
+
+    /// Runs `f` on the blocking pool with the index shared: other readers
+    /// go on alongside, writers wait
+    pub async fn read_index<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&IndexManager, &BlockManager, &MasterKey) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let index = self.index.clone().read_owned().await;
+        let (storage, key) = (self.storage.clone(), self.key.clone());
+        run(move || f(&index, &storage, &key)).await
+    }
+
+    /// Reads and joins `blocks`, e.g. a file's, without holding the index
+    pub async fn read_blocks(&self, blocks: Vec<String>) -> Result<Vec<u8>> {
+        let (storage, key) = (self.storage.clone(), self.key.clone());
+        run(move || {
+            let mut data = plaintext::with_capacity(0);
+            for block in storage.read_blocks(&blocks, &key)? {
+                plaintext::append(&mut data, &block);
+            }
+            Ok(std::mem::take(&mut *data))
+        }).await
+    }
+
+    /// The contents of the file at `path`; None if there is no such file
+    pub async fn read_file(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        let blocks = match self.index.read().await.get_file(path) {
+            Some(entry) if !entry.is_dir => entry.blocks.clone(),
+            _ => return Ok(None),
+        };
+        self.read_blocks(blocks).await.map(Some)
+    }
+
+    /// Stores `data` at `path` and saves the index, wiping `data` after.
+    /// Returns the number of new blocks written.
+    pub async fn store_file(&self, path: String, data: Vec<u8>) -> Result<usize> {
+        let data = Zeroizing::new(data);
+        if self.index.read().await.is_hidden() {
+            return self.with_index(move |index, storage, key| {
+                let written = index.store_file(storage, key, path, &data)?;
+                index.save(key)?;
+                Ok(written)
+            }).await;
+        }
+        let checked = path.clone();
+        let staged = self.read_index(move |index, storage, key| {
+            index.check_mutable(&checked)?;
+            index.stage_reader(storage, key, data.as_slice())
+        }).await?;
+        self.with_index(move |index, _, key| {
+            index.check_mutable(&path)?;
+            let written = index.commit(path, staged);
+            index.save(key)?;
+            Ok(written)
+        }).await
+    }
+
+    /// Saves the index
+    pub async fn save(&self) -> Result<()> {
+        self.with_index(|index, _, key| index.save(key)).await
+    }
+}
@@ -0,0 +1,205 @@
+//! Packs: a whole vault in one file, to mail or upload as a single object
+//! (`lethe pack`, `lethe unpack`).
+//!
+//! The file is `magic || header || files || table || trailer`. The vault
+//! header comes first as it is (u32 length, bytes), so the key can be
+//! derived from the password before anything else is read. The keyring,
+//! the index replicas and every block follow as they are on disk; they are
+//! encrypted already. The table gives each file's name, offset, length and
+//! keyed hash, sealed under a key derived from the vault key: a wrong
+//! password, a damaged file or one swapped for another is caught, and the
+//! names can't be read without the password. The trailer is the table's
+//! offset and length and the magic again, so any file is two seeks away.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{CryptoEngine, MasterKey};
+use crate::header::{VaultHeader, HEADER_FILE};
+use crate::keyring::KEYRING_FILE;
+use crate::storage::BlockManager;
+
+const MAGIC: &[u8; 8] = b"LETHEPK1";
+const TRAILER_LEN: u64 = 8 + 8 + MAGIC.len() as u64;
+const NONCE_LEN: usize = 24;
+/// Index replicas a vault folder keeps (`meta_0.bin` and on)
+const REPLICAS: usize = 3;
+
+const NOT_OPENED: &str = "Wrong password, or not a Lethe pack";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PackEntry {
+    /// File name in the vault folder
+    pub name: String,
+    pub offset: u64,
+    pub len: u64,
+    /// Keyed hash of the contents
+    hash: String,
+}
+
+pub struct Pack {
+    file: File,
+    pub header: VaultHeader,
+    header_bytes: Vec<u8>,
+    pub entries: Vec<PackEntry>,
+    key: MasterKey,
+}
+
+fn pack_key(key: &MasterKey) -> MasterKey {
+    CryptoEngine::derive_subkey(key, b"lethe pack")
+}
+
+/// The files of the vault at `vault` that go into a pack, by name
+fn vault_files(vault: &Path) -> Result<Vec<(String, std::path::PathBuf)>> {
+    let mut files = Vec::new();
+    let names = std::iter::once(KEYRING_FILE.to_string()).chain((0..REPLICAS).map(|i| format!("meta_{}.bin", i)));
+    for name in names {
+        let path = vault.join(&name);
+        if path.is_file() {
+            files.push((name, path));
+        }
+    }
+    // Every block, padding and hidden vault ones included, from every volume
+    let storage = BlockManager::new(vault)?;
+    let mut blocks = storage.list_blocks()?;
+    blocks.sort();
+    for (id, _) in blocks {
+        files.push((format!("blk_{}.bin", id), storage.block_path(&id)?));
+    }
+    Ok(files)
+}
+
+/// Writes the vault at `vault`, unlocked with `key`, to a pack at `out`.
+/// Returns what went in.
+pub fn create(vault: &Path, key: &MasterKey, out: &Path) -> Result<Vec<PackEntry>> {
+    let header = fs::read(vault.join(HEADER_FILE)).context("Failed to read the vault header")?;
+    let files = vault_files(vault)?;
+    let key = pack_key(key);
+
+    let name = out.file_name().context("A pack has to be a file")?.to_string_lossy();
+    let temp = out.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+    let written = write_pack(&temp, &header, &files, &key);
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    let entries = written?;
+    fs::rename(&temp, out).with_context(|| format!("Failed to write {:?}", out))?;
+    Ok(entries)
+}
+
+fn write_pack(temp: &Path, header: &[u8], files: &[(String, std::path::PathBuf)], key: &MasterKey) -> Result<Vec<PackEntry>> {
+    let mut out = BufWriter::new(File::create(temp).with_context(|| format!("Failed to create {:?}", temp))?);
+    out.write_all(MAGIC)?;
+    out.write_all(&(header.len() as u32).to_le_bytes())?;
+    out.write_all(header)?;
+    let mut offset = (MAGIC.len() + 4 + header.len()) as u64;
+
+    let mut entries = Vec::with_capacity(files.len());
+    for (name, path) in files {
+        let data = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        out.write_all(&data)?;
+        entries.push(PackEntry { name: name.clone(), offset, len: data.len() as u64, hash: CryptoEngine::keyed_hash(&data, key) });
+        offset += data.len() as u64;
+    }
+
+    let table = serde_cbor::to_vec(&entries).context("Failed to encode the pack table")?;
+    let (sealed, nonce) = CryptoEngine::encrypt(&table, key)?;
+    out.write_all(&nonce)?;
+    out.write_all(&sealed)?;
+    out.write_all(&offset.to_le_bytes())?;
+    out.write_all(&((nonce.len() + sealed.len()) as u64).to_le_bytes())?;
+    out.write_all(MAGIC)?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(entries)
+}
+
+/// The vault header at the front of the pack at `path`, read without the password
+pub fn read_header(path: &Path) -> Result<VaultHeader> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let (header, _) = header_of(&mut file)?;
+    Ok(header)
+}
+
+fn header_of(file: &mut File) -> Result<(VaultHeader, Vec<u8>)> {
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic).context("Not a Lethe pack")?;
+    if &magic != MAGIC {
+        anyhow::bail!("Not a Lethe pack");
+    }
+    let mut len = [0u8; 4];
+    file.read_exact(&mut len).context("The pack is cut short")?;
+    let mut bytes = vec![0u8; u32::from_le_bytes(len) as usize];
+    file.read_exact(&mut bytes).context("The pack is cut short")?;
+    Ok((VaultHeader::parse(&bytes)?, bytes))
+}
+
+impl Pack {
+    /// Opens the pack at `path` with the vault password. Returns it with the
+    /// vault key.
+    pub fn open(path: &Path, password: &str) -> Result<(Self, MasterKey)> {
+        let mut file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+        let (header, header_bytes) = header_of(&mut file)?;
+        let vault_key = header.derive_key(password)?;
+        let key = pack_key(&vault_key);
+
+        let len = file.metadata()?.len();
+        let start = (MAGIC.len() + 4 + header_bytes.len()) as u64;
+        if len < start + TRAILER_LEN {
+            anyhow::bail!("The pack is cut short");
+        }
+        let mut trailer = [0u8; TRAILER_LEN as usize];
+        file.seek(SeekFrom::Start(len - TRAILER_LEN))?;
+        file.read_exact(&mut trailer)?;
+        if &trailer[16..] != MAGIC {
+            anyhow::bail!("The pack is cut short");
+        }
+        let table_at = u64::from_le_bytes(trailer[..8].try_into().expect("8 bytes"));
+        let table_len = u64::from_le_bytes(trailer[8..16].try_into().expect("8 bytes"));
+        if table_at < start || table_at.checked_add(table_len) != Some(len - TRAILER_LEN) || table_len < NONCE_LEN as u64 {
+            anyhow::bail!("The pack is damaged");
+        }
+
+        let mut table = vec![0u8; table_len as usize];
+        file.seek(SeekFrom::Start(table_at))?;
+        file.read_exact(&mut table)?;
+        let (nonce, sealed) = table.split_at(NONCE_LEN);
+        let table = CryptoEngine::decrypt(sealed, nonce, &key).context(NOT_OPENED)?;
+        let entries: Vec<PackEntry> = serde_cbor::from_slice(&table).context("The pack table is damaged")?;
+        for entry in &entries {
+            // Names land in a folder on unpack, so nothing but a plain name
+            if entry.name.is_empty() || entry.name.contains(['/', '\\']) || entry.name.starts_with('.') {
+                anyhow::bail!("The pack names a file outside its folder: {}", entry.name);
+            }
+            if entry.offset < start || entry.offset.checked_add(entry.len).is_none_or(|end| end > table_at) {
+                anyhow::bail!("The pack is damaged ({} lies outside it)", entry.name);
+            }
+        }
+        Ok((Self { file, header, header_bytes, entries, key }, vault_key))
+    }
+
+    /// The contents of `entry`, checked against its hash
+    pub fn read(&mut self, entry: &PackEntry) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; entry.len as usize];
+        self.file.seek(SeekFrom::Start(entry.offset))?;
+        self.file.read_exact(&mut data)?;
+        if CryptoEngine::keyed_hash(&data, &self.key) != entry.hash {
+            anyhow::bail!("{} is damaged in the pack", entry.name);
+        }
+        Ok(data)
+    }
+
+    /// Writes the vault into `dir`, checking every file on the way
+    pub fn extract(&mut self, dir: &Path) -> Result<()> {
+        fs::write(dir.join(HEADER_FILE), &self.header_bytes).context("Failed to write the vault header")?;
+        for entry in self.entries.clone() {
+            let data = self.read(&entry)?;
+            let target = dir.join(&entry.name);
+            fs::write(&target, data).with_context(|| format!("Failed to write {:?}", target))?;
+        }
+        Ok(())
+    }
+}
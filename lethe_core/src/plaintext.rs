@@ -0,0 +1,52 @@
+//! Buffers holding decrypted file content.
+//!
+//! Freed memory isn't cleared: a `Vec` of plaintext that is dropped, or
+//! that outgrows its allocation and moves, leaves the old bytes in the
+//! heap for whatever reads it next. A `Plaintext` is wiped when dropped,
+//! and grows via `resize` and `write_at`, which wipe the allocation they
+//! leave behind.
+
+pub use zeroize::{Zeroize, Zeroizing};
+
+pub type Plaintext = Zeroizing<Vec<u8>>;
+
+/// An empty buffer with room for `capacity` bytes
+pub fn with_capacity(capacity: usize) -> Plaintext {
+    Zeroizing::new(Vec::with_capacity(capacity))
+}
+
+/// Makes room for `len` bytes in all. Moving to a bigger allocation is done
+/// by hand so the old one is wiped rather than freed as it is.
+pub fn reserve(buf: &mut Vec<u8>, len: usize) {
+    if len <= buf.capacity() {
+        return;
+    }
+    let mut grown = Vec::with_capacity(len.max(buf.capacity() * 2));
+    grown.extend_from_slice(buf);
+    let mut old = std::mem::replace(buf, grown);
+    old.zeroize();
+}
+
+/// `Vec::resize` with zeros, wiping anything cut off or moved
+pub fn resize(buf: &mut Vec<u8>, len: usize) {
+    if len < buf.len() {
+        buf[len..].zeroize();
+    }
+    reserve(buf, len);
+    buf.resize(len, 0);
+}
+
+/// Writes `data` at `offset`, zero-filling any gap past the end
+pub fn write_at(buf: &mut Vec<u8>, offset: usize, data: &[u8]) {
+    let end = offset + data.len();
+    if end > buf.len() {
+        resize(buf, end);
+    }
+    buf[offset..end].copy_from_slice(data);
+}
+
+/// Appends `data`, e.g. the next block of a file
+pub fn append(buf: &mut Vec<u8>, data: &[u8]) {
+    reserve(buf, buf.len() + data.len());
+    buf.extend_from_slice(data);
+}
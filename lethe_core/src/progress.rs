@@ -0,0 +1,62 @@
+//! Progress and cancellation for long operations.
+//!
+//! Storing a file (`IndexManager::store_reader`) and copying one out
+//! (`FileReader::copy_to`) take a `&dyn ProgressSink`, and so do the CLI's
+//! own long loops: verifying, restoring and collecting garbage. They report
+//! bytes and items as they get through them, and ask `cancelled` before
+//! each block or item. A cancelled operation stops there with `Cancelled`
+//! and changes nothing it hadn't finished: a store that stops part way
+//! records no file, and its blocks are left for `lethe clean`.
+
+use std::io;
+
+/// Where an operation reports to. Shared by threads working side by side.
+pub trait ProgressSink: Sync {
+    /// The work ahead, where it is known up front
+    fn start(&self, _bytes: u64, _items: u64) {}
+
+    /// `bytes` and `items` more are done
+    fn advance(&self, bytes: u64, items: u64);
+
+    /// Whether to stop at the next point that leaves nothing half done
+    fn cancelled(&self) -> bool {
+        false
+    }
+}
+
+/// Reports nowhere and never cancels
+pub struct Silent;
+
+impl ProgressSink for Silent {
+    fn advance(&self, _bytes: u64, _items: u64) {}
+}
+
+/// What a cancelled operation fails with
+#[derive(Debug, thiserror::Error)]
+#[error("Cancelled")]
+pub struct Cancelled;
+
+/// Fails with `Cancelled` if `progress` was
+pub fn check(progress: &dyn ProgressSink) -> anyhow::Result<()> {
+    match progress.cancelled() {
+        true => Err(Cancelled.into()),
+        false => Ok(()),
+    }
+}
+
+/// `check` for operations that fail with `io::Error`
+pub fn check_io(progress: &dyn ProgressSink) -> io::Result<()> {
+    match progress.cancelled() {
+        true => Err(io::Error::new(io::ErrorKind::Interrupted, Cancelled)),
+        false => Ok(()),
+    }
+}
+
+/// Whether `e` comes from a cancelled operation, directly or through
+/// `io::Error`
+pub fn is_cancelled(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause.is::<Cancelled>()
+            || cause.downcast_ref::<io::Error>().and_then(|e| e.get_ref()).is_some_and(|inner| inner.is::<Cancelled>())
+    })
+}
@@ -0,0 +1,273 @@
+//! Reading a stored file back a block at a time.
+//!
+//! A `FileReader` decrypts only the block the position falls in, so a file
+//! far larger than memory can be copied out or served. It seeks with the
+//! block sizes the index keeps (`FileEntry::sizes`); for files stored
+//! before those were kept it learns them from the blocks it passes. Read
+//! in order on a machine with cores to spare, it fetches the next few
+//! blocks in one `read_blocks` batch, decrypted side by side. Given a
+//! `BlockCache`, it looks there before fetching and leaves what it
+//! decrypts there for other readers. `write_parallel` copies a whole file
+//! out with several threads, each writing its blocks at their offsets.
+
+use std::borrow::Borrow;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::cache::BlockCache;
+use crate::config::DEFAULT_READ_AHEAD;
+use crate::crypto::MasterKey;
+use crate::index::FileEntry;
+use crate::plaintext::{Plaintext, Zeroizing};
+use crate::progress::{self, ProgressSink};
+use crate::storage::BlockManager;
+
+/// The content of a stored file. `S` and `K` are the storage and key, held
+/// by reference or by `Arc` for a reader that has to outlive the call.
+pub struct FileReader<S, K> {
+    storage: S,
+    key: K,
+    blocks: Vec<String>,
+    /// Where each block starts, for as many of them as is known; once all
+    /// are, one more entry marks the end
+    starts: Vec<u64>,
+    len: u64,
+    pos: u64,
+    /// The block read last, by number
+    current: Option<(usize, Arc<Plaintext>)>,
+    /// Blocks after `current` fetched with it, by number
+    ahead: Vec<(usize, Arc<Plaintext>)>,
+    /// Blocks fetched past the one needed when reading in order
+    read_ahead: usize,
+    cache: Option<Arc<BlockCache>>,
+}
+
+impl<S: Borrow<BlockManager>, K: Borrow<MasterKey>> FileReader<S, K> {
+    pub fn new(storage: S, key: K, entry: &FileEntry) -> Self {
+        let mut starts = vec![0];
+        if entry.sizes.len() == entry.blocks.len() {
+            for size in &entry.sizes {
+                starts.push(starts[starts.len() - 1] + size);
+            }
+        }
+        Self {
+            storage,
+            key,
+            blocks: entry.blocks.clone(),
+            starts,
+            len: entry.size,
+            pos: 0,
+            current: None,
+            ahead: Vec::new(),
+            read_ahead: DEFAULT_READ_AHEAD,
+            cache: None,
+        }
+    }
+
+    /// Fetches `blocks` past the one needed when reading in order; 0 reads
+    /// one block at a time
+    pub fn read_ahead(mut self, blocks: usize) -> Self {
+        self.read_ahead = blocks;
+        self
+    }
+
+    /// Shares decrypted blocks with other readers through `cache`
+    pub fn cached(mut self, cache: Option<Arc<BlockCache>>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Decrypts block `i` unless it is the one already at hand
+    fn load(&mut self, i: usize) -> io::Result<&[u8]> {
+        if self.current.as_ref().is_none_or(|(n, _)| *n != i) {
+            let sequential = self.current.as_ref().is_some_and(|(n, _)| n + 1 == i)
+                && self.read_ahead > 0
+                && std::thread::available_parallelism().is_ok_and(|n| n.get() > 1);
+            if let Some(at) = self.ahead.iter().position(|(n, _)| *n == i) {
+                self.current = Some(self.ahead.remove(at));
+            } else if let Some(data) = self.cache.as_ref().and_then(|c| c.get(&self.blocks[i])) {
+                self.current = Some((i, data));
+            } else if sequential {
+                let end = (i + 1 + self.read_ahead).min(self.blocks.len());
+                let fetched = self.storage.borrow().read_blocks(&self.blocks[i..end], self.key.borrow())
+                    .map_err(io::Error::other)?;
+                self.ahead = (i..end).zip(fetched.into_iter().map(Arc::new)).collect();
+                for (n, data) in &self.ahead {
+                    self.keep(*n, data);
+                }
+                self.current = Some(self.ahead.remove(0));
+            } else {
+                let data = self.storage.borrow().read_block(&self.blocks[i], self.key.borrow())
+                    .map_err(io::Error::other)?;
+                let data = Arc::new(Zeroizing::new(data));
+                self.keep(i, &data);
+                self.current = Some((i, data));
+            }
+        }
+        Ok(self.current.as_ref().map(|(_, data)| data.as_slice()).unwrap_or_default())
+    }
+
+    fn keep(&self, i: usize, data: &Arc<Plaintext>) {
+        if let Some(cache) = &self.cache {
+            cache.insert(&self.blocks[i], data.clone());
+        }
+    }
+
+    /// The block holding byte `offset`; None past the end
+    fn block_at(&mut self, offset: u64) -> io::Result<Option<usize>> {
+        if offset >= self.len {
+            return Ok(None);
+        }
+        let known = self.starts.partition_point(|start| *start <= offset);
+        if known < self.starts.len() {
+            return Ok(Some(known - 1));
+        }
+        // Past what is known: read on until a block reaches it
+        while self.starts.len() <= self.blocks.len() {
+            let i = self.starts.len() - 1;
+            let end = self.starts[i] + self.load(i)?.len() as u64;
+            self.starts.push(end);
+            if end > offset {
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Fills as much of `buf` as the block at `offset` holds, without
+    /// moving the position. Returns how much that was; 0 at the end.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(i) = self.block_at(offset)? else { return Ok(0) };
+        let from = (offset - self.starts[i]) as usize;
+        let data = self.load(i)?;
+        let n = buf.len().min(data.len().saturating_sub(from));
+        buf[..n].copy_from_slice(&data[from..from + n]);
+        Ok(n)
+    }
+
+    /// Writes the rest of the file to `out` straight from each decrypted
+    /// block. Returns how many bytes that was. Reports each block to
+    /// `progress`, and the file as one item at the end.
+    pub fn copy_to(&mut self, out: &mut impl Write, progress: &dyn ProgressSink) -> io::Result<u64> {
+        let start = self.pos;
+        while let Some(i) = self.block_at(self.pos)? {
+            progress::check_io(progress)?;
+            let from = (self.pos - self.starts[i]) as usize;
+            let data = self.load(i)?;
+            let rest = &data[from.min(data.len())..];
+            if rest.is_empty() {
+                break;
+            }
+            out.write_all(rest)?;
+            let len = rest.len() as u64;
+            self.pos += len;
+            progress.advance(len, 0);
+        }
+        progress.advance(0, 1);
+        Ok(self.pos - start)
+    }
+}
+
+impl<S: Borrow<BlockManager> + Sync, K: Borrow<MasterKey> + Sync> FileReader<S, K> {
+    /// Writes the whole file to `out` with up to `jobs` threads, each
+    /// fetching and decrypting one block at a time and writing it at its
+    /// offset, so at most `jobs` blocks are in memory. Needs the block
+    /// sizes (`FileEntry::sizes`); without them, or with one job or one
+    /// block, it is `copy_to` from the start. Reports as `copy_to` does.
+    pub fn write_parallel(&mut self, out: &File, jobs: usize, progress: &dyn ProgressSink) -> io::Result<u64> {
+        self.pos = 0;
+        if jobs < 2 || self.blocks.len() < 2 || self.starts.len() != self.blocks.len() + 1 {
+            return self.copy_to(&mut &*out, progress);
+        }
+        out.set_len(self.len)?;
+        let (storage, key) = (self.storage.borrow(), self.key.borrow());
+        let (blocks, starts) = (&self.blocks, &self.starts);
+        let next = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..jobs.min(blocks.len())).map(|_| scope.spawn(|| -> io::Result<()> {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(id) = blocks.get(i) else { return Ok(()) };
+                    let written = (|| -> io::Result<()> {
+                        progress::check_io(progress)?;
+                        let data = Zeroizing::new(storage.read_block(id, key).map_err(io::Error::other)?);
+                        let (start, len) = (starts[i], starts[i + 1] - starts[i]);
+                        if data.len() as u64 != len {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("Block {} holds {} bytes, the index says {}", i, data.len(), len),
+                            ));
+                        }
+                        write_at(out, &data, start)?;
+                        progress.advance(len, 0);
+                        Ok(())
+                    })();
+                    if written.is_err() {
+                        // The others stop after the block they are on
+                        next.store(blocks.len(), Ordering::Relaxed);
+                        return written;
+                    }
+                }
+            })).collect();
+            workers.into_iter().try_for_each(|w| w.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+        })?;
+        progress.advance(0, 1);
+        self.pos = self.len;
+        Ok(self.len)
+    }
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl<S: Borrow<BlockManager>, K: Borrow<MasterKey>> Read for FileReader<S, K> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.read_at(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<S: Borrow<BlockManager>, K: Borrow<MasterKey>> Seek for FileReader<S, K> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        match target {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start of the file")),
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! The vault's retention policy: how often a snapshot is taken on its
+//! own, and how many of those and for how long are kept (`lethe
+//! retention`). It lives in the index, so it is encrypted and every
+//! synced copy follows the same one.
+//!
+//! Only the snapshots the policy takes, named `auto-<date>-<time>`, are
+//! ever deleted by it; ones taken by name stay until deleted by hand.
+//! Whatever holds the vault unlocked applies it (see the CLI's
+//! `daemon::retention`), and `lethe clean` drops what it no longer keeps.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use anyhow::Result;
+
+use crate::index::{IndexManager, VaultIndex};
+use crate::merge;
+
+/// Names of the snapshots the policy takes start with this
+pub const AUTO_PREFIX: &str = "auto-";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Retention {
+    /// Seconds between automatic snapshots, 0 for none. They are taken
+    /// once per whole period since 1970 (UTC), so daily ones at midnight.
+    pub every: u64,
+    /// Automatic snapshots kept, newest first; 0 keeps any number
+    pub keep: u32,
+    /// Seconds an automatic snapshot is kept; 0 for no limit
+    pub max_age: u64,
+    /// Unix timestamp of the last change, for merging copies
+    pub modified: u64,
+}
+
+impl Retention {
+    /// Whether the policy does anything
+    pub fn is_set(&self) -> bool {
+        self.every != 0 || self.keep != 0 || self.max_age != 0
+    }
+}
+
+/// What the policy asks of an index now
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Plan {
+    /// Snapshot to take
+    pub take: Option<String>,
+    /// Automatic snapshots to delete, oldest first
+    pub drop: Vec<String>,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.take.is_none() && self.drop.is_empty()
+    }
+}
+
+/// What `index` is due at `now` under its policy
+pub fn plan(index: &VaultIndex, now: u64) -> Plan {
+    let take = due(index, now);
+    let drop = past(index, now, take.is_some());
+    Plan { take, drop }
+}
+
+/// The automatic snapshot to take at `now`, if this period has none yet
+pub fn due(index: &VaultIndex, now: u64) -> Option<String> {
+    let every = index.retention.every;
+    if every == 0 {
+        return None;
+    }
+    let newest = automatic(index).last().map(|(_, created)| *created);
+    if newest.is_some_and(|created| created / every >= now / every) {
+        return None;
+    }
+    let name = auto_name(now);
+    (!index.snapshots.contains_key(&name)).then_some(name)
+}
+
+/// Automatic snapshots the policy no longer keeps at `now`; with
+/// `taking`, counting one about to be taken as the newest
+pub fn past(index: &VaultIndex, now: u64, taking: bool) -> Vec<String> {
+    let policy = &index.retention;
+    let autos = automatic(index);
+    let keep = match policy.keep {
+        0 => autos.len(),
+        n => (n as usize).saturating_sub(usize::from(taking)),
+    };
+    let surplus = autos.len().saturating_sub(keep);
+    autos.iter().enumerate()
+        .filter(|(i, (_, created))| *i < surplus || (policy.max_age != 0 && created.saturating_add(policy.max_age) < now))
+        .map(|(_, (name, _))| name.clone())
+        .collect()
+}
+
+/// Carries out `plan`, returning the blocks no longer used by anything,
+/// for the caller to delete once the index is saved
+pub fn apply(index_mgr: &mut IndexManager, plan: &Plan) -> Result<Vec<String>> {
+    let before: HashSet<String> = index_mgr.data.referenced_blocks().into_iter().map(str::to_string).collect();
+    if let Some(name) = &plan.take {
+        index_mgr.create_snapshot(name)?;
+    }
+    for name in &plan.drop {
+        index_mgr.delete_snapshot(name);
+    }
+    let after = index_mgr.data.referenced_blocks();
+    Ok(before.into_iter().filter(|id| !after.contains(id.as_str())).collect())
+}
+
+/// Automatic snapshots with when they were taken, oldest first
+fn automatic(index: &VaultIndex) -> Vec<(String, u64)> {
+    let mut autos: Vec<(String, u64)> = index.snapshots.iter()
+        .filter(|(name, _)| name.starts_with(AUTO_PREFIX))
+        .map(|(name, s)| (name.clone(), s.created))
+        .collect();
+    autos.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+    autos
+}
+
+/// `auto-2024-06-01-0400` for a snapshot taken at `now`, in UTC
+fn auto_name(now: u64) -> String {
+    let time = now % 86_400;
+    format!("{}{}-{:02}{:02}", AUTO_PREFIX, merge::date(now), time / 3600, time % 3600 / 60)
+}
@@ -0,0 +1,171 @@
+//! Content index: which words each text file holds, for `lethe grep`.
+//!
+//! Built on demand (`lethe index-content`), never on save: reading every
+//! file back is a full pass over the vault. `content.bin` holds it, sealed
+//! (Nonce + Data of the CBOR) under a key derived from the master key, as
+//! the words are as telling as the files. A file counts as text when it
+//! is no larger than `MAX_TEXT` and its first `SNIFF` bytes hold no NUL.
+//! Words are runs of letters and digits, lowercased. A rebuild reads only
+//! files whose blocks changed since the last one.
+//!
+//! A search narrows down to the files holding every word asked for; the
+//! caller decrypts just those to find the lines. Files stored since the
+//! last build aren't found until the next one. Like the growth history,
+//! each copy keeps its own.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{CryptoEngine, MasterKey};
+use crate::index::{self, FileEntry, IndexManager};
+use crate::keyring::{open, seal};
+use crate::plaintext::Zeroizing;
+use crate::progress::{self, ProgressSink};
+use crate::reader::FileReader;
+use crate::storage::BlockManager;
+
+pub const CONTENT_FILE: &str = "content.bin";
+
+/// Files larger than this aren't indexed
+pub const MAX_TEXT: u64 = 16 * 1024 * 1024;
+
+/// Bytes looked at to tell text from binary
+const SNIFF: usize = 8 * 1024;
+
+/// Longer runs are hashes, base64 and the like, not words
+const MAX_WORD: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Document {
+    /// The blocks it was read from, to tell whether it changed since
+    pub blocks: Vec<String>,
+    /// Its words, sorted and each once
+    pub words: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ContentIndex {
+    /// Index revision it was built at
+    pub revision: u64,
+    /// Vault path -> what it holds; no words for binary files
+    pub files: BTreeMap<String, Document>,
+}
+
+/// What a build went through
+#[derive(Debug, Default)]
+pub struct Built {
+    pub read: usize,
+    pub unchanged: usize,
+    /// Binary or larger than `MAX_TEXT`
+    pub skipped: usize,
+}
+
+fn content_key(key: &MasterKey) -> MasterKey {
+    CryptoEngine::derive_subkey(key, b"lethe content index")
+}
+
+/// The lowercased words of `text`, in order, repeats included
+pub fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty() && w.chars().count() <= MAX_WORD)
+        .map(str::to_lowercase)
+}
+
+fn is_text(head: &[u8]) -> bool {
+    !head[..head.len().min(SNIFF)].contains(&0)
+}
+
+impl ContentIndex {
+    /// None when it was never built
+    pub fn load(vault: &Path, key: &MasterKey) -> Result<Option<Self>> {
+        let sealed = match fs::read(vault.join(CONTENT_FILE)) {
+            Ok(sealed) => sealed,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("Failed to read the content index"),
+        };
+        let plain = Zeroizing::new(open(&sealed, &content_key(key)).context("Content index doesn't open. Wrong password?")?);
+        serde_cbor::from_slice(&plain).map(Some).context("Content index is corrupted")
+    }
+
+    pub fn save(&self, vault: &Path, key: &MasterKey) -> Result<()> {
+        let plain = Zeroizing::new(serde_cbor::to_vec(self)?);
+        let sealed = seal(&plain, &content_key(key))?;
+        let tmp = vault.join(format!("{}.tmp", CONTENT_FILE));
+        fs::write(&tmp, sealed).context("Failed to write the content index")?;
+        fs::rename(&tmp, vault.join(CONTENT_FILE))?;
+        Ok(())
+    }
+
+    /// Brings the index up to the live files of `index`, reading only what
+    /// changed. Reports each file to `progress`, and its bytes when read.
+    pub fn update(&mut self, index: &IndexManager, storage: &BlockManager, key: &MasterKey, progress: &dyn ProgressSink) -> Result<Built> {
+        let live: Vec<(&String, &FileEntry)> = index.data.files.iter()
+            .filter(|(path, e)| !e.is_dir && !e.is_expired() && !index::is_snapshot_path(path))
+            .collect();
+        progress.start(live.iter().map(|(_, e)| e.size.min(MAX_TEXT)).sum(), live.len() as u64);
+        let mut built = Built::default();
+        let mut files = BTreeMap::new();
+        for (path, entry) in live {
+            progress::check(progress)?;
+            if let Some(doc) = self.files.remove(path).filter(|doc| doc.blocks == entry.blocks) {
+                files.insert(path.clone(), doc);
+                built.unchanged += 1;
+                progress.advance(entry.size.min(MAX_TEXT), 1);
+                continue;
+            }
+            let words = read_words(storage, key, entry, progress)?;
+            match words {
+                Some(_) => built.read += 1,
+                None => built.skipped += 1,
+            }
+            files.insert(path.clone(), Document { blocks: entry.blocks.clone(), words: words.unwrap_or_default() });
+            progress.advance(0, 1);
+        }
+        self.files = files;
+        self.revision = index.data.revision;
+        Ok(built)
+    }
+
+    /// Files with words in them
+    pub fn text_files(&self) -> usize {
+        self.files.values().filter(|doc| !doc.words.is_empty()).count()
+    }
+
+    /// Files holding every word of `query`
+    pub fn candidates(&self, query: &str) -> Vec<&str> {
+        let wanted: Vec<String> = words(query).collect();
+        self.files.iter()
+            .filter(|(_, doc)| wanted.iter().all(|w| doc.words.binary_search(w).is_ok()))
+            .map(|(path, _)| path.as_str())
+            .collect()
+    }
+}
+
+/// The sorted words of a text file; None for a binary or too large one
+fn read_words(storage: &BlockManager, key: &MasterKey, entry: &FileEntry, progress: &dyn ProgressSink) -> Result<Option<Vec<String>>> {
+    if entry.size > MAX_TEXT {
+        progress.advance(MAX_TEXT, 0);
+        return Ok(None);
+    }
+    let mut reader = FileReader::new(storage, key, entry);
+    let mut bytes = Zeroizing::new(Vec::with_capacity(entry.size as usize));
+    let mut head = vec![0; SNIFF.min(entry.size as usize)];
+    reader.read_exact(&mut head)?;
+    progress.advance(head.len() as u64, 0);
+    if !is_text(&head) {
+        progress.advance(entry.size - head.len() as u64, 0);
+        return Ok(None);
+    }
+    bytes.extend_from_slice(&head);
+    reader.read_to_end(&mut bytes)?;
+    progress.advance(entry.size - head.len() as u64, 0);
+    let mut found: Vec<String> = words(&String::from_utf8_lossy(&bytes)).collect();
+    found.sort_unstable();
+    found.dedup();
+    Ok(Some(found))
+}
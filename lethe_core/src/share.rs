@@ -0,0 +1,260 @@
+//! Share bundles: a few files from a vault, re-encrypted under a key
+//! derived from a passphrase of their own, in one file to hand to someone
+//! who must not get the vault key.
+//!
+//! Layout: `MAGIC`, the passphrase salt (u32 length + UTF-8), then frames
+//! of u32 length + Nonce + Data. The first frame is the manifest; the
+//! chunks of every file follow in manifest order, one per vault block.
+//! Each chunk starts with its sequence number, so frames can't be dropped
+//! or reordered without the bundle failing to open.
+//!
+//! Opening a bundle needs nothing but a reader, so it also works without
+//! the `fs` feature, e.g. in a browser handed the bundle's bytes.
+
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+#[cfg(feature = "fs")]
+use std::fs::{self, File};
+#[cfg(feature = "fs")]
+use std::io::{BufReader, BufWriter};
+#[cfg(feature = "fs")]
+use std::path::{Component, Path, PathBuf};
+#[cfg(feature = "fs")]
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{Result, Context};
+use crate::crypto::{CryptoEngine, MasterKey};
+#[cfg(feature = "fs")]
+use crate::index::FileEntry;
+#[cfg(feature = "fs")]
+use crate::storage::BlockManager;
+
+const MAGIC: &[u8; 8] = b"LETHESHR";
+const VERSION: u32 = 1;
+/// Frames are bounded before they are read; the manifest never gets near this
+const MAX_MANIFEST: u32 = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub version: u32,
+    pub created: u64,
+    pub entries: Vec<SharedEntry>,
+}
+
+/// One file or folder in a bundle, at a relative path
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SharedEntry {
+    pub path: String,
+    pub size: u64,
+    pub modified: u64,
+    pub is_dir: bool,
+    /// Chunk frames holding the contents
+    pub chunks: u32,
+}
+
+/// A chunk's zstd frame, decoded in Rust so that reading a bundle needs no
+/// C library on any target
+fn decompress(frame: &[u8]) -> Result<Vec<u8>> {
+    let mut decoder = ruzstd::decoding::StreamingDecoder::new(frame).context("Decompression failed")?;
+    let mut data = Vec::new();
+    decoder.read_to_end(&mut data).context("Decompression failed")?;
+    Ok(data)
+}
+
+#[cfg(feature = "fs")]
+fn write_frame<W: Write>(out: &mut W, plain: &[u8], key: &MasterKey) -> Result<()> {
+    let (ciphertext, nonce) = CryptoEngine::encrypt(plain, key)?;
+    let len = u32::try_from(nonce.len() + ciphertext.len()).context("Chunk too large for a share bundle")?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(&nonce)?;
+    out.write_all(&ciphertext)?;
+    Ok(())
+}
+
+fn read_frame<R: Read>(input: &mut R, limit: u32, key: &MasterKey) -> Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    input.read_exact(&mut len).context("Share bundle is truncated")?;
+    let len = u32::from_le_bytes(len);
+    if len < 24 || len > limit {
+        anyhow::bail!("Share bundle is damaged (bad frame length)");
+    }
+    let mut frame = vec![0u8; len as usize];
+    input.read_exact(&mut frame).context("Share bundle is truncated")?;
+    let (nonce, ciphertext) = frame.split_at(24);
+    CryptoEngine::decrypt(ciphertext, nonce, key)
+}
+
+/// Writes `entries` (bundle path + vault entry) to `out`, reading their
+/// blocks with the vault key and sealing them with one derived from
+/// `passphrase`.
+#[cfg(feature = "fs")]
+pub fn create(
+    out: &Path,
+    entries: &[(String, &FileEntry)],
+    storage: &BlockManager,
+    key: &MasterKey,
+    passphrase: &str,
+) -> Result<Manifest> {
+    let (share_key, salt) = CryptoEngine::derive_key(passphrase)?;
+    let manifest = Manifest {
+        version: VERSION,
+        created: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        entries: entries.iter().map(|(path, e)| SharedEntry {
+            path: path.clone(),
+            size: e.size,
+            modified: e.modified,
+            is_dir: e.is_dir,
+            chunks: if e.is_dir { 0 } else { e.blocks.len() as u32 },
+        }).collect(),
+    };
+
+    // 1. Header and manifest
+    let tmp = out.with_extension("part");
+    let mut writer = BufWriter::new(File::create(&tmp).context("Failed to create share bundle")?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&(salt.len() as u32).to_le_bytes())?;
+    writer.write_all(salt.as_bytes())?;
+    let plain = serde_cbor::to_vec(&manifest).context("Failed to serialize share manifest")?;
+    write_frame(&mut writer, &plain, &share_key)?;
+
+    // 2. Every block, re-sealed under the share key
+    let mut seq: u64 = 0;
+    for (_, entry) in entries.iter().filter(|(_, e)| !e.is_dir) {
+        for block_id in &entry.blocks {
+            let data = storage.read_block(block_id, key)?;
+            let mut chunk = seq.to_le_bytes().to_vec();
+            chunk.extend(zstd::stream::encode_all(data.as_slice(), 3).context("Compression failed")?);
+            write_frame(&mut writer, &chunk, &share_key)?;
+            seq += 1;
+        }
+    }
+
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp, out).context("Failed to finish share bundle")?;
+    Ok(manifest)
+}
+
+/// An opened bundle, positioned at its first chunk
+pub struct ShareBundle<R> {
+    pub manifest: Manifest,
+    key: MasterKey,
+    reader: R,
+    /// Next entry of the manifest to read, and the sequence number its
+    /// first chunk must carry
+    next: usize,
+    seq: u64,
+}
+
+#[cfg(feature = "fs")]
+impl ShareBundle<BufReader<File>> {
+    pub fn open(path: &Path, passphrase: &str) -> Result<Self> {
+        let reader = BufReader::new(File::open(path).with_context(|| format!("Failed to open {:?}", path))?);
+        Self::from_reader(reader, passphrase).with_context(|| format!("Can't open {:?}", path))
+    }
+}
+
+impl<R: Read> ShareBundle<R> {
+    /// Opens a bundle from any reader, e.g. bytes held in memory
+    pub fn from_reader(mut reader: R, passphrase: &str) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).ok();
+        if &magic != MAGIC {
+            anyhow::bail!("Not a Lethe share bundle");
+        }
+
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len).context("Share bundle is truncated")?;
+        let len = u32::from_le_bytes(len);
+        if len > 1024 {
+            anyhow::bail!("Share bundle is damaged (bad salt)");
+        }
+        let mut salt = vec![0u8; len as usize];
+        reader.read_exact(&mut salt).context("Share bundle is truncated")?;
+        let salt = String::from_utf8(salt).context("Share bundle is damaged (bad salt)")?;
+
+        let (key, _) = CryptoEngine::derive_key_with_salt(passphrase, &salt)?;
+        let plain = read_frame(&mut reader, MAX_MANIFEST, &key)
+            .context("Wrong passphrase, or the bundle is damaged")?;
+        let manifest: Manifest = serde_cbor::from_slice(&plain).context("Share manifest is damaged")?;
+        if manifest.version > VERSION {
+            anyhow::bail!("Share bundle version {} is newer than this build understands", manifest.version);
+        }
+        Ok(Self { manifest, key, reader, next: 0, seq: 0 })
+    }
+
+    /// Writes the contents of the next entry in the manifest to `out`
+    /// (nothing for a folder). Returns the entry and the bytes written, or
+    /// None after the last one.
+    pub fn next_entry<W: Write>(&mut self, out: &mut W) -> Result<Option<(SharedEntry, u64)>> {
+        let Some(entry) = self.manifest.entries.get(self.next).cloned() else {
+            return Ok(None);
+        };
+        self.next += 1;
+        // Compression never grows a block by more than a little
+        let limit = u32::try_from(entry.size + entry.size / 64 + 64 * 1024).unwrap_or(u32::MAX);
+        let mut written = 0;
+        for _ in 0..entry.chunks {
+            let chunk = read_frame(&mut self.reader, limit, &self.key)?;
+            if chunk.len() < 8 || chunk[..8] != self.seq.to_le_bytes() {
+                anyhow::bail!("Share bundle is damaged (chunks out of order)");
+            }
+            let data = decompress(&chunk[8..])?;
+            out.write_all(&data)?;
+            written += data.len() as u64;
+            self.seq += 1;
+        }
+        Ok(Some((entry, written)))
+    }
+
+    /// The next entry and its contents, read into memory
+    pub fn next_file(&mut self) -> Result<Option<(SharedEntry, Vec<u8>)>> {
+        let mut data = Vec::new();
+        Ok(self.next_entry(&mut data)?.map(|(entry, _)| (entry, data)))
+    }
+
+    /// Writes every entry below `dest`, which must not hold any of them yet.
+    /// Returns the bytes written.
+    #[cfg(feature = "fs")]
+    pub fn extract(mut self, dest: &Path) -> Result<u64> {
+        // 1. Check every target before writing any
+        let mut targets = Vec::new();
+        for entry in &self.manifest.entries[self.next..] {
+            let target = dest.join(safe_relative(&entry.path)?);
+            if target.exists() && !(entry.is_dir && target.is_dir()) {
+                anyhow::bail!("{:?} already exists; extract somewhere else", target);
+            }
+            targets.push((entry.is_dir, target));
+        }
+
+        // 2. Stream chunks in order
+        let mut written = 0;
+        for (is_dir, target) in targets {
+            if is_dir {
+                fs::create_dir_all(&target)?;
+                self.next_entry(&mut std::io::sink())?;
+                continue;
+            }
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut out = BufWriter::new(File::create(&target).with_context(|| format!("Failed to create {:?}", target))?);
+            if let Some((_, bytes)) = self.next_entry(&mut out)? {
+                written += bytes;
+            }
+            out.flush()?;
+        }
+        Ok(written)
+    }
+}
+
+/// A bundle path as a relative path that stays inside the target folder
+#[cfg(feature = "fs")]
+fn safe_relative(path: &str) -> Result<PathBuf> {
+    let relative = PathBuf::from(path);
+    let ok = !path.is_empty()
+        && !path.contains('\\')
+        && relative.components().all(|c| matches!(c, Component::Normal(_)));
+    if !ok {
+        anyhow::bail!("Share bundle holds an unsafe path: {:?}", path);
+    }
+    Ok(relative)
+}
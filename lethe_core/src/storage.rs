@@ -1,14 +1,66 @@
-use std::fs::{self, File};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use rand::RngCore;
 use uuid::Uuid;
 use anyhow::{Result, Context};
 use crate::crypto::{CryptoEngine, MasterKey};
+use crate::header::{self, VaultHeader};
+use crate::mapped;
+use crate::plaintext::{self, Zeroizing};
+use crate::volumes::{Policy, Volume, Volumes};
+
+/// Bytes a block file holds besides the sealed data: the nonce in front
+/// and the Poly1305 tag after
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+
+/// zstd steps over frames with this magic, so data padded with one still
+/// decompresses to itself. The frame is the magic, a u32 length, then that
+/// many bytes.
+const SKIPPABLE_MAGIC: u32 = 0x184D2A50;
+const SKIPPABLE_HEADER: usize = 8;
+
+/// Hex digits in a content id
+const CONTENT_ID_LEN: usize = 64;
 
 /// Manages the physical storage of encrypted blocks on disk.
+///
+/// Blocks are named by a random UUID, or in a vault with content ids
+/// (`header::FEATURE_CONTENT_IDS`) by `CryptoEngine::block_id` of what they
+/// hold, so equal chunks are one block however they were written and a
+/// block can be checked against its name. Padding there gets random ids of
+/// the same form.
 #[derive(Debug)]
 pub struct BlockManager {
     root_path: PathBuf,
+    /// Volumes new blocks may go to, the vault folder first (see `volumes`)
+    volumes: Vec<Volume>,
+    /// Listed in `volumes.conf` but not plugged in
+    detached: Vec<Volume>,
+    policy: Policy,
+    /// Volumes each new block is written to (see `volumes`)
+    copies: usize,
+    /// Round-robin position. It starts anywhere, so short-lived commands
+    /// that write a block or two still spread them.
+    next: AtomicUsize,
+    /// Block bytes on each attached volume, counted once a capacity needs it
+    used: Mutex<Option<Vec<u64>>>,
+    content_ids: bool,
+}
+
+/// What `BlockManager::repair_block` did to one block
+#[derive(Debug, Default)]
+pub struct Repaired {
+    /// Volumes whose copy failed and was rewritten
+    pub rewritten: Vec<String>,
+    /// Volumes it was newly copied to
+    pub added: Vec<String>,
+    /// No copy of it opens
+    pub lost: bool,
 }
 
 impl BlockManager {
@@ -20,71 +72,649 @@ impl BlockManager {
             fs::create_dir_all(&root_path)
                 .context("Failed to create vault directory")?;
         }
-        
-        Ok(Self { root_path })
+
+        let layout = Volumes::load(&root_path)?;
+        let (volumes, detached) = layout.list.iter().cloned().partition(|v| layout.attached(v));
+        let content_ids = VaultHeader::load(&root_path)
+            .is_ok_and(|h| h.features.contains(header::FEATURE_CONTENT_IDS));
+        Ok(Self {
+            root_path,
+            volumes,
+            detached,
+            policy: layout.policy,
+            copies: layout.copies,
+            next: AtomicUsize::new(rand::random::<u16>() as usize),
+            used: Mutex::new(None),
+            content_ids,
+        })
+    }
+
+    /// Whether new blocks are named by their content
+    pub fn content_ids(&self) -> bool {
+        self.content_ids
+    }
+
+    /// A fresh id for a block whose name says nothing of its content
+    fn random_id(&self) -> String {
+        match self.content_ids {
+            true => {
+                let mut bytes = [0u8; CONTENT_ID_LEN / 2];
+                rand::rngs::OsRng.fill_bytes(&mut bytes);
+                bytes.iter().map(|b| format!("{:02x}", b)).collect()
+            }
+            false => Uuid::new_v4().to_string(),
+        }
+    }
+
+    /// Volumes holding blocks right now, the vault folder first
+    pub fn attached(&self) -> &[Volume] {
+        &self.volumes
+    }
+
+    pub fn detached(&self) -> &[Volume] {
+        &self.detached
+    }
+
+    /// Where a new block of `len` bytes goes, skipping the volumes in
+    /// `avoid` (main, when nothing else will do)
+    fn pick(&self, len: u64, avoid: &[&str]) -> usize {
+        if self.volumes.len() == 1 {
+            return 0;
+        }
+        let mut used = self.used.lock().unwrap();
+        let has_limit = self.volumes.iter().any(|v| v.capacity.is_some());
+        if has_limit && used.is_none() {
+            *used = Some(self.volumes.iter().map(|v| blocks_in(&v.path).iter().map(|(_, size)| size).sum()).collect());
+        }
+        let room = |i: usize| match (&*used, self.volumes[i].capacity) {
+            (Some(used), Some(capacity)) => Some(capacity.saturating_sub(used[i])),
+            _ => None,
+        };
+        let open: Vec<usize> = (0..self.volumes.len())
+            .filter(|i| !avoid.contains(&self.volumes[*i].id.as_str()) && room(*i).is_none_or(|r| r >= len))
+            .collect();
+
+        let unlimited: Vec<usize> = open.iter().copied().filter(|i| room(*i).is_none()).collect();
+        let chosen = match self.policy {
+            Policy::RoundRobin if !open.is_empty() => open[self.next.fetch_add(1, Ordering::Relaxed) % open.len()],
+            Policy::Capacity => match open.iter().copied().filter(|i| room(*i).is_some()).max_by_key(|i| room(*i)) {
+                Some(i) => i,
+                None if !unlimited.is_empty() => unlimited[self.next.fetch_add(1, Ordering::Relaxed) % unlimited.len()],
+                // Everything is full: main still takes it rather than fail the write
+                None => 0,
+            },
+            _ => 0,
+        };
+        if let Some(used) = used.as_mut() {
+            used[chosen] += len;
+        }
+        chosen
+    }
+
+    /// The block file at `volume`
+    fn path_on(volume: &Volume, block_id: &str) -> PathBuf {
+        volume.path.join(format!("blk_{}.bin", block_id))
+    }
+
+    /// The attached volume holding a block, if any
+    fn find(&self, block_id: &str) -> Option<(&Volume, PathBuf)> {
+        self.holders(block_id).into_iter().next()
+    }
+
+    /// Every attached volume holding a copy of a block
+    fn holders(&self, block_id: &str) -> Vec<(&Volume, PathBuf)> {
+        self.volumes.iter()
+            .map(|v| (v, Self::path_on(v, block_id)))
+            .filter(|(_, path)| path.exists())
+            .collect()
+    }
+
+    /// Volumes each new block is written to
+    pub fn copies(&self) -> usize {
+        self.copies
+    }
+
+    /// The files of every copy of a block on the attached volumes
+    pub fn copy_paths(&self, block_id: &str) -> Vec<PathBuf> {
+        self.holders(block_id).into_iter().map(|(_, path)| path).collect()
+    }
+
+    /// Ids of the attached volumes holding a copy of a block
+    pub fn copies_of(&self, block_id: &str) -> Vec<&str> {
+        self.holders(block_id).into_iter().map(|(v, _)| v.id.as_str()).collect()
+    }
+
+    /// Copies a block onto other volumes until it is on `copies` of them,
+    /// or no other volume has room. Returns the volumes it was copied to.
+    fn replicate(&self, block_id: &str) -> Result<Vec<String>> {
+        let mut added = Vec::new();
+        let mut holders: Vec<&Volume> = self.holders(block_id).into_iter().map(|(v, _)| v).collect();
+        let Some(source) = holders.first().map(|v| Self::path_on(v, block_id)) else {
+            return Ok(added);
+        };
+        let len = fs::metadata(&source)?.len();
+        while holders.len() < self.copies {
+            let avoid: Vec<&str> = holders.iter().map(|v| v.id.as_str()).collect();
+            let to = &self.volumes[self.pick(len, &avoid)];
+            if avoid.contains(&to.id.as_str()) {
+                log::warn!("Block {} is on {} volume(s) of {}: no other volume has room", block_id, holders.len(), self.copies);
+                break;
+            }
+            copy_into(&source, &Self::path_on(to, block_id))
+                .with_context(|| format!("Failed to copy block {} to volume {:?}", block_id, to.id))?;
+            added.push(to.id.clone());
+            holders.push(to);
+        }
+        Ok(added)
+    }
+
+    /// Block files on the attached volume `id` as (id, size in bytes)
+    pub fn blocks_on(&self, id: &str) -> Vec<(String, u64)> {
+        self.volumes.iter().find(|v| v.id == id).map(|v| blocks_in(&v.path)).unwrap_or_default()
+    }
+
+    /// Id of the volume holding a block, if it is on one that's attached
+    pub fn locate(&self, block_id: &str) -> Option<&str> {
+        self.find(block_id).map(|(volume, _)| volume.id.as_str())
     }
 
     /// Takes raw data, compresses it, encrypts it, and saves it to disk.
-    /// Returns the UUID of the new block.
+    /// Returns the id of the block, which with content ids may be one
+    /// already there.
+    #[tracing::instrument(skip_all, fields(len = data.len()))]
     pub fn write_block(&self, data: &[u8], key: &MasterKey) -> Result<String> {
+        let content_id = self.content_ids.then(|| CryptoEngine::block_id(data, key));
+        if let Some(id) = &content_id {
+            if let Some((_, path)) = self.find(id) {
+                // It may be an orphan about to be used again; a fresh mtime
+                // keeps `lethe clean` off it until the index says so
+                if let Ok(file) = OpenOptions::new().write(true).open(&path) {
+                    let _ = file.set_modified(std::time::SystemTime::now());
+                }
+                return Ok(id.clone());
+            }
+        }
+
         // 1. Compress (Zstd)
         // Level 3 is a good balance of speed vs ratio
-        let compressed_data = zstd::stream::encode_all(data, 3)
-            .context("Compression failed")?;
+        let compressed_data = Zeroizing::new(zstd::stream::encode_all(data, 3)
+            .context("Compression failed")?);
 
         // 2. Encrypt (XChaCha20-Poly1305)
         // Returns (Ciphertext, Nonce)
         let (encrypted_data, nonce) = CryptoEngine::encrypt(&compressed_data, key)?;
 
-        // 3. Generate Random ID
-        let block_id = Uuid::new_v4().to_string();
-        let file_path = self.root_path.join(format!("blk_{}.bin", block_id));
+        // 3. Name it: by content, or a random UUID
+        let block_id = content_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+        let len = (nonce.len() + encrypted_data.len()) as u64;
+        let file_path = Self::path_on(&self.volumes[self.pick(len, &[])], &block_id);
 
-        // 4. Write to Disk (Nonce + Encrypted Data)
-        let mut file = File::create(&file_path)
+        // 4. Write to Disk (Nonce + Encrypted Data). A content id is taken
+        // as proof of the block, so it only appears once the block is whole.
+        let part_path = file_path.with_extension("part");
+        let mut file = File::create(&part_path)
             .context("Failed to create block file")?;
         
         // We prepend the nonce to the file so we can read it back later
         file.write_all(&nonce)?;
         file.write_all(&encrypted_data)?;
+        drop(file);
+        fs::rename(&part_path, &file_path).context("Failed to create block file")?;
+        self.replicate(&block_id)?;
+
+        Ok(block_id)
+    }
 
+    /// Writes a block of `len` random bytes, which reads as any other block
+    /// does to someone without the key. Returns its id.
+    pub fn write_padding(&self, len: u64) -> Result<String> {
+        let block_id = self.random_id();
+        self.write_padding_as(&block_id, len)?;
         Ok(block_id)
     }
 
-    /// Reads a block ID, reads disk, decrypts, and decompresses.
+    /// `write_padding` under a given id
+    pub fn write_padding_as(&self, block_id: &str, len: u64) -> Result<()> {
+        let file_path = Self::path_on(&self.volumes[self.pick(len, &[])], block_id);
+        let mut file = File::create(&file_path).context("Failed to create block file")?;
+        let mut chunk = vec![0u8; 64 * 1024];
+        let mut left = len;
+        while left > 0 {
+            let n = left.min(chunk.len() as u64) as usize;
+            rand::rngs::OsRng.fill_bytes(&mut chunk[..n]);
+            file.write_all(&chunk[..n])?;
+            left -= n as u64;
+        }
+        drop(file);
+        self.replicate(block_id)?;
+        Ok(())
+    }
+
+    /// Stores `data` in whichever of the padding blocks `free` it fits
+    /// best, and takes that one out of `free`. Returns its id.
+    pub fn write_padded(&self, data: &[u8], key: &MasterKey, free: &mut Vec<String>) -> Result<String> {
+        let compressed = Zeroizing::new(zstd::stream::encode_all(data, 3)
+            .context("Compression failed")?);
+        let mut fits: Vec<(u64, usize)> = free.iter().enumerate()
+            .filter_map(|(i, id)| {
+                let len = fs::metadata(self.find(id)?.1).ok()?.len();
+                padding_for(len, compressed.len()).map(|_| (len, i))
+            })
+            .collect();
+        fits.sort();
+        let Some(&(_, i)) = fits.first() else {
+            anyhow::bail!("No free padding block is large enough. Give the hidden vault more room.");
+        };
+        let block_id = free.remove(i);
+        self.fill(&block_id, &compressed, key)?;
+        Ok(block_id)
+    }
+
+    /// Overwrites the padding block `block_id` with `data`, sealed as
+    /// `write_block` seals it
+    pub fn write_into(&self, block_id: &str, data: &[u8], key: &MasterKey) -> Result<()> {
+        let compressed = Zeroizing::new(zstd::stream::encode_all(data, 3)
+            .context("Compression failed")?);
+        self.fill(block_id, &compressed, key)
+    }
+
+    /// Seals `compressed` into an existing block file, and its copies,
+    /// padded out with a skippable frame so the file keeps its size. Its
+    /// mtime stays too: a padding block shouldn't look any newer than the
+    /// day it was made. Its ctime can't be put back and shows the write
+    /// (see `hidden`).
+    fn fill(&self, block_id: &str, compressed: &[u8], key: &MasterKey) -> Result<()> {
+        let holders = self.holders(block_id);
+        let Some((_, file_path)) = holders.first() else {
+            return Err(self.missing(block_id));
+        };
+        let meta = fs::metadata(file_path)?;
+        let Some(pad) = padding_for(meta.len(), compressed.len()) else {
+            anyhow::bail!("Block {} is too small to hold {} bytes", block_id, compressed.len());
+        };
+
+        let mut sealed = plaintext::with_capacity(compressed.len() + pad);
+        plaintext::append(&mut sealed, compressed);
+        if pad > 0 {
+            plaintext::append(&mut sealed, &SKIPPABLE_MAGIC.to_le_bytes());
+            plaintext::append(&mut sealed, &((pad - SKIPPABLE_HEADER) as u32).to_le_bytes());
+            plaintext::resize(&mut sealed, compressed.len() + pad);
+        }
+        let (encrypted_data, nonce) = CryptoEngine::encrypt(&sealed, key)?;
+
+        for (_, file_path) in &holders {
+            let mut file = OpenOptions::new().write(true).open(file_path)
+                .context("Failed to open block file")?;
+            file.write_all(&nonce)?;
+            file.write_all(&encrypted_data)?;
+            if let Ok(modified) = meta.modified() {
+                let _ = file.set_modified(modified);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a block ID, reads disk, decrypts, and decompresses. A copy
+    /// that doesn't open is passed over for the next one.
+    #[tracing::instrument(skip(self, key))]
     pub fn read_block(&self, block_id: &str, key: &MasterKey) -> Result<Vec<u8>> {
-        let file_path = self.root_path.join(format!("blk_{}.bin", block_id));
-        
-        // 1. Read File
-        let mut file = File::open(&file_path)
-            .context(format!("Block not found: {}", block_id))?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
+        let holders = self.holders(block_id);
+        let mut first_error = None;
+        for (volume, file_path) in &holders {
+            // 1. Read File, mapped if large; 2. Decrypt and decompress
+            let read = mapped::read(file_path)
+                .context(format!("Block not found: {}", block_id))
+                .and_then(|buffer| unseal(&buffer, key));
+            match read {
+                Ok(data) => return Ok(data),
+                Err(e) if holders.len() > 1 => {
+                    log::warn!("Copy of block {} on volume {:?} is damaged ({:#}); `lethe repair` rewrites it", block_id, volume.id, e);
+                    first_error.get_or_insert(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(first_error.unwrap_or_else(|| self.missing(block_id)))
+    }
 
-        // 2. Split Nonce (First 24 bytes) and Data
-        // XChaCha20 nonce is 24 bytes
-        if buffer.len() < 24 {
-            return Err(anyhow::anyhow!("Block file corrupted or too short"));
+    /// Reads several blocks at once: the files in one batch (through
+    /// io_uring with the `io-uring` feature on Linux), then decrypted side
+    /// by side. Fails if any of them does, like `read_block` in a loop.
+    #[tracing::instrument(skip_all, fields(blocks = block_ids.len()))]
+    pub fn read_blocks(&self, block_ids: &[String], key: &MasterKey) -> Result<Vec<Zeroizing<Vec<u8>>>> {
+        let mut paths = Vec::with_capacity(block_ids.len());
+        for block_id in block_ids {
+            let Some((_, path)) = self.find(block_id) else {
+                return Err(self.missing(block_id));
+            };
+            paths.push(path);
         }
-        let (nonce, ciphertext) = buffer.split_at(24);
 
-        // 3. Decrypt
-        let compressed_data = CryptoEngine::decrypt(ciphertext, nonce, key)
-            .context("Decryption failed (Wrong password or corrupted block)")?;
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        let files = crate::uring::read_files(&paths).into_iter().map(|file| file.map(mapped::Contents::Read));
+        #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+        let files = paths.iter().map(|path| mapped::read(path));
+        let mut buffers = Vec::with_capacity(paths.len());
+        for (file, block_id) in files.zip(block_ids) {
+            buffers.push(file.context(format!("Block not found: {}", block_id))?);
+        }
 
-        // 4. Decompress
-        let original_data = zstd::stream::decode_all(compressed_data.as_slice())
-            .context("Decompression failed")?;
+        // A block whose first copy doesn't open is read again from the others
+        let or_other_copy = |block: Result<Vec<u8>>, block_id: &str| match block {
+            Err(_) if self.copies > 1 || self.volumes.len() > 1 => self.read_block(block_id, key),
+            block => block,
+        };
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get()).min(buffers.len());
+        if threads <= 1 {
+            return buffers.iter().zip(block_ids)
+                .map(|(buffer, block_id)| or_other_copy(unseal(buffer, key), block_id).map(Zeroizing::new))
+                .collect();
+        }
+        let per_thread = buffers.len().div_ceil(threads);
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = buffers
+                .chunks(per_thread)
+                .map(|chunk| scope.spawn(move || chunk.iter().map(|buffer| unseal(buffer, key)).collect::<Vec<_>>()))
+                .collect();
+            let mut blocks = Vec::with_capacity(buffers.len());
+            for worker in workers {
+                for block in worker.join().map_err(|_| anyhow::anyhow!("A decrypting thread panicked"))? {
+                    let block_id = &block_ids[blocks.len()];
+                    blocks.push(Zeroizing::new(or_other_copy(block, block_id)?));
+                }
+            }
+            Ok(blocks)
+        })
+    }
+
+    /// Not found, saying which detached volumes might hold it
+    fn missing(&self, block_id: &str) -> anyhow::Error {
+        match self.detached.as_slice() {
+            [] => anyhow::anyhow!("Block not found: {}", block_id),
+            detached => {
+                let names: Vec<String> = detached.iter().map(|v| format!("{} ({})", v.id, v.path.display())).collect();
+                anyhow::anyhow!("Block not found: {}. It may be on a volume that isn't attached: {}", block_id, names.join(", "))
+            }
+        }
+    }
+
+    /// Path of a block file, on whichever attached volume holds it (the
+    /// vault folder if none does). Ids come from peers during sync, so
+    /// anything that isn't a block id is refused rather than joined onto a
+    /// path.
+    pub fn block_path(&self, block_id: &str) -> Result<PathBuf> {
+        let id = checked_id(block_id)?;
+        Ok(self.find(&id).map(|(_, path)| path).unwrap_or_else(|| Self::path_on(&self.volumes[0], &id)))
+    }
+
+    /// Where a block being received is assembled before `commit_partial`,
+    /// always in the vault folder
+    pub fn partial_path(&self, block_id: &str) -> Result<PathBuf> {
+        let id = checked_id(block_id)?;
+        Ok(self.root_path.join(format!("blk_{}.part", id)))
+    }
 
-        Ok(original_data)
+    /// Compressed length of what a block holds: its file less the nonce
+    /// and tag. For padding, and blocks a hidden vault filled, the room.
+    pub fn stored_len(&self, block_id: &str) -> Option<u64> {
+        let (_, path) = self.find(block_id)?;
+        fs::metadata(path).ok()?.len().checked_sub((NONCE_LEN + TAG_LEN) as u64)
+    }
+
+    pub fn has_block(&self, block_id: &str) -> bool {
+        checked_id(block_id).is_ok() && self.find(block_id).is_some()
+    }
+
+    /// Checks a fully received block against the key, and a content id
+    /// against what it holds, and moves it into place. A block that fails
+    /// either is discarded.
+    pub fn commit_partial(&self, block_id: &str, key: &MasterKey) -> Result<()> {
+        let partial = self.partial_path(block_id)?;
+        let buffer = fs::read(&partial).context("Failed to read received block")?;
+
+        let valid = match is_content_id(block_id) {
+            true => unseal(&buffer, key).is_ok_and(|data| CryptoEngine::block_id(&Zeroizing::new(data), key) == block_id),
+            false => buffer.len() >= 24 && {
+                let (nonce, ciphertext) = buffer.split_at(24);
+                CryptoEngine::decrypt(ciphertext, nonce, key).is_ok()
+            },
+        };
+        if !valid {
+            let _ = fs::remove_file(&partial);
+            return Err(anyhow::anyhow!("Block {} failed verification", block_id));
+        }
+
+        let volume = &self.volumes[self.pick(buffer.len() as u64, &[])];
+        place(&partial, &Self::path_on(volume, block_id)).context("Failed to store block")?;
+        self.replicate(block_id)?;
+        Ok(())
+    }
+
+    /// Moves every block off the attached volume `id` onto the others, as
+    /// the policy places them; a block with a copy elsewhere is just
+    /// removed. Returns how many blocks and bytes moved.
+    pub fn evacuate(&self, id: &str) -> Result<(u64, u64)> {
+        let from = self.volumes.iter().find(|v| v.id == id)
+            .ok_or_else(|| anyhow::anyhow!("Volume {:?} isn't attached", id))?;
+        let (mut blocks, mut bytes) = (0, 0);
+        for (block_id, size) in blocks_in(&from.path) {
+            let holders = self.copies_of(&block_id);
+            if holders.len() > 1 {
+                fs::remove_file(Self::path_on(from, &block_id))
+                    .with_context(|| format!("Failed to remove block {}", block_id))?;
+                continue;
+            }
+            let to = &self.volumes[self.pick(size, &[id])];
+            if to.id == id {
+                anyhow::bail!("No other volume has room for the blocks on {:?}", id);
+            }
+            place(&Self::path_on(from, &block_id), &Self::path_on(to, &block_id))
+                .with_context(|| format!("Failed to move block {}", block_id))?;
+            blocks += 1;
+            bytes += size;
+        }
+        Ok((blocks, bytes))
+    }
+
+    /// Checks every copy of a block against the key (and a content id
+    /// against what it holds), rewrites the ones that fail from one that
+    /// doesn't, and copies it onto more volumes if it is on fewer than
+    /// `copies`. A block with no good copy is left as it is.
+    pub fn repair_block(&self, block_id: &str, key: &MasterKey) -> Result<Repaired> {
+        let mut repaired = Repaired::default();
+        let (good, bad): (Vec<_>, Vec<_>) = self.holders(block_id).into_iter()
+            .partition(|(_, path)| copy_opens(path, block_id, key));
+        let Some((_, source)) = good.first() else {
+            repaired.lost = !bad.is_empty();
+            return Ok(repaired);
+        };
+        for (volume, path) in &bad {
+            copy_into(source, path).with_context(|| format!("Failed to rewrite block {} on volume {:?}", block_id, volume.id))?;
+            repaired.rewritten.push(volume.id.clone());
+        }
+        repaired.added = self.replicate(block_id)?;
+        Ok(repaired)
+    }
+
+    /// How many attached copies of a block open with the key (and, for a
+    /// content id, hold what it names), and the volumes whose copy doesn't
+    pub fn check_block(&self, block_id: &str, key: &MasterKey) -> (usize, Vec<String>) {
+        let (good, bad): (Vec<_>, Vec<_>) = self.holders(block_id).into_iter()
+            .partition(|(_, path)| copy_opens(path, block_id, key));
+        (good.len(), bad.into_iter().map(|(volume, _)| volume.id.clone()).collect())
+    }
+
+    /// Every block file on the attached volumes as (id, size in bytes)
+    pub fn list_blocks(&self) -> Result<Vec<(String, u64)>> {
+        fs::read_dir(&self.root_path).context("Failed to read vault directory")?;
+        let mut seen = HashSet::new();
+        let mut blocks = Vec::new();
+        for volume in &self.volumes {
+            for (id, size) in blocks_in(&volume.path) {
+                if seen.insert(id.clone()) {
+                    blocks.push((id, size));
+                }
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// Overwrites a block with random bytes before deleting it, so the
+    /// ciphertext is gone even for someone who later gets the key. Storage
+    /// that never writes in place (SSDs, copy-on-write file systems) may
+    /// still keep the old bytes elsewhere.
+    pub fn shred_block(&self, block_id: &str) -> Result<()> {
+        for volume in &self.volumes {
+            let file_path = Self::path_on(volume, block_id);
+            if file_path.exists() {
+                shred(&file_path).context("Failed to delete block")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives the block `from` the id `to` as well, on every volume holding
+    /// it. Both names stay until `from` is deleted.
+    pub fn link_block(&self, from: &str, to: &str) -> Result<()> {
+        let holders = self.holders(from);
+        if holders.is_empty() {
+            return Err(self.missing(from));
+        }
+        let to = checked_id(to)?;
+        for (volume, path) in holders {
+            let target = Self::path_on(volume, &to);
+            if !target.exists() {
+                fs::hard_link(&path, &target)
+                    .or_else(|_| fs::copy(&path, &target).map(|_| ()))
+                    .with_context(|| format!("Failed to name block {} as {}", from, to))?;
+            }
+        }
+        Ok(())
     }
 
     /// Deletes a block permanently
     pub fn delete_block(&self, block_id: &str) -> Result<()> {
-        let file_path = self.root_path.join(format!("blk_{}.bin", block_id));
-        if file_path.exists() {
-            fs::remove_file(file_path).context("Failed to delete block")?;
+        for volume in &self.volumes {
+            let file_path = Self::path_on(volume, block_id);
+            if file_path.exists() {
+                fs::remove_file(file_path).context("Failed to delete block")?;
+            }
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Overwrites the file at `path` with random bytes, then deletes it
+pub fn shred(path: &Path) -> Result<()> {
+    let len = fs::metadata(path)?.len();
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let mut chunk = vec![0u8; 64 * 1024];
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(chunk.len() as u64) as usize;
+        rand::rngs::OsRng.fill_bytes(&mut chunk[..n]);
+        file.write_all(&chunk[..n])?;
+        left -= n as u64;
+    }
+    file.sync_all()?;
+    drop(file);
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Whether the copy at `path` opens and, for a content id, holds it
+fn copy_opens(path: &Path, block_id: &str, key: &MasterKey) -> bool {
+    fs::read(path).ok().and_then(|buffer| unseal(&buffer, key).ok()).is_some_and(|data| {
+        !is_content_id(block_id) || CryptoEngine::block_id(&Zeroizing::new(data), key) == block_id
+    })
+}
+
+/// Whether `id` is a content id (see `CryptoEngine::block_id`) in form
+pub fn is_content_id(id: &str) -> bool {
+    id.len() == CONTENT_ID_LEN && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// `id` if it is a UUID or a content id, and so safe in a file name
+fn checked_id(id: &str) -> Result<String> {
+    if is_content_id(id) {
+        return Ok(id.to_string());
+    }
+    Uuid::parse_str(id)
+        .map(|id| id.to_string())
+        .map_err(|_| anyhow::anyhow!("Invalid block id: {}", id))
+}
+
+/// Opens the contents of a block file: nonce, then the sealed zstd frame
+fn unseal(buffer: &[u8], key: &MasterKey) -> Result<Vec<u8>> {
+    // Split Nonce (First 24 bytes) and Data
+    // XChaCha20 nonce is 24 bytes
+    if buffer.len() < 24 {
+        return Err(anyhow::anyhow!("Block file corrupted or too short"));
+    }
+    let (nonce, ciphertext) = buffer.split_at(24);
+
+    // Decrypt
+    let compressed_data = Zeroizing::new(CryptoEngine::decrypt(ciphertext, nonce, key)
+        .context("Decryption failed (Wrong password or corrupted block)")?);
+
+    // Decompress, growing the output so no stray copies are left
+    let mut decoder = zstd::stream::read::Decoder::new(compressed_data.as_slice())
+        .context("Decompression failed")?;
+    let mut original_data = plaintext::with_capacity(compressed_data.len() * 2);
+    let mut chunk = Zeroizing::new([0u8; 64 * 1024]);
+    loop {
+        let n = decoder.read(&mut chunk[..]).context("Decompression failed")?;
+        if n == 0 {
+            break;
+        }
+        plaintext::append(&mut original_data, &chunk[..n]);
+    }
+
+    Ok(std::mem::take(&mut *original_data))
+}
+
+/// Bytes of skippable frame that pad `compressed` bytes out to a block
+/// file of `len`; None if they don't fit, or leave a gap too small for one
+fn padding_for(len: u64, compressed: usize) -> Option<usize> {
+    let room = (len as usize).checked_sub(NONCE_LEN + TAG_LEN)?;
+    match room.checked_sub(compressed)? {
+        0 => Some(0),
+        pad if pad >= SKIPPABLE_HEADER => Some(pad),
+        _ => None,
+    }
+}
+
+/// Block files directly in `dir` as (id, size in bytes)
+fn blocks_in(dir: &Path) -> Vec<(String, u64)> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries.flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name();
+            let id = name.to_str()?.strip_prefix("blk_")?.strip_suffix(".bin")?.to_string();
+            Some((id, entry.metadata().ok()?.len()))
+        })
+        .collect()
+}
+
+/// Writes a copy of a block file over `to`, or as it, through a `.part`
+/// file so a reader never sees half of one
+fn copy_into(from: &Path, to: &Path) -> std::io::Result<()> {
+    let tmp = to.with_extension("part");
+    fs::copy(from, &tmp)?;
+    File::open(&tmp)?.sync_all()?;
+    fs::rename(&tmp, to)
+}
+
+/// Moves a file into place, copying when it crosses disks
+fn place(from: &Path, to: &Path) -> std::io::Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    let tmp = to.with_extension("part");
+    fs::copy(from, &tmp)?;
+    File::open(&tmp)?.sync_all()?;
+    fs::rename(&tmp, to)?;
+    fs::remove_file(from)
+}
\ No newline at end of file
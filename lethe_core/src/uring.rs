@@ -0,0 +1,327 @@
+//! Batched file reads through io_uring (Linux, the `io-uring` feature).
+//!
+//! `BlockManager::read_blocks` hands a batch of block files to `read_files`,
+//! which queues a read for each and lets the kernel run them side by side,
+//! one `io_uring_enter` for the lot instead of an open/read/close per block.
+//! The ring is set up through the raw syscalls, so it needs nothing beyond
+//! `libc`. Kernels before 5.6, or sandboxes that filter io_uring, refuse the
+//! setup; `read_files` then reads one file after another as before.
+
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Submission queue slots; the kernel rounds up to a power of two
+const ENTRIES: u32 = 64;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OP_READ: u8 = 22;
+
+// Laid out as in <linux/io_uring.h>; not every field is read here
+
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+#[allow(dead_code)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A mapped region of the ring, unmapped on drop
+struct Map {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Map {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        // SAFETY: a fresh shared mapping of the ring fd; the kernel checks the offset and length
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED | libc::MAP_POPULATE, fd, offset)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr: ptr.cast(), len })
+    }
+
+    /// The u32 at `offset`, shared with the kernel
+    fn atomic(&self, offset: u32) -> &AtomicU32 {
+        // SAFETY: offsets come from the kernel and lie inside the mapping, aligned to 4
+        unsafe { &*self.ptr.add(offset as usize).cast::<AtomicU32>() }
+    }
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        // SAFETY: unmaps exactly what `new` mapped
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+struct Ring {
+    fd: OwnedFd,
+    sq: Map,
+    cq: Map,
+    sqes: Map,
+    params: Params,
+}
+
+impl Ring {
+    fn new() -> io::Result<Self> {
+        let mut params = Params::default();
+        // SAFETY: io_uring_setup fills `params`, which outlives the call
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, ENTRIES, &mut params as *mut Params) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: a new descriptor that nothing else owns
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len = params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let sq = Map::new(fd.as_raw_fd(), sq_len, IORING_OFF_SQ_RING)?;
+        let cq = Map::new(fd.as_raw_fd(), cq_len, IORING_OFF_CQ_RING)?;
+        let sqes = Map::new(fd.as_raw_fd(), params.sq_entries as usize * std::mem::size_of::<Sqe>(), IORING_OFF_SQES)?;
+        Ok(Self { fd, sq, cq, sqes, params })
+    }
+
+    /// Queues a read of `len` bytes at `off` of `fd` into `buf`. The caller
+    /// keeps `buf` alive and unmoved until its completion is reaped.
+    fn push_read(&self, fd: RawFd, buf: *mut u8, len: u32, off: u64, user_data: u64) {
+        let off_sq = &self.params.sq_off;
+        let tail = self.sq.atomic(off_sq.tail).load(Ordering::Relaxed);
+        let index = tail & self.sq.atomic(off_sq.ring_mask).load(Ordering::Relaxed);
+        let sqe = Sqe {
+            opcode: IORING_OP_READ,
+            flags: 0,
+            ioprio: 0,
+            fd,
+            off,
+            addr: buf as u64,
+            len,
+            rw_flags: 0,
+            user_data,
+            buf_index: 0,
+            personality: 0,
+            splice_fd_in: 0,
+            addr3: 0,
+            pad: 0,
+        };
+        // SAFETY: `index` is masked to the ring, and the slot is ours until the tail moves past it
+        unsafe {
+            ptr::write(self.sqes.ptr.cast::<Sqe>().add(index as usize), sqe);
+            ptr::write(self.sq.ptr.add(off_sq.array as usize).cast::<u32>().add(index as usize), index);
+        }
+        self.sq.atomic(off_sq.tail).store(tail.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Submits `count` queued reads and waits for at least `wait` to finish
+    fn enter(&self, count: u32, wait: u32) -> io::Result<()> {
+        loop {
+            // SAFETY: io_uring_enter on our ring with no signal mask
+            let res = unsafe {
+                libc::syscall(libc::SYS_io_uring_enter, self.fd.as_raw_fd(), count, wait, IORING_ENTER_GETEVENTS, ptr::null::<libc::sigset_t>(), 0usize)
+            };
+            if res >= 0 {
+                return Ok(());
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    /// Takes the completions that are in, as (user_data, result)
+    fn reap(&self) -> Vec<(u64, i32)> {
+        let off_cq = &self.params.cq_off;
+        let head_ref = self.cq.atomic(off_cq.head);
+        let mut head = head_ref.load(Ordering::Relaxed);
+        let tail = self.cq.atomic(off_cq.tail).load(Ordering::Acquire);
+        let mask = self.cq.atomic(off_cq.ring_mask).load(Ordering::Relaxed);
+        let mut done = Vec::new();
+        while head != tail {
+            // SAFETY: entries between head and tail are written by the kernel
+            let cqe = unsafe { ptr::read(self.cq.ptr.add(off_cq.cqes as usize).cast::<Cqe>().add((head & mask) as usize)) };
+            done.push((cqe.user_data, cqe.res));
+            head = head.wrapping_add(1);
+        }
+        head_ref.store(head, Ordering::Release);
+        done
+    }
+
+    /// Reads each of `paths` whole. An error means the ring itself failed
+    /// and must not be used again.
+    fn read_files(&self, paths: &[PathBuf]) -> io::Result<Vec<io::Result<Vec<u8>>>> {
+        let mut results: Vec<Option<io::Result<Vec<u8>>>> = Vec::with_capacity(paths.len());
+        let mut files: Vec<Option<File>> = Vec::with_capacity(paths.len());
+        let mut bufs: Vec<Vec<u8>> = Vec::with_capacity(paths.len());
+        let mut sizes = Vec::with_capacity(paths.len());
+        for path in paths {
+            let opened = File::open(path).and_then(|f| Ok((f.metadata()?.len(), f)));
+            match opened {
+                Ok((len, file)) => {
+                    results.push(None);
+                    files.push(Some(file));
+                    bufs.push(Vec::with_capacity(len as usize));
+                    sizes.push(len as usize);
+                }
+                Err(e) => {
+                    results.push(Some(Err(e)));
+                    files.push(None);
+                    bufs.push(Vec::new());
+                    sizes.push(0);
+                }
+            }
+        }
+
+        // (file, bytes read so far); reads never outnumber the queue
+        let mut queue: Vec<(usize, usize)> = (0..paths.len()).filter(|i| results[*i].is_none()).map(|i| (i, 0)).collect();
+        queue.reverse();
+        let mut filled = vec![0usize; paths.len()];
+        let mut in_flight = 0u32;
+        while !queue.is_empty() || in_flight > 0 {
+            let mut pushed = 0;
+            while in_flight + pushed < self.params.sq_entries {
+                let Some((i, done)) = queue.pop() else { break };
+                let Some(file) = &files[i] else { continue };
+                // Filled by the kernel; the length is set once it has
+                let rest = &mut bufs[i].spare_capacity_mut()[done..sizes[i]];
+                if rest.is_empty() {
+                    results[i] = Some(Ok(Vec::new()));
+                    continue;
+                }
+                let len = rest.len().min(u32::MAX as usize) as u32;
+                self.push_read(file.as_raw_fd(), rest.as_mut_ptr().cast(), len, done as u64, i as u64);
+                pushed += 1;
+            }
+            if let Err(e) = self.enter(pushed, 1) {
+                // Reads may still land in the buffers, so they can't be
+                // freed; the ring is given up on and the caller reads again
+                std::mem::forget(bufs);
+                std::mem::forget(files);
+                return Err(e);
+            }
+            in_flight += pushed;
+            for (i, res) in self.reap() {
+                in_flight -= 1;
+                let i = i as usize;
+                match res {
+                    res if res < 0 => results[i] = Some(Err(io::Error::from_raw_os_error(-res))),
+                    n => {
+                        filled[i] += n as usize;
+                        // n == 0: the file shrank since it was sized
+                        if n == 0 || filled[i] == sizes[i] {
+                            let mut buf = std::mem::take(&mut bufs[i]);
+                            // SAFETY: the kernel wrote this much from the start
+                            unsafe { buf.set_len(filled[i]) };
+                            results[i] = Some(Ok(buf));
+                        } else {
+                            queue.push((i, filled[i]));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(results.into_iter().map(|r| r.unwrap_or_else(|| Ok(Vec::new()))).collect())
+    }
+}
+
+thread_local! {
+    /// One ring per thread, set up on first use; None if the kernel refused
+    static RING: RefCell<Option<Option<Ring>>> = const { RefCell::new(None) };
+}
+
+/// Reads each of `paths` whole, in one batch where the kernel allows
+pub fn read_files(paths: &[PathBuf]) -> Vec<io::Result<Vec<u8>>> {
+    let batched = RING.with(|ring| {
+        let mut ring = ring.borrow_mut();
+        let slot = ring.get_or_insert_with(|| {
+            Ring::new().inspect_err(|e| log::debug!("io_uring unavailable ({}); reading blocks one at a time", e)).ok()
+        });
+        let results = slot.as_ref()?.read_files(paths);
+        if let Err(e) = &results {
+            log::warn!("io_uring read failed ({}); reading blocks one at a time from now on", e);
+            // Dropping the ring would unmap memory the kernel may still complete into
+            std::mem::forget(slot.take());
+        }
+        results.ok()
+    });
+    match batched {
+        Some(results) => results.into_iter().zip(paths).map(|(r, path)| r.or_else(|_| fs::read(path))).collect(),
+        None => paths.iter().map(fs::read).collect(),
+    }
+}
@@ -0,0 +1,172 @@
+//! Advisory lock on a vault, so one process at a time writes its index.
+//!
+//! Two writers would each save from their own copy of the index, and the
+//! higher revision would later win over the other's changes without a
+//! word. Whoever writes holds a lock on `vault.lock` for as long as its
+//! `VaultLock` lives; the OS lets go if the process dies. `vault.owner`
+//! names the holder for error messages. The lock is advisory: it only
+//! keeps out processes that ask for it.
+//!
+//! Some network shares can't lock files. There `vault.owner` is the lock
+//! itself: created only if absent, and removed by its holder. One left by
+//! a process that crashed is stale, and taken over once its process is
+//! gone from this machine; one from another machine can't be checked, so
+//! it stands until removed by hand.
+
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+use anyhow::{Context, Result};
+
+use crate::device;
+
+pub const LOCK_FILE: &str = "vault.lock";
+const OWNER_FILE: &str = "vault.owner";
+
+/// Vaults some `VaultLock` in this process holds, as `held_here` sees them
+static HELD: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+pub struct VaultLock {
+    owner: PathBuf,
+    /// The vault as `HELD` lists it
+    held: PathBuf,
+    /// None where the owner file is the lock
+    _file: Option<File>,
+}
+
+impl VaultLock {
+    /// Takes the lock on behalf of `holder` (e.g. "lethe put"), or None if
+    /// another process has it
+    pub fn try_acquire(vault: &Path, holder: &str) -> Result<Option<Self>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(vault.join(LOCK_FILE))
+            .context("Failed to open the vault lock")?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(e)) => {
+                log::debug!("Can't lock {:?} ({}); the owner file stands in", vault, e);
+                return Self::try_create_owner(vault, holder);
+            }
+        }
+
+        let owner = vault.join(OWNER_FILE);
+        fs::write(&owner, record(holder)).context("Failed to record the vault lock holder")?;
+        Ok(Some(Self { owner, held: hold(vault), _file: Some(file) }))
+    }
+
+    /// Takes the lock by creating `vault.owner`, over a stale one
+    fn try_create_owner(vault: &Path, holder: &str) -> Result<Option<Self>> {
+        let owner = vault.join(OWNER_FILE);
+        // A stale file is removed once; if another process takes its place
+        // in between, that one holds the lock
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&owner) {
+                Ok(mut file) => {
+                    file.write_all(record(holder).as_bytes()).context("Failed to record the vault lock holder")?;
+                    return Ok(Some(Self { owner, held: hold(vault), _file: None }));
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    let Some(stale) = fs::read_to_string(&owner).ok().filter(|r| is_stale(r)) else {
+                        return Ok(None);
+                    };
+                    log::warn!("Taking over the lock on {:?} from {}, which is no longer running", vault, stale.trim());
+                    match fs::remove_file(&owner) {
+                        Ok(()) => {}
+                        Err(e) if e.kind() == ErrorKind::NotFound => {}
+                        Err(e) => return Err(e).context("Failed to remove a stale vault lock"),
+                    }
+                }
+                Err(e) => return Err(e).context("Failed to lock the vault"),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Takes the lock, or fails naming whoever has it
+    pub fn acquire(vault: &Path, holder: &str) -> Result<Self> {
+        Self::try_acquire(vault, holder)?.ok_or_else(|| in_use(vault))
+    }
+
+    /// Whether this process holds the lock on `vault`, so what it writes
+    /// there can't race another process's
+    pub fn held_here(vault: &Path) -> bool {
+        HELD.lock().unwrap_or_else(PoisonError::into_inner).contains(&canonical(vault))
+    }
+}
+
+fn canonical(vault: &Path) -> PathBuf {
+    fs::canonicalize(vault).unwrap_or_else(|_| vault.to_path_buf())
+}
+
+fn hold(vault: &Path) -> PathBuf {
+    let held = canonical(vault);
+    HELD.lock().unwrap_or_else(PoisonError::into_inner).push(held.clone());
+    held
+}
+
+impl Drop for VaultLock {
+    fn drop(&mut self) {
+        // Before the file closes, so the next holder's name is never removed
+        let _ = fs::remove_file(&self.owner);
+        let mut held = HELD.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(at) = held.iter().position(|v| *v == self.held) {
+            held.swap_remove(at);
+        }
+    }
+}
+
+/// What `vault.owner` says: `<holder> (pid <pid> on <host>)`
+fn record(holder: &str) -> String {
+    format!("{} (pid {} on {})", holder, std::process::id(), device::name())
+}
+
+/// Whether `record` names a process on this machine that has exited
+fn is_stale(record: &str) -> bool {
+    let parsed = record.trim().strip_suffix(')')
+        .and_then(|r| r.rsplit_once(" (pid "))
+        .and_then(|(_, inner)| inner.split_once(" on "))
+        .and_then(|(pid, host)| Some((pid.parse::<u32>().ok()?, host)));
+    match parsed {
+        Some((pid, host)) => host == device::name() && !pid_alive(pid),
+        None => false,
+    }
+}
+
+/// Whether process `pid` still exists
+pub fn pid_alive(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        // SAFETY: signal 0 only checks for existence and permission
+        let rc = unsafe { libc::kill(pid as libc::pid_t, 0) };
+        rc == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    #[cfg(windows)]
+    {
+        std::process::Command::new("tasklist")
+            .args(["/FI", &format!("PID eq {}", pid), "/NH"])
+            .output()
+            .map(|o| String::from_utf8_lossy(&o.stdout).contains(&pid.to_string()))
+            .unwrap_or(false)
+    }
+}
+
+/// The holder a locked vault names, if any. Stale after a crash, so only
+/// meaningful while the lock is actually taken.
+pub fn holder(vault: &Path) -> Option<String> {
+    fs::read_to_string(vault.join(OWNER_FILE)).ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+/// Error for a vault another process holds
+pub fn in_use(vault: &Path) -> anyhow::Error {
+    let holder = holder(vault).unwrap_or_else(|| "another Lethe process".to_string());
+    anyhow::anyhow!("{:?} is in use by {}. Wait for it to finish or stop it, then try again.", vault, holder)
+}
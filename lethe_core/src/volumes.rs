@@ -0,0 +1,206 @@
+//! Spreading one vault's blocks over several disks.
+//!
+//! The vault folder is always the volume `main`: it keeps the index and
+//! everything besides blocks. `volumes.conf` in it lists the others, one
+//! `volume <id> <capacity> <path>` line each (capacity in bytes, `-` for
+//! none), plus `policy <name>` and `capacity <bytes>` for main. Each extra
+//! volume folder holds a `lethe.volume` marker with its id and the vault's
+//! salt, so a disk that isn't plugged in (an empty mountpoint) shows as
+//! detached rather than empty, and a disk from another vault is ignored.
+//!
+//! New blocks go to an attached volume picked by the policy; existing
+//! blocks are found wherever they sit. While a volume is detached, files
+//! with blocks on it can't be read and everything else works as before.
+//!
+//! The index records which volume holds each block (see
+//! `VaultIndex::placement`), so those files can be named. The layout is
+//! per copy: it never syncs.
+//!
+//! With `copies <n>` each new block is written to n volumes, no two on the
+//! same one, so a disk that dies or rots loses nothing. A copy that doesn't
+//! open is passed over on read, and `lethe repair` rewrites it from a good
+//! one and tops up blocks short of copies.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+
+use crate::header::VaultHeader;
+
+pub const VOLUMES_FILE: &str = "volumes.conf";
+pub const MARKER_FILE: &str = "lethe.volume";
+
+/// The vault folder itself
+pub const MAIN: &str = "main";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Policy {
+    /// Each new block on the next volume with room
+    #[default]
+    RoundRobin,
+    /// Each new block on the volume with the most capacity left; volumes
+    /// without a capacity only take what the others can't
+    Capacity,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "capacity" => Ok(Self::Capacity),
+            _ => Err(format!("Unknown placement policy {:?} (use round-robin or capacity)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::RoundRobin => "round-robin",
+            Self::Capacity => "capacity",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Volume {
+    pub id: String,
+    pub path: PathBuf,
+    /// Bytes of blocks it may hold; None for no limit
+    pub capacity: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Volumes {
+    pub policy: Policy,
+    /// Volumes each new block is written to
+    pub copies: usize,
+    /// `main` first
+    pub list: Vec<Volume>,
+    salt: String,
+}
+
+/// Bare names only, so a line of `volumes.conf` splits cleanly
+pub fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+impl Volumes {
+    /// The layout of the vault at `vault`; just `main` if none is set
+    pub fn load(vault: &Path) -> Result<Self> {
+        let salt = VaultHeader::load(vault).map(|h| h.salt().to_string()).unwrap_or_default();
+        let mut volumes = Self {
+            policy: Policy::default(),
+            copies: 1,
+            list: vec![Volume { id: MAIN.to_string(), path: vault.to_path_buf(), capacity: None }],
+            salt,
+        };
+        let contents = match fs::read_to_string(vault.join(VOLUMES_FILE)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(volumes),
+            Err(e) => return Err(e).context("Failed to read the volume list"),
+        };
+
+        let capacity = |s: &str| -> Result<Option<u64>> {
+            match s {
+                "-" => Ok(None),
+                s => s.parse().map(Some).with_context(|| format!("Invalid capacity {:?} in {}", s, VOLUMES_FILE)),
+            }
+        };
+        for line in contents.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let mut words = line.splitn(4, ' ');
+            match (words.next(), words.next(), words.next(), words.next()) {
+                (Some("policy"), Some(policy), None, None) => {
+                    volumes.policy = policy.parse().map_err(anyhow::Error::msg)?;
+                }
+                (Some("capacity"), Some(bytes), None, None) => volumes.list[0].capacity = capacity(bytes)?,
+                (Some("copies"), Some(n), None, None) => {
+                    volumes.copies = n.parse().ok().filter(|n| *n >= 1)
+                        .with_context(|| format!("Invalid copies {:?} in {}", n, VOLUMES_FILE))?;
+                }
+                (Some("volume"), Some(id), Some(bytes), Some(path)) if valid_id(id) && id != MAIN => {
+                    volumes.list.push(Volume { id: id.to_string(), path: PathBuf::from(path), capacity: capacity(bytes)? });
+                }
+                _ => anyhow::bail!("Invalid line in {}: {:?}", VOLUMES_FILE, line),
+            }
+        }
+        Ok(volumes)
+    }
+
+    pub fn save(&self, vault: &Path) -> Result<()> {
+        let path = vault.join(VOLUMES_FILE);
+        if self.list.len() == 1 && self.policy == Policy::default() && self.list[0].capacity.is_none() && self.copies == 1 {
+            if path.exists() {
+                fs::remove_file(&path).context("Failed to remove the volume list")?;
+            }
+            return Ok(());
+        }
+        let bytes = |capacity: Option<u64>| capacity.map(|c| c.to_string()).unwrap_or_else(|| "-".to_string());
+        let mut contents = format!("policy {}\n", self.policy);
+        if self.copies != 1 {
+            contents.push_str(&format!("copies {}\n", self.copies));
+        }
+        if let Some(capacity) = self.list[0].capacity {
+            contents.push_str(&format!("capacity {}\n", capacity));
+        }
+        for volume in &self.list[1..] {
+            contents.push_str(&format!("volume {} {} {}\n", volume.id, bytes(volume.capacity), volume.path.display()));
+        }
+        let tmp = vault.join(format!("{}.tmp", VOLUMES_FILE));
+        fs::write(&tmp, contents).context("Failed to write the volume list")?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Volume> {
+        self.list.iter().find(|v| v.id == id)
+    }
+
+    /// Whether the disk of `volume` is there and belongs to this vault
+    pub fn attached(&self, volume: &Volume) -> bool {
+        volume.id == MAIN || fs::read_to_string(volume.path.join(MARKER_FILE))
+            .is_ok_and(|marker| marker.split_whitespace().eq([volume.id.as_str(), self.salt.as_str()]))
+    }
+
+    /// Adds the folder at `path` as a volume and marks it
+    pub fn add(&mut self, id: &str, path: &Path, capacity: Option<u64>) -> Result<&Volume> {
+        if !valid_id(id) || self.get(id).is_some() {
+            anyhow::bail!("{:?} is taken or not a valid volume name (letters, digits, '-', '_' and '.')", id);
+        }
+        fs::create_dir_all(path).with_context(|| format!("Failed to create {:?}", path))?;
+        let path = fs::canonicalize(path)?;
+        for volume in &self.list {
+            let other = fs::canonicalize(&volume.path).unwrap_or_else(|_| volume.path.clone());
+            if path.starts_with(&other) || other.starts_with(&path) {
+                anyhow::bail!("{:?} overlaps volume {:?} ({:?})", path, volume.id, volume.path);
+            }
+        }
+        if path.join(MARKER_FILE).exists() {
+            anyhow::bail!("{:?} is already a volume of a vault", path);
+        }
+        fs::write(path.join(MARKER_FILE), format!("{} {}\n", id, self.salt)).context("Failed to mark the volume")?;
+        self.list.push(Volume { id: id.to_string(), path, capacity });
+        Ok(self.list.last().unwrap())
+    }
+
+    /// Drops a volume from the list and removes its marker, if the disk
+    /// is there. Its blocks must have been moved off first.
+    pub fn remove(&mut self, id: &str) -> Result<Volume> {
+        if id == MAIN {
+            anyhow::bail!("The vault folder can't be removed");
+        }
+        let at = self.list.iter().position(|v| v.id == id)
+            .ok_or_else(|| anyhow::anyhow!("No volume named {:?}", id))?;
+        let volume = self.list.remove(at);
+        let marker = volume.path.join(MARKER_FILE);
+        if marker.exists() {
+            fs::remove_file(marker).context("Failed to remove the volume marker")?;
+        }
+        Ok(volume)
+    }
+}
@@ -0,0 +1,56 @@
+//! Helpers the integration tests share
+
+// Each test crate uses only some of them
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use proptest::test_runner::Config as ProptestConfig;
+
+use lethe_core::crypto::{CryptoEngine, MasterKey};
+use lethe_core::index::{FileEntry, IndexManager};
+use lethe_core::reader::FileReader;
+use lethe_core::storage::BlockManager;
+
+/// Smallest average a vault can set, so a few hundred KiB make several chunks
+pub const SMALL_CHUNKS: u32 = 64 * 1024;
+
+/// A fresh folder for one case, removed when dropped
+pub struct Scratch(pub PathBuf);
+
+impl Scratch {
+    pub fn new(name: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let temp = std::env::temp_dir();
+        // Device ids are kept per user; these folders get theirs here
+        std::env::set_var("XDG_CONFIG_HOME", temp.join("lethe-tests-config"));
+        Self(temp.join(format!("lethe-tests-{}-{}-{}", std::process::id(), name, NEXT.fetch_add(1, Ordering::Relaxed))))
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// `cases` of a property, unless `PROPTEST_CASES` asks for more or fewer
+pub fn config(cases: u32) -> ProptestConfig {
+    let cases = std::env::var("PROPTEST_CASES").ok().and_then(|n| n.parse().ok()).unwrap_or(cases);
+    ProptestConfig { cases, failure_persistence: None, ..ProptestConfig::default() }
+}
+
+pub fn new_vault(dir: &Path) -> IndexManager {
+    let mut index = IndexManager::new_empty(dir.to_path_buf(), CryptoEngine::new_salt());
+    index.set_chunk_size(SMALL_CHUNKS).expect("chunk size");
+    index
+}
+
+pub fn read(storage: &BlockManager, key: &MasterKey, entry: &FileEntry) -> Vec<u8> {
+    let mut data = Vec::new();
+    FileReader::new(storage, key, entry).read_to_end(&mut data).expect("read back");
+    data
+}
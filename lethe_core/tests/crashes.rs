@@ -0,0 +1,267 @@
+//! Crashes at every point of a store and save, and replicas that disagree.
+//!
+//! `Disk` holds the files of a vault folder. `crash_points` takes the
+//! folder before and after an operation and gives each state a power cut
+//! could leave it in, following the order the vault writes in: new blocks
+//! (each through a `.part` file), then the three index replicas (each
+//! through a `.tmp` file), then the rest. Files written whole go through a
+//! temporary file and a rename, so a cut leaves that file cut short and
+//! the old one in place; files appended to, like the event log, are left
+//! with part of the append. A rename is taken to land only after what was
+//! written before it.
+//!
+//! From each state, loading the vault has to give the index from before
+//! or from after, with every file reading back as it was then; loading
+//! rewrites the other replicas to match, as `lethe repair` relies on. The
+//! event log has to go on taking saves and reading them back. While
+//! another process holds the vault, loading leaves the replicas alone.
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use proptest::prelude::*;
+
+use lethe_core::crypto::{CryptoEngine, MasterKey};
+use lethe_core::events::{Change, Subscription};
+use lethe_core::index::IndexManager;
+use lethe_core::storage::BlockManager;
+use lethe_core::vault_lock::LOCK_FILE;
+
+use common::{config, new_vault, read, Scratch};
+
+const PATHS: [&str; 4] = ["/a", "/b", "/docs/c", "/docs/d"];
+
+/// The files of a vault folder, by name
+type Disk = BTreeMap<String, Vec<u8>>;
+
+/// Vault path -> content
+type Tree = BTreeMap<String, Vec<u8>>;
+
+fn snapshot(dir: &Path) -> Disk {
+    fs::read_dir(dir).unwrap().flatten()
+        .filter(|e| e.file_type().unwrap().is_file())
+        .map(|e| (e.file_name().to_string_lossy().into_owned(), fs::read(e.path()).unwrap()))
+        .collect()
+}
+
+fn lay_out(dir: &Path, disk: &Disk) {
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir).unwrap();
+    for (name, bytes) in disk {
+        fs::write(dir.join(name), bytes).unwrap();
+    }
+}
+
+/// Where a file is written before it is renamed into place
+fn temporary(name: &str) -> String {
+    match name.strip_suffix(".bin") {
+        Some(block) if name.starts_with("blk_") => format!("{}.part", block),
+        Some(replica) if name.starts_with("meta_") => format!("{}.tmp", replica),
+        _ => format!("{}.tmp", name),
+    }
+}
+
+/// Blocks first, then replicas in turn, then the rest
+fn write_order(name: &str) -> (u8, String) {
+    let rank = match name {
+        n if n.starts_with("blk_") => 0,
+        n if n.starts_with("meta_") => 1,
+        _ => 2,
+    };
+    (rank, name.to_string())
+}
+
+/// Every state a power cut between `before` and `after` can leave, each
+/// file that changed cut short at `cuts` of its length (0.0 to 1.0)
+fn crash_points(before: &Disk, after: &Disk, cuts: &[f64]) -> Vec<Disk> {
+    let mut changed: Vec<&String> = after.keys().chain(before.keys())
+        .filter(|name| before.get(*name) != after.get(*name))
+        .collect();
+    changed.sort_by_key(|name| write_order(name));
+    changed.dedup();
+
+    let mut points = Vec::new();
+    let mut disk = before.clone();
+    for name in changed {
+        let Some(new) = after.get(name) else {
+            disk.remove(name);
+            points.push(disk.clone());
+            continue;
+        };
+        let old = before.get(name);
+        for cut in cuts {
+            let mut torn = disk.clone();
+            if let Some(old) = old.filter(|old| new.starts_with(old)) {
+                // Appended to in place
+                let len = old.len() + ((new.len() - old.len()) as f64 * cut) as usize;
+                torn.insert(name.clone(), new[..len].to_vec());
+            } else {
+                let len = (new.len() as f64 * cut) as usize;
+                torn.insert(temporary(name), new[..len].to_vec());
+            }
+            points.push(torn);
+        }
+        disk.insert(name.clone(), new.clone());
+        points.push(disk.clone());
+    }
+    points
+}
+
+fn files(index: &IndexManager, storage: &BlockManager, key: &MasterKey) -> Tree {
+    index.data.files.iter()
+        .filter(|(_, e)| !e.is_dir)
+        .map(|(path, e)| (path.clone(), read(storage, key, e)))
+        .collect()
+}
+
+fn apply(index: &mut IndexManager, storage: &BlockManager, key: &MasterKey, puts: &[(usize, Vec<u8>)], delete: Option<usize>) {
+    for (p, data) in puts {
+        index.store_file(storage, key, PATHS[*p].to_string(), data).expect("store");
+    }
+    if let Some(p) = delete {
+        index.data.files.remove(PATHS[p]);
+    }
+    index.save(key).expect("save");
+}
+
+fn contents() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        proptest::collection::vec(any::<u8>(), 0..200_000),
+        (any::<u8>(), 0..300_000usize).prop_map(|(byte, len)| vec![byte; len]),
+    ]
+}
+
+fn puts() -> impl Strategy<Value = Vec<(usize, Vec<u8>)>> {
+    proptest::collection::vec((0..PATHS.len(), contents()), 1..4)
+}
+
+/// A replica as a crash or a failing disk may leave it
+#[derive(Debug, Clone, Copy)]
+enum Replica {
+    Old,
+    New,
+    CutShort,
+    Garbled,
+    Missing,
+}
+
+fn replica() -> impl Strategy<Value = Replica> {
+    prop_oneof![Just(Replica::Old), Just(Replica::New), Just(Replica::CutShort), Just(Replica::Garbled), Just(Replica::Missing)]
+}
+
+proptest! {
+    #![proptest_config(config(6))]
+
+    #[test]
+    fn every_crash_during_a_save_loads_before_or_after(
+        setup in puts(),
+        change in puts(),
+        delete in proptest::option::of(0..PATHS.len()),
+        cuts in proptest::collection::vec(0.0..1.0f64, 1..3),
+    ) {
+        let dir = Scratch::new("crash");
+        let (vault, replay) = (dir.0.join("vault"), dir.0.join("replay"));
+        let key = CryptoEngine::random_key();
+        let storage = BlockManager::new(&vault).unwrap();
+        let mut index = new_vault(&vault);
+        apply(&mut index, &storage, &key, &setup, None);
+        let (before, old) = (snapshot(&vault), files(&index, &storage, &key));
+        apply(&mut index, &storage, &key, &change, delete);
+        let (after, new) = (snapshot(&vault), files(&index, &storage, &key));
+
+        for disk in crash_points(&before, &after, &cuts) {
+            lay_out(&replay, &disk);
+            let storage = BlockManager::new(&replay).unwrap();
+            let mut loaded = IndexManager::load(replay.clone(), &key).expect("load after a crash");
+            let tree = files(&loaded, &storage, &key);
+            prop_assert!(tree == old || tree == new, "a crash left neither the old nor the new files");
+
+            // Every replica now opens at the revision loaded
+            for (file, opened) in IndexManager::replicas(&replay, &key, None) {
+                let revision = opened.map(|(index, _)| index.revision).ok();
+                prop_assert_eq!(revision, Some(loaded.data.revision), "{:?} wasn't rewritten", file);
+            }
+            prop_assert!(storage.list_blocks().unwrap().iter().all(|(_, len)| *len > 0));
+
+            // Saving goes on from there, and the event log reads back whole
+            let mut events = Subscription::from_now(&replay, &key);
+            loaded.store_file(&storage, &key, "/after".to_string(), b"saved after the crash").expect("store");
+            loaded.save(&key).expect("save after a crash");
+            let seen = events.poll().expect("event log");
+            let logged = seen.iter().any(|e| matches!(&e.change, Change::FileAdded { path, .. } if path == "/after"));
+            prop_assert!(logged, "the save after the crash wasn't logged");
+            prop_assert!(Subscription::from_start(&replay, &key).poll().is_ok());
+            let reloaded = IndexManager::load(replay.clone(), &key).expect("reload");
+            prop_assert_eq!(&reloaded.data.files, &loaded.data.files);
+        }
+    }
+
+    #[test]
+    fn replicas_that_disagree_load_the_newest_that_opens(
+        setup in puts(),
+        change in puts(),
+        states in [replica(), replica(), replica()],
+        held_elsewhere in any::<bool>(),
+    ) {
+        let dir = Scratch::new("replicas");
+        let key = CryptoEngine::random_key();
+        let storage = BlockManager::new(&dir.0).unwrap();
+        let mut index = new_vault(&dir.0);
+        apply(&mut index, &storage, &key, &setup, None);
+        let (old_sealed, old) = (fs::read(dir.0.join("meta_0.bin")).unwrap(), files(&index, &storage, &key));
+        let old_revision = index.data.revision;
+        apply(&mut index, &storage, &key, &change, None);
+        let (new_sealed, new) = (fs::read(dir.0.join("meta_0.bin")).unwrap(), files(&index, &storage, &key));
+
+        for (i, state) in states.iter().enumerate() {
+            let file = dir.0.join(format!("meta_{}.bin", i));
+            let mut garbled = new_sealed.clone();
+            let at = garbled.len() / 2;
+            garbled[at] ^= 0x5a;
+            match state {
+                Replica::Old => fs::write(&file, &old_sealed).unwrap(),
+                Replica::New => fs::write(&file, &new_sealed).unwrap(),
+                Replica::CutShort => fs::write(&file, &new_sealed[..new_sealed.len() / 3]).unwrap(),
+                Replica::Garbled => fs::write(&file, &garbled).unwrap(),
+                Replica::Missing => fs::remove_file(&file).unwrap(),
+            }
+        }
+
+        let expected = if states.iter().any(|s| matches!(s, Replica::New)) {
+            Some((new, old_revision + 1))
+        } else if states.iter().any(|s| matches!(s, Replica::Old)) {
+            Some((old, old_revision))
+        } else {
+            None
+        };
+        // Another process holding the vault may be halfway through a save
+        let replicas_on_disk = || -> Disk { snapshot(&dir.0).into_iter().filter(|(name, _)| name.starts_with("meta_")).collect() };
+        let laid_out = replicas_on_disk();
+        let _other = held_elsewhere.then(|| {
+            let lock = fs::File::create(dir.0.join(LOCK_FILE)).unwrap();
+            lock.lock().unwrap();
+            lock
+        });
+        let loaded = IndexManager::load(dir.0.clone(), &key);
+        let Some((tree, revision)) = expected else {
+            prop_assert!(loaded.is_err(), "no replica opens, yet the vault loaded");
+            return Ok(());
+        };
+        let loaded = loaded.expect("load");
+        prop_assert_eq!(loaded.data.revision, revision);
+        prop_assert_eq!(files(&loaded, &storage, &key), tree);
+        if held_elsewhere {
+            prop_assert_eq!(loaded.stale_replicas(), 0);
+            prop_assert!(replicas_on_disk() == laid_out, "replicas were rewritten under another holder's lock");
+            return Ok(());
+        }
+        let stale = states.iter().filter(|s| !matches!((s, revision == old_revision), (Replica::New, false) | (Replica::Old, true))).count();
+        prop_assert_eq!(loaded.stale_replicas(), stale);
+        for (file, opened) in IndexManager::replicas(&dir.0, &key, None) {
+            prop_assert_eq!(opened.map(|(index, _)| index.revision).ok(), Some(revision), "{:?} wasn't rewritten", file);
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Files past 4 GiB and up to 10 GiB and beyond, stored and read back
+//! through the streaming paths without holding them in memory:
+//!
+//! - `store_reader`, and `stage_reader` then `commit`, take the file from
+//!   a reader;
+//! - after a save and a fresh load, `FileReader` reads it back in order
+//!   byte for byte, and `read_at` finds the bytes either side of 4 GiB
+//!   and the last few;
+//! - the process never grows past `MEMORY_LIMIT` doing so.
+//!
+//! They write each size to disk in full, so they only run when asked:
+//! `cargo test --release -p lethe_core --test large_files -- --ignored`.
+//! `LETHE_LARGE_SIZES` (bytes, comma-separated) swaps in other sizes.
+
+mod common;
+
+use std::io::{self, Read};
+
+use lethe_core::crypto::{CryptoEngine, MasterKey};
+use lethe_core::index::{FileEntry, IndexManager};
+use lethe_core::progress::Silent;
+use lethe_core::reader::FileReader;
+use lethe_core::storage::BlockManager;
+
+use common::Scratch;
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// Just past 4 GiB, 10 GiB, and past 10 GiB by an odd amount
+const SIZES: [u64; 3] = [4 * GIB + 1, 10 * GIB, 10 * GIB + 12_345];
+
+/// Most the process may have held at once, however large the file
+const MEMORY_LIMIT: u64 = GIB;
+
+const BUFFER: usize = 1024 * 1024;
+
+fn sizes() -> Vec<u64> {
+    match std::env::var("LETHE_LARGE_SIZES") {
+        Ok(sizes) => sizes.split(',').map(|n| n.trim().parse().expect("LETHE_LARGE_SIZES takes bytes")).collect(),
+        Err(_) => SIZES.to_vec(),
+    }
+}
+
+/// splitmix64, so any byte of the content can be worked out from its offset
+fn word(n: u64) -> u64 {
+    let mut z = n.wrapping_add(1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn byte_at(offset: u64) -> u8 {
+    word(offset / 8).to_le_bytes()[(offset % 8) as usize]
+}
+
+fn fill_from(offset: u64, buf: &mut [u8]) {
+    let mut at = 0;
+    while at < buf.len() {
+        let pos = offset + at as u64;
+        let bytes = word(pos / 8).to_le_bytes();
+        let from = (pos % 8) as usize;
+        let n = (8 - from).min(buf.len() - at);
+        buf[at..at + n].copy_from_slice(&bytes[from..from + n]);
+        at += n;
+    }
+}
+
+/// `len` bytes of content that doesn't compress or repeat
+struct Pattern {
+    at: u64,
+    len: u64,
+}
+
+impl Read for Pattern {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min((self.len - self.at) as usize);
+        fill_from(self.at, &mut buf[..n]);
+        self.at += n as u64;
+        Ok(n)
+    }
+}
+
+/// The most memory the process has held so far, where the kernel says
+fn peak_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse::<u64>().ok().map(|kib| kib * 1024)
+}
+
+fn check_memory(size: u64) {
+    if let Some(peak) = peak_memory() {
+        assert!(peak < MEMORY_LIMIT, "{} bytes held at once for a {} byte file", peak, size);
+    }
+}
+
+/// Reads the whole file in order and checks every byte
+fn check_streamed(storage: &BlockManager, key: &MasterKey, entry: &FileEntry) {
+    let mut reader = FileReader::new(storage, key, entry);
+    let (mut got, mut want) = (vec![0u8; BUFFER], vec![0u8; BUFFER]);
+    let mut at = 0u64;
+    loop {
+        let n = reader.read(&mut got).expect("read back");
+        if n == 0 {
+            break;
+        }
+        fill_from(at, &mut want[..n]);
+        assert!(got[..n] == want[..n], "content differs in the {} bytes from {}", n, at);
+        at += n as u64;
+    }
+    assert_eq!(at, entry.size, "read back a different length");
+}
+
+/// Reads around 4 GiB and at the very end, out of order
+fn check_random_access(storage: &BlockManager, key: &MasterKey, entry: &FileEntry) {
+    let size = entry.size;
+    let probes = [size.saturating_sub(1), 4 * GIB - 3, 4 * GIB, 0, size.saturating_sub(4096), size / 2];
+    let mut reader = FileReader::new(storage, key, entry);
+    let mut buf = [0u8; 16];
+    for offset in probes.into_iter().filter(|o| *o < size) {
+        let n = reader.read_at(offset, &mut buf).expect("read_at");
+        assert!(n > 0, "nothing at {} of {}", offset, size);
+        for (i, b) in buf[..n].iter().enumerate() {
+            assert_eq!(*b, byte_at(offset + i as u64), "byte {} of {}", offset + i as u64, size);
+        }
+    }
+    assert_eq!(reader.read_at(size, &mut buf).expect("read_at the end"), 0);
+}
+
+/// Stores a file of `size` one way, saves, loads again and reads it back
+fn round_trip(size: u64, staged: bool) {
+    let scratch = Scratch::new("large");
+    let key = CryptoEngine::random_key();
+    let storage = BlockManager::new(&scratch.0).unwrap();
+    let mut index = IndexManager::new_empty(scratch.0.clone(), CryptoEngine::new_salt());
+    let reader = Pattern { at: 0, len: size };
+    if staged {
+        let staged = index.stage_reader(&storage, &key, reader).unwrap();
+        index.commit("/large.bin".to_string(), staged);
+    } else {
+        index.store_reader(&storage, &key, "/large.bin".to_string(), reader, &Silent).unwrap();
+    }
+    index.save(&key).unwrap();
+    check_memory(size);
+
+    let index = IndexManager::load(scratch.0.clone(), &key).unwrap();
+    let entry = index.get_file("/large.bin").unwrap();
+    assert_eq!(entry.size, size);
+    check_streamed(&storage, &key, entry);
+    check_random_access(&storage, &key, entry);
+    check_memory(size);
+}
+
+#[test]
+#[ignore = "writes each size to disk in full"]
+fn store_reader_round_trips() {
+    for size in sizes() {
+        round_trip(size, false);
+    }
+}
+
+#[test]
+#[ignore = "writes each size to disk in full"]
+fn staged_round_trips() {
+    for size in sizes() {
+        round_trip(size, true);
+    }
+}
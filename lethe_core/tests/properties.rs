@@ -0,0 +1,230 @@
+//! Properties the vault format has to keep, checked over generated files,
+//! chunk sizes and sequences of changes rather than a few hand-picked ones:
+//!
+//! - chunking covers every byte once, streamed or not;
+//! - what is stored, saved and loaded again reads back the same;
+//! - merging two copies gives the same either way round, and merging the
+//!   result again changes nothing, releases of write-once files included;
+//! - deleting every block `referenced_blocks` leaves out, as `lethe clean`
+//!   does, removes each copy on every volume and never costs a live file
+//!   or a snapshot its content.
+//!
+//! `PROPTEST_CASES=500 cargo test -p lethe_core --test properties` runs
+//! more cases than the few each property defaults to.
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use proptest::prelude::*;
+
+use lethe_core::chunker::{self, Chunking, Chunks, AVERAGES};
+use lethe_core::crypto::{CryptoEngine, MasterKey};
+use lethe_core::header::VaultHeader;
+use lethe_core::index::IndexManager;
+use lethe_core::merge;
+use lethe_core::storage::BlockManager;
+use lethe_core::volumes::Volumes;
+
+use common::{config, new_vault, read, Scratch};
+
+/// Paths the changes pick from; none lies below another
+const PATHS: [&str; 6] = ["/a.txt", "/b.bin", "/docs/c.txt", "/docs/d", "/docs/sub/e", "/f"];
+
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap().flatten() {
+        if entry.file_type().unwrap().is_file() {
+            fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+        }
+    }
+}
+
+/// File contents: runs from a small pool of pieces, so files share chunks
+/// with each other and with their earlier versions
+fn contents() -> impl Strategy<Value = Vec<u8>> {
+    let piece = prop_oneof![
+        (any::<u8>(), 1..40_000usize).prop_map(|(byte, len)| vec![byte; len]),
+        proptest::collection::vec(any::<u8>(), 0..70_000),
+        Just((0..150_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect()),
+    ];
+    proptest::collection::vec(piece, 0..5).prop_map(|pieces| pieces.concat())
+}
+
+#[derive(Debug, Clone)]
+enum Change {
+    Put(usize, Vec<u8>),
+    Delete(usize),
+    Rename(usize, usize),
+    /// Makes the file write once, as `lethe put --immutable` does
+    Hold(usize),
+    /// `lethe release`
+    Release(usize),
+    Snapshot,
+    DropSnapshot(usize),
+}
+
+fn changes() -> impl Strategy<Value = Vec<Change>> {
+    let change = prop_oneof![
+        4 => (0..PATHS.len(), contents()).prop_map(|(p, data)| Change::Put(p, data)),
+        2 => (0..PATHS.len()).prop_map(Change::Delete),
+        1 => (0..PATHS.len(), 0..PATHS.len()).prop_map(|(from, to)| Change::Rename(from, to)),
+        1 => (0..PATHS.len()).prop_map(Change::Hold),
+        1 => (0..PATHS.len()).prop_map(Change::Release),
+        1 => Just(Change::Snapshot),
+        1 => (0..4usize).prop_map(Change::DropSnapshot),
+    ];
+    proptest::collection::vec(change, 1..8)
+}
+
+/// Applies `changes`, saving after each, and returns what every live path
+/// should hold afterwards
+fn apply(index: &mut IndexManager, storage: &BlockManager, key: &MasterKey, changes: &[Change], expected: &mut BTreeMap<String, Vec<u8>>) {
+    for change in changes {
+        match change {
+            Change::Put(p, data) => {
+                let path = PATHS[*p];
+                if index.check_mutable(path).is_err() {
+                    continue;
+                }
+                index.store_file(storage, key, path.to_string(), data).expect("store");
+                expected.insert(path.to_string(), data.clone());
+            }
+            Change::Delete(p) => {
+                index.data.files.remove(PATHS[*p]);
+                expected.remove(PATHS[*p]);
+            }
+            Change::Rename(from, to) => {
+                let (from, to) = (PATHS[*from], PATHS[*to]);
+                if expected.contains_key(from) && !expected.contains_key(to) && index.rename_tree(from, to) {
+                    let data = expected.remove(from).unwrap();
+                    expected.insert(to.to_string(), data);
+                }
+            }
+            Change::Hold(p) => {
+                index.set_immutable(PATHS[*p], true);
+            }
+            Change::Release(p) => {
+                index.set_immutable(PATHS[*p], false);
+            }
+            Change::Snapshot => {
+                let name = format!("s{}", index.data.snapshots.len() + index.data.revision as usize);
+                index.create_snapshot(&name).expect("snapshot");
+            }
+            Change::DropSnapshot(n) => {
+                if let Some(name) = index.data.snapshots.keys().nth(*n).cloned() {
+                    index.delete_snapshot(&name);
+                }
+            }
+        }
+        index.save(key).expect("save");
+    }
+}
+
+proptest! {
+    #![proptest_config(config(64))]
+
+    #[test]
+    fn chunks_cover_every_byte(data in proptest::collection::vec(any::<u8>(), 0..600_000), average in AVERAGES) {
+        let chunking = Chunking::averaging(average);
+        let chunks = chunker::split(&data, chunking);
+        prop_assert_eq!(chunks.concat(), data.clone());
+        prop_assert!(chunks.iter().all(|c| !c.is_empty()));
+
+        let mut streamed = Vec::new();
+        let mut reader = Chunks::new(&data[..], chunking);
+        while let Some(chunk) = reader.next_chunk().unwrap() {
+            streamed.push(chunk.to_vec());
+        }
+        prop_assert_eq!(streamed, chunks.iter().map(|c| c.to_vec()).collect::<Vec<_>>());
+    }
+}
+
+proptest! {
+    #![proptest_config(config(12))]
+
+    #[test]
+    fn stored_files_read_back_after_reload(changes in changes()) {
+        let dir = Scratch::new("roundtrip");
+        let key = CryptoEngine::random_key();
+        let storage = BlockManager::new(&dir.0).unwrap();
+        let mut index = new_vault(&dir.0);
+        let mut expected = BTreeMap::new();
+        apply(&mut index, &storage, &key, &changes, &mut expected);
+
+        let loaded = IndexManager::load(dir.0.clone(), &key).expect("load");
+        prop_assert_eq!(&loaded.data.files, &index.data.files);
+        for (path, data) in &expected {
+            let entry = loaded.get_file(path).expect("stored file in the index");
+            prop_assert_eq!(entry.size, data.len() as u64);
+            prop_assert_eq!(&read(&storage, &key, entry), data);
+        }
+    }
+
+    #[test]
+    fn merging_agrees_both_ways_and_settles(base in changes(), ours in changes(), theirs in changes()) {
+        let dir = Scratch::new("merge");
+        let (a_dir, b_dir) = (dir.0.join("a"), dir.0.join("b"));
+        let key = CryptoEngine::random_key();
+        // A header, for adopting write-once entries to record them in
+        fs::create_dir_all(&a_dir).unwrap();
+        VaultHeader::new().save(&a_dir).unwrap();
+        let mut a = new_vault(&a_dir);
+        let mut expected = BTreeMap::new();
+        apply(&mut a, &BlockManager::new(&a_dir).unwrap(), &key, &base, &mut expected);
+
+        // A second copy of the vault, changed apart from the first
+        copy_dir(&a_dir, &b_dir);
+        let mut b = IndexManager::load(b_dir.clone(), &key).expect("load copy");
+        apply(&mut a, &BlockManager::new(&a_dir).unwrap(), &key, &ours, &mut expected.clone());
+        apply(&mut b, &BlockManager::new(&b_dir).unwrap(), &key, &theirs, &mut expected);
+
+        let merged = merge::merge(&a.data, &b.data);
+        let flipped = merge::merge(&b.data, &a.data);
+        prop_assert_eq!(&merged.index.files, &flipped.index.files);
+        prop_assert_eq!(&merged.index.clock, &flipped.index.clock);
+        prop_assert_eq!(merged.conflicts.len(), flipped.conflicts.len());
+        prop_assert!(!merge::merge(&merged.index, &merged.index).differs_from(&merged.index));
+
+        // Each side adopts the merge; merging either with the other's again finds nothing new
+        a.replace(merged.index.clone(), &key).expect("adopt");
+        b.replace(flipped.index, &key).expect("adopt");
+        prop_assert!(!merge::merge(&a.data, &b.data).differs_from(&a.data));
+        prop_assert!(!merge::merge(&b.data, &a.data).differs_from(&b.data));
+    }
+
+    #[test]
+    fn clean_never_takes_a_needed_block(changes in changes()) {
+        let dir = Scratch::new("clean");
+        let key = CryptoEngine::random_key();
+        // Blocks on two volumes, each kept on both
+        let second = Scratch::new("clean-volume");
+        fs::create_dir_all(&dir.0).unwrap();
+        let mut volumes = Volumes::load(&dir.0).unwrap();
+        volumes.add("second", &second.0, None).unwrap();
+        volumes.copies = 2;
+        volumes.save(&dir.0).unwrap();
+        let storage = BlockManager::new(&dir.0).unwrap();
+        let mut index = new_vault(&dir.0);
+        let mut expected = BTreeMap::new();
+        apply(&mut index, &storage, &key, &changes, &mut expected);
+
+        let referenced = index.referenced_blocks();
+        for (id, _) in storage.list_blocks().unwrap() {
+            if !referenced.contains(id.as_str()) {
+                storage.delete_block(&id).unwrap();
+            }
+        }
+        prop_assert!(storage.list_blocks().unwrap().iter().all(|(id, _)| referenced.contains(id.as_str())));
+        for (path, data) in &expected {
+            prop_assert_eq!(&read(&storage, &key, index.get_file(path).unwrap()), data);
+        }
+        for snapshot in index.data.snapshots.values() {
+            for entry in snapshot.files.values().filter(|e| !e.is_dir) {
+                prop_assert_eq!(read(&storage, &key, entry).len() as u64, entry.size);
+            }
+        }
+    }
+}